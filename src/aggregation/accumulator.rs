@@ -0,0 +1,579 @@
+//! Accumulators, which fold the documents of a group into a single value:
+//! `$sum`, `$avg`, `$push` and the rest of the operators accepted by
+//! `$group`.
+
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Array, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::SortSpec,
+};
+
+use super::{
+    expression::{arithmetic, Expression, Variables},
+    spill::{document_size, value_size},
+};
+
+#[derive(Debug, Clone)]
+pub enum Accumulator {
+    Sum(Expression),
+    Avg(Expression),
+    Min(Expression),
+    Max(Expression),
+    First(Expression),
+    Last(Expression),
+    Push(Expression),
+    AddToSet(Expression),
+    Count,
+    StdDevPop(Expression),
+    StdDevSamp(Expression),
+    MergeObjects(Expression),
+    FirstN {
+        input: Expression,
+        n: Expression,
+    },
+    LastN {
+        input: Expression,
+        n: Expression,
+    },
+    /// `$top`, `$bottom`, `$topN` and `$bottomN`: the `output` of the first
+    /// (or last) documents in `sortBy` order. `n` is `None` for the single
+    /// value forms.
+    Sorted {
+        output: Expression,
+        sort_by: SortSpec,
+        n: Option<Expression>,
+        bottom: bool,
+    },
+}
+
+/// The running state of an accumulator over one group.
+#[derive(Debug, Clone)]
+pub enum State {
+    Sum(Value),
+    Avg(f64, u64),
+    Extreme(Option<Value>),
+    Single(Option<Value>),
+    Values(Vec<Value>),
+    Count(i64),
+    /// Count, mean and sum of squared differences, as in Welford's method.
+    Variance(u64, f64, f64),
+    Merge(Document),
+    Limited(Option<usize>, Vec<Value>),
+    Sorted(Option<usize>, Vec<(Document, Value)>),
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+/// Replaces an optional value, returning the change in size.
+fn replaced(slot: &mut Option<Value>, value: Value) -> isize {
+    let before = slot.as_ref().map_or(0, value_size) as isize;
+    let after = value_size(&value) as isize;
+    *slot = Some(value);
+    after - before
+}
+
+/// Parses the `{ input, n }` style arguments of `$firstN` and `$lastN`, or
+/// the `{ output, sortBy, n }` arguments of the `$top` family.
+fn named_args<'a>(
+    name: &str,
+    arg: &'a Value,
+    allowed: &[&str],
+) -> CommandResult<Vec<Option<&'a Value>>> {
+    let Value::Document(spec) = arg else {
+        return Err(location(
+            5787801,
+            format!("specification must be an object; found {}", arg),
+        ));
+    };
+    if let Some(key) = spec.keys().find(|key| !allowed.contains(&key.as_str())) {
+        return Err(location(
+            5787901,
+            format!("Unknown argument for '{}' operator: {}", &name[1..], key),
+        ));
+    }
+    Ok(allowed.iter().map(|key| spec.get(key)).collect())
+}
+
+fn required<'a>(value: Option<&'a Value>, field: &str, code: i32) -> CommandResult<&'a Value> {
+    value.ok_or_else(|| location(code, format!("Missing value for '{}'", field)))
+}
+
+impl Accumulator {
+    /// Parses an accumulator such as `$sum: '$a'`.
+    pub fn parse(name: &str, arg: &Value) -> CommandResult<Self> {
+        let unary = |arg: &Value| {
+            if let Value::Array(_) = arg {
+                return Err(location(
+                    40237,
+                    format!("The {} accumulator is a unary operator", name),
+                ));
+            }
+            Expression::parse(arg)
+        };
+        Ok(match name {
+            "$sum" => Accumulator::Sum(unary(arg)?),
+            "$avg" => Accumulator::Avg(unary(arg)?),
+            "$min" => Accumulator::Min(unary(arg)?),
+            "$max" => Accumulator::Max(unary(arg)?),
+            "$first" => Accumulator::First(unary(arg)?),
+            "$last" => Accumulator::Last(unary(arg)?),
+            "$push" => Accumulator::Push(unary(arg)?),
+            "$addToSet" => Accumulator::AddToSet(unary(arg)?),
+            "$stdDevPop" => Accumulator::StdDevPop(unary(arg)?),
+            "$stdDevSamp" => Accumulator::StdDevSamp(unary(arg)?),
+            "$mergeObjects" => Accumulator::MergeObjects(unary(arg)?),
+            "$count" => match arg {
+                Value::Document(spec) if spec.is_empty() => Accumulator::Count,
+                _ => {
+                    return Err(location(40272, "$count takes no arguments, i.e. $count:{}"));
+                }
+            },
+            "$firstN" | "$lastN" => {
+                let args = named_args(name, arg, &["input", "n"])?;
+                let input = Expression::parse(required(args[0], "input", 5787907)?)?;
+                let n = Expression::parse(required(args[1], "n", 5787906)?)?;
+                if name == "$firstN" {
+                    Accumulator::FirstN { input, n }
+                } else {
+                    Accumulator::LastN { input, n }
+                }
+            }
+            "$top" | "$bottom" | "$topN" | "$bottomN" => {
+                let single = matches!(name, "$top" | "$bottom");
+                let allowed: &[&str] = if single {
+                    &["output", "sortBy"]
+                } else {
+                    &["output", "sortBy", "n"]
+                };
+                let args = named_args(name, arg, allowed)?;
+                let output = Expression::parse(required(args[0], "output", 5788004)?)?;
+                let sort_by = match required(args[1], "sortBy", 5788005)? {
+                    Value::Document(spec) => SortSpec::parse(spec)?,
+                    value => {
+                        return Err(location(
+                            5788003,
+                            format!("expected an object for 'sortBy', found {}", value),
+                        ));
+                    }
+                };
+                let n = match single {
+                    true => None,
+                    false => Some(Expression::parse(required(args[2], "n", 5788002)?)?),
+                };
+                Accumulator::Sorted {
+                    output,
+                    sort_by,
+                    n,
+                    bottom: name.starts_with("$bottom"),
+                }
+            }
+            name => {
+                return Err(location(
+                    15952,
+                    format!("unknown group operator '{}'", name),
+                ));
+            }
+        })
+    }
+
+    pub fn init(&self) -> State {
+        match self {
+            Accumulator::Sum(_) => State::Sum(Value::Int32(0)),
+            Accumulator::Avg(_) => State::Avg(0.0, 0),
+            Accumulator::Min(_) | Accumulator::Max(_) => State::Extreme(None),
+            Accumulator::First(_) | Accumulator::Last(_) => State::Single(None),
+            Accumulator::Push(_) | Accumulator::AddToSet(_) => State::Values(Vec::new()),
+            Accumulator::Count => State::Count(0),
+            Accumulator::StdDevPop(_) | Accumulator::StdDevSamp(_) => State::Variance(0, 0.0, 0.0),
+            Accumulator::MergeObjects(_) => State::Merge(Document::new()),
+            Accumulator::FirstN { .. } | Accumulator::LastN { .. } => {
+                State::Limited(None, Vec::new())
+            }
+            Accumulator::Sorted { .. } => State::Sorted(None, Vec::new()),
+        }
+    }
+
+    /// Folds one more document of the group into `state`, returning by how
+    /// many bytes the state grew, as estimated for memory limits.
+    pub fn accumulate(
+        &self,
+        state: &mut State,
+        doc: &Document,
+        vars: &Variables,
+    ) -> CommandResult<isize> {
+        let mut growth = 0;
+        match (self, state) {
+            (Accumulator::Sum(expression), State::Sum(sum)) => {
+                let value = expression.evaluate(vars)?;
+                if let Some(value) = value.filter(Value::is_number) {
+                    *sum = arithmetic(sum, &value, i64::checked_add, |a, b| a + b);
+                }
+            }
+            (Accumulator::Avg(expression), State::Avg(sum, count)) => {
+                if let Some(value) = expression.evaluate(vars)?.and_then(|v| v.as_f64()) {
+                    *sum += value;
+                    *count += 1;
+                }
+            }
+            (Accumulator::Min(expression), State::Extreme(extreme))
+            | (Accumulator::Max(expression), State::Extreme(extreme)) => {
+                let Some(value) = expression
+                    .evaluate(vars)?
+                    .filter(|value| !value.is_null_or_undefined())
+                else {
+                    return Ok(0);
+                };
+                let wanted = match self {
+                    Accumulator::Min(_) => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                if extreme
+                    .as_ref()
+                    .is_none_or(|current| value.compare(current) == wanted)
+                {
+                    growth = replaced(extreme, value);
+                }
+            }
+            (Accumulator::First(expression), State::Single(single)) => {
+                if single.is_none() {
+                    growth = replaced(single, expression.eval(vars)?);
+                }
+            }
+            (Accumulator::Last(expression), State::Single(single)) => {
+                growth = replaced(single, expression.eval(vars)?);
+            }
+            (Accumulator::Push(expression), State::Values(values)) => {
+                if let Some(value) = expression.evaluate(vars)? {
+                    growth = value_size(&value) as isize;
+                    values.push(value);
+                }
+            }
+            (Accumulator::AddToSet(expression), State::Values(values)) => {
+                if let Some(value) = expression.evaluate(vars)? {
+                    if !values.iter().any(|existing| existing.equals(&value)) {
+                        growth = value_size(&value) as isize;
+                        values.push(value);
+                    }
+                }
+            }
+            (Accumulator::Count, State::Count(count)) => *count += 1,
+            (
+                Accumulator::StdDevPop(expression) | Accumulator::StdDevSamp(expression),
+                State::Variance(count, mean, m2),
+            ) => {
+                let value = expression.evaluate(vars)?;
+                if let Some(x) = value.filter(Value::is_number).and_then(|v| v.as_f64()) {
+                    *count += 1;
+                    let delta = x - *mean;
+                    *mean += delta / *count as f64;
+                    *m2 += delta * (x - *mean);
+                }
+            }
+            (Accumulator::MergeObjects(expression), State::Merge(merged)) => {
+                match expression.evaluate(vars)? {
+                    Some(Value::Document(doc)) => {
+                        let before = document_size(merged) as isize;
+                        for (key, value) in doc.0 {
+                            merged.insert(key, value);
+                        }
+                        growth = document_size(merged) as isize - before;
+                    }
+                    None | Some(Value::Null) | Some(Value::Undefined) => {}
+                    Some(value) => {
+                        return Err(location(
+                            40400,
+                            format!(
+                                "$mergeObjects requires object inputs, but input {} is of type {}",
+                                value,
+                                value.type_name()
+                            ),
+                        ));
+                    }
+                }
+            }
+            (Accumulator::FirstN { input, n }, State::Limited(limit, values))
+            | (Accumulator::LastN { input, n }, State::Limited(limit, values)) => {
+                let limit = *limit.get_or_insert(evaluate_n(n, vars)?);
+                let value = input.eval(vars)?;
+                if matches!(self, Accumulator::FirstN { .. }) {
+                    if values.len() < limit {
+                        growth = value_size(&value) as isize;
+                        values.push(value);
+                    }
+                } else {
+                    growth = value_size(&value) as isize;
+                    values.push(value);
+                    if values.len() > limit {
+                        growth -= value_size(&values.remove(0)) as isize;
+                    }
+                }
+            }
+            (
+                Accumulator::Sorted {
+                    output,
+                    sort_by,
+                    n,
+                    bottom,
+                },
+                State::Sorted(limit, entries),
+            ) => {
+                let limit = match n {
+                    Some(n) => *limit.get_or_insert(evaluate_n(n, vars)?),
+                    None => 1,
+                };
+                let value = output.eval(vars)?;
+                growth = (document_size(doc) + value_size(&value)) as isize;
+                entries.push((doc.clone(), value));
+                // Keep the buffer bounded by pruning to the best entries
+                // whenever it doubles
+                if entries.len() >= limit * 2 {
+                    let size = |entries: &[(Document, Value)]| -> isize {
+                        entries
+                            .iter()
+                            .map(|(doc, value)| (document_size(doc) + value_size(value)) as isize)
+                            .sum()
+                    };
+                    let before = size(entries);
+                    prune(entries, sort_by, limit, *bottom);
+                    growth -= before - size(entries);
+                }
+            }
+            (_, state) => unreachable!("accumulator state mismatch: {:?}", state),
+        }
+        Ok(growth)
+    }
+
+    /// The final value of the accumulator for a group.
+    pub fn finish(&self, state: State) -> Value {
+        match (self, state) {
+            (_, State::Sum(sum)) => sum,
+            (_, State::Avg(_, 0)) => Value::Null,
+            (_, State::Avg(sum, count)) => Value::Double(sum / count as f64),
+            (_, State::Extreme(extreme)) => extreme.unwrap_or(Value::Null),
+            (_, State::Single(single)) => single.unwrap_or(Value::Null),
+            (_, State::Values(values)) | (_, State::Limited(_, values)) => {
+                Value::Array(Array(values))
+            }
+            (_, State::Count(count)) => match i32::try_from(count) {
+                Ok(count) => Value::Int32(count),
+                Err(_) => Value::Int64(count),
+            },
+            (Accumulator::StdDevPop(_), State::Variance(count, _, m2)) if count > 0 => {
+                Value::Double((m2 / count as f64).sqrt())
+            }
+            (Accumulator::StdDevSamp(_), State::Variance(count, _, m2)) if count > 1 => {
+                Value::Double((m2 / (count - 1) as f64).sqrt())
+            }
+            (_, State::Variance(..)) => Value::Null,
+            (_, State::Merge(merged)) => Value::Document(merged),
+            (
+                Accumulator::Sorted {
+                    sort_by, n, bottom, ..
+                },
+                State::Sorted(limit, mut entries),
+            ) => {
+                prune(&mut entries, sort_by, limit.unwrap_or(1), *bottom);
+                let mut values = entries.into_iter().map(|(_, value)| value);
+                match n {
+                    Some(_) => Value::Array(Array(values.collect())),
+                    None => values.next().unwrap_or(Value::Null),
+                }
+            }
+            (_, State::Sorted(..)) => unreachable!("only sorted accumulators keep entries"),
+        }
+    }
+    /// The state as a value, to spill it to disk while grouping.
+    pub fn spill(&self, state: State) -> Value {
+        let optional = |value: Option<Value>| Value::from(value.into_iter().collect::<Vec<_>>());
+        let limit = |limit: Option<usize>| limit.map_or(Value::Null, |n| Value::Int64(n as i64));
+        match state {
+            State::Sum(sum) => sum,
+            State::Avg(sum, count) => {
+                Value::from(vec![Value::Double(sum), Value::Int64(count as i64)])
+            }
+            State::Extreme(value) | State::Single(value) => optional(value),
+            State::Values(values) => Value::from(values),
+            State::Count(count) => Value::Int64(count),
+            State::Variance(count, mean, m2) => Value::from(vec![
+                Value::Int64(count as i64),
+                Value::Double(mean),
+                Value::Double(m2),
+            ]),
+            State::Merge(merged) => Value::Document(merged),
+            State::Limited(n, values) => Value::from(vec![limit(n), Value::from(values)]),
+            State::Sorted(n, entries) => {
+                let entries = entries
+                    .into_iter()
+                    .map(|(doc, value)| Value::from(vec![Value::Document(doc), value]))
+                    .collect::<Vec<_>>();
+                Value::from(vec![limit(n), Value::from(entries)])
+            }
+        }
+    }
+
+    /// Reads back a state spilled by [`Accumulator::spill`].
+    pub fn unspill(&self, value: Value) -> State {
+        let items = |value: Value| match value {
+            Value::Array(items) => items.0,
+            _ => Vec::new(),
+        };
+        let limit = |value: &Value| value.as_i64().map(|n| n as usize);
+        match (self.init(), value) {
+            (State::Sum(_), sum) => State::Sum(sum),
+            (State::Avg(..), value) => match &items(value)[..] {
+                [sum, count] => State::Avg(
+                    sum.as_f64().unwrap_or(0.0),
+                    count.as_i64().unwrap_or(0) as u64,
+                ),
+                _ => self.init(),
+            },
+            (State::Extreme(_), value) => State::Extreme(items(value).pop()),
+            (State::Single(_), value) => State::Single(items(value).pop()),
+            (State::Values(_), value) => State::Values(items(value)),
+            (State::Count(_), count) => State::Count(count.as_i64().unwrap_or(0)),
+            (State::Variance(..), value) => match &items(value)[..] {
+                [count, mean, m2] => State::Variance(
+                    count.as_i64().unwrap_or(0) as u64,
+                    mean.as_f64().unwrap_or(0.0),
+                    m2.as_f64().unwrap_or(0.0),
+                ),
+                _ => self.init(),
+            },
+            (State::Merge(_), Value::Document(merged)) => State::Merge(merged),
+            (State::Limited(..), value) => match <[Value; 2]>::try_from(items(value)) {
+                Ok([n, values]) => State::Limited(limit(&n), items(values)),
+                Err(_) => self.init(),
+            },
+            (State::Sorted(..), value) => match <[Value; 2]>::try_from(items(value)) {
+                Ok([n, entries]) => {
+                    let entries = items(entries)
+                        .into_iter()
+                        .filter_map(|entry| match <[Value; 2]>::try_from(items(entry)) {
+                            Ok([Value::Document(doc), value]) => Some((doc, value)),
+                            _ => None,
+                        })
+                        .collect();
+                    State::Sorted(limit(&n), entries)
+                }
+                Err(_) => self.init(),
+            },
+            (state, _) => state,
+        }
+    }
+
+    /// Folds into `state` the state of the same group over later documents,
+    /// as when merging groups spilled to disk.
+    pub fn combine(&self, state: &mut State, later: State) {
+        match (self, state, later) {
+            (_, State::Sum(sum), State::Sum(later)) => {
+                *sum = arithmetic(sum, &later, i64::checked_add, |a, b| a + b);
+            }
+            (_, State::Avg(sum, count), State::Avg(later_sum, later_count)) => {
+                *sum += later_sum;
+                *count += later_count;
+            }
+            (_, State::Extreme(extreme), State::Extreme(Some(value))) => {
+                let wanted = match self {
+                    Accumulator::Min(_) => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                if extreme
+                    .as_ref()
+                    .is_none_or(|current| value.compare(current) == wanted)
+                {
+                    *extreme = Some(value);
+                }
+            }
+            (Accumulator::First(_), State::Single(single), State::Single(later))
+                if single.is_none() =>
+            {
+                *single = later;
+            }
+            (Accumulator::Last(_), State::Single(single), State::Single(Some(later))) => {
+                *single = Some(later);
+            }
+            (Accumulator::AddToSet(_), State::Values(values), State::Values(later)) => {
+                for value in later {
+                    if !values.iter().any(|existing| existing.equals(&value)) {
+                        values.push(value);
+                    }
+                }
+            }
+            (_, State::Values(values), State::Values(later)) => values.extend(later),
+            (_, State::Count(count), State::Count(later)) => *count += later,
+            (_, State::Variance(count, mean, m2), State::Variance(n, later_mean, later_m2)) => {
+                // Chan et al.'s parallel variant of Welford's method
+                let total = *count + n;
+                if total > 0 {
+                    let delta = later_mean - *mean;
+                    *mean += delta * n as f64 / total as f64;
+                    *m2 += later_m2 + delta * delta * (*count * n) as f64 / total as f64;
+                    *count = total;
+                }
+            }
+            (_, State::Merge(merged), State::Merge(later)) => {
+                for (key, value) in later.0 {
+                    merged.insert(key, value);
+                }
+            }
+            (_, State::Limited(limit, values), State::Limited(later_limit, later)) => {
+                let n = *limit.get_or_insert(later_limit.unwrap_or(0));
+                values.extend(later);
+                if matches!(self, Accumulator::FirstN { .. }) {
+                    values.truncate(n);
+                } else {
+                    let excess = values.len().saturating_sub(n);
+                    values.drain(..excess);
+                }
+            }
+            (
+                Accumulator::Sorted {
+                    sort_by, bottom, ..
+                },
+                State::Sorted(limit, entries),
+                State::Sorted(later_limit, later),
+            ) => {
+                let n = *limit.get_or_insert(later_limit.unwrap_or(1));
+                entries.extend(later);
+                prune(entries, sort_by, n, *bottom);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Sorts `entries` and keeps the first `limit` (or the last, in order, for
+/// the bottom accumulators).
+fn prune(entries: &mut Vec<(Document, Value)>, sort_by: &SortSpec, limit: usize, bottom: bool) {
+    entries.sort_by(|(a, _), (b, _)| sort_by.compare(a, b));
+    if bottom {
+        let excess = entries.len().saturating_sub(limit);
+        entries.drain(..excess);
+    } else {
+        entries.truncate(limit);
+    }
+}
+
+/// Evaluates the `n` argument of the `N` accumulators, which must be a
+/// positive integer.
+fn evaluate_n(n: &Expression, vars: &Variables) -> CommandResult<usize> {
+    let value = n.eval(vars)?;
+    match value.as_i64() {
+        Some(n) if value.is_number() && n > 0 => Ok(n as usize),
+        Some(n) if value.is_number() => Err(location(
+            5787908,
+            format!("'n' must be greater than 0, found {}", n),
+        )),
+        _ => Err(location(
+            5787902,
+            format!(
+                "Value for 'n' must be of integral type, but found {}",
+                value
+            ),
+        )),
+    }
+}
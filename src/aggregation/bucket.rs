@@ -0,0 +1,653 @@
+//! The `$bucket` and `$bucketAuto` stages, which group documents into
+//! ranges of the value of a `groupBy` expression.
+
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{
+    expression::Expression,
+    group::Group,
+    pipeline::{Context, Metadata},
+};
+
+/// `$bucket`: buckets with fixed boundaries, each including its lower
+/// boundary and excluding its upper one.
+#[derive(Debug, Clone)]
+pub struct Bucket {
+    group_by: Expression,
+    boundaries: Vec<Value>,
+    default: Option<Value>,
+    output: Group,
+}
+
+/// `$bucketAuto`: a number of buckets holding about as many documents each,
+/// with boundaries optionally rounded to a preferred number series.
+#[derive(Debug, Clone)]
+pub struct BucketAuto {
+    group_by: Expression,
+    buckets: usize,
+    granularity: Option<Granularity>,
+    output: Group,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Granularity {
+    /// A preferred number series, by its values in [1, 10).
+    Series(&'static [f64]),
+    PowersOf2,
+}
+
+const R5: &[f64] = &[1.0, 1.6, 2.5, 4.0, 6.3];
+const R10: &[f64] = &[1.0, 1.25, 1.6, 2.0, 2.5, 3.15, 4.0, 5.0, 6.3, 8.0];
+const R20: &[f64] = &[
+    1.0, 1.12, 1.25, 1.4, 1.6, 1.8, 2.0, 2.24, 2.5, 2.8, 3.15, 3.55, 4.0, 4.5, 5.0, 5.6, 6.3, 7.1,
+    8.0, 9.0,
+];
+const R40: &[f64] = &[
+    1.0, 1.06, 1.12, 1.18, 1.25, 1.32, 1.4, 1.5, 1.6, 1.7, 1.8, 1.9, 2.0, 2.12, 2.24, 2.36, 2.5,
+    2.65, 2.8, 3.0, 3.15, 3.35, 3.55, 3.75, 4.0, 4.25, 4.5, 4.75, 5.0, 5.3, 5.6, 6.0, 6.3, 6.7,
+    7.1, 7.5, 8.0, 8.5, 9.0, 9.5,
+];
+const R80: &[f64] = &[
+    1.0, 1.03, 1.06, 1.09, 1.12, 1.15, 1.18, 1.22, 1.25, 1.28, 1.32, 1.36, 1.4, 1.45, 1.5, 1.55,
+    1.6, 1.65, 1.7, 1.75, 1.8, 1.85, 1.9, 1.95, 2.0, 2.06, 2.12, 2.18, 2.24, 2.3, 2.36, 2.43, 2.5,
+    2.58, 2.65, 2.72, 2.8, 2.9, 3.0, 3.07, 3.15, 3.25, 3.35, 3.45, 3.55, 3.65, 3.75, 3.87, 4.0,
+    4.12, 4.25, 4.37, 4.5, 4.62, 4.75, 4.87, 5.0, 5.15, 5.3, 5.45, 5.6, 5.8, 6.0, 6.15, 6.3, 6.5,
+    6.7, 6.9, 7.1, 7.3, 7.5, 7.75, 8.0, 8.25, 8.5, 8.75, 9.0, 9.25, 9.5, 9.75,
+];
+const ONE_TWO_FIVE: &[f64] = &[1.0, 2.0, 5.0];
+const E6: &[f64] = &[1.0, 1.5, 2.2, 3.3, 4.7, 6.8];
+const E12: &[f64] = &[1.0, 1.2, 1.5, 1.8, 2.2, 2.7, 3.3, 3.9, 4.7, 5.6, 6.8, 8.2];
+const E24: &[f64] = &[
+    1.0, 1.1, 1.2, 1.3, 1.5, 1.6, 1.8, 2.0, 2.2, 2.4, 2.7, 3.0, 3.3, 3.6, 3.9, 4.3, 4.7, 5.1, 5.6,
+    6.2, 6.8, 7.5, 8.2, 9.1,
+];
+const E48: &[f64] = &[
+    1.0, 1.05, 1.1, 1.15, 1.21, 1.27, 1.33, 1.4, 1.47, 1.54, 1.62, 1.69, 1.78, 1.87, 1.96, 2.05,
+    2.15, 2.26, 2.37, 2.49, 2.61, 2.74, 2.87, 3.01, 3.16, 3.32, 3.48, 3.65, 3.83, 4.02, 4.22, 4.42,
+    4.64, 4.87, 5.11, 5.36, 5.62, 5.9, 6.19, 6.49, 6.81, 7.15, 7.5, 7.87, 8.25, 8.66, 9.09, 9.53,
+];
+const E96: &[f64] = &[
+    1.0, 1.02, 1.05, 1.07, 1.1, 1.13, 1.15, 1.18, 1.21, 1.24, 1.27, 1.3, 1.33, 1.37, 1.4, 1.43,
+    1.47, 1.5, 1.54, 1.58, 1.62, 1.65, 1.69, 1.74, 1.78, 1.82, 1.87, 1.91, 1.96, 2.0, 2.05, 2.1,
+    2.15, 2.21, 2.26, 2.32, 2.37, 2.43, 2.49, 2.55, 2.61, 2.67, 2.74, 2.8, 2.87, 2.94, 3.01, 3.09,
+    3.16, 3.24, 3.32, 3.4, 3.48, 3.57, 3.65, 3.74, 3.83, 3.92, 4.02, 4.12, 4.22, 4.32, 4.42, 4.53,
+    4.64, 4.75, 4.87, 4.99, 5.11, 5.23, 5.36, 5.49, 5.62, 5.76, 5.9, 6.04, 6.19, 6.34, 6.49, 6.65,
+    6.81, 6.98, 7.15, 7.32, 7.5, 7.68, 7.87, 8.06, 8.25, 8.45, 8.66, 8.87, 9.09, 9.31, 9.53, 9.76,
+];
+const E192: &[f64] = &[
+    1.0, 1.01, 1.02, 1.04, 1.05, 1.06, 1.07, 1.09, 1.1, 1.11, 1.13, 1.14, 1.15, 1.17, 1.18, 1.2,
+    1.21, 1.23, 1.24, 1.26, 1.27, 1.29, 1.3, 1.32, 1.33, 1.35, 1.37, 1.38, 1.4, 1.42, 1.43, 1.45,
+    1.47, 1.49, 1.5, 1.52, 1.54, 1.56, 1.58, 1.6, 1.62, 1.64, 1.65, 1.67, 1.69, 1.72, 1.74, 1.76,
+    1.78, 1.8, 1.82, 1.84, 1.87, 1.89, 1.91, 1.93, 1.96, 1.98, 2.0, 2.03, 2.05, 2.08, 2.1, 2.13,
+    2.15, 2.18, 2.21, 2.23, 2.26, 2.29, 2.32, 2.34, 2.37, 2.4, 2.43, 2.46, 2.49, 2.52, 2.55, 2.58,
+    2.61, 2.64, 2.67, 2.71, 2.74, 2.77, 2.8, 2.84, 2.87, 2.91, 2.94, 2.98, 3.01, 3.05, 3.09, 3.12,
+    3.16, 3.2, 3.24, 3.28, 3.32, 3.36, 3.4, 3.44, 3.48, 3.52, 3.57, 3.61, 3.65, 3.7, 3.74, 3.79,
+    3.83, 3.88, 3.92, 3.97, 4.02, 4.07, 4.12, 4.17, 4.22, 4.27, 4.32, 4.37, 4.42, 4.48, 4.53, 4.59,
+    4.64, 4.7, 4.75, 4.81, 4.87, 4.93, 4.99, 5.05, 5.11, 5.17, 5.23, 5.3, 5.36, 5.42, 5.49, 5.56,
+    5.62, 5.69, 5.76, 5.83, 5.9, 5.97, 6.04, 6.12, 6.19, 6.26, 6.34, 6.42, 6.49, 6.57, 6.65, 6.73,
+    6.81, 6.9, 6.98, 7.06, 7.15, 7.23, 7.32, 7.41, 7.5, 7.59, 7.68, 7.77, 7.87, 7.96, 8.06, 8.16,
+    8.25, 8.35, 8.45, 8.56, 8.66, 8.76, 8.87, 8.98, 9.09, 9.2, 9.31, 9.42, 9.53, 9.65, 9.76, 9.88,
+];
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+/// `groupBy` must be a field path or an expression object.
+fn parse_group_by(stage: &str, code: i32, value: &Value) -> CommandResult<Expression> {
+    match value {
+        Value::String(path) if path.starts_with('$') => Expression::parse(value),
+        Value::Document(_) => Expression::parse(value),
+        value => Err(location(
+            code,
+            format!(
+                "The {} 'groupBy' field must be defined as a $-prefixed path or an expression object, but found: {}",
+                stage, value
+            ),
+        )),
+    }
+}
+
+fn parse_output(stage: &str, code: i32, value: Option<&Value>) -> CommandResult<Group> {
+    match value {
+        None => Group::parse_output(None),
+        Some(Value::Document(output)) => Group::parse_output(Some(output)),
+        Some(value) => Err(location(
+            code,
+            format!(
+                "The {} 'output' field must be an object, but found type: {}",
+                stage,
+                value.type_name()
+            ),
+        )),
+    }
+}
+
+/// Whether two values have the same canonical type, numbers all being one.
+fn same_type(a: &Value, b: &Value) -> bool {
+    (a.is_number() && b.is_number()) || a.type_name() == b.type_name()
+}
+
+impl Bucket {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(location(
+                40201,
+                format!(
+                    "Argument to $bucket stage must be an object, but found type: {}",
+                    spec.type_name()
+                ),
+            ));
+        };
+        if let Some(key) = spec.keys().find(|key| {
+            !matches!(
+                key.as_str(),
+                "groupBy" | "boundaries" | "default" | "output"
+            )
+        }) {
+            return Err(location(
+                40197,
+                format!("Unrecognized option to $bucket: {}.", key),
+            ));
+        }
+        let (Some(group_by), Some(boundaries)) = (spec.get("groupBy"), spec.get("boundaries"))
+        else {
+            return Err(location(
+                40198,
+                "$bucket requires 'groupBy' and 'boundaries' to be specified.",
+            ));
+        };
+        let group_by = parse_group_by("$bucket", 40202, group_by)?;
+
+        let Value::Array(boundaries) = boundaries else {
+            return Err(location(
+                40200,
+                format!(
+                    "The $bucket 'boundaries' field must be an array, but found type: {}",
+                    boundaries.type_name()
+                ),
+            ));
+        };
+        let boundaries = boundaries.0.clone();
+        if boundaries.len() < 2 {
+            return Err(location(
+                40192,
+                format!(
+                    "The $bucket 'boundaries' field must have at least 2 values, but found {} value(s).",
+                    boundaries.len()
+                ),
+            ));
+        }
+        for pair in boundaries.windows(2) {
+            if !same_type(&pair[0], &pair[1]) {
+                return Err(location(
+                    40193,
+                    format!(
+                        "All values in the the 'boundaries' option to $bucket must have the same type. Found conflicting types {} and {}.",
+                        pair[0].type_name(),
+                        pair[1].type_name()
+                    ),
+                ));
+            }
+            if pair[0].compare(&pair[1]) != Ordering::Less {
+                return Err(location(
+                    40194,
+                    format!(
+                        "The 'boundaries' option to $bucket must be sorted, but elements {} and {} are not in ascending order.",
+                        pair[0], pair[1]
+                    ),
+                ));
+            }
+        }
+
+        let default = spec.get("default").cloned();
+        if let Some(default) = &default {
+            let (first, last) = (&boundaries[0], &boundaries[boundaries.len() - 1]);
+            let outside =
+                default.compare(first) == Ordering::Less || default.compare(last) != Ordering::Less;
+            if same_type(default, first) && !outside {
+                return Err(location(
+                    40199,
+                    "The $bucket 'default' field must be less than the lowest boundary or greater than or equal to the highest boundary.",
+                ));
+            }
+        }
+        Ok(Self {
+            group_by,
+            boundaries,
+            default,
+            output: parse_output("$bucket", 40196, spec.get("output"))?,
+        })
+    }
+
+    pub fn run(&self, documents: Vec<Document>, context: &Context) -> CommandResult<Vec<Document>> {
+        let mut keyed = Vec::new();
+        for doc in documents {
+            let value = self.group_by.eval(&context.variables(&doc))?;
+            let bucket = self.boundaries.windows(2).find(|pair| {
+                value.compare(&pair[0]) != Ordering::Less
+                    && value.compare(&pair[1]) == Ordering::Less
+            });
+            let key = match (bucket, &self.default) {
+                (Some(pair), _) => pair[0].clone(),
+                (None, Some(default)) => default.clone(),
+                (None, None) => {
+                    return Err(location(
+                        40066,
+                        format!(
+                            "$switch could not find a matching branch for an input, and no default was specified. Input value: {}",
+                            value
+                        ),
+                    ));
+                }
+            };
+            keyed.push((key, doc));
+        }
+
+        // Buckets are output in the order of their boundaries, then the
+        // default bucket
+        let mut buckets = self.output.run_keyed(
+            keyed
+                .into_iter()
+                .map(|(key, doc)| Ok((key, doc, Metadata::default()))),
+            context,
+        )?;
+        let lower = self.boundaries.len() - 1;
+        buckets.sort_by_key(|bucket| {
+            let id = bucket.get("_id");
+            self.boundaries[..lower]
+                .iter()
+                .position(|boundary| id.is_some_and(|id| id.equals(boundary)))
+                .unwrap_or(lower)
+        });
+        Ok(buckets)
+    }
+}
+
+impl BucketAuto {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(location(
+                40240,
+                format!(
+                    "The argument to $bucketAuto must be an object, but found type: {}",
+                    spec.type_name()
+                ),
+            ));
+        };
+        if let Some(key) = spec.keys().find(|key| {
+            !matches!(
+                key.as_str(),
+                "groupBy" | "buckets" | "output" | "granularity"
+            )
+        }) {
+            return Err(location(
+                40245,
+                format!("Unrecognized option to $bucketAuto: {}.", key),
+            ));
+        }
+        let (Some(group_by), Some(buckets)) = (spec.get("groupBy"), spec.get("buckets")) else {
+            return Err(location(
+                40246,
+                "$bucketAuto requires 'groupBy' and 'buckets' to be specified",
+            ));
+        };
+        let group_by = parse_group_by("$bucketAuto", 40239, group_by)?;
+
+        if !buckets.is_number() {
+            return Err(location(
+                40241,
+                format!(
+                    "The $bucketAuto 'buckets' field must be a numeric value, but found type: {}",
+                    buckets.type_name()
+                ),
+            ));
+        }
+        let Some(count) = buckets.as_i64().and_then(|n| i32::try_from(n).ok()) else {
+            return Err(location(
+                40242,
+                format!(
+                    "The $bucketAuto 'buckets' field must be representable as a 32-bit integer, but found {}",
+                    buckets
+                ),
+            ));
+        };
+        if count <= 0 {
+            return Err(location(
+                40243,
+                format!(
+                    "The $bucketAuto 'buckets' field must be greater than 0, but found: {}",
+                    count
+                ),
+            ));
+        }
+
+        let granularity = match spec.get("granularity") {
+            None => None,
+            Some(Value::String(name)) => Some(Granularity::parse(name)?),
+            Some(value) => {
+                return Err(location(
+                    40261,
+                    format!(
+                        "The $bucketAuto 'granularity' field must be a string, but found type: {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        Ok(Self {
+            group_by,
+            buckets: count as usize,
+            granularity,
+            output: parse_output("$bucketAuto", 40244, spec.get("output"))?,
+        })
+    }
+
+    pub fn run(&self, documents: Vec<Document>, context: &Context) -> CommandResult<Vec<Document>> {
+        let mut sorted = Vec::new();
+        for doc in documents {
+            let value = self.group_by.eval(&context.variables(&doc))?;
+            if self.granularity.is_some() {
+                check_roundable(&value)?;
+            }
+            sorted.push((value, doc));
+        }
+        sorted.sort_by(|a, b| a.0.compare(&b.0));
+
+        // Fill each bucket up to the approximate size, keeping equal values
+        // together; the last bucket takes whatever remains
+        let approximate_size =
+            ((sorted.len() as f64 / self.buckets as f64).round() as usize).max(1);
+        let mut ranges: Vec<(usize, usize, Value)> = Vec::new();
+        let mut end = 0;
+        while end < sorted.len() {
+            let start = end;
+            end = if ranges.len() + 1 == self.buckets {
+                sorted.len()
+            } else {
+                (start + approximate_size).min(sorted.len())
+            };
+            while end < sorted.len() && sorted[end].0.compare(&sorted[end - 1].0) == Ordering::Equal
+            {
+                end += 1;
+            }
+            let mut max = sorted[end - 1].0.clone();
+            if let Some(granularity) = self.granularity {
+                let rounded = granularity.round_up(max.as_f64().unwrap_or(0.0));
+                while end < sorted.len() && sorted[end].0.as_f64().is_some_and(|v| v < rounded) {
+                    end += 1;
+                }
+                max = Value::Double(rounded);
+            }
+            ranges.push((start, end, max));
+        }
+
+        // Without a granularity, each bucket ends where the next one starts
+        let mut keys = Vec::new();
+        for (i, (start, _, max)) in ranges.iter().enumerate() {
+            let min = match (self.granularity, i) {
+                (Some(granularity), 0) => {
+                    Value::Double(granularity.round_down(sorted[*start].0.as_f64().unwrap_or(0.0)))
+                }
+                (Some(_), i) => ranges[i - 1].2.clone(),
+                (None, _) => sorted[*start].0.clone(),
+            };
+            let max = match (self.granularity, ranges.get(i + 1)) {
+                (None, Some((next, _, _))) => sorted[*next].0.clone(),
+                _ => max.clone(),
+            };
+            let mut id = Document::new();
+            id.insert("min", min);
+            id.insert("max", max);
+            keys.push(Value::Document(id));
+        }
+
+        let mut keyed = Vec::new();
+        let mut documents = sorted.into_iter().map(|(_, doc)| doc);
+        for ((start, end, _), key) in ranges.iter().zip(keys) {
+            for doc in documents.by_ref().take(end - start) {
+                keyed.push((key.clone(), doc));
+            }
+        }
+        self.output.run_keyed(
+            keyed
+                .into_iter()
+                .map(|(key, doc)| Ok((key, doc, Metadata::default()))),
+            context,
+        )
+    }
+}
+
+fn check_roundable(value: &Value) -> CommandResult<()> {
+    let Some(number) = value.as_f64().filter(|_| value.is_number()) else {
+        return Err(location(
+            40258,
+            format!(
+                "$bucketAuto can specify a 'granularity' with numeric boundaries only, but found a value with type: {}",
+                value.type_name()
+            ),
+        ));
+    };
+    // Infinity has no number of the series above it to round up to
+    if !number.is_finite() || number < 0.0 {
+        return Err(location(
+            40260,
+            format!(
+                "$bucketAuto can specify a 'granularity' with numeric boundaries only if they are finite and non-negative, but found: {}",
+                value
+            ),
+        ));
+    }
+    Ok(())
+}
+
+impl Granularity {
+    fn parse(name: &str) -> CommandResult<Self> {
+        let series = match name {
+            "R5" => R5,
+            "R10" => R10,
+            "R20" => R20,
+            "R40" => R40,
+            "R80" => R80,
+            "1-2-5" => ONE_TWO_FIVE,
+            "E6" => E6,
+            "E12" => E12,
+            "E24" => E24,
+            "E48" => E48,
+            "E96" => E96,
+            "E192" => E192,
+            "POWERSOF2" => return Ok(Granularity::PowersOf2),
+            name => {
+                return Err(location(
+                    40257,
+                    format!("Unknown rounding granularity '{}'", name),
+                ));
+            }
+        };
+        Ok(Granularity::Series(series))
+    }
+
+    /// The smallest number of the series greater than `value`.
+    fn round_up(self, value: f64) -> f64 {
+        if value == 0.0 {
+            return 0.0;
+        }
+        match self {
+            Granularity::PowersOf2 => 2f64.powf(value.log2().floor() + 1.0),
+            Granularity::Series(series) => {
+                let mut scale = 10f64.powf(value.log10().floor());
+                loop {
+                    if let Some(rounded) = series.iter().map(|s| s * scale).find(|v| *v > value) {
+                        return rounded;
+                    }
+                    scale *= 10.0;
+                }
+            }
+        }
+    }
+
+    /// The largest number of the series less than `value`.
+    fn round_down(self, value: f64) -> f64 {
+        if value == 0.0 {
+            return 0.0;
+        }
+        match self {
+            Granularity::PowersOf2 => {
+                let power = 2f64.powf(value.log2().floor());
+                if power < value {
+                    power
+                } else {
+                    power / 2.0
+                }
+            }
+            Granularity::Series(series) => {
+                let mut scale = 10f64.powf(value.log10().floor());
+                loop {
+                    let rounded = series.iter().rev().map(|s| s * scale).find(|v| *v < value);
+                    if let Some(rounded) = rounded {
+                        return rounded;
+                    }
+                    scale /= 10.0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    fn run(stage: &str, spec: &str, documents: &[&str]) -> CommandResult<Vec<Document>> {
+        let documents = documents.iter().map(|d| doc(d)).collect();
+        let context = Context::default();
+        match stage {
+            "$bucket" => Bucket::parse(&value(spec))?.run(documents, &context),
+            _ => BucketAuto::parse(&value(spec))?.run(documents, &context),
+        }
+    }
+
+    const PRICES: [&str; 7] = [
+        "{ price: 5 }",
+        "{ price: 12 }",
+        "{ price: 12 }",
+        "{ price: 27 }",
+        "{ price: 40.5 }",
+        "{ price: 'n/a' }",
+        "{}",
+    ];
+
+    #[test]
+    fn test_bucket() {
+        assert_eq!(
+            run(
+                "$bucket",
+                "{ groupBy: '$price', boundaries: [0, 10, 20, 50], default: 'Other', output: { n: { $sum: 1 }, prices: { $push: '$price' } } }",
+                &PRICES
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: 0, n: 1, prices: [5] }"),
+                doc("{ _id: 10, n: 2, prices: [12, 12] }"),
+                doc("{ _id: 20, n: 2, prices: [27, 40.5] }"),
+                doc("{ _id: 'Other', n: 2, prices: ['n/a'] }"),
+            ]
+        );
+
+        let code = |spec| run("$bucket", spec, &PRICES).unwrap_err().code;
+        assert_eq!(
+            code("{ groupBy: '$price', boundaries: [0, 50] }"),
+            ErrorCode::Location(40066)
+        );
+        assert_eq!(
+            code("{ groupBy: '$price', boundaries: [10, 0] }"),
+            ErrorCode::Location(40194)
+        );
+        assert_eq!(
+            code("{ groupBy: '$price', boundaries: [0, 'a'] }"),
+            ErrorCode::Location(40193)
+        );
+        assert_eq!(
+            code("{ groupBy: '$price', boundaries: [0, 10, 20], default: 10 }"),
+            ErrorCode::Location(40199)
+        );
+        assert_eq!(
+            code("{ groupBy: 'price', boundaries: [0, 10] }"),
+            ErrorCode::Location(40202)
+        );
+    }
+
+    #[test]
+    fn test_bucket_auto() {
+        assert_eq!(
+            run(
+                "$bucketAuto",
+                "{ groupBy: '$price', buckets: 3 }",
+                &PRICES[..5]
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: { min: 5, max: 27 }, count: 3 }"),
+                doc("{ _id: { min: 27, max: 40.5 }, count: 2 }"),
+            ]
+        );
+        assert_eq!(
+            run(
+                "$bucketAuto",
+                "{ groupBy: '$price', buckets: 2, granularity: 'R5' }",
+                &PRICES[..5]
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: { min: 4.0, max: 16.0 }, count: 3 }"),
+                doc("{ _id: { min: 16.0, max: 63.0 }, count: 2 }"),
+            ]
+        );
+        assert_eq!(
+            run(
+                "$bucketAuto",
+                "{ groupBy: '$price', buckets: 5, granularity: 'POWERSOF2', output: { total: { $sum: '$price' } } }",
+                &PRICES[..4]
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: { min: 4.0, max: 8.0 }, total: 5 }"),
+                doc("{ _id: { min: 8.0, max: 16.0 }, total: 24 }"),
+                doc("{ _id: { min: 16.0, max: 32.0 }, total: 27 }"),
+            ]
+        );
+
+        let code = |spec| run("$bucketAuto", spec, &PRICES).unwrap_err().code;
+        assert_eq!(
+            code("{ groupBy: '$price', buckets: 0 }"),
+            ErrorCode::Location(40243)
+        );
+        assert_eq!(
+            code("{ groupBy: '$price', buckets: 2, granularity: 'R7' }"),
+            ErrorCode::Location(40257)
+        );
+        assert_eq!(
+            code("{ groupBy: '$price', buckets: 2, granularity: 'E12' }"),
+            ErrorCode::Location(40258)
+        );
+        assert_eq!(
+            code("{ groupBy: { $pow: [10, 400] }, buckets: 2, granularity: 'R5' }"),
+            ErrorCode::Location(40260)
+        );
+    }
+
+    #[test]
+    fn test_granularity_series() {
+        let lengths = [R5, R10, R20, R40, R80, E6, E12, E24, E48, E96, E192].map(<[f64]>::len);
+        assert_eq!(lengths, [5, 10, 20, 40, 80, 6, 12, 24, 48, 96, 192]);
+        let e12 = Granularity::Series(E12);
+        assert_eq!(e12.round_up(33.0), 39.0);
+        assert_eq!(e12.round_down(100.0), 82.0);
+    }
+}
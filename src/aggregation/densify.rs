@@ -0,0 +1,494 @@
+//! The `$densify` stage, which adds documents to fill the gaps in a sequence
+//! of numbers or dates, optionally within partitions.
+
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{
+    expression::{arithmetic, TimeUnit},
+    stage::{get_field, set_field},
+};
+
+/// The most documents one `$densify` stage may generate.
+const MAX_GENERATED: usize = 500_000;
+
+#[derive(Debug, Clone)]
+pub struct Densify {
+    field: Vec<String>,
+    partition_by: Vec<Vec<String>>,
+    step: Value,
+    /// The unit of the step, which makes the field a date.
+    unit: Option<TimeUnit>,
+    bounds: Bounds,
+}
+
+#[derive(Debug, Clone)]
+enum Bounds {
+    /// From the lowest to the highest value of all documents.
+    Full,
+    /// From the lowest to the highest value of each partition.
+    Partition,
+    /// From the lower bound, up to but excluding the upper one.
+    Explicit(Value, Value),
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('.').map(String::from).collect()
+}
+
+/// The documents of one partition, by whether they have a value for the
+/// field.
+struct Partition {
+    key: Vec<Option<Value>>,
+    unvalued: Vec<Document>,
+    valued: Vec<(Value, Document)>,
+}
+
+/// Orders partition keys, with missing fields first.
+fn compare_keys(a: &[Option<Value>], b: &[Option<Value>]) -> Ordering {
+    for (a, b) in a.iter().zip(b) {
+        let ordering = match (a, b) {
+            (Some(a), Some(b)) => a.compare(b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+fn wrong_type(field: &str, value: &Value, expected: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::TypeMismatch,
+        format!(
+            "BSON field '$densify.{}' is the wrong type '{}', expected type '{}'",
+            field,
+            value.type_name(),
+            expected
+        ),
+    )
+}
+
+fn missing(field: &str) -> CommandError {
+    location(
+        40414,
+        format!(
+            "BSON field '$densify.{}' is missing but a required field",
+            field
+        ),
+    )
+}
+
+impl Densify {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                format!(
+                    "the $densify stage specification must be an object, found {}",
+                    spec.type_name()
+                ),
+            ));
+        };
+        if let Some(key) = spec
+            .keys()
+            .find(|key| !matches!(key.as_str(), "field" | "partitionByFields" | "range"))
+        {
+            return Err(location(
+                40415,
+                format!("BSON field '$densify.{}' is an unknown field.", key),
+            ));
+        }
+        let field = match spec.get("field") {
+            Some(Value::String(field)) if field.starts_with('$') => {
+                return Err(location(
+                    5733408,
+                    format!("Cannot densify field starting with '$', got {}", field),
+                ));
+            }
+            Some(Value::String(field)) => split_path(field),
+            Some(value) => return Err(wrong_type("field", value, "string")),
+            None => return Err(missing("field")),
+        };
+        let partition_by = match spec.get("partitionByFields") {
+            None => Vec::new(),
+            Some(Value::Array(fields)) => {
+                let mut partition_by = Vec::new();
+                for field in &fields.0 {
+                    match field {
+                        Value::String(name) if name.starts_with('$') => {
+                            return Err(location(
+                                8993000,
+                                format!(
+                                    "Cannot partition by field starting with '$', got {}",
+                                    name
+                                ),
+                            ));
+                        }
+                        Value::String(name) => partition_by.push(split_path(name)),
+                        value => return Err(wrong_type("partitionByFields", value, "string")),
+                    }
+                }
+                partition_by
+            }
+            Some(value) => return Err(wrong_type("partitionByFields", value, "array")),
+        };
+        let range = match spec.get("range") {
+            Some(Value::Document(range)) => range,
+            Some(value) => return Err(wrong_type("range", value, "object")),
+            None => return Err(missing("range")),
+        };
+        if let Some(key) = range
+            .keys()
+            .find(|key| !matches!(key.as_str(), "step" | "unit" | "bounds"))
+        {
+            return Err(location(
+                40415,
+                format!("BSON field '$densify.range.{}' is an unknown field.", key),
+            ));
+        }
+
+        let unit = match range.get("unit") {
+            None => None,
+            Some(Value::String(name)) => Some(TimeUnit::from_name(name).ok_or_else(|| {
+                CommandError::new(
+                    ErrorCode::FailedToParse,
+                    format!("unknown time unit value: {}", name),
+                )
+            })?),
+            Some(value) => return Err(wrong_type("range.unit", value, "string")),
+        };
+        let step = match range.get("step") {
+            Some(step) if step.is_number() && step.as_f64().is_some_and(|s| s > 0.0) => step,
+            Some(_) => {
+                return Err(location(
+                    5733401,
+                    "the step parameter in a range statement must be a strictly positive numeric value",
+                ));
+            }
+            None => return Err(missing("range.step")),
+        };
+        if unit.is_some() && step.as_i64().is_none() {
+            return Err(location(
+                5733402,
+                "The step parameter in a range statement must be a whole number when densifying a date range",
+            ));
+        }
+        let bounds = match range.get("bounds") {
+            Some(Value::String(bounds)) if bounds == "full" => Bounds::Full,
+            Some(Value::String(bounds)) if bounds == "partition" => Bounds::Partition,
+            Some(Value::Array(bounds)) if bounds.0.len() == 2 => {
+                let (lower, upper) = (&bounds.0[0], &bounds.0[1]);
+                let valid = match unit {
+                    Some(_) => {
+                        matches!(lower, Value::UtcDateTime(_))
+                            && matches!(upper, Value::UtcDateTime(_))
+                    }
+                    None => lower.is_number() && upper.is_number(),
+                };
+                if !valid {
+                    return Err(location(
+                        5733403,
+                        "Explicit bounds must be two numbers, or two dates with a unit",
+                    ));
+                }
+                if lower.compare(upper) == Ordering::Greater {
+                    return Err(location(
+                        5733404,
+                        "the lower bound of a range must not exceed the upper bound",
+                    ));
+                }
+                Bounds::Explicit(lower.clone(), upper.clone())
+            }
+            Some(_) => {
+                return Err(location(
+                    5946802,
+                    "the bounds must be 'full', 'partition' or an array of two values",
+                ));
+            }
+            None => return Err(missing("range.bounds")),
+        };
+        if partition_by.contains(&field) {
+            return Err(location(
+                8993000,
+                "The densify field cannot also be a partitionByFields field",
+            ));
+        }
+
+        Ok(Self {
+            field,
+            partition_by,
+            step: step.clone(),
+            unit,
+            bounds,
+        })
+    }
+
+    pub fn run(&self, documents: Vec<Document>) -> CommandResult<Vec<Document>> {
+        let mut partitions: Vec<Partition> = Vec::new();
+        for doc in documents {
+            let key: Vec<_> = self
+                .partition_by
+                .iter()
+                .map(|path| get_field(&doc, path).cloned())
+                .collect();
+            let index = match partitions
+                .iter()
+                .position(|partition| compare_keys(&partition.key, &key) == Ordering::Equal)
+            {
+                Some(index) => index,
+                None => {
+                    partitions.push(Partition {
+                        key,
+                        unvalued: Vec::new(),
+                        valued: Vec::new(),
+                    });
+                    partitions.len() - 1
+                }
+            };
+            match get_field(&doc, &self.field) {
+                None | Some(Value::Null | Value::Undefined) => partitions[index].unvalued.push(doc),
+                Some(value) => {
+                    self.check_type(value)?;
+                    partitions[index].valued.push((value.clone(), doc));
+                }
+            }
+        }
+        partitions.sort_by(|a, b| compare_keys(&a.key, &b.key));
+        for partition in &mut partitions {
+            partition.valued.sort_by(|(a, _), (b, _)| a.compare(b));
+        }
+
+        let values = partitions
+            .iter()
+            .flat_map(|partition| partition.valued.iter().map(|(value, _)| value));
+        let full_range = values
+            .clone()
+            .min_by(|a, b| a.compare(b))
+            .cloned()
+            .zip(values.max_by(|a, b| a.compare(b)).cloned());
+
+        let mut output = Vec::new();
+        let mut generated = 0;
+        for partition in partitions {
+            let range = match &self.bounds {
+                Bounds::Full => full_range
+                    .clone()
+                    .map(|(lower, upper)| (lower, upper, true)),
+                Bounds::Partition => partition
+                    .valued
+                    .first()
+                    .zip(partition.valued.last())
+                    .map(|((lower, _), (upper, _))| (lower.clone(), upper.clone(), true)),
+                Bounds::Explicit(lower, upper) => Some((lower.clone(), upper.clone(), false)),
+            };
+            // Documents without a value for the field are passed through
+            output.extend(partition.unvalued);
+            let Some((lower, upper, inclusive)) = range else {
+                continue;
+            };
+            let in_range = |value: &Value| match value.compare(&upper) {
+                Ordering::Less => true,
+                Ordering::Equal => inclusive,
+                Ordering::Greater => false,
+            };
+            let mut emit = |value: Value, output: &mut Vec<Document>| -> CommandResult<()> {
+                generated += 1;
+                if generated > MAX_GENERATED {
+                    return Err(location(
+                        5897900,
+                        format!(
+                            "Generated {} documents in $densify, which is over the limit of {}",
+                            generated, MAX_GENERATED
+                        ),
+                    ));
+                }
+                let mut doc = Document::new();
+                for (path, value) in self.partition_by.iter().zip(&partition.key) {
+                    if let Some(value) = value {
+                        set_field(&mut doc, path, Some(value.clone()));
+                    }
+                }
+                set_field(&mut doc, &self.field, Some(value));
+                output.push(doc);
+                Ok(())
+            };
+
+            // Values are computed from the lower bound rather than from the
+            // previous value, so that months stay aligned
+            let mut steps = 0;
+            let mut next = Some(lower.clone());
+            let mut valued = partition.valued.into_iter().peekable();
+            while let Some(candidate) = next.clone().filter(|c| in_range(c)) {
+                let mut existing = false;
+                while let Some((value, _)) = valued.peek() {
+                    match value.compare(&candidate) {
+                        Ordering::Greater => break,
+                        // Existing documents take the place of generated ones
+                        ordering => existing |= ordering == Ordering::Equal,
+                    }
+                    output.push(valued.next().expect("peeked").1);
+                }
+                if !existing {
+                    emit(candidate, &mut output)?;
+                }
+                steps += 1;
+                next = self.offset(&lower, steps);
+            }
+            output.extend(valued.map(|(_, doc)| doc));
+        }
+        Ok(output)
+    }
+
+    fn check_type(&self, value: &Value) -> CommandResult<()> {
+        match (value, self.unit) {
+            (Value::UtcDateTime(_), Some(_)) => Ok(()),
+            (value, None) if value.is_number() => Ok(()),
+            (_, Some(_)) => Err(location(
+                5733200,
+                "Densify field type must be a date when a unit is specified",
+            )),
+            (_, None) => Err(location(
+                5733201,
+                "Densify field type must be numeric when no unit is specified",
+            )),
+        }
+    }
+
+    /// The value `steps` steps after `lower`.
+    fn offset(&self, lower: &Value, steps: i64) -> Option<Value> {
+        match (lower, self.unit) {
+            (Value::UtcDateTime(ms), Some(unit)) => {
+                let amount = self.step.as_i64()?.checked_mul(steps)?;
+                unit.add_utc(*ms, amount).map(Value::UtcDateTime)
+            }
+            _ => {
+                let steps = i32::try_from(steps).map_or(Value::Int64(steps), Value::Int32);
+                let distance = arithmetic(&self.step, &steps, i64::checked_mul, |a, b| a * b);
+                Some(arithmetic(lower, &distance, i64::checked_add, |a, b| a + b))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    fn densify(spec: &str, documents: &[&str]) -> CommandResult<Vec<Document>> {
+        Densify::parse(&value(spec))?.run(documents.iter().map(|d| doc(d)).collect())
+    }
+
+    #[test]
+    fn test_numeric_ranges() {
+        assert_eq!(
+            densify(
+                "{ field: 'v', range: { step: 2, bounds: 'full' } }",
+                &["{ v: 7 }", "{ v: 1 }", "{ v: 4 }", "{ other: 1 }"]
+            )
+            .unwrap(),
+            vec![
+                doc("{ other: 1 }"),
+                doc("{ v: 1 }"),
+                doc("{ v: 3 }"),
+                doc("{ v: 4 }"),
+                doc("{ v: 5 }"),
+                doc("{ v: 7 }"),
+            ]
+        );
+        let documents = ["{ p: 'a', v: 0 }", "{ p: 'b', v: 2 }", "{ p: 'b', v: 3 }"];
+        assert_eq!(
+            densify(
+                "{ field: 'v', partitionByFields: ['p'], range: { step: 1, bounds: 'full' } }",
+                &documents
+            )
+            .unwrap(),
+            vec![
+                doc("{ p: 'a', v: 0 }"),
+                doc("{ p: 'a', v: 1 }"),
+                doc("{ p: 'a', v: 2 }"),
+                doc("{ p: 'a', v: 3 }"),
+                doc("{ p: 'b', v: 0 }"),
+                doc("{ p: 'b', v: 1 }"),
+                doc("{ p: 'b', v: 2 }"),
+                doc("{ p: 'b', v: 3 }"),
+            ]
+        );
+        assert_eq!(
+            densify(
+                "{ field: 'v', partitionByFields: ['p'], range: { step: 0.5, bounds: 'partition' } }",
+                &documents
+            )
+            .unwrap(),
+            vec![
+                doc("{ p: 'a', v: 0 }"),
+                doc("{ p: 'b', v: 2 }"),
+                doc("{ p: 'b', v: 2.5 }"),
+                doc("{ p: 'b', v: 3 }"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_date_ranges() {
+        assert_eq!(
+            densify(
+                "{ field: 't', range: { step: 1, unit: 'hour', bounds: [Date(0), Date(10800000)] } }",
+                &["{ t: Date(3600000), n: 1 }"]
+            )
+            .unwrap(),
+            vec![
+                doc("{ t: Date(0) }"),
+                doc("{ t: Date(3600000), n: 1 }"),
+                doc("{ t: Date(7200000) }"),
+            ]
+        );
+        // Months are added on the calendar, from Jan 31st
+        assert_eq!(
+            densify(
+                "{ field: 't', range: { step: 1, unit: 'month', bounds: 'full' } }",
+                &["{ t: Date(2592000000) }", "{ t: Date(7776000000) }"]
+            )
+            .unwrap()
+            .len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_invalid_densify() {
+        let code = |spec, documents: &[&str]| densify(spec, documents).unwrap_err().code;
+        assert_eq!(
+            code(
+                "{ field: 'v', range: { step: 1, bounds: 'full' } }",
+                &["{ v: 'a' }"]
+            ),
+            ErrorCode::Location(5733201)
+        );
+        assert_eq!(
+            code("{ field: 'v', range: { step: 0, bounds: 'full' } }", &[]),
+            ErrorCode::Location(5733401)
+        );
+        assert_eq!(
+            code(
+                "{ field: 'v', range: { step: 1, bounds: [Date(0), Date(1)] } }",
+                &[]
+            ),
+            ErrorCode::Location(5733403)
+        );
+        assert_eq!(
+            code("{ field: 'v', range: { step: 1 } }", &[]),
+            ErrorCode::Location(40414)
+        );
+    }
+}
@@ -0,0 +1,412 @@
+//! Array operators such as `$arrayElemAt`, `$size`, `$slice` and the set
+//! operators. `$map`, `$filter` and `$reduce` bind variables and are
+//! evaluated alongside the other scoped expressions.
+
+use crate::{bson::Value, error::CommandResult};
+
+use super::location;
+
+/// The memory `$range` may use, like mongod's `internalQueryMaxRangeBytes`,
+/// counting each element as mongod's 16 byte values.
+const MAX_RANGE_BYTES: i64 = 64 * 1024 * 1024;
+
+/// Name, minimum and maximum number of arguments.
+pub const OPERATORS: &[(&str, usize, usize)] = &[
+    ("$arrayElemAt", 2, 2),
+    ("$size", 1, 1),
+    ("$concatArrays", 0, usize::MAX),
+    ("$in", 2, 2),
+    ("$indexOfArray", 2, 4),
+    ("$isArray", 1, 1),
+    ("$reverseArray", 1, 1),
+    ("$slice", 2, 3),
+    ("$range", 2, 3),
+    ("$first", 1, 1),
+    ("$last", 1, 1),
+    ("$setUnion", 0, usize::MAX),
+    ("$setIntersection", 0, usize::MAX),
+    ("$setDifference", 2, 2),
+    ("$setEquals", 2, usize::MAX),
+    ("$setIsSubset", 2, 2),
+    ("$allElementsTrue", 1, 1),
+    ("$anyElementTrue", 1, 1),
+];
+
+pub fn evaluate(name: &str, args: &[Value]) -> CommandResult<Option<Value>> {
+    let value = match name {
+        "$arrayElemAt" => return element_at(&args[0], &args[1]),
+        "$first" | "$last" => {
+            let items = match &args[0] {
+                value if value.is_null_or_undefined() => return Ok(Some(Value::Null)),
+                Value::Array(items) => &items.0,
+                value => {
+                    return Err(location(
+                        28689,
+                        format!(
+                            "{}'s argument must be an array, but is {}",
+                            name,
+                            value.type_name()
+                        ),
+                    ));
+                }
+            };
+            // Missing for an empty array
+            let item = match name {
+                "$first" => items.first(),
+                _ => items.last(),
+            };
+            return Ok(item.cloned());
+        }
+        "$size" => match &args[0] {
+            Value::Array(items) => Value::Int32(items.0.len() as i32),
+            value => {
+                return Err(location(
+                    17124,
+                    format!(
+                        "The argument to $size must be an array. Type of argument: {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        },
+        "$concatArrays" => {
+            let mut result = Vec::new();
+            for value in args {
+                match value {
+                    Value::Array(items) => result.extend(items.0.iter().cloned()),
+                    value if value.is_null_or_undefined() => return Ok(Some(Value::Null)),
+                    value => {
+                        return Err(location(
+                            28664,
+                            format!(
+                                "$concatArrays only supports arrays, not {}",
+                                value.type_name()
+                            ),
+                        ));
+                    }
+                }
+            }
+            Value::from(result)
+        }
+        "$in" => match &args[1] {
+            Value::Array(items) => Value::Boolean(items.0.iter().any(|item| item.equals(&args[0]))),
+            value => {
+                return Err(location(
+                    40081,
+                    format!(
+                        "$in requires an array as a second argument, found: {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        },
+        "$indexOfArray" => index_of_array(args)?,
+        "$isArray" => Value::Boolean(matches!(args[0], Value::Array(_))),
+        "$reverseArray" => match &args[0] {
+            value if value.is_null_or_undefined() => Value::Null,
+            Value::Array(items) => Value::from(items.0.iter().rev().cloned().collect::<Vec<_>>()),
+            value => {
+                return Err(location(
+                    34435,
+                    format!(
+                        "The argument to $reverseArray must be an array, but was of type: {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        },
+        "$slice" => slice(args)?,
+        "$range" => range(args)?,
+        "$allElementsTrue" | "$anyElementTrue" => {
+            let Value::Array(items) = &args[0] else {
+                let code = if name == "$allElementsTrue" {
+                    17040
+                } else {
+                    17041
+                };
+                return Err(location(
+                    code,
+                    format!(
+                        "{}'s argument must be an array, but is {}",
+                        name,
+                        args[0].type_name()
+                    ),
+                ));
+            };
+            Value::Boolean(match name {
+                "$allElementsTrue" => items.0.iter().all(Value::is_truthy),
+                _ => items.0.iter().any(Value::is_truthy),
+            })
+        }
+        _ => set_operation(name, args)?,
+    };
+    Ok(Some(value))
+}
+
+/// An argument that must be representable as an int.
+fn int_arg(value: &Value) -> Option<i32> {
+    value
+        .as_i64()
+        .filter(|_| value.is_number())
+        .and_then(|v| i32::try_from(v).ok())
+}
+
+fn element_at(array: &Value, index: &Value) -> CommandResult<Option<Value>> {
+    if array.is_null_or_undefined() || index.is_null_or_undefined() {
+        return Ok(Some(Value::Null));
+    }
+    let Value::Array(items) = array else {
+        return Err(location(
+            28689,
+            format!(
+                "$arrayElemAt's first argument must be an array, but is {}",
+                array.type_name()
+            ),
+        ));
+    };
+    if !index.is_number() {
+        return Err(location(
+            28690,
+            format!(
+                "$arrayElemAt's second argument must be a numeric value, but is {}",
+                index.type_name()
+            ),
+        ));
+    }
+    let Some(index) = int_arg(index) else {
+        return Err(location(
+            28691,
+            format!(
+                "$arrayElemAt's second argument must be representable as a 32-bit integer: {}",
+                index
+            ),
+        ));
+    };
+    // Negative indexes count from the end; out of bounds is missing
+    let index = match index {
+        i if i < 0 => items.0.len().checked_sub(i.unsigned_abs() as usize),
+        i => Some(i as usize),
+    };
+    Ok(index.and_then(|i| items.0.get(i)).cloned())
+}
+
+fn index_of_array(args: &[Value]) -> CommandResult<Value> {
+    let items = match &args[0] {
+        value if value.is_null_or_undefined() => return Ok(Value::Null),
+        Value::Array(items) => &items.0,
+        value => {
+            return Err(location(
+                40090,
+                format!(
+                    "$indexOfArray requires an array as a first argument, found: {}",
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    let mut bounds = [0, items.len()];
+    for (bound, value) in bounds.iter_mut().zip(&args[2..]) {
+        let Some(index) = int_arg(value) else {
+            return Err(location(
+                40096,
+                format!(
+                    "$indexOfArray requires an integral starting index, found a value of type: {}, with value: {}",
+                    value.type_name(),
+                    value
+                ),
+            ));
+        };
+        if index < 0 {
+            return Err(location(
+                40097,
+                format!(
+                    "$indexOfArray requires a nonnegative starting index, found: {}",
+                    index
+                ),
+            ));
+        }
+        *bound = (index as usize).min(items.len());
+    }
+    let [start, end] = bounds;
+    let found = (start..end).find(|&i| items[i].equals(&args[1]));
+    Ok(Value::Int32(found.map_or(-1, |i| i as i32)))
+}
+
+/// `$slice: [array, n]` takes the first `n` elements (the last when
+/// negative); `$slice: [array, position, n]` takes `n` from a position,
+/// which counts from the end when negative.
+fn slice(args: &[Value]) -> CommandResult<Value> {
+    if args.iter().any(Value::is_null_or_undefined) {
+        return Ok(Value::Null);
+    }
+    let Value::Array(items) = &args[0] else {
+        return Err(location(
+            28724,
+            format!(
+                "First argument to $slice must be an array, but is of type: {}",
+                args[0].type_name()
+            ),
+        ));
+    };
+    let items = &items.0;
+    let mut numbers = Vec::new();
+    for (value, ordinal, code) in [
+        (args.get(1), "Second", 28725),
+        (args.get(2), "Third", 28727),
+    ] {
+        let Some(value) = value else { continue };
+        match int_arg(value) {
+            Some(n) => numbers.push(n as i64),
+            None => {
+                return Err(location(
+                    code,
+                    format!(
+                        "{} argument to $slice must be numeric, but is of type: {}",
+                        ordinal,
+                        value.type_name()
+                    ),
+                ));
+            }
+        }
+    }
+    let len = items.len() as i64;
+    let (start, count) = match numbers[..] {
+        [n] if n >= 0 => (0, n),
+        [n] => ((len + n).max(0), -n),
+        [position, n] => {
+            if n <= 0 {
+                return Err(location(
+                    28729,
+                    format!("Third argument to $slice must be positive: {}", n),
+                ));
+            }
+            let start = match position {
+                p if p < 0 => (len + p).max(0),
+                p => p.min(len),
+            };
+            (start, n)
+        }
+        _ => unreachable!("$slice takes two or three arguments"),
+    };
+    let end = (start + count).min(len);
+    Ok(Value::from(items[start as usize..end as usize].to_vec()))
+}
+
+fn range(args: &[Value]) -> CommandResult<Value> {
+    let mut numbers = [0, 0, 1];
+    for (i, (value, what, code)) in [
+        (args.first(), "starting value", 34443),
+        (args.get(1), "ending value", 34444),
+        (args.get(2), "step value", 34447),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let Some(value) = value else { continue };
+        match int_arg(value) {
+            Some(n) => numbers[i] = n as i64,
+            None => {
+                return Err(location(
+                    code,
+                    format!(
+                        "$range requires a {} that can be represented as a 32-bit integer, found value: {}",
+                        what, value
+                    ),
+                ));
+            }
+        }
+    }
+    let [start, end, step] = numbers;
+    if step == 0 {
+        return Err(location(34449, "$range requires a non-zero step value"));
+    }
+    let len = ((end - start + step - step.signum()) / step).max(0);
+    if len * 16 >= MAX_RANGE_BYTES {
+        return Err(location(
+            5138000,
+            format!(
+                "$range would use too much memory ({} bytes) and cannot spill to disk. Memory limit: {} bytes",
+                len * 16,
+                MAX_RANGE_BYTES
+            ),
+        ));
+    }
+    let mut result = Vec::with_capacity(len as usize);
+    let mut current = start;
+    while (step > 0 && current < end) || (step < 0 && current > end) {
+        result.push(Value::Int32(current as i32));
+        current += step;
+    }
+    Ok(Value::from(result))
+}
+
+/// The distinct elements of `items`, in order of first appearance.
+fn distinct(items: &[Value]) -> Vec<Value> {
+    let mut result: Vec<Value> = Vec::new();
+    for item in items {
+        if !result.iter().any(|existing| existing.equals(item)) {
+            result.push(item.clone());
+        }
+    }
+    result
+}
+
+fn set_operation(name: &str, args: &[Value]) -> CommandResult<Value> {
+    let mut sets = Vec::new();
+    for value in args {
+        match value {
+            Value::Array(items) => sets.push(distinct(&items.0)),
+            // Only the set-producing operators pass null through
+            value
+                if value.is_null_or_undefined()
+                    && matches!(name, "$setUnion" | "$setIntersection" | "$setDifference") =>
+            {
+                return Ok(Value::Null);
+            }
+            value => {
+                let code = match name {
+                    "$setUnion" => 17043,
+                    "$setIntersection" => 17047,
+                    "$setDifference" => 17048,
+                    "$setEquals" => 17044,
+                    _ => 17046,
+                };
+                return Err(location(
+                    code,
+                    format!(
+                        "All operands of {} must be arrays. One argument is of type: {}",
+                        name,
+                        value.type_name()
+                    ),
+                ));
+            }
+        }
+    }
+    let contains = |set: &[Value], item: &Value| set.iter().any(|other| other.equals(item));
+    Ok(match name {
+        "$setUnion" => Value::from(distinct(&sets.concat())),
+        "$setIntersection" => {
+            let mut sets = sets.into_iter();
+            let first = sets.next().unwrap_or_default();
+            let rest: Vec<_> = sets.collect();
+            Value::from(
+                first
+                    .into_iter()
+                    .filter(|item| rest.iter().all(|set| contains(set, item)))
+                    .collect::<Vec<_>>(),
+            )
+        }
+        "$setDifference" => Value::from(
+            sets[0]
+                .iter()
+                .filter(|item| !contains(&sets[1], item))
+                .cloned()
+                .collect::<Vec<_>>(),
+        ),
+        "$setEquals" => Value::Boolean(sets.windows(2).all(|pair| {
+            pair[0].len() == pair[1].len() && pair[0].iter().all(|item| contains(&pair[1], item))
+        })),
+        "$setIsSubset" => Value::Boolean(sets[0].iter().all(|item| contains(&sets[1], item))),
+        name => unreachable!("operator {} is not an array operator", name),
+    })
+}
@@ -0,0 +1,315 @@
+//! Type operators: `$type`, `$isNumber`, `$convert` and its shorthands
+//! (`$toInt`, `$toString`, ...).
+
+use crate::{
+    bson::Value,
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::date::{format_date, parse_date};
+
+/// Name, minimum and maximum number of arguments.
+pub const OPERATORS: &[(&str, usize, usize)] = &[
+    ("$isNumber", 1, 1),
+    ("$toBool", 1, 1),
+    ("$toDate", 1, 1),
+    ("$toDouble", 1, 1),
+    ("$toInt", 1, 1),
+    ("$toLong", 1, 1),
+    ("$toObjectId", 1, 1),
+    ("$toString", 1, 1),
+];
+
+pub fn evaluate(name: &str, args: &[Value]) -> CommandResult<Option<Value>> {
+    let target = match name {
+        "$isNumber" => return Ok(Some(Value::Boolean(args[0].is_number()))),
+        "$toBool" => Target::Bool,
+        "$toDate" => Target::Date,
+        "$toDouble" => Target::Double,
+        "$toInt" => Target::Int,
+        "$toLong" => Target::Long,
+        "$toObjectId" => Target::ObjectId,
+        "$toString" => Target::String,
+        name => unreachable!("operator {} is not a type operator", name),
+    };
+    if args[0].is_null_or_undefined() {
+        return Ok(Some(Value::Null));
+    }
+    convert(&args[0], target)
+        .map(Some)
+        .map_err(|message| conversion_failure(&message))
+}
+
+/// `$convert: { input, to, onError, onNull }`, with the arguments in that
+/// order.
+pub fn evaluate_named(args: &[Option<Value>]) -> CommandResult<Option<Value>> {
+    let [input, to, on_error, on_null] = args else {
+        unreachable!("$convert has four parameters");
+    };
+    let target = match to {
+        None | Some(Value::Null | Value::Undefined) => return Ok(Some(Value::Null)),
+        Some(to) => Target::parse(to)?,
+    };
+    let input = match input {
+        None | Some(Value::Null | Value::Undefined) => {
+            return Ok(Some(on_null.clone().unwrap_or(Value::Null)));
+        }
+        Some(input) => input,
+    };
+    match convert(input, target) {
+        Ok(value) => Ok(Some(value)),
+        Err(_) if on_error.is_some() => Ok(on_error.clone()),
+        Err(message) => Err(conversion_failure(&message)),
+    }
+}
+
+fn conversion_failure(message: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::ConversionFailure,
+        format!("{} in $convert with no onError value", message),
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Double,
+    String,
+    ObjectId,
+    Bool,
+    Date,
+    Int,
+    Long,
+    Decimal,
+}
+
+impl Target {
+    /// Reads `to`, given either as a type alias or a BSON type number.
+    fn parse(value: &Value) -> CommandResult<Self> {
+        let target = match value {
+            Value::String(name) => match name.as_str() {
+                "double" => Target::Double,
+                "string" => Target::String,
+                "objectId" => Target::ObjectId,
+                "bool" => Target::Bool,
+                "date" => Target::Date,
+                "int" => Target::Int,
+                "long" => Target::Long,
+                "decimal" => Target::Decimal,
+                name => {
+                    return Err(CommandError::new(
+                        ErrorCode::BadValue,
+                        format!("Unknown type name: {}", name),
+                    ));
+                }
+            },
+            value if value.is_number() => match value.as_i64() {
+                Some(1) => Target::Double,
+                Some(2) => Target::String,
+                Some(7) => Target::ObjectId,
+                Some(8) => Target::Bool,
+                Some(9) => Target::Date,
+                Some(16) => Target::Int,
+                Some(18) => Target::Long,
+                Some(19) => Target::Decimal,
+                _ => {
+                    return Err(CommandError::new(
+                        ErrorCode::FailedToParse,
+                        format!(
+                            "In $convert, numeric value for 'to' does not correspond to a BSON type: {}",
+                            value
+                        ),
+                    ));
+                }
+            },
+            value => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    format!(
+                        "$convert's 'to' argument must be a string or number, but is {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        Ok(target)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Target::Double => "double",
+            Target::String => "string",
+            Target::ObjectId => "objectId",
+            Target::Bool => "bool",
+            Target::Date => "date",
+            Target::Int => "int",
+            Target::Long => "long",
+            Target::Decimal => "decimal",
+        }
+    }
+}
+
+/// Converts a non-null value, returning the reason on failure.
+fn convert(input: &Value, target: Target) -> Result<Value, String> {
+    let unsupported = || {
+        format!(
+            "Unsupported conversion from {} to {}",
+            input.type_name(),
+            target.name()
+        )
+    };
+    let value = match (target, input) {
+        (Target::Double, Value::Double(_)) => input.clone(),
+        (Target::Double, Value::Int32(_) | Value::Int64(_)) => {
+            Value::Double(input.as_f64().unwrap_or(0.0))
+        }
+        (Target::Double, Value::Boolean(b)) => Value::Double(*b as i32 as f64),
+        (Target::Double, Value::UtcDateTime(ms)) => Value::Double(*ms as f64),
+        (Target::Double, Value::String(s)) => Value::Double(parse_double(s)?),
+
+        (Target::Int | Target::Long, Value::Boolean(b)) => integer(target, *b as i64)?,
+        (Target::Int | Target::Long, Value::Int32(_) | Value::Int64(_)) => {
+            integer(target, input.as_i64().unwrap_or(0))?
+        }
+        (Target::Int | Target::Long, Value::Double(v)) => {
+            if v.is_nan() {
+                return Err("Attempt to convert NaN value to integer type".to_string());
+            }
+            let truncated = v.trunc();
+            if !(i64::MIN as f64..i64::MAX as f64).contains(&truncated) {
+                return Err(format!("Conversion would overflow target type: {}", input));
+            }
+            integer(target, truncated as i64)?
+        }
+        (Target::Long, Value::UtcDateTime(ms)) => Value::Int64(*ms),
+        (Target::Int | Target::Long, Value::String(s)) => {
+            let Ok(v) = s.parse::<i64>() else {
+                return Err(format!("Failed to parse number '{}'", s));
+            };
+            integer(target, v)?
+        }
+
+        (Target::Bool, Value::Boolean(_)) => input.clone(),
+        (Target::Bool, value) if value.is_number() => Value::Boolean(value.is_truthy()),
+        (Target::Bool, _) => Value::Boolean(true),
+
+        (Target::String, value) => match value {
+            Value::Document(_)
+            | Value::Array(_)
+            | Value::Binary(_)
+            | Value::Regex(_, _)
+            | Value::MinKey
+            | Value::MaxKey => return Err(unsupported()),
+            Value::ObjectId(bytes) => Value::String(hex(bytes)),
+            Value::Boolean(b) => Value::String(b.to_string()),
+            value => Value::String(coerce_to_string(value).ok_or_else(unsupported)?),
+        },
+
+        (Target::ObjectId, Value::ObjectId(_)) => input.clone(),
+        (Target::ObjectId, Value::String(s)) => {
+            if s.len() != 24 {
+                return Err(format!(
+                    "Failed to parse objectId '{}': Invalid string length for parsing to OID, expected 24 but found {}",
+                    s,
+                    s.len()
+                ));
+            }
+            let bytes = (0..24)
+                .step_by(2)
+                .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(|| {
+                    format!(
+                        "Failed to parse objectId '{}': Invalid character found in hex string",
+                        s
+                    )
+                })?;
+            Value::ObjectId(bytes)
+        }
+
+        (Target::Date, Value::UtcDateTime(_)) => input.clone(),
+        (Target::Date, Value::Int64(ms)) => Value::UtcDateTime(*ms),
+        (Target::Date, Value::Double(v)) => {
+            if !v.is_finite() {
+                return Err(format!("Conversion would overflow target type: {}", input));
+            }
+            Value::UtcDateTime(v.trunc() as i64)
+        }
+        (Target::Date, Value::ObjectId(bytes)) => {
+            let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            Value::UtcDateTime(seconds as i64 * 1000)
+        }
+        (Target::Date, Value::Timestamp(ts)) => Value::UtcDateTime((ts >> 32) as i64 * 1000),
+        (Target::Date, Value::String(s)) => match parse_date(s) {
+            Some(ms) => Value::UtcDateTime(ms),
+            None => return Err(format!("Error parsing date string '{}'", s)),
+        },
+
+        _ => return Err(unsupported()),
+    };
+    Ok(value)
+}
+
+fn integer(target: Target, value: i64) -> Result<Value, String> {
+    match target {
+        Target::Int => i32::try_from(value)
+            .map(Value::Int32)
+            .map_err(|_| format!("Conversion would overflow target type: {}", value)),
+        _ => Ok(Value::Int64(value)),
+    }
+}
+
+fn parse_double(s: &str) -> Result<f64, String> {
+    match s {
+        "Infinity" | "inf" => Ok(f64::INFINITY),
+        "-Infinity" | "-inf" => Ok(f64::NEG_INFINITY),
+        "NaN" | "nan" => Ok(f64::NAN),
+        s => s
+            .parse()
+            .ok()
+            .filter(|v: &f64| v.is_finite())
+            .ok_or_else(|| format!("Failed to parse number '{}'", s)),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Formats a double the way mongod does in `$toString` and `$concat`-like
+/// contexts: integral values without a fraction, very large or small
+/// values in exponent notation.
+pub fn format_double(v: f64) -> String {
+    if v.is_nan() {
+        return "NaN".to_string();
+    }
+    if v.is_infinite() {
+        return if v > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+    let magnitude = v.abs();
+    if magnitude != 0.0 && !(1e-4..1e15).contains(&magnitude) {
+        let formatted = format!("{:e}", v);
+        let (mantissa, exponent) = formatted.split_once('e').unwrap_or((&formatted, "0"));
+        let (sign, digits) = match exponent.strip_prefix('-') {
+            Some(digits) => ('-', digits),
+            None => ('+', exponent),
+        };
+        return format!("{}e{}{:0>2}", mantissa, sign, digits);
+    }
+    format!("{}", v)
+}
+
+/// The string form of a value where mongod implicitly converts to string,
+/// as in `$toUpper`: null is the empty string, and documents, arrays and
+/// the like can't be converted.
+pub fn coerce_to_string(value: &Value) -> Option<String> {
+    Some(match value {
+        Value::String(s) | Value::Symbol(s) => s.clone(),
+        Value::Int32(v) => v.to_string(),
+        Value::Int64(v) => v.to_string(),
+        Value::Double(v) => format_double(*v),
+        Value::UtcDateTime(ms) => format_date(*ms)?,
+        Value::Timestamp(ts) => format!("Timestamp({}, {})", ts >> 32, ts & 0xffff_ffff),
+        Value::Null | Value::Undefined => String::new(),
+        _ => return None,
+    })
+}
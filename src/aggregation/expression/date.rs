@@ -0,0 +1,1078 @@
+//! Date operators: the date parts (`$year` through `$millisecond`, the ISO
+//! week parts), `$dateToString`, `$dateFromString`, `$dateToParts`,
+//! `$dateFromParts`, `$dateAdd`, `$dateSubtract`, `$dateDiff` and
+//! `$dateTrunc`. Time zones are either Olson identifiers, resolved with the
+//! bundled tz database, or UTC offsets such as `+05:30`.
+
+use std::{ops::RangeInclusive, str::FromStr};
+
+use chrono::{
+    DateTime, Datelike, FixedOffset, LocalResult, Months, NaiveDate, NaiveDateTime, Offset,
+    TimeDelta, TimeZone, Timelike, Weekday,
+};
+use chrono_tz::Tz;
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::location;
+
+/// The operators returning one part of a date. Each takes a date, or
+/// `{ date, timezone }`.
+pub const PARTS: &[&str] = &[
+    "$year",
+    "$month",
+    "$dayOfMonth",
+    "$hour",
+    "$minute",
+    "$second",
+    "$millisecond",
+    "$dayOfWeek",
+    "$dayOfYear",
+    "$week",
+    "$isoWeek",
+    "$isoWeekYear",
+    "$isoDayOfWeek",
+];
+
+/// Name, parameters, and how many of the leading parameters are required.
+pub const OPERATORS: &[(&str, &[&str], usize)] = &[
+    ("$year", &["date", "timezone"], 1),
+    ("$month", &["date", "timezone"], 1),
+    ("$dayOfMonth", &["date", "timezone"], 1),
+    ("$hour", &["date", "timezone"], 1),
+    ("$minute", &["date", "timezone"], 1),
+    ("$second", &["date", "timezone"], 1),
+    ("$millisecond", &["date", "timezone"], 1),
+    ("$dayOfWeek", &["date", "timezone"], 1),
+    ("$dayOfYear", &["date", "timezone"], 1),
+    ("$week", &["date", "timezone"], 1),
+    ("$isoWeek", &["date", "timezone"], 1),
+    ("$isoWeekYear", &["date", "timezone"], 1),
+    ("$isoDayOfWeek", &["date", "timezone"], 1),
+    (
+        "$dateToString",
+        &["date", "format", "timezone", "onNull"],
+        1,
+    ),
+    (
+        "$dateFromString",
+        &["dateString", "format", "timezone", "onError", "onNull"],
+        1,
+    ),
+    ("$dateToParts", &["date", "timezone", "iso8601"], 1),
+    (
+        "$dateFromParts",
+        &[
+            "year",
+            "month",
+            "day",
+            "hour",
+            "minute",
+            "second",
+            "millisecond",
+            "isoWeekYear",
+            "isoWeek",
+            "isoDayOfWeek",
+            "timezone",
+        ],
+        0,
+    ),
+    ("$dateAdd", &["startDate", "unit", "amount", "timezone"], 3),
+    (
+        "$dateSubtract",
+        &["startDate", "unit", "amount", "timezone"],
+        3,
+    ),
+    (
+        "$dateDiff",
+        &["startDate", "endDate", "unit", "timezone", "startOfWeek"],
+        3,
+    ),
+    (
+        "$dateTrunc",
+        &["date", "unit", "binSize", "timezone", "startOfWeek"],
+        2,
+    ),
+];
+
+const DEFAULT_FORMAT: &str = "%Y-%m-%dT%H:%M:%S.%LZ";
+
+/// Evaluates a date operator, with its arguments in the order of its
+/// parameters.
+pub fn evaluate_named(name: &str, args: &[Option<Value>]) -> CommandResult<Option<Value>> {
+    // Null arguments make the result null, except where onNull says otherwise
+    let null_result = match name {
+        "$dateToString" => args[3].clone().unwrap_or(Value::Null),
+        _ => Value::Null,
+    };
+    if name == "$dateFromString" {
+        return from_string(args);
+    }
+    let Some(zone) = Zone::parse(zone_arg(name, args))? else {
+        return Ok(Some(null_result));
+    };
+    if name == "$dateFromParts" {
+        return from_parts(args, zone);
+    }
+    let Some(ms) = date_arg(name, args[0].as_ref())? else {
+        return Ok(Some(null_result));
+    };
+
+    let value = match name {
+        "$dateToString" => {
+            let format = match &args[1] {
+                None => DEFAULT_FORMAT,
+                Some(Value::String(format)) => format,
+                Some(Value::Null | Value::Undefined) => return Ok(Some(Value::Null)),
+                Some(value) => {
+                    return Err(location(
+                        18533,
+                        format!(
+                            "$dateToString requires that 'format' be a string, found: {} with value {}",
+                            value.type_name(),
+                            value
+                        ),
+                    ));
+                }
+            };
+            Value::String(format_local(zone.local(ms)?, zone.offset(ms)?, format)?)
+        }
+        "$dateToParts" => to_parts(zone.local(ms)?, &args[2])?,
+        "$dateAdd" | "$dateSubtract" => {
+            let [_, Some(unit), Some(amount), _] = args else {
+                unreachable!("unit and amount are required");
+            };
+            if unit.is_null_or_undefined() || amount.is_null_or_undefined() {
+                return Ok(Some(Value::Null));
+            }
+            let unit = Unit::parse(name, unit)?;
+            let amount = match amount.as_i64() {
+                Some(count) if amount.is_number() => count,
+                _ => {
+                    return Err(location(
+                        5166405,
+                        format!(
+                            "{} expects integer amount of time units, found: {}",
+                            name, amount
+                        ),
+                    ));
+                }
+            };
+            let amount = if name == "$dateSubtract" {
+                amount.checked_neg()
+            } else {
+                Some(amount)
+            };
+            let added = amount.and_then(|amount| add(ms, unit, amount, zone));
+            Value::UtcDateTime(
+                added.ok_or_else(|| location(5166406, format!("{} overflowed", name)))?,
+            )
+        }
+        "$dateDiff" => {
+            let [_, Some(end), Some(unit), _, start_of_week] = args else {
+                unreachable!("endDate and unit are required");
+            };
+            let Some(end) = date_arg(name, Some(end))? else {
+                return Ok(Some(Value::Null));
+            };
+            if unit.is_null_or_undefined() {
+                return Ok(Some(Value::Null));
+            }
+            let unit = Unit::parse(name, unit)?;
+            let Some(start_of_week) = parse_start_of_week(start_of_week)? else {
+                return Ok(Some(Value::Null));
+            };
+            Value::Int64(diff(ms, end, unit, zone, start_of_week)?)
+        }
+        "$dateTrunc" => {
+            let [_, Some(unit), bin_size, _, start_of_week] = args else {
+                unreachable!("unit is required");
+            };
+            if unit.is_null_or_undefined() {
+                return Ok(Some(Value::Null));
+            }
+            let unit = Unit::parse(name, unit)?;
+            let bin_size = match bin_size {
+                None => 1,
+                Some(value) if value.is_null_or_undefined() => return Ok(Some(Value::Null)),
+                Some(value) => match value.as_i64() {
+                    Some(size) if value.is_number() && size > 0 => size,
+                    _ => {
+                        return Err(location(
+                            5439017,
+                            format!(
+                                "$dateTrunc requires 'binSize' to be a 64-bit integer greater than 0, but got value {}",
+                                value
+                            ),
+                        ));
+                    }
+                },
+            };
+            let Some(start_of_week) = parse_start_of_week(start_of_week)? else {
+                return Ok(Some(Value::Null));
+            };
+            Value::UtcDateTime(truncate(ms, unit, bin_size, zone, start_of_week)?)
+        }
+        part => Value::Int32(date_part(part, zone.local(ms)?)),
+    };
+    Ok(Some(value))
+}
+
+fn zone_arg<'a>(name: &str, args: &'a [Option<Value>]) -> &'a Option<Value> {
+    let index = match name {
+        "$dateToString" | "$dateFromString" => 2,
+        "$dateFromParts" => 10,
+        "$dateAdd" | "$dateSubtract" | "$dateDiff" | "$dateTrunc" => 3,
+        _ => 1,
+    };
+    &args[index]
+}
+
+/// Milliseconds since the epoch of a date argument, which may also be a
+/// timestamp or an ObjectId. `None` for null.
+fn date_arg(name: &str, value: Option<&Value>) -> CommandResult<Option<i64>> {
+    match value {
+        None | Some(Value::Null | Value::Undefined) => Ok(None),
+        Some(Value::UtcDateTime(ms)) => Ok(Some(*ms)),
+        Some(Value::Timestamp(ts)) => Ok(Some((ts >> 32) as i64 * 1000)),
+        Some(Value::ObjectId(bytes)) => {
+            let seconds = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            Ok(Some(seconds as i64 * 1000))
+        }
+        Some(value) => Err(location(
+            16006,
+            format!(
+                "can't convert from BSON type {} to Date in {}",
+                value.type_name(),
+                name
+            ),
+        )),
+    }
+}
+
+fn out_of_range() -> CommandError {
+    location(
+        18537,
+        "Could not convert date to string: date component was outside the supported range of 0-9999",
+    )
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Zone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl Zone {
+    /// A missing timezone is UTC; a null one is `None`.
+    fn parse(value: &Option<Value>) -> CommandResult<Option<Zone>> {
+        match value {
+            None => Ok(Some(Zone::Fixed(
+                FixedOffset::east_opt(0).expect("valid offset"),
+            ))),
+            Some(Value::Null | Value::Undefined) => Ok(None),
+            Some(Value::String(name)) => parse_zone(name).map(Some).ok_or_else(|| {
+                location(
+                    40485,
+                    format!("unrecognized time zone identifier: \"{}\"", name),
+                )
+            }),
+            Some(value) => Err(location(
+                40517,
+                format!(
+                    "timezone must evaluate to a string, found {}",
+                    value.type_name()
+                ),
+            )),
+        }
+    }
+
+    /// Seconds east of UTC at an instant.
+    fn offset(self, ms: i64) -> CommandResult<i32> {
+        let utc = DateTime::from_timestamp_millis(ms).ok_or_else(out_of_range)?;
+        Ok(match self {
+            Zone::Fixed(offset) => offset.local_minus_utc(),
+            Zone::Named(tz) => tz
+                .offset_from_utc_datetime(&utc.naive_utc())
+                .fix()
+                .local_minus_utc(),
+        })
+    }
+
+    /// The wall clock time at an instant.
+    fn local(self, ms: i64) -> CommandResult<NaiveDateTime> {
+        let utc = DateTime::from_timestamp_millis(ms).ok_or_else(out_of_range)?;
+        Ok(utc.naive_utc() + TimeDelta::seconds(self.offset(ms)? as i64))
+    }
+
+    /// The instant of a wall clock time. Times skipped by a daylight saving
+    /// transition are read with the offset before it, and repeated times
+    /// resolve to their first occurrence.
+    fn utc(self, local: NaiveDateTime) -> i64 {
+        let offset = match self {
+            Zone::Fixed(offset) => offset.local_minus_utc(),
+            Zone::Named(tz) => match tz.offset_from_local_datetime(&local) {
+                LocalResult::Single(offset) | LocalResult::Ambiguous(offset, _) => {
+                    offset.fix().local_minus_utc()
+                }
+                LocalResult::None => tz.offset_from_utc_datetime(&local).fix().local_minus_utc(),
+            },
+        };
+        (local - TimeDelta::seconds(offset as i64))
+            .and_utc()
+            .timestamp_millis()
+    }
+}
+
+/// An Olson identifier such as `America/New_York`, or an offset: `+hh`,
+/// `+hhmm` or `+hh:mm`.
+fn parse_zone(name: &str) -> Option<Zone> {
+    if let Some(offset) = parse_offset(name) {
+        return Some(Zone::Fixed(FixedOffset::east_opt(offset)?));
+    }
+    Tz::from_str(name).ok().map(Zone::Named)
+}
+
+/// Seconds east of UTC of an offset such as `-05:00`.
+fn parse_offset(s: &str) -> Option<i32> {
+    let sign = match s.as_bytes().first()? {
+        b'+' => 1,
+        b'-' => -1,
+        _ => return None,
+    };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.bytes().all(|b| b.is_ascii_digit()) || !matches!(digits.len(), 2 | 4) {
+        return None;
+    }
+    let hours: i32 = digits[..2].parse().ok()?;
+    let minutes: i32 = digits
+        .get(2..)
+        .filter(|m| !m.is_empty())
+        .map_or(Some(0), |m| m.parse().ok())?;
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Week of the year, with weeks starting on Sunday and days before the
+/// first Sunday in week 0, as in `strftime`'s `%U`.
+fn sunday_week(local: NaiveDateTime) -> u32 {
+    (local.ordinal0() + 7 - local.weekday().num_days_from_sunday()) / 7
+}
+
+fn date_part(name: &str, local: NaiveDateTime) -> i32 {
+    let part = match name {
+        "$year" => return local.year(),
+        "$month" => local.month(),
+        "$dayOfMonth" => local.day(),
+        "$hour" => local.hour(),
+        "$minute" => local.minute(),
+        "$second" => local.second(),
+        "$millisecond" => local.nanosecond() / 1_000_000,
+        "$dayOfWeek" => local.weekday().number_from_sunday(),
+        "$dayOfYear" => local.ordinal(),
+        "$week" => sunday_week(local),
+        "$isoWeek" => local.iso_week().week(),
+        "$isoWeekYear" => return local.iso_week().year(),
+        "$isoDayOfWeek" => local.weekday().number_from_monday(),
+        name => unreachable!("operator {} is not a date part", name),
+    };
+    part as i32
+}
+
+/// Formats a wall clock time with mongod's format specifiers.
+fn format_local(local: NaiveDateTime, offset: i32, format: &str) -> CommandResult<String> {
+    if !(0..=9999).contains(&local.year()) {
+        return Err(out_of_range());
+    }
+    let mut result = String::new();
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        let Some(specifier) = chars.next() else {
+            return Err(location(18535, "Unmatched '%' at end of format string"));
+        };
+        let part = match specifier {
+            'd' => format!("{:02}", local.day()),
+            'G' => format!("{:04}", local.iso_week().year()),
+            'H' => format!("{:02}", local.hour()),
+            'j' => format!("{:03}", local.ordinal()),
+            'L' => format!("{:03}", local.nanosecond() / 1_000_000),
+            'm' => format!("{:02}", local.month()),
+            'M' => format!("{:02}", local.minute()),
+            'S' => format!("{:02}", local.second()),
+            'w' => local.weekday().number_from_sunday().to_string(),
+            'u' => local.weekday().number_from_monday().to_string(),
+            'U' => format!("{:02}", sunday_week(local)),
+            'V' => format!("{:02}", local.iso_week().week()),
+            'Y' => format!("{:04}", local.year()),
+            'z' => {
+                let sign = if offset < 0 { '-' } else { '+' };
+                let minutes = offset.abs() / 60;
+                format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+            }
+            'Z' => format!(
+                "{}{}",
+                if offset < 0 { '-' } else { '+' },
+                offset.abs() / 60
+            ),
+            '%' => "%".to_string(),
+            c => {
+                return Err(location(
+                    18536,
+                    format!("Invalid format character '%{}' in format string", c),
+                ));
+            }
+        };
+        result.push_str(&part);
+    }
+    Ok(result)
+}
+
+/// Formats a date as `2020-01-02T03:04:05.678Z`, or `None` when its year is
+/// out of range.
+pub fn format_date(ms: i64) -> Option<String> {
+    let local = DateTime::from_timestamp_millis(ms)?.naive_utc();
+    format_local(local, 0, DEFAULT_FORMAT).ok()
+}
+
+/// Parses an ISO 8601 date such as `2020-01-02T03:04:05.678Z`, as `$toDate`
+/// does: without an offset, the time is UTC.
+pub fn parse_date(s: &str) -> Option<i64> {
+    let (local, offset) = parse_iso(s)?;
+    Some(
+        (local - TimeDelta::seconds(offset.unwrap_or(0) as i64))
+            .and_utc()
+            .timestamp_millis(),
+    )
+}
+
+/// Takes `digits` leading ASCII digits (at least `min`) off `s`.
+fn take_number(s: &str, min: usize, digits: usize) -> Option<(i64, &str)> {
+    let count = s
+        .bytes()
+        .take(digits)
+        .take_while(u8::is_ascii_digit)
+        .count();
+    if count < min {
+        return None;
+    }
+    Some((s[..count].parse().ok()?, &s[count..]))
+}
+
+/// Parses `2020-01-02`, `2020-01-02T03:04`, `2020-01-02 03:04:05.678` and
+/// so on, optionally followed by `Z` or an offset, returning the wall clock
+/// time and the offset in seconds when one was given.
+fn parse_iso(s: &str) -> Option<(NaiveDateTime, Option<i32>)> {
+    let (year, rest) = take_number(s, 4, 4)?;
+    let (month, rest) = take_number(rest.strip_prefix('-')?, 2, 2)?;
+    let (day, mut rest) = take_number(rest.strip_prefix('-')?, 2, 2)?;
+    let date = NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)?;
+
+    let (mut hour, mut minute, mut second, mut millis) = (0, 0, 0, 0);
+    if let Some(time) = rest.strip_prefix('T').or_else(|| rest.strip_prefix(' ')) {
+        let time = take_number(time, 2, 2)?;
+        hour = time.0;
+        let time = take_number(time.1.strip_prefix(':')?, 2, 2)?;
+        minute = time.0;
+        rest = time.1;
+        if let Some(seconds) = rest.strip_prefix(':') {
+            let time = take_number(seconds, 2, 2)?;
+            second = time.0;
+            rest = time.1;
+            if let Some(fraction) = rest.strip_prefix('.') {
+                let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+                if digits == 0 {
+                    return None;
+                }
+                let padded = format!("{:0<3}", &fraction[..digits.min(3)]);
+                millis = padded.parse().ok()?;
+                rest = &fraction[digits..];
+            }
+        }
+    }
+    let local = date.and_hms_milli_opt(hour as u32, minute as u32, second as u32, millis as u32)?;
+    let offset = match rest {
+        "" => None,
+        "Z" => Some(0),
+        offset => Some(parse_offset(offset)?),
+    };
+    Some((local, offset))
+}
+
+/// Parses a date string against a format made of mongod's specifiers.
+fn parse_with_format(s: &str, format: &str) -> CommandResult<Option<(NaiveDateTime, Option<i32>)>> {
+    let mut fields = [None::<i64>; 10];
+    const YEAR: usize = 0;
+    const MONTH: usize = 1;
+    const DAY: usize = 2;
+    const HOUR: usize = 3;
+    const MINUTE: usize = 4;
+    const SECOND: usize = 5;
+    const MILLIS: usize = 6;
+    const ISO_YEAR: usize = 7;
+    const ISO_WEEK: usize = 8;
+    const ISO_DAY: usize = 9;
+    let mut day_of_year = None;
+    let mut offset = None;
+
+    let mut rest = s;
+    let mut chars = format.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            match rest.strip_prefix(c) {
+                Some(remaining) => rest = remaining,
+                None => return Ok(None),
+            }
+            continue;
+        }
+        let Some(specifier) = chars.next() else {
+            return Err(location(18535, "Unmatched '%' at end of format string"));
+        };
+        let (field, min, max) = match specifier {
+            'Y' => (YEAR, 4, 4),
+            'G' => (ISO_YEAR, 4, 4),
+            'm' => (MONTH, 1, 2),
+            'd' => (DAY, 1, 2),
+            'H' => (HOUR, 1, 2),
+            'M' => (MINUTE, 1, 2),
+            'S' => (SECOND, 1, 2),
+            'L' => (MILLIS, 1, 3),
+            'V' => (ISO_WEEK, 1, 2),
+            'u' => (ISO_DAY, 1, 1),
+            'j' => {
+                let Some((value, remaining)) = take_number(rest, 1, 3) else {
+                    return Ok(None);
+                };
+                day_of_year = Some(value);
+                rest = remaining;
+                continue;
+            }
+            'z' | 'Z' => {
+                let sign = match rest.as_bytes().first() {
+                    Some(b'+') => 1,
+                    Some(b'-') => -1,
+                    _ => return Ok(None),
+                };
+                let parsed = if specifier == 'z' {
+                    let end = rest[1..]
+                        .find(|c: char| !(c.is_ascii_digit() || c == ':'))
+                        .map_or(rest.len(), |i| i + 1);
+                    let parsed = parse_offset(&rest[..end]);
+                    parsed.map(|offset| (offset, &rest[end..]))
+                } else {
+                    take_number(&rest[1..], 1, 4)
+                        .map(|(minutes, remaining)| (sign * minutes as i32 * 60, remaining))
+                };
+                let Some((value, remaining)) = parsed else {
+                    return Ok(None);
+                };
+                offset = Some(value);
+                rest = remaining;
+                continue;
+            }
+            '%' => {
+                match rest.strip_prefix('%') {
+                    Some(remaining) => rest = remaining,
+                    None => return Ok(None),
+                }
+                continue;
+            }
+            c => {
+                return Err(location(
+                    18536,
+                    format!("Invalid format character '%{}' in format string", c),
+                ));
+            }
+        };
+        let Some((value, remaining)) = take_number(rest, min, max) else {
+            return Ok(None);
+        };
+        fields[field] = Some(value);
+        rest = remaining;
+    }
+    if !rest.is_empty() {
+        return Ok(None);
+    }
+
+    let date = if let Some(iso_year) = fields[ISO_YEAR] {
+        let weekday = match fields[ISO_DAY].unwrap_or(1) {
+            day @ 1..=7 => Weekday::try_from(day as u8 - 1).ok(),
+            _ => None,
+        };
+        weekday.and_then(|weekday| {
+            NaiveDate::from_isoywd_opt(
+                iso_year as i32,
+                fields[ISO_WEEK].unwrap_or(1) as u32,
+                weekday,
+            )
+        })
+    } else {
+        let year = fields[YEAR].unwrap_or(1970) as i32;
+        match day_of_year {
+            Some(day) => NaiveDate::from_yo_opt(year, day as u32),
+            None => NaiveDate::from_ymd_opt(
+                year,
+                fields[MONTH].unwrap_or(1) as u32,
+                fields[DAY].unwrap_or(1) as u32,
+            ),
+        }
+    };
+    let local = date.and_then(|date| {
+        date.and_hms_milli_opt(
+            fields[HOUR].unwrap_or(0) as u32,
+            fields[MINUTE].unwrap_or(0) as u32,
+            fields[SECOND].unwrap_or(0) as u32,
+            fields[MILLIS].unwrap_or(0) as u32,
+        )
+    });
+    Ok(local.map(|local| (local, offset)))
+}
+
+/// `$dateFromString: { dateString, format, timezone, onError, onNull }`.
+/// Parse failures are `ConversionFailure` errors, which `onError` replaces.
+fn from_string(args: &[Option<Value>]) -> CommandResult<Option<Value>> {
+    let [date_string, format, timezone, on_error, on_null] = args else {
+        unreachable!("$dateFromString has five parameters");
+    };
+    let Some(zone) = Zone::parse(timezone)? else {
+        return Ok(Some(Value::Null));
+    };
+    let format = match format {
+        None => None,
+        Some(Value::String(format)) => Some(format),
+        Some(Value::Null | Value::Undefined) => return Ok(Some(Value::Null)),
+        Some(value) => {
+            return Err(location(
+                40684,
+                format!(
+                    "$dateFromString requires that 'format' be a string, found: {} with value {}",
+                    value.type_name(),
+                    value
+                ),
+            ));
+        }
+    };
+    let failure = |message: String| -> CommandResult<Option<Value>> {
+        match on_error {
+            Some(value) => Ok(Some(value.clone())),
+            None => Err(CommandError::new(ErrorCode::ConversionFailure, message)),
+        }
+    };
+    let date_string = match date_string {
+        None | Some(Value::Null | Value::Undefined) => {
+            return Ok(Some(on_null.clone().unwrap_or(Value::Null)));
+        }
+        Some(Value::String(s)) => s,
+        Some(value) => {
+            return failure(format!(
+                "$dateFromString requires that 'dateString' be a string, found: {} with value {}",
+                value.type_name(),
+                value
+            ));
+        }
+    };
+
+    let parsed = match format {
+        Some(format) => parse_with_format(date_string, format)?,
+        None => parse_iso(date_string),
+    };
+    let Some((local, offset)) = parsed else {
+        return failure(format!("Error parsing date string '{}'", date_string));
+    };
+    let ms = match (offset, timezone) {
+        (Some(_), Some(_)) => {
+            return failure(format!(
+                "you cannot pass in a date/time string with GMT offset together with a timezone argument: '{}'",
+                date_string
+            ));
+        }
+        (Some(offset), None) => (local - TimeDelta::seconds(offset as i64))
+            .and_utc()
+            .timestamp_millis(),
+        (None, _) => zone.utc(local),
+    };
+    Ok(Some(Value::UtcDateTime(ms)))
+}
+
+fn to_parts(local: NaiveDateTime, iso8601: &Option<Value>) -> CommandResult<Value> {
+    let iso = match iso8601 {
+        None => false,
+        Some(Value::Boolean(iso)) => *iso,
+        Some(Value::Null | Value::Undefined) => return Ok(Value::Null),
+        Some(value) => {
+            return Err(location(
+                40521,
+                format!(
+                    "iso8601 must evaluate to a bool, found {}",
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    let mut parts = Document::new();
+    if iso {
+        parts.insert("isoWeekYear", Value::Int32(local.iso_week().year()));
+        parts.insert("isoWeek", Value::Int32(local.iso_week().week() as i32));
+        parts.insert(
+            "isoDayOfWeek",
+            Value::Int32(local.weekday().number_from_monday() as i32),
+        );
+    } else {
+        parts.insert("year", Value::Int32(local.year()));
+        parts.insert("month", Value::Int32(local.month() as i32));
+        parts.insert("day", Value::Int32(local.day() as i32));
+    }
+    parts.insert("hour", Value::Int32(local.hour() as i32));
+    parts.insert("minute", Value::Int32(local.minute() as i32));
+    parts.insert("second", Value::Int32(local.second() as i32));
+    parts.insert(
+        "millisecond",
+        Value::Int32((local.nanosecond() / 1_000_000) as i32),
+    );
+    Ok(Value::Document(parts))
+}
+
+/// `$dateFromParts`. Parts outside their usual range carry over, so month
+/// 14 is February of the following year.
+fn from_parts(args: &[Option<Value>], zone: Zone) -> CommandResult<Option<Value>> {
+    const NAMES: [&str; 10] = [
+        "year",
+        "month",
+        "day",
+        "hour",
+        "minute",
+        "second",
+        "millisecond",
+        "isoWeekYear",
+        "isoWeek",
+        "isoDayOfWeek",
+    ];
+    let natural = args[..3].iter().any(Option::is_some);
+    let iso = args[7..10].iter().any(Option::is_some);
+    if natural && iso {
+        return Err(location(
+            40489,
+            "$dateFromParts does not allow mixing natural dates with ISO dates",
+        ));
+    }
+    if args[0].is_none() && args[7].is_none() {
+        return Err(location(
+            40516,
+            "$dateFromParts requires either 'year' or 'isoWeekYear' to be present",
+        ));
+    }
+
+    let mut parts = [0i64; 10];
+    for (i, arg) in args[..10].iter().enumerate() {
+        let (default, range, code): (i64, RangeInclusive<i64>, i32) = match i {
+            0 | 7 => (1970, 1..=9999, if i == 0 { 40523 } else { 31095 }),
+            1 | 2 | 8 | 9 => (1, -32768..=32767, 31034),
+            _ => (0, -32768..=32767, 31034),
+        };
+        parts[i] = match arg {
+            None => default,
+            Some(value) if value.is_null_or_undefined() => return Ok(Some(Value::Null)),
+            Some(value) => match value.as_i64() {
+                Some(part) if value.is_number() && range.contains(&part) => part,
+                Some(part) if value.is_number() => {
+                    return Err(location(
+                        code,
+                        format!(
+                            "'{}' must evaluate to a value in the range [{}, {}]; value {} is not in range",
+                            NAMES[i],
+                            range.start(),
+                            range.end(),
+                            part
+                        ),
+                    ));
+                }
+                _ => {
+                    return Err(location(
+                        40515,
+                        format!(
+                            "'{}' must evaluate to an integer, found {} with value {}",
+                            NAMES[i],
+                            value.type_name(),
+                            value
+                        ),
+                    ));
+                }
+            },
+        };
+    }
+
+    let date = if iso {
+        NaiveDate::from_isoywd_opt(parts[7] as i32, 1, Weekday::Mon).and_then(|monday| {
+            monday.checked_add_signed(TimeDelta::days((parts[8] - 1) * 7 + parts[9] - 1))
+        })
+    } else {
+        let months = parts[0] * 12 + parts[1] - 1;
+        NaiveDate::from_ymd_opt(
+            months.div_euclid(12) as i32,
+            months.rem_euclid(12) as u32 + 1,
+            1,
+        )
+        .and_then(|first| first.checked_add_signed(TimeDelta::days(parts[2] - 1)))
+    };
+    let time =
+        TimeDelta::milliseconds(((parts[3] * 60 + parts[4]) * 60 + parts[5]) * 1000 + parts[6]);
+    let local = date
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| midnight.checked_add_signed(time))
+        .ok_or_else(|| location(40523, "$dateFromParts date is out of range"))?;
+    Ok(Some(Value::UtcDateTime(zone.utc(local))))
+}
+
+/// A time unit, as taken by `$dateAdd` and by range-based windows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Unit {
+    Year,
+    Quarter,
+    Month,
+    Week,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Millisecond,
+}
+
+impl Unit {
+    fn parse(name: &str, value: &Value) -> CommandResult<Self> {
+        let Value::String(unit) = value else {
+            return Err(location(
+                5439013,
+                format!(
+                    "{} requires 'unit' to be a string, but got {}",
+                    name,
+                    value.type_name()
+                ),
+            ));
+        };
+        Unit::from_name(unit).ok_or_else(|| {
+            CommandError::new(
+                ErrorCode::FailedToParse,
+                format!("unknown time unit value: {}", unit),
+            )
+        })
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "year" => Unit::Year,
+            "quarter" => Unit::Quarter,
+            "month" => Unit::Month,
+            "week" => Unit::Week,
+            "day" => Unit::Day,
+            "hour" => Unit::Hour,
+            "minute" => Unit::Minute,
+            "second" => Unit::Second,
+            "millisecond" => Unit::Millisecond,
+            _ => return None,
+        })
+    }
+
+    /// The length of the unit in UTC, where days and weeks are fixed too.
+    pub fn utc_millis(self) -> Option<i64> {
+        self.millis()
+            .or_else(|| self.days().map(|days| days * 86_400_000))
+    }
+
+    /// Adds an amount of units to a date in UTC, as `$dateAdd` does.
+    pub fn add_utc(self, ms: i64, amount: i64) -> Option<i64> {
+        add(ms, self, amount, Zone::Fixed(FixedOffset::east_opt(0)?))
+    }
+
+    /// The number of months in a calendar unit.
+    fn months(self) -> Option<i64> {
+        match self {
+            Unit::Year => Some(12),
+            Unit::Quarter => Some(3),
+            Unit::Month => Some(1),
+            _ => None,
+        }
+    }
+
+    /// The length of a unit that is a fixed number of milliseconds.
+    fn millis(self) -> Option<i64> {
+        match self {
+            Unit::Hour => Some(3_600_000),
+            Unit::Minute => Some(60_000),
+            Unit::Second => Some(1000),
+            Unit::Millisecond => Some(1),
+            _ => None,
+        }
+    }
+
+    fn days(self) -> Option<i64> {
+        match self {
+            Unit::Week => Some(7),
+            Unit::Day => Some(1),
+            _ => None,
+        }
+    }
+}
+
+/// The day weeks start on, Sunday by default. `None` when null.
+fn parse_start_of_week(value: &Option<Value>) -> CommandResult<Option<Weekday>> {
+    match value {
+        None => Ok(Some(Weekday::Sun)),
+        Some(Value::Null | Value::Undefined) => Ok(None),
+        Some(Value::String(day)) => {
+            let day = day.to_lowercase();
+            let weekday = [
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+            ]
+            .into_iter()
+            .find(|weekday| {
+                let name = format!("{:?}", weekday).to_lowercase();
+                day == name || day == full_day_name(*weekday)
+            });
+            weekday
+                .map(Some)
+                .ok_or_else(|| location(5439015, format!("unknown day of week value: {}", day)))
+        }
+        Some(value) => Err(location(
+            5439016,
+            format!(
+                "'startOfWeek' must evaluate to a string, found {}",
+                value.type_name()
+            ),
+        )),
+    }
+}
+
+fn full_day_name(weekday: Weekday) -> &'static str {
+    match weekday {
+        Weekday::Mon => "monday",
+        Weekday::Tue => "tuesday",
+        Weekday::Wed => "wednesday",
+        Weekday::Thu => "thursday",
+        Weekday::Fri => "friday",
+        Weekday::Sat => "saturday",
+        Weekday::Sun => "sunday",
+    }
+}
+
+/// Adds an amount of units. Calendar units are added to the wall clock
+/// time, clamping to the end of shorter months; hours and smaller units
+/// are fixed durations.
+fn add(ms: i64, unit: Unit, amount: i64, zone: Zone) -> Option<i64> {
+    if let Some(length) = unit.millis() {
+        return ms.checked_add(amount.checked_mul(length)?);
+    }
+    let local = zone.local(ms).ok()?;
+    let local = if let Some(months) = unit.months() {
+        let months = amount.checked_mul(months)?;
+        let count = Months::new(u32::try_from(months.unsigned_abs()).ok()?);
+        if months >= 0 {
+            local.checked_add_months(count)?
+        } else {
+            local.checked_sub_months(count)?
+        }
+    } else {
+        let days = amount.checked_mul(unit.days()?)?;
+        local.checked_add_signed(TimeDelta::try_days(days)?)?
+    };
+    Some(zone.utc(local))
+}
+
+/// Days from 1970-01-01 to a wall clock date.
+fn day_number(local: NaiveDateTime) -> i64 {
+    local
+        .date()
+        .signed_duration_since(NaiveDate::default())
+        .num_days()
+}
+
+/// The number of unit boundaries crossed between two instants.
+fn diff(
+    start: i64,
+    end: i64,
+    unit: Unit,
+    zone: Zone,
+    start_of_week: Weekday,
+) -> CommandResult<i64> {
+    let (a, b) = (zone.local(start)?, zone.local(end)?);
+    Ok(match unit {
+        Unit::Year => (b.year() - a.year()) as i64,
+        Unit::Quarter | Unit::Month => {
+            let months = |t: NaiveDateTime| t.year() as i64 * 12 + t.month0() as i64;
+            match unit {
+                Unit::Quarter => months(b).div_euclid(3) - months(a).div_euclid(3),
+                _ => months(b) - months(a),
+            }
+        }
+        Unit::Week => {
+            // 1970-01-01 was a Thursday
+            let offset = 3 - start_of_week.num_days_from_monday() as i64;
+            let week = |t| (day_number(t) + offset).div_euclid(7);
+            week(b) - week(a)
+        }
+        Unit::Day => day_number(b) - day_number(a),
+        unit => {
+            let length = unit.millis().expect("fixed length unit");
+            let local_ms = |t: NaiveDateTime| t.and_utc().timestamp_millis();
+            local_ms(b).div_euclid(length) - local_ms(a).div_euclid(length)
+        }
+    })
+}
+
+/// Truncates to the start of a bin of `bin_size` units. Bins are counted
+/// from 2000-01-01 (or, for weeks, the first `start_of_week` on or before
+/// it) in the given time zone.
+fn truncate(
+    ms: i64,
+    unit: Unit,
+    bin_size: i64,
+    zone: Zone,
+    start_of_week: Weekday,
+) -> CommandResult<i64> {
+    let local = zone.local(ms)?;
+    let reference = NaiveDate::from_ymd_opt(2000, 1, 1).expect("valid date");
+    let overflow = || location(5439018, "$dateTrunc overflowed");
+    let truncated = if let Some(months) = unit.months() {
+        let bin = bin_size.checked_mul(months).ok_or_else(overflow)?;
+        let elapsed = (local.year() as i64 - 2000) * 12 + local.month0() as i64;
+        let start = elapsed.div_euclid(bin) * bin;
+        NaiveDate::from_ymd_opt(
+            (2000 + start.div_euclid(12)) as i32,
+            start.rem_euclid(12) as u32 + 1,
+            1,
+        )
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+    } else if let Some(days) = unit.days() {
+        let reference = match unit {
+            Unit::Week => {
+                let back = (reference.weekday().num_days_from_monday() + 7
+                    - start_of_week.num_days_from_monday())
+                    % 7;
+                reference - TimeDelta::days(back as i64)
+            }
+            _ => reference,
+        };
+        let bin = bin_size.checked_mul(days).ok_or_else(overflow)?;
+        let elapsed = local.date().signed_duration_since(reference).num_days();
+        TimeDelta::try_days(elapsed.div_euclid(bin) * bin)
+            .and_then(|delta| reference.checked_add_signed(delta))
+            .and_then(|date| date.and_hms_opt(0, 0, 0))
+    } else {
+        let length = unit.millis().expect("fixed length unit");
+        let bin = bin_size.checked_mul(length).ok_or_else(overflow)?;
+        let reference = reference.and_hms_opt(0, 0, 0).expect("valid time");
+        let elapsed = local.signed_duration_since(reference).num_milliseconds();
+        reference.checked_add_signed(TimeDelta::milliseconds(elapsed.div_euclid(bin) * bin))
+    };
+    truncated.map(|local| zone.utc(local)).ok_or_else(overflow)
+}
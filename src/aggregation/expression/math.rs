@@ -0,0 +1,467 @@
+//! Arithmetic operators (`$add`, `$mod`, `$round`, `$pow`, ...) and the
+//! accumulators that also work on arrays in expressions (`$sum`, `$avg`,
+//! `$min`, `$max`, `$stdDevPop`, `$stdDevSamp`).
+
+use std::cmp::Ordering;
+
+use crate::{bson::Value, error::CommandResult};
+
+use super::location;
+
+/// Name, minimum and maximum number of arguments.
+pub const OPERATORS: &[(&str, usize, usize)] = &[
+    ("$add", 0, usize::MAX),
+    ("$subtract", 2, 2),
+    ("$multiply", 0, usize::MAX),
+    ("$divide", 2, 2),
+    ("$mod", 2, 2),
+    ("$abs", 1, 1),
+    ("$ceil", 1, 1),
+    ("$floor", 1, 1),
+    ("$round", 1, 2),
+    ("$trunc", 1, 2),
+    ("$sqrt", 1, 1),
+    ("$pow", 2, 2),
+    ("$exp", 1, 1),
+    ("$ln", 1, 1),
+    ("$log", 2, 2),
+    ("$log10", 1, 1),
+    ("$sum", 0, usize::MAX),
+    ("$avg", 0, usize::MAX),
+    ("$min", 0, usize::MAX),
+    ("$max", 0, usize::MAX),
+    ("$stdDevPop", 0, usize::MAX),
+    ("$stdDevSamp", 0, usize::MAX),
+];
+
+pub fn evaluate(name: &str, args: &[Value]) -> CommandResult<Option<Value>> {
+    let value = match name {
+        "$add" => add(args)?,
+        "$subtract" => subtract(&args[0], &args[1])?,
+        "$multiply" => multiply(args)?,
+        "$divide" => divide(&args[0], &args[1])?,
+        "$mod" => modulo(&args[0], &args[1])?,
+        "$abs" => unary(name, &args[0], abs)?,
+        "$ceil" => unary(name, &args[0], |value| Ok(integral(value, f64::ceil)))?,
+        "$floor" => unary(name, &args[0], |value| Ok(integral(value, f64::floor)))?,
+        "$round" | "$trunc" => round(name, args)?,
+        "$sqrt" => unary(name, &args[0], |value| {
+            let x = value.as_f64().unwrap_or(0.0);
+            if x < 0.0 {
+                return Err(location(
+                    28714,
+                    "$sqrt's argument must be greater than or equal to 0",
+                ));
+            }
+            Ok(Value::Double(x.sqrt()))
+        })?,
+        "$exp" => unary(name, &args[0], |value| {
+            Ok(Value::Double(value.as_f64().unwrap_or(0.0).exp()))
+        })?,
+        "$ln" => unary(name, &args[0], |value| {
+            let x = value.as_f64().unwrap_or(0.0);
+            if x <= 0.0 {
+                return Err(location(
+                    28766,
+                    format!("$ln's argument must be a positive number, but is {}", value),
+                ));
+            }
+            Ok(Value::Double(x.ln()))
+        })?,
+        "$log10" => unary(name, &args[0], |value| {
+            let x = value.as_f64().unwrap_or(0.0);
+            if x <= 0.0 {
+                return Err(location(
+                    28761,
+                    format!(
+                        "$log10's argument must be a positive number, but is {}",
+                        value
+                    ),
+                ));
+            }
+            Ok(Value::Double(x.log10()))
+        })?,
+        "$log" => log(&args[0], &args[1])?,
+        "$pow" => pow(&args[0], &args[1])?,
+        "$sum" | "$avg" | "$min" | "$max" | "$stdDevPop" | "$stdDevSamp" => {
+            // A single argument is treated as the list of values to fold
+            let values = match args {
+                [Value::Array(items)] => &items.0[..],
+                args => args,
+            };
+            fold(name, values)
+        }
+        name => unreachable!("operator {} is not arithmetic", name),
+    };
+    Ok(Some(value))
+}
+
+/// Numeric arithmetic with mongod's type promotion: int widens to long on
+/// overflow, long widens to double, and any double makes the result double.
+pub fn arithmetic(
+    a: &Value,
+    b: &Value,
+    long: fn(i64, i64) -> Option<i64>,
+    double: fn(f64, f64) -> f64,
+) -> Value {
+    let as_double = || Value::Double(double(a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0)));
+    match (a, b) {
+        (Value::Int32(x), Value::Int32(y)) => match long(*x as i64, *y as i64) {
+            Some(v) => narrow(v),
+            None => as_double(),
+        },
+        (Value::Int32(_) | Value::Int64(_), Value::Int32(_) | Value::Int64(_)) => {
+            match long(a.as_i64().unwrap_or(0), b.as_i64().unwrap_or(0)) {
+                Some(v) => Value::Int64(v),
+                None => as_double(),
+            }
+        }
+        _ => as_double(),
+    }
+}
+
+/// An int when the value fits in one, a long otherwise.
+fn narrow(value: i64) -> Value {
+    match i32::try_from(value) {
+        Ok(value) => Value::Int32(value),
+        Err(_) => Value::Int64(value),
+    }
+}
+
+fn has_null(values: &[&Value]) -> bool {
+    values.iter().any(|value| value.is_null_or_undefined())
+}
+
+/// Applies a single argument numeric operator, passing null through.
+fn unary(
+    name: &str,
+    value: &Value,
+    apply: impl FnOnce(&Value) -> CommandResult<Value>,
+) -> CommandResult<Value> {
+    if value.is_null_or_undefined() {
+        return Ok(Value::Null);
+    }
+    if !value.is_number() {
+        return Err(location(
+            28765,
+            format!(
+                "{} only supports numeric types, not {}",
+                name,
+                value.type_name()
+            ),
+        ));
+    }
+    apply(value)
+}
+
+fn add(values: &[Value]) -> CommandResult<Value> {
+    let mut sum = Value::Int32(0);
+    let mut date: Option<i64> = None;
+    for value in values {
+        match value {
+            Value::Null | Value::Undefined => return Ok(Value::Null),
+            Value::UtcDateTime(ms) => {
+                if date.is_some() {
+                    return Err(location(
+                        16612,
+                        "only one date allowed in an $add expression",
+                    ));
+                }
+                date = Some(*ms);
+            }
+            value if value.is_number() => {
+                sum = arithmetic(&sum, value, i64::checked_add, |a, b| a + b);
+            }
+            value => {
+                return Err(location(
+                    16554,
+                    format!(
+                        "$add only supports numeric or date types, not {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(match date {
+        Some(ms) => Value::UtcDateTime(ms.wrapping_add(sum.as_f64().unwrap_or(0.0).round() as i64)),
+        None => sum,
+    })
+}
+
+fn subtract(a: &Value, b: &Value) -> CommandResult<Value> {
+    Ok(match (a, b) {
+        (a, b) if has_null(&[a, b]) => Value::Null,
+        (a, b) if a.is_number() && b.is_number() => {
+            arithmetic(a, b, i64::checked_sub, |x, y| x - y)
+        }
+        (Value::UtcDateTime(x), Value::UtcDateTime(y)) => Value::Int64(x.wrapping_sub(*y)),
+        (Value::UtcDateTime(x), b) if b.is_number() => {
+            Value::UtcDateTime(x.wrapping_sub(b.as_f64().unwrap_or(0.0).round() as i64))
+        }
+        (a, b) => {
+            return Err(location(
+                16556,
+                format!("can't $subtract {} from {}", b.type_name(), a.type_name()),
+            ));
+        }
+    })
+}
+
+fn multiply(values: &[Value]) -> CommandResult<Value> {
+    let mut product = Value::Int32(1);
+    for value in values {
+        if value.is_null_or_undefined() {
+            return Ok(Value::Null);
+        }
+        if !value.is_number() {
+            return Err(location(
+                16555,
+                format!(
+                    "$multiply only supports numeric types, not {}",
+                    value.type_name()
+                ),
+            ));
+        }
+        product = arithmetic(&product, value, i64::checked_mul, |a, b| a * b);
+    }
+    Ok(product)
+}
+
+fn divide(a: &Value, b: &Value) -> CommandResult<Value> {
+    if has_null(&[a, b]) {
+        return Ok(Value::Null);
+    }
+    let (Some(x), Some(y)) = (a.as_f64(), b.as_f64()) else {
+        return Err(location(
+            16609,
+            format!(
+                "$divide only supports numeric types, not {} and {}",
+                a.type_name(),
+                b.type_name()
+            ),
+        ));
+    };
+    if y == 0.0 {
+        return Err(location(16608, "can't $divide by zero"));
+    }
+    Ok(Value::Double(x / y))
+}
+
+/// The remainder takes the sign of the dividend, as with `%` in C.
+fn modulo(a: &Value, b: &Value) -> CommandResult<Value> {
+    if has_null(&[a, b]) {
+        return Ok(Value::Null);
+    }
+    let (Some(_), Some(y)) = (a.as_f64(), b.as_f64()) else {
+        return Err(location(
+            16611,
+            format!(
+                "$mod only supports numeric types, not {} and {}",
+                a.type_name(),
+                b.type_name()
+            ),
+        ));
+    };
+    if y == 0.0 {
+        return Err(location(16610, "can't $mod by zero"));
+    }
+    // checked_rem only fails for MIN % -1, whose remainder is zero
+    Ok(arithmetic(
+        a,
+        b,
+        |x, y| Some(x.checked_rem(y).unwrap_or(0)),
+        |x, y| x % y,
+    ))
+}
+
+fn abs(value: &Value) -> CommandResult<Value> {
+    Ok(match value {
+        Value::Int32(v) => narrow((*v as i64).abs()),
+        Value::Int64(v) => match v.checked_abs() {
+            Some(v) => Value::Int64(v),
+            None => return Err(location(28680, "can't take $abs of long long min")),
+        },
+        value => Value::Double(value.as_f64().unwrap_or(0.0).abs()),
+    })
+}
+
+/// `$ceil` and `$floor`: integers are already integral.
+fn integral(value: &Value, apply: fn(f64) -> f64) -> Value {
+    match value {
+        Value::Double(v) => Value::Double(apply(*v)),
+        value => value.clone(),
+    }
+}
+
+/// `$round` (half to even) and `$trunc` to a number of decimal places, which
+/// may be negative to round to tens, hundreds and so on.
+fn round(name: &str, args: &[Value]) -> CommandResult<Value> {
+    let place = match args.get(1) {
+        None => 0,
+        Some(place) if place.is_null_or_undefined() => return Ok(Value::Null),
+        Some(place) => match place.as_i64() {
+            Some(place) if (-20..=100).contains(&place) => place,
+            Some(_) => {
+                return Err(location(
+                    51083,
+                    format!(
+                        "cannot apply {} with precision value {} value must be in [-20, 100]",
+                        name, place
+                    ),
+                ));
+            }
+            None => {
+                return Err(location(
+                    51082,
+                    format!("precision argument to {} must be a integral value", name),
+                ));
+            }
+        },
+    };
+    let apply: fn(f64) -> f64 = match name {
+        "$round" => f64::round_ties_even,
+        _ => f64::trunc,
+    };
+    unary(name, &args[0], |value| {
+        Ok(match value {
+            Value::Int32(_) | Value::Int64(_) if place >= 0 => value.clone(),
+            Value::Int32(_) | Value::Int64(_) => {
+                let v = value.as_i64().unwrap_or(0);
+                let scale = 10f64.powi(-place as i32);
+                let rounded = apply(v as f64 / scale) * scale;
+                match value {
+                    Value::Int32(_) => narrow(rounded as i64),
+                    _ => Value::Int64(rounded as i64),
+                }
+            }
+            value => {
+                let v = value.as_f64().unwrap_or(0.0);
+                let scale = 10f64.powi(place as i32);
+                Value::Double(apply(v * scale) / scale)
+            }
+        })
+    })
+}
+
+fn log(value: &Value, base: &Value) -> CommandResult<Value> {
+    if has_null(&[value, base]) {
+        return Ok(Value::Null);
+    }
+    let Some(x) = value.as_f64() else {
+        return Err(location(
+            28756,
+            format!("$log's argument must be numeric, not {}", value.type_name()),
+        ));
+    };
+    let Some(b) = base.as_f64() else {
+        return Err(location(
+            28757,
+            format!("$log's base must be numeric, not {}", base.type_name()),
+        ));
+    };
+    if x <= 0.0 {
+        return Err(location(
+            28758,
+            format!(
+                "$log's argument must be a positive number, but is {}",
+                value
+            ),
+        ));
+    }
+    if b <= 0.0 || b == 1.0 {
+        return Err(location(
+            28759,
+            format!(
+                "$log's base must be a positive number not equal to 1, but is {}",
+                base
+            ),
+        ));
+    }
+    Ok(Value::Double(x.ln() / b.ln()))
+}
+
+/// Integer powers stay integers when the result fits, like `$multiply`.
+fn pow(base: &Value, exponent: &Value) -> CommandResult<Value> {
+    if has_null(&[base, exponent]) {
+        return Ok(Value::Null);
+    }
+    let (Some(b), Some(e)) = (base.as_f64(), exponent.as_f64()) else {
+        let culprit = if base.is_number() { exponent } else { base };
+        return Err(location(
+            28762,
+            format!(
+                "$pow's base and exponent must be numeric, not {}",
+                culprit.type_name()
+            ),
+        ));
+    };
+    if b == 0.0 && e < 0.0 {
+        return Err(location(
+            28764,
+            "$pow cannot take a base of 0 and a negative exponent",
+        ));
+    }
+    let integers = matches!(base, Value::Int32(_) | Value::Int64(_))
+        && matches!(exponent, Value::Int32(_) | Value::Int64(_));
+    if integers {
+        let (b, e) = (base.as_i64().unwrap_or(0), exponent.as_i64().unwrap_or(0));
+        let power = u32::try_from(e).ok().and_then(|e| b.checked_pow(e));
+        if let Some(power) = power {
+            return Ok(match (base, exponent) {
+                (Value::Int32(_), Value::Int32(_)) => narrow(power),
+                _ => Value::Int64(power),
+            });
+        }
+    }
+    Ok(Value::Double(b.powf(e)))
+}
+
+/// `$sum`, `$avg`, `$min`, `$max` and the standard deviations over a list of
+/// values, skipping the values the accumulator would skip.
+fn fold(name: &str, values: &[Value]) -> Value {
+    let numbers = || values.iter().filter(|value| value.is_number());
+    match name {
+        "$sum" => numbers().fold(Value::Int32(0), |sum, value| {
+            arithmetic(&sum, value, i64::checked_add, |a, b| a + b)
+        }),
+        "$avg" => {
+            let count = numbers().count();
+            if count == 0 {
+                return Value::Null;
+            }
+            let sum: f64 = numbers().filter_map(Value::as_f64).sum();
+            Value::Double(sum / count as f64)
+        }
+        "$min" | "$max" => {
+            let wanted = if name == "$min" {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+            values
+                .iter()
+                .filter(|value| !value.is_null_or_undefined())
+                .fold(None::<&Value>, |extreme, value| match extreme {
+                    Some(extreme) if value.compare(extreme) != wanted => Some(extreme),
+                    _ => Some(value),
+                })
+                .cloned()
+                .unwrap_or(Value::Null)
+        }
+        _ => {
+            let numbers: Vec<f64> = numbers().filter_map(Value::as_f64).collect();
+            let samples = if name == "$stdDevSamp" {
+                numbers.len().saturating_sub(1)
+            } else {
+                numbers.len()
+            };
+            if samples == 0 {
+                return Value::Null;
+            }
+            let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+            let squares: f64 = numbers.iter().map(|x| (x - mean) * (x - mean)).sum();
+            Value::Double((squares / samples as f64).sqrt())
+        }
+    }
+}
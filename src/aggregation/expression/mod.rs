@@ -0,0 +1,1316 @@
+//! Aggregation expressions: field paths (`"$a.b"`), variables (`"$$ROOT"`),
+//! literals, object and array expressions, and operator expressions such as
+//! `{ $add: ["$a", 1] }`.
+//!
+//! The operators that bind variables or evaluate their arguments lazily
+//! (`$let`, `$map`, `$filter`, `$reduce`, `$switch`, `$cond`, `$and`, ...)
+//! are evaluated here; the rest evaluate all their arguments first and are
+//! grouped by kind in the submodules.
+
+mod array;
+mod convert;
+mod date;
+mod math;
+mod object;
+mod string;
+
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Array, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::pipeline::{no_text_score, Metadata};
+
+pub use date::Unit as TimeUnit;
+pub use math::arithmetic;
+
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Literal(Value),
+    /// A path relative to the current document, without the leading `$`.
+    FieldPath(Vec<String>),
+    /// A variable reference such as `$$ROOT.a`: the variable name and an
+    /// optional path into its value.
+    Variable(String, Vec<String>),
+    Object(Vec<(String, Expression)>),
+    Array(Vec<Expression>),
+    Operator(String, Vec<Expression>),
+    /// An operator taking named arguments, such as `$convert`. Arguments are
+    /// in the order of the operator's parameters, `None` when not given.
+    Named(String, Vec<Option<Expression>>),
+    /// `$let`: variables bound while evaluating `in`.
+    Let(Vec<(String, Expression)>, Box<Expression>),
+    /// `$map`: `in` evaluated for each element of `input`, bound to `as`.
+    Map {
+        input: Box<Expression>,
+        name: String,
+        body: Box<Expression>,
+    },
+    /// `$filter`: the elements of `input`, bound to `as`, for which `cond`
+    /// holds, up to `limit` of them.
+    Filter {
+        input: Box<Expression>,
+        name: String,
+        cond: Box<Expression>,
+        limit: Option<Box<Expression>>,
+    },
+    /// `$reduce`: folds `input` into `$$value`, starting from
+    /// `initialValue`, with each element bound to `$$this`.
+    Reduce {
+        input: Box<Expression>,
+        initial: Box<Expression>,
+        body: Box<Expression>,
+    },
+    /// `$switch`: the `then` of the first branch whose `case` holds.
+    Switch {
+        branches: Vec<(Expression, Expression)>,
+        default: Option<Box<Expression>>,
+    },
+    /// `$meta: 'textScore'`: the score of the document in a `$text` query.
+    TextScore,
+}
+
+/// The variables in scope while evaluating an expression. `CURRENT` and
+/// `ROOT` both refer to the document being processed.
+pub struct Variables<'a> {
+    root: &'a Document,
+    now: Value,
+    /// The text score `$meta` reads.
+    text_score: Option<f64>,
+    /// Variables bound by `$let`, `$map`, `$filter` and `$reduce`, innermost
+    /// last. A variable bound to a missing value is `None`.
+    bindings: Vec<(String, Option<Value>)>,
+}
+
+impl<'a> Variables<'a> {
+    pub fn new(root: &'a Document) -> Self {
+        Self {
+            root,
+            now: Value::now(),
+            text_score: None,
+            bindings: Vec::new(),
+        }
+    }
+
+    /// The variables with the metadata of the document, for `$meta`.
+    pub fn with_metadata(self, metadata: &Metadata) -> Self {
+        Self {
+            text_score: metadata.text_score,
+            ..self
+        }
+    }
+
+    /// Variables for a document with variables already bound, like those
+    /// of an enclosing `$lookup`'s `let`.
+    pub fn with_bindings(root: &'a Document, bindings: &[(String, Option<Value>)]) -> Self {
+        Self {
+            bindings: bindings.to_vec(),
+            ..Self::new(root)
+        }
+    }
+
+    /// A nested scope with more variables bound, shadowing outer ones.
+    fn bind(&self, bindings: impl IntoIterator<Item = (String, Option<Value>)>) -> Variables<'a> {
+        let mut scope = Variables {
+            root: self.root,
+            now: self.now.clone(),
+            text_score: self.text_score,
+            bindings: self.bindings.clone(),
+        };
+        scope.bindings.extend(bindings);
+        scope
+    }
+
+    fn get(&self, name: &str) -> CommandResult<Option<Value>> {
+        if let Some((_, value)) = self.bindings.iter().rev().find(|(bound, _)| bound == name) {
+            return Ok(value.clone());
+        }
+        match name {
+            "ROOT" | "CURRENT" => Ok(Some(Value::Document(self.root.clone()))),
+            "REMOVE" => Ok(None),
+            "NOW" => Ok(Some(self.now.clone())),
+            name => Err(CommandError::new(
+                ErrorCode::Location(17276),
+                format!("Use of undefined variable: {}", name),
+            )),
+        }
+    }
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('.').map(String::from).collect()
+}
+
+/// Checks the name of a variable defined by `$let` or an `as` argument:
+/// user variables start with a lowercase letter.
+pub fn validate_variable_name(name: &str) -> CommandResult<()> {
+    let Some(first) = name.chars().next() else {
+        return Err(location(16866, "empty variable names are not allowed"));
+    };
+    if first.is_ascii() && !first.is_ascii_lowercase() {
+        return Err(location(
+            16867,
+            format!(
+                "'{}' starts with an invalid character for a user variable name",
+                name
+            ),
+        ));
+    }
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || !c.is_ascii()))
+    {
+        return Err(location(
+            16868,
+            format!(
+                "'{}' contains an invalid character for a variable name: '{}'",
+                name, c
+            ),
+        ));
+    }
+    Ok(())
+}
+
+impl Expression {
+    pub fn parse(value: &Value) -> CommandResult<Self> {
+        match value {
+            Value::String(s) if s.starts_with("$$") => {
+                let mut parts = s[2..].splitn(2, '.');
+                let name = parts.next().unwrap_or_default();
+                if name.is_empty() {
+                    return Err(location(16869, "empty variable names are not allowed"));
+                }
+                let path = parts.next().map(split_path).unwrap_or_default();
+                Ok(Expression::Variable(name.to_string(), path))
+            }
+            Value::String(s) if s.starts_with('$') => {
+                if s.len() == 1 {
+                    return Err(location(16872, "'$' by itself is not a valid FieldPath"));
+                }
+                Ok(Expression::FieldPath(split_path(&s[1..])))
+            }
+            Value::Document(doc) => match doc.first() {
+                Some((name, arg)) if name.starts_with('$') => {
+                    if doc.len() > 1 {
+                        return Err(location(
+                            15983,
+                            format!(
+                                "an expression specification must contain exactly one field, the name of the expression. Found {} fields in {}",
+                                doc.len(),
+                                doc
+                            ),
+                        ));
+                    }
+                    parse_operator(name, arg)
+                }
+                _ => {
+                    let mut fields = Vec::new();
+                    for (key, value) in doc.iter() {
+                        if key.starts_with('$') {
+                            return Err(location(
+                                16410,
+                                "FieldPath field names may not start with '$'. Consider using $getField or $setField.",
+                            ));
+                        }
+                        fields.push((key.clone(), Expression::parse(value)?));
+                    }
+                    Ok(Expression::Object(fields))
+                }
+            },
+            Value::Array(items) => Ok(Expression::Array(
+                items
+                    .0
+                    .iter()
+                    .map(Expression::parse)
+                    .collect::<CommandResult<_>>()?,
+            )),
+            value => Ok(Expression::Literal(value.clone())),
+        }
+    }
+
+    /// Evaluates the expression, returning `None` when the result is missing
+    /// (e.g. a path to a field that does not exist, or `$$REMOVE`).
+    pub fn evaluate(&self, vars: &Variables) -> CommandResult<Option<Value>> {
+        match self {
+            Expression::Literal(value) => Ok(Some(value.clone())),
+            Expression::FieldPath(path) => Ok(traverse_document(vars.root, path)),
+            Expression::Variable(name, path) => {
+                Ok(vars.get(name)?.and_then(|value| traverse(&value, path)))
+            }
+            Expression::Object(fields) => {
+                let mut doc = Document::new();
+                for (key, expression) in fields {
+                    if let Some(value) = expression.evaluate(vars)? {
+                        doc.insert(key.clone(), value);
+                    }
+                }
+                Ok(Some(Value::Document(doc)))
+            }
+            Expression::Array(items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    // Missing values become null inside arrays
+                    values.push(item.eval(vars)?);
+                }
+                Ok(Some(Value::Array(Array(values))))
+            }
+            Expression::Operator(name, args) => evaluate_operator(name, args, vars),
+            Expression::Named(name, args) => {
+                let mut values = Vec::new();
+                for arg in args {
+                    values.push(match arg {
+                        Some(arg) => arg.evaluate(vars)?,
+                        None => None,
+                    });
+                }
+                match name.as_str() {
+                    "$convert" => convert::evaluate_named(&values),
+                    "$getField" | "$setField" | "$unsetField" => {
+                        object::evaluate_named(name, &values)
+                    }
+                    "$regexMatch" | "$regexFind" | "$regexFindAll" | "$trim" | "$ltrim"
+                    | "$rtrim" | "$replaceOne" | "$replaceAll" => {
+                        string::evaluate_named(name, &values)
+                    }
+                    name => date::evaluate_named(name, &values),
+                }
+            }
+            Expression::Let(bindings, body) => {
+                let mut values = Vec::new();
+                for (name, expression) in bindings {
+                    values.push((name.clone(), expression.evaluate(vars)?));
+                }
+                body.evaluate(&vars.bind(values))
+            }
+            Expression::Map { input, name, body } => {
+                let Some(items) = array_input("$map", 16883, input.eval(vars)?)? else {
+                    return Ok(Some(Value::Null));
+                };
+                let mut result = Vec::new();
+                for item in items {
+                    result.push(body.eval(&vars.bind([(name.clone(), Some(item))]))?);
+                }
+                Ok(Some(Value::from(result)))
+            }
+            Expression::Filter {
+                input,
+                name,
+                cond,
+                limit,
+            } => {
+                let Some(items) = array_input("$filter", 28651, input.eval(vars)?)? else {
+                    return Ok(Some(Value::Null));
+                };
+                let limit = match limit {
+                    Some(limit) => filter_limit(limit.eval(vars)?)?,
+                    None => None,
+                };
+                let mut result = Vec::new();
+                for item in items {
+                    if limit.is_some_and(|limit| result.len() >= limit) {
+                        break;
+                    }
+                    let scope = vars.bind([(name.clone(), Some(item.clone()))]);
+                    if cond.eval(&scope)?.is_truthy() {
+                        result.push(item);
+                    }
+                }
+                Ok(Some(Value::from(result)))
+            }
+            Expression::Reduce {
+                input,
+                initial,
+                body,
+            } => {
+                let Some(items) = array_input("$reduce", 40080, input.eval(vars)?)? else {
+                    return Ok(Some(Value::Null));
+                };
+                let mut value = initial.evaluate(vars)?;
+                for item in items {
+                    let scope = vars.bind([
+                        ("value".to_string(), value),
+                        ("this".to_string(), Some(item)),
+                    ]);
+                    value = body.evaluate(&scope)?;
+                }
+                Ok(value)
+            }
+            Expression::Switch { branches, default } => {
+                for (case, then) in branches {
+                    if case.eval(vars)?.is_truthy() {
+                        return then.evaluate(vars);
+                    }
+                }
+                match default {
+                    Some(default) => default.evaluate(vars),
+                    None => Err(location(
+                        40066,
+                        "$switch could not find a matching branch for an input, and no default was specified.",
+                    )),
+                }
+            }
+            Expression::TextScore => match vars.text_score {
+                Some(score) => Ok(Some(Value::Double(score))),
+                None => Err(no_text_score()),
+            },
+        }
+    }
+
+    /// Evaluates the expression, treating a missing result as null.
+    pub fn eval(&self, vars: &Variables) -> CommandResult<Value> {
+        Ok(self.evaluate(vars)?.unwrap_or(Value::Null))
+    }
+}
+
+/// The elements of the `input` of `$map`, `$filter` or `$reduce`, or `None`
+/// when it is null.
+fn array_input(name: &str, code: i32, input: Value) -> CommandResult<Option<Vec<Value>>> {
+    match input {
+        Value::Array(items) => Ok(Some(items.0)),
+        Value::Null | Value::Undefined => Ok(None),
+        input => Err(location(
+            code,
+            format!(
+                "input to {} must be an array not {}",
+                name,
+                input.type_name()
+            ),
+        )),
+    }
+}
+
+fn filter_limit(limit: Value) -> CommandResult<Option<usize>> {
+    if limit.is_null_or_undefined() {
+        return Ok(None);
+    }
+    match limit.as_i64().and_then(|n| i32::try_from(n).ok()) {
+        Some(n) if n > 0 => Ok(Some(n as usize)),
+        Some(_) => Err(location(
+            327392,
+            format!("$filter: limit must be greater than 0: {}", limit),
+        )),
+        None => Err(location(
+            327391,
+            format!(
+                "$filter: limit must be represented as a 32-bit integral value: {}",
+                limit
+            ),
+        )),
+    }
+}
+
+/// Reads a path from a document the way aggregation field paths do: paths
+/// through arrays collect the values found in each element.
+pub fn traverse_document(doc: &Document, path: &[String]) -> Option<Value> {
+    let (first, rest) = path.split_first()?;
+    traverse(doc.get(first)?, rest)
+}
+
+fn traverse(value: &Value, path: &[String]) -> Option<Value> {
+    if path.is_empty() {
+        return Some(value.clone());
+    }
+    match value {
+        Value::Document(doc) => traverse_document(doc, path),
+        Value::Array(items) => Some(Value::Array(Array(
+            items
+                .0
+                .iter()
+                .filter(|item| matches!(item, Value::Document(_) | Value::Array(_)))
+                .filter_map(|item| traverse(item, path))
+                .collect(),
+        ))),
+        _ => None,
+    }
+}
+
+/// The operators evaluated here: name, minimum and maximum number of
+/// arguments.
+const OPERATORS: &[(&str, usize, usize)] = &[
+    ("$cond", 3, 3),
+    ("$and", 0, usize::MAX),
+    ("$or", 0, usize::MAX),
+    ("$not", 1, 1),
+    ("$eq", 2, 2),
+    ("$ne", 2, 2),
+    ("$gt", 2, 2),
+    ("$gte", 2, 2),
+    ("$lt", 2, 2),
+    ("$lte", 2, 2),
+    ("$cmp", 2, 2),
+    ("$type", 1, 1),
+];
+
+/// Operators taking named arguments: name, parameters, and how many of the
+/// leading parameters are required.
+const NAMED_OPERATORS: &[(&str, &[&str], usize)] = &[
+    ("$convert", &["input", "to", "onError", "onNull"], 2),
+    ("$regexMatch", &["input", "regex", "options"], 2),
+    ("$regexFind", &["input", "regex", "options"], 2),
+    ("$regexFindAll", &["input", "regex", "options"], 2),
+    ("$trim", &["input", "chars"], 1),
+    ("$ltrim", &["input", "chars"], 1),
+    ("$rtrim", &["input", "chars"], 1),
+    ("$replaceOne", &["input", "find", "replacement"], 3),
+    ("$replaceAll", &["input", "find", "replacement"], 3),
+    ("$getField", &["field", "input"], 1),
+    ("$setField", &["field", "input", "value"], 3),
+    ("$unsetField", &["field", "input"], 2),
+];
+
+fn find_operator(table: &[(&str, usize, usize)], name: &str) -> Option<(usize, usize)> {
+    table
+        .iter()
+        .find(|(operator, _, _)| *operator == name)
+        .map(|(_, min, max)| (*min, *max))
+}
+
+fn operator_args(arg: &Value) -> CommandResult<Vec<Expression>> {
+    match arg {
+        Value::Array(items) => items.0.iter().map(Expression::parse).collect(),
+        arg => Ok(vec![Expression::parse(arg)?]),
+    }
+}
+
+fn expect_args(name: &str, args: &[Expression], (min, max): (usize, usize)) -> CommandResult<()> {
+    if (min..=max).contains(&args.len()) {
+        return Ok(());
+    }
+    Err(if min == max {
+        location(
+            16020,
+            format!(
+                "Expression {} takes exactly {} arguments. {} were passed in.",
+                name,
+                min,
+                args.len()
+            ),
+        )
+    } else if max == usize::MAX {
+        location(
+            16021,
+            format!(
+                "Expression {} takes at least {} arguments, and {} were passed in.",
+                name,
+                min,
+                args.len()
+            ),
+        )
+    } else {
+        location(
+            28667,
+            format!(
+                "Expression {} takes at least {} arguments, and at most {}, but {} were passed in.",
+                name,
+                min,
+                max,
+                args.len()
+            ),
+        )
+    })
+}
+
+/// Reads the arguments of an operator given as a document, as in
+/// `{ $map: { input, as, in } }`, in the order of `params`.
+fn named_args<'a>(
+    name: &str,
+    arg: &'a Value,
+    params: &[&str],
+    codes: [ErrorCode; 2],
+) -> CommandResult<Vec<Option<&'a Value>>> {
+    let Value::Document(spec) = arg else {
+        return Err(CommandError::new(
+            codes[0],
+            format!("{} only supports an object as its argument", name),
+        ));
+    };
+    if let Some(key) = spec.keys().find(|key| !params.contains(&key.as_str())) {
+        return Err(CommandError::new(
+            codes[1],
+            format!("Unrecognized parameter to {}: {}", name, key),
+        ));
+    }
+    Ok(params.iter().map(|param| spec.get(param)).collect())
+}
+
+fn required<'a>(
+    name: &str,
+    param: &str,
+    arg: Option<&'a Value>,
+    code: i32,
+) -> CommandResult<&'a Value> {
+    arg.ok_or_else(|| location(code, format!("Missing '{}' parameter to {}", param, name)))
+}
+
+/// The variable name given as `as`, `this` when omitted.
+fn variable_name(name: &str, arg: Option<&Value>) -> CommandResult<String> {
+    match arg {
+        None => Ok("this".to_string()),
+        Some(Value::String(variable)) => {
+            validate_variable_name(variable)?;
+            Ok(variable.clone())
+        }
+        Some(value) => Err(CommandError::new(
+            ErrorCode::FailedToParse,
+            format!(
+                "{} requires 'as' to be a string, found: {}",
+                name,
+                value.type_name()
+            ),
+        )),
+    }
+}
+
+fn boxed(value: &Value) -> CommandResult<Box<Expression>> {
+    Ok(Box::new(Expression::parse(value)?))
+}
+
+fn parse_operator(name: &str, arg: &Value) -> CommandResult<Expression> {
+    let location_codes =
+        |unknown: i32, object: i32| [ErrorCode::Location(object), ErrorCode::Location(unknown)];
+    let args = match name {
+        "$literal" => return Ok(Expression::Literal(arg.clone())),
+        "$meta" => {
+            return match arg {
+                Value::String(name) if name == "textScore" => Ok(Expression::TextScore),
+                Value::String(name) => Err(location(
+                    17308,
+                    format!("Unsupported argument to $meta: {}", name),
+                )),
+                _ => Err(location(17307, "$meta only supports string arguments")),
+            };
+        }
+        "$let" => {
+            let args = named_args(name, arg, &["vars", "in"], location_codes(16875, 16874))?;
+            let Value::Document(vars) = required(name, "vars", args[0], 16876)? else {
+                return Err(location(
+                    16876,
+                    "invalid parameter: expected an object (vars)",
+                ));
+            };
+            let mut bindings = Vec::new();
+            for (variable, value) in vars.iter() {
+                validate_variable_name(variable)?;
+                bindings.push((variable.clone(), Expression::parse(value)?));
+            }
+            let body = boxed(required(name, "in", args[1], 16877)?)?;
+            return Ok(Expression::Let(bindings, body));
+        }
+        "$map" => {
+            let args = named_args(
+                name,
+                arg,
+                &["input", "as", "in"],
+                location_codes(16879, 16878),
+            )?;
+            return Ok(Expression::Map {
+                input: boxed(required(name, "input", args[0], 16880)?)?,
+                name: variable_name(name, args[1])?,
+                body: boxed(required(name, "in", args[2], 16882)?)?,
+            });
+        }
+        "$filter" => {
+            let args = named_args(
+                name,
+                arg,
+                &["input", "as", "cond", "limit"],
+                location_codes(28647, 28646),
+            )?;
+            return Ok(Expression::Filter {
+                input: boxed(required(name, "input", args[0], 28648)?)?,
+                name: variable_name(name, args[1])?,
+                cond: boxed(required(name, "cond", args[2], 28650)?)?,
+                limit: args[3].map(boxed).transpose()?,
+            });
+        }
+        "$reduce" => {
+            let args = named_args(
+                name,
+                arg,
+                &["input", "initialValue", "in"],
+                location_codes(40076, 40075),
+            )?;
+            return Ok(Expression::Reduce {
+                input: boxed(required(name, "input", args[0], 40077)?)?,
+                initial: boxed(required(name, "initialValue", args[1], 40078)?)?,
+                body: boxed(required(name, "in", args[2], 40079)?)?,
+            });
+        }
+        "$switch" => return parse_switch(arg),
+        "$getField" if !is_named_spec(arg) => {
+            // The shorthand `{ $getField: "a" }` reads from $$CURRENT
+            return Ok(Expression::Named(
+                name.to_string(),
+                vec![
+                    Some(Expression::parse(arg)?),
+                    Some(Expression::Variable("CURRENT".to_string(), Vec::new())),
+                ],
+            ));
+        }
+        name if date::PARTS.contains(&name) && !is_named_spec(arg) => {
+            // `{ $year: date }` and `{ $year: [date] }` are shorthands for
+            // `{ $year: { date } }`
+            let date = match arg {
+                Value::Array(items) if items.0.len() == 1 => &items.0[0],
+                Value::Array(items) => {
+                    return Err(location(
+                        40536,
+                        format!(
+                            "{} accepts exactly one argument if given an array, but was given {}",
+                            name,
+                            items.0.len()
+                        ),
+                    ));
+                }
+                arg => arg,
+            };
+            return Ok(Expression::Named(
+                name.to_string(),
+                vec![Some(Expression::parse(date)?), None],
+            ));
+        }
+        "$cond" => match arg {
+            Value::Document(spec) => {
+                for key in spec.keys() {
+                    if !matches!(key.as_str(), "if" | "then" | "else") {
+                        return Err(location(
+                            17083,
+                            format!("Unrecognized parameter to $cond: {}", key),
+                        ));
+                    }
+                }
+                let mut args = Vec::new();
+                for (key, code) in [("if", 17080), ("then", 17081), ("else", 17082)] {
+                    match spec.get(key) {
+                        Some(value) => args.push(Expression::parse(value)?),
+                        None => {
+                            return Err(location(
+                                code,
+                                format!("Missing '{}' parameter to $cond", key),
+                            ));
+                        }
+                    }
+                }
+                args
+            }
+            arg => operator_args(arg)?,
+        },
+        _ => {
+            if let Some((_, params, required_count)) = NAMED_OPERATORS
+                .iter()
+                .chain(date::OPERATORS)
+                .find(|(operator, _, _)| *operator == name)
+            {
+                return parse_named(name, arg, params, *required_count);
+            }
+            operator_args(arg)?
+        }
+    };
+
+    if name == "$ifNull" {
+        if args.len() < 2 {
+            return Err(location(
+                1257300,
+                format!("$ifNull needs at least two arguments, had: {}", args.len()),
+            ));
+        }
+        return Ok(Expression::Operator(name.to_string(), args));
+    }
+    let arity = [
+        OPERATORS,
+        math::OPERATORS,
+        string::OPERATORS,
+        array::OPERATORS,
+        convert::OPERATORS,
+        object::OPERATORS,
+    ]
+    .into_iter()
+    .find_map(|table| find_operator(table, name));
+    let Some(arity) = arity else {
+        return Err(CommandError::new(
+            ErrorCode::InvalidPipelineOperator,
+            format!("Unrecognized expression '{}'", name),
+        ));
+    };
+    expect_args(name, &args, arity)?;
+    Ok(Expression::Operator(name.to_string(), args))
+}
+
+/// Whether an operator argument is a document of named arguments rather
+/// than an expression.
+fn is_named_spec(arg: &Value) -> bool {
+    matches!(arg, Value::Document(spec) if spec.keys().all(|key| !key.starts_with('$')))
+}
+
+fn parse_named(
+    name: &str,
+    arg: &Value,
+    params: &[&str],
+    required_count: usize,
+) -> CommandResult<Expression> {
+    let Value::Document(spec) = arg else {
+        return Err(CommandError::new(
+            ErrorCode::FailedToParse,
+            format!(
+                "{} expects an object of named arguments but found: {}",
+                name,
+                arg.type_name()
+            ),
+        ));
+    };
+    if let Some(key) = spec.keys().find(|key| !params.contains(&key.as_str())) {
+        return Err(CommandError::new(
+            ErrorCode::FailedToParse,
+            format!("{} found an unknown argument: {}", name, key),
+        ));
+    }
+    let mut args = Vec::new();
+    for (i, param) in params.iter().enumerate() {
+        match spec.get(param) {
+            Some(value) => args.push(Some(Expression::parse(value)?)),
+            None if i < required_count => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    format!("Missing '{}' parameter to {}", param, name),
+                ));
+            }
+            None => args.push(None),
+        }
+    }
+    if name == "$getField" && args[1].is_none() {
+        args[1] = Some(Expression::Variable("CURRENT".to_string(), Vec::new()));
+    }
+    Ok(Expression::Named(name.to_string(), args))
+}
+
+fn parse_switch(arg: &Value) -> CommandResult<Expression> {
+    let Value::Document(spec) = arg else {
+        return Err(location(
+            40060,
+            format!(
+                "$switch requires an object as an argument, found: {}",
+                arg.type_name()
+            ),
+        ));
+    };
+    let mut branches = Vec::new();
+    let mut default = None;
+    for (key, value) in spec.iter() {
+        match key.as_str() {
+            "branches" => {
+                let Value::Array(items) = value else {
+                    return Err(location(
+                        40061,
+                        format!(
+                            "$switch expected an array for 'branches', found: {}",
+                            value.type_name()
+                        ),
+                    ));
+                };
+                for branch in &items.0 {
+                    let Value::Document(branch) = branch else {
+                        return Err(location(
+                            40062,
+                            format!(
+                                "$switch expected each branch to be an object, found: {}",
+                                branch.type_name()
+                            ),
+                        ));
+                    };
+                    if let Some(key) = branch
+                        .keys()
+                        .find(|key| !matches!(key.as_str(), "case" | "then"))
+                    {
+                        return Err(location(
+                            40063,
+                            format!("$switch found an unknown argument to a branch: {}", key),
+                        ));
+                    }
+                    let Some(case) = branch.get("case") else {
+                        return Err(location(
+                            40064,
+                            "$switch requires each branch have a 'case' expression",
+                        ));
+                    };
+                    let Some(then) = branch.get("then") else {
+                        return Err(location(
+                            40065,
+                            "$switch requires each branch have a 'then' expression.",
+                        ));
+                    };
+                    branches.push((Expression::parse(case)?, Expression::parse(then)?));
+                }
+            }
+            "default" => default = Some(boxed(value)?),
+            key => {
+                return Err(location(
+                    40067,
+                    format!("$switch found an unknown argument: {}", key),
+                ));
+            }
+        }
+    }
+    if branches.is_empty() {
+        return Err(location(40068, "$switch requires at least one branch."));
+    }
+    Ok(Expression::Switch { branches, default })
+}
+
+fn evaluate_operator(
+    name: &str,
+    args: &[Expression],
+    vars: &Variables,
+) -> CommandResult<Option<Value>> {
+    let value = match name {
+        "$cond" => {
+            let branch = if args[0].eval(vars)?.is_truthy() {
+                &args[1]
+            } else {
+                &args[2]
+            };
+            return branch.evaluate(vars);
+        }
+        "$ifNull" => {
+            let (replacement, values) = args.split_last().expect("at least two arguments");
+            for value in values {
+                match value.evaluate(vars)? {
+                    Some(value) if !value.is_null_or_undefined() => return Ok(Some(value)),
+                    _ => {}
+                }
+            }
+            return replacement.evaluate(vars);
+        }
+        "$and" => {
+            for arg in args {
+                if !arg.eval(vars)?.is_truthy() {
+                    return Ok(Some(Value::Boolean(false)));
+                }
+            }
+            Value::Boolean(true)
+        }
+        "$or" => {
+            for arg in args {
+                if arg.eval(vars)?.is_truthy() {
+                    return Ok(Some(Value::Boolean(true)));
+                }
+            }
+            Value::Boolean(false)
+        }
+        "$not" => Value::Boolean(!args[0].eval(vars)?.is_truthy()),
+        "$eq" | "$ne" | "$gt" | "$gte" | "$lt" | "$lte" | "$cmp" => {
+            // A missing value sorts before null
+            let a = args[0].evaluate(vars)?.unwrap_or(Value::Undefined);
+            let b = args[1].evaluate(vars)?.unwrap_or(Value::Undefined);
+            let ordering = a.compare(&b);
+            match name {
+                "$eq" => Value::Boolean(ordering == Ordering::Equal),
+                "$ne" => Value::Boolean(ordering != Ordering::Equal),
+                "$gt" => Value::Boolean(ordering == Ordering::Greater),
+                "$gte" => Value::Boolean(ordering != Ordering::Less),
+                "$lt" => Value::Boolean(ordering == Ordering::Less),
+                "$lte" => Value::Boolean(ordering != Ordering::Greater),
+                _ => Value::Int32(ordering as i32),
+            }
+        }
+        "$type" => Value::from(
+            args[0]
+                .evaluate(vars)?
+                .as_ref()
+                .map_or("missing", Value::type_name),
+        ),
+        name => {
+            let values = eval_all(args, vars)?;
+            let evaluate = if find_operator(math::OPERATORS, name).is_some() {
+                math::evaluate
+            } else if find_operator(string::OPERATORS, name).is_some() {
+                string::evaluate
+            } else if find_operator(array::OPERATORS, name).is_some() {
+                array::evaluate
+            } else if find_operator(convert::OPERATORS, name).is_some() {
+                convert::evaluate
+            } else {
+                object::evaluate
+            };
+            return evaluate(name, &values);
+        }
+    };
+    Ok(Some(value))
+}
+
+fn eval_all(args: &[Expression], vars: &Variables) -> CommandResult<Vec<Value>> {
+    args.iter().map(|arg| arg.eval(vars)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    fn evaluate(expression: &str, document: &str) -> CommandResult<Option<Value>> {
+        let document = doc(document);
+        Expression::parse(&value(expression))?.evaluate(&Variables::new(&document))
+    }
+
+    #[test]
+    fn test_field_paths_and_variables() {
+        assert_eq!(
+            evaluate("'$a.b'", "{ a: { b: 1 } }").unwrap(),
+            Some(Value::Int32(1))
+        );
+        assert_eq!(
+            evaluate("'$a.b'", "{ a: [{ b: 1 }, { c: 2 }, { b: 3 }] }").unwrap(),
+            Some(value("[1, 3]"))
+        );
+        assert_eq!(evaluate("'$missing'", "{ a: 1 }").unwrap(), None);
+        assert_eq!(evaluate("'$$REMOVE'", "{ a: 1 }").unwrap(), None);
+        assert_eq!(
+            evaluate("'$$ROOT.a'", "{ a: 1 }").unwrap(),
+            Some(Value::Int32(1))
+        );
+        assert!(evaluate("'$$nope'", "{}").is_err());
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(
+            evaluate("{ $add: ['$a', 2, 0.5] }", "{ a: 1 }").unwrap(),
+            Some(Value::Double(3.5))
+        );
+        assert_eq!(
+            evaluate("{ $add: ['$a', 1] }", "{ a: 2147483647 }").unwrap(),
+            Some(Value::Int64(2147483648))
+        );
+        assert_eq!(
+            evaluate(
+                "{ $cond: { if: { $gte: ['$a', 5] }, then: 'big', else: 'small' } }",
+                "{ a: 7 }"
+            )
+            .unwrap(),
+            Some(Value::from("big"))
+        );
+        assert_eq!(
+            evaluate("{ $ifNull: ['$x', '$y', 'default'] }", "{ y: null }").unwrap(),
+            Some(Value::from("default"))
+        );
+        assert_eq!(
+            evaluate("{ $concat: ['$a', '-', { $literal: '$b' }] }", "{ a: 'x' }").unwrap(),
+            Some(Value::from("x-$b"))
+        );
+        assert_eq!(
+            evaluate(
+                "{ $mergeObjects: ['$a', { c: 3 }] }",
+                "{ a: { b: 1, c: 2 } }"
+            )
+            .unwrap(),
+            Some(value("{ b: 1, c: 3 }"))
+        );
+        assert_eq!(
+            evaluate("{ $eq: ['$missing', null] }", "{}").unwrap(),
+            Some(Value::Boolean(false))
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let code = |expression, document| evaluate(expression, document).unwrap_err().code;
+        assert_eq!(
+            code("{ $foo: 1 }", "{}"),
+            ErrorCode::InvalidPipelineOperator
+        );
+        assert_eq!(
+            code("{ $add: ['$a', 1] }", "{ a: 'x' }"),
+            ErrorCode::Location(16554)
+        );
+        assert_eq!(
+            code("{ $divide: [1, 0] }", "{}"),
+            ErrorCode::Location(16608)
+        );
+        assert_eq!(code("{ $subtract: [1] }", "{}"), ErrorCode::Location(16020));
+        assert_eq!(
+            code("{ $let: { vars: { Bad: 1 }, in: 1 } }", "{}"),
+            ErrorCode::Location(16867)
+        );
+        assert_eq!(
+            code("{ $map: { input: '$a', in: 1 } }", "{ a: 1 }"),
+            ErrorCode::Location(16883)
+        );
+        assert_eq!(
+            code(
+                "{ $switch: { branches: [{ case: false, then: 1 }] } }",
+                "{}"
+            ),
+            ErrorCode::Location(40066)
+        );
+        assert_eq!(
+            code("{ $toInt: 'abc' }", "{}"),
+            ErrorCode::ConversionFailure
+        );
+        assert_eq!(
+            code("{ $convert: { input: 1 } }", "{}"),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(
+            code("{ $regexMatch: { input: 'a', regex: '(' } }", "{}"),
+            ErrorCode::Location(51111)
+        );
+        assert_eq!(code("{ $size: 1 }", "{}"), ErrorCode::Location(17124));
+    }
+
+    #[test]
+    fn test_scoped_variables() {
+        assert_eq!(
+            evaluate(
+                "{ $let: { vars: { x: '$a', y: 2 }, in: { $multiply: ['$$x', '$$y'] } } }",
+                "{ a: 3 }"
+            )
+            .unwrap(),
+            Some(Value::Int32(6))
+        );
+        assert_eq!(
+            evaluate(
+                "{ $map: { input: '$a', as: 'n', in: { $add: ['$$n', '$b'] } } }",
+                "{ a: [1, 2], b: 10 }"
+            )
+            .unwrap(),
+            Some(value("[11, 12]"))
+        );
+        assert_eq!(
+            evaluate(
+                "{ $filter: { input: '$a', cond: { $gt: ['$$this', 1] }, limit: 2 } }",
+                "{ a: [1, 2, 3, 4] }"
+            )
+            .unwrap(),
+            Some(value("[2, 3]"))
+        );
+        assert_eq!(
+            evaluate(
+                "{ $reduce: { input: '$a', initialValue: '', in: { $concat: ['$$value', '$$this'] } } }",
+                "{ a: ['x', 'y', 'z'] }"
+            )
+            .unwrap(),
+            Some(Value::from("xyz"))
+        );
+        assert_eq!(
+            evaluate(
+                "{ $switch: { branches: [{ case: { $lt: ['$a', 0] }, then: 'neg' }], default: 'pos' } }",
+                "{ a: 1 }"
+            )
+            .unwrap(),
+            Some(Value::from("pos"))
+        );
+        assert_eq!(
+            evaluate("{ $map: { input: null, in: 1 } }", "{}").unwrap(),
+            Some(Value::Null)
+        );
+    }
+
+    #[test]
+    fn test_string_and_array_operators() {
+        let cases = [
+            ("{ $substrCP: ['héllo', 1, 3] }", "'éll'"),
+            ("{ $strLenCP: 'héllo' }", "5"),
+            ("{ $strLenBytes: 'héllo' }", "6"),
+            ("{ $toUpper: 'abc' }", "'ABC'"),
+            ("{ $split: ['a,b,c', ','] }", "['a', 'b', 'c']"),
+            ("{ $indexOfCP: ['héllo', 'l'] }", "2"),
+            ("{ $trim: { input: '  x  ' } }", "'x'"),
+            (
+                "{ $regexMatch: { input: 'Cat', regex: '^c', options: 'i' } }",
+                "true",
+            ),
+            (
+                "{ $regexFind: { input: 'a1b22', regex: '([a-z])([0-9]+)' } }",
+                "{ match: 'a1', idx: 0, captures: ['a', '1'] }",
+            ),
+            (
+                "{ $replaceAll: { input: 'aXbX', find: 'X', replacement: '-' } }",
+                "'a-b-'",
+            ),
+            ("{ $arrayElemAt: [[1, 2, 3], -1] }", "3"),
+            ("{ $size: [[1, 2, 3]] }", "3"),
+            ("{ $slice: [[1, 2, 3, 4], 1, 2] }", "[2, 3]"),
+            ("{ $slice: [[1, 2, 3, 4], -2] }", "[3, 4]"),
+            ("{ $range: [0, 10, 3] }", "[0, 3, 6, 9]"),
+            ("{ $in: [2, [1, 2]] }", "true"),
+            ("{ $reverseArray: [[1, 2]] }", "[2, 1]"),
+            ("{ $setUnion: [[1, 2], [2, 3]] }", "[1, 2, 3]"),
+            ("{ $setIsSubset: [[1], [1, 2]] }", "true"),
+            (
+                "{ $arrayToObject: [[['a', 1], ['b', 2]]] }",
+                "{ a: 1, b: 2 }",
+            ),
+            ("{ $objectToArray: { a: 1 } }", "[{ k: 'a', v: 1 }]"),
+            ("{ $getField: 'a.b' }", "1"),
+            (
+                "{ $setField: { field: 'c', input: { a: 1 }, value: 2 } }",
+                "{ a: 1, c: 2 }",
+            ),
+            ("{ $sum: [[1, 2, 'x', 3]] }", "6"),
+            ("{ $max: [1, 5, null] }", "5"),
+        ];
+        let document = "{ 'a.b': 1 }";
+        for (expression, expected) in cases {
+            assert_eq!(
+                evaluate(expression, document).unwrap(),
+                Some(value(expected)),
+                "{}",
+                expression
+            );
+        }
+        assert_eq!(evaluate("{ $arrayElemAt: [[1], 5] }", "{}").unwrap(), None);
+        assert_eq!(
+            evaluate("{ $range: [-2147483648, 2147483647] }", "{}")
+                .unwrap_err()
+                .code,
+            ErrorCode::Location(5138000)
+        );
+        assert_eq!(
+            evaluate("{ $range: [10, 0, -4] }", "{}").unwrap(),
+            Some(value("[10, 6, 2]"))
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_and_conversion() {
+        let cases = [
+            ("{ $mod: [7, -3] }", "1"),
+            ("{ $abs: -2.5 }", "2.5"),
+            ("{ $round: [2.5, 0] }", "2.0"),
+            ("{ $round: [1234, -2] }", "1200"),
+            ("{ $trunc: [1.789, 1] }", "1.7"),
+            ("{ $pow: [2, 10] }", "1024"),
+            ("{ $pow: [2, -1] }", "0.5"),
+            ("{ $sqrt: 16 }", "4.0"),
+            ("{ $toInt: '42' }", "42"),
+            ("{ $toInt: 4.9 }", "4"),
+            ("{ $toDouble: '1.5' }", "1.5"),
+            ("{ $toString: 2.0 }", "'2'"),
+            (
+                "{ $toString: Date(86400000) }",
+                "'1970-01-02T00:00:00.000Z'",
+            ),
+            ("{ $toDate: '1970-01-02T00:00:00Z' }", "Date(86400000)"),
+            ("{ $toBool: 0 }", "false"),
+            ("{ $convert: { input: 'x', to: 'int', onError: -1 } }", "-1"),
+            ("{ $convert: { input: null, to: 'int', onNull: 0 } }", "0"),
+            ("{ $type: '$missing' }", "'missing'"),
+            ("{ $type: 1 }", "'int'"),
+            ("{ $isNumber: '1' }", "false"),
+        ];
+        for (expression, expected) in cases {
+            assert_eq!(
+                evaluate(expression, "{}").unwrap(),
+                Some(value(expected)),
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn test_date_operators() {
+        // 2021-03-14T06:30:15.250Z, a Sunday
+        let document = "{ d: Date(1615703415250), old: Date(-86400000) }";
+        let cases = [
+            ("{ $year: '$d' }", "2021"),
+            ("{ $month: ['$d'] }", "3"),
+            ("{ $dayOfWeek: '$d' }", "1"),
+            ("{ $isoDayOfWeek: '$d' }", "7"),
+            ("{ $isoWeek: '$d' }", "10"),
+            ("{ $week: '$d' }", "11"),
+            ("{ $millisecond: '$d' }", "250"),
+            ("{ $hour: { date: '$d', timezone: 'America/New_York' } }", "1"),
+            ("{ $hour: { date: '$d', timezone: '+05:30' } }", "12"),
+            ("{ $year: '$old' }", "1969"),
+            ("{ $dayOfMonth: '$old' }", "31"),
+            ("{ $toString: '$old' }", "'1969-12-31T00:00:00.000Z'"),
+            (
+                "{ $dateToString: { date: '$d', format: '%Y/%m/%d %H:%M %z', timezone: 'Asia/Kolkata' } }",
+                "'2021/03/14 12:00 +0530'",
+            ),
+            ("{ $dateToString: { date: null, onNull: 'none' } }", "'none'"),
+            (
+                "{ $dateFromString: { dateString: '2021-03-14T01:30:15.250', timezone: 'America/New_York' } }",
+                "Date(1615703415250)",
+            ),
+            (
+                "{ $dateFromString: { dateString: '14/03/2021', format: '%d/%m/%Y' } }",
+                "Date(1615680000000)",
+            ),
+            (
+                "{ $dateFromString: { dateString: 'soon', onError: 'bad' } }",
+                "'bad'",
+            ),
+            (
+                "{ $dateFromParts: { year: 1969, month: 13, day: 0 } }",
+                "Date(-86400000)",
+            ),
+            (
+                "{ $dateToParts: { date: '$old' } }",
+                "{ year: 1969, month: 12, day: 31, hour: 0, minute: 0, second: 0, millisecond: 0 }",
+            ),
+            (
+                "{ $dateAdd: { startDate: Date(1612051200000), unit: 'month', amount: 1 } }",
+                "Date(1614470400000)",
+            ),
+            (
+                "{ $dateAdd: { startDate: '$d', unit: 'day', amount: 1, timezone: 'America/New_York' } }",
+                "Date(1615786215250)",
+            ),
+            (
+                "{ $dateSubtract: { startDate: '$old', unit: 'year', amount: 1 } }",
+                "Date(-31622400000)",
+            ),
+            (
+                "{ $dateDiff: { startDate: '$old', endDate: '$d', unit: 'year' } }",
+                "Long(52)",
+            ),
+            (
+                "{ $dateDiff: { startDate: Date(1615593600000), endDate: '$d', unit: 'week' } }",
+                "Long(1)",
+            ),
+            (
+                "{ $dateTrunc: { date: '$d', unit: 'hour', binSize: 2 } }",
+                "Date(1615701600000)",
+            ),
+            (
+                "{ $dateTrunc: { date: '$d', unit: 'week', startOfWeek: 'monday' } }",
+                "Date(1615161600000)",
+            ),
+            ("{ $year: { date: '$d', timezone: null } }", "null"),
+        ];
+        for (expression, expected) in cases {
+            assert_eq!(
+                evaluate(expression, document).unwrap(),
+                Some(value(expected)),
+                "{}",
+                expression
+            );
+        }
+
+        let errors = [
+            ("{ $year: 'x' }", 16006),
+            ("{ $year: ['$d', '$d'] }", 40536),
+            ("{ $year: { date: '$d', timezone: 'Mars/Olympus' } }", 40485),
+            ("{ $dateToString: { date: '$d', format: '%Q' } }", 18536),
+            ("{ $dateFromParts: { year: 2021, isoWeek: 1 } }", 40489),
+            ("{ $dateFromParts: { year: 10000 } }", 40523),
+            (
+                "{ $dateTrunc: { date: '$d', unit: 'day', binSize: 0 } }",
+                5439017,
+            ),
+        ];
+        for (expression, code) in errors {
+            let error = evaluate(expression, document).unwrap_err();
+            assert_eq!(error.code, ErrorCode::Location(code), "{}", expression);
+        }
+        let error = evaluate("{ $dateFromString: { dateString: 'soon' } }", document).unwrap_err();
+        assert_eq!(error.code, ErrorCode::ConversionFailure);
+    }
+}
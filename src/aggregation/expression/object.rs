@@ -0,0 +1,198 @@
+//! Object operators: `$mergeObjects`, `$objectToArray`, `$arrayToObject`
+//! and the field accessors `$getField`, `$setField` and `$unsetField`.
+
+use crate::{
+    bson::{Document, Value},
+    error::CommandResult,
+};
+
+use super::location;
+
+/// Name, minimum and maximum number of arguments.
+pub const OPERATORS: &[(&str, usize, usize)] = &[
+    ("$mergeObjects", 0, usize::MAX),
+    ("$objectToArray", 1, 1),
+    ("$arrayToObject", 1, 1),
+];
+
+pub fn evaluate(name: &str, args: &[Value]) -> CommandResult<Option<Value>> {
+    let value = match name {
+        "$mergeObjects" => {
+            let mut merged = Document::new();
+            for value in args {
+                match value {
+                    Value::Document(doc) => {
+                        for (key, value) in doc.iter() {
+                            merged.insert(key.clone(), value.clone());
+                        }
+                    }
+                    Value::Null | Value::Undefined => {}
+                    value => {
+                        return Err(location(
+                            40400,
+                            format!(
+                                "$mergeObjects requires object inputs, but input {} is of type {}",
+                                value,
+                                value.type_name()
+                            ),
+                        ));
+                    }
+                }
+            }
+            Value::Document(merged)
+        }
+        "$objectToArray" => match &args[0] {
+            value if value.is_null_or_undefined() => Value::Null,
+            Value::Document(doc) => Value::from(
+                doc.iter()
+                    .map(|(key, value)| {
+                        let mut pair = Document::new();
+                        pair.insert("k", Value::from(key.as_str()));
+                        pair.insert("v", value.clone());
+                        Value::Document(pair)
+                    })
+                    .collect::<Vec<_>>(),
+            ),
+            value => {
+                return Err(location(
+                    40390,
+                    format!(
+                        "$objectToArray requires a document input, found: {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        },
+        "$arrayToObject" => array_to_object(&args[0])?,
+        name => unreachable!("operator {} is not an object operator", name),
+    };
+    Ok(Some(value))
+}
+
+/// Accepts either `[[k, v], ...]` or `[{ k, v }, ...]`, but not a mix.
+fn array_to_object(value: &Value) -> CommandResult<Value> {
+    let items = match value {
+        value if value.is_null_or_undefined() => return Ok(Value::Null),
+        Value::Array(items) => &items.0,
+        value => {
+            return Err(location(
+                40386,
+                format!(
+                    "$arrayToObject requires an array input, found: {}",
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    let pairs_of_arrays = matches!(items.first(), Some(Value::Array(_)));
+    let mut result = Document::new();
+    for item in items {
+        let (key, value) = match item {
+            Value::Array(pair) if pairs_of_arrays => {
+                let [key, value] = &pair.0[..] else {
+                    return Err(location(
+                        40397,
+                        format!(
+                            "$arrayToObject requires an array of size 2 arrays,found array of size: {}",
+                            pair.0.len()
+                        ),
+                    ));
+                };
+                (key, value)
+            }
+            Value::Document(pair) if !pairs_of_arrays => {
+                if pair.len() != 2 {
+                    return Err(location(
+                        40392,
+                        format!(
+                            "$arrayToObject requires an object keys of 'k' and 'v'. Found incorrect number of keys:{}",
+                            pair.len()
+                        ),
+                    ));
+                }
+                let (Some(key), Some(value)) = (pair.get("k"), pair.get("v")) else {
+                    return Err(location(
+                        40393,
+                        format!(
+                            "$arrayToObject requires an object with keys 'k' and 'v'. Missing either or both keys from: {}",
+                            pair
+                        ),
+                    ));
+                };
+                (key, value)
+            }
+            Value::Array(_) | Value::Document(_) => {
+                return Err(location(
+                    40391,
+                    "$arrayToObject requires a consistent input format. Elements must all be arrays or all be objects",
+                ));
+            }
+            item => {
+                return Err(location(
+                    40398,
+                    format!(
+                        "Unrecognised input type format for $arrayToObject: {}",
+                        item.type_name()
+                    ),
+                ));
+            }
+        };
+        let Value::String(key) = key else {
+            return Err(location(
+                40394,
+                format!(
+                    "$arrayToObject requires a key of type string, found: {}",
+                    key.type_name()
+                ),
+            ));
+        };
+        result.insert(key.clone(), value.clone());
+    }
+    Ok(Value::Document(result))
+}
+
+/// `$getField: { field, input }`, `$setField: { field, input, value }` and
+/// `$unsetField: { field, input }`, with the arguments in those orders. A
+/// missing `value` removes the field.
+pub fn evaluate_named(name: &str, args: &[Option<Value>]) -> CommandResult<Option<Value>> {
+    let field = match &args[0] {
+        Some(Value::String(field)) => field,
+        field => {
+            return Err(location(
+                3041704,
+                format!(
+                    "{} requires 'field' to evaluate to type String, but got {}",
+                    name,
+                    field.as_ref().map_or("missing", Value::type_name)
+                ),
+            ));
+        }
+    };
+    let input = match &args[1] {
+        None | Some(Value::Null | Value::Undefined) => return Ok(Some(Value::Null)),
+        Some(Value::Document(input)) => input,
+        Some(value) => {
+            return Err(location(
+                3041705,
+                format!(
+                    "{} requires 'input' to evaluate to type Object, but got {}",
+                    name,
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    if name == "$getField" {
+        return Ok(input.get(field).cloned());
+    }
+    let mut result = input.clone();
+    match args.get(2).cloned().flatten() {
+        Some(value) => {
+            result.insert(field.clone(), value);
+        }
+        None => {
+            result.remove(field);
+        }
+    }
+    Ok(Some(Value::Document(result)))
+}
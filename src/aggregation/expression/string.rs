@@ -0,0 +1,509 @@
+//! String operators: `$concat`, `$substrCP`, `$split`, `$toUpper`, the
+//! `$regex*` family and the rest.
+
+use std::cmp::Ordering;
+
+use regex::Regex;
+
+use crate::{
+    bson::{Array, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{convert::coerce_to_string, location};
+
+/// Name, minimum and maximum number of arguments.
+pub const OPERATORS: &[(&str, usize, usize)] = &[
+    ("$concat", 0, usize::MAX),
+    ("$substr", 3, 3),
+    ("$substrBytes", 3, 3),
+    ("$substrCP", 3, 3),
+    ("$strLenBytes", 1, 1),
+    ("$strLenCP", 1, 1),
+    ("$toUpper", 1, 1),
+    ("$toLower", 1, 1),
+    ("$split", 2, 2),
+    ("$indexOfBytes", 2, 4),
+    ("$indexOfCP", 2, 4),
+    ("$strcasecmp", 2, 2),
+];
+
+pub fn evaluate(name: &str, args: &[Value]) -> CommandResult<Option<Value>> {
+    let value = match name {
+        "$concat" => {
+            let mut result = String::new();
+            for value in args {
+                match value {
+                    Value::String(s) => result.push_str(s),
+                    Value::Null | Value::Undefined => return Ok(Some(Value::Null)),
+                    value => {
+                        return Err(location(
+                            16702,
+                            format!("$concat only supports strings, not {}", value.type_name()),
+                        ));
+                    }
+                }
+            }
+            Value::String(result)
+        }
+        "$substr" | "$substrBytes" => substr_bytes(name, args)?,
+        "$substrCP" => substr_cp(args)?,
+        "$strLenBytes" | "$strLenCP" => {
+            let Value::String(s) = &args[0] else {
+                let code = if name == "$strLenCP" { 34471 } else { 34473 };
+                return Err(location(
+                    code,
+                    format!(
+                        "{} requires a string argument, found: {}",
+                        name,
+                        args[0].type_name()
+                    ),
+                ));
+            };
+            let length = match name {
+                "$strLenCP" => s.chars().count(),
+                _ => s.len(),
+            };
+            Value::Int32(length as i32)
+        }
+        "$toUpper" => Value::String(coerce(name, &args[0])?.to_uppercase()),
+        "$toLower" => Value::String(coerce(name, &args[0])?.to_lowercase()),
+        "$split" => split(&args[0], &args[1])?,
+        "$indexOfBytes" | "$indexOfCP" => index_of(name, args)?,
+        "$strcasecmp" => {
+            let a = coerce(name, &args[0])?.to_lowercase();
+            let b = coerce(name, &args[1])?.to_lowercase();
+            Value::Int32(match a.cmp(&b) {
+                Ordering::Less => -1,
+                Ordering::Equal => 0,
+                Ordering::Greater => 1,
+            })
+        }
+        name => unreachable!("operator {} is not a string operator", name),
+    };
+    Ok(Some(value))
+}
+
+/// The operators taking named arguments, given in the order of their
+/// parameter lists.
+pub fn evaluate_named(name: &str, args: &[Option<Value>]) -> CommandResult<Option<Value>> {
+    match name {
+        "$trim" | "$ltrim" | "$rtrim" => trim(name, &args[0], &args[1]),
+        "$regexMatch" | "$regexFind" | "$regexFindAll" => regex(name, &args[0], &args[1], &args[2]),
+        "$replaceOne" | "$replaceAll" => replace(name, &args[0], &args[1], &args[2]),
+        name => unreachable!("operator {} is not a string operator", name),
+    }
+}
+
+fn coerce(name: &str, value: &Value) -> CommandResult<String> {
+    coerce_to_string(value).ok_or_else(|| {
+        location(
+            16007,
+            format!(
+                "can't convert from BSON type {} to String in {}",
+                value.type_name(),
+                name
+            ),
+        )
+    })
+}
+
+/// A non-negative integral argument such as a starting index or length.
+fn index_arg(name: &str, what: &str, value: &Value, code: i32) -> CommandResult<i64> {
+    match value.as_i64() {
+        Some(index) if value.is_number() => Ok(index),
+        _ => Err(location(
+            code,
+            format!(
+                "{}: {} must be a numeric type (is BSON type {})",
+                name,
+                what,
+                value.type_name()
+            ),
+        )),
+    }
+}
+
+fn substr_bytes(name: &str, args: &[Value]) -> CommandResult<Value> {
+    let s = coerce(name, &args[0])?;
+    let start = index_arg(name, "starting index", &args[1], 16034)?;
+    let length = index_arg(name, "length", &args[2], 16035)?;
+    if start < 0 {
+        return Err(location(
+            50752,
+            format!(
+                "{}: starting index must be non-negative (got: {})",
+                name, start
+            ),
+        ));
+    }
+    let start = (start as usize).min(s.len());
+    // A negative length takes the rest of the string
+    let end = match usize::try_from(length) {
+        Ok(length) => start.saturating_add(length).min(s.len()),
+        Err(_) => s.len(),
+    };
+    if !s.is_char_boundary(start) {
+        return Err(location(
+            28656,
+            format!(
+                "{}:  Invalid range, starting index is a UTF-8 continuation byte.",
+                name
+            ),
+        ));
+    }
+    if !s.is_char_boundary(end) {
+        return Err(location(
+            28657,
+            format!(
+                "{}:  Invalid range, ending index is in the middle of a UTF-8 character.",
+                name
+            ),
+        ));
+    }
+    Ok(Value::String(s[start..end].to_string()))
+}
+
+fn substr_cp(args: &[Value]) -> CommandResult<Value> {
+    let name = "$substrCP";
+    let s = coerce(name, &args[0])?;
+    let start = index_arg(name, "starting index", &args[1], 34450)?;
+    let length = index_arg(name, "length", &args[2], 34451)?;
+    if start < 0 {
+        return Err(location(
+            34455,
+            "$substrCP: starting index must be non-negative",
+        ));
+    }
+    if length < 0 {
+        return Err(location(
+            34456,
+            "$substrCP: length must be a nonnegative integer.",
+        ));
+    }
+    Ok(Value::String(
+        s.chars()
+            .skip(start as usize)
+            .take(length as usize)
+            .collect(),
+    ))
+}
+
+fn split(input: &Value, delimiter: &Value) -> CommandResult<Value> {
+    if input.is_null_or_undefined() || delimiter.is_null_or_undefined() {
+        return Ok(Value::Null);
+    }
+    let Value::String(input) = input else {
+        return Err(location(
+            40085,
+            format!(
+                "$split requires an expression that evaluates to a string as a first argument, found: {}",
+                input.type_name()
+            ),
+        ));
+    };
+    let Value::String(delimiter) = delimiter else {
+        return Err(location(
+            40086,
+            format!(
+                "$split requires an expression that evaluates to a string as a second argument, found: {}",
+                delimiter.type_name()
+            ),
+        ));
+    };
+    if delimiter.is_empty() {
+        return Err(location(40087, "$split requires a non-empty separator"));
+    }
+    Ok(Value::from(
+        input
+            .split(delimiter.as_str())
+            .map(Value::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// `$indexOfBytes` and `$indexOfCP`: the position of a substring, optionally
+/// searching only between a start and end index.
+fn index_of(name: &str, args: &[Value]) -> CommandResult<Value> {
+    let by_code_point = name == "$indexOfCP";
+    let (string_code, substring_code, index_code) = if by_code_point {
+        (40093, 40094, 40096)
+    } else {
+        (40091, 40092, 40096)
+    };
+    let s = match &args[0] {
+        Value::String(s) => s,
+        value if value.is_null_or_undefined() => return Ok(Value::Null),
+        value => {
+            return Err(location(
+                string_code,
+                format!(
+                    "{} requires a string as the first argument, found: {}",
+                    name,
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    let Value::String(needle) = &args[1] else {
+        return Err(location(
+            substring_code,
+            format!(
+                "{} requires a string as the second argument, found: {}",
+                name,
+                args[1].type_name()
+            ),
+        ));
+    };
+
+    // Work in units of code points or bytes alike
+    let haystack: Vec<&str> = if by_code_point {
+        s.char_indices()
+            .map(|(i, c)| &s[i..i + c.len_utf8()])
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let length = if by_code_point {
+        haystack.len()
+    } else {
+        s.len()
+    };
+    let mut bounds = [0, length];
+    for (bound, value) in bounds.iter_mut().zip(&args[2..]) {
+        let index = match value.as_i64() {
+            Some(index) if value.is_number() => index,
+            _ => {
+                return Err(location(
+                    index_code,
+                    format!(
+                        "{} requires an integral starting index, found a value of type: {}, with value: {}",
+                        name,
+                        value.type_name(),
+                        value
+                    ),
+                ));
+            }
+        };
+        if index < 0 {
+            return Err(location(
+                40097,
+                format!(
+                    "{} requires a nonnegative start index, found: {}",
+                    name, index
+                ),
+            ));
+        }
+        *bound = (index as usize).min(length);
+    }
+    let [start, end] = bounds;
+
+    let found = if by_code_point {
+        let needle: Vec<&str> = needle
+            .char_indices()
+            .map(|(i, c)| &needle[i..i + c.len_utf8()])
+            .collect();
+        (start..=end.saturating_sub(needle.len()))
+            .filter(|_| start + needle.len() <= end)
+            .find(|&i| haystack[i..i + needle.len()] == needle[..])
+    } else {
+        let bytes = s.as_bytes();
+        let needle = needle.as_bytes();
+        (start..=end.saturating_sub(needle.len()))
+            .filter(|_| start + needle.len() <= end)
+            .find(|&i| &bytes[i..i + needle.len()] == needle)
+    };
+    Ok(Value::Int32(found.map_or(-1, |i| i as i32)))
+}
+
+fn trim(name: &str, input: &Option<Value>, chars: &Option<Value>) -> CommandResult<Option<Value>> {
+    let input = match input {
+        None | Some(Value::Null | Value::Undefined) => return Ok(Some(Value::Null)),
+        Some(Value::String(s)) => s,
+        Some(value) => {
+            return Err(location(
+                50699,
+                format!(
+                    "{} requires its input to be a string, got {} (of type {}) instead.",
+                    name,
+                    value,
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    let chars: Option<Vec<char>> = match chars {
+        None => None,
+        Some(Value::Null | Value::Undefined) => return Ok(Some(Value::Null)),
+        Some(Value::String(chars)) => Some(chars.chars().collect()),
+        Some(value) => {
+            return Err(location(
+                50700,
+                format!(
+                    "{} requires 'chars' to be a string, got {} (of type {}) instead.",
+                    name,
+                    value,
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    let trimmed = |c: char| match &chars {
+        Some(chars) => chars.contains(&c),
+        None => c == '\0' || c.is_whitespace(),
+    };
+    let result = match name {
+        "$ltrim" => input.trim_start_matches(trimmed),
+        "$rtrim" => input.trim_end_matches(trimmed),
+        _ => input.trim_matches(trimmed),
+    };
+    Ok(Some(Value::from(result)))
+}
+
+/// Compiles the `regex` and `options` arguments of a `$regex*` operator,
+/// returning `None` when the regex is null.
+fn compile(
+    name: &str,
+    regex: &Option<Value>,
+    options: &Option<Value>,
+) -> CommandResult<Option<Regex>> {
+    let (pattern, mut flags) = match regex {
+        None | Some(Value::Null | Value::Undefined) => return Ok(None),
+        Some(Value::String(pattern)) => (pattern.as_str(), String::new()),
+        Some(Value::Regex(pattern, flags)) => (pattern.as_str(), flags.clone()),
+        Some(value) => {
+            return Err(location(
+                51105,
+                format!(
+                    "{} needs 'regex' to be of type string or regex, found: {}",
+                    name,
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    match options {
+        None | Some(Value::Null | Value::Undefined) => {}
+        Some(Value::String(options)) => {
+            if !flags.is_empty() && !options.is_empty() {
+                return Err(location(
+                    51107,
+                    format!(
+                        "{} found regex option(s) specified in both 'regex' and 'option' fields",
+                        name
+                    ),
+                ));
+            }
+            flags.push_str(options);
+        }
+        Some(value) => {
+            return Err(location(
+                51106,
+                format!(
+                    "{} needs 'options' to be of type string, found: {}",
+                    name,
+                    value.type_name()
+                ),
+            ));
+        }
+    }
+    if let Some(flag) = flags.chars().find(|c| !matches!(c, 'i' | 'm' | 's' | 'x')) {
+        return Err(location(
+            51108,
+            format!("{} invalid flag in regex options: {}", name, flag),
+        ));
+    }
+    let source = match flags.is_empty() {
+        true => pattern.to_string(),
+        false => format!("(?{}){}", flags, pattern),
+    };
+    Regex::new(&source)
+        .map(Some)
+        .map_err(|err| location(51111, format!("Invalid Regex in {}: {}", name, err)))
+}
+
+fn regex(
+    name: &str,
+    input: &Option<Value>,
+    regex: &Option<Value>,
+    options: &Option<Value>,
+) -> CommandResult<Option<Value>> {
+    let compiled = compile(name, regex, options)?;
+    let input = match input {
+        None | Some(Value::Null | Value::Undefined) => None,
+        Some(Value::String(input)) => Some(input),
+        Some(value) => {
+            return Err(location(
+                51104,
+                format!(
+                    "{} needs 'input' to be of type string, found: {}",
+                    name,
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    let (Some(regex), Some(input)) = (compiled, input) else {
+        return Ok(Some(match name {
+            "$regexMatch" => Value::Boolean(false),
+            "$regexFind" => Value::Null,
+            _ => Value::from(Vec::new()),
+        }));
+    };
+
+    let describe = |captures: regex::Captures| {
+        let whole = captures.get(0).expect("group 0 always matches");
+        let mut result = Document::new();
+        result.insert("match", Value::from(whole.as_str()));
+        // Positions are reported in code points
+        let idx = input[..whole.start()].chars().count();
+        result.insert("idx", Value::Int32(idx as i32));
+        let groups = captures
+            .iter()
+            .skip(1)
+            .map(|group| group.map_or(Value::Null, |group| Value::from(group.as_str())))
+            .collect();
+        result.insert("captures", Value::Array(Array(groups)));
+        Value::Document(result)
+    };
+    Ok(Some(match name {
+        "$regexMatch" => Value::Boolean(regex.is_match(input)),
+        "$regexFind" => regex.captures(input).map_or(Value::Null, describe),
+        _ => Value::from(regex.captures_iter(input).map(describe).collect::<Vec<_>>()),
+    }))
+}
+
+fn replace(
+    name: &str,
+    input: &Option<Value>,
+    find: &Option<Value>,
+    replacement: &Option<Value>,
+) -> CommandResult<Option<Value>> {
+    let mut strings = Vec::new();
+    for (field, value, code) in [
+        ("input", input, 51746),
+        ("find", find, 51745),
+        ("replacement", replacement, 51744),
+    ] {
+        match value {
+            None | Some(Value::Null | Value::Undefined) => strings.push(None),
+            Some(Value::String(s)) => strings.push(Some(s)),
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::Location(code),
+                    format!(
+                        "{} requires that '{}' be a string, found: {}",
+                        name, field, value
+                    ),
+                ));
+            }
+        }
+    }
+    let [Some(input), Some(find), Some(replacement)] = strings[..] else {
+        return Ok(Some(Value::Null));
+    };
+    Ok(Some(Value::String(match name {
+        "$replaceOne" => input.replacen(find.as_str(), replacement, 1),
+        _ => input.replace(find.as_str(), replacement),
+    })))
+}
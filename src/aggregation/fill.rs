@@ -0,0 +1,248 @@
+//! The `$fill` stage, which fills null and missing fields. Like mongod, it is
+//! rewritten into a `$setWindowFields` stage for the `locf` and `linear`
+//! methods followed by an `$addFields` stage for constant values.
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::pipeline::Pipeline;
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+fn wrong_type(field: &str, value: &Value, expected: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::TypeMismatch,
+        format!(
+            "BSON field '$fill.{}' is the wrong type '{}', expected type '{}'",
+            field,
+            value.type_name(),
+            expected
+        ),
+    )
+}
+
+fn stage(name: &str, spec: Document) -> Value {
+    let mut stage = Document::new();
+    stage.insert(name, Value::Document(spec));
+    Value::Document(stage)
+}
+
+/// Parses `$fill` into the pipeline implementing it.
+pub fn parse(spec: &Value) -> CommandResult<Pipeline> {
+    let Value::Document(spec) = spec else {
+        return Err(CommandError::new(
+            ErrorCode::FailedToParse,
+            format!(
+                "the $fill stage specification must be an object, found {}",
+                spec.type_name()
+            ),
+        ));
+    };
+    if let Some(key) = spec.keys().find(|key| {
+        !matches!(
+            key.as_str(),
+            "partitionBy" | "partitionByFields" | "sortBy" | "output"
+        )
+    }) {
+        return Err(location(
+            40415,
+            format!("BSON field '$fill.{}' is an unknown field.", key),
+        ));
+    }
+    let output = match spec.get("output") {
+        Some(Value::Document(output)) => output,
+        Some(value) => return Err(wrong_type("output", value, "object")),
+        None => {
+            return Err(location(
+                40414,
+                "BSON field '$fill.output' is missing but a required field",
+            ));
+        }
+    };
+
+    let mut window_fields = Document::new();
+    match (spec.get("partitionBy"), spec.get("partitionByFields")) {
+        (Some(_), Some(_)) => {
+            return Err(location(
+                6050204,
+                "Maximum one of 'partitionBy' and 'partitionByFields' can be specified in '$fill'",
+            ));
+        }
+        (Some(partition_by), None) => {
+            window_fields.insert("partitionBy", partition_by.clone());
+        }
+        (None, Some(Value::Array(fields))) => {
+            // Partition on the array of the fields' values
+            let mut paths = Vec::new();
+            for field in &fields.0 {
+                match field {
+                    Value::String(field) if !field.starts_with('$') => {
+                        paths.push(Value::String(format!("${}", field)));
+                    }
+                    _ => {
+                        return Err(location(
+                            6050205,
+                            "partitionByFields must be an array of field names not starting with '$'",
+                        ));
+                    }
+                }
+            }
+            window_fields.insert("partitionBy", Value::from(paths));
+        }
+        (None, Some(value)) => return Err(wrong_type("partitionByFields", value, "array")),
+        (None, None) => {}
+    }
+    match spec.get("sortBy") {
+        Some(Value::Document(sort_by)) => {
+            window_fields.insert("sortBy", Value::Document(sort_by.clone()));
+        }
+        Some(value) => return Err(wrong_type("sortBy", value, "object")),
+        None => {}
+    }
+
+    let mut methods = Document::new();
+    let mut values = Document::new();
+    for (field, fill) in output.iter() {
+        let fill = match fill {
+            Value::Document(fill) if fill.len() == 1 => fill,
+            _ => {
+                return Err(location(
+                    6050203,
+                    format!(
+                        "Exactly one of 'value' or 'method' must be specified in $fill output field '{}'",
+                        field
+                    ),
+                ));
+            }
+        };
+        let current = Value::String(format!("${}", field));
+        match fill.first() {
+            Some((key, value)) if key == "value" => {
+                let mut if_null = Document::new();
+                if_null.insert("$ifNull", Value::from(vec![current, value.clone()]));
+                values.insert(field.clone(), Value::Document(if_null));
+            }
+            Some((key, Value::String(method))) if key == "method" => {
+                let function = match method.as_str() {
+                    "locf" => "$locf",
+                    "linear" if window_fields.contains_key("sortBy") => "$linearFill",
+                    "linear" => {
+                        return Err(location(
+                            6050201,
+                            "'sortBy' field is required for the 'linear' fill method",
+                        ));
+                    }
+                    method => {
+                        return Err(CommandError::new(
+                            ErrorCode::FailedToParse,
+                            format!(
+                                "Method must be either 'locf' or 'linear', found '{}'",
+                                method
+                            ),
+                        ));
+                    }
+                };
+                let mut window_function = Document::new();
+                window_function.insert(function, current);
+                methods.insert(field.clone(), Value::Document(window_function));
+            }
+            Some((key, value)) if key == "method" => {
+                return Err(wrong_type(
+                    &format!("output.{}.method", field),
+                    value,
+                    "string",
+                ));
+            }
+            _ => {
+                return Err(location(
+                    6050203,
+                    format!(
+                        "Exactly one of 'value' or 'method' must be specified in $fill output field '{}'",
+                        field
+                    ),
+                ));
+            }
+        }
+    }
+
+    let mut stages = Vec::new();
+    if !methods.is_empty() {
+        window_fields.insert("output", Value::Document(methods));
+        stages.push(stage("$setWindowFields", window_fields));
+    }
+    if !values.is_empty() {
+        stages.push(stage("$addFields", values));
+    }
+    Pipeline::parse(&stages)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        aggregation::Context,
+        bson::json::{doc, value},
+    };
+
+    fn fill(spec: &str, documents: &[&str]) -> CommandResult<Vec<Document>> {
+        parse(&value(spec))?.run(
+            documents.iter().map(|d| doc(d)).collect(),
+            &Context::default(),
+        )
+    }
+
+    #[test]
+    fn test_fill() {
+        let documents = [
+            "{ t: 4, p: 'a', v: 10 }",
+            "{ t: 1, p: 'a', v: 1 }",
+            "{ t: 2, p: 'a', v: null }",
+            "{ t: 3, p: 'b', v: 5 }",
+            "{ t: 5, p: 'b' }",
+        ];
+        assert_eq!(
+            fill(
+                "{ sortBy: { t: 1 }, partitionByFields: ['p'], output: { v: { method: 'locf' }, w: { value: 0 } } }",
+                &documents
+            )
+            .unwrap(),
+            vec![
+                doc("{ t: 1, p: 'a', v: 1, w: 0 }"),
+                doc("{ t: 2, p: 'a', v: 1, w: 0 }"),
+                doc("{ t: 4, p: 'a', v: 10, w: 0 }"),
+                doc("{ t: 3, p: 'b', v: 5, w: 0 }"),
+                doc("{ t: 5, p: 'b', v: 5, w: 0 }"),
+            ]
+        );
+        assert_eq!(
+            fill(
+                "{ sortBy: { t: 1 }, output: { v: { method: 'linear' } } }",
+                &documents[..3]
+            )
+            .unwrap(),
+            vec![
+                doc("{ t: 1, p: 'a', v: 1 }"),
+                doc("{ t: 2, p: 'a', v: 4.0 }"),
+                doc("{ t: 4, p: 'a', v: 10 }"),
+            ]
+        );
+
+        let code = |spec| fill(spec, &[]).unwrap_err().code;
+        assert_eq!(
+            code("{ output: { v: { method: 'linear' } } }"),
+            ErrorCode::Location(6050201)
+        );
+        assert_eq!(
+            code("{ partitionBy: '$p', partitionByFields: ['p'], output: { v: { value: 1 } } }"),
+            ErrorCode::Location(6050204)
+        );
+        assert_eq!(
+            code("{ output: { v: { value: 1, method: 'locf' } } }"),
+            ErrorCode::Location(6050203)
+        );
+    }
+}
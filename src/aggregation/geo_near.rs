@@ -0,0 +1,183 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    geo::Near,
+    query::{planner::Execution, Matcher},
+    storage::Collection,
+};
+
+use super::stage::set_field;
+
+/// A `$geoNear` stage, which leads its pipeline: the planner finds the
+/// documents near a point through a geo index, nearest first, and the stage
+/// adds their distance.
+#[derive(Debug, Clone)]
+pub struct GeoNear {
+    near: Near,
+    /// The path of the geo field, when given rather than left to the index.
+    key: Option<String>,
+    query: Document,
+    distance_field: Vec<String>,
+    distance_multiplier: f64,
+    include_locs: Option<Vec<String>>,
+}
+
+fn type_mismatch(option: &str, expected: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::TypeMismatch,
+        format!("$geoNear requires '{}' to be {}", option, expected),
+    )
+}
+
+fn path(value: &Value, option: &str) -> CommandResult<Vec<String>> {
+    match value {
+        Value::String(path) if !path.is_empty() && !path.starts_with('$') => {
+            Ok(path.split('.').map(String::from).collect())
+        }
+        _ => Err(type_mismatch(option, "a field path")),
+    }
+}
+
+impl GeoNear {
+    /// Parses a specification such as `{ near: { type: 'Point',
+    /// coordinates: [1, 2] }, distanceField: 'dist', maxDistance: 100 }`.
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                "$geoNear only takes an object as an argument",
+            ));
+        };
+        let mut near = None;
+        let mut spherical = false;
+        let (mut min, mut max) = (None, None);
+        let mut key = None;
+        let mut query = Document::new();
+        let mut distance_field = None;
+        let mut distance_multiplier = 1.0;
+        let mut include_locs = None;
+        let number = |value: &Value, option: &str| match value.as_f64() {
+            Some(n) if value.is_number() && n >= 0.0 => Ok(n),
+            _ => Err(type_mismatch(option, "a non-negative number")),
+        };
+        for (option, value) in spec.iter() {
+            match option.as_str() {
+                "near" => near = Some(value),
+                "spherical" => spherical = value.is_truthy(),
+                "minDistance" => min = Some(number(value, option)?),
+                "maxDistance" => max = Some(number(value, option)?),
+                "distanceMultiplier" => distance_multiplier = number(value, option)?,
+                "distanceField" => distance_field = Some(path(value, option)?),
+                "includeLocs" => include_locs = Some(path(value, option)?),
+                "key" => key = Some(path(value, option)?.join(".")),
+                "query" => match value {
+                    Value::Document(filter) => query = filter.clone(),
+                    _ => return Err(type_mismatch(option, "an object")),
+                },
+                option => {
+                    return Err(CommandError::new(
+                        ErrorCode::FailedToParse,
+                        format!("Unknown argument to $geoNear: {}", option),
+                    ));
+                }
+            }
+        }
+        let Some(near) = near else {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                "$geoNear requires a 'near' argument",
+            ));
+        };
+        let Some(distance_field) = distance_field else {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                "$geoNear requires a 'distanceField' argument",
+            ));
+        };
+        // The query is checked now, though only compiled once the key is
+        // known
+        Matcher::new(&query)?;
+        Ok(Self {
+            near: Near::from_point(near, spherical, min, max)?,
+            key,
+            query,
+            distance_field,
+            distance_multiplier,
+            include_locs,
+        })
+    }
+
+    /// The matcher the planner finds the documents with. Without a `key`,
+    /// the geo field is that of the only geo index that can answer: a `2d`
+    /// one for a legacy point, else a `2dsphere` one.
+    pub fn matcher(
+        &self,
+        collection: Option<&Collection>,
+        namespace: &str,
+    ) -> CommandResult<Matcher> {
+        let key = match (&self.key, collection) {
+            (Some(key), _) => key.clone(),
+            (None, None) => String::new(),
+            (None, Some(collection)) => {
+                let indexes = |sphere: bool| {
+                    collection
+                        .indexes()
+                        .iter()
+                        .filter(move |index| {
+                            index.geo().is_some_and(|geo| geo.is_sphere() == sphere)
+                        })
+                        .collect::<Vec<_>>()
+                };
+                let flat = if self.near.geojson {
+                    Vec::new()
+                } else {
+                    indexes(false)
+                };
+                let (found, kind) = if flat.is_empty() {
+                    (indexes(true), "2dsphere")
+                } else {
+                    (flat, "2d")
+                };
+                let index = match found[..] {
+                    [index] => index,
+                    [] => {
+                        return Err(CommandError::new(
+                            ErrorCode::IndexNotFound,
+                            "$geoNear requires a 2d or 2dsphere index, but none were found",
+                        ));
+                    }
+                    _ => {
+                        return Err(CommandError::new(
+                            ErrorCode::IndexNotFound,
+                            format!(
+                                "There is more than one {} index on {}; unsure which to use for $geoNear",
+                                kind, namespace
+                            ),
+                        ));
+                    }
+                };
+                let geo = index.geo().expect("the index is a geo index");
+                index.fields()[geo.field()].0.join(".")
+            }
+        };
+        Matcher::with_near(&self.query, &key, self.near.clone())
+    }
+
+    /// The documents the planner found as they are read, with their
+    /// distance, scaled by the
+    /// distance multiplier, and nearest location when asked for.
+    pub fn results<'a>(&'a self, execution: Execution<'a>) -> impl Iterator<Item = Document> + 'a {
+        let distances = execution.distances;
+        execution.documents.into_iter().map(move |(id, doc)| {
+            let mut doc = doc.into_owned();
+            if let Some((distance, location)) = distances.get(&id) {
+                let distance = Value::Double(distance * self.distance_multiplier);
+                set_field(&mut doc, &self.distance_field, Some(distance));
+                if let Some(path) = &self.include_locs {
+                    set_field(&mut doc, path, Some(location.clone()));
+                }
+            }
+            doc
+        })
+    }
+}
@@ -0,0 +1,506 @@
+//! The `$group` stage.
+
+use std::{cmp::Ordering, collections::BTreeMap};
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{
+    accumulator::{Accumulator, State},
+    expression::Expression,
+    pipeline::{Context, Metadata},
+    spill::{value_size, Runs},
+};
+
+#[derive(Debug, Clone)]
+pub struct Group {
+    id: Expression,
+    fields: Vec<(String, Accumulator)>,
+}
+
+/// A group key ordered by BSON comparison, so that values that compare
+/// equal (like `1` and `1.0`) fall into the same group. Under a collation it
+/// holds the key translated to sort keys, so strings the collation deems
+/// equal do too.
+#[derive(Debug)]
+struct GroupKey(Value);
+
+impl GroupKey {
+    fn new(key: &Value, context: &Context) -> Self {
+        match context.collation {
+            Some(collation) => Self(collation.translate(key)),
+            None => Self(key.clone()),
+        }
+    }
+}
+
+impl PartialEq for GroupKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.equals(&other.0)
+    }
+}
+
+impl Eq for GroupKey {}
+
+impl PartialOrd for GroupKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for GroupKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.compare(&other.0)
+    }
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+impl Group {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(location(
+                15947,
+                "a group's fields must be specified in an object",
+            ));
+        };
+        let mut id = None;
+        let mut fields = Vec::new();
+        for (field, value) in spec.iter() {
+            if field == "_id" {
+                id = Some(Expression::parse(value)?);
+                continue;
+            }
+            fields.push(parse_field(field, value)?);
+        }
+        let Some(id) = id else {
+            return Err(location(15955, "a group specification must include an _id"));
+        };
+        Ok(Self { id, fields })
+    }
+
+    /// The accumulated fields of the bucket stages' `output`, counting the
+    /// documents of each bucket by default. Keys are computed by the caller.
+    pub fn parse_output(output: Option<&Document>) -> CommandResult<Self> {
+        let mut fields = Vec::new();
+        match output {
+            Some(output) => {
+                for (field, value) in output.iter() {
+                    fields.push(parse_field(field, value)?);
+                }
+            }
+            None => {
+                let mut sum = Document::new();
+                sum.insert("$sum", Value::Int32(1));
+                fields.push(parse_field("count", &Value::Document(sum))?);
+            }
+        }
+        Ok(Self {
+            id: Expression::Literal(Value::Null),
+            fields,
+        })
+    }
+
+    pub fn run(
+        &self,
+        documents: impl IntoIterator<Item = CommandResult<(Document, Metadata)>>,
+        context: &Context,
+    ) -> CommandResult<Vec<Document>> {
+        let keyed = documents.into_iter().map(|doc| {
+            let (doc, metadata) = doc?;
+            let vars = context.variables(&doc).with_metadata(&metadata);
+            Ok((self.id.eval(&vars)?, doc, metadata))
+        });
+        self.run_keyed(keyed, context)
+    }
+
+    /// Groups documents by keys computed beforehand, taking them from
+    /// `documents` as it goes. Keys are compared under the collation of the
+    /// context, each group keeping the first key seen. Groups are output in
+    /// the order their first document was seen, unless they don't fit in
+    /// memory: then the groups so far are spilled to disk in key order, and
+    /// the spilled states of each group are merged back in key order once
+    /// the input ends.
+    pub fn run_keyed(
+        &self,
+        documents: impl IntoIterator<Item = CommandResult<(Value, Document, Metadata)>>,
+        context: &Context,
+    ) -> CommandResult<Vec<Document>> {
+        let mut index: BTreeMap<GroupKey, usize> = BTreeMap::new();
+        let mut groups: Vec<(Value, Vec<State>)> = Vec::new();
+        let mut runs = Runs::default();
+        let mut memory = 0usize;
+        for keyed in documents {
+            let (key, doc, metadata) = keyed?;
+            let vars = context.variables(&doc).with_metadata(&metadata);
+            let group_key = GroupKey::new(&key, context);
+            let position = match index.get(&group_key) {
+                Some(position) => *position,
+                None => {
+                    let states = self.fields.iter().map(|(_, acc)| acc.init()).collect();
+                    memory += value_size(&key);
+                    groups.push((key, states));
+                    index.insert(group_key, groups.len() - 1);
+                    groups.len() - 1
+                }
+            };
+            let states = &mut groups[position].1;
+            for ((_, accumulator), state) in self.fields.iter().zip(states.iter_mut()) {
+                let growth = accumulator.accumulate(state, &doc, &vars)?;
+                memory = memory.saturating_add_signed(growth);
+            }
+            if memory > context.memory_limit {
+                let spilled = self.spill(&mut index, &mut groups);
+                runs.write(spilled, "$group", context)?;
+                memory = 0;
+            }
+        }
+        if runs.is_empty() {
+            return Ok(groups
+                .into_iter()
+                .map(|(key, states)| self.output(key, states))
+                .collect());
+        }
+
+        let spilled = self.spill(&mut index, &mut groups);
+        let key = |spilled: &Document| spilled.get("k").cloned().unwrap_or(Value::Null);
+        let merged = runs.merge(spilled, |a, b| {
+            key(a).compare_with(&key(b), context.collation)
+        })?;
+        let mut output = Vec::new();
+        let mut group: Option<(Value, Vec<State>)> = None;
+        for spilled in merged {
+            let (key, states) = self.unspill(spilled?);
+            match &mut group {
+                Some((current, merged)) if current.equals_with(&key, context.collation) => {
+                    for ((_, accumulator), (state, later)) in
+                        self.fields.iter().zip(merged.iter_mut().zip(states))
+                    {
+                        accumulator.combine(state, later);
+                    }
+                }
+                _ => {
+                    output.extend(group.take().map(|(key, states)| self.output(key, states)));
+                    group = Some((key, states));
+                }
+            }
+        }
+        output.extend(group.map(|(key, states)| self.output(key, states)));
+        Ok(output)
+    }
+
+    /// Takes the groups out of memory as documents sorted by key, holding
+    /// the key and the spilled states of the accumulators.
+    fn spill(
+        &self,
+        index: &mut BTreeMap<GroupKey, usize>,
+        groups: &mut Vec<(Value, Vec<State>)>,
+    ) -> Vec<Document> {
+        let mut groups: Vec<_> = groups.drain(..).map(Some).collect();
+        std::mem::take(index)
+            .into_values()
+            .filter_map(|position| groups[position].take())
+            .map(|(key, states)| {
+                let states = self
+                    .fields
+                    .iter()
+                    .zip(states)
+                    .map(|((_, accumulator), state)| accumulator.spill(state))
+                    .collect::<Vec<_>>();
+                let mut spilled = Document::new();
+                spilled.insert("k", key);
+                spilled.insert("s", Value::from(states));
+                spilled
+            })
+            .collect()
+    }
+
+    fn unspill(&self, mut spilled: Document) -> (Value, Vec<State>) {
+        let key = spilled.remove("k").unwrap_or(Value::Null);
+        let mut states = match spilled.remove("s") {
+            Some(Value::Array(states)) => states.0.into_iter(),
+            _ => Vec::new().into_iter(),
+        };
+        let states = self
+            .fields
+            .iter()
+            .map(|(_, accumulator)| match states.next() {
+                Some(state) => accumulator.unspill(state),
+                None => accumulator.init(),
+            })
+            .collect();
+        (key, states)
+    }
+
+    fn output(&self, key: Value, states: Vec<State>) -> Document {
+        let mut output = Document::new();
+        output.insert("_id", key);
+        for ((field, accumulator), state) in self.fields.iter().zip(states) {
+            output.insert(field.clone(), accumulator.finish(state));
+        }
+        output
+    }
+}
+
+/// Parses one accumulated field such as `total: { $sum: '$qty' }`.
+fn parse_field(field: &str, value: &Value) -> CommandResult<(String, Accumulator)> {
+    if field.starts_with('$') {
+        return Err(location(
+            40236,
+            format!("The field name '{}' cannot be an operator name", field),
+        ));
+    }
+    if field.contains('.') {
+        return Err(location(
+            40235,
+            format!("The field name '{}' cannot contain '.'", field),
+        ));
+    }
+    let accumulator = match value {
+        Value::Document(accumulator) if accumulator.len() == 1 => {
+            let (name, arg) = accumulator.first().expect("one field");
+            Accumulator::parse(name, arg)?
+        }
+        Value::Document(_) => {
+            return Err(location(
+                40238,
+                format!("The field '{}' must specify one accumulator", field),
+            ));
+        }
+        _ => {
+            return Err(location(
+                40234,
+                format!("The field '{}' must be an accumulator object", field),
+            ));
+        }
+    };
+    Ok((field.to_string(), accumulator))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::json::{doc, value},
+        collation::Collation,
+    };
+
+    fn group(spec: &str, documents: &[&str]) -> CommandResult<Vec<Document>> {
+        Group::parse(&value(spec))?.run(
+            documents.iter().map(|d| Ok((doc(d), Metadata::default()))),
+            &Context::default(),
+        )
+    }
+
+    const SALES: [&str; 5] = [
+        "{ item: 1, qty: 2, price: 10, tags: { a: 1 } }",
+        "{ item: 1.0, qty: 4, price: 20, tags: { b: 2 } }",
+        "{ item: 2, qty: 1, price: 5 }",
+        "{ item: Long(1), qty: 6, price: 30, tags: null }",
+        "{ item: 2, price: 15 }",
+    ];
+
+    #[test]
+    fn test_numeric_keys_group_together() {
+        assert_eq!(
+            group(
+                "{ _id: '$item', total: { $sum: '$qty' }, avg: { $avg: '$price' }, n: { $count: {} }, low: { $min: '$qty' }, high: { $max: '$qty' } }",
+                &SALES
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: 1, total: 12, avg: 20.0, n: 3, low: 2, high: 6 }"),
+                doc("{ _id: 2, total: 1, avg: 10.0, n: 2, low: 1, high: 1 }"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_keys_group_under_collation() {
+        let collation = Collation::parse(&doc("{ locale: 'en', strength: 2 }"))
+            .unwrap()
+            .unwrap();
+        let group = Group::parse(&value("{ _id: '$name', n: { $count: {} } }")).unwrap();
+        let documents = ["{ name: 'a' }", "{ name: 'A' }", "{ name: 'b' }"];
+        let run = |context: &Context| {
+            group
+                .run(
+                    documents.iter().map(|d| Ok((doc(d), Metadata::default()))),
+                    context,
+                )
+                .unwrap()
+        };
+        let context = Context {
+            collation: Some(&collation),
+            ..Context::default()
+        };
+        // Each group keeps the first key seen
+        assert_eq!(
+            run(&context),
+            vec![doc("{ _id: 'a', n: 2 }"), doc("{ _id: 'b', n: 1 }")]
+        );
+        assert_eq!(run(&Context::default()).len(), 3);
+    }
+
+    #[test]
+    fn test_array_and_positional_accumulators() {
+        assert_eq!(
+            group(
+                "{ _id: null, first: { $first: '$qty' }, last: { $last: '$qty' }, all: { $push: '$qty' }, set: { $addToSet: '$item' }, tags: { $mergeObjects: '$tags' } }",
+                &SALES
+            )
+            .unwrap(),
+            vec![doc(
+                "{ _id: null, first: 2, last: null, all: [2, 4, 1, 6], set: [1, 2], tags: { a: 1, b: 2 } }"
+            )]
+        );
+        assert_eq!(
+            group(
+                "{ _id: null, top: { $top: { output: '$price', sortBy: { qty: -1 } } }, bottom2: { $bottomN: { output: '$price', sortBy: { price: 1 }, n: 2 } }, firstTwo: { $firstN: { input: '$qty', n: 2 } }, lastTwo: { $lastN: { input: '$qty', n: 2 } } }",
+                &SALES
+            )
+            .unwrap(),
+            vec![doc(
+                "{ _id: null, top: 30, bottom2: [20, 30], firstTwo: [2, 4], lastTwo: [6, null] }"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_standard_deviation() {
+        let result = group(
+            "{ _id: null, pop: { $stdDevPop: '$v' }, samp: { $stdDevSamp: '$v' } }",
+            &[
+                "{ v: 2 }", "{ v: 4 }", "{ v: 4 }", "{ v: 4 }", "{ v: 5 }", "{ v: 5 }", "{ v: 7 }",
+                "{ v: 9 }",
+            ],
+        )
+        .unwrap();
+        assert_eq!(result[0].get("pop"), Some(&Value::Double(2.0)));
+        let samp = result[0].get("samp").and_then(Value::as_f64).unwrap();
+        assert!((samp - 2.138089935299395).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let documents: Vec<_> = SALES.iter().map(|d| doc(d)).collect();
+        let group = Group::parse(&value(
+            "{ _id: '$item', all: { $push: '$price' }, n: { $count: {} } }",
+        ))
+        .unwrap();
+        let context = Context {
+            memory_limit: 20,
+            ..Context::default()
+        };
+        assert_eq!(
+            group
+                .run(
+                    documents
+                        .iter()
+                        .map(|doc| Ok((doc.clone(), Metadata::default()))),
+                    &context
+                )
+                .unwrap_err()
+                .code,
+            ErrorCode::QueryExceededMemoryLimitNoDiskUseAllowed
+        );
+
+        // With disk use, groups come out in key order
+        let dir = std::env::temp_dir().join(format!("oxide-test-group-{}", std::process::id()));
+        let context = Context {
+            spill_dir: Some(&dir),
+            ..context
+        };
+        assert_eq!(
+            group
+                .run(
+                    documents
+                        .into_iter()
+                        .map(|doc| Ok((doc, Metadata::default()))),
+                    &context
+                )
+                .unwrap(),
+            vec![
+                doc("{ _id: 1, all: [10, 20, 30], n: 3 }"),
+                doc("{ _id: 2, all: [5, 15], n: 2 }"),
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_spilled_states_merge() {
+        let documents: Vec<_> = (0..60)
+            .map(|i| {
+                doc(&format!(
+                    "{{ item: {}, qty: {}, tags: {{ t{}: {} }} }}",
+                    (i * 7) % 4,
+                    (i * 13) % 10,
+                    i % 3,
+                    i
+                ))
+            })
+            .collect();
+        let group = Group::parse(&value(
+            "{ _id: '$item', total: { $sum: '$qty' }, avg: { $avg: '$qty' }, low: { $min: '$qty' }, first: { $first: '$qty' }, last: { $last: '$qty' }, all: { $push: '$qty' }, set: { $addToSet: '$qty' }, n: { $count: {} }, dev: { $stdDevPop: '$qty' }, tags: { $mergeObjects: '$tags' }, firstTwo: { $firstN: { input: '$qty', n: 2 } }, lastTwo: { $lastN: { input: '$qty', n: 2 } }, top: { $topN: { output: '$qty', sortBy: { qty: -1 }, n: 3 } } }",
+        ))
+        .unwrap();
+        let mut expected = group
+            .run(
+                documents
+                    .iter()
+                    .map(|doc| Ok((doc.clone(), Metadata::default()))),
+                &Context::default(),
+            )
+            .unwrap();
+        expected.sort_by(|a, b| a.get("_id").unwrap().compare(b.get("_id").unwrap()));
+
+        // Groups spilled many times over merge back to the same results
+        let dir = std::env::temp_dir().join(format!("oxide-test-merge-{}", std::process::id()));
+        let context = Context {
+            memory_limit: 100,
+            spill_dir: Some(&dir),
+            ..Context::default()
+        };
+        let spilled = group
+            .run(
+                documents
+                    .into_iter()
+                    .map(|doc| Ok((doc, Metadata::default()))),
+                &context,
+            )
+            .unwrap();
+        for (spilled, expected) in spilled.iter().zip(&expected) {
+            for (field, value) in expected.iter() {
+                let merged = spilled.get(field).unwrap();
+                match (merged, value) {
+                    (Value::Double(merged), Value::Double(value)) => {
+                        assert!((merged - value).abs() < 1e-9, "{}", field)
+                    }
+                    _ => assert_eq!(merged, value, "{}", field),
+                }
+            }
+        }
+        assert_eq!(spilled.len(), expected.len());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_specifications() {
+        let code = |spec| group(spec, &[]).unwrap_err().code;
+        assert_eq!(code("{ total: { $sum: 1 } }"), ErrorCode::Location(15955));
+        assert_eq!(code("{ _id: null, total: 1 }"), ErrorCode::Location(40234));
+        assert_eq!(
+            code("{ _id: null, total: { $foo: 1 } }"),
+            ErrorCode::Location(15952)
+        );
+        assert_eq!(
+            code("{ _id: null, total: { $sum: [1, 2] } }"),
+            ErrorCode::Location(40237)
+        );
+    }
+}
@@ -0,0 +1,704 @@
+//! The `$lookup`, `$graphLookup` and `$unionWith` stages, which join or add
+//! documents of another collection of the same database.
+
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    hash::{Hash, Hasher},
+};
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::Matcher,
+    storage::RecordId,
+};
+
+use super::{
+    expression::{validate_variable_name, Expression},
+    pipeline::{Context, Pipeline},
+    stage::set_field,
+};
+
+/// `$lookup`: either an equality match between `localField` and
+/// `foreignField`, a pipeline run over the foreign collection with `let`
+/// variables bound, or both.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    from: String,
+    /// `localField` and `foreignField`.
+    fields: Option<(Vec<String>, String)>,
+    variables: Vec<(String, Expression)>,
+    pipeline: Option<Pipeline>,
+    as_path: Vec<String>,
+}
+
+/// `$graphLookup`: a breadth-first search of the foreign collection,
+/// following `connectFromField` to `connectToField` from `startWith`.
+#[derive(Debug, Clone)]
+pub struct GraphLookup {
+    from: String,
+    start_with: Expression,
+    connect_from: Vec<String>,
+    connect_to: String,
+    as_path: Vec<String>,
+    max_depth: Option<i64>,
+    depth_field: Option<Vec<String>>,
+    restrict: Option<Matcher>,
+}
+
+/// `$unionWith`: the documents of another collection, run through an
+/// optional pipeline, after the input documents.
+#[derive(Debug, Clone)]
+pub struct UnionWith {
+    coll: String,
+    pipeline: Option<Pipeline>,
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+fn failed_to_parse(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::FailedToParse, message)
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('.').map(String::from).collect()
+}
+
+/// Collects the values at a dotted path, expanding arrays along the path
+/// and at its end, as joins match them.
+fn collect_values(value: &Value, path: &[String], values: &mut Vec<Value>) {
+    match (value, path.split_first()) {
+        (Value::Array(items), _) => {
+            for item in &items.0 {
+                if path.is_empty() {
+                    values.push(item.clone());
+                } else {
+                    collect_values(item, path, values);
+                }
+            }
+        }
+        (value, None) => values.push(value.clone()),
+        (Value::Document(doc), Some((first, rest))) => {
+            if let Some(value) = doc.get(first) {
+                collect_values(value, rest, values);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn values_at(doc: &Document, path: &[String]) -> Vec<Value> {
+    let mut values = Vec::new();
+    if let Some((first, rest)) = path.split_first() {
+        if let Some(value) = doc.get(first) {
+            collect_values(value, rest, &mut values);
+        }
+    }
+    values
+}
+
+/// The documents of the foreign collection `from` whose `field` equals one
+/// of `values`, found like `{ field: { $in: values } }` so that an index on
+/// the field is used.
+fn foreign_matches<'a>(
+    context: &Context<'a>,
+    from: &str,
+    field: &str,
+    values: Vec<Value>,
+) -> CommandResult<Vec<(RecordId, Cow<'a, Document>)>> {
+    let mut condition = Document::new();
+    condition.insert("$in", Value::from(values));
+    let mut filter = Document::new();
+    filter.insert(field, Value::Document(condition));
+    context.find(from, &Matcher::new(&filter)?)
+}
+
+impl Lookup {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(failed_to_parse(
+                "the $lookup specification must be an Object",
+            ));
+        };
+        let (mut from, mut local_field, mut foreign_field, mut as_field) = (None, None, None, None);
+        let mut variables = Vec::new();
+        let mut has_let = false;
+        let mut pipeline = None;
+        for (key, value) in spec.iter() {
+            match (key.as_str(), value) {
+                ("from", Value::String(s)) => from = Some(s.clone()),
+                ("localField", Value::String(s)) => local_field = Some(split_path(s)),
+                ("foreignField", Value::String(s)) => foreign_field = Some(s.clone()),
+                ("as", Value::String(s)) => as_field = Some(split_path(s)),
+                ("from" | "localField" | "foreignField" | "as", value) => {
+                    return Err(failed_to_parse(format!(
+                        "$lookup argument '{}: {}' must be a string, is type {}",
+                        key,
+                        value,
+                        value.type_name()
+                    )));
+                }
+                ("let", Value::Document(bindings)) => {
+                    has_let = true;
+                    for (name, expression) in bindings.iter() {
+                        validate_variable_name(name)?;
+                        variables.push((name.clone(), Expression::parse(expression)?));
+                    }
+                }
+                ("pipeline", Value::Array(stages)) => {
+                    let parsed = Pipeline::parse(&stages.0)?;
+                    if parsed.output().is_some() {
+                        return Err(location(
+                            51047,
+                            "$out and $merge are not allowed within a $lookup pipeline",
+                        ));
+                    }
+                    if parsed.geo_near().is_some() {
+                        return Err(location(
+                            40602,
+                            "$geoNear is not allowed within a $lookup pipeline",
+                        ));
+                    }
+                    pipeline = Some(parsed);
+                }
+                ("let" | "pipeline", value) => {
+                    let expected = if key == "let" {
+                        "an object"
+                    } else {
+                        "an array"
+                    };
+                    return Err(failed_to_parse(format!(
+                        "$lookup argument '{}: {}' must be {}, is type {}",
+                        key,
+                        value,
+                        expected,
+                        value.type_name()
+                    )));
+                }
+                (key, _) => {
+                    return Err(failed_to_parse(format!(
+                        "unknown argument to $lookup: {}",
+                        key
+                    )));
+                }
+            }
+        }
+
+        let Some(from) = from else {
+            return Err(failed_to_parse("must specify 'from' field for a $lookup"));
+        };
+        let Some(as_path) = as_field else {
+            return Err(failed_to_parse("must specify 'as' field for a $lookup"));
+        };
+        let fields = match (local_field, foreign_field) {
+            (Some(local), Some(foreign)) => Some((local, foreign)),
+            (None, None) => None,
+            _ => {
+                return Err(failed_to_parse(
+                    "$lookup requires both or neither of 'localField' and 'foreignField' to be specified",
+                ));
+            }
+        };
+        if fields.is_none() && pipeline.is_none() {
+            return Err(failed_to_parse(
+                "$lookup requires either 'pipeline' or both 'localField' and 'foreignField' to be specified",
+            ));
+        }
+        if has_let && pipeline.is_none() {
+            return Err(failed_to_parse(
+                "$lookup with 'let' must also specify 'pipeline'",
+            ));
+        }
+        Ok(Self {
+            from,
+            fields,
+            variables,
+            pipeline,
+            as_path,
+        })
+    }
+
+    /// Adds the array of joined documents to `doc`.
+    pub fn run(&self, mut doc: Document, context: &Context) -> CommandResult<Document> {
+        let mut joined: Vec<Document> = match &self.fields {
+            Some((local_field, foreign_field)) => {
+                // A missing local field matches null and missing foreign fields
+                let mut values = values_at(&doc, local_field);
+                if values.is_empty() {
+                    values.push(Value::Null);
+                }
+                foreign_matches(context, &self.from, foreign_field, values)?
+                    .into_iter()
+                    .map(|(_, doc)| doc.into_owned())
+                    .collect()
+            }
+            None => context
+                .collection(&self.from)
+                .into_iter()
+                .map(|(_, doc)| doc.clone())
+                .collect(),
+        };
+
+        if let Some(pipeline) = &self.pipeline {
+            let vars = context.variables(&doc);
+            let mut variables = context.variables.to_vec();
+            for (name, expression) in &self.variables {
+                variables.push((name.clone(), expression.evaluate(&vars)?));
+            }
+            let context = Context {
+                variables: &variables,
+                ..*context
+            };
+            joined = pipeline.run(joined, &context)?;
+        }
+
+        let joined = joined.into_iter().map(Value::Document).collect::<Vec<_>>();
+        set_field(&mut doc, &self.as_path, Some(Value::from(joined)));
+        Ok(doc)
+    }
+}
+
+impl GraphLookup {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(location(
+                40327,
+                format!(
+                    "the $graphLookup stage specification must be an object, but found {}",
+                    spec.type_name()
+                ),
+            ));
+        };
+        let (mut from, mut start_with, mut connect_from, mut connect_to, mut as_field) =
+            (None, None, None, None, None);
+        let (mut max_depth, mut depth_field, mut restrict) = (None, None, None);
+        for (key, value) in spec.iter() {
+            match (key.as_str(), value) {
+                ("startWith", value) => start_with = Some(Expression::parse(value)?),
+                ("from", Value::String(s)) => from = Some(s.clone()),
+                ("connectFromField", Value::String(s)) => connect_from = Some(split_path(s)),
+                ("connectToField", Value::String(s)) => connect_to = Some(s.clone()),
+                ("as", Value::String(s)) => as_field = Some(split_path(s)),
+                ("depthField", Value::String(s)) => depth_field = Some(split_path(s)),
+                ("from" | "connectFromField" | "connectToField" | "as" | "depthField", value) => {
+                    return Err(location(
+                        40103,
+                        format!("expected string as argument for {}, found: {}", key, value),
+                    ));
+                }
+                ("maxDepth", value) => {
+                    if !value.is_number() {
+                        return Err(location(
+                            40100,
+                            format!(
+                                "maxDepth must be numeric, found type: {}",
+                                value.type_name()
+                            ),
+                        ));
+                    }
+                    let Some(depth) = value.as_i64() else {
+                        return Err(location(
+                            40102,
+                            format!(
+                                "maxDepth could not be represented as a long long: {}",
+                                value
+                            ),
+                        ));
+                    };
+                    if depth < 0 {
+                        return Err(location(
+                            40101,
+                            format!("maxDepth requires a nonnegative argument, found: {}", depth),
+                        ));
+                    }
+                    max_depth = Some(depth);
+                }
+                ("restrictSearchWithMatch", Value::Document(filter)) => {
+                    restrict = Some(Matcher::new(filter)?);
+                }
+                ("restrictSearchWithMatch", value) => {
+                    return Err(location(
+                        40185,
+                        format!(
+                            "restrictSearchWithMatch must be an object, found {}",
+                            value.type_name()
+                        ),
+                    ));
+                }
+                (key, _) => {
+                    return Err(location(
+                        40104,
+                        format!("Unknown argument to $graphLookup: {}", key),
+                    ));
+                }
+            }
+        }
+
+        let (Some(from), Some(start_with), Some(connect_from), Some(connect_to), Some(as_path)) =
+            (from, start_with, connect_from, connect_to, as_field)
+        else {
+            return Err(location(
+                40105,
+                "$graphLookup requires 'from', 'as', 'startWith', 'connectFromField', and 'connectToField' to be specified.",
+            ));
+        };
+        Ok(Self {
+            from,
+            start_with,
+            connect_from,
+            connect_to,
+            as_path,
+            max_depth,
+            depth_field,
+            restrict,
+        })
+    }
+
+    /// Adds the documents reachable from `startWith` to `doc`, each at most
+    /// once, with its depth in `depthField` when given.
+    pub fn run(&self, mut doc: Document, context: &Context) -> CommandResult<Document> {
+        let mut frontier = match self.start_with.eval(&context.variables(&doc))? {
+            Value::Array(items) => items.0,
+            value => vec![value],
+        };
+        let mut searched = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut found = Vec::new();
+        let mut depth = 0;
+        while self.max_depth.is_none_or(|max| depth <= max) {
+            // Each value is only searched for once
+            let mut values: Vec<Value> = Vec::new();
+            for value in frontier {
+                if searched.insert(Searched(value.clone())) {
+                    values.push(value);
+                }
+            }
+            if values.is_empty() {
+                break;
+            }
+
+            let mut next = Vec::new();
+            for (id, matched) in foreign_matches(context, &self.from, &self.connect_to, values)? {
+                if visited.contains(&id)
+                    || self.restrict.as_ref().is_some_and(|r| !r.matches(&matched))
+                {
+                    continue;
+                }
+                visited.insert(id);
+                next.extend(values_at(&matched, &self.connect_from));
+                let mut matched = matched.into_owned();
+                if let Some(depth_field) = &self.depth_field {
+                    set_field(&mut matched, depth_field, Some(Value::Int64(depth)));
+                }
+                found.push(Value::Document(matched));
+            }
+            frontier = next;
+            depth += 1;
+        }
+        set_field(&mut doc, &self.as_path, Some(Value::from(found)));
+        Ok(doc)
+    }
+}
+
+/// A value `$graphLookup` has searched for, equal to the values it
+/// `equals`.
+#[derive(Debug)]
+struct Searched(Value);
+
+impl PartialEq for Searched {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.equals(&other.0)
+    }
+}
+
+impl Eq for Searched {}
+
+impl Hash for Searched {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash_value(state);
+    }
+}
+
+impl UnionWith {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let spec = match spec {
+            Value::String(coll) => {
+                return Ok(Self {
+                    coll: coll.clone(),
+                    pipeline: None,
+                });
+            }
+            Value::Document(spec) => spec,
+            value => {
+                return Err(failed_to_parse(format!(
+                    "the $unionWith stage specification must be an object or string, but found {}",
+                    value.type_name()
+                )));
+            }
+        };
+        let (mut coll, mut pipeline) = (None, None);
+        for (key, value) in spec.iter() {
+            match (key.as_str(), value) {
+                ("coll", Value::String(name)) => coll = Some(name.clone()),
+                ("pipeline", Value::Array(stages)) => {
+                    let parsed = Pipeline::parse(&stages.0)?;
+                    if parsed.output().is_some() {
+                        return Err(location(
+                            31441,
+                            "$out and $merge are not allowed within a $unionWith pipeline",
+                        ));
+                    }
+                    if parsed.geo_near().is_some() {
+                        return Err(location(
+                            40602,
+                            "$geoNear is not allowed within a $unionWith pipeline",
+                        ));
+                    }
+                    pipeline = Some(parsed);
+                }
+                ("coll" | "pipeline", value) => {
+                    return Err(CommandError::new(
+                        ErrorCode::TypeMismatch,
+                        format!(
+                            "BSON field '$unionWith.{}' is the wrong type '{}', expected type '{}'",
+                            key,
+                            value.type_name(),
+                            if key == "coll" { "string" } else { "array" }
+                        ),
+                    ));
+                }
+                (key, _) => {
+                    return Err(location(
+                        40415,
+                        format!("BSON field '$unionWith.{}' is an unknown field.", key),
+                    ));
+                }
+            }
+        }
+        let Some(coll) = coll else {
+            return Err(location(
+                40414,
+                "BSON field '$unionWith.coll' is missing but a required field",
+            ));
+        };
+        Ok(Self { coll, pipeline })
+    }
+
+    pub fn run(
+        &self,
+        mut documents: Vec<Document>,
+        context: &Context,
+    ) -> CommandResult<Vec<Document>> {
+        let foreign = context
+            .collection(&self.coll)
+            .into_iter()
+            .map(|(_, doc)| doc.clone())
+            .collect();
+        let foreign = match &self.pipeline {
+            Some(pipeline) => pipeline.run(foreign, context)?,
+            None => foreign,
+        };
+        documents.extend(foreign);
+        Ok(documents)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::json::{doc, value},
+        storage::{Index, Storage},
+    };
+
+    fn aggregate(
+        storage: &Storage,
+        pipeline: &str,
+        documents: &[&str],
+    ) -> CommandResult<Vec<Document>> {
+        let Value::Array(stages) = value(pipeline) else {
+            panic!("pipeline must be an array");
+        };
+        Pipeline::parse(&stages.0)?.run(
+            documents.iter().map(|d| doc(d)).collect(),
+            &Context::new(storage, "db"),
+        )
+    }
+
+    fn storage(collection: &str, documents: &[&str]) -> Storage {
+        let mut storage = Storage::new();
+        for document in documents {
+            storage
+                .collection_mut(&format!("db.{}", collection))
+                .insert(doc(document))
+                .unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn test_lookup() {
+        let storage = storage(
+            "items",
+            &[
+                "{ _id: 1, sku: 'a', qty: 5 }",
+                "{ _id: 2, sku: 'b', qty: 1 }",
+                "{ _id: 3, sku: ['a', 'c'], qty: 2 }",
+                "{ _id: 4, qty: 0 }",
+            ],
+        );
+        assert_eq!(
+            aggregate(
+                &storage,
+                "[{ $lookup: { from: 'items', localField: 'skus', foreignField: 'sku', as: 'found' } }, { $project: { ids: '$found._id' } }]",
+                &["{ _id: 1, skus: ['c', 'b'] }", "{ _id: 2 }"]
+            )
+            .unwrap(),
+            vec![doc("{ _id: 1, ids: [2, 3] }"), doc("{ _id: 2, ids: [4] }")]
+        );
+        assert_eq!(
+            aggregate(
+                &storage,
+                "[{ $lookup: { from: 'items', let: { sku: '$sku', min: '$min' }, pipeline: [{ $match: { $expr: { $and: [{ $in: ['$$sku', { $concatArrays: [[], { $cond: [{ $isArray: '$sku' }, '$sku', ['$sku']] }] }] }, { $gte: ['$qty', '$$min'] }] } } }, { $project: { _id: 1 } }], as: 'found' } }]",
+                &["{ _id: 1, sku: 'a', min: 3 }", "{ _id: 2, sku: 'a', min: 0 }"]
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: 1, sku: 'a', min: 3, found: [{ _id: 1 }] }"),
+                doc("{ _id: 2, sku: 'a', min: 0, found: [{ _id: 1 }, { _id: 3 }] }"),
+            ]
+        );
+        assert_eq!(
+            aggregate(
+                &storage,
+                "[{ $lookup: { from: 'missing', localField: 'a', foreignField: 'a', as: 'found' } }]",
+                &["{ _id: 1 }"]
+            )
+            .unwrap(),
+            vec![doc("{ _id: 1, found: [] }")]
+        );
+
+        let code = |pipeline| aggregate(&storage, pipeline, &[]).unwrap_err().code;
+        assert_eq!(
+            code("[{ $lookup: { from: 'items', localField: 'a', as: 'b' } }]"),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(
+            code("[{ $lookup: { from: 'items', let: { a: 1 }, localField: 'a', foreignField: 'a', as: 'b' } }]"),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(
+            code("[{ $lookup: { from: 'items', let: { A: 1 }, pipeline: [], as: 'b' } }]"),
+            ErrorCode::Location(16867)
+        );
+    }
+
+    #[test]
+    fn test_lookup_uses_index() {
+        let mut storage = storage(
+            "items",
+            &[
+                "{ _id: 1, k: 'a', x: 1 }",
+                "{ _id: 2, k: 'b', x: 2 }",
+                "{ _id: 3, k: 'a', x: 3 }",
+            ],
+        );
+        let pipeline = "[{ $lookup: { from: 'items', localField: 'k', foreignField: 'k', as: 'found' } }, { $project: { ids: '$found._id' } }]";
+        assert_eq!(
+            aggregate(&storage, pipeline, &["{ _id: 1, k: 'a' }"]).unwrap(),
+            vec![doc("{ _id: 1, ids: [1, 3] }")]
+        );
+        // The matches come in index order once the index answers them
+        let collection = storage.collection_mut("db.items");
+        let index = Index::parse(&doc("{ key: { k: 1, x: -1 } }")).unwrap();
+        let index = collection.build_index(index).unwrap();
+        collection.add_index(index);
+        assert_eq!(
+            aggregate(&storage, pipeline, &["{ _id: 1, k: 'a' }"]).unwrap(),
+            vec![doc("{ _id: 1, ids: [3, 1] }")]
+        );
+    }
+
+    #[test]
+    fn test_graph_lookup() {
+        let storage = storage(
+            "employees",
+            &[
+                "{ _id: 1, name: 'Dev' }",
+                "{ _id: 2, name: 'Eliot', reportsTo: 'Dev' }",
+                "{ _id: 3, name: 'Ron', reportsTo: 'Eliot' }",
+                "{ _id: 4, name: 'Andrew', reportsTo: 'Eliot' }",
+                "{ _id: 5, name: 'Asya', reportsTo: 'Ron' }",
+                "{ _id: 6, name: 'Dan', reportsTo: 'Andrew', active: false }",
+            ],
+        );
+        assert_eq!(
+            aggregate(
+                &storage,
+                "[{ $graphLookup: { from: 'employees', startWith: '$reportsTo', connectFromField: 'reportsTo', connectToField: 'name', as: 'chain', depthField: 'depth' } }, { $project: { chain: { $map: { input: '$chain', in: ['$$this._id', '$$this.depth'] } } } }]",
+                &["{ _id: 5, reportsTo: 'Ron' }"]
+            )
+            .unwrap(),
+            vec![doc("{ _id: 5, chain: [[3, Long(0)], [2, Long(1)], [1, Long(2)]] }")]
+        );
+        assert_eq!(
+            aggregate(
+                &storage,
+                "[{ $graphLookup: { from: 'employees', startWith: '$name', connectFromField: 'name', connectToField: 'reportsTo', as: 'reports', maxDepth: 1, restrictSearchWithMatch: { active: { $ne: false } } } }, { $project: { ids: '$reports._id' } }]",
+                &["{ _id: 2, name: 'Eliot' }"]
+            )
+            .unwrap(),
+            vec![doc("{ _id: 2, ids: [3, 4, 5] }")]
+        );
+
+        let code = |pipeline| aggregate(&storage, pipeline, &[]).unwrap_err().code;
+        assert_eq!(
+            code("[{ $graphLookup: { from: 'employees', startWith: 1, as: 'x' } }]"),
+            ErrorCode::Location(40105)
+        );
+        assert_eq!(
+            code("[{ $graphLookup: { from: 'employees', startWith: 1, connectFromField: 'a', connectToField: 'b', as: 'x', maxDepth: -1 } }]"),
+            ErrorCode::Location(40101)
+        );
+        assert_eq!(
+            code("[{ $graphLookup: { from: 'employees', foo: 1 } }]"),
+            ErrorCode::Location(40104)
+        );
+    }
+
+    #[test]
+    fn test_union_with() {
+        let storage = storage("archive", &["{ _id: 1, n: 1 }", "{ _id: 2, n: 2 }"]);
+        assert_eq!(
+            aggregate(
+                &storage,
+                "[{ $unionWith: { coll: 'archive', pipeline: [{ $match: { n: { $gt: 1 } } }] } }]",
+                &["{ _id: 3, n: 3 }"]
+            )
+            .unwrap(),
+            vec![doc("{ _id: 3, n: 3 }"), doc("{ _id: 2, n: 2 }")]
+        );
+        assert_eq!(
+            aggregate(
+                &storage,
+                "[{ $unionWith: 'archive' }, { $count: 'n' }]",
+                &[]
+            )
+            .unwrap(),
+            vec![doc("{ n: 2 }")]
+        );
+
+        let code = |pipeline| aggregate(&storage, pipeline, &[]).unwrap_err().code;
+        assert_eq!(
+            code("[{ $unionWith: { pipeline: [] } }]"),
+            ErrorCode::Location(40414)
+        );
+        assert_eq!(
+            code("[{ $unionWith: { coll: 'archive', pipeline: [{ $out: 'x' }] } }]"),
+            ErrorCode::Location(31441)
+        );
+    }
+}
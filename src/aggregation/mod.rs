@@ -0,0 +1,21 @@
+//! The aggregation framework: expressions, pipeline stages, and pipelines
+//! that run documents through those stages. Update pipelines reuse the
+//! document-reshaping stages.
+
+mod accumulator;
+mod bucket;
+mod densify;
+mod expression;
+mod fill;
+mod geo_near;
+mod group;
+mod lookup;
+mod output;
+mod pipeline;
+mod projection;
+mod spill;
+mod stage;
+mod window;
+
+pub use pipeline::{Context, Metadata, Pipeline};
+pub use projection::Projection;
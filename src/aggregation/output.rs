@@ -0,0 +1,677 @@
+//! The `$out` and `$merge` stages, which write the results of a pipeline to
+//! a collection instead of returning them. They can only end a pipeline, and
+//! run after the other stages with write access to the storage.
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{planner, Matcher, Query},
+    storage::{Collection, Storage},
+    update,
+};
+
+use super::{
+    expression::{validate_variable_name, Expression, Variables},
+    pipeline::{Context, Pipeline},
+};
+
+#[derive(Debug, Clone)]
+pub enum Output {
+    /// `$out`: the collection replaced by the results.
+    Out(Target),
+    Merge(Merge),
+}
+
+#[derive(Debug, Clone)]
+pub struct Merge {
+    into: Target,
+    on: Vec<String>,
+    variables: Vec<(String, Expression)>,
+    when_matched: WhenMatched,
+    when_not_matched: WhenNotMatched,
+}
+
+#[derive(Debug, Clone)]
+enum WhenMatched {
+    Replace,
+    KeepExisting,
+    Merge,
+    Fail,
+    /// An update pipeline applied to the existing document, with the result
+    /// document available as `$$new` by default.
+    Pipeline(Pipeline),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum WhenNotMatched {
+    Insert,
+    Discard,
+    Fail,
+}
+
+/// An output collection, in the database of the aggregation unless given.
+#[derive(Debug, Clone)]
+pub struct Target {
+    db: Option<String>,
+    coll: String,
+}
+
+impl Target {
+    fn namespace(&self, db: &str) -> String {
+        format!("{}.{}", self.db.as_deref().unwrap_or(db), self.coll)
+    }
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+/// An output collection, given by name or as `{ db: ..., coll: ... }`.
+fn parse_target(stage: &str, spec: &Value) -> CommandResult<Target> {
+    let (db, coll) = match spec {
+        Value::String(coll) => (None, coll.as_str()),
+        Value::Document(spec) => {
+            if let Some(key) = spec.keys().find(|key| *key != "db" && *key != "coll") {
+                return Err(location(
+                    40415,
+                    format!("BSON field '{}.{}' is an unknown field.", stage, key),
+                ));
+            }
+            let db = match spec.get("db") {
+                Some(Value::String(db)) => Some(db.as_str()),
+                None => None,
+                Some(value) => return Err(wrong_type(stage, "db", value, "string")),
+            };
+            match spec.get("coll") {
+                Some(Value::String(coll)) => (db, coll.as_str()),
+                Some(value) => return Err(wrong_type(stage, "coll", value, "string")),
+                None => {
+                    return Err(location(
+                        40414,
+                        format!(
+                            "BSON field '{}.coll' is missing but a required field",
+                            stage
+                        ),
+                    ));
+                }
+            }
+        }
+        value => {
+            return Err(location(
+                16990,
+                format!(
+                    "{} only supports a string or object argument, but found {}",
+                    stage,
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    if db == Some("") || coll.is_empty() || coll.starts_with("system.") || coll.contains('$') {
+        return Err(CommandError::new(
+            ErrorCode::InvalidNamespace,
+            format!(
+                "Invalid {} target namespace, '{}.{}'",
+                stage,
+                db.unwrap_or_default(),
+                coll
+            ),
+        ));
+    }
+    Ok(Target {
+        db: db.map(String::from),
+        coll: coll.to_string(),
+    })
+}
+
+fn wrong_type(stage: &str, field: &str, value: &Value, expected: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::TypeMismatch,
+        format!(
+            "BSON field '{}.{}' is the wrong type '{}', expected type '{}'",
+            stage,
+            field,
+            value.type_name(),
+            expected
+        ),
+    )
+}
+
+impl Output {
+    /// Parses `$out` or `$merge`.
+    pub fn parse(name: &str, spec: &Value) -> CommandResult<Self> {
+        match name {
+            "$out" => Ok(Output::Out(parse_target(name, spec)?)),
+            _ => Ok(Output::Merge(Merge::parse(spec)?)),
+        }
+    }
+
+    /// Writes the results of the pipeline, with `db` the database of the
+    /// aggregation.
+    pub fn write(
+        &self,
+        documents: impl IntoIterator<Item = CommandResult<Document>>,
+        storage: &mut Storage,
+        db: &str,
+    ) -> CommandResult<()> {
+        match self {
+            Output::Out(target) => {
+                // The results go into a new collection which only replaces
+                // the target once every document was inserted, so a failure
+                // leaves the target as it was. The target keeps its indexes,
+                // and can't be replaced while some are being built.
+                let namespace = target.namespace(db);
+                let mut collection = match storage.collection(&namespace) {
+                    Some(existing) => {
+                        existing.check_no_index_builds()?;
+                        existing.empty_clone()
+                    }
+                    None => Collection::new(&namespace),
+                };
+                for doc in documents {
+                    collection.insert(doc?)?;
+                }
+                storage.replace_collection(collection);
+                Ok(())
+            }
+            Output::Merge(merge) => merge.write(documents, storage, &merge.into.namespace(db)),
+        }
+    }
+}
+
+impl Merge {
+    fn parse(spec: &Value) -> CommandResult<Self> {
+        let spec = match spec {
+            Value::String(_) => {
+                let mut into = Document::new();
+                into.insert("into", spec.clone());
+                into
+            }
+            Value::Document(spec) => spec.clone(),
+            value => {
+                return Err(location(
+                    51182,
+                    format!(
+                        "$merge only supports a string or object argument, but found {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        if let Some(key) = spec.keys().find(|key| {
+            !matches!(
+                key.as_str(),
+                "into" | "on" | "let" | "whenMatched" | "whenNotMatched"
+            )
+        }) {
+            return Err(location(
+                40415,
+                format!("BSON field '$merge.{}' is an unknown field.", key),
+            ));
+        }
+
+        let into = match spec.get("into") {
+            Some(into) => parse_target("$merge", into)?,
+            None => {
+                return Err(location(
+                    40414,
+                    "BSON field '$merge.into' is missing but a required field",
+                ));
+            }
+        };
+        let on = match spec.get("on") {
+            None => vec!["_id".to_string()],
+            Some(Value::String(field)) => vec![field.clone()],
+            Some(Value::Array(fields)) if !fields.0.is_empty() => {
+                let mut on = Vec::new();
+                for field in &fields.0 {
+                    match field {
+                        Value::String(field) if !on.contains(field) => on.push(field.clone()),
+                        Value::String(field) => {
+                            return Err(location(
+                                31465,
+                                format!("Found a duplicate field '{}'", field),
+                            ));
+                        }
+                        _ => {
+                            return Err(location(
+                                51134,
+                                "$merge 'on' array elements must be strings",
+                            ));
+                        }
+                    }
+                }
+                on
+            }
+            Some(Value::Array(_)) => {
+                return Err(location(
+                    51187,
+                    "If explicitly specifying $merge 'on', must include at least one field",
+                ));
+            }
+            Some(_) => {
+                return Err(location(
+                    51186,
+                    "$merge 'on' field must be either a string or an array of strings",
+                ));
+            }
+        };
+
+        let when_matched = match spec.get("whenMatched") {
+            None => WhenMatched::Merge,
+            Some(Value::String(mode)) => {
+                match mode.as_str() {
+                    "replace" => WhenMatched::Replace,
+                    "keepExisting" => WhenMatched::KeepExisting,
+                    "merge" => WhenMatched::Merge,
+                    "fail" => WhenMatched::Fail,
+                    mode => {
+                        return Err(CommandError::new(
+                        ErrorCode::BadValue,
+                        format!("Enumeration value '{}' for field 'whenMatched' is not a valid value.", mode),
+                    ));
+                    }
+                }
+            }
+            Some(Value::Array(stages)) => WhenMatched::Pipeline(update::parse_pipeline(&stages.0)?),
+            Some(value) => {
+                return Err(location(
+                    51191,
+                    format!(
+                        "$merge 'whenMatched' field must be either a string or an array, but found {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        let when_not_matched = match spec.get("whenNotMatched") {
+            None => WhenNotMatched::Insert,
+            Some(Value::String(mode)) => match mode.as_str() {
+                "insert" => WhenNotMatched::Insert,
+                "discard" => WhenNotMatched::Discard,
+                "fail" => WhenNotMatched::Fail,
+                mode => {
+                    return Err(CommandError::new(
+                        ErrorCode::BadValue,
+                        format!("Enumeration value '{}' for field 'whenNotMatched' is not a valid value.", mode),
+                    ));
+                }
+            },
+            Some(value) => return Err(wrong_type("$merge", "whenNotMatched", value, "string")),
+        };
+
+        let variables = match spec.get("let") {
+            None => Vec::new(),
+            Some(_) if !matches!(when_matched, WhenMatched::Pipeline(_)) => {
+                return Err(location(
+                    51199,
+                    "Cannot use 'let' variables with 'whenMatched' mode other than a pipeline",
+                ));
+            }
+            Some(Value::Document(variables)) => {
+                let mut parsed = Vec::new();
+                for (name, value) in variables.iter() {
+                    validate_variable_name(name)?;
+                    parsed.push((name.clone(), Expression::parse(value)?));
+                }
+                parsed
+            }
+            Some(value) => return Err(wrong_type("$merge", "let", value, "object")),
+        };
+        // Without `let`, the pipeline sees the result document as `$$new`
+        let variables = match (&when_matched, spec.contains_key("let")) {
+            (WhenMatched::Pipeline(_), false) => {
+                vec![(
+                    "new".to_string(),
+                    Expression::parse(&Value::String("$$ROOT".into()))?,
+                )]
+            }
+            _ => variables,
+        };
+
+        Ok(Self {
+            into,
+            on,
+            variables,
+            when_matched,
+            when_not_matched,
+        })
+    }
+
+    fn write(
+        &self,
+        documents: impl IntoIterator<Item = CommandResult<Document>>,
+        storage: &mut Storage,
+        namespace: &str,
+    ) -> CommandResult<()> {
+        // Matching on fields without a unique index could find several
+        // documents for one result. Like `$out`, the target can't be written
+        // while indexes are being built.
+        let unique = match storage.collection(namespace) {
+            Some(collection) => {
+                collection.check_no_index_builds()?;
+                collection.has_unique_index(&self.on)
+            }
+            None => self.on == ["_id"],
+        };
+        if !unique {
+            return Err(location(
+                51183,
+                "Cannot find index to verify that join fields will be unique",
+            ));
+        }
+
+        let collection = storage.collection_mut(namespace);
+        for doc in documents {
+            let mut doc = doc?;
+            if self.on == ["_id"] && !doc.contains_key("_id") {
+                doc.0.insert(0, ("_id".to_string(), Value::new_object_id()));
+            }
+            let mut key = Vec::new();
+            for field in &self.on {
+                match lookup(&doc, field) {
+                    Some(value)
+                        if !value.is_null_or_undefined() && !matches!(value, Value::Array(_)) =>
+                    {
+                        key.push((field, value.clone()))
+                    }
+                    _ => {
+                        return Err(location(
+                            51132,
+                            format!(
+                                "$merge write error: 'on' field '{}' cannot be missing, null, undefined or an array",
+                                field
+                            ),
+                        ));
+                    }
+                }
+            }
+            // Found through the unique index on the fields
+            let mut filter = Document::new();
+            for (field, value) in &key {
+                let mut eq = Document::new();
+                eq.insert("$eq", value.clone());
+                filter.insert(field.to_string(), Value::Document(eq));
+            }
+            let matcher = Matcher::new(&filter)?;
+            let existing = planner::plan(Some(collection), &Query::new(&matcher))?
+                .execution
+                .documents
+                .into_iter()
+                .find(|(_, existing)| {
+                    key.iter().all(|(field, value)| {
+                        lookup(existing, field).is_some_and(|v| v.equals(value))
+                    })
+                })
+                .map(|(id, existing)| (id, existing.into_owned()));
+
+            match existing {
+                None => match self.when_not_matched {
+                    WhenNotMatched::Insert => {
+                        collection.insert(doc)?;
+                    }
+                    WhenNotMatched::Discard => {}
+                    WhenNotMatched::Fail => {
+                        return Err(location(
+                            13113,
+                            "$merge could not find a matching document in the target collection for at least one document in the source collection",
+                        ));
+                    }
+                },
+                Some((id, existing)) => {
+                    let updated = match &self.when_matched {
+                        WhenMatched::KeepExisting => continue,
+                        WhenMatched::Fail => {
+                            let mut dup_key = Document::new();
+                            for (field, value) in key {
+                                dup_key.insert(field.clone(), value);
+                            }
+                            return Err(CommandError::new(
+                                ErrorCode::DuplicateKey,
+                                format!(
+                                    "E11000 duplicate key error collection: {} dup key: {}",
+                                    namespace,
+                                    Value::Document(dup_key)
+                                ),
+                            ));
+                        }
+                        WhenMatched::Replace => doc,
+                        WhenMatched::Merge => {
+                            let mut merged = existing.clone();
+                            for (field, value) in doc.iter() {
+                                merged.insert(field.clone(), value.clone());
+                            }
+                            merged
+                        }
+                        WhenMatched::Pipeline(pipeline) => {
+                            let root = Variables::new(&doc);
+                            let mut bindings = Vec::new();
+                            for (name, expression) in &self.variables {
+                                bindings.push((name.clone(), expression.evaluate(&root)?));
+                            }
+                            let context = Context {
+                                variables: &bindings,
+                                ..Context::default()
+                            };
+                            pipeline
+                                .run(vec![existing.clone()], &context)?
+                                .pop()
+                                .unwrap_or_default()
+                        }
+                    };
+                    collection.replace(id, keep_id(&existing, updated)?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The value of a dotted `on` field.
+fn lookup<'a>(doc: &'a Document, field: &str) -> Option<&'a Value> {
+    let mut parts = field.split('.');
+    let mut value = doc.get(parts.next()?)?;
+    for part in parts {
+        match value {
+            Value::Document(doc) => value = doc.get(part)?,
+            _ => return None,
+        }
+    }
+    Some(value)
+}
+
+/// Gives an updated document the `_id` of the document it replaces, which
+/// it can't change.
+fn keep_id(existing: &Document, mut updated: Document) -> CommandResult<Document> {
+    let Some(id) = existing.get("_id") else {
+        return Ok(updated);
+    };
+    match updated.get("_id") {
+        None => {
+            updated.0.insert(0, ("_id".to_string(), id.clone()));
+            Ok(updated)
+        }
+        Some(new_id) if new_id.equals(id) => Ok(updated),
+        Some(new_id) => Err(CommandError::new(
+            ErrorCode::ImmutableField,
+            format!(
+                "$merge failed to update the matching document, did you attempt to modify the _id or the shard key? The (immutable) field '_id' was found to have been altered to _id: {}",
+                new_id
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::json::{doc, value},
+        storage::{Index, IndexBuild},
+    };
+
+    fn merge(spec: &str, target: &[&str], documents: &[&str]) -> CommandResult<Vec<Document>> {
+        let mut storage = Storage::new();
+        for target in target {
+            storage.collection_mut("db.target").insert(doc(target))?;
+        }
+        Output::parse("$merge", &value(spec))?.write(
+            documents.iter().map(|d| Ok(doc(d))),
+            &mut storage,
+            "db",
+        )?;
+        Ok(storage
+            .collection("db.target")
+            .map(|collection| collection.iter().map(|(_, doc)| doc.clone()).collect())
+            .unwrap_or_default())
+    }
+
+    #[test]
+    fn test_out() {
+        let mut storage = Storage::new();
+        storage
+            .collection_mut("db.target")
+            .insert(doc("{ _id: 1 }"))
+            .unwrap();
+        let out = Output::parse("$out", &value("'target'")).unwrap();
+
+        // A failed write leaves the target untouched
+        let duplicates = [doc("{ _id: 2 }"), doc("{ _id: 2 }")].map(Ok);
+        assert_eq!(
+            out.write(duplicates, &mut storage, "db").unwrap_err().code,
+            ErrorCode::DuplicateKey
+        );
+        out.write([Ok(doc("{ _id: 2, a: 1 }"))], &mut storage, "db")
+            .unwrap();
+        let target: Vec<_> = storage
+            .collection("db.target")
+            .unwrap()
+            .iter()
+            .map(|(_, doc)| doc.clone())
+            .collect();
+        assert_eq!(target, vec![doc("{ _id: 2, a: 1 }")]);
+        Output::parse("$out", &value("{ db: 'other', coll: 'c' }"))
+            .unwrap()
+            .write([], &mut storage, "db")
+            .unwrap();
+        assert!(storage.collection("other.c").is_some());
+    }
+
+    #[test]
+    fn test_merge_modes() {
+        let target = ["{ _id: 1, a: 1, b: 1 }", "{ _id: 2, a: 2 }"];
+        let source = ["{ _id: 1, b: 2 }", "{ _id: 3, a: 3 }"];
+        assert_eq!(
+            merge("'target'", &target, &source).unwrap(),
+            vec![
+                doc("{ _id: 1, a: 1, b: 2 }"),
+                doc("{ _id: 2, a: 2 }"),
+                doc("{ _id: 3, a: 3 }"),
+            ]
+        );
+        assert_eq!(
+            merge(
+                "{ into: 'target', whenMatched: 'replace', whenNotMatched: 'discard' }",
+                &target,
+                &source
+            )
+            .unwrap(),
+            vec![doc("{ _id: 1, b: 2 }"), doc("{ _id: 2, a: 2 }")]
+        );
+        assert_eq!(
+            merge(
+                "{ into: 'target', whenMatched: [{ $set: { a: { $add: ['$a', '$$new.b'] } } }] }",
+                &target,
+                &source
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: 1, a: 3, b: 1 }"),
+                doc("{ _id: 2, a: 2 }"),
+                doc("{ _id: 3, a: 3 }"),
+            ]
+        );
+        assert_eq!(
+            merge(
+                "{ into: 'target', let: { inc: '$b' }, whenMatched: [{ $set: { b: { $multiply: ['$b', '$$inc'] } } }], whenNotMatched: 'discard' }",
+                &target,
+                &source
+            )
+            .unwrap(),
+            vec![doc("{ _id: 1, a: 1, b: 2 }"), doc("{ _id: 2, a: 2 }")]
+        );
+        assert_eq!(
+            merge("{ into: 'target', whenMatched: 'fail' }", &target, &source)
+                .unwrap_err()
+                .code,
+            ErrorCode::DuplicateKey
+        );
+        assert_eq!(
+            merge(
+                "{ into: 'target', whenNotMatched: 'fail' }",
+                &target,
+                &source
+            )
+            .unwrap_err()
+            .code,
+            ErrorCode::Location(13113)
+        );
+    }
+
+    #[test]
+    fn test_merge_on_unique_index() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("db.target");
+        let index = Index::parse(&doc("{ key: { a: 1 }, unique: true }")).unwrap();
+        let index = collection.build_index(index).unwrap();
+        collection.add_index(index);
+        for target in ["{ _id: 1, a: 1 }", "{ _id: 2, a: 2 }"] {
+            collection.insert(doc(target)).unwrap();
+        }
+        let output = Output::parse("$merge", &value("{ into: 'target', on: 'a' }")).unwrap();
+        let source = ["{ a: 2, b: 2 }", "{ a: 3, b: 3 }"].map(|d| Ok(doc(d)));
+        output.write(source, &mut storage, "db").unwrap();
+        let target: Vec<_> = storage
+            .collection("db.target")
+            .unwrap()
+            .iter()
+            .map(|(_, doc)| doc.clone())
+            .collect();
+        assert_eq!(
+            target[..2],
+            [doc("{ _id: 1, a: 1 }"), doc("{ _id: 2, a: 2, b: 2 }")]
+        );
+        assert_eq!(target[2].get("a"), Some(&Value::Int32(3)));
+
+        // Like `$out`, `$merge` waits for index builds on the target
+        let index = Index::parse(&doc("{ key: { b: 1 } }")).unwrap();
+        let build = IndexBuild::new(1, Document::new(), vec![index], Document::new(), 3);
+        storage.collection_mut("db.target").start_index_build(build);
+        assert_eq!(
+            output.write([], &mut storage, "db").unwrap_err().code,
+            ErrorCode::BackgroundOperationInProgressForNamespace
+        );
+    }
+
+    #[test]
+    fn test_invalid_merge() {
+        let code = |spec| merge(spec, &[], &["{ a: 1 }"]).unwrap_err().code;
+        assert_eq!(
+            code("{ into: 'target', on: 'a' }"),
+            ErrorCode::Location(51183)
+        );
+        assert_eq!(code("{ on: '_id' }"), ErrorCode::Location(40414));
+        assert_eq!(
+            code("{ into: 'target', let: { x: 1 } }"),
+            ErrorCode::Location(51199)
+        );
+        assert_eq!(
+            code("{ into: 'target', whenMatched: [{ $group: { _id: 1 } }] }"),
+            ErrorCode::InvalidOptions
+        );
+        assert_eq!(
+            code("{ into: 'target', whenMatched: 'upsert' }"),
+            ErrorCode::BadValue
+        );
+    }
+}
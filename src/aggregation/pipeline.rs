@@ -0,0 +1,351 @@
+use std::{borrow::Cow, path::Path};
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{planner, Matcher, Query},
+    storage::{RecordId, Storage},
+};
+
+use super::{
+    expression::Variables,
+    geo_near::GeoNear,
+    output::Output,
+    spill::{self, Buffer, MEMORY_LIMIT},
+    stage::{Documents, Stage},
+};
+
+/// What a pipeline knows of a document besides its fields, carried beside
+/// it from stage to stage and read by `$meta`. Stages building new
+/// documents, like `$group`, give them none.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Metadata {
+    /// The score of a document found by a `$text` query.
+    pub text_score: Option<f64>,
+}
+
+/// The error of reading the text score of a document no `$text` query found.
+pub fn no_text_score() -> CommandError {
+    CommandError::new(
+        ErrorCode::Location(40218),
+        "query requires text score metadata, but it is not available",
+    )
+}
+
+/// What stages can see besides their input documents: the storage, for
+/// stages reading other collections of the database, the variables bound by
+/// an enclosing `$lookup`, the memory blocking stages may use, and the
+/// collation `$match` and `$sort` compare strings under. Update pipelines
+/// run without storage.
+#[derive(Debug, Clone, Copy)]
+pub struct Context<'a> {
+    pub storage: Option<&'a Storage>,
+    pub db: &'a str,
+    pub variables: &'a [(String, Option<Value>)],
+    /// The memory each blocking stage may use, in bytes.
+    pub memory_limit: usize,
+    /// Where blocking stages spill over the memory limit, when the
+    /// aggregation allows disk use.
+    pub spill_dir: Option<&'a Path>,
+    pub collation: Option<&'a Collation>,
+}
+
+impl Default for Context<'_> {
+    fn default() -> Self {
+        Self {
+            storage: None,
+            db: "",
+            variables: &[],
+            memory_limit: MEMORY_LIMIT,
+            spill_dir: None,
+            collation: None,
+        }
+    }
+}
+
+impl<'a> Context<'a> {
+    pub fn new(storage: &'a Storage, db: &'a str) -> Self {
+        Self {
+            storage: Some(storage),
+            db,
+            ..Self::default()
+        }
+    }
+
+    /// The variables for evaluating expressions against a document.
+    pub fn variables<'d>(&self, doc: &'d Document) -> Variables<'d> {
+        Variables::with_bindings(doc, self.variables)
+    }
+
+    /// The documents of a collection of the same database, in record order.
+    pub fn collection(&self, name: &str) -> Vec<(RecordId, &'a Document)> {
+        let namespace = format!("{}.{}", self.db, name);
+        match self
+            .storage
+            .and_then(|storage| storage.collection(&namespace))
+        {
+            Some(collection) => collection.iter().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The documents of a collection of the same database that match
+    /// `matcher`, found through its indexes when the planner can use them.
+    pub fn find(
+        &self,
+        name: &str,
+        matcher: &Matcher,
+    ) -> CommandResult<Vec<(RecordId, Cow<'a, Document>)>> {
+        let namespace = format!("{}.{}", self.db, name);
+        let collection = self
+            .storage
+            .and_then(|storage| storage.collection(&namespace));
+        Ok(planner::plan(collection, &Query::new(matcher))?
+            .execution
+            .documents)
+    }
+}
+
+/// A parsed aggregation pipeline, with the `$out` or `$merge` stage ending
+/// it kept apart since it writes to the storage, and a leading `$geoNear`
+/// since the planner answers it.
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    geo_near: Option<Box<GeoNear>>,
+    stages: Vec<Stage>,
+    output: Option<Box<Output>>,
+}
+
+impl Pipeline {
+    pub fn parse(stages: &[Value]) -> CommandResult<Self> {
+        let mut pipeline = Vec::new();
+        let mut output = None;
+        let mut geo_near = None;
+        for (i, stage) in stages.iter().enumerate() {
+            let Value::Document(stage) = stage else {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    "Each element of the 'pipeline' array must be an object",
+                ));
+            };
+            match stage.first() {
+                Some((name, spec)) if name == "$out" || name == "$merge" => {
+                    if i + 1 != stages.len() {
+                        return Err(CommandError::new(
+                            ErrorCode::Location(40601),
+                            format!("{} can only be the final stage in the pipeline", name),
+                        ));
+                    }
+                    output = Some(Box::new(Output::parse(name, spec)?));
+                }
+                Some((name, spec)) if name == "$geoNear" && stage.len() == 1 => {
+                    if i > 0 {
+                        return Err(CommandError::new(
+                            ErrorCode::Location(40603),
+                            "$geoNear was not the first stage in the pipeline.",
+                        ));
+                    }
+                    geo_near = Some(Box::new(GeoNear::parse(spec)?));
+                }
+                _ => {
+                    let stage = Stage::parse(stage)?;
+                    // Only the planner can answer `$text`, from the collection
+                    if i > 0
+                        && matches!(&stage, Stage::Match(matcher, _) if matcher.text().is_some())
+                    {
+                        return Err(CommandError::new(
+                            ErrorCode::Location(17313),
+                            "$match with $text is only allowed as the first pipeline stage",
+                        ));
+                    }
+                    pipeline.push(stage);
+                }
+            }
+        }
+        Ok(Self {
+            geo_near,
+            stages: pipeline,
+            output,
+        })
+    }
+
+    /// The `$out` or `$merge` stage writing the results, which callers apply
+    /// after [`Pipeline::run`].
+    pub fn output(&self) -> Option<&Output> {
+        self.output.as_deref()
+    }
+
+    /// The leading `$geoNear` stage, which callers have the planner answer
+    /// before [`Pipeline::run`].
+    pub fn geo_near(&self) -> Option<&GeoNear> {
+        self.geo_near.as_deref()
+    }
+
+    /// The filter of a leading `$match` stage, which the planner can answer
+    /// with an index. The stage still runs, for `$expr`.
+    pub fn leading_match(&self) -> Option<&Matcher> {
+        if self.geo_near.is_some() {
+            return None;
+        }
+        match self.stages.first() {
+            Some(Stage::Match(matcher, _)) => Some(matcher),
+            _ => None,
+        }
+    }
+
+    /// Runs the documents through every stage in turn.
+    pub fn run(&self, documents: Vec<Document>, context: &Context) -> CommandResult<Vec<Document>> {
+        let documents = documents
+            .into_iter()
+            .map(|doc| Ok((doc, Metadata::default())));
+        self.stream(Box::new(documents), context)?.collect()
+    }
+
+    /// Runs documents through every stage in turn as they are read, and
+    /// holds the results under the memory limit of the context, spilling
+    /// them when it allows disk use.
+    pub fn buffer<'s>(
+        &'s self,
+        documents: impl Iterator<Item = (Document, Metadata)> + 's,
+        context: &'s Context,
+    ) -> CommandResult<Buffer> {
+        let results = self.stream(Box::new(documents.map(Ok)), context)?;
+        spill::buffer(results, "aggregate", context)
+    }
+
+    /// The documents out of the last stage, without their metadata.
+    fn stream<'s>(
+        &'s self,
+        mut documents: Documents<'s>,
+        context: &'s Context,
+    ) -> CommandResult<Box<dyn Iterator<Item = CommandResult<Document>> + 's>> {
+        for stage in &self.stages {
+            documents = stage.run(documents, context)?;
+        }
+        Ok(Box::new(documents.map(|doc| Ok(doc?.0))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    fn aggregate(pipeline: &str, documents: &[&str]) -> CommandResult<Vec<Document>> {
+        let Value::Array(stages) = value(pipeline) else {
+            panic!("pipeline must be an array");
+        };
+        Pipeline::parse(&stages.0)?.run(
+            documents.iter().map(|d| doc(d)).collect(),
+            &Context::default(),
+        )
+    }
+
+    #[test]
+    fn test_core_stages() {
+        let documents = [
+            "{ _id: 1, tags: ['a', 'b'], n: 3 }",
+            "{ _id: 2, tags: [], n: 1 }",
+            "{ _id: 3, n: 2 }",
+            "{ _id: 4, tags: 'c', n: 5 }",
+        ];
+        assert_eq!(
+            aggregate(
+                "[{ $match: { n: { $gte: 2 } } }, { $sort: { n: -1 } }, { $skip: 1 }, { $limit: 2 }, { $project: { n: 1 } }]",
+                &documents
+            )
+            .unwrap(),
+            vec![doc("{ _id: 1, n: 3 }"), doc("{ _id: 3, n: 2 }")]
+        );
+        assert_eq!(
+            aggregate(
+                "[{ $unwind: '$tags' }, { $project: { tags: 1 } }]",
+                &documents
+            )
+            .unwrap(),
+            vec![
+                doc("{ _id: 1, tags: 'a' }"),
+                doc("{ _id: 1, tags: 'b' }"),
+                doc("{ _id: 4, tags: 'c' }"),
+            ]
+        );
+        assert_eq!(
+            aggregate(
+                "[{ $unwind: { path: '$tags', includeArrayIndex: 'i', preserveNullAndEmptyArrays: true } }, { $unset: 'n' }]",
+                &documents[1..3]
+            )
+            .unwrap(),
+            vec![doc("{ _id: 2, i: null }"), doc("{ _id: 3, i: null }")]
+        );
+        assert_eq!(
+            aggregate(
+                "[{ $match: { n: { $gt: 1 } } }, { $count: 'total' }]",
+                &documents
+            )
+            .unwrap(),
+            vec![doc("{ total: 3 }")]
+        );
+        assert_eq!(
+            aggregate("[{ $sample: { size: 2 } }]", &documents)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_facet_and_sort_by_count() {
+        let documents = [
+            "{ _id: 1, color: 'red', size: 3 }",
+            "{ _id: 2, color: 'blue', size: 8 }",
+            "{ _id: 3, color: 'red', size: 12 }",
+            "{ _id: 4, color: 'red', size: 7 }",
+        ];
+        assert_eq!(
+            aggregate(
+                "[{ $facet: { colors: [{ $sortByCount: '$color' }], sizes: [{ $bucket: { groupBy: '$size', boundaries: [0, 5, 10, 15] } }], total: [{ $count: 'n' }] } }]",
+                &documents
+            )
+            .unwrap(),
+            vec![doc(
+                "{ colors: [{ _id: 'red', count: 3 }, { _id: 'blue', count: 1 }], sizes: [{ _id: 0, count: 1 }, { _id: 5, count: 2 }, { _id: 10, count: 1 }], total: [{ n: 4 }] }"
+            )]
+        );
+        let code = |pipeline| aggregate(pipeline, &[]).unwrap_err().code;
+        assert_eq!(
+            code("[{ $facet: { a: [{ $facet: { b: [{ $limit: 1 }] } }] } }]"),
+            ErrorCode::Location(40600)
+        );
+        assert_eq!(code("[{ $facet: { a: 1 } }]"), ErrorCode::Location(40170));
+        assert_eq!(
+            code("[{ $sortByCount: 'color' }]"),
+            ErrorCode::Location(40148)
+        );
+    }
+
+    #[test]
+    fn test_invalid_stages() {
+        let code = |pipeline| aggregate(pipeline, &[]).unwrap_err().code;
+        assert_eq!(code("[{ $foo: 1 }]"), ErrorCode::Location(40324));
+        assert_eq!(code("[{ $limit: 0 }]"), ErrorCode::Location(15958));
+        assert_eq!(code("[{ $unwind: 'tags' }]"), ErrorCode::Location(28818));
+        assert_eq!(
+            code("[{ $match: {}, $limit: 1 }]"),
+            ErrorCode::Location(40323)
+        );
+        assert_eq!(code("[1]"), ErrorCode::TypeMismatch);
+        assert_eq!(
+            code("[{ $out: 'target' }, { $limit: 1 }]"),
+            ErrorCode::Location(40601)
+        );
+        assert_eq!(
+            code("[{ $facet: { a: [{ $merge: 'target' }] } }]"),
+            ErrorCode::Location(40600)
+        );
+        assert_eq!(
+            code("[{ $limit: 1 }, { $geoNear: { near: [0, 0], distanceField: 'd' } }]"),
+            ErrorCode::Location(40603)
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! `$project` and `$unset` specifications: inclusion projections, which keep
+//! only the listed fields (plus `_id` unless excluded) and may compute new
+//! ones, and exclusion projections, which drop the listed fields.
+
+use crate::{
+    bson::{Array, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::expression::{Expression, Variables};
+
+#[derive(Debug, Clone)]
+pub struct Projection {
+    inclusion: bool,
+    root: Node,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    fields: Vec<(String, Field)>,
+}
+
+#[derive(Debug, Clone)]
+enum Field {
+    Include,
+    Exclude,
+    Computed(Expression),
+    Nested(Node),
+}
+
+fn invalid(code: i32, message: impl std::fmt::Display) -> CommandError {
+    CommandError::new(
+        ErrorCode::Location(code),
+        format!("Invalid $project :: caused by :: {}", message),
+    )
+}
+
+impl Node {
+    fn get_mut(&mut self, key: &str) -> Option<&mut Field> {
+        self.fields
+            .iter_mut()
+            .find(|(k, _)| k == key)
+            .map(|(_, field)| field)
+    }
+
+    /// Adds `field` at a dotted path, creating nested nodes along the way.
+    fn add(&mut self, path: &[&str], full_path: &str, field: Field) -> CommandResult<()> {
+        let collision = || invalid(31250, format!("Path collision at {}", full_path));
+        let (first, rest) = path.split_first().expect("non-empty path");
+        if rest.is_empty() {
+            if self.get_mut(first).is_some() {
+                return Err(collision());
+            }
+            self.fields.push((first.to_string(), field));
+            return Ok(());
+        }
+        match self.get_mut(first) {
+            Some(Field::Nested(node)) => node.add(rest, full_path, field),
+            Some(_) => Err(collision()),
+            None => {
+                let mut node = Node::default();
+                node.add(rest, full_path, field)?;
+                self.fields.push((first.to_string(), Field::Nested(node)));
+                Ok(())
+            }
+        }
+    }
+
+    fn parse(&mut self, spec: &Document, prefix: &str) -> CommandResult<()> {
+        for (key, value) in spec.iter() {
+            if key.starts_with('$') {
+                return Err(invalid(
+                    16410,
+                    "FieldPath field names may not start with '$'. Consider using $getField or $setField.",
+                ));
+            }
+            let path = format!("{}{}", prefix, key);
+            let field = match value {
+                Value::Boolean(_) | Value::Int32(_) | Value::Int64(_) | Value::Double(_) => {
+                    if value.is_truthy() {
+                        Field::Include
+                    } else {
+                        Field::Exclude
+                    }
+                }
+                Value::Document(nested)
+                    if !nested.is_empty()
+                        && !nested.first().is_some_and(|(k, _)| k.starts_with('$')) =>
+                {
+                    let mut node = Node::default();
+                    node.parse(nested, &format!("{}.", path))?;
+                    Field::Nested(node)
+                }
+                value => Field::Computed(Expression::parse(value)?),
+            };
+            let segments: Vec<&str> = key.split('.').collect();
+            self.add(&segments, &path, field)?;
+        }
+        Ok(())
+    }
+
+    /// Finds the first field other than the top-level `_id` matching
+    /// `predicate`, returning its dotted path.
+    fn find(&self, prefix: &str, predicate: &dyn Fn(&Field) -> bool) -> Option<String> {
+        for (key, field) in &self.fields {
+            if prefix.is_empty() && key == "_id" {
+                continue;
+            }
+            let path = format!("{}{}", prefix, key);
+            match field {
+                Field::Nested(node) => {
+                    if let Some(found) = node.find(&format!("{}.", path), predicate) {
+                        return Some(found);
+                    }
+                }
+                field if predicate(field) => return Some(path),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn include(&self, doc: &Document, vars: &Variables) -> CommandResult<Document> {
+        let mut projected = Document::new();
+        for (key, value) in doc.iter() {
+            match (self.fields.iter().find(|(k, _)| k == key), value) {
+                (Some((_, Field::Include)), value) => {
+                    projected.insert(key.clone(), value.clone());
+                }
+                (Some((_, Field::Nested(node))), Value::Document(nested)) => {
+                    projected.insert(key.clone(), Value::Document(node.include(nested, vars)?));
+                }
+                (Some((_, Field::Nested(node))), Value::Array(items)) => {
+                    projected.insert(key.clone(), node.include_array(items, vars)?);
+                }
+                _ => {}
+            }
+        }
+        for (key, field) in &self.fields {
+            match field {
+                Field::Computed(expression) => {
+                    if let Some(value) = expression.evaluate(vars)? {
+                        projected.insert(key.clone(), value);
+                    }
+                }
+                Field::Nested(node) if !projected.contains_key(key) => {
+                    let nested = node.include(&Document::new(), vars)?;
+                    if !nested.is_empty() {
+                        projected.insert(key.clone(), Value::Document(nested));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(projected)
+    }
+
+    fn include_array(&self, items: &Array, vars: &Variables) -> CommandResult<Value> {
+        let mut projected = Vec::new();
+        for item in &items.0 {
+            match item {
+                Value::Document(doc) => projected.push(Value::Document(self.include(doc, vars)?)),
+                Value::Array(items) => projected.push(self.include_array(items, vars)?),
+                _ => {}
+            }
+        }
+        Ok(Value::Array(Array(projected)))
+    }
+
+    fn exclude(&self, doc: &Document) -> Document {
+        let mut projected = Document::new();
+        for (key, value) in doc.iter() {
+            match (self.fields.iter().find(|(k, _)| k == key), value) {
+                (Some((_, Field::Exclude)), _) => {}
+                (Some((_, Field::Nested(node))), Value::Document(nested)) => {
+                    projected.insert(key.clone(), Value::Document(node.exclude(nested)));
+                }
+                (Some((_, Field::Nested(node))), Value::Array(items)) => {
+                    projected.insert(key.clone(), node.exclude_array(items));
+                }
+                (_, value) => {
+                    projected.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        projected
+    }
+
+    fn exclude_array(&self, items: &Array) -> Value {
+        Value::Array(Array(
+            items
+                .0
+                .iter()
+                .map(|item| match item {
+                    Value::Document(doc) => Value::Document(self.exclude(doc)),
+                    Value::Array(items) => self.exclude_array(items),
+                    item => item.clone(),
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl Projection {
+    /// Parses a `$project` specification.
+    pub fn parse(spec: &Document) -> CommandResult<Self> {
+        if spec.is_empty() {
+            return Err(invalid(
+                51272,
+                "projection specification must have at least one field",
+            ));
+        }
+        let mut root = Node::default();
+        root.parse(spec, "")?;
+
+        let inclusion = root
+            .find("", &|f| matches!(f, Field::Include | Field::Computed(_)))
+            .is_some()
+            || matches!(
+                root.get_mut("_id"),
+                Some(Field::Include | Field::Computed(_))
+            );
+        if inclusion {
+            if let Some(path) = root.find("", &|f| matches!(f, Field::Exclude)) {
+                return Err(invalid(
+                    31254,
+                    format!(
+                        "Cannot do exclusion on field {} in inclusion projection",
+                        path
+                    ),
+                ));
+            }
+            if root.get_mut("_id").is_none() {
+                root.fields.insert(0, ("_id".to_string(), Field::Include));
+            }
+        }
+        Ok(Self { inclusion, root })
+    }
+
+    /// Parses a `$unset` specification: a field path or an array of them.
+    pub fn parse_unset(spec: &Value) -> CommandResult<Self> {
+        let invalid_spec = || {
+            CommandError::new(
+                ErrorCode::Location(31120),
+                "$unset specification must be a string or an array containing only string values",
+            )
+        };
+        let fields: Vec<&str> = match spec {
+            Value::String(field) => vec![field],
+            Value::Array(fields) if fields.0.is_empty() => {
+                return Err(CommandError::new(
+                    ErrorCode::Location(31119),
+                    "$unset specification must be a string or an array with at least one field",
+                ));
+            }
+            Value::Array(fields) => fields
+                .0
+                .iter()
+                .map(|field| field.as_str().ok_or_else(invalid_spec))
+                .collect::<CommandResult<_>>()?,
+            _ => return Err(invalid_spec()),
+        };
+        let mut root = Node::default();
+        for field in fields {
+            let segments: Vec<&str> = field.split('.').collect();
+            root.add(&segments, field, Field::Exclude)?;
+        }
+        Ok(Self {
+            inclusion: false,
+            root,
+        })
+    }
+
+    pub fn apply(&self, doc: &Document) -> CommandResult<Document> {
+        self.apply_with(doc, &Variables::new(doc))
+    }
+
+    /// Applies the projection with variables bound, as in a `$lookup`
+    /// subpipeline.
+    pub(super) fn apply_with(&self, doc: &Document, vars: &Variables) -> CommandResult<Document> {
+        if self.inclusion {
+            self.root.include(doc, vars)
+        } else {
+            Ok(self.root.exclude(doc))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn project(spec: &str, document: &str) -> CommandResult<Document> {
+        Projection::parse(&doc(spec))?.apply(&doc(document))
+    }
+
+    #[test]
+    fn test_inclusion() {
+        assert_eq!(
+            project(
+                "{ b: 1, 'c.d': 1 }",
+                "{ _id: 1, a: 1, b: 2, c: { d: 3, e: 4 } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, b: 2, c: { d: 3 } }")
+        );
+        assert_eq!(
+            project(
+                "{ _id: 0, total: { $add: ['$a', '$b'] }, a: 1 }",
+                "{ _id: 1, a: 1, b: 2 }"
+            )
+            .unwrap(),
+            doc("{ a: 1, total: 3 }")
+        );
+        assert_eq!(
+            project("{ 'a.b': 1 }", "{ a: [{ b: 1, c: 2 }, 5, { c: 3 }] }").unwrap(),
+            doc("{ a: [{ b: 1 }, {}] }")
+        );
+    }
+
+    #[test]
+    fn test_exclusion() {
+        assert_eq!(
+            project(
+                "{ a: 0, 'c.d': 0 }",
+                "{ _id: 1, a: 1, b: 2, c: { d: 3, e: 4 } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, b: 2, c: { e: 4 } }")
+        );
+        assert_eq!(
+            Projection::parse_unset(&crate::bson::json::value("['a', 'b.c']"))
+                .unwrap()
+                .apply(&doc("{ a: 1, b: { c: 1, d: 2 } }"))
+                .unwrap(),
+            doc("{ b: { d: 2 } }")
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let code = |spec| project(spec, "{}").unwrap_err().code;
+        assert_eq!(code("{ a: 1, b: 0 }"), ErrorCode::Location(31254));
+        assert_eq!(code("{ a: 1, 'a.b': 1 }"), ErrorCode::Location(31250));
+        assert_eq!(code("{}"), ErrorCode::Location(51272));
+    }
+}
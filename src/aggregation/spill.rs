@@ -0,0 +1,350 @@
+//! Memory accounting for blocking stages such as `$sort` and `$group`, which
+//! hold all of their input before producing output. Over the memory limit
+//! they fail, unless the aggregation allows disk use: then sorted runs are
+//! spilled to temporary files and merged back as the next stage reads them.
+//! The results of a pipeline, which its cursor or output stage holds, are
+//! buffered under the same limit.
+
+use std::{
+    cmp::Ordering,
+    collections::VecDeque,
+    fs::{self, File},
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+    vec,
+};
+
+use crate::{
+    bson::{Bson, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::pipeline::Context;
+
+/// The memory a blocking stage may use by default, as in mongod.
+pub const MEMORY_LIMIT: usize = 100 * 1024 * 1024;
+
+/// Numbers the spill files of the process.
+static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+
+/// The memory held by a value, estimated by its BSON size.
+pub fn value_size(value: &Value) -> usize {
+    value.to_bytes().len()
+}
+
+pub fn document_size(doc: &Document) -> usize {
+    doc.to_bytes().len()
+}
+
+/// The error of a stage going over the memory limit without disk use.
+pub fn exceeded_memory_limit(stage: &str, context: &Context) -> CommandError {
+    CommandError::new(
+        ErrorCode::QueryExceededMemoryLimitNoDiskUseAllowed,
+        format!(
+            "{} exceeded memory limit of {} bytes, but did not opt in to external sorting. Pass allowDiskUse:true to opt in.",
+            stage, context.memory_limit
+        ),
+    )
+}
+
+fn io_error(err: std::io::Error) -> CommandError {
+    CommandError::new(
+        ErrorCode::InternalError,
+        format!("error spilling to disk: {}", err),
+    )
+}
+
+/// A sorted run of documents written to a temporary file, which is removed
+/// once the run is dropped.
+struct Run {
+    path: PathBuf,
+    reader: BufReader<File>,
+}
+
+impl Run {
+    fn write(dir: &Path, documents: Vec<Document>) -> CommandResult<Self> {
+        fs::create_dir_all(dir).map_err(io_error)?;
+        let path = dir.join(format!(
+            "extsort-{}-{}",
+            process::id(),
+            NEXT_RUN.fetch_add(1, AtomicOrdering::Relaxed)
+        ));
+        let mut writer = BufWriter::new(File::create(&path).map_err(io_error)?);
+        for doc in documents {
+            writer.write_all(&doc.to_bytes()).map_err(io_error)?;
+        }
+        writer.flush().map_err(io_error)?;
+        let reader = BufReader::new(File::open(&path).map_err(io_error)?);
+        Ok(Self { path, reader })
+    }
+
+    fn next(&mut self) -> CommandResult<Option<Document>> {
+        let mut length = [0; 4];
+        match self.reader.read_exact(&mut length) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(io_error(err)),
+        }
+        let mut bytes = vec![0; i32::from_le_bytes(length).max(4) as usize];
+        bytes[..4].copy_from_slice(&length);
+        self.reader.read_exact(&mut bytes[4..]).map_err(io_error)?;
+        Ok(Some(Bson::from_bytes(&bytes).parse()))
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// The sorted runs a blocking stage spilled so far.
+#[derive(Default)]
+pub struct Runs(Vec<Run>);
+
+impl Runs {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Spills documents, already sorted, to the context's spill directory.
+    /// Without one, fails with the memory limit error naming `stage`.
+    pub fn write(
+        &mut self,
+        documents: Vec<Document>,
+        stage: &str,
+        context: &Context,
+    ) -> CommandResult<()> {
+        let Some(dir) = context.spill_dir else {
+            return Err(exceeded_memory_limit(stage, context));
+        };
+        self.0.push(Run::write(dir, documents)?);
+        Ok(())
+    }
+
+    /// Merges the runs with the sorted documents still in memory, which
+    /// come after them.
+    pub fn merge<C>(self, memory: Vec<Document>, compare: C) -> CommandResult<Merge<C>>
+    where
+        C: Fn(&Document, &Document) -> Ordering,
+    {
+        let mut runs = self.0;
+        let mut memory = memory.into_iter();
+        let mut heads = Vec::new();
+        for run in &mut runs {
+            heads.push(run.next()?);
+        }
+        heads.push(memory.next());
+        Ok(Merge {
+            runs,
+            memory,
+            heads,
+            compare,
+        })
+    }
+}
+
+/// The documents of sorted runs, read back in order as they are merged.
+/// Documents comparing equal come in the order of their runs, so that a
+/// sort stays stable.
+pub struct Merge<C> {
+    runs: Vec<Run>,
+    memory: vec::IntoIter<Document>,
+    /// The next document of each run, then of the documents in memory.
+    heads: Vec<Option<Document>>,
+    compare: C,
+}
+
+impl<C> Iterator for Merge<C>
+where
+    C: Fn(&Document, &Document) -> Ordering,
+{
+    type Item = CommandResult<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some(head) = head else { continue };
+            let smaller = next.is_none_or(|j| {
+                (self.compare)(head, self.heads[j].as_ref().expect("head exists")) == Ordering::Less
+            });
+            if smaller {
+                next = Some(i);
+            }
+        }
+        let i = next?;
+        let doc = self.heads[i].take();
+        self.heads[i] = match self.runs.get_mut(i) {
+            Some(run) => match run.next() {
+                Ok(head) => head,
+                Err(err) => return Some(Err(err)),
+            },
+            None => self.memory.next(),
+        };
+        doc.map(Ok)
+    }
+}
+
+/// Sorts documents stably within the memory limit of the context, taking
+/// them from `documents` as it goes. Over the limit, sorted runs are
+/// spilled to the context's spill directory, or the sort fails with an
+/// error naming `stage` if there is none. The spilled runs are merged as
+/// the sorted documents are read.
+pub fn sort<C>(
+    documents: impl IntoIterator<Item = CommandResult<Document>>,
+    compare: C,
+    stage: &str,
+    context: &Context,
+) -> CommandResult<Merge<C>>
+where
+    C: Fn(&Document, &Document) -> Ordering,
+{
+    let mut runs = Runs::default();
+    let mut buffer = Vec::new();
+    let mut memory = 0;
+    for doc in documents {
+        let doc = doc?;
+        memory += document_size(&doc);
+        buffer.push(doc);
+        if memory > context.memory_limit {
+            buffer.sort_by(&compare);
+            runs.write(std::mem::take(&mut buffer), stage, context)?;
+            memory = 0;
+        }
+    }
+    buffer.sort_by(&compare);
+    runs.merge(buffer, compare)
+}
+
+/// Documents kept in their order, read back from the spilled runs first and
+/// then from memory.
+pub struct Buffer {
+    runs: VecDeque<Run>,
+    memory: vec::IntoIter<Document>,
+}
+
+impl Iterator for Buffer {
+    type Item = CommandResult<Document>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(run) = self.runs.front_mut() {
+            match run.next() {
+                Ok(Some(doc)) => return Some(Ok(doc)),
+                Ok(None) => {
+                    self.runs.pop_front();
+                }
+                Err(err) => return Some(Err(err)),
+            }
+        }
+        self.memory.next().map(Ok)
+    }
+}
+
+/// Holds the results of a pipeline in order within the memory limit of the
+/// context, taking them from `documents` as it goes. Over the limit, the
+/// results so far are spilled to the context's spill directory, or the
+/// buffer fails with an error naming `stage` if there is none.
+pub fn buffer(
+    documents: impl IntoIterator<Item = CommandResult<Document>>,
+    stage: &str,
+    context: &Context,
+) -> CommandResult<Buffer> {
+    let mut runs = Runs::default();
+    let mut buffer = Vec::new();
+    let mut memory = 0;
+    for doc in documents {
+        let doc = doc?;
+        memory += document_size(&doc);
+        buffer.push(doc);
+        if memory > context.memory_limit {
+            runs.write(std::mem::take(&mut buffer), stage, context)?;
+            memory = 0;
+        }
+    }
+    Ok(Buffer {
+        runs: runs.0.into(),
+        memory: buffer.into_iter(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn spill_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("oxide-test-{}-{}", name, process::id()))
+    }
+
+    #[test]
+    fn test_external_sort() {
+        let documents: Vec<_> = (0..100)
+            .map(|i| doc(&format!("{{ n: {}, i: {} }}", (i * 37) % 10, i)))
+            .collect();
+        let compare = |a: &Document, b: &Document| a.get("n").unwrap().compare(b.get("n").unwrap());
+        let mut expected = documents.clone();
+        expected.sort_by(compare);
+
+        let dir = spill_dir("sort");
+        let context = Context {
+            memory_limit: 200,
+            spill_dir: Some(&dir),
+            ..Context::default()
+        };
+        let input = documents.clone().into_iter().map(Ok);
+        let mut sorted = sort(input, compare, "Sort", &context).unwrap();
+        // The input went to the runs, which are read back as needed
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), sorted.runs.len());
+        assert!(sorted.runs.len() > 1);
+        assert_eq!(sorted.next().unwrap().unwrap(), expected[0]);
+        let rest: CommandResult<Vec<_>> = sorted.collect();
+        assert_eq!(rest.unwrap(), expected[1..]);
+        // Spill files are removed after the merge
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+
+        let context = Context {
+            memory_limit: 200,
+            ..Context::default()
+        };
+        let input = documents.into_iter().map(Ok);
+        assert_eq!(
+            sort(input, compare, "Sort", &context).err().unwrap().code,
+            ErrorCode::QueryExceededMemoryLimitNoDiskUseAllowed
+        );
+    }
+
+    #[test]
+    fn test_buffer() {
+        let documents: Vec<_> = (0..100).map(|i| doc(&format!("{{ i: {} }}", i))).collect();
+        let dir = spill_dir("buffer");
+        let context = Context {
+            memory_limit: 200,
+            spill_dir: Some(&dir),
+            ..Context::default()
+        };
+        let input = documents.clone().into_iter().map(Ok);
+        let mut buffered = buffer(input, "aggregate", &context).unwrap();
+        // Only the documents past the last spill stay in memory
+        assert!(buffered.runs.len() > 1);
+        let memory: usize = buffered.memory.as_slice().iter().map(document_size).sum();
+        assert!(memory <= context.memory_limit);
+        assert_eq!(buffered.next().unwrap().unwrap(), documents[0]);
+        let rest: CommandResult<Vec<_>> = buffered.collect();
+        assert_eq!(rest.unwrap(), documents[1..]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir(&dir).unwrap();
+
+        let context = Context {
+            memory_limit: 200,
+            ..Context::default()
+        };
+        let input = documents.into_iter().map(Ok);
+        assert_eq!(
+            buffer(input, "aggregate", &context).err().unwrap().code,
+            ErrorCode::QueryExceededMemoryLimitNoDiskUseAllowed
+        );
+    }
+}
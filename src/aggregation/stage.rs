@@ -0,0 +1,821 @@
+//! Pipeline stages. Each stage consumes the documents produced by the
+//! previous one and produces its own.
+
+use std::{
+    borrow::Cow,
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{Matcher, SortSpec},
+};
+
+use super::{
+    bucket::{Bucket, BucketAuto},
+    densify::Densify,
+    expression::Expression,
+    fill,
+    group::Group,
+    lookup::{GraphLookup, Lookup, UnionWith},
+    pipeline::{no_text_score, Context, Metadata, Pipeline},
+    projection::Projection,
+    spill,
+    window::SetWindowFields,
+};
+
+/// The documents passed from one stage to the next with their metadata,
+/// produced as they are read.
+pub type Documents<'s> = Box<dyn Iterator<Item = CommandResult<(Document, Metadata)>> + 's>;
+
+/// The fields of the documents `$sort` sorts, which wrap each document with
+/// its text score so that both spill together.
+const SORTED_DOCUMENT: &str = "d";
+const SORTED_SCORE: &str = "s";
+
+#[derive(Debug, Clone)]
+pub enum Stage {
+    /// `$match`, with the aggregation expression of a top-level `$expr`.
+    Match(Matcher, Option<Expression>),
+    /// `$addFields` and its alias `$set`: dotted paths and the expressions
+    /// computing their new values.
+    AddFields(Vec<(Vec<String>, Expression)>),
+    /// `$project` and `$unset`.
+    Project(Projection),
+    /// `$replaceRoot` and `$replaceWith`, with the name used in errors for
+    /// the replacement expression.
+    ReplaceRoot(Expression, &'static str),
+    /// `$sort`, with keys on the wrapped documents it sorts.
+    Sort(SortSpec),
+    Skip(usize),
+    Limit(usize),
+    Count(String),
+    Unwind(Unwind),
+    Sample(usize),
+    Group(Group),
+    Lookup(Lookup),
+    GraphLookup(GraphLookup),
+    /// `$facet`: output names and the pipelines computing them.
+    Facet(Vec<(String, Pipeline)>),
+    Bucket(Bucket),
+    BucketAuto(BucketAuto),
+    /// `$sortByCount`, as the group counting each value.
+    SortByCount(Group),
+    SetWindowFields(SetWindowFields),
+    UnionWith(UnionWith),
+    Densify(Densify),
+    /// `$fill`, as the pipeline it is rewritten into.
+    Fill(Pipeline),
+}
+
+/// Stages that can't be used in a `$facet` subpipeline.
+const FACET_EXCLUDED_STAGES: &[&str] = &[
+    "$facet",
+    "$out",
+    "$merge",
+    "$collStats",
+    "$indexStats",
+    "$geoNear",
+];
+
+#[derive(Debug, Clone)]
+pub struct Unwind {
+    path: Vec<String>,
+    include_array_index: Option<String>,
+    preserve_null_and_empty_arrays: bool,
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+impl Stage {
+    /// Parses a stage document such as `{ $set: { a: 1 } }`.
+    pub fn parse(stage: &Document) -> CommandResult<Self> {
+        let (name, spec) = match stage.first() {
+            Some(first) if stage.len() == 1 => first,
+            _ => {
+                return Err(location(
+                    40323,
+                    "A pipeline stage specification object must contain exactly one field.",
+                ));
+            }
+        };
+        match name.as_str() {
+            "$match" => match spec {
+                Value::Document(filter) => {
+                    // `$expr` is evaluated here, where variables are in scope
+                    let mut filter = filter.clone();
+                    let expr = match filter.remove("$expr") {
+                        Some(expr) => Some(Expression::parse(&expr)?),
+                        None => None,
+                    };
+                    Ok(Stage::Match(Matcher::for_query(&filter)?.forbid_near()?, expr))
+                }
+                _ => Err(location(
+                    15959,
+                    "the match filter must be an expression in an object",
+                )),
+            },
+            "$addFields" | "$set" => {
+                let Value::Document(spec) = spec else {
+                    return Err(location(
+                        40272,
+                        format!(
+                            "{} specification stage must be an object, got {}",
+                            name,
+                            spec.type_name()
+                        ),
+                    ));
+                };
+                let mut fields = Vec::new();
+                parse_add_fields(spec, &[], &mut fields)?;
+                Ok(Stage::AddFields(fields))
+            }
+            "$project" => match spec {
+                Value::Document(spec) => Ok(Stage::Project(Projection::parse(spec)?)),
+                spec => Err(location(
+                    15969,
+                    format!(
+                        "$project specification must be an object, got {}",
+                        spec.type_name()
+                    ),
+                )),
+            },
+            "$unset" => Ok(Stage::Project(Projection::parse_unset(spec)?)),
+            "$replaceRoot" => {
+                let Value::Document(spec) = spec else {
+                    return Err(location(
+                        40228,
+                        format!(
+                            "the $replaceRoot stage specification must be an object, but found {}",
+                            spec.type_name()
+                        ),
+                    ));
+                };
+                if let Some(key) = spec.keys().find(|key| *key != "newRoot") {
+                    return Err(location(
+                        40415,
+                        format!("BSON field '$replaceRoot.{}' is an unknown field.", key),
+                    ));
+                }
+                match spec.get("newRoot") {
+                    Some(new_root) => Ok(Stage::ReplaceRoot(
+                        Expression::parse(new_root)?,
+                        "'newRoot' expression",
+                    )),
+                    None => Err(location(
+                        40414,
+                        "BSON field '$replaceRoot.newRoot' is missing but a required field",
+                    )),
+                }
+            }
+            "$replaceWith" => Ok(Stage::ReplaceRoot(
+                Expression::parse(spec)?,
+                "'replacement document'",
+            )),
+            "$sort" => match spec {
+                Value::Document(spec) if spec.is_empty() => Err(location(
+                    15976,
+                    "$sort stage must have at least one sort key",
+                )),
+                Value::Document(spec) => Ok(Stage::Sort(parse_sort(spec)?)),
+                _ => Err(location(
+                    15973,
+                    "the $sort key specification must be an object",
+                )),
+            },
+            "$skip" => match spec.as_i64() {
+                Some(skip) if spec.is_number() && skip >= 0 => Ok(Stage::Skip(skip as usize)),
+                Some(_) if spec.is_number() => Err(location(
+                    15956,
+                    format!(
+                        "invalid argument to $skip stage: Expected a non-negative number in: $skip: {}",
+                        spec
+                    ),
+                )),
+                _ => Err(location(
+                    15972,
+                    format!(
+                        "invalid argument to $skip stage: Expected an integer: $skip: {}",
+                        spec
+                    ),
+                )),
+            },
+            "$limit" => match spec.as_i64() {
+                Some(limit) if spec.is_number() && limit > 0 => Ok(Stage::Limit(limit as usize)),
+                Some(_) if spec.is_number() => Err(location(15958, "the limit must be positive")),
+                _ => Err(location(15957, "the limit must be specified as a number")),
+            },
+            "$count" => match spec {
+                Value::String(field) if field.is_empty() => Err(location(
+                    40157,
+                    "the count field must be a non-empty string",
+                )),
+                Value::String(field) if field.starts_with('$') => Err(location(
+                    40158,
+                    "the count field cannot be a $-prefixed path",
+                )),
+                Value::String(field) if field.contains('.') => {
+                    Err(location(40160, "the count field cannot contain '.'"))
+                }
+                Value::String(field) => Ok(Stage::Count(field.clone())),
+                _ => Err(location(
+                    40156,
+                    "the count field must be a non-empty string",
+                )),
+            },
+            "$unwind" => Ok(Stage::Unwind(Unwind::parse(spec)?)),
+            "$group" => Ok(Stage::Group(Group::parse(spec)?)),
+            "$lookup" => Ok(Stage::Lookup(Lookup::parse(spec)?)),
+            "$graphLookup" => Ok(Stage::GraphLookup(GraphLookup::parse(spec)?)),
+            "$unionWith" => Ok(Stage::UnionWith(UnionWith::parse(spec)?)),
+            "$densify" => Ok(Stage::Densify(Densify::parse(spec)?)),
+            "$fill" => Ok(Stage::Fill(fill::parse(spec)?)),
+            "$facet" => parse_facet(spec),
+            "$bucket" => Ok(Stage::Bucket(Bucket::parse(spec)?)),
+            "$bucketAuto" => Ok(Stage::BucketAuto(BucketAuto::parse(spec)?)),
+            "$sortByCount" => {
+                let group_by = match spec {
+                    Value::String(path) if path.starts_with('$') => spec,
+                    Value::Document(expression)
+                        if expression.len() == 1
+                            && expression.keys().all(|key| key.starts_with('$')) =>
+                    {
+                        spec
+                    }
+                    _ => {
+                        return Err(location(
+                            40148,
+                            "the sortByCount field must be defined as a $-prefixed path or an expression",
+                        ));
+                    }
+                };
+                let mut count = Document::new();
+                count.insert("$sum", Value::Int32(1));
+                let mut group = Document::new();
+                group.insert("_id", group_by.clone());
+                group.insert("count", Value::Document(count));
+                Ok(Stage::SortByCount(Group::parse(&Value::Document(group))?))
+            }
+            "$setWindowFields" => Ok(Stage::SetWindowFields(SetWindowFields::parse(spec)?)),
+            "$sample" => {
+                let Value::Document(spec) = spec else {
+                    return Err(location(
+                        28745,
+                        "the $sample stage specification must be an object",
+                    ));
+                };
+                let mut size = None;
+                for (key, value) in spec.iter() {
+                    if key != "size" {
+                        return Err(location(
+                            28748,
+                            format!("unrecognized option to $sample: {}", key),
+                        ));
+                    }
+                    match value.as_i64() {
+                        Some(n) if value.is_number() && n >= 0 => size = Some(n as usize),
+                        Some(_) if value.is_number() => {
+                            return Err(location(
+                                28747,
+                                "size argument to $sample must not be negative",
+                            ));
+                        }
+                        _ => {
+                            return Err(location(
+                                28746,
+                                "size argument to $sample must be a number",
+                            ));
+                        }
+                    }
+                }
+                match size {
+                    Some(size) => Ok(Stage::Sample(size)),
+                    None => Err(location(
+                        28749,
+                        "$sample stage must specify a size",
+                    )),
+                }
+            }
+            name => Err(location(
+                40324,
+                format!("Unrecognized pipeline stage name: '{}'", name),
+            )),
+        }
+    }
+
+    /// Runs the stage over the documents produced by the previous stage.
+    /// Stages that work a document at a time produce theirs as they are
+    /// read, blocking stages once they have taken all of their input.
+    pub fn run<'s>(
+        &'s self,
+        documents: Documents<'s>,
+        context: &'s Context,
+    ) -> CommandResult<Documents<'s>> {
+        Ok(match self {
+            Stage::Match(matcher, expr) => {
+                let matcher = match context.collation {
+                    Some(_) => Cow::Owned(matcher.clone().with_collation(context.collation)),
+                    None => Cow::Borrowed(matcher),
+                };
+                let keep = move |(doc, metadata): (Document, Metadata)| {
+                    if !matcher.matches(&doc) {
+                        return Ok(None);
+                    }
+                    if let Some(expr) = expr {
+                        let vars = context.variables(&doc).with_metadata(&metadata);
+                        if !expr.eval(&vars)?.is_truthy() {
+                            return Ok(None);
+                        }
+                    }
+                    Ok(Some((doc, metadata)))
+                };
+                Box::new(documents.filter_map(move |doc| doc.and_then(&keep).transpose()))
+            }
+            Stage::AddFields(_) | Stage::Project(_) | Stage::ReplaceRoot(..) => {
+                // The reshaped documents keep their metadata
+                Box::new(documents.map(|doc| {
+                    let (doc, metadata) = doc?;
+                    Ok((self.reshape(doc, &metadata, context)?, metadata))
+                }))
+            }
+            Stage::Sort(sort) => {
+                let sort = sort.clone().with_collation(context.collation);
+                let scored = sort.keys().iter().any(|(path, _)| path[0] == SORTED_SCORE);
+                let wrapped = documents.map(move |doc| {
+                    let (doc, metadata) = doc?;
+                    if scored && metadata.text_score.is_none() {
+                        return Err(no_text_score());
+                    }
+                    let mut wrapped = Document::new();
+                    wrapped.insert(SORTED_DOCUMENT, Value::Document(doc));
+                    if let Some(score) = metadata.text_score {
+                        wrapped.insert(SORTED_SCORE, Value::Double(score));
+                    }
+                    Ok(wrapped)
+                });
+                let sorted = spill::sort(wrapped, move |a, b| sort.compare(a, b), "Sort", context)?;
+                Box::new(sorted.map(|wrapped| {
+                    let mut wrapped = wrapped?;
+                    let metadata = Metadata {
+                        text_score: wrapped.get(SORTED_SCORE).and_then(Value::as_f64),
+                    };
+                    match wrapped.remove(SORTED_DOCUMENT) {
+                        Some(Value::Document(doc)) => Ok((doc, metadata)),
+                        _ => unreachable!("sorted documents are wrapped"),
+                    }
+                }))
+            }
+            Stage::Skip(skip) => {
+                // Errors are passed on rather than counted as skipped
+                let mut skipped = 0;
+                Box::new(documents.filter(move |doc| {
+                    if doc.is_err() || skipped == *skip {
+                        return true;
+                    }
+                    skipped += 1;
+                    false
+                }))
+            }
+            Stage::Limit(limit) => Box::new(documents.take(*limit)),
+            Stage::Unwind(unwind) => Box::new(documents.flat_map(|doc| {
+                let mut unwound = Vec::new();
+                let metadata = match doc {
+                    Ok((doc, metadata)) => {
+                        unwind.unwind(doc, &mut unwound);
+                        metadata
+                    }
+                    Err(err) => return vec![Err(err)],
+                };
+                unwound.into_iter().map(|doc| Ok((doc, metadata))).collect()
+            })),
+            Stage::Group(group) => Box::new(with_no_metadata(group.run(documents, context)?)),
+            Stage::Lookup(lookup) => Box::new(documents.map(|doc| {
+                let (doc, metadata) = doc?;
+                Ok((lookup.run(doc, context)?, metadata))
+            })),
+            Stage::GraphLookup(lookup) => Box::new(documents.map(|doc| {
+                let (doc, metadata) = doc?;
+                Ok((lookup.run(doc, context)?, metadata))
+            })),
+            Stage::SortByCount(group) => {
+                let mut counts = group.run(documents, context)?;
+                counts.sort_by(|a, b| {
+                    let count = |doc: &Document| doc.get("count").cloned().unwrap_or(Value::Null);
+                    count(b).compare(&count(a))
+                });
+                Box::new(with_no_metadata(counts))
+            }
+            Stage::Sample(size) => {
+                let documents = documents.collect::<CommandResult<Vec<_>>>()?;
+                Box::new(sample(documents, *size).into_iter().map(Ok))
+            }
+            _ => {
+                let documents = documents
+                    .map(|doc| Ok(doc?.0))
+                    .collect::<CommandResult<Vec<_>>>()?;
+                Box::new(with_no_metadata(self.run_blocking(documents, context)?))
+            }
+        })
+    }
+
+    /// Runs a blocking stage that needs all of its input at once.
+    fn run_blocking(
+        &self,
+        documents: Vec<Document>,
+        context: &Context,
+    ) -> CommandResult<Vec<Document>> {
+        match self {
+            Stage::Count(field) => {
+                if documents.is_empty() {
+                    return Ok(documents);
+                }
+                let mut count = Document::new();
+                count.insert(field.clone(), Value::Int32(documents.len() as i32));
+                Ok(vec![count])
+            }
+            Stage::Facet(facets) => {
+                let mut output = Document::new();
+                for (name, pipeline) in facets {
+                    let results = pipeline.run(documents.clone(), context)?;
+                    let results = results.into_iter().map(Value::Document).collect::<Vec<_>>();
+                    output.insert(name.clone(), Value::from(results));
+                }
+                Ok(vec![output])
+            }
+            Stage::Bucket(bucket) => bucket.run(documents, context),
+            Stage::BucketAuto(bucket) => bucket.run(documents, context),
+            Stage::SetWindowFields(stage) => stage.run(documents, context),
+            Stage::UnionWith(union) => union.run(documents, context),
+            Stage::Densify(densify) => densify.run(documents),
+            Stage::Fill(pipeline) => pipeline.run(documents, context),
+            _ => unreachable!("stage runs a document at a time: {:?}", self),
+        }
+    }
+
+    /// Runs a stage that maps each document to exactly one new document.
+    fn reshape(
+        &self,
+        doc: Document,
+        metadata: &Metadata,
+        context: &Context,
+    ) -> CommandResult<Document> {
+        let vars = context.variables(&doc).with_metadata(metadata);
+        match self {
+            Stage::AddFields(fields) => {
+                // Every expression sees the input document, not the fields
+                // added before it
+                let values = fields
+                    .iter()
+                    .map(|(_, expression)| expression.evaluate(&vars))
+                    .collect::<CommandResult<Vec<_>>>()?;
+                let mut updated = doc.clone();
+                for ((path, _), value) in fields.iter().zip(values) {
+                    set_field(&mut updated, path, value);
+                }
+                Ok(updated)
+            }
+            Stage::Project(projection) => projection.apply_with(&doc, &vars),
+            Stage::ReplaceRoot(expression, what) => match expression.evaluate(&vars)? {
+                Some(Value::Document(root)) => Ok(root),
+                value => {
+                    let (value, type_name) = match &value {
+                        Some(value) => (value.to_string(), value.type_name()),
+                        None => ("MISSING".to_string(), "missing"),
+                    };
+                    Err(location(
+                        40228,
+                        format!(
+                            "{} must evaluate to an object, but resulting value was: {}. Type of resulting value: '{}'. Input document: {}",
+                            what, value, type_name, doc
+                        ),
+                    ))
+                }
+            },
+            _ => Ok(doc),
+        }
+    }
+}
+
+impl Unwind {
+    fn parse(spec: &Value) -> CommandResult<Self> {
+        let (path, options) = match spec {
+            Value::String(path) => (path.as_str(), None),
+            Value::Document(options) => match options.get("path") {
+                Some(Value::String(path)) => (path.as_str(), Some(options)),
+                Some(path) => {
+                    return Err(location(
+                        28808,
+                        format!(
+                            "expected a string as the path for $unwind stage, got {}",
+                            path.type_name()
+                        ),
+                    ));
+                }
+                None => return Err(location(28812, "no path specified to $unwind stage")),
+            },
+            spec => {
+                return Err(location(
+                    15981,
+                    format!(
+                        "expected either a string or an object as specification for $unwind stage, got {}",
+                        spec.type_name()
+                    ),
+                ));
+            }
+        };
+        let Some(path) = path.strip_prefix('$') else {
+            return Err(location(
+                28818,
+                format!(
+                    "path option to $unwind stage should be prefixed with a '$': {}",
+                    path
+                ),
+            ));
+        };
+
+        let mut unwind = Self {
+            path: path.split('.').map(String::from).collect(),
+            include_array_index: None,
+            preserve_null_and_empty_arrays: false,
+        };
+        for (key, value) in options.into_iter().flat_map(|options| options.iter()) {
+            match (key.as_str(), value) {
+                ("path", _) => {}
+                ("includeArrayIndex", Value::String(field)) if field.starts_with('$') => {
+                    return Err(location(
+                        28822,
+                        format!(
+                            "includeArrayIndex option to $unwind stage should not be prefixed with a '$': {}",
+                            field
+                        ),
+                    ));
+                }
+                ("includeArrayIndex", Value::String(field)) => {
+                    unwind.include_array_index = Some(field.clone());
+                }
+                ("includeArrayIndex", value) => {
+                    return Err(location(
+                        28810,
+                        format!(
+                            "expected a non-empty string for the includeArrayIndex option to $unwind stage, got {}",
+                            value.type_name()
+                        ),
+                    ));
+                }
+                ("preserveNullAndEmptyArrays", Value::Boolean(preserve)) => {
+                    unwind.preserve_null_and_empty_arrays = *preserve;
+                }
+                ("preserveNullAndEmptyArrays", value) => {
+                    return Err(location(
+                        28809,
+                        format!(
+                            "expected a boolean for the preserveNullAndEmptyArrays option to $unwind stage, got {}",
+                            value.type_name()
+                        ),
+                    ));
+                }
+                (key, _) => {
+                    return Err(location(
+                        28811,
+                        format!("unrecognized option to $unwind stage: {}", key),
+                    ));
+                }
+            }
+        }
+        Ok(unwind)
+    }
+
+    /// Outputs a copy of `doc` for each element of the array at the path.
+    fn unwind(&self, doc: Document, output: &mut Vec<Document>) {
+        let index_path = self
+            .include_array_index
+            .as_ref()
+            .map(|field| field.split('.').map(String::from).collect::<Vec<_>>());
+        let with_index = |mut doc: Document, index: Value| {
+            if let Some(index_path) = &index_path {
+                set_field(&mut doc, index_path, Some(index));
+            }
+            doc
+        };
+
+        match get_field(&doc, &self.path) {
+            Some(Value::Array(items)) if !items.0.is_empty() => {
+                let items = items.0.clone();
+                for (index, item) in items.into_iter().enumerate() {
+                    let mut unwound = doc.clone();
+                    set_field(&mut unwound, &self.path, Some(item));
+                    output.push(with_index(unwound, Value::Int64(index as i64)));
+                }
+            }
+            Some(Value::Array(_)) => {
+                if self.preserve_null_and_empty_arrays {
+                    let mut preserved = doc;
+                    set_field(&mut preserved, &self.path, None);
+                    output.push(with_index(preserved, Value::Null));
+                }
+            }
+            Some(Value::Null) | None => {
+                if self.preserve_null_and_empty_arrays {
+                    output.push(with_index(doc, Value::Null));
+                }
+            }
+            // A non-array value is treated as a single element array
+            Some(_) => output.push(with_index(doc, Value::Null)),
+        }
+    }
+}
+
+/// Reads a dotted path through embedded documents only, without expanding
+/// arrays.
+pub fn get_field<'a>(doc: &'a Document, path: &[String]) -> Option<&'a Value> {
+    let (first, rest) = path.split_first()?;
+    match (doc.get(first)?, rest.is_empty()) {
+        (value, true) => Some(value),
+        (Value::Document(nested), false) => get_field(nested, rest),
+        _ => None,
+    }
+}
+
+fn parse_facet(spec: &Value) -> CommandResult<Stage> {
+    let spec = match spec {
+        Value::Document(spec) if !spec.is_empty() => spec,
+        spec => {
+            return Err(location(
+                40169,
+                format!(
+                    "the $facet specification must be a non-empty object, but found: {}",
+                    spec
+                ),
+            ));
+        }
+    };
+    let mut facets = Vec::new();
+    for (name, stages) in spec.iter() {
+        if name.is_empty() || name.starts_with('$') || name.contains('.') {
+            return Err(location(
+                40353,
+                format!("$facet output field names must not be empty, start with '$' or contain '.': {}", name),
+            ));
+        }
+        let stages = match stages {
+            Value::Array(stages) if !stages.0.is_empty() => &stages.0,
+            Value::Array(_) => {
+                return Err(location(
+                    40171,
+                    format!("sub-pipeline in $facet stage cannot be empty: {}", name),
+                ));
+            }
+            stages => {
+                return Err(location(
+                    40170,
+                    format!(
+                        "arguments to $facet must be arrays, {} is type {}",
+                        name,
+                        stages.type_name()
+                    ),
+                ));
+            }
+        };
+        for stage in stages {
+            if let Value::Document(stage) = stage {
+                if let Some(excluded) = stage
+                    .keys()
+                    .find(|key| FACET_EXCLUDED_STAGES.contains(&key.as_str()))
+                {
+                    return Err(location(
+                        40600,
+                        format!(
+                            "{} is not allowed to be used within a $facet stage",
+                            excluded
+                        ),
+                    ));
+                }
+            }
+        }
+        facets.push((name.clone(), Pipeline::parse(stages)?));
+    }
+    Ok(Stage::Facet(facets))
+}
+
+/// Parses the specification of a `$sort` stage, in which
+/// `{ $meta: 'textScore' }` sorts by text score, highest first.
+fn parse_sort(spec: &Document) -> CommandResult<SortSpec> {
+    let mut keys = Document::new();
+    for (field, direction) in spec.iter() {
+        match direction {
+            Value::Document(meta) if meta.contains_key("$meta") => {
+                if meta.len() != 1 || meta.get("$meta") != Some(&Value::from("textScore")) {
+                    return Err(location(
+                        31138,
+                        format!("Illegal $meta sort: {}", direction),
+                    ));
+                }
+                keys.insert(SORTED_SCORE, Value::Int32(-1));
+            }
+            direction => {
+                let path = format!("{}.{}", SORTED_DOCUMENT, field);
+                keys.insert(path, direction.clone());
+            }
+        }
+    }
+    SortSpec::parse(&keys)
+}
+
+/// Documents built by a stage, which have no metadata.
+fn with_no_metadata(
+    documents: Vec<Document>,
+) -> impl Iterator<Item = CommandResult<(Document, Metadata)>> {
+    documents
+        .into_iter()
+        .map(|doc| Ok((doc, Metadata::default())))
+}
+
+/// Picks `size` documents at random, in random order.
+fn sample<T>(mut documents: Vec<T>, size: usize) -> Vec<T> {
+    let random = RandomState::new();
+    let mut state = random.build_hasher().finish() | 1;
+    let mut next = || {
+        // xorshift64
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state
+    };
+    let size = size.min(documents.len());
+    for i in 0..size {
+        let j = i + (next() % (documents.len() - i) as u64) as usize;
+        documents.swap(i, j);
+    }
+    documents.truncate(size);
+    documents
+}
+
+/// Flattens an `$addFields` specification: nested documents that are not
+/// expressions add fields inside the embedded document.
+fn parse_add_fields(
+    spec: &Document,
+    prefix: &[String],
+    fields: &mut Vec<(Vec<String>, Expression)>,
+) -> CommandResult<()> {
+    for (key, value) in spec.iter() {
+        if key.starts_with('$') {
+            return Err(location(
+                16410,
+                "FieldPath field names may not start with '$'. Consider using $getField or $setField.",
+            ));
+        }
+        let mut path = prefix.to_vec();
+        path.extend(key.split('.').map(String::from));
+        match value {
+            Value::Document(nested)
+                if !nested.is_empty()
+                    && !nested.first().is_some_and(|(k, _)| k.starts_with('$')) =>
+            {
+                parse_add_fields(nested, &path, fields)?;
+            }
+            value => fields.push((path, Expression::parse(value)?)),
+        }
+    }
+    Ok(())
+}
+
+/// Sets a dotted path for `$addFields`, replacing non-document parents and
+/// setting the field in every document of an array along the path. A
+/// missing value removes the field.
+pub fn set_field(doc: &mut Document, path: &[String], value: Option<Value>) {
+    let (first, rest) = path.split_first().expect("non-empty path");
+    if rest.is_empty() {
+        match value {
+            Some(value) => {
+                doc.insert(first.clone(), value);
+            }
+            None => {
+                doc.remove(first);
+            }
+        }
+        return;
+    }
+    match doc.get_mut(first) {
+        Some(Value::Document(nested)) => set_field(nested, rest, value),
+        Some(Value::Array(items)) => {
+            for item in items.0.iter_mut() {
+                if !matches!(item, Value::Document(_)) {
+                    *item = Value::Document(Document::new());
+                }
+                if let Value::Document(nested) = item {
+                    set_field(nested, rest, value.clone());
+                }
+            }
+        }
+        _ if value.is_none() => {}
+        _ => {
+            let mut nested = Document::new();
+            set_field(&mut nested, rest, value);
+            doc.insert(first.clone(), Value::Document(nested));
+        }
+    }
+}
@@ -0,0 +1,1043 @@
+//! The `$setWindowFields` stage, which computes fields from a window of
+//! neighbouring documents in the same partition: running totals, moving
+//! averages, ranks and the like.
+
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::SortSpec,
+};
+
+use super::{
+    accumulator::Accumulator,
+    expression::{Expression, TimeUnit},
+    pipeline::Context,
+    stage::{get_field, set_field},
+};
+
+#[derive(Debug, Clone)]
+pub struct SetWindowFields {
+    partition_by: Option<Expression>,
+    sort_by: Option<SortSpec>,
+    outputs: Vec<(Vec<String>, WindowFunction)>,
+}
+
+#[derive(Debug, Clone)]
+struct WindowFunction {
+    name: String,
+    function: Function,
+    window: Window,
+}
+
+#[derive(Debug, Clone)]
+enum Function {
+    /// A `$group` accumulator applied to the documents of the window.
+    Accumulator(Accumulator),
+    Rank,
+    DenseRank,
+    DocumentNumber,
+    Shift {
+        output: Expression,
+        by: i64,
+        default: Value,
+    },
+    Derivative {
+        input: Expression,
+        unit: Option<TimeUnit>,
+    },
+    Integral {
+        input: Expression,
+        unit: Option<TimeUnit>,
+    },
+    ExpMovingAvg {
+        input: Expression,
+        alpha: f64,
+    },
+    /// The last non-null value so far.
+    Locf(Expression),
+    /// Null values interpolated between the surrounding non-null ones.
+    LinearFill(Expression),
+    Covariance {
+        x: Expression,
+        y: Expression,
+        sample: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Bound<T> {
+    Unbounded,
+    Current,
+    Offset(T),
+}
+
+/// The documents a window function sees, relative to the current document:
+/// by position in the partition, or by the value of the single `sortBy`
+/// field, which must be a date when a unit is given.
+#[derive(Debug, Clone)]
+enum Window {
+    Documents(Bound<i64>, Bound<i64>),
+    Range {
+        lower: Bound<f64>,
+        upper: Bound<f64>,
+        unit: Option<TimeUnit>,
+    },
+}
+
+fn location(code: i32, message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::Location(code), message)
+}
+
+fn failed_to_parse(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::FailedToParse, message)
+}
+
+impl SetWindowFields {
+    pub fn parse(spec: &Value) -> CommandResult<Self> {
+        let Value::Document(spec) = spec else {
+            return Err(failed_to_parse(format!(
+                "the $setWindowFields stage specification must be an object, found {}",
+                spec.type_name()
+            )));
+        };
+        if let Some(key) = spec
+            .keys()
+            .find(|key| !matches!(key.as_str(), "partitionBy" | "sortBy" | "output"))
+        {
+            return Err(location(
+                40415,
+                format!("BSON field '$setWindowFields.{}' is an unknown field.", key),
+            ));
+        }
+        let partition_by = match spec.get("partitionBy") {
+            Some(partition_by) => Some(Expression::parse(partition_by)?),
+            None => None,
+        };
+        let sort_by = match spec.get("sortBy") {
+            Some(Value::Document(sort_by)) => Some(SortSpec::parse(sort_by)?),
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "BSON field '$setWindowFields.sortBy' is the wrong type '{}', expected type 'object'",
+                        value.type_name()
+                    ),
+                ));
+            }
+            None => None,
+        };
+        let output = match spec.get("output") {
+            Some(Value::Document(output)) => output,
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "BSON field '$setWindowFields.output' is the wrong type '{}', expected type 'object'",
+                        value.type_name()
+                    ),
+                ));
+            }
+            None => {
+                return Err(location(
+                    40414,
+                    "BSON field '$setWindowFields.output' is missing but a required field",
+                ));
+            }
+        };
+
+        let mut outputs = Vec::new();
+        for (field, spec) in output.iter() {
+            let Value::Document(spec) = spec else {
+                return Err(failed_to_parse(format!(
+                    "The field '{}' must be an object",
+                    field
+                )));
+            };
+            let path = field.split('.').map(String::from).collect();
+            outputs.push((path, WindowFunction::parse(spec, sort_by.as_ref())?));
+        }
+        Ok(Self {
+            partition_by,
+            sort_by,
+            outputs,
+        })
+    }
+
+    /// Sorts the documents by partition and `sortBy`, and adds the output
+    /// fields to each.
+    pub fn run(&self, documents: Vec<Document>, context: &Context) -> CommandResult<Vec<Document>> {
+        let mut keyed = Vec::new();
+        for doc in documents {
+            let key = match &self.partition_by {
+                Some(partition_by) => partition_by.eval(&context.variables(&doc))?,
+                None => Value::Null,
+            };
+            keyed.push((key, doc));
+        }
+        keyed.sort_by(|(key_a, a), (key_b, b)| {
+            key_a.compare(key_b).then_with(|| {
+                self.sort_by
+                    .as_ref()
+                    .map_or(Ordering::Equal, |sort_by| sort_by.compare(a, b))
+            })
+        });
+
+        let mut output = Vec::new();
+        let mut rest = keyed;
+        while !rest.is_empty() {
+            let len = rest
+                .iter()
+                .position(|(key, _)| key.compare(&rest[0].0) != Ordering::Equal)
+                .unwrap_or(rest.len());
+            let tail = rest.split_off(len);
+            let partition = rest.into_iter().map(|(_, doc)| doc).collect();
+            output.extend(self.run_partition(partition, context)?);
+            rest = tail;
+        }
+        Ok(output)
+    }
+
+    fn run_partition(
+        &self,
+        mut documents: Vec<Document>,
+        context: &Context,
+    ) -> CommandResult<Vec<Document>> {
+        // Every function sees the input documents, not the fields computed
+        // before it
+        let mut columns = Vec::new();
+        for (_, function) in &self.outputs {
+            columns.push(function.compute(&documents, self.sort_by.as_ref(), context)?);
+        }
+        for ((path, _), column) in self.outputs.iter().zip(columns) {
+            for (doc, value) in documents.iter_mut().zip(column) {
+                set_field(doc, path, Some(value));
+            }
+        }
+        Ok(documents)
+    }
+}
+
+/// Reads the named arguments of a window function, rejecting unknown ones.
+fn named_args<'a>(
+    name: &str,
+    arg: &'a Value,
+    params: &[&str],
+) -> CommandResult<Vec<Option<&'a Value>>> {
+    let Value::Document(spec) = arg else {
+        return Err(failed_to_parse(format!(
+            "{} must be specified with an object, found {}",
+            name,
+            arg.type_name()
+        )));
+    };
+    if let Some(key) = spec.keys().find(|key| !params.contains(&key.as_str())) {
+        return Err(failed_to_parse(format!(
+            "{} found an unknown argument: {}",
+            name, key
+        )));
+    }
+    Ok(params.iter().map(|param| spec.get(param)).collect())
+}
+
+fn required<'a>(name: &str, param: &str, arg: Option<&'a Value>) -> CommandResult<&'a Value> {
+    arg.ok_or_else(|| failed_to_parse(format!("{} requires an '{}' argument", name, param)))
+}
+
+/// The `unit` of `$derivative` and `$integral`, which must have a fixed
+/// length.
+fn parse_fixed_unit(name: &str, unit: Option<&Value>) -> CommandResult<Option<TimeUnit>> {
+    let Some(unit) = unit else {
+        return Ok(None);
+    };
+    let unit = match unit {
+        Value::String(unit) => TimeUnit::from_name(unit),
+        _ => None,
+    };
+    match unit {
+        Some(unit) if unit.utc_millis().is_some() => Ok(Some(unit)),
+        _ => Err(failed_to_parse(format!(
+            "{} 'unit' must be one of 'week', 'day', 'hour', 'minute', 'second' or 'millisecond'",
+            name
+        ))),
+    }
+}
+
+impl WindowFunction {
+    fn parse(spec: &Document, sort_by: Option<&SortSpec>) -> CommandResult<Self> {
+        let mut window = None;
+        let mut function = None;
+        for (key, value) in spec.iter() {
+            match key.as_str() {
+                "window" => window = Some(Window::parse(value)?),
+                key if key.starts_with('$') && function.is_none() => function = Some((key, value)),
+                key if key.starts_with('$') => {
+                    return Err(failed_to_parse(format!(
+                        "Cannot specify multiple functions in window function spec: {}",
+                        key
+                    )));
+                }
+                key => {
+                    return Err(failed_to_parse(format!(
+                        "Window function found an unknown argument: {}",
+                        key
+                    )));
+                }
+            }
+        }
+        let Some((name, arg)) = function else {
+            return Err(failed_to_parse("Expected a $-prefixed window function"));
+        };
+
+        let single_sort = sort_by.and_then(SortSpec::single_path).is_some();
+        let requires_sort = |single: bool| {
+            let sorted = if single {
+                single_sort
+            } else {
+                sort_by.is_some()
+            };
+            if sorted {
+                Ok(())
+            } else {
+                Err(location(
+                    5371602,
+                    format!(
+                        "{} must be specified with a top level sortBy expression with exactly one element",
+                        name
+                    ),
+                ))
+            }
+        };
+        let no_window = || match window {
+            Some(_) => Err(location(
+                5371601,
+                format!(
+                    "Window function {} does not accept a 'window' argument",
+                    name
+                ),
+            )),
+            None => Ok(()),
+        };
+
+        let function = match name {
+            "$rank" | "$denseRank" | "$documentNumber" => {
+                if !matches!(arg, Value::Document(arg) if arg.is_empty()) {
+                    return Err(failed_to_parse(format!(
+                        "{} must be specified with '{{}}' as the value",
+                        name
+                    )));
+                }
+                if name != "$documentNumber" {
+                    requires_sort(true)?;
+                }
+                no_window()?;
+                match name {
+                    "$rank" => Function::Rank,
+                    "$denseRank" => Function::DenseRank,
+                    _ => Function::DocumentNumber,
+                }
+            }
+            "$shift" => {
+                requires_sort(false)?;
+                no_window()?;
+                let args = named_args(name, arg, &["output", "by", "default"])?;
+                let output = Expression::parse(required(name, "output", args[0])?)?;
+                let by = required(name, "by", args[1])?;
+                let by = match by.as_i64() {
+                    Some(by) => by,
+                    None => {
+                        return Err(failed_to_parse(format!(
+                            "'$shift:by' field must be an integer, but found {}",
+                            by
+                        )));
+                    }
+                };
+                let default = match args[2].map(Expression::parse).transpose()? {
+                    None => Value::Null,
+                    Some(Expression::Literal(default)) => default,
+                    Some(_) => {
+                        return Err(failed_to_parse(
+                            "'$shift:default' expression must yield a constant value.",
+                        ));
+                    }
+                };
+                Function::Shift {
+                    output,
+                    by,
+                    default,
+                }
+            }
+            "$derivative" | "$integral" => {
+                requires_sort(true)?;
+                let args = named_args(name, arg, &["input", "unit"])?;
+                let input = Expression::parse(required(name, "input", args[0])?)?;
+                let unit = parse_fixed_unit(name, args[1])?;
+                if name == "$derivative" {
+                    if window.is_none() {
+                        return Err(failed_to_parse(
+                            "$derivative requires explicit window bounds",
+                        ));
+                    }
+                    Function::Derivative { input, unit }
+                } else {
+                    Function::Integral { input, unit }
+                }
+            }
+            "$locf" => {
+                no_window()?;
+                Function::Locf(Expression::parse(arg)?)
+            }
+            "$linearFill" => {
+                requires_sort(true)?;
+                no_window()?;
+                Function::LinearFill(Expression::parse(arg)?)
+            }
+            "$expMovingAvg" => {
+                requires_sort(false)?;
+                no_window()?;
+                let args = named_args(name, arg, &["input", "N", "alpha"])?;
+                let input = Expression::parse(required(name, "input", args[0])?)?;
+                let alpha = match (args[1], args[2]) {
+                    (Some(n), None) => match n.as_i64() {
+                        Some(n) if n > 0 => 2.0 / (n as f64 + 1.0),
+                        _ => {
+                            return Err(failed_to_parse(
+                                "'N' field must be an integer greater than zero",
+                            ));
+                        }
+                    },
+                    (None, Some(alpha)) => match alpha.as_f64() {
+                        Some(alpha) if alpha > 0.0 && alpha < 1.0 => alpha,
+                        _ => {
+                            return Err(failed_to_parse(
+                                "'alpha' must be a number between 0 and 1 (exclusive)",
+                            ));
+                        }
+                    },
+                    _ => {
+                        return Err(failed_to_parse(
+                            "$expMovingAvg requires exactly one of 'N' and 'alpha'",
+                        ));
+                    }
+                };
+                Function::ExpMovingAvg { input, alpha }
+            }
+            "$covariancePop" | "$covarianceSamp" => match arg {
+                Value::Array(args) if args.0.len() == 2 => Function::Covariance {
+                    x: Expression::parse(&args.0[0])?,
+                    y: Expression::parse(&args.0[1])?,
+                    sample: name == "$covarianceSamp",
+                },
+                _ => {
+                    return Err(failed_to_parse(format!(
+                        "{} requires an array of two expressions",
+                        name
+                    )));
+                }
+            },
+            name => match Accumulator::parse(name, arg) {
+                Ok(accumulator) => Function::Accumulator(accumulator),
+                Err(error) if error.code == ErrorCode::Location(15952) => {
+                    return Err(failed_to_parse(format!(
+                        "Unrecognized window function, {}",
+                        name
+                    )));
+                }
+                Err(error) => return Err(error),
+            },
+        };
+
+        let window = window.unwrap_or(Window::Documents(Bound::Unbounded, Bound::Unbounded));
+        if matches!(window, Window::Range { .. }) && !single_sort {
+            return Err(location(
+                5339902,
+                "Range-based bounds require sortBy a single field",
+            ));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            function,
+            window,
+        })
+    }
+
+    /// The values of the function for each document of a sorted partition.
+    fn compute(
+        &self,
+        documents: &[Document],
+        sort_by: Option<&SortSpec>,
+        context: &Context,
+    ) -> CommandResult<Vec<Value>> {
+        let mut values = Vec::with_capacity(documents.len());
+        match &self.function {
+            Function::DocumentNumber => {
+                values.extend((1..=documents.len()).map(|n| Value::Int32(n as i32)));
+            }
+            Function::Rank | Function::DenseRank => {
+                let sort_by = sort_by.expect("ranks require sortBy");
+                let (mut rank, mut dense_rank) = (0, 0);
+                for (i, doc) in documents.iter().enumerate() {
+                    // Documents that tie share a rank
+                    if i == 0 || sort_by.compare(&documents[i - 1], doc) != Ordering::Equal {
+                        rank = i + 1;
+                        dense_rank += 1;
+                    }
+                    let rank = match self.function {
+                        Function::Rank => rank,
+                        _ => dense_rank,
+                    };
+                    values.push(Value::Int32(rank as i32));
+                }
+            }
+            Function::Shift {
+                output,
+                by,
+                default,
+            } => {
+                for i in 0..documents.len() {
+                    let target = usize::try_from(i as i64 + by)
+                        .ok()
+                        .and_then(|j| documents.get(j));
+                    values.push(match target {
+                        Some(doc) => output.eval(&context.variables(doc))?,
+                        None => default.clone(),
+                    });
+                }
+            }
+            Function::Locf(input) => {
+                let mut last = Value::Null;
+                for doc in documents {
+                    if let Some(value) = input.evaluate(&context.variables(doc))? {
+                        if !value.is_null_or_undefined() {
+                            last = value;
+                        }
+                    }
+                    values.push(last.clone());
+                }
+            }
+            Function::LinearFill(input) => {
+                let path = sort_by
+                    .and_then(SortSpec::single_path)
+                    .expect("requires a single sortBy field");
+                let mut points = Vec::with_capacity(documents.len());
+                for doc in documents {
+                    let x = match get_field(doc, path) {
+                        Some(Value::UtcDateTime(ms)) => *ms as f64,
+                        Some(value) if value.is_number() => value.as_f64().unwrap_or(0.0),
+                        value => {
+                            return Err(location(
+                                6050106,
+                                format!(
+                                    "$linearFill expects the sortBy field to be numeric or a date, but it was: {}",
+                                    value.map_or("missing", Value::type_name)
+                                ),
+                            ));
+                        }
+                    };
+                    let y = input
+                        .evaluate(&context.variables(doc))?
+                        .filter(|y| !y.is_null_or_undefined());
+                    if y.as_ref().is_some_and(|y| !y.is_number()) {
+                        return Err(location(6050105, "$linearFill only fills numeric values"));
+                    }
+                    points.push((x, y));
+                }
+                let number = |(x, y): &(f64, Option<Value>)| Some((*x, y.as_ref()?.as_f64()?));
+                for (i, (x, y)) in points.iter().enumerate() {
+                    if let Some(y) = y {
+                        values.push(y.clone());
+                        continue;
+                    }
+                    let before = points[..i].iter().rev().find_map(number);
+                    let after = points[i + 1..].iter().find_map(number);
+                    values.push(match (before, after) {
+                        (Some((x0, y0)), Some((x1, y1))) if x1 != x0 => {
+                            Value::Double(y0 + (y1 - y0) * (x - x0) / (x1 - x0))
+                        }
+                        _ => Value::Null,
+                    });
+                }
+            }
+            Function::ExpMovingAvg { input, alpha } => {
+                let mut average: Option<f64> = None;
+                for doc in documents {
+                    let value = input.evaluate(&context.variables(doc))?;
+                    match value.filter(Value::is_number).and_then(|v| v.as_f64()) {
+                        Some(x) => {
+                            let next =
+                                average.map_or(x, |average| alpha * x + (1.0 - alpha) * average);
+                            average = Some(next);
+                            values.push(Value::Double(next));
+                        }
+                        None => values.push(Value::Null),
+                    }
+                }
+            }
+            _ => {
+                let path = sort_by.and_then(SortSpec::single_path);
+                for i in 0..documents.len() {
+                    let (start, end) = self.window.bounds(i, documents, path)?;
+                    values.push(self.over_window(&documents[start..end], path, context)?);
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// Applies a function to the documents of one window.
+    fn over_window(
+        &self,
+        window: &[Document],
+        path: Option<&[String]>,
+        context: &Context,
+    ) -> CommandResult<Value> {
+        match &self.function {
+            Function::Accumulator(accumulator) => {
+                let mut state = accumulator.init();
+                for doc in window {
+                    accumulator.accumulate(&mut state, doc, &context.variables(doc))?;
+                }
+                Ok(accumulator.finish(state))
+            }
+            Function::Covariance { x, y, sample } => {
+                let mut points = Vec::new();
+                for doc in window {
+                    let vars = context.variables(doc);
+                    let number = |value: Option<Value>| value.filter(Value::is_number)?.as_f64();
+                    if let (Some(x), Some(y)) =
+                        (number(x.evaluate(&vars)?), number(y.evaluate(&vars)?))
+                    {
+                        points.push((x, y));
+                    }
+                }
+                let count = points.len() as f64;
+                let divisor = if *sample { count - 1.0 } else { count };
+                if divisor <= 0.0 {
+                    return Ok(Value::Null);
+                }
+                let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+                let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+                let sum: f64 = points
+                    .iter()
+                    .map(|(x, y)| (x - mean_x) * (y - mean_y))
+                    .sum();
+                Ok(Value::Double(sum / divisor))
+            }
+            Function::Derivative { input, unit } | Function::Integral { input, unit } => {
+                let path = path.expect("requires a single sortBy field");
+                let mut points = Vec::new();
+                for doc in window {
+                    let y = input.evaluate(&context.variables(doc))?;
+                    if let Some(y) = y.filter(Value::is_number).and_then(|y| y.as_f64()) {
+                        points.push((self.position(doc, path, *unit)?, y));
+                    }
+                }
+                if let Function::Derivative { .. } = self.function {
+                    let (Some(first), Some(last)) = (points.first(), points.last()) else {
+                        return Ok(Value::Null);
+                    };
+                    if points.len() < 2 || last.0 == first.0 {
+                        return Ok(Value::Null);
+                    }
+                    return Ok(Value::Double((last.1 - first.1) / (last.0 - first.0)));
+                }
+                if points.is_empty() {
+                    return Ok(Value::Null);
+                }
+                // The trapezoidal rule
+                let area = points
+                    .windows(2)
+                    .map(|pair| (pair[1].0 - pair[0].0) * (pair[0].1 + pair[1].1) / 2.0)
+                    .sum();
+                Ok(Value::Double(area))
+            }
+            _ => unreachable!("{} is not computed over a window", self.name),
+        }
+    }
+
+    /// The position of a document along the `sortBy` field for
+    /// `$derivative` and `$integral`: a number, or a date in units.
+    fn position(
+        &self,
+        doc: &Document,
+        path: &[String],
+        unit: Option<TimeUnit>,
+    ) -> CommandResult<f64> {
+        match (get_field(doc, path), unit) {
+            (Some(Value::UtcDateTime(ms)), Some(unit)) => {
+                Ok(*ms as f64 / unit.utc_millis().expect("fixed length unit") as f64)
+            }
+            (Some(Value::UtcDateTime(_)), None) => Err(location(
+                5624900,
+                format!("{} where the sortBy is a Date requires a 'unit'", self.name),
+            )),
+            (Some(value), None) if value.is_number() => Ok(value.as_f64().unwrap_or(0.0)),
+            (value, unit) => Err(location(
+                5624901,
+                format!(
+                    "{} expects the sortBy field to be {}, but it was: {}",
+                    self.name,
+                    if unit.is_some() { "a Date" } else { "numeric" },
+                    value.map_or("missing", Value::type_name)
+                ),
+            )),
+        }
+    }
+}
+
+impl Window {
+    fn parse(value: &Value) -> CommandResult<Self> {
+        let spec = match value {
+            Value::Document(spec)
+                if spec
+                    .keys()
+                    .all(|key| matches!(key.as_str(), "documents" | "range" | "unit")) =>
+            {
+                spec
+            }
+            _ => {
+                return Err(failed_to_parse(
+                    "'window' field can only contain 'documents' as the only argument or 'range' with an optional 'unit' field",
+                ));
+            }
+        };
+        let unit = match spec.get("unit") {
+            None => None,
+            Some(Value::String(name)) => Some(
+                TimeUnit::from_name(name)
+                    .ok_or_else(|| failed_to_parse(format!("unknown time unit value: {}", name)))?,
+            ),
+            Some(value) => {
+                return Err(failed_to_parse(format!(
+                    "'unit' must be a string, found {}",
+                    value.type_name()
+                )));
+            }
+        };
+        match (spec.get("documents"), spec.get("range")) {
+            (Some(bounds), None) if unit.is_none() => {
+                let [lower, upper] = parse_bounds("documents", bounds, Value::as_i64)?;
+                Ok(Window::Documents(lower, upper))
+            }
+            (None, Some(bounds)) => {
+                let [lower, upper] = parse_bounds("range", bounds, |value| {
+                    let offset = value.as_f64().filter(|_| value.is_number())?;
+                    // Offsets in time units must be whole
+                    match unit {
+                        Some(_) => value.as_i64().map(|offset| offset as f64),
+                        None => Some(offset),
+                    }
+                })?;
+                Ok(Window::Range { lower, upper, unit })
+            }
+            _ => Err(failed_to_parse(
+                "'window' field can only contain 'documents' as the only argument or 'range' with an optional 'unit' field",
+            )),
+        }
+    }
+
+    /// The range of indexes of the window around document `i`.
+    fn bounds(
+        &self,
+        i: usize,
+        documents: &[Document],
+        path: Option<&[String]>,
+    ) -> CommandResult<(usize, usize)> {
+        match self {
+            Window::Documents(lower, upper) => {
+                let position = |bound: &Bound<i64>, unbounded: i64| match bound {
+                    Bound::Unbounded => unbounded,
+                    Bound::Current => i as i64,
+                    Bound::Offset(offset) => i as i64 + offset,
+                };
+                let len = documents.len() as i64;
+                let start = position(lower, 0).clamp(0, len);
+                let end = (position(upper, len - 1) + 1).clamp(start, len);
+                Ok((start as usize, end as usize))
+            }
+            Window::Range { lower, upper, unit } => {
+                let path = path.expect("range windows require a single sortBy field");
+                let mut values = Vec::with_capacity(documents.len());
+                for doc in documents {
+                    values.push(range_value(doc, path, *unit)?);
+                }
+                let current = values[i];
+                let limit = |bound: &Bound<f64>, unbounded: f64| match (bound, unit) {
+                    (Bound::Unbounded, _) => unbounded,
+                    (Bound::Current, _) => current,
+                    (Bound::Offset(offset), None) => current + offset,
+                    (Bound::Offset(offset), Some(unit)) => unit
+                        .add_utc(current as i64, *offset as i64)
+                        .map_or(unbounded, |ms| ms as f64),
+                };
+                let (low, high) = (limit(lower, f64::NEG_INFINITY), limit(upper, f64::INFINITY));
+                // Partitions are sorted on the field, so the window is
+                // contiguous
+                let mut inside =
+                    (0..values.len()).filter(|&j| values[j] >= low && values[j] <= high);
+                let start = inside.next();
+                let end = inside.next_back().or(start);
+                Ok(match (start, end) {
+                    (Some(start), Some(end)) => (start, end + 1),
+                    _ => (0, 0),
+                })
+            }
+        }
+    }
+}
+
+/// The value of the `sortBy` field of a range-based window: a number, or a
+/// date in milliseconds when the window has a unit.
+fn range_value(doc: &Document, path: &[String], unit: Option<TimeUnit>) -> CommandResult<f64> {
+    match (get_field(doc, path), unit) {
+        (Some(Value::UtcDateTime(ms)), Some(_)) => Ok(*ms as f64),
+        (Some(value), None) if value.is_number() => Ok(value.as_f64().unwrap_or(0.0)),
+        (value, Some(_)) => Err(location(
+            5429513,
+            format!(
+                "Invalid range: Expected the sortBy field to be a Date, but it was {}",
+                value.map_or("missing", Value::type_name)
+            ),
+        )),
+        (value, None) => Err(location(
+            5429414,
+            format!(
+                "Invalid range: Expected the sortBy field to be a number, but it was {}",
+                value.map_or("missing", Value::type_name)
+            ),
+        )),
+    }
+}
+
+/// Parses `[lower, upper]`, where each bound is `"unbounded"`, `"current"`
+/// or an offset read by `offset`.
+fn parse_bounds<T: Copy + PartialOrd + Default>(
+    kind: &str,
+    bounds: &Value,
+    offset: impl Fn(&Value) -> Option<T>,
+) -> CommandResult<[Bound<T>; 2]> {
+    let invalid = || {
+        failed_to_parse(format!(
+            "Window bounds must be a 2-element array of 'unbounded', 'current' or {}, found: {}",
+            if kind == "documents" {
+                "integers"
+            } else {
+                "numbers"
+            },
+            bounds
+        ))
+    };
+    let Value::Array(items) = bounds else {
+        return Err(invalid());
+    };
+    let [lower, upper] = &items.0[..] else {
+        return Err(invalid());
+    };
+    let mut parsed = [Bound::Unbounded; 2];
+    for (bound, value) in parsed.iter_mut().zip([lower, upper]) {
+        *bound = match value {
+            Value::String(s) if s == "unbounded" => Bound::Unbounded,
+            Value::String(s) if s == "current" => Bound::Current,
+            value => Bound::Offset(offset(value).ok_or_else(invalid)?),
+        };
+    }
+    // Compare the bounds as offsets from the current document
+    let offset_of = |bound: Bound<T>| match bound {
+        Bound::Offset(offset) => Some(offset),
+        Bound::Current => Some(T::default()),
+        Bound::Unbounded => None,
+    };
+    if let (Some(lower), Some(upper)) = (offset_of(parsed[0]), offset_of(parsed[1])) {
+        if lower > upper {
+            return Err(location(
+                5339900,
+                format!("Lower bound must not exceed upper bound: {}", bounds),
+            ));
+        }
+    }
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    fn run(spec: &str, documents: &[&str]) -> CommandResult<Vec<Document>> {
+        SetWindowFields::parse(&value(spec))?.run(
+            documents.iter().map(|d| doc(d)).collect(),
+            &Context::default(),
+        )
+    }
+
+    /// The value of `field` in each output document.
+    fn column(spec: &str, documents: &[&str], field: &str) -> Vec<Value> {
+        run(spec, documents)
+            .unwrap()
+            .iter()
+            .map(|doc| doc.get(field).cloned().unwrap_or(Value::Null))
+            .collect()
+    }
+
+    const SALES: [&str; 6] = [
+        "{ store: 'b', day: 3, amount: 10 }",
+        "{ store: 'a', day: 1, amount: 5 }",
+        "{ store: 'a', day: 2, amount: 7 }",
+        "{ store: 'b', day: 1, amount: 2 }",
+        "{ store: 'a', day: 4, amount: 7 }",
+        "{ store: 'b', day: 2, amount: 4 }",
+    ];
+
+    #[test]
+    fn test_document_windows_and_ranks() {
+        assert_eq!(
+            column(
+                "{ partitionBy: '$store', sortBy: { day: 1 }, output: { total: { $sum: '$amount', window: { documents: ['unbounded', 'current'] } } } }",
+                &SALES,
+                "total"
+            ),
+            [5, 12, 19, 2, 6, 16].map(Value::Int32)
+        );
+        assert_eq!(
+            column(
+                "{ partitionBy: '$store', sortBy: { day: 1 }, output: { avg: { $avg: '$amount', window: { documents: [-1, 1] } } } }",
+                &SALES,
+                "avg"
+            ),
+            [6.0, 19.0 / 3.0, 7.0, 3.0, 16.0 / 3.0, 7.0].map(Value::Double)
+        );
+        assert_eq!(
+            column(
+                "{ sortBy: { amount: -1 }, output: { rank: { $rank: {} } } }",
+                &SALES,
+                "rank"
+            ),
+            [1, 2, 2, 4, 5, 6].map(Value::Int32)
+        );
+        assert_eq!(
+            column(
+                "{ sortBy: { amount: -1 }, output: { rank: { $denseRank: {} } } }",
+                &SALES,
+                "rank"
+            ),
+            [1, 2, 2, 3, 4, 5].map(Value::Int32)
+        );
+        assert_eq!(
+            column(
+                "{ partitionBy: '$store', sortBy: { day: 1 }, output: { previous: { $shift: { output: '$amount', by: -1, default: 0 } } } }",
+                &SALES,
+                "previous"
+            ),
+            [0, 5, 7, 0, 2, 4].map(Value::Int32)
+        );
+        assert_eq!(
+            column(
+                "{ partitionBy: '$store', output: { n: { $documentNumber: {} } } }",
+                &SALES,
+                "n"
+            ),
+            [1, 2, 3, 1, 2, 3].map(Value::Int32)
+        );
+    }
+
+    #[test]
+    fn test_range_windows_and_calculus() {
+        let readings = [
+            "{ t: Date(0), v: 0 }",
+            "{ t: Date(60000), v: 6 }",
+            "{ t: Date(120000), v: 6 }",
+            "{ t: Date(300000), v: 0 }",
+        ];
+        assert_eq!(
+            column(
+                "{ sortBy: { t: 1 }, output: { recent: { $push: '$v', window: { range: [-2, 0], unit: 'minute' } } } }",
+                &readings,
+                "recent"
+            ),
+            vec![
+                value("[0]"),
+                value("[0, 6]"),
+                value("[0, 6, 6]"),
+                value("[0]"),
+            ]
+        );
+        assert_eq!(
+            column(
+                "{ sortBy: { t: 1 }, output: { area: { $integral: { input: '$v', unit: 'minute' } } } }",
+                &readings,
+                "area"
+            ),
+            vec![Value::Double(18.0); 4]
+        );
+        assert_eq!(
+            column(
+                "{ sortBy: { t: 1 }, output: { rate: { $derivative: { input: '$v', unit: 'minute' }, window: { documents: [-1, 0] } } } }",
+                &readings,
+                "rate"
+            ),
+            vec![
+                Value::Null,
+                Value::Double(6.0),
+                Value::Double(0.0),
+                Value::Double(-2.0),
+            ]
+        );
+        assert_eq!(
+            column(
+                "{ sortBy: { day: 1 }, output: { near: { $count: {}, window: { range: [-1, 1] } } } }",
+                &SALES,
+                "near"
+            ),
+            [4, 4, 5, 5, 4, 2].map(Value::Int32)
+        );
+        assert_eq!(
+            column(
+                "{ sortBy: { day: 1 }, output: { ema: { $expMovingAvg: { input: '$amount', alpha: 0.5 } } } }",
+                &SALES[..2],
+                "ema"
+            ),
+            [5.0, 7.5].map(Value::Double)
+        );
+        assert_eq!(
+            column(
+                "{ output: { cov: { $covariancePop: ['$day', '$amount'] } } }",
+                &SALES[1..3],
+                "cov"
+            ),
+            [0.5, 0.5].map(Value::Double)
+        );
+    }
+
+    #[test]
+    fn test_invalid_specifications() {
+        let code = |spec| run(spec, &[]).unwrap_err().code;
+        assert_eq!(
+            code("{ output: { r: { $rank: {} } } }"),
+            ErrorCode::Location(5371602)
+        );
+        assert_eq!(
+            code(
+                "{ sortBy: { a: 1 }, output: { r: { $rank: {}, window: { documents: [0, 1] } } } }"
+            ),
+            ErrorCode::Location(5371601)
+        );
+        assert_eq!(
+            code("{ output: { s: { $sum: 1, window: { range: [0, 1] } } } }"),
+            ErrorCode::Location(5339902)
+        );
+        assert_eq!(
+            code("{ output: { s: { $sum: 1, window: { documents: [1, 0] } } } }"),
+            ErrorCode::Location(5339900)
+        );
+        assert_eq!(
+            code("{ output: { s: { $foo: 1 } } }"),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(
+            code("{ sortBy: { a: 1 }, output: { d: { $derivative: { input: '$a' } } } }"),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(code("{ partitionBy: '$a' }"), ErrorCode::Location(40414));
+    }
+}
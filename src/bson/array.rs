@@ -1,6 +1,8 @@
+use std::fmt;
+
 use super::{Document, Value};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Array(pub Vec<Value>);
 
 impl Array {
@@ -12,12 +14,34 @@ impl Array {
         Self(array)
     }
 
+    /// Arrays are encoded as documents keyed by "0", "1", ...
+    pub fn to_document(&self) -> Document {
+        Document(
+            self.0
+                .iter()
+                .enumerate()
+                .map(|(i, value)| (i.to_string(), value.clone()))
+                .collect(),
+        )
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        for value in &self.0 {
-            bytes.extend_from_slice(&value.to_bytes());
+        self.to_document().to_bytes()
+    }
+}
+
+impl fmt::Display for Array {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "[]");
+        }
+        write!(f, "[ ")?;
+        for (i, value) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", value)?;
         }
-        bytes.push(0);
-        bytes
+        write!(f, " ]")
     }
 }
@@ -0,0 +1,251 @@
+use std::{
+    cmp::Ordering,
+    hash::{Hash, Hasher},
+};
+
+use super::{Document, Value};
+use crate::collation::Collation;
+
+impl Value {
+    /// The rank of the value's type in the BSON comparison order. Values of
+    /// different types compare by rank; all numeric types share one rank, as
+    /// do strings and symbols.
+    pub fn canonical_type(&self) -> i32 {
+        match self {
+            Value::MinKey => -1,
+            Value::Undefined => 0,
+            Value::Null => 5,
+            Value::Double(_) | Value::Int32(_) | Value::Int64(_) | Value::Decimal128(_) => 10,
+            Value::String(_) | Value::Symbol(_) => 15,
+            Value::Document(_) => 20,
+            Value::Array(_) => 25,
+            Value::Binary(_) => 30,
+            Value::ObjectId(_) => 35,
+            Value::Boolean(_) => 40,
+            Value::UtcDateTime(_) => 45,
+            Value::Timestamp(_) => 47,
+            Value::Regex(_, _) => 50,
+            Value::DBPointer(_, _) => 55,
+            Value::JavaScriptCode(_) => 60,
+            Value::JavaScriptCodeWithScope(_, _) => 65,
+            Value::MaxKey => 127,
+        }
+    }
+
+    /// Compares two values using the BSON comparison order, so `1`,
+    /// `NumberLong(1)` and `1.0` are equal.
+    pub fn compare(&self, other: &Value) -> Ordering {
+        self.compare_with(other, None)
+    }
+
+    /// Compares two values like `compare`, with strings, down to those
+    /// nested in documents and arrays, compared under a collation.
+    pub fn compare_with(&self, other: &Value, collation: Option<&Collation>) -> Ordering {
+        let rank = self.canonical_type().cmp(&other.canonical_type());
+        if rank != Ordering::Equal {
+            return rank;
+        }
+
+        match (self, other) {
+            (Value::Int32(a), Value::Int32(b)) => a.cmp(b),
+            (Value::Int32(_) | Value::Int64(_), Value::Int32(_) | Value::Int64(_)) => self
+                .as_i64()
+                .unwrap_or_default()
+                .cmp(&other.as_i64().unwrap_or_default()),
+            (a, b) if a.is_number() => compare_numbers(a, b),
+            (Value::String(a) | Value::Symbol(a), Value::String(b) | Value::Symbol(b)) => {
+                match collation {
+                    Some(collation) => collation.compare(a, b),
+                    None => a.as_bytes().cmp(b.as_bytes()),
+                }
+            }
+            (Value::Document(a), Value::Document(b)) => a.compare_with(b, collation),
+            (Value::Array(a), Value::Array(b)) => compare_slices(&a.0, &b.0, collation),
+            (Value::Binary(a), Value::Binary(b)) => a.len().cmp(&b.len()).then_with(|| a.cmp(b)),
+            (Value::ObjectId(a), Value::ObjectId(b)) => a.cmp(b),
+            (Value::Boolean(a), Value::Boolean(b)) => a.cmp(b),
+            (Value::UtcDateTime(a), Value::UtcDateTime(b)) => a.cmp(b),
+            (Value::Timestamp(a), Value::Timestamp(b)) => a.cmp(b),
+            (Value::Regex(p1, o1), Value::Regex(p2, o2)) => p1.cmp(p2).then_with(|| o1.cmp(o2)),
+            (Value::DBPointer(n1, i1), Value::DBPointer(n2, i2)) => n1
+                .len()
+                .cmp(&n2.len())
+                .then_with(|| n1.cmp(n2))
+                .then_with(|| i1.cmp(i2)),
+            (Value::JavaScriptCode(a), Value::JavaScriptCode(b)) => a.cmp(b),
+            (Value::JavaScriptCodeWithScope(c1, s1), Value::JavaScriptCodeWithScope(c2, s2)) => {
+                c1.cmp(c2).then_with(|| s1.compare(s2))
+            }
+            _ => Ordering::Equal,
+        }
+    }
+
+    /// Equality under the BSON comparison order.
+    pub fn equals(&self, other: &Value) -> bool {
+        self.compare(other) == Ordering::Equal
+    }
+
+    /// Hashes the value so that values that are `equals` hash alike:
+    /// numbers by their value as a double, strings and symbols by their
+    /// text.
+    pub fn hash_value<H: Hasher>(&self, state: &mut H) {
+        self.canonical_type().hash(state);
+        match self {
+            value if value.is_number() => {
+                // NaN is equal to itself, and -0 to 0
+                let number = value.as_f64().unwrap_or(f64::NAN);
+                let number = if number.is_nan() {
+                    f64::NAN
+                } else {
+                    number + 0.0
+                };
+                number.to_bits().hash(state);
+            }
+            Value::String(s) | Value::Symbol(s) => s.hash(state),
+            Value::Document(doc) => doc.hash_value(state),
+            Value::Array(items) => {
+                items.0.len().hash(state);
+                for item in &items.0 {
+                    item.hash_value(state);
+                }
+            }
+            Value::Binary(bytes) | Value::ObjectId(bytes) => bytes.hash(state),
+            Value::Boolean(b) => b.hash(state),
+            Value::UtcDateTime(millis) => millis.hash(state),
+            Value::Timestamp(ts) => ts.hash(state),
+            Value::Regex(pattern, options) => (pattern, options).hash(state),
+            Value::DBPointer(namespace, id) => (namespace, id).hash(state),
+            Value::JavaScriptCode(code) => code.hash(state),
+            Value::JavaScriptCodeWithScope(code, scope) => {
+                code.hash(state);
+                scope.hash_value(state);
+            }
+            _ => {}
+        }
+    }
+
+    /// Equality under the BSON comparison order and a collation.
+    pub fn equals_with(&self, other: &Value, collation: Option<&Collation>) -> bool {
+        self.compare_with(other, collation) == Ordering::Equal
+    }
+}
+
+impl Document {
+    /// Compares documents field by field: first by the type of each value,
+    /// then by field name, then by value. A document that is a prefix of
+    /// another sorts first.
+    pub fn compare(&self, other: &Document) -> Ordering {
+        self.compare_with(other, None)
+    }
+
+    /// Compares documents like `compare`, with strings compared under a
+    /// collation.
+    pub fn compare_with(&self, other: &Document, collation: Option<&Collation>) -> Ordering {
+        for ((k1, v1), (k2, v2)) in self.iter().zip(other.iter()) {
+            let ordering = v1
+                .canonical_type()
+                .cmp(&v2.canonical_type())
+                .then_with(|| k1.cmp(k2))
+                .then_with(|| v1.compare_with(v2, collation));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        self.len().cmp(&other.len())
+    }
+
+    /// Hashes the document consistently with `Value::hash_value`.
+    pub fn hash_value<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for (key, value) in self.iter() {
+            key.hash(state);
+            value.hash_value(state);
+        }
+    }
+}
+
+fn compare_slices(a: &[Value], b: &[Value], collation: Option<&Collation>) -> Ordering {
+    for (v1, v2) in a.iter().zip(b.iter()) {
+        let ordering = v1.compare_with(v2, collation);
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// NaN sorts before every other number and is equal to itself.
+fn compare_numbers(a: &Value, b: &Value) -> Ordering {
+    let a = a.as_f64().unwrap_or(f64::NAN);
+    let b = b.as_f64().unwrap_or(f64::NAN);
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Less,
+        (false, true) => Ordering::Greater,
+        (false, false) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::hash::DefaultHasher;
+
+    use super::*;
+
+    #[test]
+    fn test_numbers_compare_across_types() {
+        assert!(Value::Int32(1).equals(&Value::Double(1.0)));
+        assert!(Value::Int64(1).equals(&Value::Int32(1)));
+        assert_eq!(
+            Value::Int32(2).compare(&Value::Double(1.5)),
+            Ordering::Greater
+        );
+        assert_eq!(
+            Value::Double(f64::NAN).compare(&Value::Int32(-100)),
+            Ordering::Less
+        );
+    }
+
+    #[test]
+    fn test_equal_values_hash_alike() {
+        let hash = |value: &Value| {
+            let mut state = DefaultHasher::new();
+            value.hash_value(&mut state);
+            state.finish()
+        };
+        let equal = [
+            (Value::Int32(1), Value::Double(1.0)),
+            (Value::Int64(1), Value::Int32(1)),
+            (Value::Double(-0.0), Value::Int32(0)),
+            (Value::Double(f64::NAN), Value::Double(-f64::NAN)),
+            (Value::String("a".into()), Value::Symbol("a".into())),
+            (
+                Value::from(vec![Value::Int32(1)]),
+                Value::from(vec![Value::Double(1.0)]),
+            ),
+        ];
+        for (a, b) in &equal {
+            assert!(a.equals(b), "{:?} {:?}", a, b);
+            assert_eq!(hash(a), hash(b), "{:?} {:?}", a, b);
+        }
+        assert_ne!(hash(&Value::Int32(1)), hash(&Value::Int32(2)));
+    }
+
+    #[test]
+    fn test_type_order() {
+        let ordered = [
+            Value::MinKey,
+            Value::Null,
+            Value::Int32(100),
+            Value::String("a".into()),
+            Value::Document(Document::new()),
+            Value::from(Vec::<Value>::new()),
+            Value::Boolean(false),
+            Value::UtcDateTime(0),
+            Value::MaxKey,
+        ];
+        for pair in ordered.windows(2) {
+            assert_eq!(pair[0].compare(&pair[1]), Ordering::Less, "{:?}", pair);
+        }
+    }
+}
@@ -1,19 +1,110 @@
-use std::collections::HashMap;
+use std::fmt;
 
 use super::Value;
 
-#[derive(Debug)]
-pub struct Document(pub HashMap<String, Value>);
+/// An ordered BSON document. Field order is significant in MongoDB (command
+/// names, sort specifications, index key patterns), so fields are kept in
+/// insertion order rather than in a hash map.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Document(pub Vec<(String, Value)>);
 
 impl Document {
+    pub fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        self.0.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.iter().any(|(k, _)| k == key)
+    }
+
+    /// Sets `key` to `value`, keeping the position of an existing field or
+    /// appending a new one. Returns the previous value, if any.
+    pub fn insert(&mut self, key: impl Into<String>, value: Value) -> Option<Value> {
+        let key = key.into();
+        match self.get_mut(&key) {
+            Some(existing) => Some(std::mem::replace(existing, value)),
+            None => {
+                self.0.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        let index = self.0.iter().position(|(k, _)| k == key)?;
+        Some(self.0.remove(index).1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.0.iter().map(|(k, v)| (k, v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.0.iter().map(|(k, _)| k)
+    }
+
+    /// Returns the first field of the document, which is how commands are
+    /// identified on the wire.
+    pub fn first(&self) -> Option<(&String, &Value)> {
+        self.0.first().map(|(k, v)| (k, v))
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
+        // Document length placeholder
+        bytes.extend_from_slice(&[0u8; 4]);
         for (key, value) in &self.0 {
-            bytes.extend_from_slice(&key.as_bytes());
+            bytes.push(value.element_type());
+            bytes.extend_from_slice(key.as_bytes());
             bytes.push(0);
             bytes.extend_from_slice(&value.to_bytes());
         }
         bytes.push(0);
+
+        let length = bytes.len() as i32;
+        bytes[0..4].copy_from_slice(&length.to_le_bytes());
         bytes
     }
 }
+
+impl FromIterator<(String, Value)> for Document {
+    fn from_iter<T: IntoIterator<Item = (String, Value)>>(iter: T) -> Self {
+        let mut doc = Document::new();
+        for (key, value) in iter {
+            doc.insert(key, value);
+        }
+        doc
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "{{}}");
+        }
+        write!(f, "{{ ")?;
+        for (i, (key, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", key, value)?;
+        }
+        write!(f, " }}")
+    }
+}
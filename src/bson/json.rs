@@ -0,0 +1,178 @@
+//! A relaxed JSON reader for tests, accepting the mongo shell style of
+//! unquoted keys so fixtures stay readable: `doc("{ a: 1, b: [1.5, 'x'] }")`.
+//! Integers become `Int32` (or `Int64` when they don't fit), numbers with a
+//! fraction or exponent become `Double`. `Date(ms)` creates a UTC datetime.
+
+use super::{Array, Document, Value};
+
+pub fn doc(input: &str) -> Document {
+    match value(input) {
+        Value::Document(doc) => doc,
+        other => panic!("expected a document, got {:?}", other),
+    }
+}
+
+pub fn value(input: &str) -> Value {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        pos: 0,
+    };
+    let value = parser.parse_value();
+    parser.skip_whitespace();
+    assert_eq!(
+        parser.pos,
+        parser.chars.len(),
+        "trailing input in {}",
+        input
+    );
+    value
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> char {
+        let c = self.chars[self.pos];
+        self.pos += 1;
+        c
+    }
+
+    fn expect(&mut self, expected: char) {
+        self.skip_whitespace();
+        let c = self.next();
+        assert_eq!(c, expected, "at position {}", self.pos);
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_value(&mut self) -> Value {
+        self.skip_whitespace();
+        match self.peek().expect("unexpected end of input") {
+            '{' => Value::Document(self.parse_document()),
+            '[' => {
+                self.next();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    if self.peek() == Some(']') {
+                        self.next();
+                        break;
+                    }
+                    items.push(self.parse_value());
+                    self.skip_whitespace();
+                    if self.peek() == Some(',') {
+                        self.next();
+                    }
+                }
+                Value::Array(Array(items))
+            }
+            '"' | '\'' => Value::String(self.parse_string()),
+            c if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            _ => {
+                let word = self.parse_word();
+                match word.as_str() {
+                    "true" => Value::Boolean(true),
+                    "false" => Value::Boolean(false),
+                    "null" => Value::Null,
+                    "MinKey" => Value::MinKey,
+                    "MaxKey" => Value::MaxKey,
+                    "Date" => {
+                        self.expect('(');
+                        let ms = self.parse_number();
+                        self.expect(')');
+                        Value::UtcDateTime(ms.as_i64().expect("integer date"))
+                    }
+                    "Long" => {
+                        self.expect('(');
+                        let v = self.parse_number();
+                        self.expect(')');
+                        Value::Int64(v.as_i64().expect("integer long"))
+                    }
+                    _ => panic!("unexpected token {}", word),
+                }
+            }
+        }
+    }
+
+    fn parse_document(&mut self) -> Document {
+        self.expect('{');
+        let mut doc = Document::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some('}') {
+                self.next();
+                break;
+            }
+            let key = match self.peek() {
+                Some('"' | '\'') => self.parse_string(),
+                _ => self.parse_word(),
+            };
+            self.expect(':');
+            let value = self.parse_value();
+            doc.0.push((key, value));
+            self.skip_whitespace();
+            if self.peek() == Some(',') {
+                self.next();
+            }
+        }
+        doc
+    }
+
+    fn parse_string(&mut self) -> String {
+        let quote = self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                '\\' => s.push(self.next()),
+                c if c == quote => break,
+                c => s.push(c),
+            }
+        }
+        s
+    }
+
+    fn parse_word(&mut self) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || matches!(c, '_' | '$' | '.') {
+                s.push(c);
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        s
+    }
+
+    fn parse_number(&mut self) -> Value {
+        self.skip_whitespace();
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E') {
+                s.push(c);
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+        if s.contains(['.', 'e', 'E']) {
+            return Value::Double(s.parse().expect("valid double"));
+        }
+        let n: i64 = s.parse().expect("valid integer");
+        match i32::try_from(n) {
+            Ok(n) => Value::Int32(n),
+            Err(_) => Value::Int64(n),
+        }
+    }
+}
@@ -1,12 +1,13 @@
-use std::collections::HashMap;
-
 mod array;
+mod compare;
 mod document;
+#[cfg(test)]
+pub mod json;
 mod value;
 
 pub use array::Array;
 pub use document::Document;
-pub use value::Value;
+pub use value::{Decimal128, Value};
 
 pub struct Bson<'a> {
     bytes: &'a [u8],
@@ -30,7 +31,7 @@ impl<'a> Bson<'a> {
     }
 
     pub fn parse_document(&self, start_from: usize) -> Document {
-        let mut map = HashMap::new();
+        let mut doc = Document::new();
         let mut i = start_from;
 
         loop {
@@ -85,14 +86,11 @@ impl<'a> Bson<'a> {
                 // Binary
                 0x05 => {
                     let value = self.parse_binary(i);
-                    i += value.len() + 1;
+                    i += 4 + 1 + value.len();
                     Value::Binary(value)
                 }
                 // Undefined
-                0x06 => {
-                    i += 1;
-                    Value::Undefined
-                }
+                0x06 => Value::Undefined,
                 // ObjectId
                 0x07 => {
                     let value = self.parse_object_id(i);
@@ -122,26 +120,26 @@ impl<'a> Bson<'a> {
                 // DBPointer
                 0x0C => {
                     let value = self.parse_db_pointer(i);
-                    i += value.0.len() + 1 + 12;
+                    i += 4 + value.0.len() + 1 + 12;
                     Value::DBPointer(value.0, value.1)
                 }
                 // JavaScriptCode
                 0x0D => {
-                    let value = self.parse_java_script_code(i);
-                    i += 1 + name.len() + 1 + value.len() + 1;
+                    let (value, size) = self.parse_java_script_code(i);
+                    i += size + 4;
                     Value::JavaScriptCode(value)
                 }
                 // Symbol
                 0x0E => {
-                    let value = self.parse_symbol(i);
-                    i += value.len() + 1;
+                    let (value, size) = self.parse_symbol(i);
+                    i += size + 4;
                     Value::Symbol(value)
                 }
                 // JavaScriptCodeWithScope
                 0x0F => {
-                    let value = self.parse_java_script_code_with_scope(i + 1 + name.len() + 1);
-                    println!("name: {:?}, value: {:?}", name, value);
-                    i += value.0.len() + 1 + /* TODO: value.1.len() + */ 1;
+                    let size = self.parse_int32(i) as usize;
+                    let value = self.parse_java_script_code_with_scope(i + 4);
+                    i += size;
                     Value::JavaScriptCodeWithScope(value.0, value.1)
                 }
                 // Int32
@@ -164,33 +162,25 @@ impl<'a> Bson<'a> {
                 }
                 // Decimal128
                 0x13 => {
-                    // let value = self.parse_decimal128(i + 1 + name.len() + 1);
-                    // println!("name: {:?}, value: {:?}", name, value);
-                    // i += 1 + name.len() + 1 + 16;
-                    // Value::Decimal128(value)
-                    todo!()
+                    let value = self.parse_decimal128(i);
+                    i += 16;
+                    Value::Decimal128(value)
                 }
                 // MinKey
-                0xFF => {
-                    i += 1 + name.len() + 1;
-                    Value::MinKey
-                }
+                0xFF => Value::MinKey,
                 // MaxKey
-                0x7F => {
-                    i += 1 + name.len() + 1;
-                    Value::MaxKey
-                }
+                0x7F => Value::MaxKey,
                 _ => {
                     panic!("unknown element type: {:x?}", element_type);
                 }
             };
-            map.insert(name, value);
+            doc.0.push((name, value));
 
             if i >= self.len() as usize {
                 break;
             }
         }
-        Document(map)
+        doc
     }
 
     pub fn parse_string(&self, i: usize) -> (String, usize) {
@@ -230,7 +220,8 @@ impl<'a> Bson<'a> {
                 .expect("message is well formed"),
         ) as usize;
 
-        self.bytes[i + 4..i + 4 + size].to_vec()
+        // skip the subtype byte
+        self.bytes[i + 5..i + 5 + size].to_vec()
     }
 
     pub fn parse_object_id(&self, i: usize) -> Vec<u8> {
@@ -241,8 +232,8 @@ impl<'a> Bson<'a> {
         self.bytes[i] == 0x01
     }
 
-    pub fn parse_utc_date_time(&self, i: usize) -> u64 {
-        u64::from_le_bytes(
+    pub fn parse_utc_date_time(&self, i: usize) -> i64 {
+        i64::from_le_bytes(
             self.bytes[i..i + 8]
                 .try_into()
                 .expect("message is well formed"),
@@ -256,22 +247,22 @@ impl<'a> Bson<'a> {
     }
 
     pub fn parse_db_pointer(&self, i: usize) -> (String, Vec<u8>) {
-        let collection = self.parse_cstring(i);
-        let id = self.bytes[i + collection.len() + 1..i + collection.len() + 1 + 12].to_vec();
+        let (collection, size) = self.parse_string(i);
+        let id = self.bytes[i + 4 + size..i + 4 + size + 12].to_vec();
         (collection, id)
     }
 
-    pub fn parse_java_script_code(&self, i: usize) -> String {
-        self.parse_cstring(i)
+    pub fn parse_java_script_code(&self, i: usize) -> (String, usize) {
+        self.parse_string(i)
     }
 
-    pub fn parse_symbol(&self, i: usize) -> String {
-        self.parse_cstring(i)
+    pub fn parse_symbol(&self, i: usize) -> (String, usize) {
+        self.parse_string(i)
     }
 
     pub fn parse_java_script_code_with_scope(&self, i: usize) -> (String, Document) {
-        let code = self.parse_java_script_code(i);
-        let scope = self.parse_document(i + code.len() + 1);
+        let (code, size) = self.parse_java_script_code(i);
+        let scope = self.parse_document(i + 4 + size + 4);
         (code, scope)
     }
 
@@ -299,9 +290,13 @@ impl<'a> Bson<'a> {
         )
     }
 
-    // pub fn parse_decimal128(&self, i: usize) -> Decimal128 {
-    //     todo!()
-    // }
+    pub fn parse_decimal128(&self, i: usize) -> Decimal128 {
+        Decimal128(
+            self.bytes[i..i + 16]
+                .try_into()
+                .expect("message is well formed"),
+        )
+    }
 }
 
 #[cfg(test)]
@@ -341,4 +336,14 @@ mod test {
         let doc = Bson::from_bytes(&data).parse();
         println!("doc: {:#?}", doc);
     }
+
+    #[test]
+    fn test_round_trip_preserves_field_order() {
+        let doc = json::doc(
+            "{ z: 1, a: { y: 'two', b: [1.5, true, null] }, m: Long(3000000000), d: Date(42) }",
+        );
+        let bytes = doc.to_bytes();
+        assert_eq!(Bson::from_bytes(&bytes).len() as usize, bytes.len());
+        assert_eq!(Bson::from_bytes(&bytes).parse(), doc);
+    }
 }
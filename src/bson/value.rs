@@ -1,6 +1,12 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use super::{Array, Document};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Double(f64),                               // \x01
     String(String),                            // \x02
@@ -10,7 +16,7 @@ pub enum Value {
     Undefined,                                 // \x06
     ObjectId(Vec<u8>),                         // \x07
     Boolean(bool),                             // \x08
-    UtcDateTime(u64),                          // \x09
+    UtcDateTime(i64),                          // \x09
     Null,                                      // \x0A
     Regex(String, String),                     // \x0B
     DBPointer(String, Vec<u8>),                // \x0C
@@ -26,109 +32,343 @@ pub enum Value {
 }
 
 impl Value {
+    pub fn element_type(&self) -> u8 {
+        match self {
+            Value::Double(_) => 0x01,
+            Value::String(_) => 0x02,
+            Value::Document(_) => 0x03,
+            Value::Array(_) => 0x04,
+            Value::Binary(_) => 0x05,
+            Value::Undefined => 0x06,
+            Value::ObjectId(_) => 0x07,
+            Value::Boolean(_) => 0x08,
+            Value::UtcDateTime(_) => 0x09,
+            Value::Null => 0x0A,
+            Value::Regex(_, _) => 0x0B,
+            Value::DBPointer(_, _) => 0x0C,
+            Value::JavaScriptCode(_) => 0x0D,
+            Value::Symbol(_) => 0x0E,
+            Value::JavaScriptCodeWithScope(_, _) => 0x0F,
+            Value::Int32(_) => 0x10,
+            Value::Timestamp(_) => 0x11,
+            Value::Int64(_) => 0x12,
+            Value::Decimal128(_) => 0x13,
+            Value::MinKey => 0xFF,
+            Value::MaxKey => 0x7F,
+        }
+    }
+
+    /// The type alias MongoDB uses for this value in `$type` and in error
+    /// messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Double(_) => "double",
+            Value::String(_) => "string",
+            Value::Document(_) => "object",
+            Value::Array(_) => "array",
+            Value::Binary(_) => "binData",
+            Value::Undefined => "undefined",
+            Value::ObjectId(_) => "objectId",
+            Value::Boolean(_) => "bool",
+            Value::UtcDateTime(_) => "date",
+            Value::Null => "null",
+            Value::Regex(_, _) => "regex",
+            Value::DBPointer(_, _) => "dbPointer",
+            Value::JavaScriptCode(_) => "javascript",
+            Value::Symbol(_) => "symbol",
+            Value::JavaScriptCodeWithScope(_, _) => "javascriptWithScope",
+            Value::Int32(_) => "int",
+            Value::Timestamp(_) => "timestamp",
+            Value::Int64(_) => "long",
+            Value::Decimal128(_) => "decimal",
+            Value::MinKey => "minKey",
+            Value::MaxKey => "maxKey",
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(
+            self,
+            Value::Double(_) | Value::Int32(_) | Value::Int64(_) | Value::Decimal128(_)
+        )
+    }
+
+    pub fn is_null_or_undefined(&self) -> bool {
+        matches!(self, Value::Null | Value::Undefined)
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Double(v) => Some(*v),
+            Value::Int32(v) => Some(*v as f64),
+            Value::Int64(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+
+    /// Returns the value as an integer if it is an integral number, the way
+    /// mongod accepts `1`, `1.0` and `NumberLong(1)` interchangeably in
+    /// command arguments.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int32(v) => Some(*v as i64),
+            Value::Int64(v) => Some(*v),
+            Value::Double(v) if v.fract() == 0.0 && v.is_finite() => Some(*v as i64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::Array(v) => Some(&v.0),
+            _ => None,
+        }
+    }
+
+    /// Truthiness as used by command options and `$cond`-like contexts: false,
+    /// null, undefined and numeric zero are false, everything else is true.
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            Value::Boolean(v) => *v,
+            Value::Null | Value::Undefined => false,
+            Value::Int32(v) => *v != 0,
+            Value::Int64(v) => *v != 0,
+            Value::Double(v) => *v != 0.0,
+            _ => true,
+        }
+    }
+
+    pub fn new_object_id() -> Value {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let counter = COUNTER.fetch_add(1, Ordering::Relaxed);
+        // 5 "random" bytes unique to this process
+        let process = (std::process::id() as u64) << 8 ^ (now.subsec_nanos() as u64 & 0xff);
+
+        let mut bytes = Vec::with_capacity(12);
+        bytes.extend_from_slice(&(now.as_secs() as u32).to_be_bytes());
+        bytes.extend_from_slice(&process.to_be_bytes()[3..8]);
+        bytes.extend_from_slice(&counter.to_be_bytes()[1..4]);
+        Value::ObjectId(bytes)
+    }
+
+    pub fn now() -> Value {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Value::UtcDateTime(now.as_millis() as i64)
+    }
+
+    /// Encodes the value payload, without the element type and name.
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         match self {
             Value::Double(v) => {
-                bytes.push(0x01);
                 bytes.extend_from_slice(&v.to_le_bytes());
             }
-            Value::String(v) => {
-                bytes.push(0x02);
-                bytes.extend_from_slice(&(v.len() as i32).to_le_bytes());
+            Value::String(v) | Value::JavaScriptCode(v) | Value::Symbol(v) => {
+                bytes.extend_from_slice(&(v.len() as i32 + 1).to_le_bytes());
                 bytes.extend_from_slice(v.as_bytes());
                 bytes.push(0);
             }
             Value::Document(v) => {
-                bytes.push(0x03);
                 bytes.extend_from_slice(&v.to_bytes());
             }
             Value::Array(v) => {
-                bytes.push(0x04);
                 bytes.extend_from_slice(&v.to_bytes());
             }
             Value::Binary(v) => {
-                bytes.push(0x05);
                 bytes.extend_from_slice(&(v.len() as i32).to_le_bytes());
                 bytes.push(0);
                 bytes.extend_from_slice(v);
             }
-            Value::Undefined => {
-                bytes.push(0x06);
-            }
+            Value::Undefined | Value::Null | Value::MinKey | Value::MaxKey => {}
             Value::ObjectId(v) => {
-                bytes.push(0x07);
                 bytes.extend_from_slice(v);
             }
             Value::Boolean(v) => {
-                bytes.push(0x08);
                 bytes.push(if *v { 0x01 } else { 0x00 });
             }
             Value::UtcDateTime(v) => {
-                bytes.push(0x09);
                 bytes.extend_from_slice(&v.to_le_bytes());
             }
-            Value::Null => {
-                bytes.push(0x0A);
-            }
             Value::Regex(v1, v2) => {
-                bytes.push(0x0B);
                 bytes.extend_from_slice(v1.as_bytes());
                 bytes.push(0);
                 bytes.extend_from_slice(v2.as_bytes());
                 bytes.push(0);
             }
             Value::DBPointer(v1, v2) => {
-                bytes.push(0x0C);
-                bytes.extend_from_slice(&(v1.len() as i32).to_le_bytes());
+                bytes.extend_from_slice(&(v1.len() as i32 + 1).to_le_bytes());
                 bytes.extend_from_slice(v1.as_bytes());
                 bytes.push(0);
                 bytes.extend_from_slice(v2);
             }
-            Value::JavaScriptCode(v) => {
-                bytes.push(0x0D);
-                bytes.extend_from_slice(&(v.len() as i32).to_le_bytes());
-                bytes.extend_from_slice(v.as_bytes());
-                bytes.push(0);
-            }
-            Value::Symbol(v) => {
-                bytes.push(0x0E);
-                bytes.extend_from_slice(&(v.len() as i32).to_le_bytes());
-                bytes.extend_from_slice(v.as_bytes());
-                bytes.push(0);
-            }
             Value::JavaScriptCodeWithScope(v1, v2) => {
-                bytes.push(0x0F);
-                bytes.extend_from_slice(&(v1.len() as i32).to_le_bytes());
+                let scope = v2.to_bytes();
+                let length = 4 + 4 + v1.len() + 1 + scope.len();
+                bytes.extend_from_slice(&(length as i32).to_le_bytes());
+                bytes.extend_from_slice(&(v1.len() as i32 + 1).to_le_bytes());
                 bytes.extend_from_slice(v1.as_bytes());
                 bytes.push(0);
-                bytes.extend_from_slice(&v2.to_bytes());
+                bytes.extend_from_slice(&scope);
             }
             Value::Int32(v) => {
-                bytes.push(0x10);
                 bytes.extend_from_slice(&v.to_le_bytes());
             }
             Value::Timestamp(v) => {
-                bytes.push(0x11);
                 bytes.extend_from_slice(&v.to_le_bytes());
             }
             Value::Int64(v) => {
-                bytes.push(0x12);
                 bytes.extend_from_slice(&v.to_le_bytes());
             }
-            Value::Decimal128(_) => {
-                bytes.push(0x13);
-            }
-            Value::MinKey => {
-                bytes.push(0xFF);
-            }
-            Value::MaxKey => {
-                bytes.push(0x7F);
+            Value::Decimal128(v) => {
+                bytes.extend_from_slice(&v.0);
             }
         }
         bytes
     }
 }
 
-#[derive(Debug)]
-pub struct Decimal128;
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::String(v.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::String(v)
+    }
+}
+
+impl From<i32> for Value {
+    fn from(v: i32) -> Self {
+        Value::Int32(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int64(v)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Self {
+        Value::Double(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Boolean(v)
+    }
+}
+
+impl From<Document> for Value {
+    fn from(v: Document) -> Self {
+        Value::Document(v)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(v: Vec<Value>) -> Self {
+        Value::Array(Array(v))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Formats values the way mongod renders them in error messages.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Double(v) => write!(f, "{:?}", v),
+            Value::String(v) => write!(f, "{:?}", v),
+            Value::Document(v) => write!(f, "{}", v),
+            Value::Array(v) => write!(f, "{}", v),
+            Value::Binary(v) => write!(f, "BinData(0, {})", hex(v)),
+            Value::Undefined => write!(f, "undefined"),
+            Value::ObjectId(v) => write!(f, "ObjectId('{}')", hex(v)),
+            Value::Boolean(v) => write!(f, "{}", v),
+            Value::UtcDateTime(v) => write!(f, "new Date({})", v),
+            Value::Null => write!(f, "null"),
+            Value::Regex(pattern, options) => write!(f, "/{}/{}", pattern, options),
+            Value::DBPointer(ns, id) => write!(f, "DBPointer('{}', {})", ns, hex(id)),
+            Value::JavaScriptCode(v) => write!(f, "{}", v),
+            Value::Symbol(v) => write!(f, "{:?}", v),
+            Value::JavaScriptCodeWithScope(code, scope) => {
+                write!(f, "CodeWScope({}, {})", code, scope)
+            }
+            Value::Int32(v) => write!(f, "{}", v),
+            Value::Timestamp(v) => write!(f, "Timestamp({}, {})", v >> 32, v & 0xffff_ffff),
+            Value::Int64(v) => write!(f, "{}", v),
+            Value::Decimal128(_) => write!(f, "NumberDecimal(?)"),
+            Value::MinKey => write!(f, "MinKey"),
+            Value::MaxKey => write!(f, "MaxKey"),
+        }
+    }
+}
+
+/// Raw IEEE 754-2008 decimal128 bytes, stored as received.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decimal128(pub [u8; 16]);
+
+impl Decimal128 {
+    /// The value rounded to an integer, ties to even, like mongod's
+    /// `Decimal128::toLong`. NaN, infinities and values out of range have
+    /// none.
+    pub fn to_i64(&self) -> Option<i64> {
+        let bits = u128::from_le_bytes(self.0);
+        let negative = bits >> 127 == 1;
+        let (exponent, coefficient) = match (bits >> 125) & 0b11 {
+            // The coefficient of the other form is always too large to be
+            // canonical, and reads as zero
+            0b11 if (bits >> 122) & 0b1111 == 0b1111 => return None,
+            0b11 => ((bits >> 111) & 0x3fff, 0),
+            _ => ((bits >> 113) & 0x3fff, bits & ((1 << 113) - 1)),
+        };
+        let coefficient = if coefficient < 10u128.pow(34) {
+            coefficient
+        } else {
+            0
+        };
+        let exponent = exponent as i32 - 6176;
+        let magnitude = if coefficient == 0 {
+            0
+        } else if exponent >= 0 {
+            10u128
+                .checked_pow(exponent as u32)
+                .and_then(|scale| coefficient.checked_mul(scale))?
+        } else if exponent < -38 {
+            // Less than a half, as the coefficient has at most 34 digits
+            0
+        } else {
+            let scale = 10u128.pow(exponent.unsigned_abs());
+            let (quotient, remainder) = (coefficient / scale, coefficient % scale);
+            if remainder * 2 > scale || (remainder * 2 == scale && quotient % 2 == 1) {
+                quotient + 1
+            } else {
+                quotient
+            }
+        };
+        if negative {
+            0i64.checked_sub_unsigned(u64::try_from(magnitude).ok()?)
+        } else {
+            i64::try_from(magnitude).ok()
+        }
+    }
+}
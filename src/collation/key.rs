@@ -0,0 +1,316 @@
+//! Sort keys. A string is decomposed into base characters and combining
+//! marks, mapped to collation elements carrying a weight per level, and the
+//! weights are laid out level after level so that comparing two keys
+//! compares the strings under the collation.
+
+use super::{Alternate, CaseFirst, Collation, MaxVariable};
+
+/// The letters a locale sorts differently from the root collation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tailoring {
+    Root,
+    /// `ñ` is a letter after `n`.
+    Spanish,
+    /// `å`, `ä` and `ö` are letters after `z`, with `æ` and `ø` as variants
+    /// of the last two.
+    Swedish,
+    /// `æ`, `ø` and `å` are letters after `z`, with `ä` and `ö` as accented
+    /// forms of the first two.
+    Danish,
+}
+
+/// The separator of the levels of a key, below any weight.
+const SEPARATOR: char = '\u{1}';
+
+// The primary weights of the classes of characters, in their order.
+const SPACE: u32 = 0x10;
+const PUNCT: u32 = 0x40;
+const SYMBOL: u32 = 0x100;
+const OTHER_SYMBOL: u32 = 0x200;
+const DIGIT: u32 = 0x120000;
+/// The weight of a number of the given length under `numericOrdering`,
+/// before its digits.
+const NUMBER: u32 = DIGIT + 0x10;
+const LATIN: u32 = 0x130000;
+const OTHER_LETTER: u32 = 0x140000;
+/// The quaternary weight of the characters a shifted collation doesn't
+/// ignore.
+const MAX: u32 = 0xffffff;
+
+const COMMON: u32 = 0x05;
+const LOWER: u32 = 0x05;
+const VARIANT: u32 = 0x06;
+const UPPER: u32 = 0x07;
+/// The tertiary weight of uppercase letters with `caseFirst: 'upper'`.
+const UPPER_FIRST: u32 = 0x03;
+
+const SPACES: &str = "\t\n\u{b}\u{c}\r\u{85}\u{2028}\u{2029} ";
+const PUNCTUATION: &str = "_-,;:!¡?¿.·'\"«»()[]{}§¶@*/\\&#%";
+const SYMBOLS: &str = "`´^¨+±÷×<=>¬|¦~¤¢$£¥©®°";
+
+/// The combining marks in the order of their secondary weights.
+const MARKS: &str = "\u{301}\u{300}\u{306}\u{302}\u{30c}\u{30a}\u{308}\u{30b}\u{303}\u{307}\u{338}\u{327}\u{328}\u{304}";
+
+/// The precomposed lowercase letters of Latin-1 and Latin Extended-A, by the
+/// mark they carry, with their base letters. Strokes and the middle dot of
+/// `ŀ` don't decompose in Unicode, but sort like marks.
+const COMPOSED: &[(char, &str, &str)] = &[
+    ('\u{301}', "áéíóúýćĺńŕśź", "aeiouyclnrsz"),
+    ('\u{300}', "àèìòù", "aeiou"),
+    ('\u{306}', "ăĕğĭŏŭ", "aegiou"),
+    ('\u{302}', "âêîôûĉĝĥĵŝŵŷ", "aeioucghjswy"),
+    ('\u{30c}', "čďěľňřšťž", "cdelnrstz"),
+    ('\u{30a}', "åů", "au"),
+    ('\u{308}', "äëïöüÿ", "aeiouy"),
+    ('\u{30b}', "őű", "ou"),
+    ('\u{303}', "ãñõĩũ", "anoiu"),
+    ('\u{307}', "ċėġżŀ", "cegzl"),
+    ('\u{338}', "øđħłŧð", "odhltd"),
+    ('\u{327}', "çģķļņŗşţ", "cgklnrst"),
+    ('\u{328}', "ąęįų", "aeiu"),
+    ('\u{304}', "āēīōū", "aeiou"),
+];
+
+/// The letters sorting as two, with the case of the original.
+const EXPANSIONS: &[(char, &str)] = &[('ß', "ss"), ('æ', "ae"), ('œ', "oe"), ('ĳ', "ij")];
+
+struct Element {
+    /// Zero for combining marks.
+    primary: u32,
+    secondary: u32,
+    tertiary: u32,
+    /// Whether a shifted collation ignores the character.
+    variable: bool,
+}
+
+fn lowercase(c: char) -> char {
+    let mut lower = c.to_lowercase();
+    match (lower.next(), lower.next()) {
+        (Some(lower), None) => lower,
+        _ => c,
+    }
+}
+
+fn is_mark(c: char) -> bool {
+    ('\u{300}'..='\u{36f}').contains(&c)
+}
+
+/// Splits the precomposed letters of a string into their base letter and
+/// mark.
+fn decompose(s: &str) -> Vec<char> {
+    let mut chars = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        let lower = lowercase(c);
+        let composed = COMPOSED.iter().find_map(|(mark, composed, bases)| {
+            let i = composed.chars().position(|composed| composed == lower)?;
+            bases.chars().nth(i).map(|base| (base, *mark))
+        });
+        match composed {
+            Some((base, mark)) if c != lower => chars.extend([base.to_ascii_uppercase(), mark]),
+            Some((base, mark)) => chars.extend([base, mark]),
+            None => chars.push(c),
+        }
+    }
+    chars
+}
+
+impl Tailoring {
+    /// The primary weight of a letter the locale tailors, given lowercase
+    /// and with the mark following it, whether the mark is part of the
+    /// letter, and whether the letter is a variant of another.
+    fn letter(self, c: char, mark: Option<char>) -> Option<(u32, bool, bool)> {
+        let after_z = |i: u32| LATIN + 4 * 26 + i;
+        let tailored = match (self, c, mark) {
+            (Self::Spanish, 'n', Some('\u{303}')) => (LATIN + 4 * 13 + 2, true, false),
+            (Self::Swedish, 'a', Some('\u{30a}')) => (after_z(1), true, false),
+            (Self::Swedish, 'a', Some('\u{308}')) => (after_z(2), true, false),
+            (Self::Swedish, 'æ', _) => (after_z(2), false, true),
+            (Self::Swedish, 'o', Some('\u{308}')) => (after_z(3), true, false),
+            (Self::Swedish, 'o', Some('\u{338}')) => (after_z(3), false, false),
+            (Self::Danish, 'æ', _) => (after_z(1), false, false),
+            (Self::Danish, 'a', Some('\u{308}')) => (after_z(1), false, false),
+            (Self::Danish, 'o', Some('\u{338}')) => (after_z(2), true, false),
+            (Self::Danish, 'o', Some('\u{308}')) => (after_z(2), false, false),
+            (Self::Danish, 'a', Some('\u{30a}')) => (after_z(3), true, false),
+            _ => return None,
+        };
+        Some(tailored)
+    }
+}
+
+impl Collation {
+    /// The sort key of a string: comparing keys compares the strings.
+    pub(super) fn sort_key(&self, s: &str) -> String {
+        let chars = decompose(s);
+        let shifted = self.alternate == Alternate::Shifted;
+        let mut primaries = Vec::new();
+        let mut secondaries = Vec::new();
+        let mut cases = Vec::new();
+        let mut tertiaries = Vec::new();
+        let mut quaternaries = Vec::new();
+        // Marks on ignored characters are ignored with them
+        let mut after_variable = false;
+        for element in self.elements(&chars) {
+            if shifted && element.variable {
+                quaternaries.push(element.primary);
+                after_variable = true;
+                continue;
+            }
+            if element.primary != 0 {
+                after_variable = false;
+                primaries.push(element.primary);
+                let upper = element.tertiary == UPPER;
+                cases.push(if upper == (self.case_first == CaseFirst::Upper) {
+                    1
+                } else {
+                    2
+                });
+            } else if shifted && after_variable {
+                continue;
+            }
+            secondaries.push(element.secondary);
+            tertiaries.push(match element.tertiary {
+                UPPER if self.case_first == CaseFirst::Upper => UPPER_FIRST,
+                tertiary => tertiary,
+            });
+            quaternaries.push(MAX);
+        }
+        if self.backwards {
+            secondaries.reverse();
+        }
+
+        let mut key = String::new();
+        push_weights(&mut key, &primaries);
+        if self.strength >= 2 {
+            key.push(SEPARATOR);
+            push_weights(&mut key, &secondaries);
+        }
+        if self.case_level {
+            key.push(SEPARATOR);
+            push_weights(&mut key, &cases);
+        }
+        if self.strength >= 3 {
+            key.push(SEPARATOR);
+            push_weights(&mut key, &tertiaries);
+        }
+        if self.strength >= 4 && shifted {
+            key.push(SEPARATOR);
+            push_weights(&mut key, &quaternaries);
+        }
+        if self.strength == 5 {
+            key.push(SEPARATOR);
+            let code_points: Vec<u32> = chars.iter().map(|c| *c as u32).collect();
+            push_weights(&mut key, &code_points);
+        }
+        key
+    }
+
+    fn elements(&self, chars: &[char]) -> Vec<Element> {
+        let mut elements = Vec::with_capacity(chars.len());
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let tertiary = if c.is_uppercase() { UPPER } else { LOWER };
+            let letter = |primary, tertiary| Element {
+                primary,
+                secondary: COMMON,
+                tertiary,
+                variable: false,
+            };
+            let lower = lowercase(c);
+            if let Some((primary, with_mark, variant)) =
+                self.tailoring.letter(lower, chars.get(i + 1).copied())
+            {
+                let tertiary = if variant && tertiary == LOWER {
+                    VARIANT
+                } else {
+                    tertiary
+                };
+                elements.push(letter(primary, tertiary));
+                i += if with_mark { 2 } else { 1 };
+                continue;
+            }
+            if self.numeric_ordering && c.is_ascii_digit() {
+                let end = chars[i..]
+                    .iter()
+                    .position(|c| !c.is_ascii_digit())
+                    .map_or(chars.len(), |length| i + length);
+                // Leading zeros don't count
+                let digits: Vec<u32> = chars[i..end]
+                    .iter()
+                    .map(|c| *c as u32 - '0' as u32)
+                    .skip_while(|digit| *digit == 0)
+                    .collect();
+                elements.push(letter(NUMBER + digits.len().min(0xffff) as u32, LOWER));
+                elements.extend(digits.iter().map(|digit| letter(DIGIT + digit, LOWER)));
+                i = end;
+                continue;
+            }
+            i += 1;
+
+            if is_mark(c) {
+                let secondary = match MARKS.chars().position(|mark| mark == c) {
+                    Some(position) => 0x10 + position as u32,
+                    None => 0x40 + (c as u32 - 0x300),
+                };
+                elements.push(Element {
+                    primary: 0,
+                    secondary,
+                    tertiary: COMMON,
+                    variable: false,
+                });
+            } else if let Some((_, expansion)) = EXPANSIONS.iter().find(|(c, _)| *c == lower) {
+                let tertiary = if tertiary == LOWER { VARIANT } else { tertiary };
+                elements.extend(
+                    expansion
+                        .chars()
+                        .map(|c| letter(LATIN + 4 * (c as u32 - 'a' as u32), tertiary)),
+                );
+            } else if c.is_ascii_alphabetic() {
+                elements.push(letter(LATIN + 4 * (lower as u32 - 'a' as u32), tertiary));
+            } else if c.is_ascii_digit() {
+                elements.push(letter(DIGIT + (c as u32 - '0' as u32), LOWER));
+            } else if c.is_alphanumeric() {
+                elements.push(letter(OTHER_LETTER + lower as u32, tertiary));
+            } else if c.is_whitespace() {
+                // Other spaces are variants of the space
+                let (position, tertiary) = match SPACES.chars().position(|space| space == c) {
+                    Some(position) => (position, LOWER),
+                    None => (SPACES.chars().count() - 1, VARIANT),
+                };
+                elements.push(Element {
+                    primary: SPACE + position as u32,
+                    secondary: COMMON,
+                    tertiary,
+                    variable: true,
+                });
+            } else if c.is_control() {
+                // Ignored altogether
+            } else if let Some(position) = PUNCTUATION.chars().position(|punct| punct == c) {
+                elements.push(Element {
+                    primary: PUNCT + position as u32,
+                    secondary: COMMON,
+                    tertiary: LOWER,
+                    variable: self.max_variable == MaxVariable::Punct,
+                });
+            } else {
+                let primary = match SYMBOLS.chars().position(|symbol| symbol == c) {
+                    Some(position) => SYMBOL + position as u32,
+                    None => OTHER_SYMBOL + c as u32,
+                };
+                elements.push(letter(primary, LOWER));
+            }
+        }
+        elements
+    }
+}
+
+/// Appends weights of up to 24 bits, as three characters each of a byte
+/// above the separator, which sort like the weights.
+fn push_weights(key: &mut String, weights: &[u32]) {
+    for weight in weights {
+        for shift in [16, 8, 0] {
+            key.extend(char::from_u32((weight >> shift & 0xff) + 2));
+        }
+    }
+}
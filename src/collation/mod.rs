@@ -0,0 +1,373 @@
+//! Collations: the language-aware string comparison of queries, sorts and
+//! indexes. Strings are compared by sort keys built like those of the Unicode
+//! Collation Algorithm: a level of base letters, then one of accents, then
+//! one of case, each only consulted when the ones before are equal, up to
+//! the strength of the collation. The `simple` collation compares strings by
+//! their bytes, and is represented by no collation at all.
+
+mod key;
+
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Array, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use key::Tailoring;
+
+/// The ICU version mongod reports its collations with.
+const VERSION: &str = "57.1";
+
+/// The locales with their tailoring, as mongod names them.
+const LOCALES: &[(&str, Tailoring)] = &[
+    ("root", Tailoring::Root),
+    ("en", Tailoring::Root),
+    ("en_US", Tailoring::Root),
+    ("en_GB", Tailoring::Root),
+    ("fr", Tailoring::Root),
+    ("fr_CA", Tailoring::Root),
+    ("de", Tailoring::Root),
+    ("de_AT", Tailoring::Root),
+    ("it", Tailoring::Root),
+    ("pt", Tailoring::Root),
+    ("nl", Tailoring::Root),
+    ("es", Tailoring::Spanish),
+    ("sv", Tailoring::Swedish),
+    ("fi", Tailoring::Swedish),
+    ("da", Tailoring::Danish),
+    ("nb", Tailoring::Danish),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseFirst {
+    Off,
+    Upper,
+    Lower,
+}
+
+/// Whether whitespace and punctuation count at the base levels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alternate {
+    NonIgnorable,
+    Shifted,
+}
+
+/// The characters ignored by a `shifted` collation: spaces, or spaces and
+/// punctuation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaxVariable {
+    Punct,
+    Space,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Collation {
+    locale: String,
+    tailoring: Tailoring,
+    case_level: bool,
+    case_first: CaseFirst,
+    /// The number of levels compared, from 1 (base letters) to 5
+    /// (identical).
+    strength: u8,
+    numeric_ordering: bool,
+    alternate: Alternate,
+    max_variable: MaxVariable,
+    normalization: bool,
+    /// Whether accents are compared from the end of the string, as in
+    /// Canadian French.
+    backwards: bool,
+}
+
+fn wrong_type(field: &str, value: &Value, expected: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::TypeMismatch,
+        format!(
+            "BSON field 'collation.{}' is the wrong type '{}', expected type '{}'",
+            field,
+            value.type_name(),
+            expected
+        ),
+    )
+}
+
+fn get_bool(field: &str, value: &Value) -> CommandResult<bool> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        value => Err(wrong_type(field, value, "bool")),
+    }
+}
+
+impl Collation {
+    /// Parses a collation specification such as `{ locale: 'fr', strength:
+    /// 2 }`. The `simple` locale gives no collation.
+    pub fn parse(spec: &Document) -> CommandResult<Option<Self>> {
+        let locale = match spec.get("locale") {
+            Some(Value::String(locale)) => locale.as_str(),
+            Some(value) => return Err(wrong_type("locale", value, "string")),
+            None => {
+                return Err(CommandError::new(
+                    ErrorCode::Location(40414),
+                    "BSON field 'collation.locale' is missing but a required field",
+                ));
+            }
+        };
+        if locale == "simple" {
+            if spec.len() > 1 {
+                return Err(CommandError::new(
+                    ErrorCode::BadValue,
+                    "If \"locale\" is set to \"simple\", then no other fields may be present",
+                ));
+            }
+            return Ok(None);
+        }
+        let Some((locale, tailoring)) = LOCALES.iter().find(|(name, _)| *name == locale) else {
+            return Err(CommandError::new(
+                ErrorCode::BadValue,
+                format!("Field 'locale' is invalid in: {}", spec),
+            ));
+        };
+        let mut collation = Self {
+            locale: locale.to_string(),
+            tailoring: *tailoring,
+            case_level: false,
+            case_first: if *tailoring == Tailoring::Danish {
+                CaseFirst::Upper
+            } else {
+                CaseFirst::Off
+            },
+            strength: 3,
+            numeric_ordering: false,
+            alternate: Alternate::NonIgnorable,
+            max_variable: MaxVariable::Punct,
+            normalization: false,
+            backwards: *locale == "fr_CA",
+        };
+        let bad_value = |field: &str, expected: &str, value: &Value| {
+            CommandError::new(
+                ErrorCode::BadValue,
+                format!("Field '{}' must be {}. Got: {}", field, expected, value),
+            )
+        };
+        for (field, value) in spec.iter() {
+            match field.as_str() {
+                "locale" => {}
+                "caseLevel" => collation.case_level = get_bool(field, value)?,
+                "numericOrdering" => collation.numeric_ordering = get_bool(field, value)?,
+                "normalization" => collation.normalization = get_bool(field, value)?,
+                "backwards" => collation.backwards = get_bool(field, value)?,
+                "strength" => {
+                    if !value.is_number() {
+                        return Err(wrong_type(field, value, "int"));
+                    }
+                    collation.strength = match value.as_i64() {
+                        Some(strength @ 1..=5) => strength as u8,
+                        _ => return Err(bad_value(field, "an integer 1 through 5", value)),
+                    };
+                }
+                "caseFirst" => {
+                    collation.case_first = match value.as_str() {
+                        Some("off") => CaseFirst::Off,
+                        Some("upper") => CaseFirst::Upper,
+                        Some("lower") => CaseFirst::Lower,
+                        Some(_) => {
+                            return Err(bad_value(field, "'upper', 'lower', or 'off'", value));
+                        }
+                        None => return Err(wrong_type(field, value, "string")),
+                    };
+                }
+                "alternate" => {
+                    collation.alternate = match value.as_str() {
+                        Some("non-ignorable") => Alternate::NonIgnorable,
+                        Some("shifted") => Alternate::Shifted,
+                        Some(_) => {
+                            return Err(bad_value(field, "'non-ignorable' or 'shifted'", value));
+                        }
+                        None => return Err(wrong_type(field, value, "string")),
+                    };
+                }
+                "maxVariable" => {
+                    collation.max_variable = match value.as_str() {
+                        Some("punct") => MaxVariable::Punct,
+                        Some("space") => MaxVariable::Space,
+                        Some(_) => return Err(bad_value(field, "'punct' or 'space'", value)),
+                        None => return Err(wrong_type(field, value, "string")),
+                    };
+                }
+                // Reported by mongod, and accepted back
+                "version" if matches!(value, Value::String(_)) => {}
+                "version" => return Err(wrong_type(field, value, "string")),
+                field => {
+                    return Err(CommandError::new(
+                        ErrorCode::Location(40415),
+                        format!("BSON field 'collation.{}' is an unknown field.", field),
+                    ));
+                }
+            }
+        }
+        Ok(Some(collation))
+    }
+
+    /// The full specification, with every option, as mongod reports it.
+    pub fn spec(&self) -> Document {
+        let case_first = match self.case_first {
+            CaseFirst::Off => "off",
+            CaseFirst::Upper => "upper",
+            CaseFirst::Lower => "lower",
+        };
+        let alternate = match self.alternate {
+            Alternate::NonIgnorable => "non-ignorable",
+            Alternate::Shifted => "shifted",
+        };
+        let max_variable = match self.max_variable {
+            MaxVariable::Punct => "punct",
+            MaxVariable::Space => "space",
+        };
+        let mut spec = Document::new();
+        spec.insert("locale", Value::from(self.locale.as_str()));
+        spec.insert("caseLevel", Value::Boolean(self.case_level));
+        spec.insert("caseFirst", Value::from(case_first));
+        spec.insert("strength", Value::Int32(self.strength as i32));
+        spec.insert("numericOrdering", Value::Boolean(self.numeric_ordering));
+        spec.insert("alternate", Value::from(alternate));
+        spec.insert("maxVariable", Value::from(max_variable));
+        spec.insert("normalization", Value::Boolean(self.normalization));
+        spec.insert("backwards", Value::Boolean(self.backwards));
+        spec.insert("version", Value::from(VERSION));
+        spec
+    }
+
+    /// Compares two strings.
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        self.sort_key(a).cmp(&self.sort_key(b))
+    }
+
+    /// Replaces the strings of a value, down to those nested in documents
+    /// and arrays, with their sort keys: the translated values compare by
+    /// their bytes like the originals do under the collation. Indexes store
+    /// keys translated this way.
+    pub fn translate(&self, value: &Value) -> Value {
+        match value {
+            Value::String(s) => Value::String(self.sort_key(s)),
+            Value::Symbol(s) => Value::Symbol(self.sort_key(s)),
+            Value::Document(doc) => Value::Document(Document(
+                doc.iter()
+                    .map(|(field, value)| (field.clone(), self.translate(value)))
+                    .collect(),
+            )),
+            Value::Array(items) => Value::Array(Array(
+                items.0.iter().map(|item| self.translate(item)).collect(),
+            )),
+            value => value.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn collation(spec: &str) -> Collation {
+        Collation::parse(&doc(spec)).unwrap().unwrap()
+    }
+
+    fn sorted(spec: &str, words: &[&str]) -> Vec<String> {
+        let collation = collation(spec);
+        let mut words: Vec<String> = words.iter().map(|word| word.to_string()).collect();
+        words.sort_by(|a, b| collation.compare(a, b));
+        words
+    }
+
+    #[test]
+    fn test_collation() {
+        let en = "{ locale: 'en' }";
+        assert_eq!(
+            sorted(en, &["b", "B", "a", "Å", "á", "A", "ab", "10", "9", "-a"]),
+            ["-a", "10", "9", "a", "A", "á", "Å", "ab", "b", "B"]
+        );
+        assert_eq!(
+            sorted("{ locale: 'en', caseFirst: 'upper' }", &["a", "A", "b"]),
+            ["A", "a", "b"]
+        );
+        assert_eq!(
+            sorted(
+                "{ locale: 'en', numericOrdering: true }",
+                &["10", "9", "a1", "a02"]
+            ),
+            ["9", "10", "a1", "a02"]
+        );
+        // Accents are compared from the end in Canadian French
+        let words = ["côté", "coté", "côte", "cote"];
+        assert_eq!(
+            sorted("{ locale: 'fr' }", &words),
+            ["cote", "coté", "côte", "côté"]
+        );
+        assert_eq!(
+            sorted("{ locale: 'fr_CA' }", &words),
+            ["cote", "côte", "coté", "côté"]
+        );
+        // Letters some languages add after z
+        assert_eq!(
+            sorted("{ locale: 'es' }", &["o", "ñ", "n"]),
+            ["n", "ñ", "o"]
+        );
+        assert_eq!(
+            sorted("{ locale: 'sv' }", &["z", "ö", "å", "ä"]),
+            ["z", "å", "ä", "ö"]
+        );
+        assert_eq!(sorted(en, &["z", "ö", "å", "ä"]), ["å", "ä", "ö", "z"]);
+
+        let equal = |spec: &str, a: &str, b: &str| collation(spec).compare(a, b).is_eq();
+        assert!(equal("{ locale: 'en', strength: 1 }", "Résumé", "resume"));
+        assert!(!equal("{ locale: 'en', strength: 2 }", "Résumé", "resume"));
+        assert!(equal("{ locale: 'en', strength: 2 }", "Résumé", "résumé"));
+        assert!(!equal(
+            "{ locale: 'en', strength: 1, caseLevel: true }",
+            "A",
+            "a"
+        ));
+        assert!(equal(
+            "{ locale: 'en', strength: 1, caseLevel: true }",
+            "á",
+            "a"
+        ));
+        assert!(equal("{ locale: 'de', strength: 2 }", "Straße", "strasse"));
+        assert!(equal(
+            "{ locale: 'en', alternate: 'shifted' }",
+            "black-bird",
+            "blackbird"
+        ));
+        assert!(!equal(
+            "{ locale: 'en', alternate: 'shifted', strength: 4 }",
+            "black-bird",
+            "blackbird"
+        ));
+        assert!(equal("{ locale: 'en', strength: 5 }", "e\u{301}", "é"));
+        assert!(equal("{ locale: 'en' }", "a\u{7}b", "ab"));
+        assert!(!equal("{ locale: 'en', strength: 5 }", "a\u{7}b", "ab"));
+
+        assert_eq!(
+            collation("{ locale: 'fr_CA', strength: 2 }").spec(),
+            doc(
+                "{ locale: 'fr_CA', caseLevel: false, caseFirst: 'off', strength: 2, \
+                 numericOrdering: false, alternate: 'non-ignorable', maxVariable: 'punct', \
+                 normalization: false, backwards: true, version: '57.1' }"
+            )
+        );
+        assert_eq!(
+            Collation::parse(&doc("{ locale: 'simple' }")).unwrap(),
+            None
+        );
+        for spec in [
+            "{}",
+            "{ locale: 'xx' }",
+            "{ locale: 'en', strength: 6 }",
+            "{ locale: 'en', caseFirst: 'title' }",
+            "{ locale: 'en', foo: 1 }",
+            "{ locale: 'simple', strength: 1 }",
+        ] {
+            assert!(Collation::parse(&doc(spec)).is_err(), "{}", spec);
+        }
+    }
+}
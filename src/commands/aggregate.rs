@@ -0,0 +1,322 @@
+use crate::{
+    aggregation::{Context, Metadata, Pipeline},
+    bson::{Document, Value},
+    cursor::{CursorManager, CursorOptions},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{planner, Matcher, Query},
+    storage::Storage,
+};
+
+use super::{
+    cursor_reply,
+    explain::{self, Explain, Verbosity},
+    get_array, get_bool, get_collation, get_count, get_hint, namespace, session_id, wrong_type,
+};
+
+pub fn run(
+    storage: &mut Storage,
+    cursors: &mut CursorManager,
+    db: &str,
+    command: &Document,
+) -> CommandResult<Document> {
+    let stages = get_array(command, "aggregate", "pipeline")?;
+    if let Some((_, value)) = command.first().filter(|(_, value)| value.is_number()) {
+        // Collection-less aggregations are written `{ aggregate: 1 }`
+        let first_stage = match stages.first() {
+            Some(Value::Document(stage)) => stage.keys().next().cloned().unwrap_or_default(),
+            _ => String::new(),
+        };
+        return Err(CommandError::new(
+            ErrorCode::InvalidNamespace,
+            format!(
+                "{{aggregate: {}}} is not valid for '{}'; a collection is required.",
+                value, first_stage
+            ),
+        ));
+    }
+    if get_bool(command, "aggregate", "explain", false)? {
+        return explain::explain_command(storage, db, command, Verbosity::QueryPlanner);
+    }
+    let namespace = namespace(db, command)?;
+    let pipeline = Pipeline::parse(stages)?;
+
+    let batch_size = match command.get("cursor") {
+        Some(Value::Document(cursor)) => get_count(cursor, "aggregate.cursor", "batchSize")?,
+        Some(value) => return Err(wrong_type("aggregate", "cursor", value, "object")),
+        None => {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                "The 'cursor' option is required, except for aggregate with the explain argument",
+            ));
+        }
+    };
+
+    let collection = storage.collection(&namespace);
+    let collation = get_collation(command, "aggregate", collection)?;
+    let matcher = match pipeline.geo_near() {
+        Some(geo_near) => geo_near.matcher(collection, &namespace)?,
+        None => match pipeline.leading_match() {
+            Some(matcher) => matcher.clone(),
+            None => Matcher::new(&Document::new())?,
+        },
+    }
+    .with_collation(collation.as_ref());
+    let query = Query {
+        hint: get_hint(command, "aggregate")?,
+        ..Query::new(&matcher)
+    };
+    let execution = planner::plan(collection, &query)?.execution;
+    let allow_disk_use = get_bool(command, "aggregate", "allowDiskUse", false)?;
+    let temp_dir = storage.temp_dir();
+    let context = Context {
+        spill_dir: allow_disk_use.then_some(temp_dir.as_path()),
+        collation: collation.as_ref(),
+        ..Context::new(storage, db)
+    };
+    // Records are copied as the first stage reads them, so that only the
+    // blocking stages and the results hold documents
+    let results = match pipeline.geo_near() {
+        Some(geo_near) => {
+            let documents = geo_near.results(execution);
+            pipeline.buffer(documents.map(|doc| (doc, Metadata::default())), &context)?
+        }
+        None => {
+            // For `$meta` to read in the stages
+            let scores = execution.text_scores;
+            let documents = execution.documents.into_iter().map(move |(id, doc)| {
+                let metadata = Metadata {
+                    text_score: scores.get(&id).copied(),
+                };
+                (doc.into_owned(), metadata)
+            });
+            pipeline.buffer(documents, &context)?
+        }
+    };
+    if let Some(output) = pipeline.output() {
+        // Written results aren't returned
+        output.write(results, storage, db)?;
+        return Ok(cursor_reply(&namespace, 0, "firstBatch", Vec::new()));
+    }
+
+    let options = CursorOptions {
+        batch_size,
+        session: session_id(command),
+        ..Default::default()
+    };
+    let (batch, id) = cursors.open(&namespace, results, options)?;
+    Ok(cursor_reply(&namespace, id, "firstBatch", batch))
+}
+
+/// Explains the query of the pipeline, as a `$cursor` stage ahead of the
+/// others unless the pipeline is only a `$match`, or a `$geoNearCursor` one
+/// for a leading `$geoNear`. The stages aren't run.
+pub fn explain(
+    storage: &Storage,
+    db: &str,
+    command: &Document,
+    explain: &Explain,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let stages = get_array(command, "aggregate", "pipeline")?;
+    let pipeline = Pipeline::parse(stages)?;
+    let collection = storage.collection(&namespace);
+    let collation = get_collation(command, "aggregate", collection)?;
+    let matcher = match pipeline.geo_near() {
+        Some(geo_near) => geo_near.matcher(collection, &namespace)?,
+        None => match pipeline.leading_match() {
+            Some(matcher) => matcher.clone(),
+            None => Matcher::new(&Document::new())?,
+        },
+    }
+    .with_collation(collation.as_ref());
+    let query = Query {
+        hint: get_hint(command, "aggregate")?,
+        ..Query::new(&matcher)
+    };
+    let planned = planner::plan(collection, &query)?;
+    let returned = planned.execution.documents.len();
+    let cursor = explain.plans(
+        &namespace,
+        collection,
+        &matcher,
+        &planned,
+        returned,
+        |_, input| input,
+    );
+
+    let leading = pipeline.geo_near().is_some() || pipeline.leading_match().is_some();
+    let rest = &stages[usize::from(leading)..];
+    if rest.is_empty() && pipeline.geo_near().is_none() {
+        return Ok(cursor);
+    }
+    let mut cursor_stage = Document::new();
+    let name = match pipeline.geo_near() {
+        Some(_) => "$geoNearCursor",
+        None => "$cursor",
+    };
+    cursor_stage.insert(name, Value::Document(cursor));
+    let mut output = Document::new();
+    output.insert(
+        "stages",
+        Value::from(
+            std::iter::once(Value::Document(cursor_stage))
+                .chain(rest.iter().cloned())
+                .collect::<Vec<_>>(),
+        ),
+    );
+    Ok(output)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::json::{doc, value},
+        storage::Index,
+    };
+
+    #[test]
+    fn test_geo_near() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.places");
+        let index = Index::parse(&doc("{ key: { loc: '2dsphere', kind: 1 } }")).unwrap();
+        let index = collection.build_index(index).unwrap();
+        collection.add_index(index);
+        for document in [
+            "{ _id: 1, loc: [0, 0], kind: 'cafe' }",
+            "{ _id: 2, loc: { type: 'Point', coordinates: [0, 2] }, kind: 'park' }",
+            "{ _id: 3, loc: [[5, 5], [0, 1]], kind: 'cafe' }",
+        ] {
+            collection.insert(doc(document)).unwrap();
+        }
+        let mut cursors = CursorManager::default();
+        let mut aggregate = |pipeline: &str| -> CommandResult<Value> {
+            let command = format!(
+                "{{ aggregate: 'places', pipeline: {}, cursor: {{}} }}",
+                pipeline
+            );
+            let reply = run(&mut storage, &mut cursors, "test", &doc(&command))?;
+            match reply.get("cursor") {
+                Some(Value::Document(cursor)) => Ok(cursor.get("firstBatch").unwrap().clone()),
+                _ => panic!("no cursor in {}", reply),
+            }
+        };
+
+        // Legacy points are measured in radians, here scaled to degrees
+        assert_eq!(
+            aggregate(
+                "[{ $geoNear: { near: [0, 0.1], distanceField: 'dist.deg', spherical: true, \
+                 distanceMultiplier: 57.29577951308232, includeLocs: 'at', query: { kind: 'cafe' } } }, \
+                 { $project: { dist: { $round: ['$dist.deg', 6] }, at: 1 } }]"
+            )
+            .unwrap(),
+            value("[{ _id: 1, at: [0, 0], dist: 0.1 }, { _id: 3, at: [0, 1], dist: 0.9 }]")
+        );
+        assert_eq!(
+            aggregate(
+                "[{ $geoNear: { near: { type: 'Point', coordinates: [0, 0] }, distanceField: 'd', \
+                 minDistance: 1, maxDistance: 200000 } }, { $project: { _id: 1 } }]"
+            )
+            .unwrap(),
+            value("[{ _id: 3 }]")
+        );
+
+        let code = |result: CommandResult<Value>| result.unwrap_err().code;
+        assert_eq!(
+            code(aggregate("[{ $geoNear: { near: [0, 0] } }]")),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(
+            code(aggregate(
+                "[{ $facet: { a: [{ $geoNear: { near: [0, 0], distanceField: 'd' } }] } }]"
+            )),
+            ErrorCode::Location(40600)
+        );
+        storage
+            .collection_mut("test.plain")
+            .insert(doc("{ _id: 1 }"))
+            .unwrap();
+        let command = doc(
+            "{ aggregate: 'plain', pipeline: [{ $geoNear: { near: [0, 0], distanceField: 'd' } }], cursor: {} }",
+        );
+        assert_eq!(
+            run(&mut storage, &mut cursors, "test", &command)
+                .unwrap_err()
+                .code,
+            ErrorCode::IndexNotFound
+        );
+    }
+
+    #[test]
+    fn test_text_score() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        let index = Index::parse(&doc("{ key: { title: 'text' } }")).unwrap();
+        let index = collection.build_index(index).unwrap();
+        collection.add_index(index);
+        for document in [
+            "{ _id: 1, title: 'coffee and cake and more' }",
+            "{ _id: 2, title: 'coffee coffee' }",
+            "{ _id: 3, title: 'tea' }",
+            "{ _id: 4, '$textScore': 5 }",
+        ] {
+            collection.insert(doc(document)).unwrap();
+        }
+        let mut cursors = CursorManager::default();
+        let mut aggregate = |pipeline: &str| -> CommandResult<Value> {
+            let command = format!("{{ aggregate: 'c', pipeline: {}, cursor: {{}} }}", pipeline);
+            let reply = run(&mut storage, &mut cursors, "test", &doc(&command))?;
+            match reply.get("cursor") {
+                Some(Value::Document(cursor)) => Ok(cursor.get("firstBatch").unwrap().clone()),
+                _ => panic!("no cursor in {}", reply),
+            }
+        };
+
+        // The score outlives the projection, and isn't returned itself
+        assert_eq!(
+            aggregate(
+                "[{ $match: { $text: { $search: 'coffee' } } }, { $project: { title: 1 } }, \
+                 { $sort: { score: { $meta: 'textScore' } } }, \
+                 { $addFields: { score: { $meta: 'textScore' } } }, { $project: { title: 0 } }]"
+            )
+            .unwrap(),
+            value("[{ _id: 2, score: 1.5 }, { _id: 1, score: 0.75 }]")
+        );
+
+        // A stored field of the same name is neither a score nor hidden
+        assert_eq!(
+            aggregate("[{ $match: { _id: 4 } }]").unwrap(),
+            value("[{ _id: 4, '$textScore': 5 }]")
+        );
+
+        let code = |result: CommandResult<Value>| result.unwrap_err().code;
+        assert_eq!(
+            code(aggregate(
+                "[{ $match: { _id: 4 } }, { $project: { score: { $meta: 'textScore' } } }]"
+            )),
+            ErrorCode::Location(40218)
+        );
+        assert_eq!(
+            code(aggregate(
+                "[{ $project: { score: { $meta: 'textScore' } } }]"
+            )),
+            ErrorCode::Location(40218)
+        );
+        assert_eq!(
+            code(aggregate("[{ $sort: { score: { $meta: 'textScore' } } }]")),
+            ErrorCode::Location(40218)
+        );
+        assert_eq!(
+            code(aggregate(
+                "[{ $sort: { score: { $meta: 'searchScore' } } }]"
+            )),
+            ErrorCode::Location(31138)
+        );
+        assert_eq!(
+            code(aggregate(
+                "[{ $project: { score: { $meta: 'searchScore' } } }]"
+            )),
+            ErrorCode::Location(17308)
+        );
+    }
+}
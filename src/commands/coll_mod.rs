@@ -0,0 +1,165 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    storage::{parse_expire_after_seconds, Storage},
+};
+
+use super::{namespace, wrong_type};
+
+fn invalid_options(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::InvalidOptions, message)
+}
+
+/// Modifies collection options. Only the `expireAfterSeconds` of TTL indexes
+/// can be changed, through the `index` option.
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    if let Some(option) = command
+        .keys()
+        .skip(1)
+        .find(|key| !matches!(key.as_str(), "index" | "lsid" | "$db"))
+    {
+        return Err(invalid_options(format!(
+            "unknown option to collMod: {}",
+            option
+        )));
+    }
+    let Some(collection) = storage.collection(&namespace) else {
+        return Err(CommandError::new(
+            ErrorCode::NamespaceNotFound,
+            format!("ns does not exist: {}", namespace),
+        ));
+    };
+    collection.check_no_index_builds()?;
+    let mut reply = Document::new();
+    let spec = match command.get("index") {
+        Some(Value::Document(spec)) => spec,
+        Some(value) => return Err(wrong_type("collMod", "index", value, "object")),
+        None => return Ok(reply),
+    };
+
+    let index = match (spec.get("name"), spec.get("keyPattern")) {
+        (Some(Value::String(name)), None) => collection.index(name).ok_or_else(|| {
+            CommandError::new(
+                ErrorCode::IndexNotFound,
+                format!("cannot find index {} for ns {}", name, namespace),
+            )
+        })?,
+        (None, Some(Value::Document(key_pattern))) => collection
+            .indexes()
+            .iter()
+            .find(|index| index.key_pattern().compare(key_pattern).is_eq())
+            .ok_or_else(|| {
+                CommandError::new(
+                    ErrorCode::IndexNotFound,
+                    format!("cannot find index {} for ns {}", key_pattern, namespace),
+                )
+            })?,
+        (Some(_), Some(_)) => {
+            return Err(invalid_options(
+                "Both keyPattern and name are specified in the index option",
+            ));
+        }
+        (Some(value), None) => return Err(wrong_type("collMod", "index.name", value, "string")),
+        (None, Some(value)) => {
+            return Err(wrong_type("collMod", "index.keyPattern", value, "object"));
+        }
+        (None, None) => {
+            return Err(invalid_options(
+                "must specify either index name or key pattern",
+            ));
+        }
+    };
+    let Some(value) = spec.get("expireAfterSeconds") else {
+        return Err(invalid_options("no expireAfterSeconds field to update"));
+    };
+    let Some(old) = index.expire_after_seconds() else {
+        return Err(invalid_options("no expireAfterSeconds field to update"));
+    };
+    let new = parse_expire_after_seconds(value)?;
+
+    let name = index.name().to_string();
+    if let Some(index) = storage.collection_mut(&namespace).index_mut(&name) {
+        index.set_expire_after_seconds(new);
+    }
+    reply.insert("expireAfterSeconds_old", Value::Int32(old as i32));
+    reply.insert("expireAfterSeconds_new", Value::Int32(new as i32));
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bson::json::doc, storage::Index};
+
+    #[test]
+    fn test_coll_mod_expire_after_seconds() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        for spec in [
+            "{ key: { at: 1 }, expireAfterSeconds: 60 }",
+            "{ key: { other: 1 } }",
+        ] {
+            let index = collection.build_index(Index::parse(&doc(spec)).unwrap());
+            collection.add_index(index.unwrap());
+        }
+        collection
+            .insert(doc("{ _id: 1, at: [Date(50000), Date(200000)] }"))
+            .unwrap();
+        collection
+            .insert(doc("{ _id: 2, at: Date(100000) }"))
+            .unwrap();
+        collection.insert(doc("{ _id: 3, at: 'never' }")).unwrap();
+        collection.insert(doc("{ _id: 4 }")).unwrap();
+
+        // Expiry goes by the earliest date of an array
+        assert_eq!(storage.remove_expired(110000), 1);
+        let reply = run(
+            &mut storage,
+            "test",
+            &doc("{ collMod: 'c', index: { keyPattern: { at: 1 }, expireAfterSeconds: 10 } }"),
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            doc("{ expireAfterSeconds_old: 60, expireAfterSeconds_new: 10 }")
+        );
+        assert_eq!(storage.remove_expired(110000), 1);
+        let collection = storage.collection("test.c").unwrap();
+        assert_eq!(
+            collection
+                .iter()
+                .map(|(_, doc)| doc.clone())
+                .collect::<Vec<_>>(),
+            vec![doc("{ _id: 3, at: 'never' }"), doc("{ _id: 4 }")]
+        );
+
+        let code =
+            |storage: &mut Storage, command| run(storage, "test", &doc(command)).unwrap_err().code;
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ collMod: 'c', index: { name: 'other_1', expireAfterSeconds: 10 } }"
+            ),
+            ErrorCode::InvalidOptions
+        );
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ collMod: 'c', index: { name: 'at_1', expireAfterSeconds: -1 } }"
+            ),
+            ErrorCode::CannotCreateIndex
+        );
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ collMod: 'c', index: { name: 'x', expireAfterSeconds: 10 } }"
+            ),
+            ErrorCode::IndexNotFound
+        );
+        assert_eq!(
+            code(&mut storage, "{ collMod: 'd', index: { name: 'x' } }"),
+            ErrorCode::NamespaceNotFound
+        );
+    }
+}
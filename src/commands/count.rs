@@ -0,0 +1,95 @@
+use crate::{
+    bson::{Document, Value},
+    error::CommandResult,
+    query::{
+        planner::{self, Planned},
+        Matcher, Query,
+    },
+    storage::{Collection, Storage},
+};
+
+use super::{
+    explain::{stage, Explain},
+    get_collation, get_count, get_hint, namespace, wrong_type,
+};
+
+/// The options of a count command.
+struct Count<'c> {
+    matcher: Matcher,
+    hint: Option<&'c Value>,
+    skip: usize,
+    limit: Option<usize>,
+}
+
+impl<'c> Count<'c> {
+    fn parse(command: &'c Document, collection: Option<&Collection>) -> CommandResult<Self> {
+        let matcher = match command.get("query") {
+            None | Some(Value::Null) => Matcher::new(&Document::new())?,
+            Some(Value::Document(query)) => Matcher::for_query(query)?.forbid_near()?,
+            Some(value) => return Err(wrong_type("count", "query", value, "object")),
+        };
+        let collation = get_collation(command, "count", collection)?;
+        Ok(Self {
+            matcher: matcher.with_collation(collation.as_ref()),
+            hint: get_hint(command, "count")?,
+            skip: get_count(command, "count", "skip")?.unwrap_or(0),
+            limit: get_count(command, "count", "limit")?.filter(|limit| *limit > 0),
+        })
+    }
+
+    fn query(&self) -> Query<'_> {
+        Query {
+            hint: self.hint,
+            ..Query::new(&self.matcher)
+        }
+    }
+
+    fn n(&self, planned: &Planned) -> usize {
+        planned
+            .execution
+            .documents
+            .len()
+            .saturating_sub(self.skip)
+            .min(self.limit.unwrap_or(usize::MAX))
+    }
+}
+
+pub fn run(storage: &Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let count = Count::parse(command, collection)?;
+    let planned = planner::plan(collection, &count.query())?;
+    let mut reply = Document::new();
+    reply.insert("n", Value::Int32(count.n(&planned) as i32));
+    Ok(reply)
+}
+
+/// Like mongod, `nReturned` is 0: counting returns no documents.
+pub fn explain(
+    storage: &Storage,
+    db: &str,
+    command: &Document,
+    explain: &Explain,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let count = Count::parse(command, collection)?;
+    let planned = planner::plan(collection, &count.query())?;
+    Ok(explain.plans(
+        &namespace,
+        collection,
+        &count.matcher,
+        &planned,
+        0,
+        |_, input| {
+            let mut fields = Vec::new();
+            if count.skip > 0 {
+                fields.push(("skipAmount", Value::Int64(count.skip as i64)));
+            }
+            if let Some(limit) = count.limit {
+                fields.push(("limitAmount", Value::Int64(limit as i64)));
+            }
+            stage("COUNT", fields, input)
+        },
+    ))
+}
@@ -0,0 +1,106 @@
+use crate::{
+    bson::Document,
+    error::{CommandError, CommandResult, ErrorCode},
+    storage::{Collection, Storage},
+};
+
+use super::{get_collation, namespace};
+
+/// Creates a collection explicitly, which unlike the implicit creation on
+/// the first write can give it a default `collation`. Its `_id` index gets
+/// that collation too.
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    if let Some(option) = command
+        .keys()
+        .skip(1)
+        .find(|key| !matches!(key.as_str(), "collation" | "lsid" | "$db"))
+    {
+        return Err(CommandError::new(
+            ErrorCode::Location(40415),
+            format!("BSON field 'create.{}' is an unknown field.", option),
+        ));
+    }
+    if storage.collection(&namespace).is_some() {
+        return Err(CommandError::new(
+            ErrorCode::NamespaceExists,
+            format!("Collection {} already exists.", namespace),
+        ));
+    }
+    let collation = get_collation(command, "create", None)?;
+    storage.replace_collection(Collection::with_collation(&namespace, collation));
+    Ok(Document::new())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::{json::doc, Value},
+        commands::find,
+        cursor::CursorManager,
+    };
+
+    #[test]
+    fn test_create_with_default_collation() {
+        let mut storage = Storage::new();
+        run(
+            &mut storage,
+            "test",
+            &doc("{ create: 'c', collation: { locale: 'en', strength: 2 } }"),
+        )
+        .unwrap();
+        let collection = storage.collection_mut("test.c");
+        for (id, name) in [(1, "Bob"), (2, "alice"), (3, "bob"), (4, "Álvaro")] {
+            collection
+                .insert(doc(&format!("{{ _id: {}, name: '{}' }}", id, name)))
+                .unwrap();
+        }
+        assert_eq!(
+            collection.indexes()[0].spec(),
+            doc(
+                "{ v: 2, key: { _id: 1 }, name: '_id_', collation: { locale: 'en', \
+                 caseLevel: false, caseFirst: 'off', strength: 2, numericOrdering: false, \
+                 alternate: 'non-ignorable', maxVariable: 'punct', normalization: false, \
+                 backwards: false, version: '57.1' } }"
+            )
+        );
+
+        // Queries and sorts default to the collation of the collection
+        let mut cursors = CursorManager::default();
+        let mut ids = |command: &str| {
+            let reply = find::run(&storage, &mut cursors, "test", &doc(command)).unwrap();
+            let Some(Value::Document(cursor)) = reply.get("cursor") else {
+                panic!("no cursor in {}", reply);
+            };
+            cursor.get("firstBatch").cloned().unwrap()
+        };
+        assert_eq!(
+            ids("{ find: 'c', filter: { name: 'BOB' }, projection: { _id: 1 } }"),
+            crate::bson::json::value("[{ _id: 1 }, { _id: 3 }]")
+        );
+        assert_eq!(
+            ids("{ find: 'c', sort: { name: 1 }, projection: { _id: 1 } }"),
+            crate::bson::json::value("[{ _id: 2 }, { _id: 4 }, { _id: 1 }, { _id: 3 }]")
+        );
+        assert_eq!(
+            ids("{ find: 'c', filter: { name: 'BOB' }, collation: { locale: 'simple' } }"),
+            crate::bson::json::value("[]")
+        );
+
+        let code =
+            |storage: &mut Storage, command| run(storage, "test", &doc(command)).unwrap_err().code;
+        assert_eq!(
+            code(&mut storage, "{ create: 'c' }"),
+            ErrorCode::NamespaceExists
+        );
+        assert_eq!(
+            code(&mut storage, "{ create: 'd', capped: true }"),
+            ErrorCode::Location(40415)
+        );
+        assert_eq!(
+            code(&mut storage, "{ create: 'd', collation: { locale: 'xx' } }"),
+            ErrorCode::BadValue
+        );
+    }
+}
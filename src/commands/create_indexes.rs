@@ -0,0 +1,427 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    storage::{Collection, Index, IndexBuild, Storage},
+};
+
+use super::{get_array, namespace, wrong_type, Reply};
+
+/// Creates indexes. On a collection holding documents they are built in the
+/// background, and the command replies once the build ends.
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Reply> {
+    let namespace = namespace(db, command)?;
+    let specs = get_array(command, "createIndexes", "indexes")?;
+    if specs.is_empty() {
+        return Err(CommandError::new(
+            ErrorCode::BadValue,
+            "Must specify at least one index to create",
+        ));
+    }
+    // There is no replica set to wait for
+    if let Some(commit_quorum) = command.get("commitQuorum") {
+        check_commit_quorum(commit_quorum)?;
+    }
+    // The specs are checked before the collection is created, so that a
+    // failed command doesn't create it
+    let existing = storage.collection(&namespace);
+    let created_automatically = existing.is_none();
+    let implicit;
+    let collection = match existing {
+        Some(collection) => collection,
+        None => {
+            implicit = Collection::new(&namespace);
+            &implicit
+        }
+    };
+
+    let mut requested = Vec::new();
+    for spec in specs {
+        let Value::Document(spec) = spec else {
+            return Err(wrong_type("createIndexes", "indexes", spec, "object"));
+        };
+        // Indexes without a collation get the default of the collection
+        let mut spec = spec.clone();
+        if let (None, Some(collation)) = (spec.get("collation"), collection.collation()) {
+            spec.insert("collation", Value::Document(collation.spec()));
+        }
+        requested.push(Index::parse(&spec)?);
+    }
+    let before = collection.indexes().len();
+
+    let mut new: Vec<Index> = Vec::new();
+    for index in requested {
+        if let Some(other) = collection
+            .index_builds()
+            .iter()
+            .flat_map(|build| build.indexes())
+            .find(|other| conflicts(other, &index))
+        {
+            return Err(CommandError::new(
+                ErrorCode::BackgroundOperationInProgressForNamespace,
+                format!(
+                    "An index build is already in progress for index {}",
+                    other.name()
+                ),
+            ));
+        }
+        let conflict = collection
+            .indexes()
+            .iter()
+            .chain(&new)
+            .find(|other| conflicts(other, &index));
+        match conflict {
+            Some(other) if other.spec() == index.spec() => continue,
+            Some(other) if other.name() == index.name() && other.same_key(&index) => {
+                return Err(CommandError::new(
+                    ErrorCode::IndexOptionsConflict,
+                    format!(
+                        "An equivalent index already exists with the same name but different options. Requested index: {}, existing index: {}",
+                        index.spec(),
+                        other.spec()
+                    ),
+                ));
+            }
+            Some(other) if other.name() == index.name() => {
+                return Err(CommandError::new(
+                    ErrorCode::IndexKeySpecsConflict,
+                    format!(
+                        "An existing index has the same name as the requested index. When index names are not specified, they are auto generated and can cause conflicts. Please refer to our documentation. Requested index: {}, existing index: {}",
+                        index.spec(),
+                        other.spec()
+                    ),
+                ));
+            }
+            Some(other) => {
+                return Err(CommandError::new(
+                    ErrorCode::IndexOptionsConflict,
+                    format!(
+                        "Index already exists with a different name: {}",
+                        other.name()
+                    ),
+                ));
+            }
+            None => new.push(index),
+        }
+    }
+
+    let mut reply = Document::new();
+    reply.insert(
+        "createdCollectionAutomatically",
+        Value::Boolean(created_automatically),
+    );
+    reply.insert("numIndexesBefore", Value::Int32(before as i32));
+    reply.insert("numIndexesAfter", Value::Int32((before + new.len()) as i32));
+    if new.is_empty() {
+        reply.insert("note", Value::String("all indexes already exist".into()));
+        return Ok(Reply::Ready(reply));
+    }
+    // Like mongod, indexes on an empty collection are created right away
+    if collection.is_empty() {
+        let built = new
+            .into_iter()
+            .map(|index| collection.build_index(index))
+            .collect::<CommandResult<Vec<_>>>()?;
+        let collection = storage.collection_mut(&namespace);
+        for index in built {
+            collection.add_index(index);
+        }
+        return Ok(Reply::Ready(reply));
+    }
+    let total = collection.len();
+    let op_id = storage.next_op_id();
+    let build = IndexBuild::new(op_id, command.clone(), new, reply, total);
+    storage.collection_mut(&namespace).start_index_build(build);
+    Ok(Reply::Pending(op_id))
+}
+
+/// Whether two indexes can't both exist: they have the same name, or the
+/// same key pattern and collation.
+fn conflicts(index: &Index, other: &Index) -> bool {
+    index.name() == other.name()
+        || (index.same_key(other) && index.collation() == other.collation())
+}
+
+/// Checks the `commitQuorum` of a build, which is either a number of nodes
+/// or the name of a replication mode.
+fn check_commit_quorum(commit_quorum: &Value) -> CommandResult<()> {
+    match commit_quorum {
+        Value::String(_) => Ok(()),
+        value if value.is_number() => match value.as_i64() {
+            Some(n) if n >= 0 => Ok(()),
+            _ => Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                "commitQuorum has to be a non-negative number",
+            )),
+        },
+        _ => Err(CommandError::new(
+            ErrorCode::FailedToParse,
+            "commitQuorum has to be a number or a string",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::json::doc,
+        commands::{coll_mod, current_op, drop_indexes},
+    };
+
+    /// Runs the command, and its index build to the end.
+    fn create(storage: &mut Storage, command: &str) -> CommandResult<Document> {
+        match run(storage, "test", &doc(command))? {
+            Reply::Ready(reply) => Ok(reply),
+            Reply::Pending(op_id) => loop {
+                let ended = storage.continue_index_builds();
+                if let Some((_, result)) = ended.into_iter().find(|(id, _)| *id == op_id) {
+                    return result;
+                }
+            },
+        }
+    }
+
+    #[test]
+    fn test_create_indexes() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        collection
+            .insert(doc("{ _id: 1, a: [1, 2], b: 3, c: [4] }"))
+            .unwrap();
+        collection.insert(doc("{ _id: 2, a: 1 }")).unwrap();
+
+        let reply = create(&mut storage, "{ createIndexes: 'c', indexes: [{ key: { a: 1 }, name: 'a_1' }, { key: { a: 1, b: -1 }, name: 'ab' }] }",
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            doc("{ createdCollectionAutomatically: false, numIndexesBefore: 1, numIndexesAfter: 3 }")
+        );
+        assert!(storage.collection("test.c").unwrap().index("ab").is_some());
+
+        // Existing indexes are left alone
+        let reply = create(
+            &mut storage,
+            "{ createIndexes: 'c', indexes: [{ key: { a: 1 }, name: 'a_1' }] }",
+        )
+        .unwrap();
+        assert_eq!(
+            reply.get("note"),
+            Some(&Value::String("all indexes already exist".into()))
+        );
+
+        let code = |storage: &mut Storage, command| create(storage, command).unwrap_err().code;
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { b: 1 }, name: 'a_1' }] }"
+            ),
+            ErrorCode::IndexKeySpecsConflict
+        );
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { a: 1 }, name: 'other' }] }"
+            ),
+            ErrorCode::IndexOptionsConflict
+        );
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { b: 1 } }, { key: { a: 1, c: 1 } }] }"
+            ),
+            ErrorCode::CannotIndexParallelArrays
+        );
+        // The failed command created none of its indexes
+        assert_eq!(storage.collection("test.c").unwrap().indexes().len(), 3);
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { a: 0 } }] }"
+            ),
+            ErrorCode::CannotCreateIndex
+        );
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { a: 1 }, foo: 1 }] }"
+            ),
+            ErrorCode::InvalidIndexSpecificationOption
+        );
+
+        // A failed command doesn't create the collection
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ createIndexes: 'd', indexes: [{ key: { a: 1 } }, { key: { a: 1 }, foo: 1 }] }"
+            ),
+            ErrorCode::InvalidIndexSpecificationOption
+        );
+        assert_eq!(
+            code(
+                &mut storage,
+                "{ createIndexes: 'd', indexes: [{ key: { a: 1 }, name: '_id_' }] }"
+            ),
+            ErrorCode::IndexKeySpecsConflict
+        );
+        assert!(storage.collection("test.d").is_none());
+        let reply = create(
+            &mut storage,
+            "{ createIndexes: 'd', indexes: [{ key: { a: 1 } }] }",
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            doc(
+                "{ createdCollectionAutomatically: true, numIndexesBefore: 1, numIndexesAfter: 2 }"
+            )
+        );
+        assert!(storage.collection("test.d").unwrap().index("a_1").is_some());
+    }
+
+    #[test]
+    fn test_background_index_build() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        for i in 0..2500 {
+            collection
+                .insert(doc(&format!("{{ _id: {}, a: {}, b: {} }}", i, i, i % 2)))
+                .unwrap();
+        }
+        let Ok(Reply::Pending(op_id)) = run(
+            &mut storage,
+            "test",
+            &doc("{ createIndexes: 'c', indexes: [{ key: { a: 1 }, unique: true }], commitQuorum: 'majority' }"),
+        ) else {
+            panic!("the build didn't start");
+        };
+        assert!(storage.continue_index_builds().is_empty());
+
+        let current_op = |storage: &Storage, command: &str| {
+            let reply = current_op::run(storage, "admin", &doc(command)).unwrap();
+            reply
+                .get("inprog")
+                .and_then(Value::as_array)
+                .unwrap()
+                .clone()
+        };
+        let Value::Document(op) =
+            &current_op(&storage, "{ currentOp: 1, 'command.createIndexes': 'c' }")[0]
+        else {
+            panic!("no operation");
+        };
+        assert_eq!(op.get("opid"), Some(&Value::Int32(op_id)));
+        let Some(Value::Document(progress)) = op.get("progress") else {
+            panic!("no progress");
+        };
+        assert_eq!(progress.get("done"), Some(&Value::Int64(1000)));
+        assert_eq!(progress.get("total"), Some(&Value::Int64(2500)));
+        assert_eq!(
+            op.get("msg"),
+            Some(&Value::String(
+                "Index Build: scanning collection Index Build: scanning collection: 1000/2500 40%"
+                    .into()
+            ))
+        );
+        assert!(current_op(&storage, "{ currentOp: 1, ns: 'test.d' }").is_empty());
+
+        // Reads and writes go on during the build, which indexes documents
+        // written behind and ahead of its scan
+        let collection = storage.collection_mut("test.c");
+        collection.replace(1, doc("{ _id: 0, a: -1 }")).unwrap();
+        collection.remove(2000);
+        collection.insert(doc("{ _id: 2500, a: 2500 }")).unwrap();
+        assert!(collection.index("a_1").is_none());
+        let code = |result: CommandResult<Document>| result.unwrap_err().code;
+        assert_eq!(
+            code(coll_mod::run(
+                &mut storage,
+                "test",
+                &doc("{ collMod: 'c' }")
+            )),
+            ErrorCode::BackgroundOperationInProgressForNamespace
+        );
+        assert_eq!(
+            code(create(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { a: 1 }, unique: true }] }"
+            )),
+            ErrorCode::BackgroundOperationInProgressForNamespace
+        );
+        let reply = loop {
+            if let Some((_, result)) = storage.continue_index_builds().pop() {
+                break result.unwrap();
+            }
+        };
+        assert_eq!(
+            reply,
+            doc("{ createdCollectionAutomatically: false, numIndexesBefore: 1, numIndexesAfter: 2 }")
+        );
+        let collection = storage.collection("test.c").unwrap();
+        let entries: Vec<_> = collection
+            .index("a_1")
+            .unwrap()
+            .entries_from(Vec::new())
+            .map(|(key, id)| (key[0].value.clone(), *id))
+            .collect();
+        assert_eq!(entries.len(), 2500);
+        assert_eq!(entries[0], (Value::Int32(-1), 1));
+        assert!(!entries.iter().any(|(_, id)| *id == 2000));
+        assert!(current_op(&storage, "{ currentOp: 1 }").is_empty());
+
+        // A failed build leaves no index behind, be it for the documents
+        // scanned or for writes
+        assert_eq!(
+            code(create(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { b: 1 }, unique: true }] }"
+            )),
+            ErrorCode::DuplicateKey
+        );
+        let Ok(Reply::Pending(_)) = run(
+            &mut storage,
+            "test",
+            &doc("{ createIndexes: 'c', indexes: [{ key: { a: 1, c: 1 } }] }"),
+        ) else {
+            panic!("the build didn't start");
+        };
+        storage.continue_index_builds();
+        storage
+            .collection_mut("test.c")
+            .replace(3, doc("{ _id: 2, a: [-2], c: [2] }"))
+            .unwrap();
+        assert_eq!(
+            code(storage.continue_index_builds().pop().unwrap().1),
+            ErrorCode::CannotIndexParallelArrays
+        );
+        assert_eq!(storage.collection("test.c").unwrap().indexes().len(), 2);
+
+        // Dropping the indexes of a build aborts it
+        let Ok(Reply::Pending(_)) = run(
+            &mut storage,
+            "test",
+            &doc("{ createIndexes: 'c', indexes: [{ key: { b: 1 } }] }"),
+        ) else {
+            panic!("the build didn't start");
+        };
+        drop_indexes::run(
+            &mut storage,
+            "test",
+            &doc("{ dropIndexes: 'c', index: 'b_1' }"),
+        )
+        .unwrap();
+        assert_eq!(
+            code(storage.continue_index_builds().pop().unwrap().1),
+            ErrorCode::IndexBuildAborted
+        );
+        assert_eq!(storage.collection("test.c").unwrap().indexes().len(), 2);
+
+        assert_eq!(
+            code(create(
+                &mut storage,
+                "{ createIndexes: 'c', indexes: [{ key: { b: 1 } }], commitQuorum: [] }"
+            )),
+            ErrorCode::FailedToParse
+        );
+    }
+}
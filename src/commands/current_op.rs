@@ -0,0 +1,37 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::Matcher,
+    storage::Storage,
+};
+
+/// Reports the operations in progress. Every command but `createIndexes`
+/// runs to completion before the next, so they are the index builds. The
+/// fields besides the options filter them like a query.
+pub fn run(storage: &Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    if db != "admin" {
+        return Err(CommandError::new(
+            ErrorCode::Unauthorized,
+            "currentOp may only be run against the admin database.",
+        ));
+    }
+    let mut filter = Document::new();
+    for (field, value) in command.iter().skip(1) {
+        if !matches!(field.as_str(), "$all" | "$ownOps" | "lsid" | "$db") {
+            filter.insert(field.clone(), value.clone());
+        }
+    }
+    let matcher = Matcher::new(&filter)?;
+
+    let mut builds: Vec<_> = storage.index_builds().collect();
+    builds.sort_by_key(|(_, build)| build.op_id());
+    let operations: Vec<Value> = builds
+        .into_iter()
+        .map(|(namespace, build)| build.current_op(namespace))
+        .filter(|op| matcher.matches(op))
+        .map(Value::Document)
+        .collect();
+    let mut reply = Document::new();
+    reply.insert("inprog", Value::from(operations));
+    Ok(reply)
+}
@@ -0,0 +1,136 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{planner, Matcher, Query},
+    storage::{Collection, Storage},
+};
+
+use super::{
+    explain::{single_statement, stage, Explain},
+    get_array, get_bool, get_collation, get_document, get_hint, missing_field, namespace,
+    write_error, wrong_type,
+};
+
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let statements = get_array(command, "delete", "deletes")?;
+    let ordered = get_bool(command, "delete", "ordered", true)?;
+
+    let mut n = 0;
+    let mut write_errors = Vec::new();
+    for (index, statement) in statements.iter().enumerate() {
+        let Value::Document(statement) = statement else {
+            return Err(wrong_type("delete", "deletes", statement, "object"));
+        };
+        match delete_one_statement(storage, &namespace, statement) {
+            Ok(deleted) => n += deleted,
+            Err(err) => {
+                write_errors.push(write_error(index, &err));
+                if ordered {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut reply = Document::new();
+    reply.insert("n", Value::Int32(n));
+    if !write_errors.is_empty() {
+        reply.insert("writeErrors", Value::from(write_errors));
+    }
+    Ok(reply)
+}
+
+/// A statement of a delete command.
+struct DeleteStatement<'s> {
+    matcher: Matcher,
+    hint: Option<&'s Value>,
+    single: bool,
+}
+
+impl<'s> DeleteStatement<'s> {
+    fn parse(statement: &'s Document, collection: Option<&Collection>) -> CommandResult<Self> {
+        let query = get_document(statement, "delete.deletes", "q")?;
+        let single = match statement.get("limit") {
+            Some(limit) if limit.is_number() => match limit.as_i64() {
+                Some(0) => false,
+                Some(1) => true,
+                _ => {
+                    return Err(CommandError::new(
+                        ErrorCode::FailedToParse,
+                        format!(
+                            "The limit field in delete objects must be 0 or 1. Got {}",
+                            limit
+                        ),
+                    ));
+                }
+            },
+            Some(limit) => return Err(wrong_type("delete.deletes", "limit", limit, "long")),
+            None => return Err(missing_field("delete.deletes", "limit")),
+        };
+        let collation = get_collation(statement, "delete.deletes", collection)?;
+        Ok(Self {
+            matcher: Matcher::for_query(query)?.with_collation(collation.as_ref()),
+            hint: get_hint(statement, "delete.deletes")?,
+            single,
+        })
+    }
+
+    fn query(&self) -> Query<'_> {
+        Query {
+            hint: self.hint,
+            ..Query::new(&self.matcher)
+        }
+    }
+}
+
+fn delete_one_statement(
+    storage: &mut Storage,
+    namespace: &str,
+    statement: &Document,
+) -> CommandResult<i32> {
+    let collection = storage.collection(namespace);
+    let statement = DeleteStatement::parse(statement, collection)?;
+    let planned = planner::plan(collection, &statement.query())?;
+    let matches: Vec<_> = planned
+        .execution
+        .documents
+        .iter()
+        .map(|(id, _)| *id)
+        .take(if statement.single { 1 } else { usize::MAX })
+        .collect();
+    if matches.is_empty() {
+        return Ok(0);
+    }
+
+    let collection = storage.collection_mut(namespace);
+    for id in &matches {
+        collection.remove(*id);
+    }
+    Ok(matches.len() as i32)
+}
+
+/// Explains a delete of a single statement, without deleting anything.
+pub fn explain(
+    storage: &Storage,
+    db: &str,
+    command: &Document,
+    explain: &Explain,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let statement =
+        DeleteStatement::parse(single_statement(command, "delete", "deletes")?, collection)?;
+    let planned = planner::plan(collection, &statement.query())?;
+    Ok(explain.plans(
+        &namespace,
+        collection,
+        &statement.matcher,
+        &planned,
+        0,
+        |_, input| {
+            let fields = vec![("isMulti", Value::Boolean(!statement.single))];
+            stage("DELETE", fields, input)
+        },
+    ))
+}
@@ -0,0 +1,168 @@
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{planner, Matcher, Query},
+    storage::{Collection, Storage},
+};
+
+use super::{explain::Explain, get_collation, get_hint, missing_field, namespace, wrong_type};
+
+/// The options of a distinct command.
+struct Distinct<'c> {
+    key: Vec<String>,
+    matcher: Matcher,
+    hint: Option<&'c Value>,
+    /// The collation values are told apart under, as well as matched.
+    collation: Option<Collation>,
+}
+
+impl<'c> Distinct<'c> {
+    fn parse(command: &'c Document, collection: Option<&Collection>) -> CommandResult<Self> {
+        let key = match command.get("key") {
+            Some(Value::String(key)) if key.is_empty() || key.split('.').any(str::is_empty) => {
+                return Err(CommandError::new(
+                    ErrorCode::Location(15998),
+                    "FieldPath field names may not be empty strings.",
+                ));
+            }
+            Some(Value::String(key)) => key.split('.').map(String::from).collect(),
+            Some(value) => return Err(wrong_type("distinct", "key", value, "string")),
+            None => return Err(missing_field("distinct", "key")),
+        };
+        let matcher = match command.get("query") {
+            None | Some(Value::Null) => Matcher::new(&Document::new())?,
+            Some(Value::Document(query)) => Matcher::for_query(query)?.forbid_near()?,
+            Some(value) => return Err(wrong_type("distinct", "query", value, "object")),
+        };
+        let collation = get_collation(command, "distinct", collection)?;
+        Ok(Self {
+            key,
+            matcher: matcher.with_collation(collation.as_ref()),
+            hint: get_hint(command, "distinct")?,
+            collation,
+        })
+    }
+
+    fn query(&self) -> Query<'_> {
+        Query {
+            hint: self.hint,
+            ..Query::new(&self.matcher)
+        }
+    }
+}
+
+pub fn run(storage: &Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let distinct = Distinct::parse(command, collection)?;
+    let planned = planner::plan(collection, &distinct.query())?;
+    let mut values: Vec<Value> = Vec::new();
+    for (_, doc) in &planned.execution.documents {
+        let mut found = Vec::new();
+        collect(doc, &distinct.key, &mut found);
+        for value in found {
+            let collation = distinct.collation.as_ref();
+            if !values
+                .iter()
+                .any(|existing| existing.equals_with(&value, collation))
+            {
+                values.push(value);
+            }
+        }
+    }
+
+    let mut reply = Document::new();
+    reply.insert("values", Value::from(values));
+    Ok(reply)
+}
+
+/// The values are gathered from the documents the plan returns, so the plan
+/// has no stages above it.
+pub fn explain(
+    storage: &Storage,
+    db: &str,
+    command: &Document,
+    explain: &Explain,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let distinct = Distinct::parse(command, collection)?;
+    let planned = planner::plan(collection, &distinct.query())?;
+    let returned = planned.execution.documents.len();
+    Ok(explain.plans(
+        &namespace,
+        collection,
+        &distinct.matcher,
+        &planned,
+        returned,
+        |_, input| input,
+    ))
+}
+
+/// Collects the values at `path`, with arrays contributing their elements.
+fn collect(doc: &Document, path: &[String], values: &mut Vec<Value>) {
+    let Some((first, rest)) = path.split_first() else {
+        return;
+    };
+    match doc.get(first) {
+        Some(Value::Array(items)) if rest.is_empty() => values.extend(items.0.iter().cloned()),
+        Some(value) if rest.is_empty() => values.push(value.clone()),
+        Some(Value::Document(doc)) => collect(doc, rest, values),
+        Some(Value::Array(items)) => {
+            for item in &items.0 {
+                if let Value::Document(doc) = item {
+                    collect(doc, rest, values);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    #[test]
+    fn test_count_and_distinct() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        for document in [
+            "{ _id: 1, a: 1, tags: ['x', 'y'] }",
+            "{ _id: 2, a: 2, tags: ['y'] }",
+            "{ _id: 3, a: 2, tags: 'z', n: { m: 1 } }",
+            "{ _id: 4, a: 3, n: [{ m: 1 }, { m: 2 }] }",
+        ] {
+            collection.insert(doc(document)).unwrap();
+        }
+        let index = crate::storage::Index::parse(&doc("{ key: { a: 1 } }")).unwrap();
+        collection.add_index(collection.build_index(index).unwrap());
+
+        let distinct = |command| run(&storage, "test", &doc(command)).unwrap();
+        assert_eq!(
+            distinct("{ distinct: 'c', key: 'tags' }").get("values"),
+            Some(&value("['x', 'y', 'z']"))
+        );
+        assert_eq!(
+            distinct("{ distinct: 'c', key: 'n.m', query: { a: { $gte: 3 } }, hint: 'a_1' }")
+                .get("values"),
+            Some(&value("[1, 2]"))
+        );
+
+        let count = |command| super::super::count::run(&storage, "test", &doc(command));
+        assert_eq!(
+            count("{ count: 'c', query: { a: 2 } }").unwrap(),
+            doc("{ n: 2 }")
+        );
+        assert_eq!(
+            count("{ count: 'c', skip: 1, limit: 2 }").unwrap(),
+            doc("{ n: 2 }")
+        );
+        assert_eq!(
+            count("{ count: 'c', hint: 'b_1' }").unwrap_err().code,
+            ErrorCode::BadValue
+        );
+    }
+}
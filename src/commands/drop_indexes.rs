@@ -0,0 +1,174 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    storage::{OpId, Storage, ID_INDEX_NAME},
+};
+
+use super::{missing_field, namespace, wrong_type};
+
+fn cannot_drop_id() -> CommandError {
+    CommandError::new(ErrorCode::InvalidOptions, "cannot drop _id index")
+}
+
+fn not_found(name: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::IndexNotFound,
+        format!("index not found with name [{}]", name),
+    )
+}
+
+/// Drops indexes given by name, by key pattern, as an array of names, or all
+/// but the `_id` index with `'*'`. Like mongod, dropping every index of a
+/// build in progress aborts it, and `'*'` aborts every build.
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let Some(collection) = storage.collection(&namespace) else {
+        return Err(CommandError::new(
+            ErrorCode::NamespaceNotFound,
+            format!("ns not found {}", namespace),
+        ));
+    };
+    let all = matches!(command.get("index"), Some(Value::String(name)) if name == "*");
+    let names: Vec<String> = match command.get("index") {
+        Some(Value::String(name)) if name == "*" => collection
+            .indexes()
+            .iter()
+            .map(|index| index.name().to_string())
+            .filter(|name| name != ID_INDEX_NAME)
+            .collect(),
+        Some(Value::String(name)) => vec![name.clone()],
+        Some(Value::Array(names)) => {
+            let mut parsed = Vec::new();
+            for name in &names.0 {
+                match name {
+                    Value::String(name) => parsed.push(name.clone()),
+                    name => return Err(wrong_type("dropIndexes", "index", name, "string")),
+                }
+            }
+            parsed
+        }
+        Some(Value::Document(key_pattern)) => {
+            // Key patterns are unique among the indexes of a collection
+            match collection
+                .indexes()
+                .iter()
+                .chain(
+                    collection
+                        .index_builds()
+                        .iter()
+                        .flat_map(|build| build.indexes()),
+                )
+                .find(|index| index.key_pattern().compare(key_pattern).is_eq())
+            {
+                Some(index) => vec![index.name().to_string()],
+                None => {
+                    return Err(CommandError::new(
+                        ErrorCode::IndexNotFound,
+                        format!("can't find index with key: {}", key_pattern),
+                    ));
+                }
+            }
+        }
+        Some(value) => return Err(wrong_type("dropIndexes", "index", value, "string")),
+        None => return Err(missing_field("dropIndexes", "index")),
+    };
+    let aborted: Vec<OpId> = collection
+        .index_builds()
+        .iter()
+        .filter(|build| {
+            all || build
+                .indexes()
+                .iter()
+                .all(|index| names.iter().any(|name| name == index.name()))
+        })
+        .map(|build| build.op_id())
+        .collect();
+    for name in &names {
+        if name == ID_INDEX_NAME {
+            return Err(cannot_drop_id());
+        }
+        if collection.index(name).is_some() {
+            continue;
+        }
+        match collection
+            .index_builds()
+            .iter()
+            .find(|build| build.indexes().iter().any(|index| index.name() == name))
+        {
+            Some(build) if aborted.contains(&build.op_id()) => {}
+            Some(_) => {
+                return Err(CommandError::new(
+                    ErrorCode::BackgroundOperationInProgressForNamespace,
+                    format!(
+                        "cannot drop index {} without the other indexes of its build in progress",
+                        name
+                    ),
+                ));
+            }
+            None => return Err(not_found(name)),
+        }
+    }
+
+    let mut reply = Document::new();
+    reply.insert(
+        "nIndexesWas",
+        Value::Int32(collection.indexes().len() as i32),
+    );
+    let collection = storage.collection_mut(&namespace);
+    for name in &names {
+        collection.drop_index(name);
+    }
+    for build in collection.index_builds_mut() {
+        if aborted.contains(&build.op_id()) {
+            build.abort(CommandError::new(
+                ErrorCode::IndexBuildAborted,
+                format!(
+                    "Index build aborted: {}: the indexes were dropped",
+                    build.op_id()
+                ),
+            ));
+        }
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bson::json::doc, storage::Index};
+
+    #[test]
+    fn test_drop_indexes() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        for key in ["{ a: 1 }", "{ b: 1 }", "{ c: -1 }"] {
+            let index = Index::parse(&doc(&format!("{{ key: {} }}", key))).unwrap();
+            collection.add_index(collection.build_index(index).unwrap());
+        }
+
+        let mut drop = |index: &str| {
+            run(
+                &mut storage,
+                "test",
+                &doc(&format!("{{ dropIndexes: 'c', index: {} }}", index)),
+            )
+        };
+        assert_eq!(drop("'a_1'").unwrap(), doc("{ nIndexesWas: 4 }"));
+        assert_eq!(drop("{ c: -1 }").unwrap(), doc("{ nIndexesWas: 3 }"));
+        assert_eq!(drop("'a_1'").unwrap_err().code, ErrorCode::IndexNotFound);
+        assert_eq!(drop("'_id_'").unwrap_err().code, ErrorCode::InvalidOptions);
+        assert_eq!(drop("'*'").unwrap(), doc("{ nIndexesWas: 2 }"));
+        assert_eq!(drop("'*'").unwrap(), doc("{ nIndexesWas: 1 }"));
+
+        assert_eq!(
+            run(
+                &mut storage,
+                "test",
+                &doc("{ dropIndexes: 'd', index: '*' }")
+            )
+            .unwrap_err()
+            .code,
+            ErrorCode::NamespaceNotFound
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use std::time::Instant;
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{
+        planner::{Execution, Plan, Planned},
+        Matcher,
+    },
+    storage::{Collection, Storage},
+};
+
+use super::{
+    aggregate, count, delete, distinct, find, get_array, get_document, update, wrong_type,
+};
+
+/// How much `explain` reports: the plans considered, the work of the
+/// winning plan too, or the work of every plan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verbosity {
+    QueryPlanner,
+    ExecutionStats,
+    AllPlansExecution,
+}
+
+/// Explains a `find`, `aggregate`, `count`, `distinct`, `update` or `delete`
+/// command. Writes are planned but not carried out.
+pub fn run(storage: &Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let explained = get_document(command, "explain", "explain")?;
+    let verbosity = match command.get("verbosity") {
+        None => Verbosity::AllPlansExecution,
+        Some(Value::String(verbosity)) => match verbosity.as_str() {
+            "queryPlanner" => Verbosity::QueryPlanner,
+            "executionStats" => Verbosity::ExecutionStats,
+            "allPlansExecution" => Verbosity::AllPlansExecution,
+            _ => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    "verbosity string must be one of \
+                     {'queryPlanner', 'executionStats', 'allPlansExecution'}",
+                ));
+            }
+        },
+        Some(value) => return Err(wrong_type("explain", "verbosity", value, "string")),
+    };
+    let mut reply = explain_command(storage, db, explained, verbosity)?;
+    reply.insert("command", Value::Document(explained.clone()));
+    Ok(reply)
+}
+
+/// The explain output of a command, also given by `aggregate` with
+/// `explain: true`.
+pub fn explain_command(
+    storage: &Storage,
+    db: &str,
+    explained: &Document,
+    verbosity: Verbosity,
+) -> CommandResult<Document> {
+    let explain = Explain::new(verbosity);
+    let name = explained
+        .first()
+        .map(|(name, _)| name.as_str())
+        .unwrap_or_default();
+    let output = match name {
+        "find" => find::explain(storage, db, explained, &explain),
+        "aggregate" => aggregate::explain(storage, db, explained, &explain),
+        "count" => count::explain(storage, db, explained, &explain),
+        "distinct" => distinct::explain(storage, db, explained, &explain),
+        "update" => update::explain(storage, db, explained, &explain),
+        "delete" => delete::explain(storage, db, explained, &explain),
+        name => {
+            return Err(CommandError::new(
+                ErrorCode::CommandNotFound,
+                format!("Explain failed due to unknown command: {}", name),
+            ));
+        }
+    }?;
+
+    let mut reply = Document::new();
+    reply.insert("explainVersion", Value::from("1"));
+    for (field, value) in output.iter() {
+        reply.insert(field.clone(), value.clone());
+    }
+    Ok(reply)
+}
+
+/// Builds the explain output of planned queries, timed from its creation.
+pub struct Explain {
+    verbosity: Verbosity,
+    started: Instant,
+}
+
+impl Explain {
+    pub fn new(verbosity: Verbosity) -> Self {
+        Self {
+            verbosity,
+            started: Instant::now(),
+        }
+    }
+
+    /// The `queryPlanner` and `executionStats` of a query on `namespace`.
+    /// `stages` adds the stages of the command above those of a plan, and
+    /// `returned` is the number of documents the command returned.
+    pub fn plans(
+        &self,
+        namespace: &str,
+        collection: Option<&Collection>,
+        matcher: &Matcher,
+        planned: &Planned,
+        returned: usize,
+        stages: impl Fn(&Plan, Document) -> Document,
+    ) -> Document {
+        let explain_plan = |plan: &Plan, execution: Option<&Execution>| match collection {
+            Some(collection) => {
+                Value::Document(stages(plan, plan.explain(collection, matcher, execution)))
+            }
+            None => {
+                let mut eof = Document::new();
+                eof.insert("stage", Value::from("EOF"));
+                Value::Document(eof)
+            }
+        };
+
+        let mut query_planner = Document::new();
+        query_planner.insert("namespace", Value::from(namespace));
+        query_planner.insert("indexFilterSet", Value::Boolean(false));
+        query_planner.insert("parsedQuery", Value::Document(matcher.filter().clone()));
+        query_planner.insert("winningPlan", explain_plan(&planned.winner, None));
+        query_planner.insert(
+            "rejectedPlans",
+            Value::from(
+                planned
+                    .rejected
+                    .iter()
+                    .map(|(plan, _)| explain_plan(plan, None))
+                    .collect::<Vec<_>>(),
+            ),
+        );
+        let mut output = Document::new();
+        output.insert("queryPlanner", Value::Document(query_planner));
+        if self.verbosity == Verbosity::QueryPlanner {
+            return output;
+        }
+
+        let mut stats = Document::new();
+        stats.insert("executionSuccess", Value::Boolean(true));
+        stats.insert("nReturned", Value::Int32(returned as i32));
+        stats.insert(
+            "executionTimeMillis",
+            Value::Int32(self.started.elapsed().as_millis() as i32),
+        );
+        let execution = &planned.execution;
+        stats.insert(
+            "totalKeysExamined",
+            Value::Int32(execution.keys_examined as i32),
+        );
+        stats.insert(
+            "totalDocsExamined",
+            Value::Int32(execution.docs_examined as i32),
+        );
+        stats.insert(
+            "executionStages",
+            explain_plan(&planned.winner, Some(execution)),
+        );
+        if self.verbosity == Verbosity::AllPlansExecution {
+            // The winner ran to the end, the other candidates to the end of
+            // their trial
+            let all_plans = std::iter::once((&planned.winner, execution))
+                .chain(
+                    planned
+                        .rejected
+                        .iter()
+                        .map(|(plan, execution)| (plan, execution)),
+                )
+                .map(|(plan, execution)| {
+                    let mut stats = Document::new();
+                    stats.insert("nReturned", Value::Int32(execution.documents.len() as i32));
+                    stats.insert(
+                        "totalKeysExamined",
+                        Value::Int32(execution.keys_examined as i32),
+                    );
+                    stats.insert(
+                        "totalDocsExamined",
+                        Value::Int32(execution.docs_examined as i32),
+                    );
+                    stats.insert("executionStages", explain_plan(plan, Some(execution)));
+                    Value::Document(stats)
+                })
+                .collect::<Vec<_>>();
+            stats.insert("allPlansExecution", Value::from(all_plans));
+        }
+        output.insert("executionStats", Value::Document(stats));
+        output
+    }
+}
+
+/// A stage of a command above the stages of its plan, as `explain` shows it.
+pub fn stage(name: &str, fields: Vec<(&str, Value)>, input: Document) -> Document {
+    let mut stage = Document::new();
+    stage.insert("stage", Value::from(name));
+    for (field, value) in fields {
+        stage.insert(field, value);
+    }
+    stage.insert("inputStage", Value::Document(input));
+    stage
+}
+
+/// The one statement of an explained write command, in the array `field`.
+pub fn single_statement<'a>(
+    command: &'a Document,
+    name: &str,
+    field: &str,
+) -> CommandResult<&'a Document> {
+    match get_array(command, name, field)?.as_slice() {
+        [Value::Document(statement)] => Ok(statement),
+        [statement] => Err(wrong_type(name, field, statement, "object")),
+        _ => Err(CommandError::new(
+            ErrorCode::InvalidLength,
+            "explained write batches must be of size 1",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::json::{doc, value},
+        storage::Index,
+    };
+
+    fn storage() -> Storage {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        for spec in ["{ key: { a: 1 } }", "{ key: { a: 1, b: -1 } }"] {
+            let index = collection.build_index(Index::parse(&doc(spec)).unwrap());
+            collection.add_index(index.unwrap());
+        }
+        for i in 0..10 {
+            collection
+                .insert(doc(&format!("{{ _id: {}, a: {}, b: {} }}", i, i % 5, i)))
+                .unwrap();
+        }
+        storage
+    }
+
+    fn field<'a>(doc: &'a Document, path: &str) -> &'a Value {
+        let mut parts = path.split('.');
+        let mut found = parts.next().and_then(|part| doc.get(part));
+        for part in parts {
+            found = match found {
+                Some(Value::Document(doc)) => doc.get(part),
+                Some(Value::Array(items)) => part.parse().ok().and_then(|i: usize| items.0.get(i)),
+                _ => None,
+            };
+        }
+        found.unwrap_or_else(|| panic!("no {} in {}", path, doc))
+    }
+
+    #[test]
+    fn test_explain() {
+        let storage = storage();
+        let explain = |command: &str| run(&storage, "test", &doc(command));
+
+        let reply = explain(
+            "{ explain: { find: 'c', filter: { a: 2, b: { $gt: 3 } }, sort: { _id: 1 }, \
+             projection: { b: 1 }, limit: 1 }, verbosity: 'executionStats' }",
+        )
+        .unwrap();
+        let planner = |path: &str| field(&reply, &format!("queryPlanner.{}", path));
+        assert_eq!(planner("namespace"), &value("'test.c'"));
+        assert_eq!(planner("winningPlan.stage"), &value("'PROJECTION_SIMPLE'"));
+        let scan = "winningPlan.inputStage.inputStage.inputStage.inputStage";
+        assert_eq!(planner(&format!("{}.stage", scan)), &value("'IXSCAN'"));
+        assert_eq!(
+            planner(&format!("{}.indexBounds", scan)),
+            &value("{ a: ['[2, 2]'], b: ['[inf.0, 3)'] }")
+        );
+        assert_eq!(
+            planner("rejectedPlans.1.inputStage.inputStage.inputStage.inputStage.indexName"),
+            &value("'a_1'")
+        );
+        // Both keys with `a: 2` are checked against the bounds of `b`, and
+        // the scan stops at the key after them
+        assert_eq!(field(&reply, "executionStats.nReturned"), &value("1"));
+        assert_eq!(
+            field(&reply, "executionStats.totalKeysExamined"),
+            &value("3")
+        );
+        assert_eq!(
+            field(&reply, "executionStats.totalDocsExamined"),
+            &value("1")
+        );
+        assert!(reply.get("command").is_some());
+
+        // Covered queries don't fetch; unindexed ones scan the collection
+        let reply = explain(
+            "{ explain: { find: 'c', filter: { a: 1 }, projection: { _id: 0, a: 1 } }, \
+             verbosity: 'queryPlanner' }",
+        )
+        .unwrap();
+        assert_eq!(
+            field(&reply, "queryPlanner.winningPlan.stage"),
+            &value("'PROJECTION_COVERED'")
+        );
+        assert!(reply.get("executionStats").is_none());
+        let reply = explain("{ explain: { count: 'c', query: { b: 1 } } }").unwrap();
+        assert_eq!(
+            field(&reply, "queryPlanner.winningPlan"),
+            &value("{ stage: 'COUNT', inputStage: { stage: 'COLLSCAN', filter: { b: 1 }, direction: 'forward' } }")
+        );
+        assert_eq!(
+            field(&reply, "executionStats.totalDocsExamined"),
+            &value("10")
+        );
+
+        // Writes are only planned
+        let reply =
+            explain("{ explain: { delete: 'c', deletes: [{ q: { a: { $lt: 1 } }, limit: 0 }] } }")
+                .unwrap();
+        assert_eq!(
+            field(&reply, "queryPlanner.winningPlan.stage"),
+            &value("'DELETE'")
+        );
+        assert_eq!(storage.collection("test.c").unwrap().iter().count(), 10);
+        let reply = explain(
+            "{ explain: { update: 'c', updates: [{ q: { a: 3 }, u: { $set: { b: 0 } } }] } }",
+        )
+        .unwrap();
+        assert_eq!(
+            field(&reply, "queryPlanner.winningPlan.inputStage.stage"),
+            &value("'FETCH'")
+        );
+        assert_eq!(
+            explain("{ explain: { update: 'c', updates: [] } }")
+                .unwrap_err()
+                .code,
+            ErrorCode::InvalidLength
+        );
+
+        let reply = explain(
+            "{ explain: { aggregate: 'c', pipeline: [{ $match: { a: 4 } }, { $count: 'n' }], cursor: {} } }",
+        )
+        .unwrap();
+        assert_eq!(
+            field(&reply, "stages.0.$cursor.executionStats.nReturned"),
+            &value("2")
+        );
+        assert_eq!(field(&reply, "stages.1"), &value("{ $count: 'n' }"));
+        assert_eq!(
+            explain("{ explain: { insert: 'c', documents: [] } }")
+                .unwrap_err()
+                .code,
+            ErrorCode::CommandNotFound
+        );
+    }
+}
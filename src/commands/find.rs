@@ -0,0 +1,558 @@
+use std::cmp::Ordering;
+
+use crate::{
+    aggregation::Projection,
+    bson::{Document, Value},
+    collation::Collation,
+    cursor::{CursorManager, CursorOptions},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{
+        planner::{self, Plan, Planned},
+        Matcher, Query, SortSpec,
+    },
+    storage::{Collection, RecordId, Storage},
+};
+
+use super::{
+    cursor_reply,
+    explain::{stage, Explain},
+    get_bool, get_collation, get_count, get_hint, namespace, session_id, wrong_type,
+};
+
+fn get_optional_document<'a>(
+    command: &'a Document,
+    field: &str,
+) -> CommandResult<Option<&'a Document>> {
+    match command.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Document(doc)) => Ok(Some(doc)),
+        Some(value) => Err(wrong_type("find", field, value, "object")),
+    }
+}
+
+/// Whether a projected or sorted value is `{ $meta: 'textScore' }`, the
+/// score of a document found by a `$text` query.
+fn is_text_score(value: &Value) -> CommandResult<bool> {
+    let Value::Document(meta) = value else {
+        return Ok(false);
+    };
+    match meta.get("$meta") {
+        None => Ok(false),
+        Some(Value::String(name)) if name == "textScore" && meta.len() == 1 => Ok(true),
+        Some(_) => Err(CommandError::new(
+            ErrorCode::BadValue,
+            format!("unsupported metadata: {}", value),
+        )),
+    }
+}
+
+/// A key of the sort of a find: some fields, or the text score, highest
+/// first.
+enum SortKey {
+    Fields(SortSpec),
+    TextScore,
+}
+
+impl SortKey {
+    /// The keys of a sort specification, fields between text scores being
+    /// grouped, with strings sorted under `collation`.
+    fn parse(spec: &Document, collation: Option<&Collation>) -> CommandResult<Vec<Self>> {
+        let mut keys = Vec::new();
+        let mut fields = Document::new();
+        let parse = |fields: &Document| -> CommandResult<Self> {
+            Ok(SortKey::Fields(
+                SortSpec::parse(fields)?.with_collation(collation),
+            ))
+        };
+        for (field, value) in spec.iter() {
+            if !is_text_score(value)? {
+                fields.insert(field.clone(), value.clone());
+                continue;
+            }
+            if !fields.is_empty() {
+                keys.push(parse(&fields)?);
+                fields = Document::new();
+            }
+            keys.push(SortKey::TextScore);
+        }
+        if !fields.is_empty() {
+            keys.push(parse(&fields)?);
+        }
+        Ok(keys)
+    }
+}
+
+/// The options of a find command that decide its results.
+struct Find<'c> {
+    matcher: Matcher,
+    sort: Vec<SortKey>,
+    /// The sort an index may provide, empty when sorting by text score.
+    index_sort: SortSpec,
+    sort_spec: Option<&'c Document>,
+    projection_spec: Option<&'c Document>,
+    projection: Option<Projection>,
+    /// The fields projected to the text score.
+    text_score_fields: Vec<String>,
+    hint: Option<&'c Value>,
+    skip: usize,
+    limit: Option<usize>,
+}
+
+impl<'c> Find<'c> {
+    fn parse(command: &'c Document, collection: Option<&Collection>) -> CommandResult<Self> {
+        let empty = Document::new();
+        let sort_spec = get_optional_document(command, "sort")?;
+        let projection_spec = get_optional_document(command, "projection")?
+            .filter(|projection| !projection.is_empty());
+        let collation = get_collation(command, "find", collection)?;
+        let matcher =
+            Matcher::for_query(get_optional_document(command, "filter")?.unwrap_or(&empty))?
+                .with_collation(collation.as_ref());
+        let sort = SortKey::parse(sort_spec.unwrap_or(&empty), collation.as_ref())?;
+        let index_sort = match &sort[..] {
+            [SortKey::Fields(spec)] => spec.clone(),
+            _ => SortSpec::default(),
+        };
+
+        // Text scores are added to the projected documents
+        let mut projected = Document::new();
+        let mut text_score_fields = Vec::new();
+        for (field, value) in projection_spec.iter().flat_map(|spec| spec.iter()) {
+            if is_text_score(value)? {
+                text_score_fields.push(field.clone());
+            } else {
+                projected.insert(field.clone(), value.clone());
+            }
+        }
+        let uses_text_score = !text_score_fields.is_empty()
+            || sort.iter().any(|key| matches!(key, SortKey::TextScore));
+        if uses_text_score && matcher.text().is_none() {
+            return Err(CommandError::new(
+                ErrorCode::Location(40218),
+                "query requires text score metadata, but it is not available",
+            ));
+        }
+        Ok(Self {
+            matcher,
+            sort,
+            index_sort,
+            sort_spec,
+            projection_spec,
+            projection: (!projected.is_empty())
+                .then(|| Projection::parse(&projected))
+                .transpose()?,
+            text_score_fields,
+            hint: get_hint(command, "find")?,
+            skip: get_count(command, "find", "skip")?.unwrap_or(0),
+            limit: get_count(command, "find", "limit")?.filter(|limit| *limit > 0),
+        })
+    }
+
+    fn query(&self) -> Query<'_> {
+        Query {
+            matcher: &self.matcher,
+            sort: Some(&self.index_sort),
+            projection: self.projection_spec,
+            hint: self.hint,
+        }
+    }
+
+    /// Sorts, skips, limits and projects the documents found by the plan.
+    fn results(&self, planned: &Planned) -> CommandResult<Vec<Document>> {
+        let scores = &planned.execution.text_scores;
+        let score = |id: &RecordId| scores.get(id).copied().unwrap_or_default();
+        let mut documents: Vec<(RecordId, &Document)> = planned
+            .execution
+            .documents
+            .iter()
+            .map(|(id, doc)| (*id, doc.as_ref()))
+            .collect();
+        if !self.sort.is_empty() && !planned.winner.sorted {
+            documents.sort_by(|(a_id, a), (b_id, b)| {
+                self.sort
+                    .iter()
+                    .map(|key| match key {
+                        SortKey::Fields(spec) => spec.compare(a, b),
+                        SortKey::TextScore => score(b_id).total_cmp(&score(a_id)),
+                    })
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            });
+        }
+        documents
+            .into_iter()
+            .skip(self.skip)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|(id, doc)| {
+                let mut doc = match &self.projection {
+                    Some(projection) => projection.apply(doc)?,
+                    None => doc.clone(),
+                };
+                for field in &self.text_score_fields {
+                    doc.insert(field.clone(), Value::Double(score(&id)));
+                }
+                Ok(doc)
+            })
+            .collect()
+    }
+
+    /// The stages doing the work of [`Find::results`] above those of a plan.
+    fn stages(&self, plan: &Plan, mut input: Document) -> Document {
+        if !self.sort.is_empty() && !plan.sorted {
+            let spec = self.sort_spec.cloned().unwrap_or_default();
+            let mut fields = vec![("sortPattern", Value::Document(spec))];
+            if let Some(limit) = self.limit {
+                fields.push(("limitAmount", Value::Int64((self.skip + limit) as i64)));
+            }
+            input = stage("SORT", fields, input);
+        }
+        if self.skip > 0 {
+            input = stage(
+                "SKIP",
+                vec![("skipAmount", Value::Int64(self.skip as i64))],
+                input,
+            );
+        }
+        if let Some(limit) = self.limit {
+            input = stage(
+                "LIMIT",
+                vec![("limitAmount", Value::Int64(limit as i64))],
+                input,
+            );
+        }
+        if let Some(spec) = self.projection_spec {
+            let simple = spec
+                .iter()
+                .all(|(_, value)| matches!(value, Value::Boolean(_)) || value.is_number());
+            let name = match (plan.covered, simple) {
+                (true, _) => "PROJECTION_COVERED",
+                (false, true) => "PROJECTION_SIMPLE",
+                (false, false) => "PROJECTION_DEFAULT",
+            };
+            input = stage(
+                name,
+                vec![("transformBy", Value::Document(spec.clone()))],
+                input,
+            );
+        }
+        input
+    }
+}
+
+pub fn run(
+    storage: &Storage,
+    cursors: &mut CursorManager,
+    db: &str,
+    command: &Document,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let find = Find::parse(command, collection)?;
+    let options = CursorOptions {
+        batch_size: get_count(command, "find", "batchSize")?,
+        single_batch: get_bool(command, "find", "singleBatch", false)?,
+        no_timeout: get_bool(command, "find", "noCursorTimeout", false)?,
+        session: session_id(command),
+    };
+
+    let planned = planner::plan(collection, &find.query())?;
+    let documents = find.results(&planned)?;
+    let (batch, id) = cursors.open(&namespace, documents.into_iter().map(Ok), options)?;
+    Ok(cursor_reply(&namespace, id, "firstBatch", batch))
+}
+
+pub fn explain(
+    storage: &Storage,
+    db: &str,
+    command: &Document,
+    explain: &Explain,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let find = Find::parse(command, collection)?;
+    let planned = planner::plan(collection, &find.query())?;
+    let returned = find.results(&planned)?.len();
+    Ok(explain.plans(
+        &namespace,
+        collection,
+        &find.matcher,
+        &planned,
+        returned,
+        |plan, input| find.stages(plan, input),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        bson::json::{doc, value},
+        storage::Index,
+    };
+
+    #[test]
+    fn test_find_returns_batches() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        for i in 0..5 {
+            collection
+                .insert(Document::from_iter([
+                    ("_id".to_string(), Value::Int32(i)),
+                    ("even".to_string(), Value::Boolean(i % 2 == 0)),
+                ]))
+                .unwrap();
+        }
+        let mut cursors = CursorManager::default();
+
+        let reply = run(
+            &storage,
+            &mut cursors,
+            "test",
+            &doc("{ find: 'c', filter: { even: true }, sort: { _id: -1 }, projection: { even: 0 }, batchSize: 2 }"),
+        )
+        .unwrap();
+        let Some(Value::Document(cursor)) = reply.get("cursor") else {
+            panic!("no cursor in {}", reply);
+        };
+        assert_eq!(
+            cursor.get("firstBatch"),
+            Some(&value("[{ _id: 4 }, { _id: 2 }]"))
+        );
+        let Some(Value::Int64(id)) = cursor.get("id") else {
+            panic!("no cursor id in {}", reply);
+        };
+        let (batch, open) = cursors.get_more(*id, "test.c", None, None).unwrap();
+        assert_eq!((batch, open), (vec![doc("{ _id: 0 }")], false));
+
+        let reply = run(
+            &storage,
+            &mut cursors,
+            "test",
+            &doc("{ find: 'c', skip: 1, limit: 2 }"),
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            doc("{ cursor: { firstBatch: [{ _id: 1, even: false }, { _id: 2, even: true }], id: Long(0), ns: 'test.c' } }")
+        );
+    }
+
+    #[test]
+    fn test_text_search() {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.c");
+        let index = Index::parse(&doc(
+            "{ key: { title: 'text', body: 'text' }, weights: { title: 2 }, language_override: 'lang' }",
+        ))
+        .unwrap();
+        assert_eq!(index.name(), "title_text_body_text");
+        let index = collection.build_index(index).unwrap();
+        collection.add_index(index);
+        for document in [
+            "{ _id: 1, title: 'Coffee', body: 'about tea' }",
+            "{ _id: 2, title: 'Tea time', body: 'coffee and cake' }",
+            "{ _id: 3, title: 'Kaffee', body: 'the Kuchen', lang: 'none' }",
+            "{ _id: 4, title: 'Latte art', body: 'coffee latte art' }",
+        ] {
+            collection.insert(doc(document)).unwrap();
+        }
+        storage
+            .collection_mut("test.plain")
+            .insert(doc("{ _id: 1 }"))
+            .unwrap();
+        let mut cursors = CursorManager::default();
+        let mut find = |command: &str| -> CommandResult<Value> {
+            let reply = run(&storage, &mut cursors, "test", &doc(command))?;
+            match reply.get("cursor") {
+                Some(Value::Document(cursor)) => Ok(cursor.get("firstBatch").unwrap().clone()),
+                _ => panic!("no cursor in {}", reply),
+            }
+        };
+
+        // The title weighs twice as much, and a term making up less of its
+        // string scores less
+        assert_eq!(
+            find(
+                "{ find: 'c', filter: { $text: { $search: 'coffees' } }, \
+                 projection: { title: 1, score: { $meta: 'textScore' } }, \
+                 sort: { score: { $meta: 'textScore' } } }"
+            )
+            .unwrap(),
+            value(
+                "[{ _id: 1, title: 'Coffee', score: 2.0 }, { _id: 2, title: 'Tea time', score: 0.75 }, \
+                 { _id: 4, title: 'Latte art', score: 0.6666666666666666 }]"
+            )
+        );
+        let mut ids = |filter: &str| {
+            let command = format!(
+                "{{ find: 'c', filter: {}, projection: {{ _id: 1 }} }}",
+                filter
+            );
+            find(&command)
+        };
+        assert_eq!(
+            ids("{ $text: { $search: 'coffee -tea' } }").unwrap(),
+            value("[{ _id: 4 }]")
+        );
+        assert_eq!(
+            ids("{ $text: { $search: 'coffee \"latte art\"' }, _id: { $gt: 1 } }").unwrap(),
+            value("[{ _id: 4 }]")
+        );
+        // Stop words are only indexed in documents without a language
+        assert_eq!(
+            ids("{ $text: { $search: 'the', $language: 'none' } }").unwrap(),
+            value("[{ _id: 3 }]")
+        );
+        assert_eq!(ids("{ $text: { $search: 'the' } }").unwrap(), value("[]"));
+
+        assert_eq!(
+            find("{ find: 'c', sort: { score: { $meta: 'textScore' } } }")
+                .unwrap_err()
+                .code,
+            ErrorCode::Location(40218)
+        );
+        assert_eq!(
+            find("{ find: 'plain', filter: { $text: { $search: 'a' } } }")
+                .unwrap_err()
+                .code,
+            ErrorCode::IndexNotFound
+        );
+    }
+
+    #[test]
+    fn test_geo_queries() {
+        let mut storage = Storage::new();
+        for (name, spec) in [
+            ("stores", "{ key: { location: '2dsphere' } }"),
+            ("grid", "{ key: { pos: '2d' }, min: -10, max: 10 }"),
+        ] {
+            let collection = storage.collection_mut(&format!("test.{}", name));
+            let index = collection.build_index(Index::parse(&doc(spec)).unwrap());
+            collection.add_index(index.unwrap());
+        }
+        let stores = storage.collection_mut("test.stores");
+        for document in [
+            "{ _id: 1, location: { type: 'Point', coordinates: [-73.97, 40.77] } }",
+            "{ _id: 2, location: { type: 'Point', coordinates: [-73.99, 40.73] } }",
+            "{ _id: 3, location: [-74.01, 40.71] }",
+            "{ _id: 4, location: { type: 'Point', coordinates: [-118.24, 34.05] } }",
+            "{ _id: 5, location: { type: 'LineString', coordinates: [[-74.0, 40.6], [-73.9, 40.6]] } }",
+            "{ _id: 6, name: 'online' }",
+        ] {
+            stores.insert(doc(document)).unwrap();
+        }
+        assert_eq!(
+            stores
+                .insert(doc(
+                    "{ _id: 7, location: { type: 'Point', coordinates: [200, 0] } }"
+                ))
+                .unwrap_err()
+                .code,
+            ErrorCode::Location(16755)
+        );
+        let grid = storage.collection_mut("test.grid");
+        for document in [
+            "{ _id: 1, pos: [0, 0] }",
+            "{ _id: 2, pos: [3, 4] }",
+            "{ _id: 3, pos: { x: 1, y: 1 } }",
+        ] {
+            grid.insert(doc(document)).unwrap();
+        }
+        assert_eq!(
+            grid.insert(doc("{ _id: 4, pos: [10, 0] }"))
+                .unwrap_err()
+                .code,
+            ErrorCode::Location(13027)
+        );
+
+        let mut cursors = CursorManager::default();
+        // Results come in the order of the cells they were found in, unless
+        // sorted by distance
+        let mut ids = |collection: &str, filter: &str| -> CommandResult<Value> {
+            let sort = if filter.contains("$near") {
+                "{}"
+            } else {
+                "{ _id: 1 }"
+            };
+            let command = format!(
+                "{{ find: '{}', filter: {}, sort: {}, projection: {{ _id: 1 }} }}",
+                collection, filter, sort
+            );
+            let reply = run(&storage, &mut cursors, "test", &doc(&command))?;
+            match reply.get("cursor") {
+                Some(Value::Document(cursor)) => Ok(cursor.get("firstBatch").unwrap().clone()),
+                _ => panic!("no cursor in {}", reply),
+            }
+        };
+
+        // Nearest first, in meters from a GeoJSON point
+        let near =
+            "{ location: { $near: { $geometry: { type: 'Point', coordinates: [-73.98, 40.76] }, \
+                    $maxDistance: 7000 } } }";
+        assert_eq!(
+            ids("stores", near).unwrap(),
+            value("[{ _id: 1 }, { _id: 2 }, { _id: 3 }]")
+        );
+        assert_eq!(
+            ids(
+                "stores",
+                "{ location: { $nearSphere: [-73.98, 40.76], $minDistance: 0.0005, $maxDistance: 0.001 } }"
+            )
+            .unwrap(),
+            value("[{ _id: 2 }, { _id: 3 }]")
+        );
+        assert_eq!(
+            ids(
+                "stores",
+                "{ location: { $geoWithin: { $geometry: { type: 'Polygon', coordinates: \
+                 [[[-74.02, 40.7], [-73.96, 40.7], [-73.96, 40.78], [-74.02, 40.78], [-74.02, 40.7]]] } } } }"
+            )
+            .unwrap(),
+            value("[{ _id: 1 }, { _id: 2 }, { _id: 3 }]")
+        );
+        assert_eq!(
+            ids(
+                "stores",
+                "{ location: { $geoWithin: { $centerSphere: [[-118.2, 34.0], 0.01] } } }"
+            )
+            .unwrap(),
+            value("[{ _id: 4 }]")
+        );
+        assert_eq!(
+            ids(
+                "stores",
+                "{ location: { $geoIntersects: { $geometry: { type: 'LineString', coordinates: \
+                 [[-73.95, 40.5], [-73.95, 40.7]] } } } }"
+            )
+            .unwrap(),
+            value("[{ _id: 5 }]")
+        );
+
+        // Distances on the plane of a 2d index
+        assert_eq!(
+            ids("grid", "{ pos: { $near: [0, 0], $maxDistance: 2 } }").unwrap(),
+            value("[{ _id: 1 }, { _id: 3 }]")
+        );
+        assert_eq!(
+            ids(
+                "grid",
+                "{ pos: { $geoWithin: { $box: [[0, 0], [3, 4]] } }, _id: { $gt: 1 } }"
+            )
+            .unwrap(),
+            value("[{ _id: 2 }, { _id: 3 }]")
+        );
+
+        assert_eq!(
+            ids("stores", "{ name: { $near: [0, 0] } }")
+                .unwrap_err()
+                .code,
+            ErrorCode::NoQueryExecutionPlans
+        );
+        assert_eq!(
+            ids("stores", "{ $or: [{ location: { $near: [0, 0] } }] }")
+                .unwrap_err()
+                .code,
+            ErrorCode::BadValue
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! `findAndModify`: updates or removes a single document and returns it, as a
+//! single atomic read-modify-write. Commands run one at a time on the server
+//! thread, so no other operation can interleave between the find and the
+//! write.
+
+use crate::{
+    aggregation::Projection,
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{planner, Matcher, Query, SortSpec},
+    storage::Storage,
+    update::Update,
+};
+
+use super::{get_bool, get_collation, namespace, wrong_type};
+
+const NAME: &str = "findAndModify";
+
+/// Reads an optional document option, treating null as absent.
+fn get_optional_document<'a>(
+    command: &'a Document,
+    field: &str,
+) -> CommandResult<Option<&'a Document>> {
+    match command.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::Document(doc)) => Ok(Some(doc)),
+        Some(value) => Err(wrong_type(NAME, field, value, "object")),
+    }
+}
+
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let empty = Document::new();
+    let query = get_optional_document(command, "query")?.unwrap_or(&empty);
+    let collation = get_collation(command, NAME, storage.collection(&namespace))?;
+    let sort = SortSpec::parse(get_optional_document(command, "sort")?.unwrap_or(&empty))?
+        .with_collation(collation.as_ref());
+    let fields = get_optional_document(command, "fields")?
+        .filter(|fields| !fields.is_empty())
+        .map(Projection::parse)
+        .transpose()?;
+    let remove = get_bool(command, NAME, "remove", false)?;
+    let return_new = get_bool(command, NAME, "new", false)?;
+    let upsert = get_bool(command, NAME, "upsert", false)?;
+    let update = command.get("update").filter(|u| !matches!(u, Value::Null));
+
+    let failed = |message: &str| Err(CommandError::new(ErrorCode::FailedToParse, message));
+    match (update, remove) {
+        (Some(_), true) => return failed("Cannot specify both an update and remove=true"),
+        (None, false) => return failed("Either an update or remove=true must be specified"),
+        (None, true) if upsert => return failed("Cannot specify both upsert=true and remove=true"),
+        (None, true) if return_new => {
+            return failed(
+                "Cannot specify both new=true and remove=true; 'remove' always returns the deleted document",
+            );
+        }
+        _ => {}
+    }
+    if remove && command.contains_key("arrayFilters") {
+        return failed("Cannot specify arrayFilters and remove=true");
+    }
+    let update = update
+        .map(|update| Update::parse(update, command.get("arrayFilters")))
+        .transpose()?;
+
+    let matcher = Matcher::for_query(query)?.with_collation(collation.as_ref());
+    let plan_query = Query {
+        sort: Some(&sort),
+        ..Query::new(&matcher)
+    };
+    let planned = planner::plan(storage.collection(&namespace), &plan_query)?;
+    let mut candidates = planned.execution.documents.iter();
+    let found = if sort.is_empty() || planned.winner.sorted {
+        candidates.next()
+    } else {
+        // The first of the documents in the order of the sort
+        candidates.reduce(|best, candidate| {
+            if sort.compare(&candidate.1, &best.1).is_lt() {
+                candidate
+            } else {
+                best
+            }
+        })
+    };
+    let target = found.map(|(id, doc)| (*id, matcher.match_position(doc).flatten()));
+
+    // The collection is only taken for writing, so that no-ops and failed
+    // updates don't create it
+    let mut last_error = Document::new();
+    let value = match (update, target) {
+        (None, Some((id, _))) => {
+            last_error.insert("n", Value::Int32(1));
+            storage.collection_mut(&namespace).remove(id)
+        }
+        (None, None) => {
+            last_error.insert("n", Value::Int32(0));
+            None
+        }
+        (Some(update), Some((id, position))) => {
+            let original = storage
+                .collection(&namespace)
+                .and_then(|collection| collection.get(id))
+                .expect("record was just found")
+                .clone();
+            let mut doc = original.clone();
+            if update.apply(&mut doc, position, false)? {
+                storage
+                    .collection_mut(&namespace)
+                    .replace(id, doc.clone())?;
+            }
+            last_error.insert("n", Value::Int32(1));
+            last_error.insert("updatedExisting", Value::Boolean(true));
+            Some(if return_new { doc } else { original })
+        }
+        (Some(update), None) if upsert => {
+            let doc = update.upsert(query)?;
+            let id = doc.get("_id").cloned().unwrap_or(Value::Null);
+            storage.collection_mut(&namespace).insert(doc.clone())?;
+            last_error.insert("n", Value::Int32(1));
+            last_error.insert("updatedExisting", Value::Boolean(false));
+            last_error.insert("upserted", id);
+            return_new.then_some(doc)
+        }
+        (Some(_), None) => {
+            last_error.insert("n", Value::Int32(0));
+            last_error.insert("updatedExisting", Value::Boolean(false));
+            None
+        }
+    };
+
+    let value = match (value, &fields) {
+        (Some(doc), Some(fields)) => Value::Document(fields.apply(&doc)?),
+        (Some(doc), None) => Value::Document(doc),
+        (None, _) => Value::Null,
+    };
+
+    let mut reply = Document::new();
+    reply.insert("lastErrorObject", Value::Document(last_error));
+    reply.insert("value", value);
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn run_command(storage: &mut Storage, command: &str) -> CommandResult<Document> {
+        run(storage, "test", &doc(command))
+    }
+
+    fn storage() -> Storage {
+        let mut storage = Storage::new();
+        let collection = storage.collection_mut("test.jobs");
+        for job in [
+            "{ _id: 1, state: 'ready', priority: 1 }",
+            "{ _id: 2, state: 'ready', priority: 5 }",
+            "{ _id: 3, state: 'done', priority: 9 }",
+        ] {
+            collection.insert(doc(job)).unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn test_update_returns_old_or_new_document() {
+        let mut storage = storage();
+        let reply = run_command(
+            &mut storage,
+            "{ findAndModify: 'jobs', query: { state: 'ready' }, sort: { priority: -1 }, update: { $set: { state: 'running' } } }",
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            doc("{ lastErrorObject: { n: 1, updatedExisting: true }, value: { _id: 2, state: 'ready', priority: 5 } }")
+        );
+
+        let reply = run_command(
+            &mut storage,
+            "{ findAndModify: 'jobs', query: { state: 'ready' }, update: { $inc: { priority: 1 } }, new: true, fields: { priority: 1 } }",
+        )
+        .unwrap();
+        assert_eq!(
+            reply.get("value"),
+            Some(&Value::Document(doc("{ _id: 1, priority: 2 }")))
+        );
+    }
+
+    #[test]
+    fn test_remove_and_upsert() {
+        let mut storage = storage();
+        let reply = run_command(
+            &mut storage,
+            "{ findAndModify: 'jobs', query: { state: 'done' }, remove: true }",
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            doc("{ lastErrorObject: { n: 1 }, value: { _id: 3, state: 'done', priority: 9 } }")
+        );
+
+        let reply = run_command(
+            &mut storage,
+            "{ findAndModify: 'jobs', query: { _id: 4 }, update: { $set: { state: 'ready' } }, upsert: true, new: true }",
+        )
+        .unwrap();
+        assert_eq!(
+            reply,
+            doc("{ lastErrorObject: { n: 1, updatedExisting: false, upserted: 4 }, value: { _id: 4, state: 'ready' } }")
+        );
+
+        let err = run_command(
+            &mut storage,
+            "{ findAndModify: 'jobs', remove: true, update: { $set: { a: 1 } } }",
+        )
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::FailedToParse);
+    }
+
+    #[test]
+    fn test_missing_collection_is_not_created() {
+        let mut storage = Storage::new();
+        for command in [
+            "{ findAndModify: 'c', query: { a: 1 }, remove: true }",
+            "{ findAndModify: 'c', query: { a: 1 }, update: { $set: { b: 1 } } }",
+        ] {
+            let reply = run_command(&mut storage, command).unwrap();
+            assert_eq!(reply.get("value"), Some(&Value::Null));
+        }
+        let err = run_command(
+            &mut storage,
+            "{ findAndModify: 'c', query: { a: 1 }, update: { $set: { 'a.b': 1 } }, upsert: true }",
+        )
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::PathNotViable);
+        assert!(storage.collection("test.c").is_none());
+    }
+}
@@ -0,0 +1,37 @@
+use crate::{
+    bson::{Document, Value},
+    cursor::CursorManager,
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{cursor_reply, get_count, missing_field, session_id, wrong_type};
+
+pub fn run(cursors: &mut CursorManager, db: &str, command: &Document) -> CommandResult<Document> {
+    let id = match command.get("getMore") {
+        Some(Value::Int64(id)) => *id,
+        Some(value) => return Err(wrong_type("getMore", "getMore", value, "long")),
+        None => return Err(missing_field("getMore", "getMore")),
+    };
+    let collection = match command.get("collection") {
+        Some(Value::String(collection)) if !collection.is_empty() => collection,
+        Some(Value::String(_)) => {
+            return Err(CommandError::new(
+                ErrorCode::InvalidNamespace,
+                format!("Invalid namespace specified '{}.'", db),
+            ));
+        }
+        Some(value) => return Err(wrong_type("getMore", "collection", value, "string")),
+        None => return Err(missing_field("getMore", "collection")),
+    };
+    let namespace = format!("{}.{}", db, collection);
+    let batch_size = get_count(command, "getMore", "batchSize")?.filter(|size| *size > 0);
+
+    let (batch, open) =
+        cursors.get_more(id, &namespace, batch_size, session_id(command).as_ref())?;
+    Ok(cursor_reply(
+        &namespace,
+        if open { id } else { 0 },
+        "nextBatch",
+        batch,
+    ))
+}
@@ -0,0 +1,38 @@
+use crate::{
+    bson::{Document, Value},
+    error::CommandResult,
+    storage::Storage,
+};
+
+use super::{get_array, get_bool, namespace, write_error, wrong_type};
+
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let documents = get_array(command, "insert", "documents")?;
+    let ordered = get_bool(command, "insert", "ordered", true)?;
+    let collection = storage.collection_mut(&namespace);
+
+    let mut n = 0;
+    let mut write_errors = Vec::new();
+    for (index, doc) in documents.iter().enumerate() {
+        let Value::Document(doc) = doc else {
+            return Err(wrong_type("insert", "documents", doc, "object"));
+        };
+        match collection.insert(doc.clone()) {
+            Ok(_) => n += 1,
+            Err(err) => {
+                write_errors.push(write_error(index, &err));
+                if ordered {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut reply = Document::new();
+    reply.insert("n", Value::Int32(n));
+    if !write_errors.is_empty() {
+        reply.insert("writeErrors", Value::from(write_errors));
+    }
+    Ok(reply)
+}
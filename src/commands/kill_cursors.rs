@@ -0,0 +1,33 @@
+use crate::{
+    bson::{Document, Value},
+    cursor::CursorManager,
+    error::CommandResult,
+};
+
+use super::{get_array, namespace, session_id, wrong_type};
+
+pub fn run(cursors: &mut CursorManager, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let ids = get_array(command, "killCursors", "cursors")?;
+    let session = session_id(command);
+
+    let mut killed = Vec::new();
+    let mut not_found = Vec::new();
+    for id in ids {
+        let Value::Int64(id) = id else {
+            return Err(wrong_type("killCursors", "cursors", id, "long"));
+        };
+        if cursors.kill(*id, &namespace, session.as_ref())? {
+            killed.push(Value::Int64(*id));
+        } else {
+            not_found.push(Value::Int64(*id));
+        }
+    }
+
+    let mut reply = Document::new();
+    reply.insert("cursorsKilled", Value::from(killed));
+    reply.insert("cursorsNotFound", Value::from(not_found));
+    reply.insert("cursorsAlive", Value::from(Vec::new()));
+    reply.insert("cursorsUnknown", Value::from(Vec::new()));
+    Ok(reply)
+}
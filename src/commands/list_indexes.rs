@@ -0,0 +1,93 @@
+use crate::{
+    bson::{Document, Value},
+    cursor::{CursorManager, CursorOptions},
+    error::{CommandError, CommandResult, ErrorCode},
+    storage::Storage,
+};
+
+use super::{cursor_reply, get_count, namespace, session_id, wrong_type};
+
+pub fn run(
+    storage: &Storage,
+    cursors: &mut CursorManager,
+    db: &str,
+    command: &Document,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let batch_size = match command.get("cursor") {
+        Some(Value::Document(cursor)) => get_count(cursor, "listIndexes.cursor", "batchSize")?,
+        Some(value) => return Err(wrong_type("listIndexes", "cursor", value, "object")),
+        None => None,
+    };
+    let Some(collection) = storage.collection(&namespace) else {
+        return Err(CommandError::new(
+            ErrorCode::NamespaceNotFound,
+            format!("ns does not exist: {}", namespace),
+        ));
+    };
+    let specs: Vec<_> = collection
+        .indexes()
+        .iter()
+        .map(|index| Ok(index.spec()))
+        .collect();
+
+    // Like mongod, the cursor belongs to a pseudo-collection of the database
+    let (_, collection) = namespace.split_once('.').unwrap_or_default();
+    let cursor_namespace = format!("{}.$cmd.listIndexes.{}", db, collection);
+    let options = CursorOptions {
+        batch_size,
+        session: session_id(command),
+        ..Default::default()
+    };
+    let (batch, id) = cursors.open(&cursor_namespace, specs, options)?;
+    Ok(cursor_reply(&cursor_namespace, id, "firstBatch", batch))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bson::json::doc, storage::Index};
+
+    #[test]
+    fn test_list_indexes() {
+        let mut storage = Storage::new();
+        let mut cursors = CursorManager::default();
+        let command = doc("{ listIndexes: 'c', cursor: { batchSize: 1 } }");
+        assert_eq!(
+            run(&storage, &mut cursors, "test", &command)
+                .unwrap_err()
+                .code,
+            ErrorCode::NamespaceNotFound
+        );
+
+        let collection = storage.collection_mut("test.c");
+        let index = Index::parse(&doc("{ key: { a: -1, 'b.c': 1 } }")).unwrap();
+        collection.add_index(collection.build_index(index).unwrap());
+        let reply = run(&storage, &mut cursors, "test", &command).unwrap();
+        let Some(Value::Document(cursor)) = reply.get("cursor") else {
+            panic!("no cursor in {}", reply);
+        };
+        assert_eq!(
+            cursor.get("firstBatch"),
+            Some(&crate::bson::json::value(
+                "[{ v: 2, key: { _id: 1 }, name: '_id_' }]"
+            ))
+        );
+        assert_eq!(
+            cursor.get("ns"),
+            Some(&Value::String("test.$cmd.listIndexes.c".into()))
+        );
+        let Some(Value::Int64(id)) = cursor.get("id") else {
+            panic!("no cursor id in {}", reply);
+        };
+        let (batch, _) = cursors
+            .get_more(*id, "test.$cmd.listIndexes.c", None, None)
+            .unwrap();
+        assert_eq!(
+            batch,
+            vec![doc(
+                "{ v: 2, key: { a: -1, 'b.c': 1 }, name: 'a_-1_b.c_1' }"
+            )]
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! Database commands, as sent to the `<db>.$cmd` pseudo-collection. The first
+//! field of the command document names the command and usually holds the
+//! target collection.
+
+mod aggregate;
+mod coll_mod;
+mod count;
+mod create;
+mod create_indexes;
+mod current_op;
+mod delete;
+mod distinct;
+mod drop_indexes;
+mod explain;
+mod find;
+mod find_and_modify;
+mod get_more;
+mod insert;
+mod kill_cursors;
+mod list_indexes;
+mod update;
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    cursor::{CursorId, CursorManager},
+    error::{CommandError, CommandResult, ErrorCode},
+    storage::{Collection, OpId, Storage},
+};
+
+/// The outcome of a command: its reply, or the operation it waits for, which
+/// gives the reply when it ends.
+#[derive(Debug)]
+pub enum Reply<T = Document> {
+    Ready(T),
+    /// An index build.
+    Pending(OpId),
+}
+
+impl<T> Reply<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> Reply<U> {
+        match self {
+            Reply::Ready(reply) => Reply::Ready(f(reply)),
+            Reply::Pending(op_id) => Reply::Pending(op_id),
+        }
+    }
+}
+
+/// Runs a command sent by a client. `createIndexes` waits for its index
+/// builds, which take the collection a batch at a time; every other command
+/// replies right away.
+pub fn start_command(
+    storage: &mut Storage,
+    cursors: &mut CursorManager,
+    db: &str,
+    command: &Document,
+) -> Reply {
+    match command.first() {
+        Some((name, _)) if name == "createIndexes" => {
+            match create_indexes::run(storage, db, command) {
+                Ok(reply) => reply.map(|reply| command_reply(Ok(reply))),
+                Err(err) => Reply::Ready(error_reply(&err)),
+            }
+        }
+        _ => Reply::Ready(run_command(storage, cursors, db, command)),
+    }
+}
+
+/// Runs a command that replies right away, which is any but `createIndexes`,
+/// and builds the reply document.
+pub fn run_command(
+    storage: &mut Storage,
+    cursors: &mut CursorManager,
+    db: &str,
+    command: &Document,
+) -> Document {
+    command_reply(dispatch(storage, cursors, db, command))
+}
+
+/// The reply document of a command that ended, with `ok: 1` on success or
+/// the error details on failure.
+pub fn command_reply(result: CommandResult<Document>) -> Document {
+    match result {
+        Ok(mut reply) => {
+            reply.insert("ok", Value::Double(1.0));
+            reply
+        }
+        Err(err) => error_reply(&err),
+    }
+}
+
+fn dispatch(
+    storage: &mut Storage,
+    cursors: &mut CursorManager,
+    db: &str,
+    command: &Document,
+) -> CommandResult<Document> {
+    let name = command
+        .first()
+        .map(|(name, _)| name.as_str())
+        .unwrap_or_default();
+    match name {
+        "aggregate" => aggregate::run(storage, cursors, db, command),
+        "find" => find::run(storage, cursors, db, command),
+        "getMore" => get_more::run(cursors, db, command),
+        "killCursors" => kill_cursors::run(cursors, db, command),
+        "insert" => insert::run(storage, db, command),
+        "update" => update::run(storage, db, command),
+        "delete" => delete::run(storage, db, command),
+        "findAndModify" | "findandmodify" => find_and_modify::run(storage, db, command),
+        "create" => create::run(storage, db, command),
+        "listIndexes" => list_indexes::run(storage, cursors, db, command),
+        "dropIndexes" | "deleteIndexes" => drop_indexes::run(storage, db, command),
+        "collMod" => coll_mod::run(storage, db, command),
+        "count" => count::run(storage, db, command),
+        "currentOp" => current_op::run(storage, db, command),
+        "distinct" => distinct::run(storage, db, command),
+        "explain" => explain::run(storage, db, command),
+        name => Err(CommandError::new(
+            ErrorCode::CommandNotFound,
+            format!("no such command: '{}'", name),
+        )),
+    }
+}
+
+pub fn error_reply(err: &CommandError) -> Document {
+    let mut reply = Document::new();
+    reply.insert("ok", Value::Double(0.0));
+    reply.insert("errmsg", Value::String(err.message.clone()));
+    reply.insert("code", Value::Int32(err.code.code()));
+    reply.insert("codeName", Value::String(err.code.name()));
+    for (field, value) in err.info.iter() {
+        reply.insert(field.clone(), value.clone());
+    }
+    reply
+}
+
+/// The entry reported in `writeErrors` for a failed write at `index`.
+fn write_error(index: usize, err: &CommandError) -> Value {
+    let mut error = Document::new();
+    error.insert("index", Value::Int32(index as i32));
+    error.insert("code", Value::Int32(err.code.code()));
+    for (field, value) in err.info.iter() {
+        error.insert(field.clone(), value.clone());
+    }
+    error.insert("errmsg", Value::String(err.message.clone()));
+    Value::Document(error)
+}
+
+/// The namespace targeted by a command, taken from the value of its first
+/// field.
+fn namespace(db: &str, command: &Document) -> CommandResult<String> {
+    match command.first() {
+        Some((_, Value::String(collection))) if !collection.is_empty() => {
+            Ok(format!("{}.{}", db, collection))
+        }
+        Some((_, Value::String(_))) => Err(CommandError::new(
+            ErrorCode::InvalidNamespace,
+            format!("Invalid namespace specified '{}.'", db),
+        )),
+        Some((_, value)) => Err(CommandError::new(
+            ErrorCode::InvalidNamespace,
+            format!("collection name has invalid type {}", value.type_name()),
+        )),
+        None => Err(CommandError::new(
+            ErrorCode::InvalidNamespace,
+            "no collection name specified",
+        )),
+    }
+}
+
+fn wrong_type(command: &str, field: &str, value: &Value, expected: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::TypeMismatch,
+        format!(
+            "BSON field '{}.{}' is the wrong type '{}', expected type '{}'",
+            command,
+            field,
+            value.type_name(),
+            expected
+        ),
+    )
+}
+
+fn missing_field(command: &str, field: &str) -> CommandError {
+    CommandError::new(
+        ErrorCode::Location(40414),
+        format!(
+            "BSON field '{}.{}' is missing but a required field",
+            command, field
+        ),
+    )
+}
+
+fn get_array<'a>(command: &'a Document, name: &str, field: &str) -> CommandResult<&'a Vec<Value>> {
+    match command.get(field) {
+        Some(Value::Array(items)) => Ok(&items.0),
+        Some(value) => Err(wrong_type(name, field, value, "array")),
+        None => Err(missing_field(name, field)),
+    }
+}
+
+fn get_document<'a>(command: &'a Document, name: &str, field: &str) -> CommandResult<&'a Document> {
+    match command.get(field) {
+        Some(Value::Document(doc)) => Ok(doc),
+        Some(value) => Err(wrong_type(name, field, value, "object")),
+        None => Err(missing_field(name, field)),
+    }
+}
+
+/// Reads an optional boolean option. Like mongod, numbers are accepted too.
+fn get_bool(command: &Document, name: &str, field: &str, default: bool) -> CommandResult<bool> {
+    match command.get(field) {
+        None => Ok(default),
+        Some(
+            value @ (Value::Boolean(_) | Value::Int32(_) | Value::Int64(_) | Value::Double(_)),
+        ) => Ok(value.is_truthy()),
+        Some(value) => Err(wrong_type(name, field, value, "bool")),
+    }
+}
+
+/// Reads an optional non-negative integer option such as `limit` or
+/// `batchSize`.
+fn get_count(command: &Document, name: &str, field: &str) -> CommandResult<Option<usize>> {
+    match command.get(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) if value.is_number() => match value.as_i64() {
+            Some(n) if n >= 0 => Ok(Some(n as usize)),
+            _ => Err(CommandError::new(
+                ErrorCode::Location(51024),
+                format!(
+                    "BSON field '{}' value must be >= 0, actual value '{}'",
+                    field, value
+                ),
+            )),
+        },
+        Some(value) => Err(wrong_type(name, field, value, "long")),
+    }
+}
+
+/// Reads the optional `hint` naming the index a query must use, by name or
+/// key pattern.
+fn get_hint<'a>(command: &'a Document, name: &str) -> CommandResult<Option<&'a Value>> {
+    match command.get("hint") {
+        None => Ok(None),
+        Some(Value::Document(hint)) if hint.is_empty() => Ok(None),
+        Some(hint @ (Value::String(_) | Value::Document(_))) => Ok(Some(hint)),
+        Some(hint) => Err(wrong_type(name, "hint", hint, "[string, object]")),
+    }
+}
+
+/// Reads the optional `collation` of a command or statement, which defaults
+/// to the collation of the collection.
+fn get_collation(
+    command: &Document,
+    name: &str,
+    collection: Option<&Collection>,
+) -> CommandResult<Option<Collation>> {
+    match command.get("collation") {
+        None => Ok(collection.and_then(Collection::collation).cloned()),
+        Some(Value::Document(spec)) => Collation::parse(spec),
+        Some(value) => Err(wrong_type(name, "collation", value, "object")),
+    }
+}
+
+/// The `lsid.id` of the logical session a command runs in, if any.
+fn session_id(command: &Document) -> Option<Value> {
+    match command.get("lsid") {
+        Some(Value::Document(lsid)) => lsid.get("id").cloned(),
+        _ => None,
+    }
+}
+
+/// The `cursor` reply shared by commands returning cursors, with the batch
+/// under `firstBatch` or `nextBatch`.
+fn cursor_reply(
+    namespace: &str,
+    id: CursorId,
+    batch_field: &str,
+    batch: Vec<Document>,
+) -> Document {
+    let mut cursor = Document::new();
+    cursor.insert(
+        batch_field,
+        Value::from(batch.into_iter().map(Value::Document).collect::<Vec<_>>()),
+    );
+    cursor.insert("id", Value::Int64(id));
+    cursor.insert("ns", Value::String(namespace.to_string()));
+
+    let mut reply = Document::new();
+    reply.insert("cursor", Value::Document(cursor));
+    reply
+}
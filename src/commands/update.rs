@@ -0,0 +1,209 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::{planner, Matcher, Query},
+    storage::{Collection, Storage},
+    update::Update,
+};
+
+use super::{
+    explain::{single_statement, stage, Explain},
+    get_array, get_bool, get_collation, get_document, get_hint, namespace, write_error, wrong_type,
+};
+
+#[derive(Debug, Default)]
+struct UpdateResult {
+    matched: i32,
+    modified: i32,
+    upserted: Option<Value>,
+}
+
+pub fn run(storage: &mut Storage, db: &str, command: &Document) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let statements = get_array(command, "update", "updates")?;
+    let ordered = get_bool(command, "update", "ordered", true)?;
+
+    let mut n = 0;
+    let mut n_modified = 0;
+    let mut upserted = Vec::new();
+    let mut write_errors = Vec::new();
+    for (index, statement) in statements.iter().enumerate() {
+        let Value::Document(statement) = statement else {
+            return Err(wrong_type("update", "updates", statement, "object"));
+        };
+        match update_one_statement(storage, &namespace, statement) {
+            Ok(result) => {
+                n += result.matched;
+                n_modified += result.modified;
+                if let Some(id) = result.upserted {
+                    let mut entry = Document::new();
+                    entry.insert("index", Value::Int32(index as i32));
+                    entry.insert("_id", id);
+                    upserted.push(Value::Document(entry));
+                }
+            }
+            Err(err) => {
+                write_errors.push(write_error(index, &err));
+                if ordered {
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut reply = Document::new();
+    reply.insert("n", Value::Int32(n));
+    reply.insert("nModified", Value::Int32(n_modified));
+    if !upserted.is_empty() {
+        reply.insert("upserted", Value::from(upserted));
+    }
+    if !write_errors.is_empty() {
+        reply.insert("writeErrors", Value::from(write_errors));
+    }
+    Ok(reply)
+}
+
+/// A statement of an update command.
+struct UpdateStatement<'s> {
+    query: &'s Document,
+    update: Update,
+    upsert: bool,
+    multi: bool,
+    matcher: Matcher,
+    hint: Option<&'s Value>,
+}
+
+impl<'s> UpdateStatement<'s> {
+    fn parse(statement: &'s Document, collection: Option<&Collection>) -> CommandResult<Self> {
+        let query = get_document(statement, "update.updates", "q")?;
+        let Some(spec) = statement.get("u") else {
+            return Err(super::missing_field("update.updates", "u"));
+        };
+        let upsert = get_bool(statement, "update.updates", "upsert", false)?;
+        let multi = get_bool(statement, "update.updates", "multi", false)?;
+
+        let update = Update::parse(spec, statement.get("arrayFilters"))?;
+        if multi && update.is_replacement() {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                "multi update is not supported for replacement-style update",
+            ));
+        }
+        let collation = get_collation(statement, "update.updates", collection)?;
+        Ok(Self {
+            query,
+            update,
+            upsert,
+            multi,
+            matcher: Matcher::for_query(query)?.with_collation(collation.as_ref()),
+            hint: get_hint(statement, "update.updates")?,
+        })
+    }
+
+    fn plan_query(&self) -> Query<'_> {
+        Query {
+            hint: self.hint,
+            ..Query::new(&self.matcher)
+        }
+    }
+}
+
+fn update_one_statement(
+    storage: &mut Storage,
+    namespace: &str,
+    statement: &Document,
+) -> CommandResult<UpdateResult> {
+    let collection = storage.collection(namespace);
+    let statement = UpdateStatement::parse(statement, collection)?;
+    let planned = planner::plan(collection, &statement.plan_query())?;
+    let matches: Vec<_> = planned
+        .execution
+        .documents
+        .iter()
+        .map(|(id, doc)| (*id, statement.matcher.match_position(doc).flatten()))
+        .take(if statement.multi { usize::MAX } else { 1 })
+        .collect();
+
+    // A collection is only created by an upsert
+    let mut result = UpdateResult::default();
+    if matches.is_empty() {
+        if statement.upsert {
+            let doc = statement.update.upsert(statement.query)?;
+            result.matched = 1;
+            result.upserted = doc.get("_id").cloned();
+            storage.collection_mut(namespace).insert(doc)?;
+        }
+        return Ok(result);
+    }
+    let collection = storage.collection_mut(namespace);
+
+    for (id, position) in matches {
+        let mut doc = collection.get(id).expect("record was just found").clone();
+        result.matched += 1;
+        if statement.update.apply(&mut doc, position, false)? {
+            collection.replace(id, doc)?;
+            result.modified += 1;
+        }
+    }
+    Ok(result)
+}
+
+/// Explains an update of a single statement, without updating anything.
+pub fn explain(
+    storage: &Storage,
+    db: &str,
+    command: &Document,
+    explain: &Explain,
+) -> CommandResult<Document> {
+    let namespace = namespace(db, command)?;
+    let collection = storage.collection(&namespace);
+    let statement =
+        UpdateStatement::parse(single_statement(command, "update", "updates")?, collection)?;
+    let planned = planner::plan(collection, &statement.plan_query())?;
+    Ok(explain.plans(
+        &namespace,
+        collection,
+        &statement.matcher,
+        &planned,
+        0,
+        |_, input| {
+            let fields = vec![
+                ("isMulti", Value::Boolean(statement.multi)),
+                ("isUpsert", Value::Boolean(statement.upsert)),
+            ];
+            stage("UPDATE", fields, input)
+        },
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bson::json::doc, commands::delete};
+
+    #[test]
+    fn test_missing_collection_is_not_created() {
+        let mut storage = Storage::new();
+        let update =
+            |storage: &mut Storage, command: &str| run(storage, "test", &doc(command)).unwrap();
+        let reply = update(
+            &mut storage,
+            "{ update: 'c', updates: [{ q: { a: 1 }, u: { $set: { b: 1 } } }] }",
+        );
+        assert_eq!(reply.get("n"), Some(&Value::Int32(0)));
+        delete::run(
+            &mut storage,
+            "test",
+            &doc("{ delete: 'c', deletes: [{ q: {}, limit: 0 }] }"),
+        )
+        .unwrap();
+        assert!(storage.collection("test.c").is_none());
+
+        // Upserts still create it
+        update(
+            &mut storage,
+            "{ update: 'c', updates: [{ q: { a: 1 }, u: { $set: { b: 1 } }, upsert: true }] }",
+        );
+        assert_eq!(storage.collection("test.c").unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,359 @@
+//! Server-side cursors. A query's results are held by a cursor, in memory or
+//! spilled to disk by an aggregation, and returned in batches: the first
+//! with the command that opened it (`find`, `aggregate`), the rest through
+//! `getMore`, until the cursor is exhausted or killed. Cursors left idle for
+//! longer than the timeout are reaped.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    iter::Peekable,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+pub type CursorId = i64;
+
+/// How long a cursor may sit unused before it is reaped, like mongod's
+/// `cursorTimeoutMillis`.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The number of documents in a first batch when no `batchSize` is given.
+pub const DEFAULT_BATCH_SIZE: usize = 101;
+
+/// Batches stop growing once they reach this many bytes of documents.
+const MAX_BATCH_BYTES: usize = 16 * 1024 * 1024;
+
+/// The documents a cursor has left to return, which may be read back from
+/// disk as batches take them.
+type Documents = Peekable<Box<dyn Iterator<Item = CommandResult<Document>>>>;
+
+struct Cursor {
+    namespace: String,
+    documents: Documents,
+    /// The `lsid.id` of the session that opened the cursor.
+    session: Option<Value>,
+    no_timeout: bool,
+    last_used: Instant,
+}
+
+impl fmt::Debug for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cursor")
+            .field("namespace", &self.namespace)
+            .field("session", &self.session)
+            .field("no_timeout", &self.no_timeout)
+            .field("last_used", &self.last_used)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Options given when opening a cursor.
+#[derive(Debug, Default)]
+pub struct CursorOptions {
+    pub batch_size: Option<usize>,
+    pub single_batch: bool,
+    pub no_timeout: bool,
+    pub session: Option<Value>,
+}
+
+#[derive(Debug)]
+pub struct CursorManager {
+    cursors: HashMap<CursorId, Cursor>,
+    idle_timeout: Duration,
+    hasher: RandomState,
+    counter: u64,
+}
+
+impl Default for CursorManager {
+    fn default() -> Self {
+        Self::new(DEFAULT_IDLE_TIMEOUT)
+    }
+}
+
+impl CursorManager {
+    pub fn new(idle_timeout: Duration) -> Self {
+        Self {
+            cursors: HashMap::new(),
+            idle_timeout,
+            hasher: RandomState::new(),
+            counter: 0,
+        }
+    }
+
+    /// Random, positive and unused, so ids can't be guessed from one another.
+    fn next_id(&mut self) -> CursorId {
+        loop {
+            self.counter += 1;
+            let mut hasher = self.hasher.build_hasher();
+            hasher.write_u64(self.counter);
+            let id = (hasher.finish() & i64::MAX as u64) as CursorId;
+            if id != 0 && !self.cursors.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    /// Takes the first batch of `documents`, keeping the rest in a new cursor.
+    /// Returns the batch and the cursor id, which is 0 when everything fit.
+    pub fn open<I>(
+        &mut self,
+        namespace: &str,
+        documents: I,
+        options: CursorOptions,
+    ) -> CommandResult<(Vec<Document>, CursorId)>
+    where
+        I: IntoIterator<Item = CommandResult<Document>>,
+        I::IntoIter: 'static,
+    {
+        let documents: Box<dyn Iterator<Item = _>> = Box::new(documents.into_iter());
+        let mut documents = documents.peekable();
+        let batch = take_batch(
+            &mut documents,
+            Some(options.batch_size.unwrap_or(DEFAULT_BATCH_SIZE)),
+        )?;
+        if documents.peek().is_none() || options.single_batch {
+            return Ok((batch, 0));
+        }
+
+        let id = self.next_id();
+        self.cursors.insert(
+            id,
+            Cursor {
+                namespace: namespace.to_string(),
+                documents,
+                session: options.session,
+                no_timeout: options.no_timeout,
+                last_used: Instant::now(),
+            },
+        );
+        Ok((batch, id))
+    }
+
+    /// Returns the next batch of a cursor opened on `namespace` by `session`,
+    /// and whether the cursor is still open afterwards.
+    pub fn get_more(
+        &mut self,
+        id: CursorId,
+        namespace: &str,
+        batch_size: Option<usize>,
+        session: Option<&Value>,
+    ) -> CommandResult<(Vec<Document>, bool)> {
+        let Some(cursor) = self.cursors.get_mut(&id) else {
+            return Err(not_found(id));
+        };
+        if cursor.namespace != namespace {
+            return Err(CommandError::new(
+                ErrorCode::Unauthorized,
+                format!(
+                    "Requested getMore on namespace '{}', but cursor belongs to a different namespace {}",
+                    namespace, cursor.namespace
+                ),
+            ));
+        }
+        check_session("getMore", id, cursor.session.as_ref(), session)?;
+
+        let batch = take_batch(&mut cursor.documents, batch_size)?;
+        cursor.last_used = Instant::now();
+        if cursor.documents.peek().is_none() {
+            self.cursors.remove(&id);
+            return Ok((batch, false));
+        }
+        Ok((batch, true))
+    }
+
+    /// The namespace a cursor was opened on.
+    pub fn namespace(&self, id: CursorId) -> Option<&str> {
+        self.cursors
+            .get(&id)
+            .map(|cursor| cursor.namespace.as_str())
+    }
+
+    /// Kills a cursor opened on `namespace` by `session`, returning whether
+    /// it existed.
+    pub fn kill(
+        &mut self,
+        id: CursorId,
+        namespace: &str,
+        session: Option<&Value>,
+    ) -> CommandResult<bool> {
+        match self.cursors.get(&id) {
+            Some(cursor) if cursor.namespace == namespace => {
+                check_session("killCursors", id, cursor.session.as_ref(), session)?;
+                self.cursors.remove(&id);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Removes cursors idle for longer than the timeout, returning how many
+    /// were removed.
+    pub fn reap(&mut self, now: Instant) -> usize {
+        let before = self.cursors.len();
+        let timeout = self.idle_timeout;
+        self.cursors.retain(|_, cursor| {
+            cursor.no_timeout || now.saturating_duration_since(cursor.last_used) < timeout
+        });
+        before - self.cursors.len()
+    }
+}
+
+pub fn not_found(id: CursorId) -> CommandError {
+    CommandError::new(
+        ErrorCode::CursorNotFound,
+        format!("cursor id {} not found", id),
+    )
+}
+
+/// Checks that `command` runs on a cursor in the session that opened it.
+fn check_session(
+    command: &str,
+    id: CursorId,
+    owner: Option<&Value>,
+    session: Option<&Value>,
+) -> CommandResult<()> {
+    match (owner, session) {
+        (Some(owner), Some(session)) if owner.equals(session) => Ok(()),
+        (None, None) => Ok(()),
+        (Some(owner), None) => Err(CommandError::new(
+            ErrorCode::Location(50737),
+            format!(
+                "Cannot run {} on cursor {}, which was created in session {}, without an lsid",
+                command, id, owner
+            ),
+        )),
+        (Some(owner), Some(session)) => Err(CommandError::new(
+            ErrorCode::Location(50738),
+            format!(
+                "Cannot run {} on cursor {}, which was created in session {}, in session {}",
+                command, id, owner, session
+            ),
+        )),
+        (None, Some(session)) => Err(CommandError::new(
+            ErrorCode::Location(50736),
+            format!(
+                "Cannot run {} on cursor {}, which was not created in a session, in session {}",
+                command, id, session
+            ),
+        )),
+    }
+}
+
+/// Takes up to `limit` documents (all when `None`), stopping early once the
+/// batch reaches the size limit. A batch always holds at least one document
+/// when any are left, so an oversized document can't stall a cursor.
+fn take_batch(documents: &mut Documents, limit: Option<usize>) -> CommandResult<Vec<Document>> {
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut batch = Vec::new();
+    let mut bytes = 0;
+    while batch.len() < limit {
+        let size = match documents.peek() {
+            Some(Ok(next)) => next.to_bytes().len(),
+            // Taken below, to fail the batch
+            Some(Err(_)) => 0,
+            None => break,
+        };
+        if !batch.is_empty() && bytes + size > MAX_BATCH_BYTES {
+            break;
+        }
+        bytes += size;
+        batch.extend(documents.next().transpose()?);
+    }
+    Ok(batch)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn documents(n: i32) -> Vec<CommandResult<Document>> {
+        (0..n)
+            .map(|i| Ok(Document::from_iter([("_id".to_string(), Value::Int32(i))])))
+            .collect()
+    }
+
+    #[test]
+    fn test_batches_until_exhausted() {
+        let mut cursors = CursorManager::default();
+        let options = CursorOptions {
+            batch_size: Some(2),
+            ..Default::default()
+        };
+        let (batch, id) = cursors.open("test.c", documents(5), options).unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_ne!(id, 0);
+
+        let (batch, open) = cursors.get_more(id, "test.c", Some(2), None).unwrap();
+        assert_eq!((batch.len(), open), (2, true));
+        let (batch, open) = cursors.get_more(id, "test.c", None, None).unwrap();
+        assert_eq!((batch.len(), open), (1, false));
+
+        let err = cursors.get_more(id, "test.c", None, None).unwrap_err();
+        assert_eq!(err.code, ErrorCode::CursorNotFound);
+    }
+
+    #[test]
+    fn test_ownership_and_kill() {
+        let mut cursors = CursorManager::default();
+        let options = CursorOptions {
+            batch_size: Some(1),
+            session: Some(Value::Int32(1)),
+            ..Default::default()
+        };
+        let (_, id) = cursors.open("test.c", documents(3), options).unwrap();
+        let code = |result: CommandResult<_>| result.unwrap_err().code;
+        assert_eq!(
+            code(cursors.get_more(id, "test.other", None, Some(&Value::Int32(1)))),
+            ErrorCode::Unauthorized
+        );
+        assert_eq!(
+            code(cursors.get_more(id, "test.c", None, None)),
+            ErrorCode::Location(50737)
+        );
+        assert_eq!(
+            code(cursors.get_more(id, "test.c", None, Some(&Value::Int32(2)))),
+            ErrorCode::Location(50738)
+        );
+
+        let session = Some(&Value::Int32(1));
+        assert!(!cursors.kill(id, "test.other", session).unwrap());
+        assert_eq!(
+            cursors.kill(id, "test.c", None).unwrap_err().code,
+            ErrorCode::Location(50737)
+        );
+        assert_eq!(
+            cursors
+                .kill(id, "test.c", Some(&Value::Int32(2)))
+                .unwrap_err()
+                .code,
+            ErrorCode::Location(50738)
+        );
+        assert!(cursors.kill(id, "test.c", session).unwrap());
+        assert!(!cursors.kill(id, "test.c", session).unwrap());
+    }
+
+    #[test]
+    fn test_reaps_idle_cursors() {
+        let mut cursors = CursorManager::new(Duration::from_secs(60));
+        let options = |no_timeout| CursorOptions {
+            batch_size: Some(1),
+            no_timeout,
+            ..Default::default()
+        };
+        let (_, idle) = cursors
+            .open("test.c", documents(2), options(false))
+            .unwrap();
+        let (_, pinned) = cursors.open("test.c", documents(2), options(true)).unwrap();
+
+        assert_eq!(cursors.reap(Instant::now()), 0);
+        assert_eq!(cursors.reap(Instant::now() + Duration::from_secs(61)), 1);
+        assert!(cursors.get_more(idle, "test.c", None, None).is_err());
+        assert!(cursors.get_more(pinned, "test.c", None, None).is_ok());
+    }
+}
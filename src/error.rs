@@ -1,14 +1,26 @@
-use std::sync::mpsc;
+use std::{fmt, sync::mpsc};
 
-use crate::Message;
+use crate::{bson::Document, Message};
 
 #[derive(Debug)]
 pub enum Error {
     AcceptingConnection(std::io::Error),
     ReceiverHungup(mpsc::RecvError),
-    SenderError(mpsc::SendError<Message>),
+    Sender(mpsc::SendError<Message>),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::AcceptingConnection(err) => write!(f, "connection error: {}", err),
+            Error::ReceiverHungup(err) => write!(f, "receiver hung up: {}", err),
+            Error::Sender(err) => write!(f, "sender error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::AcceptingConnection(err)
@@ -23,8 +35,153 @@ impl From<mpsc::RecvError> for Error {
 
 impl From<mpsc::SendError<Message>> for Error {
     fn from(err: mpsc::SendError<Message>) -> Self {
-        Error::SenderError(err)
+        Error::Sender(err)
     }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
+
+/// Error codes returned to clients, numbered as in mongod's `error_codes.yml`
+/// so drivers can react to them. Errors that mongod raises with an ad hoc
+/// numeric code (reported as `Location<code>`) use `Location`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    InternalError,
+    BadValue,
+    FailedToParse,
+    Unauthorized,
+    TypeMismatch,
+    InvalidLength,
+    NamespaceNotFound,
+    IndexNotFound,
+    PathNotViable,
+    ConflictingUpdateOperators,
+    CursorNotFound,
+    NamespaceExists,
+    DollarPrefixedFieldName,
+    EmptyFieldName,
+    CommandNotFound,
+    ImmutableField,
+    CannotCreateIndex,
+    InvalidOptions,
+    InvalidNamespace,
+    IndexOptionsConflict,
+    IndexKeySpecsConflict,
+    InvalidPipelineOperator,
+    CannotIndexParallelArrays,
+    InvalidIndexSpecificationOption,
+    ConversionFailure,
+    IndexBuildAborted,
+    NoQueryExecutionPlans,
+    QueryExceededMemoryLimitNoDiskUseAllowed,
+    DuplicateKey,
+    BackgroundOperationInProgressForNamespace,
+    Location(i32),
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> i32 {
+        match self {
+            ErrorCode::InternalError => 1,
+            ErrorCode::BadValue => 2,
+            ErrorCode::FailedToParse => 9,
+            ErrorCode::Unauthorized => 13,
+            ErrorCode::TypeMismatch => 14,
+            ErrorCode::InvalidLength => 16,
+            ErrorCode::NamespaceNotFound => 26,
+            ErrorCode::IndexNotFound => 27,
+            ErrorCode::PathNotViable => 28,
+            ErrorCode::ConflictingUpdateOperators => 40,
+            ErrorCode::CursorNotFound => 43,
+            ErrorCode::NamespaceExists => 48,
+            ErrorCode::DollarPrefixedFieldName => 52,
+            ErrorCode::EmptyFieldName => 56,
+            ErrorCode::CommandNotFound => 59,
+            ErrorCode::ImmutableField => 66,
+            ErrorCode::CannotCreateIndex => 67,
+            ErrorCode::InvalidOptions => 72,
+            ErrorCode::InvalidNamespace => 73,
+            ErrorCode::IndexOptionsConflict => 85,
+            ErrorCode::IndexKeySpecsConflict => 86,
+            ErrorCode::InvalidPipelineOperator => 168,
+            ErrorCode::CannotIndexParallelArrays => 171,
+            ErrorCode::InvalidIndexSpecificationOption => 197,
+            ErrorCode::ConversionFailure => 241,
+            ErrorCode::IndexBuildAborted => 276,
+            ErrorCode::NoQueryExecutionPlans => 291,
+            ErrorCode::QueryExceededMemoryLimitNoDiskUseAllowed => 292,
+            ErrorCode::DuplicateKey => 11000,
+            ErrorCode::BackgroundOperationInProgressForNamespace => 12587,
+            ErrorCode::Location(code) => *code,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            ErrorCode::InternalError => "InternalError".to_string(),
+            ErrorCode::BadValue => "BadValue".to_string(),
+            ErrorCode::FailedToParse => "FailedToParse".to_string(),
+            ErrorCode::Unauthorized => "Unauthorized".to_string(),
+            ErrorCode::TypeMismatch => "TypeMismatch".to_string(),
+            ErrorCode::InvalidLength => "InvalidLength".to_string(),
+            ErrorCode::NamespaceNotFound => "NamespaceNotFound".to_string(),
+            ErrorCode::IndexNotFound => "IndexNotFound".to_string(),
+            ErrorCode::PathNotViable => "PathNotViable".to_string(),
+            ErrorCode::ConflictingUpdateOperators => "ConflictingUpdateOperators".to_string(),
+            ErrorCode::CursorNotFound => "CursorNotFound".to_string(),
+            ErrorCode::NamespaceExists => "NamespaceExists".to_string(),
+            ErrorCode::DollarPrefixedFieldName => "DollarPrefixedFieldName".to_string(),
+            ErrorCode::EmptyFieldName => "EmptyFieldName".to_string(),
+            ErrorCode::CommandNotFound => "CommandNotFound".to_string(),
+            ErrorCode::ImmutableField => "ImmutableField".to_string(),
+            ErrorCode::CannotCreateIndex => "CannotCreateIndex".to_string(),
+            ErrorCode::InvalidOptions => "InvalidOptions".to_string(),
+            ErrorCode::InvalidNamespace => "InvalidNamespace".to_string(),
+            ErrorCode::IndexOptionsConflict => "IndexOptionsConflict".to_string(),
+            ErrorCode::IndexKeySpecsConflict => "IndexKeySpecsConflict".to_string(),
+            ErrorCode::InvalidPipelineOperator => "InvalidPipelineOperator".to_string(),
+            ErrorCode::CannotIndexParallelArrays => "CannotIndexParallelArrays".to_string(),
+            ErrorCode::InvalidIndexSpecificationOption => {
+                "InvalidIndexSpecificationOption".to_string()
+            }
+            ErrorCode::ConversionFailure => "ConversionFailure".to_string(),
+            ErrorCode::IndexBuildAborted => "IndexBuildAborted".to_string(),
+            ErrorCode::NoQueryExecutionPlans => "NoQueryExecutionPlans".to_string(),
+            ErrorCode::QueryExceededMemoryLimitNoDiskUseAllowed => {
+                "QueryExceededMemoryLimitNoDiskUseAllowed".to_string()
+            }
+            ErrorCode::DuplicateKey => "DuplicateKey".to_string(),
+            ErrorCode::BackgroundOperationInProgressForNamespace => {
+                "BackgroundOperationInProgressForNamespace".to_string()
+            }
+            ErrorCode::Location(code) => format!("Location{}", code),
+        }
+    }
+}
+
+/// An error raised while executing a command, reported to the client as
+/// `{ ok: 0, errmsg, code, codeName }`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Further fields of the reply, such as the `keyPattern` and `keyValue`
+    /// of a duplicate key.
+    pub info: Document,
+}
+
+impl CommandError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            info: Document::new(),
+        }
+    }
+
+    pub fn with_info(self, info: Document) -> Self {
+        Self { info, ..self }
+    }
+}
+
+pub type CommandResult<T> = std::result::Result<T, CommandError>;
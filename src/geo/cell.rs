@@ -0,0 +1,145 @@
+//! The cells geo indexes key geometries by. The space of an index is split
+//! into four quadrants, each of them into four again, and so on; a cell is
+//! named by the digits of the quadrants leading to it, so that the name of a
+//! cell starts with the names of the cells holding it.
+
+use super::geometry::Point;
+
+/// The most cells a geometry or query region is covered with.
+const MAX_CELLS: u64 = 16;
+
+/// An axis-aligned box.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub min: Point,
+    pub max: Point,
+}
+
+impl Rect {
+    /// The smallest box holding some points.
+    pub fn around(points: &[Point]) -> Rect {
+        let mut rect = Rect {
+            min: Point {
+                x: f64::INFINITY,
+                y: f64::INFINITY,
+            },
+            max: Point {
+                x: f64::NEG_INFINITY,
+                y: f64::NEG_INFINITY,
+            },
+        };
+        for point in points {
+            rect.min.x = rect.min.x.min(point.x);
+            rect.min.y = rect.min.y.min(point.y);
+            rect.max.x = rect.max.x.max(point.x);
+            rect.max.y = rect.max.y.max(point.y);
+        }
+        rect
+    }
+}
+
+/// The space of a geo index, split into cells down to `levels` levels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+    min: Point,
+    max: Point,
+    levels: u32,
+}
+
+impl Grid {
+    /// The longitudes and latitudes of a `2dsphere` index.
+    pub fn sphere() -> Grid {
+        Grid {
+            min: Point {
+                x: -180.0,
+                y: -90.0,
+            },
+            max: Point { x: 180.0, y: 90.0 },
+            levels: 24,
+        }
+    }
+
+    /// The square from `min` to `max` of a `2d` index, with cells `bits`
+    /// levels deep.
+    pub fn flat(min: f64, max: f64, bits: u32) -> Grid {
+        Grid {
+            min: Point { x: min, y: min },
+            max: Point { x: max, y: max },
+            levels: bits,
+        }
+    }
+
+    /// The column or row of the cells at `level` holding a coordinate.
+    fn position(&self, value: f64, min: f64, max: f64, level: u32) -> u64 {
+        let count = 1u64 << level;
+        let position = ((value - min) / (max - min) * count as f64).floor();
+        position.clamp(0.0, (count - 1) as f64) as u64
+    }
+
+    fn name(column: u64, row: u64, level: u32) -> String {
+        (0..level)
+            .rev()
+            .map(|bit| {
+                let digit = (column >> bit & 1) + 2 * (row >> bit & 1);
+                char::from(b'0' + digit as u8)
+            })
+            .collect()
+    }
+
+    /// The cells covering a box, at the deepest level taking no more than
+    /// a handful of them. A point is covered by a single cell of the last
+    /// level.
+    pub fn covering(&self, rect: &Rect) -> Vec<String> {
+        let span = |level: u32| {
+            let columns = (
+                self.position(rect.min.x, self.min.x, self.max.x, level),
+                self.position(rect.max.x, self.min.x, self.max.x, level),
+            );
+            let rows = (
+                self.position(rect.min.y, self.min.y, self.max.y, level),
+                self.position(rect.max.y, self.min.y, self.max.y, level),
+            );
+            (columns, rows)
+        };
+        let count = |((x0, x1), (y0, y1)): ((u64, u64), (u64, u64))| {
+            (x1 - x0 + 1).saturating_mul(y1 - y0 + 1)
+        };
+        let level = (1..=self.levels)
+            .take_while(|level| count(span(*level)) <= MAX_CELLS)
+            .last()
+            .unwrap_or(0);
+        let ((x0, x1), (y0, y1)) = span(level);
+        (y0..=y1)
+            .flat_map(|row| (x0..=x1).map(move |column| Self::name(column, row, level)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_covering() {
+        let grid = Grid::flat(0.0, 16.0, 4);
+        let point = |x, y| Rect {
+            min: Point { x, y },
+            max: Point { x, y },
+        };
+        assert_eq!(grid.covering(&point(0.0, 0.0)), ["0000"]);
+        assert_eq!(grid.covering(&point(15.5, 8.0)), ["3111"]);
+        // The last edge belongs to the last cell
+        assert_eq!(grid.covering(&point(16.0, 16.0)), ["3333"]);
+
+        let rect = Rect::around(&[Point { x: 1.0, y: 1.0 }, Point { x: 6.0, y: 3.0 }]);
+        assert_eq!(rect.max, Point { x: 6.0, y: 3.0 });
+        assert_eq!(
+            grid.covering(&rect),
+            ["000", "001", "010", "011", "002", "003", "012", "013"]
+        );
+        // The whole grid is covered by the cells of the second level
+        let all = Rect::around(&[Point { x: 0.0, y: 0.0 }, Point { x: 16.0, y: 16.0 }]);
+        assert_eq!(grid.covering(&all).len(), 16);
+        assert_eq!(Grid::sphere().covering(&point(0.0, 0.0))[0].len(), 24);
+    }
+}
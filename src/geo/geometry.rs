@@ -0,0 +1,299 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+fn bad_value(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::BadValue, message)
+}
+
+/// A position: longitude and latitude in degrees on the sphere, or `x` and
+/// `y` on the plane of a `2d` index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl Point {
+    /// A legacy coordinate pair: an array or document whose first two values
+    /// are numbers, as in `[x, y]` or `{ lng: x, lat: y }`.
+    pub fn parse_legacy(value: &Value) -> Option<Point> {
+        let mut values: Box<dyn Iterator<Item = &Value>> = match value {
+            Value::Array(items) => Box::new(items.0.iter()),
+            Value::Document(doc) if !doc.contains_key("type") => {
+                Box::new(doc.iter().map(|(_, value)| value))
+            }
+            _ => return None,
+        };
+        let x = values.next()?.as_f64()?;
+        let y = values.next()?.as_f64()?;
+        Some(Point { x, y })
+    }
+
+    /// A GeoJSON position, `[longitude, latitude]`.
+    fn parse_position(value: &Value) -> CommandResult<Point> {
+        let coordinates = match value.as_array() {
+            Some(coordinates) if coordinates.len() >= 2 => coordinates,
+            _ => {
+                return Err(bad_value(format!(
+                    "GeoJSON coordinates must be an array of at least two numbers, not {}",
+                    value
+                )));
+            }
+        };
+        let (Some(x), Some(y)) = (coordinates[0].as_f64(), coordinates[1].as_f64()) else {
+            return Err(bad_value(format!(
+                "GeoJSON coordinates must be numbers: {}",
+                value
+            )));
+        };
+        Point::on_sphere(x, y)
+    }
+
+    /// A longitude and latitude, which must be within their ranges.
+    pub fn on_sphere(x: f64, y: f64) -> CommandResult<Point> {
+        if !(-180.0..=180.0).contains(&x) || !(-90.0..=90.0).contains(&y) {
+            return Err(bad_value(format!(
+                "longitude/latitude is out of bounds, lng: {} lat: {}",
+                x, y
+            )));
+        }
+        Ok(Point { x, y })
+    }
+}
+
+/// A GeoJSON geometry, or a legacy coordinate pair as a point.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Geometry {
+    Point(Point),
+    LineString(Vec<Point>),
+    /// The outer ring, then the holes. Rings are closed: their last vertex
+    /// repeats the first.
+    Polygon(Vec<Vec<Point>>),
+    MultiPoint(Vec<Point>),
+    MultiLineString(Vec<Vec<Point>>),
+    MultiPolygon(Vec<Vec<Vec<Point>>>),
+    /// A GeoJSON `GeometryCollection`.
+    Collection(Vec<Geometry>),
+}
+
+impl Geometry {
+    /// The geometry a stored value holds: a GeoJSON object or a legacy
+    /// coordinate pair. Other values hold none.
+    pub fn parse(value: &Value) -> CommandResult<Option<Geometry>> {
+        match value {
+            Value::Document(doc) if doc.contains_key("type") => Ok(Some(Self::parse_geojson(doc)?)),
+            value => Ok(Point::parse_legacy(value).map(Geometry::Point)),
+        }
+    }
+
+    /// Parses a GeoJSON object such as
+    /// `{ type: 'Point', coordinates: [-73.97, 40.77] }`.
+    pub fn parse_geojson(doc: &Document) -> CommandResult<Geometry> {
+        match doc.get("crs") {
+            None => {}
+            Some(Value::Document(crs)) if is_default_crs(crs) => {}
+            Some(crs) => {
+                return Err(bad_value(format!("unknown GeoJSON crs: {}", crs)));
+            }
+        }
+        let kind = match doc.get("type") {
+            Some(Value::String(kind)) => kind.as_str(),
+            _ => return Err(bad_value(format!("unknown GeoJSON type: {}", doc))),
+        };
+        if kind == "GeometryCollection" {
+            let Some(geometries) = doc.get("geometries").and_then(Value::as_array) else {
+                return Err(bad_value("GeometryCollection geometries must be an array"));
+            };
+            let mut parsed = Vec::new();
+            for geometry in geometries {
+                match geometry {
+                    Value::Document(geometry) => parsed.push(Self::parse_geojson(geometry)?),
+                    geometry => {
+                        return Err(bad_value(format!(
+                            "Element of geometries is not an object: {}",
+                            geometry
+                        )));
+                    }
+                }
+            }
+            return Ok(Geometry::Collection(parsed));
+        }
+        let coordinates = match doc.get("coordinates") {
+            Some(coordinates) if coordinates.as_array().is_some() => coordinates,
+            _ => {
+                return Err(bad_value(format!("coordinates must be an array: {}", doc)));
+            }
+        };
+        Ok(match kind {
+            "Point" => Geometry::Point(Point::parse_position(coordinates)?),
+            "LineString" => Geometry::LineString(parse_line(coordinates)?),
+            "Polygon" => Geometry::Polygon(parse_polygon(coordinates)?),
+            "MultiPoint" => Geometry::MultiPoint(parse_list(coordinates, Point::parse_position)?),
+            "MultiLineString" => Geometry::MultiLineString(parse_list(coordinates, parse_line)?),
+            "MultiPolygon" => Geometry::MultiPolygon(parse_list(coordinates, parse_polygon)?),
+            kind => return Err(bad_value(format!("unknown GeoJSON type: {}", kind))),
+        })
+    }
+
+    /// Every vertex of the geometry.
+    pub fn points(&self) -> Vec<Point> {
+        match self {
+            Geometry::Point(point) => vec![*point],
+            Geometry::LineString(points) | Geometry::MultiPoint(points) => points.clone(),
+            Geometry::Polygon(rings) | Geometry::MultiLineString(rings) => {
+                rings.iter().flatten().copied().collect()
+            }
+            Geometry::MultiPolygon(polygons) => {
+                polygons.iter().flatten().flatten().copied().collect()
+            }
+            Geometry::Collection(geometries) => {
+                geometries.iter().flat_map(Geometry::points).collect()
+            }
+        }
+    }
+
+    /// Every edge of the lines and polygon rings of the geometry.
+    pub fn edges(&self) -> Vec<(Point, Point)> {
+        let line_edges = |points: &Vec<Point>| {
+            points
+                .windows(2)
+                .map(|pair| (pair[0], pair[1]))
+                .collect::<Vec<_>>()
+        };
+        match self {
+            Geometry::Point(_) | Geometry::MultiPoint(_) => Vec::new(),
+            Geometry::LineString(points) => line_edges(points),
+            Geometry::Polygon(rings) | Geometry::MultiLineString(rings) => {
+                rings.iter().flat_map(line_edges).collect()
+            }
+            Geometry::MultiPolygon(polygons) => {
+                polygons.iter().flatten().flat_map(line_edges).collect()
+            }
+            Geometry::Collection(geometries) => {
+                geometries.iter().flat_map(Geometry::edges).collect()
+            }
+        }
+    }
+
+    /// The polygons of the geometry, each as its rings.
+    pub fn polygons(&self) -> Vec<&[Vec<Point>]> {
+        match self {
+            Geometry::Polygon(rings) => vec![rings.as_slice()],
+            Geometry::MultiPolygon(polygons) => polygons.iter().map(Vec::as_slice).collect(),
+            Geometry::Collection(geometries) => {
+                geometries.iter().flat_map(Geometry::polygons).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// The coordinate reference system GeoJSON uses by default, WGS84.
+fn is_default_crs(crs: &Document) -> bool {
+    let name = match crs.get("properties") {
+        Some(Value::Document(properties)) => properties.get("name"),
+        _ => None,
+    };
+    matches!(
+        name,
+        Some(Value::String(name))
+            if name == "EPSG:4326" || name == "urn:ogc:def:crs:OGC:1.3:CRS84"
+    )
+}
+
+fn parse_list<T>(
+    value: &Value,
+    parse: impl Fn(&Value) -> CommandResult<T>,
+) -> CommandResult<Vec<T>> {
+    let Some(items) = value.as_array() else {
+        return Err(bad_value(format!(
+            "coordinates must be an array: {}",
+            value
+        )));
+    };
+    items.iter().map(parse).collect()
+}
+
+fn parse_line(value: &Value) -> CommandResult<Vec<Point>> {
+    let points = parse_list(value, Point::parse_position)?;
+    if points.len() < 2 {
+        return Err(bad_value(format!(
+            "GeoJSON LineString must have at least 2 vertices: {}",
+            value
+        )));
+    }
+    Ok(points)
+}
+
+fn parse_polygon(value: &Value) -> CommandResult<Vec<Vec<Point>>> {
+    let rings = parse_list(value, |ring| {
+        let points = parse_list(ring, Point::parse_position)?;
+        if points.len() < 4 {
+            return Err(bad_value(format!(
+                "Loop must have at least 3 different vertices: {}",
+                ring
+            )));
+        }
+        if points.first() != points.last() {
+            return Err(bad_value(format!(
+                "Loop is not closed, first vertex does not equal last vertex: {}",
+                ring
+            )));
+        }
+        Ok(points)
+    })?;
+    if rings.is_empty() {
+        return Err(bad_value("Polygon has no loops."));
+    }
+    Ok(rings)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    #[test]
+    fn test_parse_geometry() {
+        assert_eq!(
+            Geometry::parse(&value("[1, 2]")).unwrap(),
+            Some(Geometry::Point(Point { x: 1.0, y: 2.0 }))
+        );
+        assert_eq!(
+            Geometry::parse(&value("{ lng: 3, lat: 4 }")).unwrap(),
+            Some(Geometry::Point(Point { x: 3.0, y: 4.0 }))
+        );
+        assert_eq!(Geometry::parse(&value("'here'")).unwrap(), None);
+
+        let polygon = Geometry::parse_geojson(&doc(
+            "{ type: 'Polygon', coordinates: [[[0, 0], [4, 0], [4, 4], [0, 0]]] }",
+        ))
+        .unwrap();
+        assert_eq!(polygon.points().len(), 4);
+        assert_eq!(polygon.edges().len(), 3);
+        assert_eq!(polygon.polygons().len(), 1);
+        let collection = Geometry::parse_geojson(&doc(
+            "{ type: 'GeometryCollection', geometries: [{ type: 'Point', coordinates: [1, 1] }, \
+             { type: 'LineString', coordinates: [[0, 0], [1, 1]] }] }",
+        ))
+        .unwrap();
+        assert_eq!(collection.edges().len(), 1);
+
+        for invalid in [
+            "{ type: 'Point', coordinates: [200, 0] }",
+            "{ type: 'Point', coordinates: ['a', 0] }",
+            "{ type: 'LineString', coordinates: [[0, 0]] }",
+            "{ type: 'Polygon', coordinates: [[[0, 0], [1, 0], [1, 1], [0, 1]]] }",
+            "{ type: 'Polygon', coordinates: [[[0, 0], [1, 0], [0, 0]]] }",
+            "{ type: 'Circle', coordinates: [0, 0] }",
+        ] {
+            assert!(
+                Geometry::parse_geojson(&doc(invalid)).is_err(),
+                "{}",
+                invalid
+            );
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{
+    cell::{Grid, Rect},
+    geometry::{Geometry, Point},
+    query::locations,
+    sphere,
+};
+
+/// The version of the `2dsphere` index format, as mongod's current one.
+pub const SPHERE_INDEX_VERSION: i32 = 3;
+
+fn cannot_create(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::CannotCreateIndex, message)
+}
+
+/// The definition of the geo field of an index: a `2dsphere` field keyed by
+/// the cells of the GeoJSON geometries or legacy points it holds, or a `2d`
+/// field keyed by the cells of the points on a flat square.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoIndex {
+    /// The position of the geo field in the key pattern.
+    field: usize,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    Sphere,
+    Flat {
+        bits: u32,
+        min: f64,
+        max: f64,
+        /// The options as given, for `listIndexes`.
+        options: Document,
+    },
+}
+
+impl GeoIndex {
+    /// Parses the options of an index whose field at `field` is given the
+    /// plugin `plugin`, `"2dsphere"` or `"2d"`.
+    pub fn parse(field: usize, plugin: &str, spec: &Document) -> CommandResult<Self> {
+        if plugin == "2dsphere" {
+            match spec.get("2dsphereIndexVersion") {
+                None => {}
+                Some(version) if version.as_i64() == Some(SPHERE_INDEX_VERSION as i64) => {}
+                Some(version) => {
+                    return Err(cannot_create(format!(
+                        "unsupported geo index version {{ 2dsphereIndexVersion : {} }}, only version {} is supported",
+                        version, SPHERE_INDEX_VERSION
+                    )));
+                }
+            }
+            return Ok(Self {
+                field,
+                kind: Kind::Sphere,
+            });
+        }
+        if field != 0 {
+            return Err(cannot_create("2d has to be first in index"));
+        }
+        let mut options = Document::new();
+        let mut option = |name: &str, default: f64| -> CommandResult<f64> {
+            match spec.get(name) {
+                None => Ok(default),
+                Some(value) => {
+                    let number = value
+                        .as_f64()
+                        .filter(|_| value.is_number())
+                        .ok_or_else(|| {
+                            CommandError::new(
+                                ErrorCode::TypeMismatch,
+                                format!("{} must be a number, not {}", name, value.type_name()),
+                            )
+                        })?;
+                    options.insert(name, value.clone());
+                    Ok(number)
+                }
+            }
+        };
+        let bits = option("bits", 26.0)?;
+        let min = option("min", -180.0)?;
+        let max = option("max", 180.0)?;
+        if !(1.0..=32.0).contains(&bits) {
+            return Err(cannot_create(format!(
+                "bits in geo index must be between 1 and 32, not {}",
+                bits
+            )));
+        }
+        if min.partial_cmp(&max) != Some(Ordering::Less) {
+            return Err(cannot_create(format!(
+                "region for hash must be valid and have positive area, but [{}, {}] was specified",
+                min, max
+            )));
+        }
+        Ok(Self {
+            field,
+            kind: Kind::Flat {
+                bits: bits as u32,
+                min,
+                max,
+                options,
+            },
+        })
+    }
+
+    /// Adds the options of the index to its `listIndexes` specification.
+    pub fn add_spec(&self, spec: &mut Document) {
+        match &self.kind {
+            Kind::Sphere => {
+                spec.insert("2dsphereIndexVersion", Value::Int32(SPHERE_INDEX_VERSION));
+            }
+            Kind::Flat { options, .. } => {
+                for (name, value) in options.iter() {
+                    spec.insert(name.clone(), value.clone());
+                }
+            }
+        }
+    }
+
+    /// The position of the geo field in the key pattern.
+    pub fn field(&self) -> usize {
+        self.field
+    }
+
+    /// Whether this is a `2dsphere` index rather than a `2d` one.
+    pub fn is_sphere(&self) -> bool {
+        self.kind == Kind::Sphere
+    }
+
+    pub fn grid(&self) -> Grid {
+        match &self.kind {
+            Kind::Sphere => Grid::sphere(),
+            Kind::Flat { bits, min, max, .. } => Grid::flat(*min, *max, *bits),
+        }
+    }
+
+    /// The cells covering the geometries a document holds at the geo field
+    /// `path`. A document without the field isn't indexed; like mongod, one
+    /// with a value that isn't a geometry can't be stored at all.
+    pub fn cells(&self, doc: &Document, path: &[String]) -> CommandResult<Vec<String>> {
+        let grid = self.grid();
+        let mut cells = Vec::new();
+        for value in locations(doc, path) {
+            for rect in self.bounds(doc, value)? {
+                cells.extend(grid.covering(&rect));
+            }
+        }
+        cells.sort();
+        cells.dedup();
+        Ok(cells)
+    }
+
+    /// The boxes holding the geometries of one value of the geo field.
+    fn bounds(&self, doc: &Document, value: &Value) -> CommandResult<Vec<Rect>> {
+        if matches!(value, Value::Null | Value::Undefined) {
+            return Ok(Vec::new());
+        }
+        let point = Point::parse_legacy(value);
+        let items = match value {
+            Value::Array(items) if point.is_none() => items.0.iter().collect(),
+            value => vec![value],
+        };
+        match &self.kind {
+            Kind::Sphere => items
+                .into_iter()
+                .map(|item| {
+                    let geometry = match Geometry::parse(item) {
+                        Ok(Some(Geometry::Point(point))) => {
+                            Point::on_sphere(point.x, point.y).map(Geometry::Point)
+                        }
+                        Ok(Some(geometry)) => Ok(geometry),
+                        Ok(None) => Err(CommandError::new(
+                            ErrorCode::BadValue,
+                            format!("Point must be an array or object: {}", item),
+                        )),
+                        Err(error) => Err(error),
+                    };
+                    match geometry {
+                        Ok(geometry) => Ok(sphere::bounds(&geometry)),
+                        Err(error) => Err(CommandError::new(
+                            ErrorCode::Location(16755),
+                            format!("Can't extract geo keys: {}  {}", doc, error.message),
+                        )),
+                    }
+                })
+                .collect(),
+            Kind::Flat { min, max, .. } => items
+                .into_iter()
+                .map(|item| {
+                    let Some(point) = Point::parse_legacy(item) else {
+                        return Err(CommandError::new(
+                            ErrorCode::Location(13026),
+                            format!(
+                                "geo values must be 'legacy coordinate pairs' for 2d indexes: {}",
+                                item
+                            ),
+                        ));
+                    };
+                    let inside = |n: f64| *min <= n && n < *max;
+                    if !inside(point.x) || !inside(point.y) {
+                        return Err(CommandError::new(
+                            ErrorCode::Location(13027),
+                            format!(
+                                "point not in interval of [ {}, {} ) :: caused by :: {}",
+                                min, max, item
+                            ),
+                        ));
+                    }
+                    Ok(Rect {
+                        min: point,
+                        max: point,
+                    })
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,15 @@
+//! Geospatial indexes and queries. Geometries are GeoJSON objects or legacy
+//! coordinate pairs; on the sphere they are WGS84 longitudes and latitudes,
+//! and a `2d` index puts legacy points on a flat square instead. Geo indexes
+//! key a geometry by the cells of a grid covering it, and queries look up the
+//! cells covering the region they ask about before testing the geometries
+//! found.
+
+mod cell;
+mod geometry;
+mod index;
+mod query;
+mod sphere;
+
+pub use index::GeoIndex;
+pub use query::{locations, GeoQuery, Near};
@@ -0,0 +1,431 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{
+    cell::Rect,
+    geometry::{Geometry, Point},
+    sphere::{self, EARTH_RADIUS_METERS},
+};
+
+fn bad_value(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::BadValue, message)
+}
+
+/// The values at `path` of a document, looking into arrays of documents
+/// along the way. Arrays at the end of the path are kept whole, as they may
+/// be points.
+pub fn locations<'d>(doc: &'d Document, path: &[String]) -> Vec<&'d Value> {
+    let mut values = Vec::new();
+    if let Some(value) = doc.get(&path[0]) {
+        collect(value, &path[1..], &mut values);
+    }
+    values
+}
+
+fn collect<'d>(value: &'d Value, path: &[String], values: &mut Vec<&'d Value>) {
+    let Some((first, rest)) = path.split_first() else {
+        values.push(value);
+        return;
+    };
+    match value {
+        Value::Document(doc) => {
+            if let Some(value) = doc.get(first) {
+                collect(value, rest, values);
+            }
+        }
+        Value::Array(items) => {
+            for item in &items.0 {
+                if let Value::Document(_) = item {
+                    collect(item, path, values);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The geometries a value holds: itself, or the elements of an array that
+/// isn't a legacy point. Values that aren't geometries are skipped.
+fn geometries(value: &Value) -> Vec<Geometry> {
+    let items = match value {
+        Value::Array(items) if Point::parse_legacy(value).is_none() => items.0.iter().collect(),
+        value => vec![value],
+    };
+    items
+        .into_iter()
+        .filter_map(|item| Geometry::parse(item).ok().flatten())
+        .collect()
+}
+
+/// The point of a geometry that is one.
+fn point(geometry: &Geometry) -> Option<Point> {
+    match geometry {
+        Geometry::Point(point) => Some(*point),
+        _ => None,
+    }
+}
+
+/// A region of `$geoWithin`. GeoJSON polygons and `$centerSphere` are on the
+/// sphere; `$box`, `$polygon` and `$center` are on the flat plane of `2d`
+/// indexes and only hold points.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Region {
+    Polygons(Geometry),
+    /// A center and a radius in radians.
+    CenterSphere(Point, f64),
+    Box(Rect),
+    Polygon(Vec<Point>),
+    Center(Point, f64),
+}
+
+/// A `$geoWithin` or `$geoIntersects` predicate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoQuery {
+    Within(Region),
+    Intersects(Geometry),
+}
+
+impl GeoQuery {
+    /// Parses the argument of `$geoWithin` (or its old name `$within`) or
+    /// `$geoIntersects`.
+    pub fn parse(operator: &str, argument: &Value) -> CommandResult<GeoQuery> {
+        let Value::Document(argument) = argument else {
+            return Err(bad_value(format!("{} must be an object", operator)));
+        };
+        let Some((shape, value)) = argument.first() else {
+            return Err(bad_value(format!("{} must not be empty", operator)));
+        };
+        if operator == "$geoIntersects" {
+            return match (shape.as_str(), value) {
+                ("$geometry", Value::Document(geometry)) => {
+                    Ok(GeoQuery::Intersects(Geometry::parse_geojson(geometry)?))
+                }
+                _ => Err(bad_value(format!(
+                    "$geoIntersects not supported with provided geometry: {}",
+                    argument
+                ))),
+            };
+        }
+        let region = match (shape.as_str(), value) {
+            ("$geometry", Value::Document(geometry)) => {
+                let geometry = Geometry::parse_geojson(geometry)?;
+                if !matches!(geometry, Geometry::Polygon(_) | Geometry::MultiPolygon(_)) {
+                    return Err(bad_value(format!(
+                        "$geoWithin not supported with provided geometry: {}",
+                        argument
+                    )));
+                }
+                Region::Polygons(geometry)
+            }
+            ("$centerSphere" | "$center", value) => {
+                let circle = value.as_array().filter(|circle| circle.len() == 2);
+                let center = circle.and_then(|circle| Point::parse_legacy(&circle[0]));
+                let radius = circle.and_then(|circle| circle[1].as_f64());
+                let (Some(center), Some(radius)) = (center, radius) else {
+                    return Err(bad_value(format!(
+                        "{} must be an array of a point and a radius: {}",
+                        shape, value
+                    )));
+                };
+                if radius < 0.0 {
+                    return Err(bad_value(format!(
+                        "radius must be a non-negative number: {}",
+                        value
+                    )));
+                }
+                if shape == "$center" {
+                    Region::Center(center, radius)
+                } else {
+                    Region::CenterSphere(Point::on_sphere(center.x, center.y)?, radius)
+                }
+            }
+            ("$box", value) => {
+                let corners: Vec<Point> = value
+                    .as_array()
+                    .map(|corners| corners.iter().filter_map(Point::parse_legacy).collect())
+                    .unwrap_or_default();
+                if corners.len() != 2 {
+                    return Err(bad_value(format!(
+                        "$box must be an array of two points: {}",
+                        value
+                    )));
+                }
+                Region::Box(Rect::around(&corners))
+            }
+            ("$polygon", value) => {
+                let points: Vec<Point> = value
+                    .as_array()
+                    .map(|points| points.iter().filter_map(Point::parse_legacy).collect())
+                    .unwrap_or_default();
+                if points.len() < 3 {
+                    return Err(bad_value(format!(
+                        "Polygon must have at least 3 points: {}",
+                        value
+                    )));
+                }
+                Region::Polygon(points)
+            }
+            _ => {
+                return Err(bad_value(format!(
+                    "unknown geo specifier: {}: {}",
+                    shape, value
+                )));
+            }
+        };
+        Ok(GeoQuery::Within(region))
+    }
+
+    /// Whether the geometries a field holds satisfy the predicate.
+    pub fn matches(&self, value: &Value) -> bool {
+        geometries(value)
+            .iter()
+            .any(|geometry| self.matches_geometry(geometry))
+    }
+
+    fn matches_geometry(&self, geometry: &Geometry) -> bool {
+        match self {
+            GeoQuery::Intersects(other) => sphere::intersects(geometry, other),
+            GeoQuery::Within(Region::Polygons(region)) => sphere::within(geometry, region),
+            GeoQuery::Within(Region::CenterSphere(center, radius)) => {
+                // A cap smaller than a hemisphere holds the edges between
+                // the points it holds
+                let points = geometry.points();
+                let inside = |point: &Point| sphere::distance(*center, *point) <= *radius;
+                !points.is_empty() && points.iter().all(inside)
+            }
+            GeoQuery::Within(Region::Box(rect)) => point(geometry).is_some_and(|point| {
+                (rect.min.x..=rect.max.x).contains(&point.x)
+                    && (rect.min.y..=rect.max.y).contains(&point.y)
+            }),
+            GeoQuery::Within(Region::Polygon(vertices)) => {
+                point(geometry).is_some_and(|point| flat_polygon_contains(vertices, point))
+            }
+            GeoQuery::Within(Region::Center(center, radius)) => {
+                point(geometry).is_some_and(|point| flat_distance(*center, point) <= *radius)
+            }
+        }
+    }
+
+    /// Whether a `2d` index can answer the predicate, as it can only hold
+    /// points.
+    pub fn is_flat(&self) -> bool {
+        matches!(self, GeoQuery::Within(_))
+    }
+
+    /// A box holding every geometry satisfying the predicate, in the
+    /// coordinates of the index cells.
+    pub fn bounds(&self) -> Rect {
+        match self {
+            GeoQuery::Intersects(geometry) | GeoQuery::Within(Region::Polygons(geometry)) => {
+                sphere::bounds(geometry)
+            }
+            GeoQuery::Within(Region::CenterSphere(center, radius)) => {
+                sphere::cap_bounds(*center, *radius)
+            }
+            GeoQuery::Within(Region::Box(rect)) => *rect,
+            GeoQuery::Within(Region::Polygon(vertices)) => Rect::around(vertices),
+            GeoQuery::Within(Region::Center(center, radius)) => Rect {
+                min: Point {
+                    x: center.x - radius,
+                    y: center.y - radius,
+                },
+                max: Point {
+                    x: center.x + radius,
+                    y: center.y + radius,
+                },
+            },
+        }
+    }
+}
+
+fn flat_distance(a: Point, b: Point) -> f64 {
+    (a.x - b.x).hypot(a.y - b.y)
+}
+
+/// Whether a polygon on the plane holds a point, its edges included.
+fn flat_polygon_contains(vertices: &[Point], point: Point) -> bool {
+    let mut inside = false;
+    let mut previous = vertices[vertices.len() - 1];
+    for &vertex in vertices {
+        let (a, b) = (previous, vertex);
+        previous = vertex;
+        let cross = (b.x - a.x) * (point.y - a.y) - (b.y - a.y) * (point.x - a.x);
+        let between = |n: f64, p: f64, q: f64| p.min(q) <= n && n <= p.max(q);
+        if cross == 0.0 && between(point.x, a.x, b.x) && between(point.y, a.y, b.y) {
+            return true;
+        }
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+/// The point of a `$near`, `$nearSphere` or `$geoNear` search, with the
+/// distances results must be within.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Near {
+    pub point: Point,
+    /// Whether the point was given as GeoJSON, making distances meters.
+    pub geojson: bool,
+    /// Whether distances are measured on the sphere, even with a `2d` index.
+    pub spherical: bool,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+impl Near {
+    /// Parses the operators of a `$near` or `$nearSphere` query on a field,
+    /// such as `{ $near: { $geometry: { type: 'Point', coordinates: [1, 2] },
+    /// $maxDistance: 100 } }` or `{ $nearSphere: [1, 2], $maxDistance: 0.1 }`.
+    pub fn parse(operators: &Document) -> CommandResult<Near> {
+        let mut near = None;
+        let (mut min, mut max) = (None, None);
+        for (operator, argument) in operators.iter() {
+            match operator.as_str() {
+                "$near" | "$nearSphere" => {
+                    if near.is_some() {
+                        return Err(bad_value("Only one $near or $nearSphere is allowed"));
+                    }
+                    near = Some((operator.as_str(), argument));
+                }
+                "$minDistance" => min = Some(parse_distance(operator, argument)?),
+                "$maxDistance" => max = Some(parse_distance(operator, argument)?),
+                operator => {
+                    return Err(bad_value(format!(
+                        "{} is not allowed with $near or $nearSphere",
+                        operator
+                    )));
+                }
+            }
+        }
+        let Some((operator, argument)) = near else {
+            return Err(bad_value("$near or $nearSphere is required"));
+        };
+        let spherical = operator == "$nearSphere";
+        match argument {
+            Value::Document(argument) if argument.contains_key("$geometry") => {
+                for (field, value) in argument.iter() {
+                    match field.as_str() {
+                        "$geometry" => {}
+                        "$minDistance" => min = Some(parse_distance(field, value)?),
+                        "$maxDistance" => max = Some(parse_distance(field, value)?),
+                        field => {
+                            return Err(bad_value(format!(
+                                "invalid argument in geo near query: {}",
+                                field
+                            )));
+                        }
+                    }
+                }
+                match argument.get("$geometry") {
+                    Some(geometry @ Value::Document(_)) => {
+                        Self::from_point(geometry, spherical, min, max)
+                    }
+                    _ => Err(bad_value("$geometry must be a GeoJSON point")),
+                }
+            }
+            argument => Self::from_point(argument, spherical, min, max),
+        }
+    }
+
+    /// A search from a GeoJSON or legacy point, as given to `$geoNear`.
+    pub fn from_point(
+        value: &Value,
+        spherical: bool,
+        min: Option<f64>,
+        max: Option<f64>,
+    ) -> CommandResult<Near> {
+        let (point, geojson) = match value {
+            Value::Document(doc) if doc.contains_key("type") => {
+                match Geometry::parse_geojson(doc)? {
+                    Geometry::Point(point) => (point, true),
+                    _ => {
+                        return Err(bad_value(format!(
+                            "invalid point in geo near query $geometry argument: {}",
+                            value
+                        )));
+                    }
+                }
+            }
+            value => match Point::parse_legacy(value) {
+                Some(point) => (point, false),
+                None => {
+                    return Err(bad_value(format!(
+                        "near must be a point or a GeoJSON point: {}",
+                        value
+                    )));
+                }
+            },
+        };
+        if spherical {
+            Point::on_sphere(point.x, point.y)?;
+        }
+        Ok(Near {
+            point,
+            geojson,
+            spherical: spherical || geojson,
+            min,
+            max,
+        })
+    }
+
+    /// The distance to the nearest geometry of a value, in meters for a
+    /// GeoJSON point, on the plane when `flat` and otherwise in radians,
+    /// with the nearest location.
+    pub fn nearest<'v>(&self, value: &'v Value, flat: bool) -> Option<(f64, &'v Value)> {
+        let items = match value {
+            Value::Array(items) if Point::parse_legacy(value).is_none() => items.0.iter().collect(),
+            value => vec![value],
+        };
+        items
+            .into_iter()
+            .filter_map(|item| {
+                let geometry = Geometry::parse(item).ok().flatten()?;
+                let distance = if flat {
+                    flat_distance(self.point, point(&geometry)?)
+                } else if self.geojson {
+                    sphere::distance_to(self.point, &geometry) * EARTH_RADIUS_METERS
+                } else {
+                    sphere::distance_to(self.point, &geometry)
+                };
+                Some((distance, item))
+            })
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+    }
+
+    /// Whether a distance is within the minimum and maximum distances.
+    pub fn in_range(&self, distance: f64) -> bool {
+        self.min.is_none_or(|min| distance >= min) && self.max.is_none_or(|max| distance <= max)
+    }
+
+    /// A box holding every location within the maximum distance, which
+    /// must be given.
+    pub fn bounds(&self, flat: bool) -> Option<Rect> {
+        let max = self.max?;
+        Some(if flat {
+            GeoQuery::Within(Region::Center(self.point, max)).bounds()
+        } else {
+            let radians = if self.geojson {
+                max / EARTH_RADIUS_METERS
+            } else {
+                max
+            };
+            sphere::cap_bounds(self.point, radians)
+        })
+    }
+}
+
+fn parse_distance(operator: &str, value: &Value) -> CommandResult<f64> {
+    match value.as_f64() {
+        Some(distance) if value.is_number() && distance >= 0.0 => Ok(distance),
+        _ => Err(bad_value(format!(
+            "{} must be a non-negative number: {}",
+            operator, value
+        ))),
+    }
+}
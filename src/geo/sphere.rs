@@ -0,0 +1,339 @@
+//! Geometry on the unit sphere. Points are turned into unit vectors, edges
+//! are the shorter great circle arcs between their ends, and distances are
+//! angles in radians. Like mongod, polygons are taken to be smaller than a
+//! hemisphere.
+
+use std::f64::consts::PI;
+
+use super::{
+    cell::Rect,
+    geometry::{Geometry, Point},
+};
+
+/// The radius of the Earth mongod converts radians to meters with.
+pub const EARTH_RADIUS_METERS: f64 = 6378100.0;
+
+/// The distance under which two points are taken to be the same.
+const EPSILON: f64 = 1e-12;
+
+type Vector = [f64; 3];
+
+fn vector(point: Point) -> Vector {
+    let (lng, lat) = (point.x.to_radians(), point.y.to_radians());
+    [lat.cos() * lng.cos(), lat.cos() * lng.sin(), lat.sin()]
+}
+
+fn dot(a: Vector, b: Vector) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross(a: Vector, b: Vector) -> Vector {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn norm(a: Vector) -> f64 {
+    dot(a, a).sqrt()
+}
+
+fn scale(a: Vector, factor: f64) -> Vector {
+    [a[0] * factor, a[1] * factor, a[2] * factor]
+}
+
+fn angle(a: Vector, b: Vector) -> f64 {
+    norm(cross(a, b)).atan2(dot(a, b))
+}
+
+/// The distance between two points, in radians.
+pub fn distance(a: Point, b: Point) -> f64 {
+    angle(vector(a), vector(b))
+}
+
+/// Whether `q`, on the great circle through `a` and `b` with normal `n`,
+/// lies on the arc between them.
+fn on_arc(a: Vector, b: Vector, n: Vector, q: Vector) -> bool {
+    dot(cross(a, q), n) >= 0.0 && dot(cross(q, b), n) >= 0.0
+}
+
+fn distance_to_edge(p: Vector, a: Vector, b: Vector) -> f64 {
+    let n = cross(a, b);
+    let length = norm(n);
+    if length > EPSILON {
+        let n = scale(n, 1.0 / length);
+        // The closest point of the great circle, if it is on the arc
+        let along = dot(p, n);
+        let q = [
+            p[0] - along * n[0],
+            p[1] - along * n[1],
+            p[2] - along * n[2],
+        ];
+        if norm(q) > EPSILON && on_arc(a, b, n, q) {
+            return along.abs().min(1.0).asin();
+        }
+    }
+    angle(p, a).min(angle(p, b))
+}
+
+/// Whether arcs `ab` and `cd` cross at a point inside both.
+fn edges_cross(a: Vector, b: Vector, c: Vector, d: Vector) -> bool {
+    let ab = cross(a, b);
+    let acb = -dot(ab, c);
+    let bda = dot(ab, d);
+    if acb * bda <= 0.0 {
+        return false;
+    }
+    let cd = cross(c, d);
+    let cbd = -dot(cd, b);
+    let dac = dot(cd, a);
+    acb * cbd > 0.0 && acb * dac > 0.0
+}
+
+/// Whether a closed ring contains a point, its edges included. The angles
+/// the edges span as seen from inside the ring add up to a full turn.
+fn ring_contains(ring: &[Point], point: Point) -> bool {
+    let p = vector(point);
+    let vertices: Vec<Vector> = ring.iter().copied().map(vector).collect();
+    let edges = vertices.windows(2).map(|pair| (pair[0], pair[1]));
+    if edges
+        .clone()
+        .any(|(a, b)| distance_to_edge(p, a, b) < EPSILON)
+    {
+        return true;
+    }
+    // Rings are smaller than a hemisphere, which excludes the antipodes of
+    // the points they contain
+    let center = vertices[1..].iter().fold([0.0; 3], |sum, v| {
+        [sum[0] + v[0], sum[1] + v[1], sum[2] + v[2]]
+    });
+    if dot(p, center) < 0.0 {
+        return false;
+    }
+    let winding: f64 = edges
+        .map(|(a, b)| dot(p, cross(a, b)).atan2(dot(a, b) - dot(p, a) * dot(p, b)))
+        .sum();
+    winding.abs() > PI
+}
+
+/// Whether a polygon, given as its outer ring and holes, contains a point.
+pub fn polygon_contains(rings: &[Vec<Point>], point: Point) -> bool {
+    ring_contains(&rings[0], point) && !rings[1..].iter().any(|hole| ring_contains(hole, point))
+}
+
+/// The distance from a point to the nearest point of a geometry, in radians.
+pub fn distance_to(point: Point, geometry: &Geometry) -> f64 {
+    if geometry
+        .polygons()
+        .iter()
+        .any(|polygon| polygon_contains(polygon, point))
+    {
+        return 0.0;
+    }
+    let p = vector(point);
+    let vertices = geometry.points().into_iter().map(|q| angle(p, vector(q)));
+    let edges = geometry
+        .edges()
+        .into_iter()
+        .map(|(a, b)| distance_to_edge(p, vector(a), vector(b)));
+    vertices.chain(edges).fold(f64::INFINITY, f64::min)
+}
+
+/// Whether two geometries share a point.
+pub fn intersects(a: &Geometry, b: &Geometry) -> bool {
+    let touches = |a: &Geometry, b: &Geometry| {
+        let polygons = b.polygons();
+        a.points().into_iter().any(|point| {
+            distance_to(point, b) < EPSILON
+                || polygons
+                    .iter()
+                    .any(|polygon| polygon_contains(polygon, point))
+        })
+    };
+    if touches(a, b) || touches(b, a) {
+        return true;
+    }
+    let b_edges: Vec<_> = b
+        .edges()
+        .into_iter()
+        .map(|(c, d)| (vector(c), vector(d)))
+        .collect();
+    a.edges().into_iter().any(|(a0, a1)| {
+        let (a0, a1) = (vector(a0), vector(a1));
+        b_edges.iter().any(|(b0, b1)| edges_cross(a0, a1, *b0, *b1))
+    })
+}
+
+/// Whether a geometry lies inside the polygons of `region`: its vertices
+/// are inside, its edges don't cross their boundaries, and its own polygons
+/// don't enclose their holes.
+pub fn within(geometry: &Geometry, region: &Geometry) -> bool {
+    let polygons = region.polygons();
+    let points = geometry.points();
+    let inside = points.iter().all(|point| {
+        polygons
+            .iter()
+            .any(|polygon| polygon_contains(polygon, *point))
+    });
+    if points.is_empty() || !inside {
+        return false;
+    }
+    let boundary: Vec<_> = region
+        .edges()
+        .into_iter()
+        .map(|(a, b)| (vector(a), vector(b)))
+        .collect();
+    let crosses = geometry.edges().into_iter().any(|(a, b)| {
+        let (a, b) = (vector(a), vector(b));
+        boundary.iter().any(|(c, d)| edges_cross(a, b, *c, *d))
+    });
+    let own = geometry.polygons();
+    let encloses_hole = polygons
+        .iter()
+        .flat_map(|polygon| polygon[1..].iter().flatten())
+        .any(|vertex| own.iter().any(|polygon| polygon_contains(polygon, *vertex)));
+    !crosses && !encloses_hole
+}
+
+/// The lowest and highest latitudes of the arc between two points.
+fn arc_latitudes(a: Point, b: Point) -> (f64, f64) {
+    let (low, high) = (a.y.min(b.y), a.y.max(b.y));
+    let (va, vb) = (vector(a), vector(b));
+    let n = cross(va, vb);
+    let length = norm(n);
+    if length < EPSILON {
+        return (low, high);
+    }
+    let n = scale(n, 1.0 / length);
+    // The northernmost point of the great circle
+    let top = [-n[0] * n[2], -n[1] * n[2], 1.0 - n[2] * n[2]];
+    let top_length = norm(top);
+    if top_length < EPSILON {
+        return (low, high);
+    }
+    let top = scale(top, 1.0 / top_length);
+    let latitude = |v: Vector| v[2].clamp(-1.0, 1.0).asin().to_degrees();
+    let high = if on_arc(va, vb, n, top) {
+        high.max(latitude(top))
+    } else {
+        high
+    };
+    let bottom = scale(top, -1.0);
+    let low = if on_arc(va, vb, n, bottom) {
+        low.min(latitude(bottom))
+    } else {
+        low
+    };
+    (low, high)
+}
+
+/// A longitude and latitude box holding a geometry.
+pub fn bounds(geometry: &Geometry) -> Rect {
+    let mut rect = Rect::around(&geometry.points());
+    for (a, b) in geometry.edges() {
+        // Edges across the antimeridian wrap around
+        if (a.x - b.x).abs() > 180.0 {
+            rect.min.x = -180.0;
+            rect.max.x = 180.0;
+        }
+        let (low, high) = arc_latitudes(a, b);
+        rect.min.y = rect.min.y.min(low);
+        rect.max.y = rect.max.y.max(high);
+    }
+    for polygon in geometry.polygons() {
+        for pole in [90.0, -90.0] {
+            if polygon_contains(polygon, Point { x: 0.0, y: pole }) {
+                rect.min.x = -180.0;
+                rect.max.x = 180.0;
+                rect.min.y = rect.min.y.min(pole);
+                rect.max.y = rect.max.y.max(pole);
+            }
+        }
+    }
+    rect
+}
+
+/// A longitude and latitude box holding the points within `radius` radians
+/// of `center`.
+pub fn cap_bounds(center: Point, radius: f64) -> Rect {
+    let degrees = radius.to_degrees();
+    let mut rect = Rect {
+        min: Point {
+            x: -180.0,
+            y: (center.y - degrees).max(-90.0),
+        },
+        max: Point {
+            x: 180.0,
+            y: (center.y + degrees).min(90.0),
+        },
+    };
+    let reaches_pole = rect.min.y <= -90.0 || rect.max.y >= 90.0;
+    let ratio = radius.sin() / center.y.to_radians().cos();
+    if reaches_pole || radius >= PI / 2.0 || ratio >= 1.0 {
+        return rect;
+    }
+    let spread = ratio.asin().to_degrees();
+    if center.x - spread >= -180.0 && center.x + spread <= 180.0 {
+        rect.min.x = center.x - spread;
+        rect.max.x = center.x + spread;
+    }
+    rect
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn point(x: f64, y: f64) -> Point {
+        Point { x, y }
+    }
+
+    fn square(x: f64, y: f64, size: f64) -> Geometry {
+        Geometry::Polygon(vec![vec![
+            point(x, y),
+            point(x + size, y),
+            point(x + size, y + size),
+            point(x, y + size),
+            point(x, y),
+        ]])
+    }
+
+    #[test]
+    fn test_spherical_geometry() {
+        // A degree of latitude, and of longitude along the equator
+        let degree = distance(point(0.0, 0.0), point(0.0, 1.0));
+        assert!((degree - 1f64.to_radians()).abs() < 1e-12);
+        assert!((distance(point(179.5, 0.0), point(-179.5, 0.0)) - degree).abs() < 1e-12);
+
+        let polygon = square(0.0, 0.0, 10.0);
+        assert!(polygon_contains(polygon.polygons()[0], point(5.0, 5.0)));
+        assert!(!polygon_contains(polygon.polygons()[0], point(15.0, 5.0)));
+        assert!(!polygon_contains(
+            polygon.polygons()[0],
+            point(-175.0, -5.0)
+        ));
+        assert_eq!(distance_to(point(5.0, 5.0), &polygon), 0.0);
+        assert!((distance_to(point(12.0, 5.0), &polygon) - 2.0 * degree).abs() < 1e-3);
+
+        let line = Geometry::LineString(vec![point(-5.0, 5.0), point(15.0, 5.0)]);
+        assert!(intersects(&line, &polygon));
+        assert!(!within(&line, &polygon));
+        assert!(within(&square(2.0, 2.0, 2.0), &polygon));
+        assert!(!intersects(&square(20.0, 20.0, 1.0), &polygon));
+
+        // The edge between two points at 60 degrees north bulges northwards
+        let rect = bounds(&Geometry::LineString(vec![
+            point(-60.0, 60.0),
+            point(60.0, 60.0),
+        ]));
+        assert!(rect.max.y > 70.0);
+        let rect = bounds(&Geometry::LineString(vec![
+            point(170.0, 0.0),
+            point(-170.0, 0.0),
+        ]));
+        assert_eq!((rect.min.x, rect.max.x), (-180.0, 180.0));
+        let rect = cap_bounds(point(0.0, 60.0), 1f64.to_radians());
+        assert!((rect.max.x - 2.0).abs() < 0.01);
+    }
+}
@@ -0,0 +1,296 @@
+//! The pre-3.6 wire protocol opcodes. Each legacy operation is translated into
+//! the equivalent command; queries and `OP_GET_MORE` are answered with an
+//! `OP_REPLY`, while legacy writes are fire-and-forget and get no reply.
+
+use crate::{
+    bson::{Document, Value},
+    commands::{self, Reply},
+    cursor::CursorManager,
+    error::ErrorCode,
+    storage::Storage,
+    types::{
+        OpDelete, OpGetMore, OpInsert, OpKillCursors, OpQuery, OpReply, OpUpdate, ResponseFlag,
+    },
+};
+
+/// Splits `db.collection` into its database and collection names.
+fn split_namespace(namespace: &str) -> Option<(&str, &str)> {
+    namespace
+        .split_once('.')
+        .filter(|(db, collection)| !db.is_empty() && !collection.is_empty())
+}
+
+fn command(name: &str, collection: &str, fields: Vec<(&str, Value)>) -> Document {
+    let mut command = Document::new();
+    command.insert(name, Value::String(collection.to_string()));
+    for (key, value) in fields {
+        command.insert(key, value);
+    }
+    command
+}
+
+/// A reply carrying only the `QueryFailure` flag and the `$err` document.
+fn query_failure(message: &str, code: i32) -> OpReply {
+    let mut err = Document::new();
+    err.insert("$err", Value::String(message.to_string()));
+    err.insert("code", Value::Int32(code));
+    let mut reply = OpReply::new(vec![err]);
+    reply.set_flag(ResponseFlag::QueryFailure);
+    reply
+}
+
+/// Turns a `find` or `getMore` command reply into an `OP_REPLY`.
+fn cursor_reply(reply: Document) -> OpReply {
+    if reply.get("ok").is_some_and(|ok| !ok.is_truthy()) {
+        let message = reply.get("errmsg").and_then(Value::as_str).unwrap_or("");
+        let code = reply.get("code").and_then(Value::as_i64).unwrap_or(0) as i32;
+        if code == ErrorCode::CursorNotFound.code() {
+            let mut reply = OpReply::new(Vec::new());
+            reply.set_flag(ResponseFlag::CursorNotFound);
+            return reply;
+        }
+        return query_failure(message, code);
+    }
+
+    let Some(Value::Document(cursor)) = reply.get("cursor") else {
+        return query_failure("command returned no cursor", 0);
+    };
+    let documents = cursor
+        .get("firstBatch")
+        .or_else(|| cursor.get("nextBatch"))
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|doc| match doc {
+            Value::Document(doc) => Some(doc.clone()),
+            _ => None,
+        })
+        .collect();
+    let mut op_reply = OpReply::new(documents);
+    op_reply.cursor_id = cursor.get("id").and_then(Value::as_i64).unwrap_or(0);
+    op_reply
+}
+
+/// `OP_QUERY`: a command when sent to `<db>.$cmd`, otherwise a query that
+/// opens a cursor. Only a command can leave the reply pending.
+pub fn query(storage: &mut Storage, cursors: &mut CursorManager, op: &OpQuery) -> Reply<OpReply> {
+    let namespace = op.full_collection_name();
+    let Some((db, collection)) = split_namespace(&namespace) else {
+        return Reply::Ready(query_failure(&format!("Invalid ns [{}]", namespace), 16256));
+    };
+    if collection == "$cmd" {
+        return commands::start_command(storage, cursors, db, &op.query())
+            .map(|reply| OpReply::new(vec![reply]));
+    }
+
+    // The query is either a plain filter or wraps it along with modifiers,
+    // as in `{ $query: { a: 1 }, $orderby: { b: -1 } }`. Like mongod, a
+    // filter is only taken to be wrapped in `query` when it comes first.
+    let query = op.query();
+    let wrapped = query
+        .get("$query")
+        .or_else(|| {
+            query
+                .first()
+                .filter(|(field, _)| *field == "query")
+                .map(|(_, filter)| filter)
+        })
+        .filter(|filter| matches!(filter, Value::Document(_)));
+    let mut fields = match wrapped {
+        Some(filter) => {
+            let mut fields = vec![("filter", filter.clone())];
+            if let Some(sort) = query.get("$orderby").or_else(|| query.get("orderby")) {
+                fields.push(("sort", sort.clone()));
+            }
+            fields
+        }
+        None => vec![("filter", Value::Document(query.clone()))],
+    };
+    if let Some(projection) = op.return_fields_selector() {
+        fields.push(("projection", Value::Document(projection)));
+    }
+    fields.push(("skip", Value::Int64(op.number_to_skip().max(0) as i64)));
+
+    // A negative number to return (or 1) asks for a single batch of at most
+    // that many documents
+    match op.number_to_return() {
+        0 => {}
+        n if n < 0 || n == 1 => {
+            fields.push(("limit", Value::Int64(n.unsigned_abs() as i64)));
+            fields.push(("singleBatch", Value::Boolean(true)));
+        }
+        n => fields.push(("batchSize", Value::Int64(n as i64))),
+    }
+
+    let reply = commands::run_command(storage, cursors, db, &command("find", collection, fields));
+    Reply::Ready(cursor_reply(reply))
+}
+
+/// `OP_GET_MORE`: the next batch of a cursor.
+pub fn get_more(storage: &mut Storage, cursors: &mut CursorManager, op: &OpGetMore) -> OpReply {
+    let namespace = op.full_collection_name();
+    let Some((db, collection)) = split_namespace(&namespace) else {
+        return query_failure(&format!("Invalid ns [{}]", namespace), 16256);
+    };
+    let mut command = Document::new();
+    command.insert("getMore", Value::Int64(op.cursor_id()));
+    command.insert("collection", Value::String(collection.to_string()));
+    if op.number_to_return() > 0 {
+        command.insert("batchSize", Value::Int64(op.number_to_return() as i64));
+    }
+    cursor_reply(commands::run_command(storage, cursors, db, &command))
+}
+
+/// `OP_KILL_CURSORS`, which names cursors without their namespace or a
+/// session, so cursors opened in a session are left open.
+pub fn kill_cursors(cursors: &mut CursorManager, op: &OpKillCursors) {
+    for id in op.cursor_ids() {
+        if let Some(namespace) = cursors.namespace(id).map(str::to_string) {
+            // There's no reply to report a failure in
+            let _ = cursors.kill(id, &namespace, None);
+        }
+    }
+}
+
+fn run_write(
+    storage: &mut Storage,
+    cursors: &mut CursorManager,
+    namespace: &str,
+    build: impl FnOnce(&str) -> Document,
+) {
+    let Some((db, collection)) = split_namespace(namespace) else {
+        eprintln!("Invalid namespace in legacy write: {}", namespace);
+        return;
+    };
+    // Legacy writes get no reply
+    commands::run_command(storage, cursors, db, &build(collection));
+}
+
+/// `OP_INSERT`.
+pub fn insert(storage: &mut Storage, cursors: &mut CursorManager, op: &OpInsert) {
+    run_write(storage, cursors, &op.full_collection_name(), |collection| {
+        let documents: Vec<Value> = op.documents().into_iter().map(Value::Document).collect();
+        command(
+            "insert",
+            collection,
+            vec![
+                ("documents", Value::from(documents)),
+                ("ordered", Value::Boolean(!op.continue_on_error())),
+            ],
+        )
+    });
+}
+
+/// `OP_UPDATE`.
+pub fn update(storage: &mut Storage, cursors: &mut CursorManager, op: &OpUpdate) {
+    run_write(storage, cursors, &op.full_collection_name(), |collection| {
+        let mut statement = Document::new();
+        statement.insert("q", Value::Document(op.selector()));
+        statement.insert("u", Value::Document(op.update()));
+        statement.insert("upsert", Value::Boolean(op.upsert()));
+        statement.insert("multi", Value::Boolean(op.multi_update()));
+        command(
+            "update",
+            collection,
+            vec![("updates", Value::from(vec![Value::Document(statement)]))],
+        )
+    });
+}
+
+/// `OP_DELETE`.
+pub fn delete(storage: &mut Storage, cursors: &mut CursorManager, op: &OpDelete) {
+    run_write(storage, cursors, &op.full_collection_name(), |collection| {
+        let mut statement = Document::new();
+        statement.insert("q", Value::Document(op.selector()));
+        statement.insert("limit", Value::Int32(op.single_remove() as i32));
+        command(
+            "delete",
+            collection,
+            vec![("deletes", Value::from(vec![Value::Document(statement)]))],
+        )
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn message(op_code: i32, body: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&(16 + body.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(&1i32.to_le_bytes());
+        bytes.extend_from_slice(&0i32.to_le_bytes());
+        bytes.extend_from_slice(&op_code.to_le_bytes());
+        bytes.extend_from_slice(body);
+        bytes
+    }
+
+    fn cstring(s: &str) -> Vec<u8> {
+        let mut bytes = s.as_bytes().to_vec();
+        bytes.push(0);
+        bytes
+    }
+
+    #[test]
+    fn test_legacy_operations() {
+        let mut storage = Storage::new();
+        let mut cursors = CursorManager::default();
+
+        let mut body = 1i32.to_le_bytes().to_vec();
+        body.extend(cstring("test.c"));
+        for i in 0..3 {
+            body.extend(doc(&format!("{{ _id: {} }}", i)).to_bytes());
+        }
+        insert(
+            &mut storage,
+            &mut cursors,
+            &OpInsert::new(&message(2002, &body)),
+        );
+
+        let mut body = 0i32.to_le_bytes().to_vec();
+        body.extend(cstring("test.c"));
+        body.extend(1i32.to_le_bytes());
+        body.extend(doc("{ _id: 0 }").to_bytes());
+        delete(
+            &mut storage,
+            &mut cursors,
+            &OpDelete::new(&message(2006, &body)),
+        );
+
+        let mut run_query = |filter: &str| {
+            let mut body = 0i32.to_le_bytes().to_vec();
+            body.extend(cstring("test.c"));
+            body.extend(0i32.to_le_bytes());
+            body.extend(2i32.to_le_bytes());
+            body.extend(doc(filter).to_bytes());
+            let Reply::Ready(reply) = query(
+                &mut storage,
+                &mut cursors,
+                &OpQuery::new(&message(2004, &body)),
+            ) else {
+                panic!("the query is pending");
+            };
+            reply
+        };
+        let reply = run_query("{ $query: {}, $orderby: { _id: -1 } }");
+        assert_eq!(reply.documents, vec![doc("{ _id: 2 }"), doc("{ _id: 1 }")]);
+        assert_eq!(reply.cursor_id, 0);
+        let reply = run_query("{ query: { _id: 1 } }");
+        assert_eq!(reply.documents, vec![doc("{ _id: 1 }")]);
+        // A field named `query` elsewhere is part of a plain filter
+        let reply = run_query("{ _id: { $gt: 1 }, query: { $exists: false } }");
+        assert_eq!(reply.documents, vec![doc("{ _id: 2 }")]);
+
+        let mut body = 0i32.to_le_bytes().to_vec();
+        body.extend(cstring("test.c"));
+        body.extend(0i32.to_le_bytes());
+        body.extend(42i64.to_le_bytes());
+        let reply = get_more(
+            &mut storage,
+            &mut cursors,
+            &OpGetMore::new(&message(2005, &body)),
+        );
+        assert!(reply.has_flag(&ResponseFlag::CursorNotFound));
+    }
+}
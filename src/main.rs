@@ -1,19 +1,35 @@
 use std::{
     collections::HashMap,
     env,
-    io::{Read, Write},
+    io::{self, Read, Write},
     net::{SocketAddr, TcpListener, TcpStream},
+    process,
     sync::{mpsc, Arc},
     thread,
+    time::{Duration, Instant},
 };
 
+mod aggregation;
 mod bson;
+mod collation;
+mod commands;
+mod cursor;
 mod error;
+mod geo;
+mod legacy;
+mod query;
+mod storage;
+mod text;
 mod types;
+mod update;
 
 use crate::{
+    bson::Value,
+    commands::Reply,
+    cursor::CursorManager,
     error::Result,
-    types::{MsgHeader, OpQuery},
+    storage::{OpId, Storage},
+    types::{MsgHeader, OpDelete, OpGetMore, OpInsert, OpKillCursors, OpQuery, OpReply, OpUpdate},
 };
 
 #[derive(Debug)]
@@ -29,30 +45,54 @@ pub enum Message {
         addr: SocketAddr,
         bytes: Box<[u8]>,
     },
+    /// Sent periodically to run background housekeeping such as reaping
+    /// idle cursors.
+    Tick,
+    /// Sent by the TTL monitor to delete expired documents.
+    ExpireDocuments,
 }
 
+/// The length of a message header.
+const HEADER_LENGTH: i32 = 16;
+
+/// The largest message accepted, like mongod's `maxMessageSizeBytes`.
+const MAX_MESSAGE_SIZE: i32 = 48_000_000;
+
+/// How often the server runs its background housekeeping.
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often the TTL monitor runs when no interval is given, like mongod's
+/// `ttlMonitorSleepSecs`.
+const DEFAULT_TTL_MONITOR_INTERVAL: Duration = Duration::from_secs(60);
+
 struct Client {
-    #[allow(unused)]
     stream: Arc<TcpStream>,
 }
 
 struct Server {
     clients: HashMap<SocketAddr, Client>,
+    storage: Storage,
+    cursors: CursorManager,
+    next_request_id: i32,
+    /// The clients waiting for an operation to reply, with the id of their
+    /// request.
+    waiting: HashMap<OpId, (SocketAddr, i32)>,
 }
 
 impl Server {
-    fn new() -> Self {
+    fn new(storage: Storage) -> Self {
         Self {
             clients: HashMap::new(),
+            storage,
+            cursors: CursorManager::default(),
+            next_request_id: 1,
+            waiting: HashMap::new(),
         }
     }
 
     fn client_connected(&mut self, stream: Arc<TcpStream>, addr: SocketAddr) {
-        let client = Client {
-            stream: stream.clone(),
-        };
+        let client = Client { stream };
         self.clients.insert(addr, client);
-        _ = write!(stream.as_ref(), "Hello");
     }
 
     fn client_disconnected(&mut self, addr: SocketAddr) {
@@ -66,15 +106,83 @@ impl Server {
         println!("message: {:#?}", header);
 
         match header.op_code() {
+            2001 => {
+                let op_update = OpUpdate::new(bytes);
+                legacy::update(&mut self.storage, &mut self.cursors, &op_update);
+            }
+            2002 => {
+                let op_insert = OpInsert::new(bytes);
+                legacy::insert(&mut self.storage, &mut self.cursors, &op_insert);
+            }
             2004 => {
                 let op_query = OpQuery::new(bytes);
-                println!("op_query: {:#?}", op_query);
+                match legacy::query(&mut self.storage, &mut self.cursors, &op_query) {
+                    Reply::Ready(mut reply) => self.reply(addr, header.request_id(), &mut reply),
+                    Reply::Pending(op_id) => {
+                        self.waiting.insert(op_id, (addr, header.request_id()));
+                    }
+                }
+            }
+            2005 => {
+                let op_get_more = OpGetMore::new(bytes);
+                let mut reply =
+                    legacy::get_more(&mut self.storage, &mut self.cursors, &op_get_more);
+                self.reply(addr, header.request_id(), &mut reply);
+            }
+            2006 => {
+                let op_delete = OpDelete::new(bytes);
+                legacy::delete(&mut self.storage, &mut self.cursors, &op_delete);
+            }
+            2007 => {
+                let op_kill_cursors = OpKillCursors::new(bytes);
+                legacy::kill_cursors(&mut self.cursors, &op_kill_cursors);
             }
             op_code => {
                 unimplemented!("op_code: {}", op_code);
             }
         }
     }
+
+    fn tick(&mut self) {
+        let reaped = self.cursors.reap(Instant::now());
+        if reaped > 0 {
+            println!("Reaped {} idle cursors", reaped);
+        }
+    }
+
+    fn expire_documents(&mut self) {
+        let Value::UtcDateTime(now) = Value::now() else {
+            return;
+        };
+        let removed = self.storage.remove_expired(now);
+        if removed > 0 {
+            println!("TTL monitor removed {} expired documents", removed);
+        }
+    }
+
+    /// Takes the index builds a batch further, replying to the clients
+    /// waiting for those that ended. A client that disconnected meanwhile
+    /// doesn't stop its build.
+    fn continue_index_builds(&mut self) {
+        for (op_id, result) in self.storage.continue_index_builds() {
+            if let Some((addr, response_to)) = self.waiting.remove(&op_id) {
+                let mut reply = OpReply::new(vec![commands::command_reply(result)]);
+                self.reply(addr, response_to, &mut reply);
+            }
+        }
+    }
+
+    fn reply(&mut self, addr: SocketAddr, response_to: i32, reply: &mut OpReply) {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        if let Some(client) = self.clients.get(&addr) {
+            let bytes = reply.to_bytes(request_id, response_to);
+            if let Err(err) = client.stream.as_ref().write_all(&bytes) {
+                eprintln!("Error writing reply to {}: {}", addr, err);
+            }
+        }
+    }
 }
 
 fn client(stream: Arc<TcpStream>, tx: mpsc::Sender<Message>) -> Result<()> {
@@ -85,33 +193,73 @@ fn client(stream: Arc<TcpStream>, tx: mpsc::Sender<Message>) -> Result<()> {
         addr,
     })?;
 
-    let mut buffer = [0; 1024];
     loop {
-        let n = stream.as_ref().read(&mut buffer)?;
-        if n > 0 {
-            let bytes = buffer[..n].iter().cloned().collect();
-            println!("Request: {}", String::from_utf8_lossy(&buffer[..]));
-            tx.send(Message::NewMessage { addr, bytes })?;
-        } else {
-            tx.send(Message::ClientDisconnected { addr })?;
-            break;
-        }
+        let bytes = match read_message(&stream) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                if err.kind() == io::ErrorKind::InvalidData {
+                    eprintln!("Closing connection to {}: {}", addr, err);
+                }
+                tx.send(Message::ClientDisconnected { addr })?;
+                break;
+            }
+        };
+        println!("Request: {}", String::from_utf8_lossy(&bytes));
+        tx.send(Message::NewMessage {
+            addr,
+            bytes: bytes.into_boxed_slice(),
+        })?;
     }
 
     Ok(())
 }
 
-fn server(rx: mpsc::Receiver<Message>) -> Result<()> {
-    let mut server = Server::new();
+/// Reads a message, which starts with its total length, header included.
+/// Like mongod, lengths over the maximum message size are rejected rather
+/// than allocated.
+fn read_message(mut stream: &TcpStream) -> io::Result<Vec<u8>> {
+    let mut length = [0; 4];
+    stream.read_exact(&mut length)?;
+    let length = i32::from_le_bytes(length);
+    if !(HEADER_LENGTH..=MAX_MESSAGE_SIZE).contains(&length) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid message length {}", length),
+        ));
+    }
+    let mut bytes = vec![0; length as usize];
+    bytes[..4].copy_from_slice(&length.to_le_bytes());
+    stream.read_exact(&mut bytes[4..])?;
+    Ok(bytes)
+}
+
+fn server(rx: mpsc::Receiver<Message>, storage: Storage) -> Result<()> {
+    let mut server = Server::new(storage);
     loop {
-        let msg = rx.recv()?;
+        // Index builds go on while no message is waiting, and a batch at a
+        // time between messages
+        let msg = if server.storage.index_builds().next().is_some() {
+            match rx.try_recv() {
+                Ok(msg) => msg,
+                Err(mpsc::TryRecvError::Empty) => {
+                    server.continue_index_builds();
+                    continue;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => return Err(mpsc::RecvError.into()),
+            }
+        } else {
+            rx.recv()?
+        };
         println!("Message: {:?}", msg);
 
         match msg {
             Message::ClientConnected { stream, addr } => server.client_connected(stream, addr),
             Message::ClientDisconnected { addr } => server.client_disconnected(addr),
             Message::NewMessage { addr, bytes } => server.new_message(addr, &bytes),
+            Message::Tick => server.tick(),
+            Message::ExpireDocuments => server.expire_documents(),
         }
+        server.continue_index_builds();
     }
 }
 
@@ -127,7 +275,39 @@ fn main() -> Result<()> {
     println!("Listening on: {}...", addr);
     let (tx, rx) = mpsc::channel();
 
-    thread::spawn(|| server(rx));
+    let storage = match args.get(2) {
+        Some(data_dir) => Storage::with_data_dir(data_dir),
+        None => Storage::new(),
+    };
+    let ttl_monitor_interval = match args.get(3) {
+        Some(seconds) => match seconds.parse() {
+            Ok(seconds) => Duration::from_secs(seconds),
+            Err(_) => {
+                eprintln!("Invalid TTL monitor interval: {}", seconds);
+                process::exit(1);
+            }
+        },
+        None => DEFAULT_TTL_MONITOR_INTERVAL,
+    };
+    thread::spawn(move || server(rx, storage));
+
+    let ticker = tx.clone();
+    thread::spawn(move || loop {
+        thread::sleep(TICK_INTERVAL);
+        if ticker.send(Message::Tick).is_err() {
+            break;
+        }
+    });
+
+    // The TTL monitor only triggers deletions, which the server runs between
+    // messages like every other write
+    let ttl_monitor = tx.clone();
+    thread::spawn(move || loop {
+        thread::sleep(ttl_monitor_interval);
+        if ttl_monitor.send(Message::ExpireDocuments).is_err() {
+            break;
+        }
+    });
 
     for stream in listener.incoming() {
         match stream {
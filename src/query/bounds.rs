@@ -0,0 +1,442 @@
+//! Index bounds: the ranges of values a filter allows for a field, from
+//! which the planner derives the parts of an index to scan. Bounds only need
+//! to contain every matching value, since the filter is applied again to
+//! the documents found. Under a collation, strings are bounded by their
+//! sort keys, which only an index with the same collation holds.
+
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    storage::hash_value,
+};
+
+/// A range of values in the BSON comparison order, from `start` to `end`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interval {
+    pub start: Value,
+    pub start_inclusive: bool,
+    pub end: Value,
+    pub end_inclusive: bool,
+}
+
+impl Interval {
+    fn new(start: Value, start_inclusive: bool, end: Value, end_inclusive: bool) -> Self {
+        Self {
+            start,
+            start_inclusive,
+            end,
+            end_inclusive,
+        }
+    }
+
+    fn point(value: Value) -> Self {
+        Self::new(value.clone(), true, value, true)
+    }
+
+    fn is_empty(&self) -> bool {
+        match self.start.compare(&self.end) {
+            Ordering::Less => false,
+            Ordering::Equal => !(self.start_inclusive && self.end_inclusive),
+            Ordering::Greater => true,
+        }
+    }
+
+    pub fn is_point(&self) -> bool {
+        self.start_inclusive && self.end_inclusive && self.start.equals(&self.end)
+    }
+
+    pub fn contains(&self, value: &Value) -> bool {
+        let after_start = match value.compare(&self.start) {
+            Ordering::Greater => true,
+            Ordering::Equal => self.start_inclusive,
+            Ordering::Less => false,
+        };
+        let before_end = match value.compare(&self.end) {
+            Ordering::Less => true,
+            Ordering::Equal => self.end_inclusive,
+            Ordering::Greater => false,
+        };
+        after_start && before_end
+    }
+
+    fn intersect(&self, other: &Interval) -> Interval {
+        let (start, start_inclusive) = match self.start.compare(&other.start) {
+            Ordering::Greater => (&self.start, self.start_inclusive),
+            Ordering::Less => (&other.start, other.start_inclusive),
+            Ordering::Equal => (&self.start, self.start_inclusive && other.start_inclusive),
+        };
+        let (end, end_inclusive) = match self.end.compare(&other.end) {
+            Ordering::Less => (&self.end, self.end_inclusive),
+            Ordering::Greater => (&other.end, other.end_inclusive),
+            Ordering::Equal => (&self.end, self.end_inclusive && other.end_inclusive),
+        };
+        Interval::new(start.clone(), start_inclusive, end.clone(), end_inclusive)
+    }
+
+    /// Whether every value of this interval is in `other`.
+    fn within(&self, other: &Interval) -> bool {
+        let start = match self.start.compare(&other.start) {
+            Ordering::Greater => true,
+            Ordering::Equal => other.start_inclusive || !self.start_inclusive,
+            Ordering::Less => false,
+        };
+        let end = match self.end.compare(&other.end) {
+            Ordering::Less => true,
+            Ordering::Equal => other.end_inclusive || !self.end_inclusive,
+            Ordering::Greater => false,
+        };
+        start && end
+    }
+}
+
+/// Shown like mongod's explain output, as in `[1, inf.0)`.
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = |value: &Value| match value {
+            Value::Double(n) if n.is_infinite() && *n > 0.0 => "inf.0".to_string(),
+            Value::Double(n) if n.is_infinite() => "-inf.0".to_string(),
+            value => value.to_string(),
+        };
+        write!(
+            f,
+            "{}{}, {}{}",
+            if self.start_inclusive { '[' } else { '(' },
+            value(&self.start),
+            value(&self.end),
+            if self.end_inclusive { ']' } else { ')' },
+        )
+    }
+}
+
+/// The values allowed for a field: a union of disjoint intervals in
+/// ascending order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldBounds(Vec<Interval>);
+
+impl FieldBounds {
+    /// Every value.
+    pub fn full() -> Self {
+        Self(vec![Interval::new(
+            Value::MinKey,
+            true,
+            Value::MaxKey,
+            true,
+        )])
+    }
+
+    /// A single value.
+    pub fn point(value: Value) -> Self {
+        Self(vec![Interval::point(value)])
+    }
+
+    fn from_intervals(mut intervals: Vec<Interval>) -> Self {
+        intervals.retain(|interval| !interval.is_empty());
+        intervals.sort_by(|a, b| {
+            a.start
+                .compare(&b.start)
+                .then_with(|| b.start_inclusive.cmp(&a.start_inclusive))
+        });
+        // Merge overlapping intervals
+        let mut merged: Vec<Interval> = Vec::new();
+        for interval in intervals {
+            match merged.last_mut() {
+                Some(last)
+                    if match interval.start.compare(&last.end) {
+                        Ordering::Less => true,
+                        Ordering::Equal => interval.start_inclusive || last.end_inclusive,
+                        Ordering::Greater => false,
+                    } =>
+                {
+                    if interval.end.compare(&last.end) == Ordering::Greater
+                        || (interval.end.equals(&last.end) && interval.end_inclusive)
+                    {
+                        last.end = interval.end;
+                        last.end_inclusive = interval.end_inclusive;
+                    }
+                }
+                _ => merged.push(interval),
+            }
+        }
+        Self(merged)
+    }
+
+    pub fn intervals(&self) -> &[Interval] {
+        &self.0
+    }
+
+    pub fn is_full(&self) -> bool {
+        *self == Self::full()
+    }
+
+    pub fn contains(&self, value: &Value) -> bool {
+        self.0.iter().any(|interval| interval.contains(value))
+    }
+
+    pub fn intersect(&self, other: &FieldBounds) -> FieldBounds {
+        let mut intervals = Vec::new();
+        for a in &self.0 {
+            for b in &other.0 {
+                intervals.push(a.intersect(b));
+            }
+        }
+        Self::from_intervals(intervals)
+    }
+
+    /// The bounds on the hashes of the values allowed here, for a hashed
+    /// field: the hash of every value when the bounds are single values,
+    /// else every hash, as hashes don't keep the order of the values.
+    pub fn hashed(&self) -> FieldBounds {
+        if !self.0.iter().all(Interval::is_point) {
+            return Self::full();
+        }
+        let hashes = self
+            .0
+            .iter()
+            .map(|interval| Interval::point(Value::Int64(hash_value(&interval.start))))
+            .collect();
+        Self::from_intervals(hashes)
+    }
+
+    /// Whether only scalars are allowed: a wildcard index keys neither the
+    /// documents holding other values nor the fields a document misses.
+    pub fn is_scalar(&self) -> bool {
+        let object = Value::Document(Document::new());
+        self.0.iter().all(|interval| {
+            !interval.contains(&Value::Null)
+                && !interval.contains(&object)
+                && !matches!(interval.start, Value::Document(_) | Value::Array(_))
+        })
+    }
+
+    /// Whether strings, or documents and arrays that may hold them, are
+    /// allowed, whose bounds depend on the collation.
+    pub fn has_strings(&self) -> bool {
+        // Strings rank first of the three types, arrays last
+        self.0.iter().any(|interval| {
+            interval.start.canonical_type() <= 25 && interval.end.canonical_type() >= 15
+        })
+    }
+
+    /// Whether every value allowed here is allowed by `other`.
+    pub fn within(&self, other: &FieldBounds) -> bool {
+        self.0
+            .iter()
+            .all(|interval| other.0.iter().any(|outer| interval.within(outer)))
+    }
+}
+
+/// The bounds a filter puts on each path, one per predicate so that the
+/// planner can decide whether they may be intersected: on a multikey index
+/// each predicate may be satisfied by a different array element.
+#[derive(Debug, Clone, Default)]
+pub struct Predicates {
+    pub paths: BTreeMap<String, Vec<FieldBounds>>,
+    /// Whether the bounds describe the filter exactly, so that a document
+    /// within them all matches it, but for the paths in `exists`.
+    pub exact: bool,
+    /// The paths `$exists: true` requires. Missing fields are keyed as null,
+    /// so their bounds are full: only a sparse index tells them apart.
+    pub exists: BTreeSet<String>,
+}
+
+impl Predicates {
+    /// The bounds of a filter comparing strings under `collation`, on the
+    /// keys of an index with that collation.
+    pub fn new(filter: &Document, collation: Option<&Collation>) -> Self {
+        let mut predicates = Self {
+            paths: BTreeMap::new(),
+            exact: true,
+            exists: BTreeSet::new(),
+        };
+        predicates.add_filter(filter, collation);
+        predicates
+    }
+
+    /// The bounds on `path`: all predicates intersected, or only the first
+    /// one for a multikey index.
+    pub fn bounds(&self, path: &str, multikey: bool) -> FieldBounds {
+        match self.paths.get(path) {
+            Some(bounds) if multikey => bounds[0].clone(),
+            Some(bounds) => bounds
+                .iter()
+                .fold(FieldBounds::full(), |all, bounds| all.intersect(bounds)),
+            None => FieldBounds::full(),
+        }
+    }
+
+    fn add(&mut self, path: &str, bounds: Option<FieldBounds>) {
+        match bounds {
+            Some(bounds) => self.paths.entry(path.to_string()).or_default().push(bounds),
+            None => self.exact = false,
+        }
+    }
+
+    fn add_filter(&mut self, filter: &Document, collation: Option<&Collation>) {
+        for (key, value) in filter.iter() {
+            match (key.as_str(), value) {
+                ("$and", Value::Array(clauses)) => {
+                    for clause in &clauses.0 {
+                        match clause {
+                            Value::Document(clause) => self.add_filter(clause, collation),
+                            _ => self.exact = false,
+                        }
+                    }
+                }
+                ("$comment", _) => {}
+                (key, _) if key.starts_with('$') => self.exact = false,
+                (path, Value::Document(operators))
+                    if operators.first().is_some_and(|(op, _)| op.starts_with('$')) =>
+                {
+                    for (operator, argument) in operators.iter() {
+                        if operator == "$exists" && argument.is_truthy() {
+                            self.exists.insert(path.to_string());
+                        }
+                        self.add(path, operator_bounds(operator, argument, collation));
+                    }
+                }
+                (path, value) => self.add(path, equality_bounds(value, collation)),
+            }
+        }
+    }
+}
+
+/// The bounds of `$eq`. Arrays and regular expressions also match elements
+/// or strings that aren't equal to them, so they aren't bounded.
+fn equality_bounds(value: &Value, collation: Option<&Collation>) -> Option<FieldBounds> {
+    match value {
+        Value::Array(_) | Value::Regex(_, _) => None,
+        value => Some(FieldBounds(vec![Interval::point(translate(
+            value, collation,
+        ))])),
+    }
+}
+
+/// A value as an index with the collation holds it.
+fn translate(value: &Value, collation: Option<&Collation>) -> Value {
+    match collation {
+        Some(collation) => collation.translate(value),
+        None => value.clone(),
+    }
+}
+
+/// The first and last values of the type of `value` in the comparison
+/// order, as comparisons only match values of the same type.
+fn type_bracket(value: &Value) -> (Value, bool, Value, bool) {
+    match value {
+        value if value.is_number() => (
+            Value::Double(f64::NEG_INFINITY),
+            true,
+            Value::Double(f64::INFINITY),
+            true,
+        ),
+        Value::String(_) | Value::Symbol(_) => (
+            Value::String(String::new()),
+            true,
+            Value::Document(Document::new()),
+            false,
+        ),
+        Value::UtcDateTime(_) => (
+            Value::UtcDateTime(i64::MIN),
+            true,
+            Value::UtcDateTime(i64::MAX),
+            true,
+        ),
+        _ => (Value::MinKey, true, Value::MaxKey, true),
+    }
+}
+
+fn operator_bounds(
+    operator: &str,
+    argument: &Value,
+    collation: Option<&Collation>,
+) -> Option<FieldBounds> {
+    let range = |argument: &Value| match argument {
+        Value::Array(_) | Value::Regex(_, _) | Value::Null => None,
+        argument => Some((translate(argument, collation), type_bracket(argument))),
+    };
+    let interval = match operator {
+        "$eq" => return equality_bounds(argument, collation),
+        "$in" => {
+            let mut intervals = Vec::new();
+            for value in argument.as_array()? {
+                intervals.extend(equality_bounds(value, collation)?.0);
+            }
+            return Some(FieldBounds::from_intervals(intervals));
+        }
+        "$gt" | "$gte" => {
+            let (value, (_, _, end, end_inclusive)) = range(argument)?;
+            Interval::new(value, operator == "$gte", end, end_inclusive)
+        }
+        "$lt" | "$lte" => {
+            let (value, (start, start_inclusive, _, _)) = range(argument)?;
+            Interval::new(start, start_inclusive, value, operator == "$lte")
+        }
+        "$exists" if argument.is_truthy() => return Some(FieldBounds::full()),
+        _ => return None,
+    };
+    Some(FieldBounds::from_intervals(vec![interval]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn bounds(filter: &str, path: &str) -> String {
+        Predicates::new(&doc(filter), None)
+            .bounds(path, false)
+            .intervals()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    #[test]
+    fn test_bounds() {
+        assert_eq!(bounds("{ a: 5 }", "a"), "[5, 5]");
+        assert_eq!(bounds("{ a: { $gt: 1, $lte: 5 } }", "a"), "(1, 5]");
+        assert_eq!(bounds("{ a: { $gte: 1 } }", "a"), "[1, inf.0]");
+        assert_eq!(bounds("{ a: { $lt: 'm' } }", "a"), "[\"\", \"m\")");
+        assert_eq!(
+            bounds("{ a: { $in: [3, 1, 3] }, $and: [{ a: { $lt: 3 } }] }", "a"),
+            "[1, 1]"
+        );
+        assert_eq!(bounds("{ a: { $gt: 5, $lt: 1 } }", "a"), "");
+        assert_eq!(bounds("{ a: { $ne: 5 } }", "a"), "[MinKey, MaxKey]");
+
+        let predicates = Predicates::new(&doc("{ a: { $gt: 1 }, b: { $regex: 'x' } }"), None);
+        assert!(!predicates.exact);
+        let multikey = Predicates::new(&doc("{ a: { $gt: 1, $lt: 5 } }"), None).bounds("a", true);
+        assert!(multikey.within(&FieldBounds::full()));
+        assert!(!FieldBounds::full().within(&multikey));
+        assert!(!multikey.contains(&Value::Int32(0)));
+
+        let hashed = Predicates::new(&doc("{ a: { $in: [1, 1.0] } }"), None)
+            .bounds("a", false)
+            .hashed();
+        assert_eq!(
+            hashed,
+            FieldBounds::point(Value::Int64(hash_value(&Value::Int32(1))))
+        );
+        assert!(Predicates::new(&doc("{ a: { $gt: 1 } }"), None)
+            .bounds("a", false)
+            .hashed()
+            .is_full());
+        assert!(Predicates::new(&doc("{ a: { $lt: 'm' } }"), None)
+            .bounds("a", false)
+            .is_scalar());
+        assert!(!Predicates::new(&doc("{ a: { $ne: 1 } }"), None)
+            .bounds("a", false)
+            .is_scalar());
+        assert!(!Predicates::new(&doc("{ a: { b: 1 } }"), None)
+            .bounds("a", false)
+            .is_scalar());
+    }
+}
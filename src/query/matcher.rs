@@ -0,0 +1,686 @@
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    error::{CommandError, CommandResult, ErrorCode},
+    geo::{GeoQuery, Near},
+    text::TextSearch,
+};
+
+/// A compiled query filter, as used by `find`, the `q` of updates and array
+/// filters.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    filter: Document,
+    expression: Expression,
+    /// A top level `$text`, which only a text index can answer.
+    text: Option<TextSearch>,
+    /// A top level `$near` or `$nearSphere` on a field, which only a geo
+    /// index can answer.
+    near: Option<(String, Near)>,
+    /// The collation strings are compared under, bytewise without.
+    collation: Option<Collation>,
+}
+
+#[derive(Debug, Clone)]
+enum Expression {
+    And(Vec<Expression>),
+    Or(Vec<Expression>),
+    Nor(Vec<Expression>),
+    Not(Box<Expression>),
+    Field(String, Predicate),
+    AlwaysFalse,
+}
+
+#[derive(Debug, Clone)]
+enum Predicate {
+    Eq(Value),
+    Gt(Value),
+    Gte(Value),
+    Lt(Value),
+    Lte(Value),
+    In(Vec<Value>),
+    Exists,
+    Type(Vec<TypeAlias>),
+    Size(i64),
+    Mod(i64, i64),
+    ElemMatchValue(Vec<Predicate>),
+    ElemMatchObject(Box<Expression>),
+    Geo(GeoQuery),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum TypeAlias {
+    Number,
+    Code(u8),
+}
+
+impl Matcher {
+    pub fn new(filter: &Document) -> CommandResult<Self> {
+        if filter.contains_key("$text") {
+            return Err(bad_value("$text is not allowed in this context"));
+        }
+        Self::for_query(filter)?.forbid_near()
+    }
+
+    /// Compiles the filter of a query, which may have a top level `$text`
+    /// for the planner to look up in a text index, or a top level `$near`
+    /// for it to search a geo index with.
+    pub fn for_query(filter: &Document) -> CommandResult<Self> {
+        let mut rest = filter.clone();
+        let text = match rest.remove("$text") {
+            Some(operator) => Some(TextSearch::parse(&operator)?),
+            None => None,
+        };
+        let mut near = None;
+        for (path, value) in filter.iter() {
+            let Value::Document(operators) = value else {
+                continue;
+            };
+            if !operators.contains_key("$near") && !operators.contains_key("$nearSphere") {
+                continue;
+            }
+            if near.is_some() {
+                return Err(bad_value("Too many geoNear expressions"));
+            }
+            if text.is_some() {
+                return Err(bad_value("text and geoNear not allowed in same query"));
+            }
+            near = Some((path.clone(), Near::parse(operators)?));
+            rest.remove(path);
+        }
+        Ok(Self {
+            filter: filter.clone(),
+            expression: parse_filter(&rest)?,
+            text,
+            near,
+            collation: None,
+        })
+    }
+
+    /// Compiles the `query` of a `$geoNear` stage, searching from `near` on
+    /// the field at `path`.
+    pub fn with_near(filter: &Document, path: &str, near: Near) -> CommandResult<Self> {
+        let mut matcher = Self::new(filter)?;
+        matcher.near = Some((path.to_string(), near));
+        Ok(matcher)
+    }
+
+    /// Fails for a filter with `$near` or `$nearSphere`, in the contexts
+    /// that can't return documents by distance.
+    pub fn forbid_near(self) -> CommandResult<Self> {
+        if self.near.is_some() {
+            return Err(bad_value(
+                "$geoNear, $near, and $nearSphere are not allowed in this context",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// Compares strings under `collation`.
+    pub fn with_collation(mut self, collation: Option<&Collation>) -> Self {
+        self.collation = collation.cloned();
+        self
+    }
+
+    /// The collation of the query, which the indexes answering it must
+    /// share for their string bounds to hold.
+    pub fn collation(&self) -> Option<&Collation> {
+        self.collation.as_ref()
+    }
+
+    /// The filter the matcher was compiled from.
+    pub fn filter(&self) -> &Document {
+        &self.filter
+    }
+
+    /// The `$text` search of the filter. Documents are only matched against
+    /// the rest of the filter; the planner looks the search up in the text
+    /// index.
+    pub fn text(&self) -> Option<&TextSearch> {
+        self.text.as_ref()
+    }
+
+    /// The field and point of the `$near` search of the filter. Documents
+    /// are only matched against the rest of the filter; the planner checks
+    /// their distance.
+    pub fn near(&self) -> Option<(&str, &Near)> {
+        self.near.as_ref().map(|(path, near)| (path.as_str(), near))
+    }
+
+    /// The `$geoWithin` and `$geoIntersects` predicates every matching
+    /// document satisfies, with their fields, which a geo index can look up.
+    pub fn geo_queries(&self) -> Vec<(&str, &GeoQuery)> {
+        let expressions = match &self.expression {
+            Expression::And(children) => children.iter().collect(),
+            expression => vec![expression],
+        };
+        let mut queries = Vec::new();
+        for expression in expressions {
+            match expression {
+                Expression::Field(path, Predicate::Geo(query)) => {
+                    queries.push((path.as_str(), query));
+                }
+                Expression::And(children) => {
+                    for child in children {
+                        if let Expression::Field(path, Predicate::Geo(query)) = child {
+                            queries.push((path.as_str(), query));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        queries
+    }
+
+    pub fn matches(&self, doc: &Document) -> bool {
+        self.match_position(doc).is_some()
+    }
+
+    /// Matches `doc`, returning the index of the array element that satisfied
+    /// the filter, if any. This is what the positional `$` update operator
+    /// refers to.
+    pub fn match_position(&self, doc: &Document) -> Option<Option<usize>> {
+        let mut position = None;
+        if self
+            .expression
+            .matches(doc, &mut position, self.collation.as_ref())
+        {
+            Some(position)
+        } else {
+            None
+        }
+    }
+}
+
+fn bad_value(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::BadValue, message)
+}
+
+fn parse_filter(filter: &Document) -> CommandResult<Expression> {
+    let mut expressions = Vec::new();
+    for (key, value) in filter.iter() {
+        match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let clauses = match value.as_array() {
+                    Some(clauses) if !clauses.is_empty() => clauses,
+                    _ => return Err(bad_value(format!("{} must be a nonempty array", key))),
+                };
+                let mut children = Vec::new();
+                for clause in clauses {
+                    let Value::Document(clause) = clause else {
+                        return Err(bad_value(format!(
+                            "{} argument's entries must be objects",
+                            key
+                        )));
+                    };
+                    children.push(parse_filter(clause)?);
+                }
+                expressions.push(match key.as_str() {
+                    "$and" => Expression::And(children),
+                    "$or" => Expression::Or(children),
+                    _ => Expression::Nor(children),
+                });
+            }
+            "$comment" => {}
+            key if key.starts_with('$') => {
+                return Err(bad_value(format!("unknown top level operator: {}", key)));
+            }
+            path => expressions.push(parse_field(path, value)?),
+        }
+    }
+    Ok(match expressions.len() {
+        1 => expressions.remove(0),
+        _ => Expression::And(expressions),
+    })
+}
+
+fn is_operator_document(value: &Value) -> bool {
+    match value {
+        Value::Document(doc) => doc.first().is_some_and(|(k, _)| k.starts_with('$')),
+        _ => false,
+    }
+}
+
+fn parse_field(path: &str, value: &Value) -> CommandResult<Expression> {
+    let Value::Document(operators) = value else {
+        return Ok(Expression::Field(
+            path.to_string(),
+            Predicate::Eq(value.clone()),
+        ));
+    };
+    if !is_operator_document(value) {
+        return Ok(Expression::Field(
+            path.to_string(),
+            Predicate::Eq(value.clone()),
+        ));
+    }
+
+    let mut expressions = Vec::new();
+    for (op, arg) in operators.iter() {
+        expressions.push(parse_operator(path, op, arg)?);
+    }
+    Ok(match expressions.len() {
+        1 => expressions.remove(0),
+        _ => Expression::And(expressions),
+    })
+}
+
+fn parse_operator(path: &str, op: &str, arg: &Value) -> CommandResult<Expression> {
+    let field = |predicate| Expression::Field(path.to_string(), predicate);
+    let not = |expression| Expression::Not(Box::new(expression));
+
+    Ok(match op {
+        "$not" => match arg {
+            Value::Document(doc) if doc.is_empty() => {
+                return Err(bad_value("$not cannot be empty"));
+            }
+            Value::Document(_) if is_operator_document(arg) => not(parse_field(path, arg)?),
+            _ => return Err(bad_value("$not needs a regex or a document")),
+        },
+        "$ne" => not(field(Predicate::Eq(arg.clone()))),
+        "$nin" => not(field(Predicate::In(parse_in(op, arg)?))),
+        "$exists" if arg.is_truthy() => field(Predicate::Exists),
+        "$exists" => not(field(Predicate::Exists)),
+        "$all" => {
+            let Some(values) = arg.as_array() else {
+                return Err(bad_value("$all needs an array"));
+            };
+            if values.is_empty() {
+                return Ok(Expression::AlwaysFalse);
+            }
+            let mut children = Vec::new();
+            for value in values {
+                children.push(match value {
+                    Value::Document(doc) if doc.first().is_some_and(|(k, _)| k == "$elemMatch") => {
+                        parse_field(path, value)?
+                    }
+                    value => field(Predicate::Eq(value.clone())),
+                });
+            }
+            Expression::And(children)
+        }
+        _ => field(parse_predicate(op, arg)?),
+    })
+}
+
+fn parse_in(op: &str, arg: &Value) -> CommandResult<Vec<Value>> {
+    match arg.as_array() {
+        Some(values) => Ok(values.clone()),
+        None => Err(bad_value(format!("{} needs an array", op))),
+    }
+}
+
+/// Parses operators that can be applied directly to a value, which is also
+/// what `$elemMatch` accepts when given an operator document.
+fn parse_predicate(op: &str, arg: &Value) -> CommandResult<Predicate> {
+    Ok(match op {
+        "$eq" => Predicate::Eq(arg.clone()),
+        "$gt" => Predicate::Gt(arg.clone()),
+        "$gte" => Predicate::Gte(arg.clone()),
+        "$lt" => Predicate::Lt(arg.clone()),
+        "$lte" => Predicate::Lte(arg.clone()),
+        "$in" => Predicate::In(parse_in(op, arg)?),
+        "$type" => {
+            let aliases = match arg {
+                Value::Array(values) => values.0.iter().map(parse_type_alias).collect(),
+                value => vec![parse_type_alias(value)],
+            };
+            Predicate::Type(aliases.into_iter().collect::<CommandResult<_>>()?)
+        }
+        "$size" => match arg.as_i64() {
+            Some(size) => Predicate::Size(size),
+            None => return Err(bad_value("$size needs a number")),
+        },
+        "$mod" => {
+            let Some(values) = arg.as_array() else {
+                return Err(bad_value("malformed mod, needs to be an array"));
+            };
+            if values.len() < 2 {
+                return Err(bad_value("malformed mod, not enough elements"));
+            }
+            if values.len() > 2 {
+                return Err(bad_value("malformed mod, too many elements"));
+            }
+            let (Some(divisor), Some(remainder)) = (values[0].as_f64(), values[1].as_f64()) else {
+                return Err(bad_value(
+                    "malformed mod, divisor and remainder must be numbers",
+                ));
+            };
+            if divisor as i64 == 0 {
+                return Err(bad_value("divisor cannot be 0"));
+            }
+            Predicate::Mod(divisor as i64, remainder as i64)
+        }
+        "$elemMatch" => {
+            let Value::Document(doc) = arg else {
+                return Err(bad_value("$elemMatch needs an Object"));
+            };
+            let is_value_match = doc.first().is_some_and(|(k, _)| {
+                k.starts_with('$') && !matches!(k.as_str(), "$and" | "$or" | "$nor")
+            });
+            if is_value_match {
+                let mut predicates = Vec::new();
+                for (op, arg) in doc.iter() {
+                    predicates.push(parse_predicate(op, arg)?);
+                }
+                Predicate::ElemMatchValue(predicates)
+            } else {
+                Predicate::ElemMatchObject(Box::new(parse_filter(doc)?))
+            }
+        }
+        "$geoWithin" | "$within" | "$geoIntersects" => Predicate::Geo(GeoQuery::parse(op, arg)?),
+        "$near" | "$nearSphere" => return Err(bad_value("geoNear must be top-level expr")),
+        op => return Err(bad_value(format!("unknown operator: {}", op))),
+    })
+}
+
+fn parse_type_alias(value: &Value) -> CommandResult<TypeAlias> {
+    if let Some(code) = value.as_i64() {
+        return Ok(TypeAlias::Code(code as u8));
+    }
+    let Some(name) = value.as_str() else {
+        return Err(bad_value(
+            "type must be represented as a number or a string",
+        ));
+    };
+    if name == "number" {
+        return Ok(TypeAlias::Number);
+    }
+    let code = match name {
+        "double" => 0x01,
+        "string" => 0x02,
+        "object" => 0x03,
+        "array" => 0x04,
+        "binData" => 0x05,
+        "undefined" => 0x06,
+        "objectId" => 0x07,
+        "bool" => 0x08,
+        "date" => 0x09,
+        "null" => 0x0A,
+        "regex" => 0x0B,
+        "dbPointer" => 0x0C,
+        "javascript" => 0x0D,
+        "symbol" => 0x0E,
+        "javascriptWithScope" => 0x0F,
+        "int" => 0x10,
+        "timestamp" => 0x11,
+        "long" => 0x12,
+        "decimal" => 0x13,
+        "minKey" => 0xFF,
+        "maxKey" => 0x7F,
+        _ => return Err(bad_value(format!("Unknown type name alias: {}", name))),
+    };
+    Ok(TypeAlias::Code(code))
+}
+
+impl Expression {
+    fn matches(
+        &self,
+        doc: &Document,
+        position: &mut Option<usize>,
+        collation: Option<&Collation>,
+    ) -> bool {
+        match self {
+            Expression::And(children) => {
+                children.iter().all(|c| c.matches(doc, position, collation))
+            }
+            Expression::Or(children) => {
+                children.iter().any(|c| c.matches(doc, position, collation))
+            }
+            Expression::Nor(children) => !children
+                .iter()
+                .any(|c| c.matches(doc, &mut None, collation)),
+            Expression::Not(child) => !child.matches(doc, &mut None, collation),
+            Expression::Field(path, predicate) => {
+                let segments: Vec<&str> = path.split('.').collect();
+                match_document(doc, &segments, predicate, None, position, collation)
+            }
+            Expression::AlwaysFalse => false,
+        }
+    }
+}
+
+fn record(position: &mut Option<usize>, index: Option<usize>) -> bool {
+    if position.is_none() {
+        *position = index;
+    }
+    true
+}
+
+fn match_document(
+    doc: &Document,
+    segments: &[&str],
+    predicate: &Predicate,
+    index: Option<usize>,
+    position: &mut Option<usize>,
+    collation: Option<&Collation>,
+) -> bool {
+    match doc.get(segments[0]) {
+        Some(value) => match_value(value, &segments[1..], predicate, index, position, collation),
+        None => predicate.test(None, collation) && record(position, index),
+    }
+}
+
+/// Walks the remaining path segments below `value`, traversing arrays
+/// implicitly: a path into an array applies to each of its elements as well
+/// as to numeric indexes into it.
+fn match_value(
+    value: &Value,
+    segments: &[&str],
+    predicate: &Predicate,
+    index: Option<usize>,
+    position: &mut Option<usize>,
+    collation: Option<&Collation>,
+) -> bool {
+    if segments.is_empty() {
+        if let Some(element) = predicate.elem_match_index(value, collation) {
+            return record(position, index.or(Some(element)));
+        }
+        if predicate.test(Some(value), collation) {
+            return record(position, index);
+        }
+        if let (true, Value::Array(items)) = (predicate.traverses_arrays(), value) {
+            for (i, item) in items.0.iter().enumerate() {
+                if predicate.test(Some(item), collation) {
+                    return record(position, index.or(Some(i)));
+                }
+            }
+        }
+        return false;
+    }
+
+    match value {
+        Value::Document(doc) => {
+            match_document(doc, segments, predicate, index, position, collation)
+        }
+        Value::Array(items) => {
+            if let Ok(i) = segments[0].parse::<usize>() {
+                if let Some(item) = items.0.get(i) {
+                    if match_value(item, &segments[1..], predicate, index, position, collation) {
+                        return true;
+                    }
+                }
+            }
+            for (i, item) in items.0.iter().enumerate() {
+                let matched = match item {
+                    Value::Document(doc) => match_document(
+                        doc,
+                        segments,
+                        predicate,
+                        index.or(Some(i)),
+                        position,
+                        collation,
+                    ),
+                    Value::Array(_) => false,
+                    _ => predicate.test(None, collation) && record(position, index.or(Some(i))),
+                };
+                if matched {
+                    return true;
+                }
+            }
+            false
+        }
+        _ => predicate.test(None, collation) && record(position, index),
+    }
+}
+
+/// Comparison operators only match values of the same canonical type, except
+/// when comparing against `MinKey` or `MaxKey`.
+fn compare_bracketed(
+    value: &Value,
+    operand: &Value,
+    collation: Option<&Collation>,
+) -> Option<Ordering> {
+    let comparable = value.canonical_type() == operand.canonical_type()
+        || matches!(operand, Value::MinKey | Value::MaxKey);
+    comparable.then(|| value.compare_with(operand, collation))
+}
+
+fn equals(value: Option<&Value>, operand: &Value, collation: Option<&Collation>) -> bool {
+    match (value, operand) {
+        (None, Value::Null) => true,
+        (None, _) => false,
+        (Some(v), Value::Null) => v.is_null_or_undefined(),
+        (Some(v), operand) => compare_bracketed(v, operand, collation) == Some(Ordering::Equal),
+    }
+}
+
+impl Predicate {
+    /// Whether the predicate also applies to the elements of an array found
+    /// at the end of the path.
+    fn traverses_arrays(&self) -> bool {
+        !matches!(
+            self,
+            Predicate::Size(_)
+                | Predicate::Exists
+                | Predicate::ElemMatchValue(_)
+                | Predicate::ElemMatchObject(_)
+                | Predicate::Geo(_)
+        )
+    }
+
+    fn elem_match_index(&self, value: &Value, collation: Option<&Collation>) -> Option<usize> {
+        let Value::Array(items) = value else {
+            return None;
+        };
+        match self {
+            Predicate::ElemMatchValue(predicates) => items
+                .0
+                .iter()
+                .position(|item| predicates.iter().all(|p| p.test_element(item, collation))),
+            Predicate::ElemMatchObject(expression) => items.0.iter().position(|item| match item {
+                Value::Document(doc) => expression.matches(doc, &mut None, collation),
+                _ => false,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Tests an array element for `$elemMatch`, where nested arrays are
+    /// traversed like any other value.
+    fn test_element(&self, value: &Value, collation: Option<&Collation>) -> bool {
+        if self.elem_match_index(value, collation).is_some() || self.test(Some(value), collation) {
+            return true;
+        }
+        match (self.traverses_arrays(), value) {
+            (true, Value::Array(items)) => {
+                items.0.iter().any(|item| self.test(Some(item), collation))
+            }
+            _ => false,
+        }
+    }
+
+    fn test(&self, value: Option<&Value>, collation: Option<&Collation>) -> bool {
+        let compare = |operand| value.and_then(|v| compare_bracketed(v, operand, collation));
+        match self {
+            Predicate::Eq(operand) => equals(value, operand, collation),
+            Predicate::In(operands) => operands
+                .iter()
+                .any(|operand| equals(value, operand, collation)),
+            Predicate::Gt(operand) => compare(operand) == Some(Ordering::Greater),
+            Predicate::Lt(operand) => compare(operand) == Some(Ordering::Less),
+            Predicate::Gte(operand) => {
+                equals(value, operand, collation) || compare(operand) == Some(Ordering::Greater)
+            }
+            Predicate::Lte(operand) => {
+                equals(value, operand, collation) || compare(operand) == Some(Ordering::Less)
+            }
+            Predicate::Exists => value.is_some(),
+            Predicate::Type(aliases) => value.is_some_and(|v| {
+                aliases.iter().any(|alias| match alias {
+                    TypeAlias::Number => v.is_number(),
+                    TypeAlias::Code(code) => v.element_type() == *code,
+                })
+            }),
+            Predicate::Size(size) => value
+                .and_then(|v| v.as_array())
+                .is_some_and(|items| items.len() as i64 == *size),
+            Predicate::Mod(divisor, remainder) => value
+                .and_then(|v| v.as_f64())
+                .is_some_and(|v| !v.is_nan() && (v as i64) % divisor == *remainder),
+            Predicate::ElemMatchValue(_) | Predicate::ElemMatchObject(_) => false,
+            // Arrays may be points, or hold geometries
+            Predicate::Geo(query) => value.is_some_and(|v| query.matches(v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn matches(filter: &str, document: &str) -> bool {
+        Matcher::new(&doc(filter)).unwrap().matches(&doc(document))
+    }
+
+    #[test]
+    fn test_equality_and_arrays() {
+        assert!(matches("{ a: 1 }", "{ a: 1.0 }"));
+        assert!(matches("{ a: 1 }", "{ a: [3, 1] }"));
+        assert!(matches("{ a: [3, 1] }", "{ a: [3, 1] }"));
+        assert!(!matches("{ a: 1 }", "{ a: '1' }"));
+        assert!(matches("{ 'a.b': 2 }", "{ a: [{ b: 1 }, { b: 2 }] }"));
+        assert!(matches("{ 'a.1': 5 }", "{ a: [4, 5] }"));
+        assert!(matches("{ a: null }", "{ b: 1 }"));
+        assert!(!matches("{ a: { $ne: null } }", "{ b: 1 }"));
+    }
+
+    #[test]
+    fn test_comparisons_are_type_bracketed() {
+        assert!(matches("{ a: { $gt: 1, $lte: 5 } }", "{ a: 5 }"));
+        assert!(!matches("{ a: { $gt: 1 } }", "{ a: 'z' }"));
+        assert!(matches("{ a: { $in: [1, 'x'] } }", "{ a: 'x' }"));
+        assert!(matches("{ a: { $nin: [1, 2] } }", "{ a: 3 }"));
+    }
+
+    #[test]
+    fn test_logical_operators() {
+        assert!(matches("{ $or: [{ a: 1 }, { b: 2 }] }", "{ b: 2 }"));
+        assert!(!matches("{ $nor: [{ a: 1 }, { b: 2 }] }", "{ b: 2 }"));
+        assert!(matches("{ a: { $not: { $gt: 5 } } }", "{ a: 3 }"));
+        assert!(matches("{ a: { $exists: false } }", "{ b: 3 }"));
+    }
+
+    #[test]
+    fn test_elem_match_records_position() {
+        let matcher = Matcher::new(&doc("{ a: { $elemMatch: { b: 2, c: 3 } } }")).unwrap();
+        let position =
+            matcher.match_position(&doc("{ a: [{ b: 2, c: 1 }, { b: 1 }, { b: 2, c: 3 }] }"));
+        assert_eq!(position, Some(Some(2)));
+
+        let matcher = Matcher::new(&doc("{ 'grades': { $gte: 90 } }")).unwrap();
+        let position = matcher.match_position(&doc("{ grades: [80, 85, 92] }"));
+        assert_eq!(position, Some(Some(2)));
+    }
+
+    #[test]
+    fn test_invalid_filters() {
+        assert!(Matcher::new(&doc("{ a: { $foo: 1 } }")).is_err());
+        assert!(Matcher::new(&doc("{ $and: [] }")).is_err());
+        assert!(Matcher::new(&doc("{ a: { $in: 1 } }")).is_err());
+    }
+}
@@ -0,0 +1,8 @@
+mod bounds;
+mod matcher;
+pub mod planner;
+mod sort;
+
+pub use matcher::Matcher;
+pub use planner::Query;
+pub use sort::SortSpec;
@@ -0,0 +1,1592 @@
+//! The query planner. For a filter, and optionally a sort and projection, it
+//! builds a plan for every index whose leading field the filter bounds or
+//! that provides the sort, and runs them in a trial, as mongod does: each
+//! until it has found a first batch, or has done more work than a plan that
+//! did. The one that got there with the least work wins and runs to the end.
+//! Without candidates the collection is scanned. A `$text`
+//! query is always answered from the text index, and a `$near` query from a
+//! geo index on its field. A wildcard index makes a candidate for every path
+//! the filter bounds that it indexes. An index only bounds strings for
+//! queries with its collation.
+
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    error::{CommandError, CommandResult, ErrorCode},
+    geo::{locations, Near},
+    storage::{Collection, Index, IndexKey, KeyValue, RecordId, PATH_FIELD},
+    text::{TextQuery, TextSearch, TEXT_INDEX_VERSION},
+};
+
+use super::{
+    bounds::{FieldBounds, Interval, Predicates},
+    Matcher, SortSpec,
+};
+
+/// The work a candidate plan may do in the trial, unless the collection is
+/// larger: then a fraction of its documents, as mongod's
+/// `internalQueryPlanEvaluationWorks` and `internalQueryPlanEvaluationCollFraction`.
+const TRIAL_WORKS: usize = 10_000;
+const TRIAL_COLLECTION_FRACTION: f64 = 0.3;
+
+/// The first batch a candidate plan may find in the trial, as mongod's
+/// `internalQueryPlanEvaluationMaxResults`.
+const TRIAL_RESULTS: usize = 101;
+
+/// What a command asks of the planner.
+#[derive(Debug, Clone, Copy)]
+pub struct Query<'q> {
+    pub matcher: &'q Matcher,
+    pub sort: Option<&'q SortSpec>,
+    /// The projection, which lets a query be answered from index keys alone
+    /// when it only includes indexed fields.
+    pub projection: Option<&'q Document>,
+    /// An index name or key pattern, or `{ $natural: 1 }` for a collection
+    /// scan.
+    pub hint: Option<&'q Value>,
+}
+
+impl<'q> Query<'q> {
+    pub fn new(matcher: &'q Matcher) -> Self {
+        Self {
+            matcher,
+            sort: None,
+            projection: None,
+            hint: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Access {
+    CollectionScan {
+        reverse: bool,
+    },
+    IndexScan {
+        index: String,
+        /// The bounds of every field of the key pattern.
+        bounds: Vec<FieldBounds>,
+        reverse: bool,
+    },
+    /// Looks the terms of a `$text` query up in a text index.
+    Text {
+        index: String,
+        query: TextQuery,
+    },
+    /// Looks the cells covering the region of a `$geoWithin` or
+    /// `$geoIntersects` up in a geo index.
+    GeoScan {
+        index: String,
+        cells: Vec<String>,
+    },
+    /// Finds the documents near a point through a geo index, nearest first.
+    /// With a maximum distance only the cells within it are looked up.
+    GeoNear {
+        index: String,
+        path: String,
+        near: Near,
+        cells: Option<Vec<String>>,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct Plan {
+    pub access: Access,
+    /// Whether the documents come out in the order of the query's sort.
+    pub sorted: bool,
+    /// Whether the documents are built from index keys without fetching.
+    pub covered: bool,
+}
+
+/// The documents a plan found, with the work it took.
+#[derive(Debug)]
+pub struct Execution<'a> {
+    pub documents: Vec<(RecordId, Cow<'a, Document>)>,
+    pub keys_examined: usize,
+    pub docs_examined: usize,
+    /// The text score of every document found by a `$text` query.
+    pub text_scores: HashMap<RecordId, f64>,
+    /// The distance of every document found by a `$near` query, with its
+    /// nearest location.
+    pub distances: HashMap<RecordId, (f64, Value)>,
+    /// Whether the plan ran to the end, rather than stopping with its trial.
+    eof: bool,
+}
+
+impl Execution<'_> {
+    fn new() -> Self {
+        Self {
+            documents: Vec::new(),
+            keys_examined: 0,
+            docs_examined: 0,
+            text_scores: HashMap::new(),
+            distances: HashMap::new(),
+            eof: false,
+        }
+    }
+
+    fn works(&self) -> usize {
+        self.keys_examined + self.docs_examined
+    }
+
+    /// Whether a candidate completed the trial: it ran to the end or found
+    /// a first batch.
+    fn completed(&self) -> bool {
+        self.eof || self.documents.len() >= TRIAL_RESULTS
+    }
+}
+
+/// The winning plan with its results, and the candidates that lost with
+/// what they found in the trial.
+#[derive(Debug)]
+pub struct Planned<'a> {
+    pub winner: Plan,
+    pub execution: Execution<'a>,
+    pub rejected: Vec<(Plan, Execution<'a>)>,
+}
+
+fn bad_hint() -> CommandError {
+    CommandError::new(
+        ErrorCode::BadValue,
+        "hint provided does not correspond to an existing index",
+    )
+}
+
+/// Plans and runs a query on a collection, which may not exist.
+pub fn plan<'a>(collection: Option<&'a Collection>, query: &Query) -> CommandResult<Planned<'a>> {
+    let Some(collection) = collection else {
+        return Ok(Planned {
+            winner: Plan {
+                access: Access::CollectionScan { reverse: false },
+                sorted: false,
+                covered: false,
+            },
+            execution: Execution::new(),
+            rejected: Vec::new(),
+        });
+    };
+    let special = match (query.matcher.text(), query.matcher.near()) {
+        (Some(search), _) => Some(text_plan(collection, query, search)?),
+        (None, Some((path, near))) => Some(near_plan(collection, query, path, near)?),
+        (None, None) => None,
+    };
+    if let Some(winner) = special {
+        let execution = winner.execute(collection, query.matcher);
+        return Ok(Planned {
+            winner,
+            execution,
+            rejected: Vec::new(),
+        });
+    }
+    let collation = query.matcher.collation();
+    let predicates = Predicates::new(query.matcher.filter(), collation);
+
+    let candidates = match query.hint {
+        Some(hint) => vec![hinted_plan(collection, &predicates, query, hint)?],
+        None => collection
+            .indexes()
+            .iter()
+            .filter(|index| can_answer(index, &predicates, collation))
+            .flat_map(|index| match index.geo() {
+                Some(_) => geo_plan(index, query.matcher).into_iter().collect(),
+                None if index.text().is_some() => Vec::new(),
+                None if index.wildcard().is_some() => wildcard_plans(index, &predicates, collation),
+                None => index_plan(index, &predicates, query, false)
+                    .into_iter()
+                    .collect(),
+            })
+            .collect(),
+    };
+    if candidates.is_empty() {
+        let winner = Plan {
+            access: Access::CollectionScan { reverse: false },
+            sorted: false,
+            covered: false,
+        };
+        let execution = winner.execute(collection, query.matcher);
+        return Ok(Planned {
+            winner,
+            execution,
+            rejected: Vec::new(),
+        });
+    }
+
+    // Every candidate finds the same documents, so the best completes the
+    // trial with the least work, with ties going to plans that spare a sort.
+    // Once a candidate completed, the others stop when they did as much
+    // work; if none completes, the best found the most documents.
+    let mut works = TRIAL_WORKS.max((collection.len() as f64 * TRIAL_COLLECTION_FRACTION) as usize);
+    let mut trials = Vec::new();
+    for plan in candidates {
+        let execution = plan.trial(collection, query.matcher, works);
+        if execution.completed() {
+            works = works.min(execution.works());
+        }
+        trials.push((plan, execution));
+    }
+    let best = (0..trials.len())
+        .min_by_key(|i| {
+            let (plan, execution) = &trials[*i];
+            match execution.completed() {
+                true => (false, execution.works(), !plan.sorted),
+                false => (true, usize::MAX - execution.documents.len(), !plan.sorted),
+            }
+        })
+        .expect("there are candidates");
+    let (winner, _) = trials.remove(best);
+    let execution = winner.execute(collection, query.matcher);
+    Ok(Planned {
+        winner,
+        execution,
+        rejected: trials,
+    })
+}
+
+fn hinted_plan(
+    collection: &Collection,
+    predicates: &Predicates,
+    query: &Query,
+    hint: &Value,
+) -> CommandResult<Plan> {
+    let index = match hint {
+        Value::String(name) => collection.index(name),
+        Value::Document(hint) => match hint.first() {
+            Some((field, direction)) if field == "$natural" => {
+                return Ok(Plan {
+                    access: Access::CollectionScan {
+                        reverse: direction.as_f64().is_some_and(|n| n < 0.0),
+                    },
+                    sorted: false,
+                    covered: false,
+                });
+            }
+            _ => collection
+                .indexes()
+                .iter()
+                .find(|index| index.key_pattern().compare(hint).is_eq()),
+        },
+        _ => None,
+    };
+    // Text indexes only answer `$text` queries, and geo indexes geo ones
+    let index = index
+        .filter(|index| index.text().is_none())
+        .ok_or_else(bad_hint)?;
+    if index.geo().is_some() {
+        return geo_plan(index, query.matcher).ok_or_else(bad_hint);
+    }
+    if index.wildcard().is_some() {
+        let plans = wildcard_plans(index, predicates, query.matcher.collation());
+        return plans.into_iter().next().ok_or_else(bad_hint);
+    }
+    Ok(index_plan(index, predicates, query, true).expect("hinted plans are always built"))
+}
+
+/// Whether a hint names an index, or there is none.
+fn is_hinted(index: &Index, hint: Option<&Value>) -> bool {
+    match hint {
+        None => true,
+        Some(Value::String(name)) => name == index.name(),
+        Some(Value::Document(hint)) => index.key_pattern().compare(hint).is_eq(),
+        Some(_) => false,
+    }
+}
+
+/// The plan of a `$text` query, which needs the text index of the
+/// collection. A hint may only name that index.
+fn text_plan(collection: &Collection, query: &Query, search: &TextSearch) -> CommandResult<Plan> {
+    let Some((index, text)) = collection
+        .indexes()
+        .iter()
+        .find_map(|index| Some((index, index.text()?)))
+    else {
+        return Err(CommandError::new(
+            ErrorCode::IndexNotFound,
+            "text index required for $text query",
+        ));
+    };
+    if !is_hinted(index, query.hint) {
+        return Err(bad_hint());
+    }
+    Ok(Plan {
+        access: Access::Text {
+            index: index.name().to_string(),
+            query: search.query(text.default_language()),
+        },
+        sorted: false,
+        covered: false,
+    })
+}
+
+/// The plan of a `$near` query, which needs a geo index on its field: a
+/// `2dsphere` one for a GeoJSON point, preferably a `2d` one for a legacy
+/// point. A hint may only name that index.
+fn near_plan(
+    collection: &Collection,
+    query: &Query,
+    path: &str,
+    near: &Near,
+) -> CommandResult<Plan> {
+    let on_path = |index: &&Index| {
+        index
+            .geo()
+            .is_some_and(|geo| index.fields()[geo.field()].0.join(".") == path)
+    };
+    let sphere = collection
+        .indexes()
+        .iter()
+        .filter(on_path)
+        .find(|index| index.geo().is_some_and(|geo| geo.is_sphere()));
+    let flat = collection
+        .indexes()
+        .iter()
+        .filter(on_path)
+        .find(|index| index.geo().is_some_and(|geo| !geo.is_sphere()));
+    let index = match (near.geojson, flat, sphere) {
+        (false, Some(index), _) | (_, _, Some(index)) => index,
+        _ => {
+            return Err(CommandError::new(
+                ErrorCode::NoQueryExecutionPlans,
+                "unable to find index for $geoNear query",
+            ));
+        }
+    };
+    if !is_hinted(index, query.hint) {
+        return Err(bad_hint());
+    }
+    let geo = index.geo().expect("the index is a geo index");
+    let flat = !geo.is_sphere() && !near.spherical;
+    // Cells can only be looked up on the leading field
+    let cells = near
+        .bounds(flat)
+        .filter(|_| geo.field() == 0)
+        .map(|bounds| geo.grid().covering(&bounds));
+    Ok(Plan {
+        access: Access::GeoNear {
+            index: index.name().to_string(),
+            path: path.to_string(),
+            near: near.clone(),
+            cells,
+        },
+        sorted: false,
+        covered: false,
+    })
+}
+
+/// The plan looking up the region of a geo query on the leading field of a
+/// geo index, which a `2d` index can only do for `$geoWithin`.
+fn geo_plan(index: &Index, matcher: &Matcher) -> Option<Plan> {
+    let geo = index.geo()?;
+    if geo.field() != 0 {
+        return None;
+    }
+    let path = index.fields()[0].0.join(".");
+    let (_, query) = matcher
+        .geo_queries()
+        .into_iter()
+        .find(|(field, query)| *field == path && (geo.is_sphere() || query.is_flat()))?;
+    Some(Plan {
+        access: Access::GeoScan {
+            index: index.name().to_string(),
+            cells: geo.grid().covering(&query.bounds()),
+        },
+        sorted: false,
+        covered: false,
+    })
+}
+
+/// Whether an index that leaves documents out has every document the query
+/// can match: a sparse index when the filter rules out missing fields, a
+/// partial index when the filter implies its partial filter. Strings in the
+/// bounds can only be told apart under the same collation.
+fn can_answer(index: &Index, predicates: &Predicates, collation: Option<&Collation>) -> bool {
+    let excludes_missing = |predicates: &Predicates, path: &str, multikey: bool| {
+        predicates.exists.contains(path)
+            || !predicates.bounds(path, multikey).contains(&Value::Null)
+    };
+    if index.is_sparse() {
+        return index
+            .fields()
+            .iter()
+            .any(|(path, _)| excludes_missing(predicates, &path.join("."), index.is_multikey()));
+    }
+    if let Some(filter) = index.partial_filter() {
+        let partial = Predicates::new(filter, index.collation());
+        let collated = index.collation() == collation;
+        return partial.exact
+            && partial.paths.keys().all(|path| {
+                let bounds = predicates.bounds(path, false);
+                let partial_bounds = partial.bounds(path, false);
+                predicates.paths.contains_key(path)
+                    && (collated || !(bounds.has_strings() || partial_bounds.has_strings()))
+                    && bounds.within(&partial_bounds)
+                    && (!partial.exists.contains(path) || excludes_missing(predicates, path, false))
+            });
+    }
+    true
+}
+
+/// The plan scanning an index, unless it helps neither with the filter nor
+/// with the sort. Hinted indexes are always used. An index with another
+/// collation than the query's can't bound strings, nor give their order.
+fn index_plan(index: &Index, predicates: &Predicates, query: &Query, hinted: bool) -> Option<Plan> {
+    let collated = index.collation() == query.matcher.collation();
+    let mut bounds: Vec<FieldBounds> = index
+        .fields()
+        .iter()
+        .map(|(path, _)| {
+            let bounds = predicates.bounds(&path.join("."), index.is_multikey());
+            if collated || !bounds.has_strings() {
+                bounds
+            } else {
+                FieldBounds::full()
+            }
+        })
+        .collect();
+    if let Some(hashed) = index.hashed() {
+        bounds[hashed] = bounds[hashed].hashed();
+    }
+    let sort_direction = query
+        .sort
+        .filter(|sort| collated && !sort.is_empty())
+        .and_then(|sort| sort_direction(index, &bounds, sort));
+    // A sparse index leaves out the documents `$exists` rules out
+    let skips_missing = index.is_sparse()
+        && index
+            .fields()
+            .first()
+            .is_some_and(|(path, _)| predicates.exists.contains(&path.join(".")));
+    if !hinted && bounds[0].is_full() && sort_direction.is_none() && !skips_missing {
+        return None;
+    }
+    Some(Plan {
+        covered: is_covered(index, predicates, query),
+        access: Access::IndexScan {
+            index: index.name().to_string(),
+            bounds,
+            reverse: sort_direction.unwrap_or(false),
+        },
+        sorted: sort_direction.is_some(),
+    })
+}
+
+/// The plans scanning a wildcard index for the values of one path, one for
+/// every path the filter bounds to scalars. Paths through array positions
+/// aren't used, as the index keys elements by the path of their array.
+fn wildcard_plans(
+    index: &Index,
+    predicates: &Predicates,
+    collation: Option<&Collation>,
+) -> Vec<Plan> {
+    let Some(wildcard) = index.wildcard() else {
+        return Vec::new();
+    };
+    predicates
+        .paths
+        .keys()
+        .filter(|path| {
+            let path: Vec<String> = path.split('.').map(String::from).collect();
+            wildcard.indexes(&path) && !path.iter().any(|part| part.parse::<usize>().is_ok())
+        })
+        .filter_map(|path| {
+            let bounds = predicates.bounds(path, index.is_multikey());
+            let collated = index.collation() == collation;
+            if bounds.is_full() || !bounds.is_scalar() || (!collated && bounds.has_strings()) {
+                return None;
+            }
+            Some(Plan {
+                access: Access::IndexScan {
+                    index: index.name().to_string(),
+                    bounds: vec![FieldBounds::point(Value::String(path.clone())), bounds],
+                    reverse: false,
+                },
+                sorted: false,
+                covered: false,
+            })
+        })
+        .collect()
+}
+
+/// Whether scanning the index gives the order of the sort, forwards
+/// (`Some(false)`) or backwards (`Some(true)`). Fields bounded to a single
+/// value don't affect the order and may be skipped. Arrays sort by their
+/// smallest or largest element, which a multikey index doesn't give, and
+/// hashes don't keep the order of the values.
+fn sort_direction(index: &Index, bounds: &[FieldBounds], sort: &SortSpec) -> Option<bool> {
+    if index.is_multikey() {
+        return None;
+    }
+    let mut reverse = None;
+    let mut fields = index.fields().iter().zip(bounds).enumerate();
+    for (path, ascending) in sort.keys() {
+        loop {
+            let (i, ((field, descending), bounds)) = fields.next()?;
+            if field == path && index.hashed() == Some(i) {
+                return None;
+            }
+            if field == path {
+                let backwards = *ascending == *descending;
+                if *reverse.get_or_insert(backwards) != backwards {
+                    return None;
+                }
+                break;
+            }
+            let single_value = matches!(bounds.intervals(), [interval] if interval.is_point());
+            if !single_value {
+                return None;
+            }
+        }
+    }
+    reverse
+}
+
+fn is_indexed(index: &Index, path: &str) -> bool {
+    index.paths().any(|field| field.join(".") == path)
+}
+
+/// Whether the bounds on the index say exactly which documents match, so
+/// that the filter needn't be checked on them. Multikey keys hold array
+/// elements rather than the arrays, so they can't tell, and neither can
+/// hashes, which values may share, nor an index with another collation.
+/// Only a sparse index on the single field tells missing fields from nulls.
+fn answers_filter(index: &Index, predicates: &Predicates, collation: Option<&Collation>) -> bool {
+    index.collation() == collation
+        && !index.is_multikey()
+        && index.hashed().is_none()
+        && index.wildcard().is_none()
+        && predicates.exact
+        && predicates.paths.keys().all(|path| is_indexed(index, path))
+        && (predicates.exists.is_empty() || (index.is_sparse() && index.fields().len() == 1))
+}
+
+/// Whether the index has every field the query filters, sorts and projects
+/// on, so that documents can be built from its keys. The keys of an index
+/// with a collation hold sort keys rather than strings.
+fn is_covered(index: &Index, predicates: &Predicates, query: &Query) -> bool {
+    let Some(projection) = query.projection else {
+        return false;
+    };
+    if projection.is_empty()
+        || index.collation().is_some()
+        || !answers_filter(index, predicates, query.matcher.collation())
+    {
+        return false;
+    }
+    let is_indexed = |path: &str| is_indexed(index, path);
+    if let Some(sort) = query.sort {
+        if !sort
+            .keys()
+            .iter()
+            .all(|(path, _)| is_indexed(&path.join(".")))
+        {
+            return false;
+        }
+    }
+    let mut includes_id = true;
+    for (field, value) in projection.iter() {
+        let included = match value {
+            Value::Boolean(_) => value.is_truthy(),
+            value if value.is_number() => value.is_truthy(),
+            _ => return false,
+        };
+        match (field.as_str(), included) {
+            ("_id", false) => includes_id = false,
+            (field, true) if is_indexed(field) => {}
+            _ => return false,
+        }
+    }
+    !includes_id || is_indexed("_id")
+}
+
+impl Plan {
+    /// Finds the documents matching the query, in the order of the plan.
+    pub fn execute<'a>(&self, collection: &'a Collection, matcher: &Matcher) -> Execution<'a> {
+        let mut execution = Execution::new();
+        execution.eof = self.run(collection, matcher, None, &mut execution);
+        execution
+    }
+
+    /// Runs the plan as a candidate in the trial: until it has done `works`
+    /// or found a first batch of documents.
+    fn trial<'a>(
+        &self,
+        collection: &'a Collection,
+        matcher: &Matcher,
+        works: usize,
+    ) -> Execution<'a> {
+        let mut execution = Execution::new();
+        execution.eof = self.run(collection, matcher, Some(works), &mut execution);
+        execution
+    }
+
+    /// Finds documents into `execution`, up to the end of the trial if any.
+    /// Returns whether the plan ran to the end.
+    fn run<'a>(
+        &self,
+        collection: &'a Collection,
+        matcher: &Matcher,
+        trial: Option<usize>,
+        execution: &mut Execution<'a>,
+    ) -> bool {
+        let stopped = |execution: &Execution| {
+            trial.is_some_and(|works| {
+                execution.works() >= works || execution.documents.len() >= TRIAL_RESULTS
+            })
+        };
+        match &self.access {
+            Access::CollectionScan { reverse } => {
+                let mut records: Vec<_> = collection.iter().collect();
+                if *reverse {
+                    records.reverse();
+                }
+                for (id, doc) in records {
+                    execution.docs_examined += 1;
+                    if matcher.matches(doc) {
+                        execution.documents.push((id, Cow::Borrowed(doc)));
+                    }
+                }
+            }
+            Access::IndexScan {
+                index,
+                bounds,
+                reverse,
+            } => {
+                let Some(index) = collection.index(index) else {
+                    return true;
+                };
+                // A multikey index has an entry per element
+                let mut seen = HashSet::new();
+                for entry in scan(index, bounds, *reverse) {
+                    if stopped(execution) {
+                        return false;
+                    }
+                    execution.keys_examined += 1;
+                    let Some((id, key)) = entry else { continue };
+                    if !seen.insert(id) {
+                        continue;
+                    }
+                    let doc = match self.covered.then(|| key_document(index, key)).flatten() {
+                        Some(doc) => Cow::Owned(doc),
+                        None => {
+                            execution.docs_examined += 1;
+                            match collection.get(id) {
+                                Some(doc) => Cow::Borrowed(doc),
+                                None => continue,
+                            }
+                        }
+                    };
+                    if matcher.matches(&doc) {
+                        execution.documents.push((id, doc));
+                    }
+                }
+            }
+            Access::Text { index, query } => {
+                let Some((index, text)) = collection
+                    .index(index)
+                    .and_then(|index| Some((index, index.text()?)))
+                else {
+                    return true;
+                };
+                // A document scores the sum of the scores of the terms of
+                // the query it has
+                let mut scores: BTreeMap<RecordId, f64> = BTreeMap::new();
+                for term in &query.lookup {
+                    let term = KeyValue {
+                        value: Value::String(term.clone()),
+                        descending: false,
+                    };
+                    for (key, id) in index.entries_from(vec![term.clone()]) {
+                        execution.keys_examined += 1;
+                        if key[0] != term {
+                            break;
+                        }
+                        if let Some(score) = key[1].value.as_f64() {
+                            *scores.entry(*id).or_default() += score;
+                        }
+                    }
+                }
+                for (id, score) in scores {
+                    execution.docs_examined += 1;
+                    let Some(doc) = collection.get(id) else {
+                        continue;
+                    };
+                    if text.matches(doc, query) && matcher.matches(doc) {
+                        execution.documents.push((id, Cow::Borrowed(doc)));
+                        execution.text_scores.insert(id, score);
+                    }
+                }
+            }
+            Access::GeoScan { index, cells } => {
+                let Some(index) = collection.index(index) else {
+                    return true;
+                };
+                for id in cell_entries(index, cells, &mut execution.keys_examined) {
+                    if stopped(execution) {
+                        return false;
+                    }
+                    execution.docs_examined += 1;
+                    let Some(doc) = collection.get(id) else {
+                        continue;
+                    };
+                    if matcher.matches(doc) {
+                        execution.documents.push((id, Cow::Borrowed(doc)));
+                    }
+                }
+            }
+            Access::GeoNear {
+                index,
+                path,
+                near,
+                cells,
+            } => {
+                let Some((index, geo)) = collection
+                    .index(index)
+                    .and_then(|index| Some((index, index.geo()?)))
+                else {
+                    return true;
+                };
+                let ids = match cells {
+                    Some(cells) => cell_entries(index, cells, &mut execution.keys_examined),
+                    None => {
+                        let mut seen = HashSet::new();
+                        let mut ids = Vec::new();
+                        for (_, id) in index.entries_from(Vec::new()) {
+                            execution.keys_examined += 1;
+                            if seen.insert(*id) {
+                                ids.push(*id);
+                            }
+                        }
+                        ids
+                    }
+                };
+                let flat = !geo.is_sphere() && !near.spherical;
+                let path: Vec<String> = path.split('.').map(String::from).collect();
+                let mut found = Vec::new();
+                for id in ids {
+                    execution.docs_examined += 1;
+                    let Some(doc) = collection.get(id) else {
+                        continue;
+                    };
+                    let nearest = locations(doc, &path)
+                        .into_iter()
+                        .filter_map(|value| near.nearest(value, flat))
+                        .min_by(|a, b| a.0.total_cmp(&b.0));
+                    let Some((distance, location)) = nearest else {
+                        continue;
+                    };
+                    if near.in_range(distance) && matcher.matches(doc) {
+                        found.push((distance, id, doc, location.clone()));
+                    }
+                }
+                found.sort_by(|a, b| a.0.total_cmp(&b.0));
+                for (distance, id, doc, location) in found {
+                    execution.documents.push((id, Cow::Borrowed(doc)));
+                    execution.distances.insert(id, (distance, location));
+                }
+            }
+        }
+        true
+    }
+
+    /// The stages of the plan as `explain` shows them, with the work they
+    /// did when the plan's execution is given.
+    pub fn explain(
+        &self,
+        collection: &Collection,
+        matcher: &Matcher,
+        execution: Option<&Execution>,
+    ) -> Document {
+        let filter = matcher.filter();
+        let direction = |reverse: bool| if reverse { "backward" } else { "forward" };
+        let mut stage = Document::new();
+        match &self.access {
+            Access::CollectionScan { reverse } => {
+                stage.insert("stage", Value::from("COLLSCAN"));
+                if !filter.is_empty() {
+                    stage.insert("filter", Value::Document(filter.clone()));
+                }
+                stage.insert("direction", Value::from(direction(*reverse)));
+                if let Some(execution) = execution {
+                    stage.insert("nReturned", Value::from(execution.documents.len() as i32));
+                    stage.insert("docsExamined", Value::from(execution.docs_examined as i32));
+                }
+            }
+            Access::IndexScan {
+                index,
+                bounds,
+                reverse,
+            } => {
+                let Some(index) = collection.index(index) else {
+                    stage.insert("stage", Value::from("EOF"));
+                    return stage;
+                };
+                stage = index_scan_stage(index, direction(*reverse));
+                if index.wildcard().is_some() {
+                    let mut key_pattern = Document::new();
+                    for (field, descending) in scanned_fields(index, bounds) {
+                        key_pattern.insert(field, Value::Int32(if descending { -1 } else { 1 }));
+                    }
+                    stage.insert("keyPattern", Value::Document(key_pattern));
+                }
+                stage.insert(
+                    "indexBounds",
+                    Value::Document(index_bounds(index, bounds, *reverse)),
+                );
+                if let Some(execution) = execution {
+                    stage.insert("keysExamined", Value::from(execution.keys_examined as i32));
+                }
+                if self.covered {
+                    return stage;
+                }
+
+                let mut fetch = Document::new();
+                fetch.insert("stage", Value::from("FETCH"));
+                let collation = matcher.collation();
+                let predicates = Predicates::new(filter, collation);
+                if !answers_filter(index, &predicates, collation) {
+                    fetch.insert("filter", Value::Document(filter.clone()));
+                }
+                if let Some(execution) = execution {
+                    fetch.insert("nReturned", Value::from(execution.documents.len() as i32));
+                    fetch.insert("docsExamined", Value::from(execution.docs_examined as i32));
+                }
+                fetch.insert("inputStage", Value::Document(stage));
+                return fetch;
+            }
+            Access::Text { index, query } => {
+                let Some(index) = collection.index(index) else {
+                    stage.insert("stage", Value::from("EOF"));
+                    return stage;
+                };
+                // A scan of the entries of every term, from the highest
+                // scores down
+                let scans: Vec<Value> = query
+                    .lookup
+                    .iter()
+                    .map(|_| Value::Document(index_scan_stage(index, "backward")))
+                    .collect();
+                let mut or = Document::new();
+                or.insert("stage", Value::from("TEXT_OR"));
+                if let Some(execution) = execution {
+                    or.insert("keysExamined", Value::from(execution.keys_examined as i32));
+                }
+                or.insert("inputStages", Value::from(scans));
+
+                let mut fetch = Document::new();
+                fetch.insert("stage", Value::from("FETCH"));
+                let mut rest = filter.clone();
+                rest.remove("$text");
+                if !rest.is_empty() {
+                    fetch.insert("filter", Value::Document(rest));
+                }
+                if let Some(execution) = execution {
+                    fetch.insert("docsExamined", Value::from(execution.docs_examined as i32));
+                }
+                fetch.insert("inputStage", Value::Document(or));
+
+                stage.insert("stage", Value::from("TEXT_MATCH"));
+                stage.insert("indexName", Value::from(index.name()));
+                stage.insert("parsedTextQuery", Value::Document(query.explain()));
+                stage.insert("textIndexVersion", Value::from(TEXT_INDEX_VERSION));
+                if let Some(execution) = execution {
+                    stage.insert("nReturned", Value::from(execution.documents.len() as i32));
+                }
+                stage.insert("inputStage", Value::Document(fetch));
+            }
+            Access::GeoScan { index, cells } => {
+                let Some(index) = collection.index(index) else {
+                    stage.insert("stage", Value::from("EOF"));
+                    return stage;
+                };
+                let mut scan = geo_scan_stage(index, Some(cells));
+                if let Some(execution) = execution {
+                    scan.insert("keysExamined", Value::from(execution.keys_examined as i32));
+                }
+                stage.insert("stage", Value::from("FETCH"));
+                stage.insert("filter", Value::Document(filter.clone()));
+                if let Some(execution) = execution {
+                    stage.insert("nReturned", Value::from(execution.documents.len() as i32));
+                    stage.insert("docsExamined", Value::from(execution.docs_examined as i32));
+                }
+                stage.insert("inputStage", Value::Document(scan));
+            }
+            Access::GeoNear {
+                index, path, cells, ..
+            } => {
+                let Some((index, geo)) = collection
+                    .index(index)
+                    .and_then(|index| Some((index, index.geo()?)))
+                else {
+                    stage.insert("stage", Value::from("EOF"));
+                    return stage;
+                };
+                let mut scan = geo_scan_stage(index, cells.as_deref());
+                if let Some(execution) = execution {
+                    scan.insert("keysExamined", Value::from(execution.keys_examined as i32));
+                }
+                let mut fetch = Document::new();
+                fetch.insert("stage", Value::from("FETCH"));
+                let mut rest = filter.clone();
+                rest.remove(path);
+                if !rest.is_empty() {
+                    fetch.insert("filter", Value::Document(rest));
+                }
+                if let Some(execution) = execution {
+                    fetch.insert("docsExamined", Value::from(execution.docs_examined as i32));
+                }
+                fetch.insert("inputStage", Value::Document(scan));
+
+                let name = if geo.is_sphere() {
+                    "GEO_NEAR_2DSPHERE"
+                } else {
+                    "GEO_NEAR_2D"
+                };
+                stage.insert("stage", Value::from(name));
+                stage.insert("keyPattern", Value::Document(index.key_pattern().clone()));
+                stage.insert("indexName", Value::from(index.name()));
+                stage.insert("indexVersion", Value::from(index.version()));
+                if let Some(execution) = execution {
+                    stage.insert("nReturned", Value::from(execution.documents.len() as i32));
+                }
+                stage.insert("inputStage", Value::Document(fetch));
+            }
+        }
+        stage
+    }
+}
+
+/// The `IXSCAN` stage of a geo index, looking up some cells on the geo field
+/// or scanning the whole index.
+fn geo_scan_stage(index: &Index, cells: Option<&[String]>) -> Document {
+    let mut stage = index_scan_stage(index, "forward");
+    let geo_field = index.geo().map(|geo| geo.field());
+    let mut bounds = Document::new();
+    for (i, (path, _)) in index.fields().iter().enumerate() {
+        let intervals = match cells {
+            Some(cells) if geo_field == Some(i) => cells
+                .iter()
+                .map(|cell| Value::String(format!("cell {}", cell)))
+                .collect(),
+            _ => vec![Value::from("[MinKey, MaxKey]")],
+        };
+        bounds.insert(path.join("."), Value::from(intervals));
+    }
+    stage.insert("indexBounds", Value::Document(bounds));
+    stage
+}
+
+/// The `IXSCAN` stage of an index without its bounds.
+fn index_scan_stage(index: &Index, direction: &str) -> Document {
+    let mut stage = Document::new();
+    stage.insert("stage", Value::from("IXSCAN"));
+    stage.insert("keyPattern", Value::Document(index.key_pattern().clone()));
+    stage.insert("indexName", Value::from(index.name()));
+    stage.insert("isMultiKey", Value::from(index.is_multikey()));
+    stage.insert("isUnique", Value::from(index.is_unique()));
+    stage.insert("isSparse", Value::from(index.is_sparse()));
+    stage.insert("isPartial", Value::from(index.partial_filter().is_some()));
+    stage.insert("indexVersion", Value::from(index.version()));
+    stage.insert("direction", Value::from(direction));
+    stage
+}
+
+/// The fields of the keys an index scan goes through: those of the key
+/// pattern, or `$_path` and the path scanned for a wildcard index.
+fn scanned_fields(index: &Index, bounds: &[FieldBounds]) -> Vec<(String, bool)> {
+    let mut fields: Vec<(String, bool)> = index
+        .fields()
+        .iter()
+        .map(|(path, descending)| (path.join("."), *descending))
+        .collect();
+    if index.wildcard().is_some() {
+        if let [interval] = bounds[0].intervals() {
+            if let Value::String(path) = &interval.start {
+                fields[1].0 = path.clone();
+            }
+        }
+        fields[0].0 = PATH_FIELD.to_string();
+    }
+    fields
+}
+
+/// The intervals scanned on every field, in the order of the scan as mongod
+/// shows them: `[MaxKey, MinKey]` for a descending field scanned forwards.
+fn index_bounds(index: &Index, bounds: &[FieldBounds], reverse: bool) -> Document {
+    let mut shown = Document::new();
+    for ((path, descending), bounds) in scanned_fields(index, bounds).into_iter().zip(bounds) {
+        let mut intervals: Vec<Value> = bounds
+            .intervals()
+            .iter()
+            .map(|interval| {
+                if descending != reverse {
+                    let flipped = Interval {
+                        start: interval.end.clone(),
+                        start_inclusive: interval.end_inclusive,
+                        end: interval.start.clone(),
+                        end_inclusive: interval.start_inclusive,
+                    };
+                    Value::String(flipped.to_string())
+                } else {
+                    Value::String(interval.to_string())
+                }
+            })
+            .collect();
+        if descending != reverse {
+            intervals.reverse();
+        }
+        shown.insert(path, Value::from(intervals));
+    }
+    shown
+}
+
+/// The entries within the bounds, in index order or backwards, read as they
+/// are needed. The intervals of the first field are sought; the other
+/// fields are checked key by key. Every item is a key examined, `None` for
+/// keys out of the bounds, such as the one ending an interval.
+fn scan<'i>(
+    index: &'i Index,
+    bounds: &'i [FieldBounds],
+    reverse: bool,
+) -> impl Iterator<Item = Option<(RecordId, &'i IndexKey)>> + 'i {
+    let descending = index.fields()[0].1;
+    let mut intervals = bounds[0].intervals().to_vec();
+    if descending != reverse {
+        intervals.reverse();
+    }
+    intervals.into_iter().flat_map(move |interval| {
+        // The ends of the interval in index order
+        let (low, low_inclusive, high, high_inclusive) = if descending {
+            (
+                interval.end,
+                interval.end_inclusive,
+                interval.start,
+                interval.start_inclusive,
+            )
+        } else {
+            (
+                interval.start,
+                interval.start_inclusive,
+                interval.end,
+                interval.end_inclusive,
+            )
+        };
+        let low = KeyValue {
+            value: low,
+            descending,
+        };
+        let high = KeyValue {
+            value: high,
+            descending,
+        };
+        // The ends in the order of the scan
+        let (first, first_inclusive, last, last_inclusive) = if reverse {
+            (high, high_inclusive, low, low_inclusive)
+        } else {
+            (low, low_inclusive, high, high_inclusive)
+        };
+        let entries: Box<dyn Iterator<Item = &'i (IndexKey, RecordId)>> = if reverse {
+            Box::new(index.entries_back_from(first.clone()))
+        } else {
+            Box::new(index.entries_from(vec![first.clone()]))
+        };
+        let mut ended = false;
+        entries.map_while(move |(key, id)| {
+            if ended {
+                return None;
+            }
+            let past = match key[0].cmp(&last) {
+                ordering if reverse => ordering.reverse(),
+                ordering => ordering,
+            };
+            if past.is_gt() || (past.is_eq() && !last_inclusive) {
+                ended = true;
+                return Some(None);
+            }
+            if !first_inclusive && key[0] == first {
+                return Some(None);
+            }
+            let within = key[1..]
+                .iter()
+                .zip(&bounds[1..])
+                .all(|(part, bounds)| bounds.contains(&part.value));
+            Some(within.then_some((*id, key)))
+        })
+    })
+}
+
+/// The documents a geo index has in some cells, in the order found: those
+/// keyed by the cells or cells within them, found by their common prefix, and
+/// those keyed by cells holding them.
+fn cell_entries(index: &Index, cells: &[String], keys_examined: &mut usize) -> Vec<RecordId> {
+    let mut seen = HashSet::new();
+    let mut ids = Vec::new();
+    let mut ancestors = HashSet::new();
+    for cell in cells {
+        let start = KeyValue {
+            value: Value::String(cell.clone()),
+            descending: false,
+        };
+        for (key, id) in index.entries_from(vec![start]) {
+            *keys_examined += 1;
+            match &key[0].value {
+                Value::String(found) if found.starts_with(cell.as_str()) => {}
+                _ => break,
+            }
+            if seen.insert(*id) {
+                ids.push(*id);
+            }
+        }
+        for end in 0..cell.len() {
+            let ancestor = &cell[..end];
+            if !ancestors.insert(ancestor) {
+                continue;
+            }
+            let key = KeyValue {
+                value: Value::String(ancestor.to_string()),
+                descending: false,
+            };
+            for (found, id) in index.entries_from(vec![key.clone()]) {
+                *keys_examined += 1;
+                if found[0] != key {
+                    break;
+                }
+                if seen.insert(*id) {
+                    ids.push(*id);
+                }
+            }
+        }
+    }
+    ids
+}
+
+/// The document made of the indexed fields of a key, unless some field is
+/// null: the key doesn't tell a null from a missing field.
+fn key_document(index: &Index, key: &IndexKey) -> Option<Document> {
+    let mut doc = Document::new();
+    for ((path, _), part) in index.fields().iter().zip(key) {
+        if part.value.is_null_or_undefined() {
+            return None;
+        }
+        insert_path(&mut doc, path, part.value.clone());
+    }
+    Some(doc)
+}
+
+fn insert_path(doc: &mut Document, path: &[String], value: Value) {
+    let Some((first, rest)) = path.split_first() else {
+        return;
+    };
+    if rest.is_empty() {
+        doc.insert(first.clone(), value);
+        return;
+    }
+    if !matches!(doc.get(first), Some(Value::Document(_))) {
+        doc.insert(first.clone(), Value::Document(Document::new()));
+    }
+    if let Some(Value::Document(child)) = doc.get_mut(first) {
+        insert_path(child, rest, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn collection() -> Collection {
+        let mut collection = Collection::new("test.c");
+        for spec in [
+            "{ key: { a: 1 } }",
+            "{ key: { b: -1, c: 1 } }",
+            "{ key: { s: 1 }, sparse: true }",
+        ] {
+            let index = collection.build_index(Index::parse(&doc(spec)).unwrap());
+            collection.add_index(index.unwrap());
+        }
+        for i in 0..20 {
+            collection
+                .insert(doc(&format!(
+                    "{{ _id: {}, a: {}, b: {}, c: {}, d: 1 }}",
+                    i,
+                    i,
+                    i % 4,
+                    i % 5
+                )))
+                .unwrap();
+        }
+        collection.insert(doc("{ _id: 20, s: 1 }")).unwrap();
+        collection
+    }
+
+    fn run<'a>(
+        collection: &'a Collection,
+        filter: &str,
+        sort: Option<&str>,
+        projection: Option<&str>,
+        hint: Option<&str>,
+    ) -> CommandResult<(Planned<'a>, Vec<Document>)> {
+        let matcher = Matcher::new(&doc(filter))?;
+        let sort = sort.map(|sort| SortSpec::parse(&doc(sort))).transpose()?;
+        let projection = projection.map(doc);
+        let hint = hint.map(crate::bson::json::value);
+        let query = Query {
+            matcher: &matcher,
+            sort: sort.as_ref(),
+            projection: projection.as_ref(),
+            hint: hint.as_ref(),
+        };
+        let planned = plan(Some(collection), &query)?;
+        let documents = planned
+            .execution
+            .documents
+            .iter()
+            .map(|(_, doc)| doc.clone().into_owned())
+            .collect();
+        Ok((planned, documents))
+    }
+
+    fn index_name(plan: &Plan) -> Option<&str> {
+        match &plan.access {
+            Access::IndexScan { index, .. } => Some(index),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_index_selection() {
+        let collection = collection();
+
+        let (planned, documents) =
+            run(&collection, "{ a: { $gte: 3, $lt: 6 } }", None, None, None).unwrap();
+        assert_eq!(index_name(&planned.winner), Some("a_1"));
+        assert_eq!(documents.len(), 3);
+        assert_eq!(planned.execution.docs_examined, 3);
+        assert_eq!(planned.execution.keys_examined, 4);
+
+        // The compound index bounds both fields; the other filter field is
+        // checked on the fetched documents
+        let (planned, documents) = run(
+            &collection,
+            "{ b: 2, c: { $in: [0, 2] }, a: { $gt: 5 } }",
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(index_name(&planned.winner), Some("b_-1_c_1"));
+        let ids: Vec<_> = documents
+            .iter()
+            .map(|doc| doc.get("_id").unwrap().clone())
+            .collect();
+        assert_eq!(ids, vec![Value::Int32(10)]);
+
+        let (planned, _) = run(&collection, "{ d: 1 }", None, None, None).unwrap();
+        assert_eq!(index_name(&planned.winner), None);
+        assert_eq!(planned.execution.docs_examined, 21);
+
+        // A sparse index can't find documents missing the field
+        let (planned, _) = run(&collection, "{ s: null }", None, None, None).unwrap();
+        assert_eq!(index_name(&planned.winner), None);
+        let (planned, documents) = run(&collection, "{ s: 1 }", None, None, None).unwrap();
+        assert_eq!(index_name(&planned.winner), Some("s_1"));
+        assert_eq!(documents, vec![doc("{ _id: 20, s: 1 }")]);
+    }
+
+    #[test]
+    fn test_trial_stops_losing_plans() {
+        let collection = collection();
+        let (planned, documents) = run(
+            &collection,
+            "{ a: { $lt: 3 }, b: { $gte: 0 } }",
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(index_name(&planned.winner), Some("a_1"));
+        assert_eq!(documents.len(), 3);
+        assert_eq!(planned.execution.works(), 7);
+        // The other index would scan every key, but stops once it did as
+        // much work as the winner, finishing with the fetch of its last key
+        let (plan, execution) = &planned.rejected[0];
+        assert_eq!(index_name(plan), Some("b_-1_c_1"));
+        assert_eq!(execution.keys_examined, 4);
+        assert_eq!(execution.works(), 8);
+        assert!(!execution.completed());
+    }
+
+    #[test]
+    fn test_sort_covering_and_hints() {
+        let collection = collection();
+
+        // Equality on `b` leaves the index ordered by `c`, backwards here
+        let (planned, documents) =
+            run(&collection, "{ b: 1 }", Some("{ c: -1 }"), None, None).unwrap();
+        assert!(planned.winner.sorted);
+        let c: Vec<_> = documents
+            .iter()
+            .map(|doc| doc.get("c").unwrap().clone())
+            .collect();
+        assert_eq!(c, [4, 3, 2, 1, 0].map(Value::Int32).to_vec());
+        let (planned, _) = run(&collection, "{}", Some("{ a: -1 }"), None, None).unwrap();
+        assert_eq!(index_name(&planned.winner), Some("a_1"));
+        assert!(planned.winner.sorted);
+
+        let (planned, documents) = run(
+            &collection,
+            "{ a: { $lt: 2 } }",
+            None,
+            Some("{ a: 1, _id: 0 }"),
+            None,
+        )
+        .unwrap();
+        assert!(planned.winner.covered);
+        assert_eq!(planned.execution.docs_examined, 0);
+        assert_eq!(documents, vec![doc("{ a: 0 }"), doc("{ a: 1 }")]);
+        let (planned, _) = run(
+            &collection,
+            "{ a: { $lt: 2 } }",
+            None,
+            Some("{ a: 1 }"),
+            None,
+        )
+        .unwrap();
+        assert!(!planned.winner.covered);
+
+        let (planned, documents) = run(
+            &collection,
+            "{ a: { $lt: 2 } }",
+            None,
+            None,
+            Some("{ $natural: -1 }"),
+        )
+        .unwrap();
+        assert_eq!(index_name(&planned.winner), None);
+        assert_eq!(documents[0].get("_id"), Some(&Value::Int32(1)));
+        let (planned, _) = run(&collection, "{ a: 1 }", None, None, Some("'b_-1_c_1'")).unwrap();
+        assert_eq!(index_name(&planned.winner), Some("b_-1_c_1"));
+        assert_eq!(
+            run(&collection, "{}", None, None, Some("{ x: 1 }"))
+                .unwrap_err()
+                .code,
+            ErrorCode::BadValue
+        );
+    }
+
+    #[test]
+    fn test_exists_keeps_nulls() {
+        let collection = |documents: &[&str]| {
+            let mut collection = Collection::new("test.c");
+            for spec in ["{ key: { e: 1 } }", "{ key: { e: -1 }, sparse: true }"] {
+                let index = collection.build_index(Index::parse(&doc(spec)).unwrap());
+                collection.add_index(index.unwrap());
+            }
+            for document in documents {
+                collection.insert(doc(document)).unwrap();
+            }
+            collection
+        };
+        let documents = ["{ _id: 1, e: 1 }", "{ _id: 2, e: null }", "{ _id: 3 }"];
+        let filter = "{ e: { $exists: true } }";
+        let multikey = collection(&[&documents[..], &["{ _id: 4, e: [null] }"]].concat());
+        let ids = |hint: &str| {
+            let (planned, documents) = run(&multikey, filter, None, None, Some(hint)).unwrap();
+            let mut ids: Vec<_> = documents
+                .iter()
+                .map(|doc| doc.get("_id").unwrap().clone())
+                .collect();
+            ids.sort_by(Value::compare);
+            (index_name(&planned.winner).map(String::from), ids)
+        };
+        let scanned = ids("{ $natural: 1 }");
+        assert_eq!(scanned.1, [1, 2, 4].map(Value::Int32).to_vec());
+        assert_eq!(ids("'e_1'"), (Some("e_1".to_string()), scanned.1.clone()));
+        assert_eq!(ids("'e_-1'"), (Some("e_-1".to_string()), scanned.1));
+
+        // Only the sparse index needs no filter on the documents
+        let collection = collection(&documents);
+        let (planned, documents) = run(
+            &collection,
+            filter,
+            None,
+            Some("{ e: 1, _id: 0 }"),
+            Some("'e_-1'"),
+        )
+        .unwrap();
+        // Null keys are still fetched, as they could be missing fields
+        assert!(planned.winner.covered);
+        assert_eq!(documents.len(), 2);
+        assert_eq!(planned.execution.docs_examined, 1);
+        let (planned, _) = run(
+            &collection,
+            filter,
+            None,
+            Some("{ e: 1, _id: 0 }"),
+            Some("'e_1'"),
+        )
+        .unwrap();
+        assert!(!planned.winner.covered);
+    }
+
+    #[test]
+    fn test_hashed_and_wildcard_indexes() {
+        let mut collection = Collection::new("test.c");
+        for spec in [
+            "{ key: { h: 'hashed' } }",
+            "{ key: { '$**': 1 }, wildcardProjection: { h: 0 } }",
+        ] {
+            let index = collection.build_index(Index::parse(&doc(spec)).unwrap());
+            collection.add_index(index.unwrap());
+        }
+        for i in 0..10 {
+            collection
+                .insert(doc(&format!(
+                    "{{ _id: {}, h: {}, x: {{ y: [{}, {{ z: {} }}] }} }}",
+                    i,
+                    i % 3,
+                    i,
+                    i % 2
+                )))
+                .unwrap();
+        }
+        assert_eq!(
+            collection
+                .insert(doc("{ _id: 10, h: [1] }"))
+                .unwrap_err()
+                .code,
+            ErrorCode::Location(16766)
+        );
+
+        // Equality on a hashed field looks its hashes up, fetching the
+        // documents with `h: 2` for 2.5 too; ranges can't
+        let (planned, documents) =
+            run(&collection, "{ h: { $in: [1, 2.5] } }", None, None, None).unwrap();
+        assert_eq!(index_name(&planned.winner), Some("h_hashed"));
+        assert_eq!(documents.len(), 3);
+        assert_eq!(planned.execution.docs_examined, 6);
+        let (planned, _) = run(&collection, "{ h: { $gt: 1 } }", None, None, None).unwrap();
+        assert_eq!(index_name(&planned.winner), None);
+        let (planned, _) = run(&collection, "{ h: 1 }", Some("{ h: 1 }"), None, None).unwrap();
+        assert!(!planned.winner.sorted);
+
+        // A wildcard index answers any one path, through arrays too
+        let (planned, documents) = run(
+            &collection,
+            "{ 'x.y.z': 1, 'x.y': { $gte: 8 } }",
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(index_name(&planned.winner), Some("$**_1"));
+        assert_eq!(
+            documents,
+            vec![doc("{ _id: 9, h: 0, x: { y: [9, { z: 1 }] } }")]
+        );
+        assert_eq!(planned.execution.docs_examined, 2);
+        let explained =
+            planned
+                .winner
+                .explain(&collection, &Matcher::new(&doc("{}")).unwrap(), None);
+        assert_eq!(
+            explained.get("inputStage").and_then(|scan| match scan {
+                Value::Document(scan) => scan.get("indexBounds").cloned(),
+                _ => None,
+            }),
+            Some(crate::bson::json::value(
+                "{ '$_path': ['[\"x.y\", \"x.y\"]'], 'x.y': ['[8, inf.0]'] }"
+            ))
+        );
+        // Neither missing fields nor excluded paths are indexed
+        for filter in [
+            "{ 'x.w': null }",
+            "{ 'x.y': { $ne: 1 } }",
+            "{ 'x.y.0': 1 }",
+            "{ _id: 1, h: 1 }",
+        ] {
+            let (planned, _) = run(&collection, filter, None, None, None).unwrap();
+            assert_ne!(index_name(&planned.winner), Some("$**_1"), "{}", filter);
+        }
+
+        for spec in [
+            "{ key: { a: 'hashed' }, unique: true }",
+            "{ key: { a: 'hashed', b: 'hashed' } }",
+            "{ key: { '$**': 1, a: 1 } }",
+            "{ key: { 'a.$**': 1 }, wildcardProjection: { b: 1 } }",
+            "{ key: { '$**': 1 }, wildcardProjection: { a: 1, b: 0 } }",
+            "{ key: { '$**': 1 }, sparse: true }",
+            "{ key: { 'a.$**.b': 1 } }",
+        ] {
+            assert!(Index::parse(&doc(spec)).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_collated_indexes() {
+        let mut collection = Collection::new("test.c");
+        let index = Index::parse(&doc(
+            "{ key: { name: 1 }, collation: { locale: 'en', strength: 2 } }",
+        ));
+        let index = collection.build_index(index.unwrap()).unwrap();
+        collection.add_index(index);
+        for (i, name) in ["Bob", "alice", "bob", "Carol"].iter().enumerate() {
+            collection
+                .insert(doc(&format!("{{ _id: {}, name: '{}' }}", i, name)))
+                .unwrap();
+        }
+        let plan = |filter: &str, sort: Option<&str>, collation: Option<&str>| {
+            let collation = collation.map(|spec| Collation::parse(&doc(spec)).unwrap().unwrap());
+            let matcher = Matcher::new(&doc(filter))
+                .unwrap()
+                .with_collation(collation.as_ref());
+            let sort = sort.map(|sort| {
+                SortSpec::parse(&doc(sort))
+                    .unwrap()
+                    .with_collation(collation.as_ref())
+            });
+            let query = Query {
+                matcher: &matcher,
+                sort: sort.as_ref(),
+                projection: None,
+                hint: None,
+            };
+            let planned = plan(Some(&collection), &query).unwrap();
+            let ids: Vec<_> = planned
+                .execution
+                .documents
+                .iter()
+                .map(|(_, doc)| doc.get("_id").unwrap().clone())
+                .collect();
+            (
+                index_name(&planned.winner).map(String::from),
+                planned.winner.sorted,
+                planned.execution.keys_examined,
+                ids,
+            )
+        };
+
+        // A query of the same collation bounds and sorts strings with it
+        let strength_2 = Some("{ locale: 'en', strength: 2 }");
+        assert_eq!(
+            plan("{ name: 'BOB' }", Some("{ name: 1 }"), strength_2),
+            (
+                Some("name_1".to_string()),
+                true,
+                3,
+                [0, 2].map(Value::Int32).to_vec()
+            )
+        );
+        // Any other collation can't bound strings, but still bounds numbers
+        let (index, sorted, _, ids) = plan("{ name: 'bob' }", Some("{ name: 1 }"), None);
+        assert_eq!(index, None);
+        assert!(!sorted);
+        assert_eq!(ids, vec![Value::Int32(2)]);
+        let (index, _, keys_examined, ids) = plan("{ name: { $in: [1, 2] } }", None, None);
+        assert_eq!(index.as_deref(), Some("name_1"));
+        assert_eq!((keys_examined, ids.len()), (2, 0));
+    }
+}
@@ -0,0 +1,184 @@
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+/// A sort specification such as `{ a: 1, b: -1 }`.
+#[derive(Debug, Clone, Default)]
+pub struct SortSpec {
+    keys: Vec<(Vec<String>, bool)>,
+    /// The collation strings are sorted by, bytewise without.
+    collation: Option<Collation>,
+}
+
+impl SortSpec {
+    pub fn parse(spec: &Document) -> CommandResult<Self> {
+        let mut keys = Vec::new();
+        for (field, direction) in spec.iter() {
+            let ascending = match direction.as_f64() {
+                Some(1.0) => true,
+                Some(-1.0) => false,
+                _ => {
+                    return Err(CommandError::new(
+                        ErrorCode::Location(15975),
+                        "$sort key ordering must be 1 (for ascending) or -1 (for descending)",
+                    ));
+                }
+            };
+            if field.is_empty() || field.split('.').any(str::is_empty) {
+                return Err(CommandError::new(
+                    ErrorCode::Location(15998),
+                    "FieldPath field names may not be empty strings.",
+                ));
+            }
+            keys.push((field.split('.').map(String::from).collect(), ascending));
+        }
+        Ok(Self {
+            keys,
+            collation: None,
+        })
+    }
+
+    /// Sorts strings under `collation`.
+    pub fn with_collation(mut self, collation: Option<&Collation>) -> Self {
+        self.collation = collation.cloned();
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// The paths sorted on, and whether each is ascending.
+    pub fn keys(&self) -> &[(Vec<String>, bool)] {
+        &self.keys
+    }
+
+    /// The path sorted on, when the specification has a single key.
+    pub fn single_path(&self) -> Option<&[String]> {
+        match &self.keys[..] {
+            [(path, _)] => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Compares two documents. For fields holding arrays, an ascending sort
+    /// uses the smallest element and a descending sort the largest.
+    pub fn compare(&self, a: &Document, b: &Document) -> Ordering {
+        let collation = self.collation.as_ref();
+        for (path, ascending) in &self.keys {
+            let ordering = sort_key(a, path, *ascending, collation)
+                .compare_with(&sort_key(b, path, *ascending, collation), collation);
+            let ordering = if *ascending {
+                ordering
+            } else {
+                ordering.reverse()
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+fn sort_key(
+    doc: &Document,
+    path: &[String],
+    ascending: bool,
+    collation: Option<&Collation>,
+) -> Value {
+    let mut values = Vec::new();
+    let found = collect(doc, path, &mut values);
+    let pick = if ascending {
+        values
+            .into_iter()
+            .min_by(|a, b| a.compare_with(b, collation))
+    } else {
+        values
+            .into_iter()
+            .max_by(|a, b| a.compare_with(b, collation))
+    };
+    match pick {
+        Some(value) => value,
+        // An empty array sorts before null
+        None if found => Value::Undefined,
+        None => Value::Null,
+    }
+}
+
+/// Collects the values at `path`, expanding arrays. Returns whether the path
+/// led anywhere.
+fn collect(doc: &Document, path: &[String], values: &mut Vec<Value>) -> bool {
+    let Some((first, rest)) = path.split_first() else {
+        return false;
+    };
+    match doc.get(first) {
+        None => false,
+        Some(value) => collect_value(value, rest, values),
+    }
+}
+
+fn collect_value(value: &Value, path: &[String], values: &mut Vec<Value>) -> bool {
+    match value {
+        Value::Array(items) => {
+            let mut found = path.is_empty();
+            for item in &items.0 {
+                match item {
+                    Value::Document(doc) if !path.is_empty() => found |= collect(doc, path, values),
+                    item if path.is_empty() => values.push(item.clone()),
+                    _ => {}
+                }
+            }
+            found
+        }
+        value if path.is_empty() => {
+            values.push(value.clone());
+            true
+        }
+        Value::Document(doc) => collect(doc, path, values),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::doc;
+
+    fn sorted(spec: &str, documents: &[&str]) -> Vec<Document> {
+        let spec = SortSpec::parse(&doc(spec)).unwrap();
+        let mut documents: Vec<Document> = documents.iter().map(|d| doc(d)).collect();
+        documents.sort_by(|a, b| spec.compare(a, b));
+        documents
+    }
+
+    #[test]
+    fn test_sort_orders_by_keys_and_arrays() {
+        assert_eq!(
+            sorted(
+                "{ a: 1, b: -1 }",
+                &[
+                    "{ a: 2, b: 1 }",
+                    "{ a: 1, b: 1 }",
+                    "{ a: 1, b: 2 }",
+                    "{ b: 3 }"
+                ]
+            ),
+            vec![
+                doc("{ b: 3 }"),
+                doc("{ a: 1, b: 2 }"),
+                doc("{ a: 1, b: 1 }"),
+                doc("{ a: 2, b: 1 }"),
+            ]
+        );
+        assert_eq!(
+            sorted("{ a: -1 }", &["{ a: [1, 5] }", "{ a: 3 }"]),
+            vec![doc("{ a: [1, 5] }"), doc("{ a: 3 }")]
+        );
+        assert!(SortSpec::parse(&doc("{ a: 2 }")).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+//! Index builds on collections holding documents. Like mongod's, a build
+//! holds an intent lock on its collection, so reads and writes go on while
+//! it scans the documents a batch at a time between other operations. Writes
+//! to the documents already scanned are applied to the indexes being built,
+//! later documents are indexed when the scan reaches them. The indexes of a
+//! build are committed together once the scan ends, or the build aborts
+//! leaving the collection as it was.
+//!
+//! Unlike mongod, there is nothing to recover from a crash: the storage is
+//! in memory, so a build is never partly committed. Its indexes are kept in
+//! the build, where queries don't see them, and only added to the collection
+//! on commit. A build that aborts, whether on an error, on `dropIndexes` or
+//! with its collection dropped, is discarded along with them.
+
+use std::{collections::BTreeMap, ops::Bound, time::Instant};
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult},
+};
+
+use super::{Index, RecordId};
+
+/// Identifies an operation in `currentOp`.
+pub type OpId = i32;
+
+/// The number of documents scanned before letting other operations run,
+/// like mongod's `internalQueryExecYieldIterations`.
+const BATCH_SIZE: usize = 1000;
+
+/// The message of a scanning build in `currentOp`. Like mongod, the progress
+/// after it repeats the message.
+const SCANNING: &str = "Index Build: scanning collection";
+
+#[derive(Debug)]
+pub struct IndexBuild {
+    op_id: OpId,
+    /// The `createIndexes` command that started the build.
+    command: Document,
+    indexes: Vec<Index>,
+    /// The reply to the command once the build commits.
+    reply: Document,
+    /// The last record scanned, none before the first.
+    scanned: Option<RecordId>,
+    done: usize,
+    /// The number of documents when the build started, which the progress
+    /// is reported against.
+    total: usize,
+    started: Instant,
+    /// Why the build must abort, set by a write the indexes couldn't take or
+    /// by `dropIndexes`.
+    error: Option<CommandError>,
+}
+
+impl IndexBuild {
+    pub fn new(
+        op_id: OpId,
+        command: Document,
+        indexes: Vec<Index>,
+        reply: Document,
+        total: usize,
+    ) -> Self {
+        Self {
+            op_id,
+            command,
+            indexes,
+            reply,
+            scanned: None,
+            done: 0,
+            total,
+            started: Instant::now(),
+            error: None,
+        }
+    }
+
+    pub fn op_id(&self) -> OpId {
+        self.op_id
+    }
+
+    pub fn indexes(&self) -> &[Index] {
+        &self.indexes
+    }
+
+    /// Makes the build abort at its next step with `error`.
+    pub fn abort(&mut self, error: CommandError) {
+        self.error.get_or_insert(error);
+    }
+
+    /// The build as reported by `currentOp`.
+    pub fn current_op(&self, namespace: &str) -> Document {
+        let running = self.started.elapsed();
+        let mut progress = Document::new();
+        progress.insert("done", Value::Int64(self.done as i64));
+        progress.insert("total", Value::Int64(self.total as i64));
+        // Intent locks, held for the whole scan
+        let mut locks = Document::new();
+        for resource in ["Global", "Database", "Collection"] {
+            locks.insert(resource, Value::String("w".into()));
+        }
+
+        let mut op = Document::new();
+        op.insert("type", Value::String("op".into()));
+        op.insert("desc", Value::String("IndexBuildsCoordinator".into()));
+        op.insert("active", Value::Boolean(true));
+        op.insert("opid", Value::Int32(self.op_id));
+        op.insert("secs_running", Value::Int64(running.as_secs() as i64));
+        op.insert(
+            "microsecs_running",
+            Value::Int64(running.as_micros() as i64),
+        );
+        op.insert("op", Value::String("command".into()));
+        op.insert("ns", Value::String(namespace.to_string()));
+        op.insert("command", Value::Document(self.command.clone()));
+        op.insert(
+            "msg",
+            Value::String(format!(
+                "{} {}: {}/{} {}%",
+                SCANNING,
+                SCANNING,
+                self.done,
+                self.total,
+                (self.done * 100).checked_div(self.total).unwrap_or(100)
+            )),
+        );
+        op.insert("progress", Value::Document(progress));
+        op.insert("locks", Value::Document(locks));
+        op.insert("waitingForLock", Value::Boolean(false));
+        op
+    }
+
+    /// Indexes the documents after the last one scanned, up to a batch.
+    /// Returns whether the scan reached the end of the collection.
+    pub(super) fn scan(&mut self, records: &BTreeMap<RecordId, Document>) -> CommandResult<bool> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        let start = match self.scanned {
+            Some(id) => Bound::Excluded(id),
+            None => Bound::Unbounded,
+        };
+        let mut scanned = 0;
+        for (id, doc) in records.range((start, Bound::Unbounded)).take(BATCH_SIZE) {
+            for index in &mut self.indexes {
+                let keys = index.keys(doc)?;
+                index.insert(*id, keys);
+            }
+            self.scanned = Some(*id);
+            self.done += 1;
+            scanned += 1;
+        }
+        Ok(scanned < BATCH_SIZE)
+    }
+
+    /// Applies a write to the document with record id `id`: `old` is the
+    /// document replaced or removed, `new` the one written. Documents the
+    /// scan didn't reach yet are left to it. Writes aren't checked against
+    /// unique indexes, which are checked as a whole on commit.
+    pub(super) fn apply(&mut self, id: RecordId, old: Option<&Document>, new: Option<&Document>) {
+        if !matches!(self.scanned, Some(scanned) if id <= scanned) {
+            return;
+        }
+        for index in &mut self.indexes {
+            if let Some(old) = old {
+                index.remove(id, old);
+            }
+            match new.map(|new| index.keys(new)).transpose() {
+                Ok(Some(keys)) => index.insert(id, keys),
+                Ok(None) => {}
+                Err(error) => {
+                    self.error.get_or_insert(error);
+                }
+            }
+        }
+    }
+
+    /// The indexes to add to the collection and the reply to the command,
+    /// once the scan ended.
+    pub(super) fn commit(self, namespace: &str) -> CommandResult<(Vec<Index>, Document)> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        for index in &self.indexes {
+            index.check_no_duplicates(namespace)?;
+        }
+        Ok((self.indexes, self.reply))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bson::json::doc, error::ErrorCode, storage::Collection};
+
+    #[test]
+    fn test_aborted_build_leaves_nothing() {
+        let mut collection = Collection::new("test.c");
+        for i in 0..(BATCH_SIZE * 2) {
+            collection
+                .insert(doc(&format!("{{ _id: {}, a: {} }}", i, i)))
+                .unwrap();
+        }
+        let index = Index::parse(&doc("{ key: { a: 1 } }")).unwrap();
+        let build = IndexBuild::new(
+            1,
+            Document::new(),
+            vec![index],
+            Document::new(),
+            BATCH_SIZE * 2,
+        );
+        collection.start_index_build(build);
+
+        // Half scanned, the index isn't part of the collection yet
+        assert!(collection.continue_index_builds().is_empty());
+        assert!(collection.index("a_1").is_none());
+
+        collection.index_builds_mut()[0]
+            .abort(CommandError::new(ErrorCode::IndexBuildAborted, "aborted"));
+        let ended = collection.continue_index_builds();
+        assert_eq!(ended.len(), 1);
+        assert_eq!(
+            ended[0].1.as_ref().unwrap_err().code,
+            ErrorCode::IndexBuildAborted
+        );
+        assert!(collection.index_builds().is_empty());
+        assert_eq!(collection.indexes().len(), 1);
+    }
+}
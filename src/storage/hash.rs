@@ -0,0 +1,213 @@
+//! The hashes of `hashed` indexes. Like mongod, a value is hashed with MD5
+//! over its canonical type and payload, so that the hashes match those of a
+//! real server: numbers hash by their 64-bit integer value, documents and
+//! arrays by their elements with their field names.
+
+use crate::bson::{Document, Value};
+
+/// The seed mongod hashes index keys with.
+const SEED: i32 = 0;
+
+/// The hash of a value as stored in a `hashed` index: the first eight bytes
+/// of the digest, read as a little-endian integer.
+pub fn hash_value(value: &Value) -> i64 {
+    let mut md5 = Md5::new();
+    md5.update(&SEED.to_le_bytes());
+    add_value(&mut md5, value, None);
+    let digest = md5.finish();
+    let mut first = [0; 8];
+    first.copy_from_slice(&digest[..8]);
+    i64::from_le_bytes(first)
+}
+
+/// Adds a value, with its field name when it is an element of a document or
+/// array.
+fn add_value(md5: &mut Md5, value: &Value, name: Option<&str>) {
+    md5.update(&value.canonical_type().to_le_bytes());
+    if let Some(name) = name {
+        md5.update(name.as_bytes());
+        md5.update(&[0]);
+    }
+    match value {
+        Value::Document(doc) => add_elements(md5, doc),
+        Value::Array(items) => {
+            for (i, item) in items.0.iter().enumerate() {
+                add_value(md5, item, Some(&i.to_string()));
+            }
+            add_end(md5);
+        }
+        Value::JavaScriptCodeWithScope(code, scope) => {
+            md5.update(code.as_bytes());
+            md5.update(&[0]);
+            add_elements(md5, scope);
+        }
+        // Numbers are truncated to integers, saturating at the ends of the
+        // range, so that `1`, `1.5` and `NumberLong(1)` hash alike. Decimals
+        // round to even instead, and those with no integer value hash as the
+        // smallest one, as in mongod's `safeNumberLong`.
+        value if value.is_number() => {
+            let number = match value {
+                Value::Int64(n) => *n,
+                Value::Decimal128(decimal) => decimal.to_i64().unwrap_or(i64::MIN),
+                value => value
+                    .as_f64()
+                    .filter(|n| !n.is_nan())
+                    .map_or(0, |n| n as i64),
+            };
+            md5.update(&number.to_le_bytes());
+        }
+        value => md5.update(&value.to_bytes()),
+    }
+}
+
+fn add_elements(md5: &mut Md5, doc: &Document) {
+    for (name, value) in doc.iter() {
+        add_value(md5, value, Some(name));
+    }
+    add_end(md5);
+}
+
+/// The end of a document, hashed like mongod's `EOO` element: a type of
+/// zero and an empty name.
+fn add_end(md5: &mut Md5) {
+    md5.update(&0i32.to_le_bytes());
+    md5.update(&[0]);
+}
+
+/// The per-round shift amounts of MD5.
+const SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15,
+    21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+];
+
+/// An MD5 digest (RFC 1321), computed incrementally.
+struct Md5 {
+    state: [u32; 4],
+    /// The bytes not yet making up a whole block.
+    buffer: Vec<u8>,
+    length: u64,
+}
+
+impl Md5 {
+    fn new() -> Self {
+        Self {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476],
+            buffer: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        self.length += bytes.len() as u64;
+        for byte in bytes {
+            self.buffer.push(*byte);
+            if self.buffer.len() == 64 {
+                let block = std::mem::take(&mut self.buffer);
+                self.compress(&block);
+                self.buffer = block;
+                self.buffer.clear();
+            }
+        }
+    }
+
+    fn finish(mut self) -> [u8; 16] {
+        let bits = self.length.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.buffer.len() != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bits.to_le_bytes());
+        let mut digest = [0; 16];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for (i, shift) in SHIFTS.iter().enumerate() {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            // The constants are the integer parts of the sines of 1 to 64,
+            // scaled by 2^32
+            let k = ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32;
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(k)
+                .wrapping_add(words[g])
+                .rotate_left(*shift);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        for (word, value) in self.state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::{json::value, Decimal128};
+
+    #[test]
+    fn test_hash() {
+        let hex = |bytes: &str| {
+            let mut md5 = Md5::new();
+            md5.update(bytes.as_bytes());
+            md5.finish()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect::<String>()
+        };
+        assert_eq!(hex(""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(
+            hex("The quick brown fox jumps over the lazy dog"),
+            "9e107d9d372bb6826bd81d3542a419d6"
+        );
+        assert_eq!(hex(&"a".repeat(100)), "36a92cc94a9e0fa21f625f8bfb007adf");
+
+        // The hash mongod gives 1
+        assert_eq!(hash_value(&value("1")), 5902408780260971510);
+        assert_eq!(hash_value(&value("1")), hash_value(&Value::Int64(1)));
+        assert_eq!(hash_value(&value("1")), hash_value(&value("1.9")));
+        assert_ne!(hash_value(&value("1")), hash_value(&value("'1'")));
+
+        // NumberDecimal("1") hashes like 1 in mongod, and other decimals
+        // like the integers they round to
+        let decimal = |coefficient: u128, exponent: i32, negative: bool| {
+            let bits =
+                ((negative as u128) << 127) | (((exponent + 6176) as u128) << 113) | coefficient;
+            Value::Decimal128(Decimal128(bits.to_le_bytes()))
+        };
+        assert_eq!(hash_value(&decimal(1, 0, false)), 5902408780260971510);
+        assert_eq!(
+            hash_value(&decimal(100, -2, false)),
+            hash_value(&value("1"))
+        );
+        assert_eq!(hash_value(&decimal(25, -1, false)), hash_value(&value("2")));
+        assert_eq!(hash_value(&decimal(35, -1, true)), hash_value(&value("-4")));
+        assert_eq!(
+            hash_value(&decimal(1, 19, false)),
+            hash_value(&Value::Int64(i64::MIN))
+        );
+        let nan = Value::Decimal128(Decimal128((0b11111u128 << 122).to_le_bytes()));
+        assert_eq!(hash_value(&nan), hash_value(&Value::Int64(i64::MIN)));
+        assert_ne!(
+            hash_value(&value("{ a: 1 }")),
+            hash_value(&value("{ b: 1 }"))
+        );
+    }
+}
@@ -0,0 +1,1124 @@
+//! Secondary indexes. An index holds an entry for every key a document
+//! generates under its key pattern, ordered by the BSON comparison order of
+//! the key values with each field in its own direction. A field holding an
+//! array generates a key per element, making the index multikey. A text
+//! index instead holds a key per term of the strings of a document, with its
+//! score, and a geo index keys its geo field by the cells covering the
+//! geometries there. A hashed field is keyed by the hashes of its values, and
+//! a wildcard index keys every scalar of a document by its path. An index
+//! with a collation holds the sort keys of strings in their place.
+
+use std::{cmp::Ordering, collections::BTreeSet};
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    error::{CommandError, CommandResult, ErrorCode},
+    geo::GeoIndex,
+    query::Matcher,
+    text::TextIndex,
+};
+
+use super::{
+    hash::hash_value,
+    wildcard::{WildcardIndex, PATH_FIELD},
+    RecordId,
+};
+
+/// The name of the index every collection has on `_id`.
+pub const ID_INDEX_NAME: &str = "_id_";
+
+/// One value of an index key, ordered in the direction of its field.
+#[derive(Debug, Clone)]
+pub struct KeyValue {
+    pub value: Value,
+    pub descending: bool,
+}
+
+impl Ord for KeyValue {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ordering = self.value.compare(&other.value);
+        if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+impl PartialOrd for KeyValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for KeyValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for KeyValue {}
+
+/// The values a document has for the fields of a key pattern, in order.
+pub type IndexKey = Vec<KeyValue>;
+
+fn cannot_create(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::CannotCreateIndex, message)
+}
+
+#[derive(Debug, Clone)]
+pub struct Index {
+    name: String,
+    version: i32,
+    key_pattern: Document,
+    /// The path of every field of the key pattern, and whether it is
+    /// descending.
+    fields: Vec<(Vec<String>, bool)>,
+    unique: bool,
+    /// Whether documents missing every field of the key pattern are left out.
+    sparse: bool,
+    /// The filter documents must match to be indexed, as given and compiled.
+    partial: Option<(Document, Matcher)>,
+    /// How long after the date in its field a document expires, for a TTL
+    /// index.
+    expire_after_seconds: Option<i64>,
+    /// The fields and options of a text index, whose key pattern is
+    /// `{ _fts: 'text', _ftsx: 1 }`.
+    text: Option<TextIndex>,
+    /// The geo field of a `2dsphere` or `2d` index.
+    geo: Option<GeoIndex>,
+    /// The position of the `hashed` field in the key pattern.
+    hashed: Option<usize>,
+    /// The options of a wildcard index, whose fields are `$_path` and the
+    /// path of the key pattern.
+    wildcard: Option<WildcardIndex>,
+    /// The collation the strings of the keys are ordered by.
+    collation: Option<Collation>,
+    multikey: bool,
+    entries: BTreeSet<(IndexKey, RecordId)>,
+}
+
+impl Index {
+    /// The index on `_id` created with every collection, which has the
+    /// default collation of the collection.
+    pub fn id(collation: Option<&Collation>) -> Self {
+        let mut key_pattern = Document::new();
+        key_pattern.insert("_id", Value::Int32(1));
+        Self {
+            name: ID_INDEX_NAME.to_string(),
+            version: 2,
+            key_pattern,
+            fields: vec![(vec!["_id".to_string()], false)],
+            unique: true,
+            sparse: false,
+            partial: None,
+            expire_after_seconds: None,
+            text: None,
+            geo: None,
+            hashed: None,
+            wildcard: None,
+            collation: collation.cloned(),
+            multikey: false,
+            entries: BTreeSet::new(),
+        }
+    }
+
+    /// Parses an index specification as given to `createIndexes`, such as
+    /// `{ key: { a: 1, b: -1 }, name: 'a_1_b_-1' }`. The name defaults to one
+    /// derived from the key pattern.
+    pub fn parse(spec: &Document) -> CommandResult<Self> {
+        for (field, value) in spec.iter() {
+            match field.as_str() {
+                "key"
+                | "name"
+                | "v"
+                | "ns"
+                | "partialFilterExpression"
+                | "expireAfterSeconds"
+                | "collation" => {}
+                "unique" | "sparse" if matches!(value, Value::Boolean(_)) || value.is_number() => {}
+                // Every build runs in the background nowadays
+                "background" if matches!(value, Value::Boolean(_)) || value.is_number() => {}
+                "weights" | "default_language" | "language_override" | "textIndexVersion"
+                    if has_plugin(spec.get("key"), "text") => {}
+                "2dsphereIndexVersion" if has_plugin(spec.get("key"), "2dsphere") => {}
+                "bits" | "min" | "max" if has_plugin(spec.get("key"), "2d") => {}
+                "wildcardProjection" if has_wildcard(spec.get("key")) => {}
+                _ => {
+                    return Err(CommandError::new(
+                        ErrorCode::InvalidIndexSpecificationOption,
+                        format!(
+                            "The field '{}' is not valid for an index specification. Specification: {}",
+                            field, spec
+                        ),
+                    ));
+                }
+            }
+        }
+
+        let key_pattern = match spec.get("key") {
+            Some(Value::Document(key_pattern)) => key_pattern.clone(),
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "The field 'key' must be an object, but got {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+            None => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    "The 'key' field is a required property of an index specification",
+                ));
+            }
+        };
+        if key_pattern.is_empty() {
+            return Err(cannot_create("Index keys cannot be empty."));
+        }
+        let mut fields = Vec::new();
+        let mut text_fields = Vec::new();
+        let mut geo = None;
+        let mut hashed = None;
+        let mut wildcard = None;
+        for (field, direction) in key_pattern.iter() {
+            if field.is_empty() || field.split('.').any(str::is_empty) {
+                return Err(cannot_create("Index keys cannot be an empty field."));
+            }
+            let text = matches!(direction, Value::String(plugin) if plugin == "text");
+            if text && (field == "$**" || field == "_fts") {
+                text_fields.push(field.clone());
+                continue;
+            }
+            let prefix = wildcard_prefix(field);
+            if prefix
+                .unwrap_or(field)
+                .split('.')
+                .any(|part| part.starts_with('$'))
+            {
+                return Err(cannot_create(format!(
+                    "Index key contains an illegal field name: field name starts with '$'. Key: {}",
+                    field
+                )));
+            }
+            let descending = match direction {
+                Value::String(_) if text => {
+                    text_fields.push(field.clone());
+                    continue;
+                }
+                Value::String(plugin) if plugin == "2dsphere" || plugin == "2d" => {
+                    if geo.is_some() {
+                        return Err(cannot_create(format!(
+                            "can't have 2 geo fields. Key pattern: {}",
+                            key_pattern
+                        )));
+                    }
+                    geo = Some(GeoIndex::parse(fields.len(), plugin, spec)?);
+                    false
+                }
+                Value::String(plugin) if plugin == "hashed" => {
+                    if hashed.is_some() {
+                        return Err(CommandError::new(
+                            ErrorCode::Location(31303),
+                            format!(
+                                "A maximum of one index field is allowed to be hashed but found 2 for 'key' {}",
+                                key_pattern
+                            ),
+                        ));
+                    }
+                    hashed = Some(fields.len());
+                    false
+                }
+                Value::String(plugin) => {
+                    return Err(cannot_create(format!("Unknown index plugin '{}'", plugin)));
+                }
+                direction if direction.is_number() => match direction.as_f64() {
+                    Some(n) if n > 0.0 => false,
+                    Some(n) if n < 0.0 => true,
+                    _ => {
+                        return Err(cannot_create(format!(
+                            "Values in the index key pattern can't be 0. Key pattern: {}",
+                            key_pattern
+                        )));
+                    }
+                },
+                direction => {
+                    return Err(cannot_create(format!(
+                        "Values in v:2 index key pattern cannot be of type {}. Only numbers > 0, numbers < 0, and strings are allowed.",
+                        direction.type_name()
+                    )));
+                }
+            };
+            if let Some(prefix) = prefix {
+                let prefix = prefix.split('.').filter(|part| !part.is_empty());
+                wildcard = Some(WildcardIndex::parse(
+                    prefix.map(String::from).collect(),
+                    spec,
+                )?);
+            }
+            fields.push((field.split('.').map(String::from).collect(), descending));
+        }
+        let plugins = [geo.is_some(), hashed.is_some(), wildcard.is_some()];
+        if plugins.into_iter().filter(|plugin| *plugin).count() > 1 {
+            return Err(cannot_create(
+                "Can't use more than one index plugin for a single index.",
+            ));
+        }
+        if wildcard.is_some() {
+            if fields.len() > 1 {
+                return Err(cannot_create(format!(
+                    "wildcard indexes do not allow compounding. Key pattern: {}",
+                    key_pattern
+                )));
+            }
+            for option in ["unique", "sparse", "expireAfterSeconds"] {
+                if spec.get(option).is_some_and(Value::is_truthy) {
+                    return Err(cannot_create(format!(
+                        "Index type 'wildcard' does not support the '{}' option",
+                        option
+                    )));
+                }
+            }
+            fields.insert(0, (vec![PATH_FIELD.to_string()], false));
+        }
+        if hashed.is_some() && spec.get("unique").is_some_and(Value::is_truthy) {
+            return Err(cannot_create(
+                "Currently hashed indexes cannot guarantee uniqueness. Use a regular index.",
+            ));
+        }
+        let collation = match spec.get("collation") {
+            None => None,
+            Some(Value::Document(collation)) => Collation::parse(collation)?,
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "The field 'collation' must be an object, but got {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        if let Some(collation) = &collation {
+            let plugin = ["text", "2d"]
+                .into_iter()
+                .find(|plugin| has_plugin(spec.get("key"), plugin));
+            if let Some(plugin) = plugin {
+                return Err(cannot_create(format!(
+                    "Index type '{}' does not support collation: {}",
+                    plugin,
+                    collation.spec()
+                )));
+            }
+        }
+        // The fields of a text index are all text fields, bar the `_ftsx`
+        // of the key pattern it is listed with
+        let text = if text_fields.is_empty() {
+            None
+        } else {
+            let listed = fields.len() == 1 && fields[0].0 == ["_ftsx"] && text_fields == ["_fts"];
+            if !fields.is_empty() && !listed {
+                return Err(cannot_create(format!(
+                    "text index fields cannot be combined with other fields. Key pattern: {}",
+                    key_pattern
+                )));
+            }
+            text_fields.retain(|field| field != "_fts");
+            Some(TextIndex::parse(&text_fields, spec)?)
+        };
+
+        let name = match spec.get("name") {
+            Some(Value::String(name)) if name.is_empty() => {
+                return Err(cannot_create("index name cannot be empty"));
+            }
+            Some(Value::String(name)) if name == "*" => {
+                return Err(CommandError::new(
+                    ErrorCode::BadValue,
+                    "The index name '*' is not valid",
+                ));
+            }
+            Some(Value::String(name)) => name.clone(),
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "The field 'name' must be a string, but got {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+            None => default_name(&key_pattern),
+        };
+        let (key_pattern, fields) = match text {
+            Some(_) => {
+                let mut key_pattern = Document::new();
+                key_pattern.insert("_fts", Value::from("text"));
+                key_pattern.insert("_ftsx", Value::Int32(1));
+                let fields = vec![
+                    (vec!["_fts".to_string()], false),
+                    (vec!["_ftsx".to_string()], false),
+                ];
+                (key_pattern, fields)
+            }
+            None => (key_pattern, fields),
+        };
+        let version = match spec.get("v") {
+            None => 2,
+            Some(v) if v.is_number() => match v.as_i64() {
+                Some(v @ (1 | 2)) => v as i32,
+                _ => {
+                    return Err(cannot_create(format!(
+                        "Invalid index specification {}; cannot create an index with v={}",
+                        spec, v
+                    )));
+                }
+            },
+            Some(v) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!("The field 'v' must be a number, but got {}", v.type_name()),
+                ));
+            }
+        };
+
+        let unique = spec.get("unique").is_some_and(Value::is_truthy);
+        let sparse = spec.get("sparse").is_some_and(Value::is_truthy);
+        let partial = match spec.get("partialFilterExpression") {
+            None => None,
+            Some(_) if sparse => {
+                return Err(cannot_create(
+                    "cannot mix \"partialFilterExpression\" and \"sparse\" options",
+                ));
+            }
+            Some(Value::Document(filter)) => {
+                check_partial_filter(filter)?;
+                let matcher = Matcher::new(filter)?.with_collation(collation.as_ref());
+                Some((filter.clone(), matcher))
+            }
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "The field 'partialFilterExpression' must be an object, but got {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        let expire_after_seconds = match spec.get("expireAfterSeconds") {
+            None => None,
+            Some(_) if fields.len() > 1 => {
+                return Err(cannot_create(
+                    "TTL indexes are single-field indexes, compound indexes do not support TTL",
+                ));
+            }
+            Some(_) if key_pattern.first().is_some_and(|(field, _)| field == "_id") => {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidIndexSpecificationOption,
+                    "The field 'expireAfterSeconds' is not valid for an _id index specification",
+                ));
+            }
+            Some(value) => Some(parse_expire_after_seconds(value)?),
+        };
+
+        Ok(Self {
+            name,
+            version,
+            key_pattern,
+            fields,
+            unique,
+            sparse,
+            partial,
+            expire_after_seconds,
+            text,
+            geo,
+            hashed,
+            wildcard,
+            collation,
+            multikey: false,
+            entries: BTreeSet::new(),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn version(&self) -> i32 {
+        self.version
+    }
+
+    pub fn key_pattern(&self) -> &Document {
+        &self.key_pattern
+    }
+
+    /// Whether both indexes have the same key pattern.
+    pub fn same_key(&self, other: &Index) -> bool {
+        self.key_pattern.compare(&other.key_pattern) == Ordering::Equal
+    }
+
+    /// The specification reported by `listIndexes`.
+    pub fn spec(&self) -> Document {
+        let mut spec = Document::new();
+        spec.insert("v", Value::Int32(self.version));
+        spec.insert("key", Value::Document(self.key_pattern.clone()));
+        spec.insert("name", Value::String(self.name.clone()));
+        // The `_id` index is unique without saying so
+        if self.unique && self.name != ID_INDEX_NAME {
+            spec.insert("unique", Value::Boolean(true));
+        }
+        if self.sparse {
+            spec.insert("sparse", Value::Boolean(true));
+        }
+        if let Some((filter, _)) = &self.partial {
+            spec.insert("partialFilterExpression", Value::Document(filter.clone()));
+        }
+        if let Some(seconds) = self.expire_after_seconds {
+            spec.insert("expireAfterSeconds", Value::Int32(seconds as i32));
+        }
+        if let Some(text) = &self.text {
+            text.add_spec(&mut spec);
+        }
+        if let Some(geo) = &self.geo {
+            geo.add_spec(&mut spec);
+        }
+        if let Some(wildcard) = &self.wildcard {
+            wildcard.add_spec(&mut spec);
+        }
+        if let Some(collation) = &self.collation {
+            spec.insert("collation", Value::Document(collation.spec()));
+        }
+        spec
+    }
+
+    /// The definition of a text index.
+    pub fn text(&self) -> Option<&TextIndex> {
+        self.text.as_ref()
+    }
+
+    /// The definition of the geo field of a geo index.
+    pub fn geo(&self) -> Option<&GeoIndex> {
+        self.geo.as_ref()
+    }
+
+    /// The position of the `hashed` field in the key pattern.
+    pub fn hashed(&self) -> Option<usize> {
+        self.hashed
+    }
+
+    /// The definition of a wildcard index.
+    pub fn wildcard(&self) -> Option<&WildcardIndex> {
+        self.wildcard.as_ref()
+    }
+
+    /// The collation of the strings of the keys, which only queries with
+    /// the same collation can use the index for.
+    pub fn collation(&self) -> Option<&Collation> {
+        self.collation.as_ref()
+    }
+
+    pub fn expire_after_seconds(&self) -> Option<i64> {
+        self.expire_after_seconds
+    }
+
+    pub fn set_expire_after_seconds(&mut self, seconds: i64) {
+        self.expire_after_seconds = Some(seconds);
+    }
+
+    /// The documents of a TTL index expired at `now`, in milliseconds since
+    /// the epoch: those whose field holds a date, or an array whose earliest
+    /// date, is at least `expireAfterSeconds` old. Other values never expire.
+    pub fn expired(&self, now: i64) -> BTreeSet<RecordId> {
+        let Some(seconds) = self.expire_after_seconds else {
+            return BTreeSet::new();
+        };
+        let cutoff = now.saturating_sub(seconds.saturating_mul(1000));
+        self.entries
+            .iter()
+            .filter(|(key, _)| matches!(key[0].value, Value::UtcDateTime(date) if date <= cutoff))
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    /// The path of every field of the key pattern, and whether it is
+    /// descending.
+    pub fn fields(&self) -> &[(Vec<String>, bool)] {
+        &self.fields
+    }
+
+    /// Whether some document generated several keys from an array.
+    pub fn is_multikey(&self) -> bool {
+        self.multikey
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        self.sparse
+    }
+
+    pub fn partial_filter(&self) -> Option<&Document> {
+        self.partial.as_ref().map(|(filter, _)| filter)
+    }
+
+    /// The entries from `start` on, in index order.
+    pub fn entries_from(&self, start: IndexKey) -> impl Iterator<Item = &(IndexKey, RecordId)> {
+        self.entries.range((start, RecordId::MIN)..)
+    }
+
+    /// The entries up to the last whose first value is `end`, backwards.
+    pub fn entries_back_from(&self, end: KeyValue) -> impl Iterator<Item = &(IndexKey, RecordId)> {
+        // The largest key starting with `end` has the largest value of
+        // every other field
+        let mut last = vec![end];
+        last.extend(self.fields[1..].iter().map(|(_, descending)| KeyValue {
+            value: if *descending {
+                Value::MinKey
+            } else {
+                Value::MaxKey
+            },
+            descending: *descending,
+        }));
+        self.entries.range(..=(last, RecordId::MAX)).rev()
+    }
+
+    pub fn is_unique(&self) -> bool {
+        self.unique
+    }
+
+    /// Whether the index leaves some documents out, so that it can't stand
+    /// for the whole collection.
+    pub fn is_sparse_or_partial(&self) -> bool {
+        self.sparse || self.partial.is_some()
+    }
+
+    /// The paths of the fields of the key pattern, in order.
+    pub fn paths(&self) -> impl Iterator<Item = &[String]> {
+        self.fields.iter().map(|(path, _)| path.as_slice())
+    }
+
+    /// A copy of the index definition without entries.
+    pub fn empty_clone(&self) -> Self {
+        Self {
+            multikey: false,
+            entries: BTreeSet::new(),
+            ..self.clone()
+        }
+    }
+
+    /// The keys a document generates, and whether any came from an array.
+    /// A missing field is indexed as null and an empty array as undefined.
+    /// Like mongod, only one field of a compound key may hold an array, since
+    /// indexing the product of parallel arrays could take unbounded space.
+    /// Documents a sparse or partial index leaves out generate no keys, as do
+    /// those without the geo field of a geo index. A hashed field can't hold
+    /// an array, as its elements would be lost in the hash.
+    pub fn keys(&self, doc: &Document) -> CommandResult<(BTreeSet<IndexKey>, bool)> {
+        let excluded = match &self.partial {
+            Some((_, matcher)) => !matcher.matches(doc),
+            None => self.sparse && !self.paths().any(|path| has_path(doc, path)),
+        };
+        if excluded {
+            return Ok((BTreeSet::new(), false));
+        }
+        if let Some(text) = &self.text {
+            let keys = text
+                .terms(doc)?
+                .into_iter()
+                .map(|(term, score)| {
+                    vec![
+                        KeyValue {
+                            value: Value::String(term),
+                            descending: false,
+                        },
+                        KeyValue {
+                            value: Value::Double(score),
+                            descending: false,
+                        },
+                    ]
+                })
+                .collect();
+            return Ok((keys, false));
+        }
+        if let Some(wildcard) = &self.wildcard {
+            let (keys, multikey) = wildcard.keys(doc);
+            let keys = keys
+                .into_iter()
+                .map(|(path, value)| {
+                    vec![
+                        KeyValue {
+                            value: Value::String(path),
+                            descending: false,
+                        },
+                        KeyValue {
+                            value: self.translate(value),
+                            descending: self.fields[1].1,
+                        },
+                    ]
+                })
+                .collect();
+            return Ok((keys, multikey));
+        }
+        let mut array_field: Option<(&[String], String)> = None;
+        let mut keys: Vec<IndexKey> = vec![Vec::new()];
+        for (i, (path, descending)) in self.fields.iter().enumerate() {
+            let mut values = Vec::new();
+            let mut array = None;
+            match &self.geo {
+                Some(geo) if geo.field() == i => {
+                    values.extend(geo.cells(doc, path)?.into_iter().map(Value::String));
+                    if values.is_empty() {
+                        return Ok((BTreeSet::new(), false));
+                    }
+                }
+                _ => {
+                    match doc.get(&path[0]) {
+                        Some(value) => collect(value, path, 1, &mut values, &mut array),
+                        None => values.push(Value::Null),
+                    }
+                    values = values
+                        .into_iter()
+                        .map(|value| self.translate(value))
+                        .collect();
+                }
+            }
+            if self.hashed == Some(i) {
+                if let Some(prefix) = array {
+                    return Err(CommandError::new(
+                        ErrorCode::Location(16766),
+                        format!(
+                            "Error: hashed indexes do not currently support array values. Found array at path: {}",
+                            path[..prefix].join(".")
+                        ),
+                    ));
+                }
+                values = values
+                    .iter()
+                    .map(|value| Value::Int64(hash_value(value)))
+                    .collect();
+            }
+            if let Some(prefix) = array {
+                let prefix = path[..prefix].join(".");
+                match &array_field {
+                    Some((other, other_prefix)) if *other_prefix != prefix => {
+                        return Err(CommandError::new(
+                            ErrorCode::CannotIndexParallelArrays,
+                            format!(
+                                "cannot index parallel arrays [{}] [{}]",
+                                path.join("."),
+                                other.join(".")
+                            ),
+                        ));
+                    }
+                    _ => array_field = Some((path, prefix)),
+                }
+            }
+            keys = keys
+                .into_iter()
+                .flat_map(|key| {
+                    values.iter().map(move |value| {
+                        let mut key = key.clone();
+                        key.push(KeyValue {
+                            value: value.clone(),
+                            descending: *descending,
+                        });
+                        key
+                    })
+                })
+                .collect();
+        }
+        Ok((keys.into_iter().collect(), array_field.is_some()))
+    }
+
+    /// A key value as the index holds it, translated by the collation.
+    fn translate(&self, value: Value) -> Value {
+        match &self.collation {
+            Some(collation) => collation.translate(&value),
+            None => value,
+        }
+    }
+
+    /// Fails with a duplicate key error when a unique index has one of the
+    /// keys for a document other than `id`.
+    pub fn check_unique(
+        &self,
+        namespace: &str,
+        id: Option<RecordId>,
+        keys: &BTreeSet<IndexKey>,
+    ) -> CommandResult<()> {
+        if !self.unique {
+            return Ok(());
+        }
+        for key in keys {
+            let mut matching = self
+                .entries
+                .range((key.clone(), RecordId::MIN)..=(key.clone(), RecordId::MAX));
+            if matching.any(|(_, other)| Some(*other) != id) {
+                return Err(self.duplicate_key(namespace, key));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails with a duplicate key error when a unique index holds a key for
+    /// two documents, as it can after a build took writes unchecked.
+    pub fn check_no_duplicates(&self, namespace: &str) -> CommandResult<()> {
+        if !self.unique {
+            return Ok(());
+        }
+        let mut pairs = self.entries.iter().zip(self.entries.iter().skip(1));
+        match pairs.find(|((key, _), (next, _))| key == next) {
+            Some(((key, _), _)) => Err(self.duplicate_key(namespace, key)),
+            None => Ok(()),
+        }
+    }
+
+    fn duplicate_key(&self, namespace: &str, key: &IndexKey) -> CommandError {
+        let mut key_value = Document::new();
+        for ((field, _), part) in self.key_pattern.iter().zip(key) {
+            key_value.insert(field.clone(), part.value.clone());
+        }
+        let mut info = Document::new();
+        info.insert("keyPattern", Value::Document(self.key_pattern.clone()));
+        info.insert("keyValue", Value::Document(key_value.clone()));
+        CommandError::new(
+            ErrorCode::DuplicateKey,
+            format!(
+                "E11000 duplicate key error collection: {} index: {} dup key: {}",
+                namespace, self.name, key_value
+            ),
+        )
+        .with_info(info)
+    }
+
+    /// Adds the entries of a document, given its keys from [`Index::keys`].
+    pub fn insert(&mut self, id: RecordId, keys: (BTreeSet<IndexKey>, bool)) {
+        let (keys, multikey) = keys;
+        self.multikey |= multikey;
+        for key in keys {
+            self.entries.insert((key, id));
+        }
+    }
+
+    /// Removes the entries of a document.
+    pub fn remove(&mut self, id: RecordId, doc: &Document) {
+        // The keys were generated without error when the document was
+        // inserted
+        if let Ok((keys, _)) = self.keys(doc) {
+            for key in keys {
+                self.entries.remove(&(key, id));
+            }
+        }
+    }
+}
+
+/// Whether a key pattern has a field of some plugin, such as `"text"`.
+fn has_plugin(key_pattern: Option<&Value>, name: &str) -> bool {
+    match key_pattern {
+        Some(Value::Document(key_pattern)) => key_pattern
+            .iter()
+            .any(|(_, direction)| matches!(direction, Value::String(plugin) if plugin == name)),
+        _ => false,
+    }
+}
+
+/// Whether a key pattern has a wildcard field.
+fn has_wildcard(key_pattern: Option<&Value>) -> bool {
+    match key_pattern {
+        Some(Value::Document(key_pattern)) => key_pattern
+            .iter()
+            .any(|(field, _)| wildcard_prefix(field).is_some()),
+        _ => false,
+    }
+}
+
+/// The path a wildcard field such as `a.$**` is on, empty for `$**`.
+fn wildcard_prefix(field: &str) -> Option<&str> {
+    match field.strip_suffix("$**")? {
+        "" => Some(""),
+        prefix => prefix.strip_suffix('.'),
+    }
+}
+
+/// The name mongod gives an index by default, such as `a_1_b_-1`.
+fn default_name(key_pattern: &Document) -> String {
+    key_pattern
+        .iter()
+        .map(|(field, direction)| match direction {
+            Value::String(plugin) => format!("{}_{}", field, plugin),
+            direction => format!("{}_{}", field, direction),
+        })
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+/// Reads `expireAfterSeconds`, which like mongod's must be a number of
+/// seconds from 0 to the largest 32-bit integer.
+pub fn parse_expire_after_seconds(value: &Value) -> CommandResult<i64> {
+    let seconds = match value {
+        value if value.is_number() => value.as_f64().unwrap_or(f64::NAN),
+        value => {
+            return Err(CommandError::new(
+                ErrorCode::TypeMismatch,
+                format!(
+                    "TTL index 'expireAfterSeconds' option must be numeric, but received a type of '{}'",
+                    value.type_name()
+                ),
+            ));
+        }
+    };
+    if !(0.0..=i32::MAX as f64).contains(&seconds) {
+        return Err(cannot_create(format!(
+            "TTL index 'expireAfterSeconds' option must be within an acceptable range, try a lower number. Received: {}",
+            value
+        )));
+    }
+    Ok(seconds as i64)
+}
+
+/// Partial filters are limited to the expressions mongod can use to answer
+/// queries from the index.
+fn check_partial_filter(filter: &Document) -> CommandResult<()> {
+    let unsupported = |expression: &dyn std::fmt::Display| {
+        cannot_create(format!(
+            "Expression not supported in partial index: {}",
+            expression
+        ))
+    };
+    for (field, value) in filter.iter() {
+        match (field.as_str(), value) {
+            ("$and" | "$or", Value::Array(clauses)) => {
+                for clause in &clauses.0 {
+                    match clause {
+                        Value::Document(clause) => check_partial_filter(clause)?,
+                        clause => return Err(unsupported(clause)),
+                    }
+                }
+            }
+            (operator, _) if operator.starts_with('$') => return Err(unsupported(filter)),
+            (_, Value::Document(operators))
+                if operators.first().is_some_and(|(op, _)| op.starts_with('$')) =>
+            {
+                for (operator, argument) in operators.iter() {
+                    let supported = match operator.as_str() {
+                        "$eq" | "$gt" | "$gte" | "$lt" | "$lte" | "$type" | "$in" => true,
+                        "$exists" => argument.is_truthy(),
+                        _ => false,
+                    };
+                    if !supported {
+                        return Err(unsupported(operators));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Whether a document has a value at `path`, in any element of the arrays
+/// along it.
+fn has_path(doc: &Document, path: &[String]) -> bool {
+    let Some((first, rest)) = path.split_first() else {
+        return true;
+    };
+    match doc.get(first) {
+        None => false,
+        Some(_) if rest.is_empty() => true,
+        Some(Value::Document(doc)) => has_path(doc, rest),
+        Some(Value::Array(items)) => items.0.iter().any(|item| match item {
+            Value::Document(doc) => has_path(doc, rest),
+            _ => false,
+        }),
+        Some(_) => false,
+    }
+}
+
+/// Collects the values at `path` of a value found at its first `i` fields,
+/// expanding arrays and recording in `array` the length of the path prefix
+/// holding the first one.
+fn collect(
+    value: &Value,
+    path: &[String],
+    i: usize,
+    values: &mut Vec<Value>,
+    array: &mut Option<usize>,
+) {
+    match value {
+        Value::Array(items) => {
+            array.get_or_insert(i);
+            if items.0.is_empty() {
+                values.push(if i == path.len() {
+                    Value::Undefined
+                } else {
+                    Value::Null
+                });
+            }
+            for item in &items.0 {
+                match item {
+                    // Nested arrays are indexed whole
+                    item if i == path.len() => values.push(item.clone()),
+                    Value::Document(_) => collect(item, path, i, values, &mut None),
+                    _ => values.push(Value::Null),
+                }
+            }
+        }
+        value if i == path.len() => values.push(value.clone()),
+        Value::Document(doc) => match doc.get(&path[i]) {
+            Some(value) => collect(value, path, i + 1, values, array),
+            None => values.push(Value::Null),
+        },
+        _ => values.push(Value::Null),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{bson::json::doc, storage::Collection};
+
+    fn keys(pattern: &str, document: &str) -> CommandResult<Vec<Vec<Value>>> {
+        let index = Index::parse(&doc(&format!("{{ key: {} }}", pattern)))?;
+        let (keys, _) = index.keys(&doc(document))?;
+        Ok(keys
+            .into_iter()
+            .map(|key| key.into_iter().map(|part| part.value).collect())
+            .collect())
+    }
+
+    #[test]
+    fn test_key_generation() {
+        assert_eq!(
+            keys("{ a: 1, b: -1 }", "{ a: 1 }").unwrap(),
+            vec![vec![Value::Int32(1), Value::Null]]
+        );
+        assert_eq!(
+            keys("{ a: 1 }", "{ a: [3, 1, 3, [2]] }").unwrap(),
+            vec![
+                vec![Value::Int32(1)],
+                vec![Value::Int32(3)],
+                vec![Value::from(vec![Value::Int32(2)])],
+            ]
+        );
+        assert_eq!(
+            keys("{ 'a.b': 1 }", "{ a: [{ b: 2 }, { c: 1 }, 5] }").unwrap(),
+            vec![vec![Value::Null], vec![Value::Int32(2)]]
+        );
+        assert_eq!(
+            keys("{ a: 1, c: 1 }", "{ a: [], c: [1, 2] }")
+                .unwrap_err()
+                .code,
+            ErrorCode::CannotIndexParallelArrays
+        );
+        assert_eq!(
+            keys("{ 'a.b': 1, 'a.c': 1 }", "{ a: [{ b: 1, c: 2 }] }").unwrap(),
+            vec![vec![Value::Int32(1), Value::Int32(2)]]
+        );
+    }
+
+    #[test]
+    fn test_descending_order() {
+        let mut index = Index::parse(&doc("{ key: { a: -1, b: 1 } }")).unwrap();
+        assert_eq!(index.name(), "a_-1_b_1");
+        for (id, document) in ["{ a: 1, b: 2 }", "{ a: 2, b: 1 }", "{ a: 1, b: 1 }"]
+            .iter()
+            .enumerate()
+        {
+            let keys = index.keys(&doc(document)).unwrap();
+            index.insert(id as RecordId, keys);
+        }
+        let ids: Vec<_> = index.entries.iter().map(|(_, id)| *id).collect();
+        assert_eq!(ids, vec![1, 2, 0]);
+        assert!(!index.multikey);
+
+        let keys = index.keys(&doc("{ a: [3, 4], b: 1 }")).unwrap();
+        index.insert(3, keys);
+        assert!(index.multikey);
+        assert_eq!(index.entries.len(), 5);
+
+        index.remove(1, &doc("{ a: 2, b: 1 }"));
+        index.remove(3, &doc("{ a: [3, 4], b: 1 }"));
+        assert_eq!(index.entries.len(), 2);
+    }
+
+    #[test]
+    fn test_unique_sparse_and_partial() {
+        let mut collection = Collection::new("test.c");
+        let create = |collection: &mut Collection, spec: &str| {
+            let index = Index::parse(&doc(spec))?;
+            let index = collection.build_index(index)?;
+            collection.add_index(index);
+            Ok::<_, CommandError>(())
+        };
+        create(
+            &mut collection,
+            "{ key: { a: 1 }, unique: true, sparse: true }",
+        )
+        .unwrap();
+        create(
+            &mut collection,
+            "{ key: { b: 1, c: -1 }, unique: true, partialFilterExpression: { b: { $gt: 0 } } }",
+        )
+        .unwrap();
+
+        collection.insert(doc("{ _id: 1, a: [1, 2] }")).unwrap();
+        // Documents without `a` are left out of the sparse index
+        collection.insert(doc("{ _id: 2 }")).unwrap();
+        collection.insert(doc("{ _id: 3 }")).unwrap();
+        let err = collection.insert(doc("{ _id: 4, a: 2 }")).unwrap_err();
+        assert_eq!(err.code, ErrorCode::DuplicateKey);
+        assert_eq!(
+            err.info,
+            doc("{ keyPattern: { a: 1 }, keyValue: { a: 2 } }")
+        );
+        assert_eq!(
+            collection.insert(doc("{ _id: 1 }")).unwrap_err().info,
+            doc("{ keyPattern: { _id: 1 }, keyValue: { _id: 1 } }")
+        );
+
+        // Only documents matching the partial filter must be unique
+        collection.insert(doc("{ _id: 5, b: 0, c: 1 }")).unwrap();
+        collection.insert(doc("{ _id: 6, b: 0, c: 1 }")).unwrap();
+        let id = collection.insert(doc("{ _id: 7, b: 1, c: 1 }")).unwrap();
+        assert_eq!(
+            collection
+                .insert(doc("{ _id: 8, b: 1, c: 1 }"))
+                .unwrap_err()
+                .code,
+            ErrorCode::DuplicateKey
+        );
+        // A document doesn't conflict with itself when replaced
+        collection
+            .replace(id, doc("{ _id: 7, b: 1, c: 1, d: 1 }"))
+            .unwrap();
+        collection.remove(id);
+        collection.insert(doc("{ _id: 8, b: 1, c: 1 }")).unwrap();
+
+        assert!(collection.has_unique_index(&["_id".to_string()]));
+        assert!(!collection.has_unique_index(&["a".to_string()]));
+        assert_eq!(
+            create(&mut collection, "{ key: { c: 1 }, unique: true }")
+                .unwrap_err()
+                .code,
+            ErrorCode::DuplicateKey
+        );
+        assert_eq!(
+            create(
+                &mut collection,
+                "{ key: { e: 1 }, sparse: true, partialFilterExpression: { e: 1 } }"
+            )
+            .unwrap_err()
+            .code,
+            ErrorCode::CannotCreateIndex
+        );
+        assert_eq!(
+            create(
+                &mut collection,
+                "{ key: { e: 1 }, partialFilterExpression: { e: { $ne: 1 } } }"
+            )
+            .unwrap_err()
+            .code,
+            ErrorCode::CannotCreateIndex
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! An in-memory storage engine. Collections are keyed by namespace
+//! (`db.collection`) and hold documents by record id, so that other
+//! structures can refer to a document independently of its contents.
+//! Collections maintain their indexes on every write, and the indexes being
+//! built in the background.
+
+mod build;
+mod hash;
+mod index;
+mod wildcard;
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    bson::{Document, Value},
+    collation::Collation,
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+pub use build::{IndexBuild, OpId};
+pub use hash::hash_value;
+pub use index::{parse_expire_after_seconds, Index, IndexKey, KeyValue, ID_INDEX_NAME};
+pub use wildcard::PATH_FIELD;
+
+pub type RecordId = u64;
+
+/// The data directory used when none is given.
+pub const DEFAULT_DATA_DIR: &str = "data";
+
+/// The collections, and the data directory for files such as the ones
+/// aggregations spill to.
+#[derive(Debug)]
+pub struct Storage {
+    collections: HashMap<String, Collection>,
+    data_dir: PathBuf,
+    next_op_id: OpId,
+}
+
+#[derive(Debug)]
+pub struct Collection {
+    namespace: String,
+    next_record_id: RecordId,
+    records: BTreeMap<RecordId, Document>,
+    /// The `_id` index first, then the others in creation order.
+    indexes: Vec<Index>,
+    /// The collation of queries and indexes that don't give one.
+    collation: Option<Collation>,
+    /// The index builds in progress, in the order they started.
+    builds: Vec<IndexBuild>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self::with_data_dir(DEFAULT_DATA_DIR)
+    }
+}
+
+impl Storage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_data_dir(data_dir: impl AsRef<Path>) -> Self {
+        Self {
+            collections: HashMap::new(),
+            data_dir: data_dir.as_ref().to_path_buf(),
+            next_op_id: 1,
+        }
+    }
+
+    /// The directory for temporary files, which like mongod's is `_tmp`
+    /// under the data directory.
+    pub fn temp_dir(&self) -> PathBuf {
+        self.data_dir.join("_tmp")
+    }
+
+    pub fn collection(&self, namespace: &str) -> Option<&Collection> {
+        self.collections.get(namespace)
+    }
+
+    /// Returns the collection, creating it implicitly like mongod does on the
+    /// first write.
+    pub fn collection_mut(&mut self, namespace: &str) -> &mut Collection {
+        self.collections
+            .entry(namespace.to_string())
+            .or_insert_with(|| Collection::new(namespace))
+    }
+
+    /// Deletes the documents TTL indexes consider expired at `now`, in
+    /// milliseconds since the epoch. Returns how many were deleted.
+    pub fn remove_expired(&mut self, now: i64) -> usize {
+        self.collections
+            .values_mut()
+            .map(|collection| collection.remove_expired(now))
+            .sum()
+    }
+
+    /// A new id for an operation such as an index build.
+    pub fn next_op_id(&mut self) -> OpId {
+        let id = self.next_op_id;
+        self.next_op_id += 1;
+        id
+    }
+
+    /// The index builds in progress, with the namespace of their collection.
+    pub fn index_builds(&self) -> impl Iterator<Item = (&str, &IndexBuild)> {
+        self.collections.values().flat_map(|collection| {
+            collection
+                .builds
+                .iter()
+                .map(|build| (collection.namespace.as_str(), build))
+        })
+    }
+
+    /// Takes every index build a step further. Returns the builds that
+    /// ended, with the reply to their command or the error that aborted
+    /// them.
+    pub fn continue_index_builds(&mut self) -> Vec<(OpId, CommandResult<Document>)> {
+        self.collections
+            .values_mut()
+            .flat_map(Collection::continue_index_builds)
+            .collect()
+    }
+
+    /// Replaces the collection of the same namespace, or adds it.
+    pub fn replace_collection(&mut self, collection: Collection) {
+        self.collections
+            .insert(collection.namespace.clone(), collection);
+    }
+}
+
+impl Collection {
+    pub fn new(namespace: &str) -> Self {
+        Self::with_collation(namespace, None)
+    }
+
+    /// A collection with a default collation, as created by `create`.
+    pub fn with_collation(namespace: &str, collation: Option<Collation>) -> Self {
+        Self {
+            namespace: namespace.to_string(),
+            next_record_id: 1,
+            records: BTreeMap::new(),
+            indexes: vec![Index::id(collation.as_ref())],
+            collation,
+            builds: Vec::new(),
+        }
+    }
+
+    /// An empty collection of the same namespace with the same indexes and
+    /// default collation.
+    pub fn empty_clone(&self) -> Self {
+        Self {
+            indexes: self.indexes.iter().map(Index::empty_clone).collect(),
+            collation: self.collation.clone(),
+            ..Self::new(&self.namespace)
+        }
+    }
+
+    /// The default collation of the collection.
+    pub fn collation(&self) -> Option<&Collation> {
+        self.collation.as_ref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn get(&self, id: RecordId) -> Option<&Document> {
+        self.records.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RecordId, &Document)> {
+        self.records.iter().map(|(id, doc)| (*id, doc))
+    }
+
+    /// Inserts a document, generating an `_id` when it has none.
+    pub fn insert(&mut self, mut doc: Document) -> CommandResult<RecordId> {
+        match doc.get("_id") {
+            Some(Value::Array(_)) => {
+                return Err(CommandError::new(
+                    ErrorCode::BadValue,
+                    "can't use an array for _id",
+                ));
+            }
+            Some(_) => {}
+            None => doc.0.insert(0, ("_id".to_string(), Value::new_object_id())),
+        }
+        let keys = self.index_keys(&doc, None)?;
+
+        let id = self.next_record_id;
+        self.next_record_id += 1;
+        for (index, keys) in self.indexes.iter_mut().zip(keys) {
+            index.insert(id, keys);
+        }
+        self.records.insert(id, doc);
+        Ok(id)
+    }
+
+    pub fn replace(&mut self, id: RecordId, doc: Document) -> CommandResult<()> {
+        let keys = self.index_keys(&doc, Some(id))?;
+        let old = self.records.insert(id, doc);
+        if let Some(old) = &old {
+            for index in &mut self.indexes {
+                index.remove(id, old);
+            }
+        }
+        for (index, keys) in self.indexes.iter_mut().zip(keys) {
+            index.insert(id, keys);
+        }
+        for build in &mut self.builds {
+            build.apply(id, old.as_ref(), self.records.get(&id));
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: RecordId) -> Option<Document> {
+        let doc = self.records.remove(&id)?;
+        for index in &mut self.indexes {
+            index.remove(id, &doc);
+        }
+        for build in &mut self.builds {
+            build.apply(id, Some(&doc), None);
+        }
+        Some(doc)
+    }
+
+    pub fn indexes(&self) -> &[Index] {
+        &self.indexes
+    }
+
+    pub fn index(&self, name: &str) -> Option<&Index> {
+        self.indexes.iter().find(|index| index.name() == name)
+    }
+
+    pub fn index_mut(&mut self, name: &str) -> Option<&mut Index> {
+        self.indexes.iter_mut().find(|index| index.name() == name)
+    }
+
+    fn remove_expired(&mut self, now: i64) -> usize {
+        let expired: BTreeSet<RecordId> = self
+            .indexes
+            .iter()
+            .flat_map(|index| index.expired(now))
+            .collect();
+        for id in &expired {
+            self.remove(*id);
+        }
+        expired.len()
+    }
+
+    /// Fills an index with the entries of every document, without adding it
+    /// to the collection. Fails if the documents break its unique constraint.
+    pub fn build_index(&self, mut index: Index) -> CommandResult<Index> {
+        for (id, doc) in &self.records {
+            let keys = index.keys(doc)?;
+            index.check_unique(&self.namespace, Some(*id), &keys.0)?;
+            index.insert(*id, keys);
+        }
+        Ok(index)
+    }
+
+    /// Adds an index built by [`Collection::build_index`].
+    pub fn add_index(&mut self, index: Index) {
+        self.indexes.push(index);
+    }
+
+    /// Starts building indexes in the background. They are added once
+    /// [`Storage::continue_index_builds`] scanned every document.
+    pub fn start_index_build(&mut self, build: IndexBuild) {
+        self.builds.push(build);
+    }
+
+    pub fn index_builds(&self) -> &[IndexBuild] {
+        &self.builds
+    }
+
+    pub fn index_builds_mut(&mut self) -> &mut [IndexBuild] {
+        &mut self.builds
+    }
+
+    /// Fails for operations that need the collection to themselves, which
+    /// conflict with the intent lock an index build holds.
+    pub fn check_no_index_builds(&self) -> CommandResult<()> {
+        if self.builds.is_empty() {
+            return Ok(());
+        }
+        Err(CommandError::new(
+            ErrorCode::BackgroundOperationInProgressForNamespace,
+            format!(
+                "cannot perform operation: an index build is currently running for collection {}",
+                self.namespace
+            ),
+        ))
+    }
+
+    /// Scans a batch of documents for every build, committing the builds
+    /// whose scan ended and dropping those that failed.
+    fn continue_index_builds(&mut self) -> Vec<(OpId, CommandResult<Document>)> {
+        let mut ended = Vec::new();
+        for mut build in std::mem::take(&mut self.builds) {
+            let op_id = build.op_id();
+            match build.scan(&self.records) {
+                Ok(false) => self.builds.push(build),
+                Ok(true) => match build.commit(&self.namespace) {
+                    Ok((indexes, reply)) => {
+                        self.indexes.extend(indexes);
+                        ended.push((op_id, Ok(reply)));
+                    }
+                    Err(err) => ended.push((op_id, Err(err))),
+                },
+                Err(err) => ended.push((op_id, Err(err))),
+            }
+        }
+        ended
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> Option<Index> {
+        let position = self.indexes.iter().position(|index| index.name() == name)?;
+        Some(self.indexes.remove(position))
+    }
+
+    /// The keys of the document with record id `id` for every index,
+    /// generated and checked against unique indexes before any index changes
+    /// so that a failure leaves them all as they were.
+    fn index_keys(
+        &self,
+        doc: &Document,
+        id: Option<RecordId>,
+    ) -> CommandResult<Vec<(BTreeSet<IndexKey>, bool)>> {
+        let mut keys = Vec::new();
+        for index in &self.indexes {
+            let index_keys = index.keys(doc)?;
+            index.check_unique(&self.namespace, id, &index_keys.0)?;
+            keys.push(index_keys);
+        }
+        Ok(keys)
+    }
+
+    /// Whether a unique index guarantees that no two documents share values
+    /// for these fields, in any order. Sparse and partial indexes don't, as
+    /// they leave documents out.
+    pub fn has_unique_index(&self, fields: &[String]) -> bool {
+        let mut fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+        fields.sort_unstable();
+        self.indexes
+            .iter()
+            .filter(|index| index.is_unique() && !index.is_sparse_or_partial())
+            .any(|index| {
+                let mut paths: Vec<String> = index.paths().map(|path| path.join(".")).collect();
+                paths.sort_unstable();
+                paths == fields
+            })
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+/// The field of a wildcard index key holding the path of the value.
+pub const PATH_FIELD: &str = "$_path";
+
+/// The definition of a wildcard index, on `"$**"` or `"path.$**"`: it keys
+/// every scalar under its path by the path of the scalar and its value, so
+/// that a query on any one path can be answered from it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WildcardIndex {
+    /// The path the index is on, empty for `"$**"`.
+    prefix: Vec<String>,
+    /// The `wildcardProjection` as given, for `listIndexes`.
+    projection: Option<Document>,
+    /// The paths of the projection, included or excluded.
+    paths: Vec<Vec<String>>,
+    inclusion: bool,
+    /// Whether `_id` is indexed, which takes a projection including it.
+    include_id: bool,
+}
+
+impl WildcardIndex {
+    /// Parses the options of a wildcard index on `prefix`, the path its key
+    /// pattern gives before `$**`.
+    pub fn parse(prefix: Vec<String>, spec: &Document) -> CommandResult<Self> {
+        let mut index = Self {
+            prefix,
+            projection: None,
+            paths: Vec::new(),
+            inclusion: false,
+            include_id: false,
+        };
+        let projection = match spec.get("wildcardProjection") {
+            None => return Ok(index),
+            Some(_) if !index.prefix.is_empty() => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    "The field 'wildcardProjection' is only allowed when 'key' is {\"$**\": ±1}",
+                ));
+            }
+            Some(Value::Document(projection)) if projection.is_empty() => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    "The 'wildcardProjection' field can't be an empty object",
+                ));
+            }
+            Some(Value::Document(projection)) => projection,
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "The field 'wildcardProjection' must be an object, but got {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        let mut fields = Vec::new();
+        flatten(projection, &mut Vec::new(), &mut fields)?;
+        let mut inclusion = None;
+        for (path, included) in fields {
+            if path == ["_id"] {
+                index.include_id = included;
+                continue;
+            }
+            match inclusion {
+                None => inclusion = Some(included),
+                Some(true) if !included => {
+                    return Err(CommandError::new(
+                        ErrorCode::Location(31254),
+                        format!(
+                            "Cannot do exclusion on field {} in inclusion projection",
+                            path.join(".")
+                        ),
+                    ));
+                }
+                Some(false) if included => {
+                    return Err(CommandError::new(
+                        ErrorCode::Location(31253),
+                        format!(
+                            "Cannot do inclusion on field {} in exclusion projection",
+                            path.join(".")
+                        ),
+                    ));
+                }
+                Some(_) => {}
+            }
+            index.paths.push(path);
+        }
+        // A projection of `_id` alone includes nothing else
+        index.inclusion = inclusion.unwrap_or(index.include_id);
+        index.projection = Some(projection.clone());
+        Ok(index)
+    }
+
+    /// Adds the options of the index to its `listIndexes` specification.
+    pub fn add_spec(&self, spec: &mut Document) {
+        if let Some(projection) = &self.projection {
+            spec.insert("wildcardProjection", Value::Document(projection.clone()));
+        }
+    }
+
+    /// Whether the scalars at `path` are indexed.
+    pub fn indexes(&self, path: &[String]) -> bool {
+        if !path.starts_with(&self.prefix) {
+            return false;
+        }
+        if self.prefix.is_empty() && path.first().is_some_and(|field| field == "_id") {
+            return self.include_id;
+        }
+        let projected = self
+            .paths
+            .iter()
+            .any(|projected| path.starts_with(projected));
+        projected == self.inclusion
+    }
+
+    /// The paths and values of the scalars of a document the index keys,
+    /// and whether any came from an array. Arrays are keyed by the path of
+    /// the field holding them, like a query sees their elements.
+    pub fn keys(&self, doc: &Document) -> (Vec<(String, Value)>, bool) {
+        let mut keys = Vec::new();
+        let mut multikey = false;
+        let mut path = Vec::new();
+        for (field, value) in doc.iter() {
+            path.push(field.clone());
+            self.collect(value, &mut path, &mut keys, &mut multikey);
+            path.pop();
+        }
+        (keys, multikey)
+    }
+
+    fn collect(
+        &self,
+        value: &Value,
+        path: &mut Vec<String>,
+        keys: &mut Vec<(String, Value)>,
+        multikey: &mut bool,
+    ) {
+        match value {
+            Value::Document(doc) => {
+                for (field, value) in doc.iter() {
+                    path.push(field.clone());
+                    self.collect(value, path, keys, multikey);
+                    path.pop();
+                }
+            }
+            Value::Array(items) => {
+                *multikey = true;
+                for item in &items.0 {
+                    self.collect(item, path, keys, multikey);
+                }
+            }
+            value if self.indexes(path) => keys.push((path.join("."), value.clone())),
+            _ => {}
+        }
+    }
+}
+
+/// The paths of a projection and whether each is included, with nested
+/// documents such as `{ a: { b: 1 } }` standing for dotted paths.
+fn flatten(
+    projection: &Document,
+    prefix: &mut Vec<String>,
+    fields: &mut Vec<(Vec<String>, bool)>,
+) -> CommandResult<()> {
+    for (field, value) in projection.iter() {
+        prefix.extend(field.split('.').map(String::from));
+        match value {
+            Value::Document(nested) if !nested.is_empty() => flatten(nested, prefix, fields)?,
+            Value::Boolean(included) => fields.push((prefix.clone(), *included)),
+            value if value.is_number() => fields.push((prefix.clone(), value.is_truthy())),
+            value => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    format!(
+                        "The 'wildcardProjection' field {} must be a number or boolean, not {}",
+                        field,
+                        value.type_name()
+                    ),
+                ));
+            }
+        }
+        prefix.truncate(prefix.len() - field.split('.').count());
+    }
+    Ok(())
+}
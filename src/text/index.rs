@@ -0,0 +1,309 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{normalize, term, words, Language, Sensitivity, TextQuery};
+
+/// The path of a text index that indexes every string of a document.
+const WILDCARD: &str = "$**";
+
+/// The version of the text index format, as mongod's current one.
+pub const TEXT_INDEX_VERSION: i32 = 3;
+
+fn cannot_create(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::CannotCreateIndex, message)
+}
+
+/// The definition of a text index: the fields it indexes with their
+/// weights, and the language of the documents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextIndex {
+    /// The weight of every indexed path, sorted by path.
+    weights: Vec<(String, f64)>,
+    default_language: Language,
+    /// The field of a document naming its language, when not the default.
+    language_override: String,
+}
+
+impl TextIndex {
+    /// Parses the options of a text index on `fields`, the paths given the
+    /// value `"text"` in its key pattern. Fields only named in `weights` are
+    /// indexed too.
+    pub fn parse(fields: &[String], spec: &Document) -> CommandResult<Self> {
+        let mut weights: BTreeMap<String, f64> =
+            fields.iter().map(|field| (field.clone(), 1.0)).collect();
+        match spec.get("weights") {
+            None => {}
+            Some(Value::Document(given)) => {
+                for (field, weight) in given.iter() {
+                    let weight = match weight.as_f64() {
+                        Some(weight) if weight.is_finite() && weight > 0.0 && weight < 100000.0 => {
+                            weight
+                        }
+                        _ => {
+                            return Err(cannot_create(format!(
+                                "text index weight must be in the exclusive interval (0,100000) but found: {}",
+                                weight
+                            )));
+                        }
+                    };
+                    weights.insert(field.clone(), weight);
+                }
+            }
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!("weights must be an object, not {}", value.type_name()),
+                ));
+            }
+        }
+        if weights.is_empty() {
+            return Err(cannot_create("text index must index at least one field"));
+        }
+
+        let default_language = match spec.get("default_language") {
+            None => Language::English,
+            Some(Value::String(language)) => Language::parse(language)?,
+            Some(value) => {
+                return Err(CommandError::new(
+                    ErrorCode::TypeMismatch,
+                    format!(
+                        "default_language must be a string, not {}",
+                        value.type_name()
+                    ),
+                ));
+            }
+        };
+        let language_override = match spec.get("language_override") {
+            None => "language".to_string(),
+            Some(Value::String(field)) if !field.is_empty() && !field.starts_with('$') => {
+                field.clone()
+            }
+            Some(value) => {
+                return Err(cannot_create(format!(
+                    "language_override must be a field name, not {}",
+                    value
+                )));
+            }
+        };
+        match spec.get("textIndexVersion") {
+            None => {}
+            Some(version) if version.as_i64() == Some(TEXT_INDEX_VERSION as i64) => {}
+            Some(version) => {
+                return Err(cannot_create(format!(
+                    "Currently only textIndexVersion {} is supported, not {}",
+                    TEXT_INDEX_VERSION, version
+                )));
+            }
+        }
+        Ok(Self {
+            weights: weights.into_iter().collect(),
+            default_language,
+            language_override,
+        })
+    }
+
+    pub fn default_language(&self) -> Language {
+        self.default_language
+    }
+
+    /// Adds the options of the index to its `listIndexes` specification.
+    pub fn add_spec(&self, spec: &mut Document) {
+        let mut weights = Document::new();
+        for (path, weight) in &self.weights {
+            let weight = if weight.fract() == 0.0 {
+                Value::Int32(*weight as i32)
+            } else {
+                Value::Double(*weight)
+            };
+            weights.insert(path.clone(), weight);
+        }
+        spec.insert("weights", Value::Document(weights));
+        spec.insert(
+            "default_language",
+            Value::String(self.default_language.name().to_string()),
+        );
+        spec.insert(
+            "language_override",
+            Value::String(self.language_override.clone()),
+        );
+        spec.insert("textIndexVersion", Value::Int32(TEXT_INDEX_VERSION));
+    }
+
+    /// The language of a document, which may name its own.
+    fn language(&self, doc: &Document) -> CommandResult<Language> {
+        match doc.get(&self.language_override) {
+            Some(Value::String(language)) => Language::parse(language),
+            _ => Ok(self.default_language),
+        }
+    }
+
+    /// The indexed strings of a document with their weights.
+    fn strings<'d>(&self, doc: &'d Document) -> Vec<(&'d str, f64)> {
+        let mut strings = Vec::new();
+        for (path, weight) in &self.weights {
+            if path == WILDCARD {
+                collect_all(doc, "", &mut |path, string| {
+                    // Fields with a weight of their own are indexed with it
+                    if !self.weights.iter().any(|(weighted, _)| *weighted == path) {
+                        strings.push((string, *weight));
+                    }
+                });
+            } else {
+                let path: Vec<&str> = path.split('.').collect();
+                collect_path(doc, &path, &mut |string| strings.push((string, *weight)));
+            }
+        }
+        strings
+    }
+
+    /// The terms of a document with their scores. Like mongod, a term scores
+    /// more the more often it appears in a string, with diminishing returns,
+    /// and the larger the share of the string it makes up, times the weight
+    /// of the field. A term making up a whole string gets a small bonus.
+    pub fn terms(&self, doc: &Document) -> CommandResult<HashMap<String, f64>> {
+        let language = self.language(doc)?;
+        let mut scores: HashMap<String, f64> = HashMap::new();
+        for (string, weight) in self.strings(doc) {
+            let terms: Vec<String> = words(string)
+                .filter_map(|word| term(word, language, Sensitivity::default()))
+                .collect();
+            // The count of every term, and the sum of 1, 1/2, 1/4... over
+            // its occurrences
+            let mut frequencies: HashMap<&str, (usize, f64)> = HashMap::new();
+            for term in &terms {
+                let (count, frequency) = frequencies.entry(term).or_default();
+                *frequency += 1.0 / f64::powi(2.0, *count as i32);
+                *count += 1;
+            }
+            for (term, (count, frequency)) in frequencies {
+                let coefficient = 0.5 * count as f64 / terms.len() as f64 + 0.5;
+                let adjustment = if string.eq_ignore_ascii_case(term) {
+                    1.1
+                } else {
+                    1.0
+                };
+                *scores.entry(term.to_string()).or_default() +=
+                    weight * frequency * coefficient * adjustment;
+            }
+        }
+        Ok(scores)
+    }
+
+    /// Whether a document found through the index for some term of a query
+    /// also has the terms and phrases the query needs, and none it excludes.
+    /// Terms are compared the way the query asks, in its language.
+    pub fn matches(&self, doc: &Document, query: &TextQuery) -> bool {
+        let strings: Vec<&str> = self.strings(doc).into_iter().map(|(s, _)| s).collect();
+        let terms: HashSet<String> = strings
+            .iter()
+            .flat_map(|string| words(string))
+            .filter_map(|word| term(word, query.language, query.sensitivity))
+            .collect();
+        if query.sensitivity != Sensitivity::default()
+            && !query.terms.iter().any(|term| terms.contains(term))
+        {
+            return false;
+        }
+        if query.negated_terms.iter().any(|term| terms.contains(term)) {
+            return false;
+        }
+        let strings: Vec<String> = strings
+            .iter()
+            .map(|string| normalize(string, query.sensitivity))
+            .collect();
+        let contains = |phrase: &String| strings.iter().any(|string| string.contains(phrase));
+        query.phrases.iter().all(contains) && !query.negated_phrases.iter().any(contains)
+    }
+}
+
+/// Calls `found` with every string at `path`, looking into arrays.
+fn collect_path<'d>(doc: &'d Document, path: &[&str], found: &mut impl FnMut(&'d str)) {
+    let Some((first, rest)) = path.split_first() else {
+        return;
+    };
+    if let Some(value) = doc.get(first) {
+        collect_value(value, rest, found);
+    }
+}
+
+fn collect_value<'d>(value: &'d Value, path: &[&str], found: &mut impl FnMut(&'d str)) {
+    match value {
+        Value::String(string) if path.is_empty() => found(string),
+        Value::Document(doc) => collect_path(doc, path, found),
+        Value::Array(items) => {
+            for item in &items.0 {
+                collect_value(item, path, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Calls `found` with every string of a document and its path.
+fn collect_all<'d>(doc: &'d Document, prefix: &str, found: &mut impl FnMut(&str, &'d str)) {
+    for (field, value) in doc.iter() {
+        let path = if prefix.is_empty() {
+            field.clone()
+        } else {
+            format!("{}.{}", prefix, field)
+        };
+        collect_all_value(value, &path, found);
+    }
+}
+
+fn collect_all_value<'d>(value: &'d Value, path: &str, found: &mut impl FnMut(&str, &'d str)) {
+    match value {
+        Value::String(string) => found(path, string),
+        Value::Document(doc) => collect_all(doc, path, found),
+        Value::Array(items) => {
+            for item in &items.0 {
+                collect_all_value(item, path, found);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{bson::json::doc, storage::Index};
+
+    #[test]
+    fn test_text_index() {
+        let index = Index::parse(&doc(
+            "{ key: { '$**': 'text' }, weights: { title: 5 }, default_language: 'none' }",
+        ))
+        .unwrap();
+        assert_eq!(
+            index.spec(),
+            doc(
+                "{ v: 2, key: { _fts: 'text', _ftsx: 1 }, name: '$**_text', \
+                 weights: { '$**': 1, title: 5 }, default_language: 'none', \
+                 language_override: 'language', textIndexVersion: 3 }"
+            )
+        );
+        // The wildcard indexes every string but those weighted on their own
+        let text = index.text().unwrap();
+        let terms = text
+            .terms(&doc("{ title: 'a', tags: ['b', { c: 'a b' }], n: 1 }"))
+            .unwrap();
+        assert_eq!(terms.get("a"), Some(&6.25));
+        assert_eq!(terms.get("b"), Some(&1.85));
+        // The listed specification creates the same index
+        assert_eq!(Index::parse(&index.spec()).unwrap().spec(), index.spec());
+
+        for spec in [
+            "{ key: { a: 'text', b: 1 } }",
+            "{ key: { a: 'text' }, weights: { a: 0 } }",
+            "{ key: { a: 'text' }, textIndexVersion: 2 }",
+            "{ key: { a: 'text' }, default_language: 'klingon' }",
+            "{ key: { a: 1 }, weights: { a: 2 } }",
+        ] {
+            assert!(Index::parse(&doc(spec)).is_err(), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,297 @@
+//! Full text search. Strings are split into words, which are reduced to
+//! terms: without case or diacritics by default, stemmed, and dropping stop
+//! words. A text index holds the terms of the strings of every document with
+//! a score, and `$text` queries look their terms up in it.
+
+mod index;
+mod search;
+mod stemmer;
+
+pub use index::{TextIndex, TEXT_INDEX_VERSION};
+pub use search::{TextQuery, TextSearch};
+
+use crate::error::{CommandError, CommandResult, ErrorCode};
+
+/// The languages text can be indexed and searched in. With `none`, words are
+/// neither stemmed nor checked against a list of stop words.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    None,
+}
+
+impl Language {
+    /// The language of a name or code as accepted by mongod.
+    pub fn parse(name: &str) -> CommandResult<Self> {
+        match name {
+            "english" | "en" => Ok(Language::English),
+            "none" => Ok(Language::None),
+            _ => Err(CommandError::new(
+                ErrorCode::BadValue,
+                format!(
+                    "unsupported language: \"{}\" for text index version 3",
+                    name
+                ),
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::English => "english",
+            Language::None => "none",
+        }
+    }
+}
+
+/// Whether terms keep their case and diacritics. Both are ignored by
+/// default; indexes always ignore them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sensitivity {
+    pub case: bool,
+    pub diacritics: bool,
+}
+
+/// The words of a text: runs of letters and digits.
+pub fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+/// A text with the case and diacritics it is compared without removed.
+pub fn normalize(text: &str, sensitivity: Sensitivity) -> String {
+    let text: String = if sensitivity.diacritics {
+        text.to_string()
+    } else {
+        text.chars().map(remove_diacritic).collect()
+    };
+    if sensitivity.case {
+        text
+    } else {
+        text.to_lowercase()
+    }
+}
+
+/// The term of a word in a language, or `None` for a stop word.
+pub fn term(word: &str, language: Language, sensitivity: Sensitivity) -> Option<String> {
+    let word = normalize(
+        word,
+        Sensitivity {
+            case: true,
+            ..sensitivity
+        },
+    );
+    let lowercase = word.to_lowercase();
+    if language == Language::English && ENGLISH_STOP_WORDS.binary_search(&&*lowercase).is_ok() {
+        return None;
+    }
+    let stem = match language {
+        Language::English => stemmer::stem(&lowercase),
+        Language::None => lowercase,
+    };
+    if !sensitivity.case {
+        return Some(stem);
+    }
+    // The stem keeps the case the word had on the letters it kept
+    let mut original = word.chars();
+    Some(
+        stem.chars()
+            .map(|c| match original.next() {
+                Some(o) if o.to_lowercase().eq(std::iter::once(c)) => o,
+                _ => c,
+            })
+            .collect(),
+    )
+}
+
+/// The letter a Latin letter with a diacritic is a variant of.
+fn remove_diacritic(c: char) -> char {
+    match c {
+        'à'..='å' => 'a',
+        'À'..='Å' => 'A',
+        'ç' => 'c',
+        'Ç' => 'C',
+        'è'..='ë' => 'e',
+        'È'..='Ë' => 'E',
+        'ì'..='ï' => 'i',
+        'Ì'..='Ï' => 'I',
+        'ñ' => 'n',
+        'Ñ' => 'N',
+        'ò'..='ö' | 'ø' => 'o',
+        'Ò'..='Ö' | 'Ø' => 'O',
+        'ù'..='ü' => 'u',
+        'Ù'..='Ü' => 'U',
+        'ý' | 'ÿ' => 'y',
+        'Ý' => 'Y',
+        c => c,
+    }
+}
+
+/// Words too common to be worth indexing, sorted. Words with apostrophes
+/// are left out, as they are split into several words.
+const ENGLISH_STOP_WORDS: &[&str] = &[
+    "a",
+    "about",
+    "above",
+    "after",
+    "again",
+    "against",
+    "all",
+    "am",
+    "an",
+    "and",
+    "any",
+    "are",
+    "as",
+    "at",
+    "be",
+    "because",
+    "been",
+    "before",
+    "being",
+    "below",
+    "between",
+    "both",
+    "but",
+    "by",
+    "can",
+    "cannot",
+    "could",
+    "did",
+    "do",
+    "does",
+    "doing",
+    "down",
+    "during",
+    "each",
+    "few",
+    "for",
+    "from",
+    "further",
+    "had",
+    "has",
+    "have",
+    "having",
+    "he",
+    "her",
+    "here",
+    "hers",
+    "herself",
+    "him",
+    "himself",
+    "his",
+    "how",
+    "i",
+    "if",
+    "in",
+    "into",
+    "is",
+    "it",
+    "its",
+    "itself",
+    "me",
+    "more",
+    "most",
+    "my",
+    "myself",
+    "no",
+    "nor",
+    "not",
+    "of",
+    "off",
+    "on",
+    "once",
+    "only",
+    "or",
+    "other",
+    "ought",
+    "our",
+    "ours",
+    "ourselves",
+    "out",
+    "over",
+    "own",
+    "same",
+    "she",
+    "should",
+    "so",
+    "some",
+    "such",
+    "than",
+    "that",
+    "the",
+    "their",
+    "theirs",
+    "them",
+    "themselves",
+    "then",
+    "there",
+    "these",
+    "they",
+    "this",
+    "those",
+    "through",
+    "to",
+    "too",
+    "under",
+    "until",
+    "up",
+    "very",
+    "was",
+    "we",
+    "were",
+    "what",
+    "when",
+    "where",
+    "which",
+    "while",
+    "who",
+    "whom",
+    "why",
+    "will",
+    "with",
+    "would",
+    "you",
+    "your",
+    "yours",
+    "yourself",
+    "yourselves",
+];
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_terms() {
+        assert!(ENGLISH_STOP_WORDS.windows(2).all(|pair| pair[0] < pair[1]));
+        let terms = |text: &str, language, sensitivity| {
+            words(text)
+                .filter_map(|word| term(word, language, sensitivity))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            terms(
+                "The Cafés were running, don't stop",
+                Language::English,
+                Sensitivity::default()
+            ),
+            vec!["cafe", "run", "don", "t", "stop"]
+        );
+        assert_eq!(
+            terms(
+                "The Cafés",
+                Language::English,
+                Sensitivity {
+                    case: true,
+                    diacritics: true
+                }
+            ),
+            vec!["Café"]
+        );
+        assert_eq!(
+            terms("The runners", Language::None, Sensitivity::default()),
+            vec!["the", "runners"]
+        );
+    }
+}
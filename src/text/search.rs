@@ -0,0 +1,196 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+};
+
+use super::{normalize, term, words, Language, Sensitivity};
+
+fn bad_value(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::BadValue, message)
+}
+
+/// A `$text` query operator, as in
+/// `{ $text: { $search: 'coffee -tea "latte art"', $language: 'english' } }`.
+#[derive(Debug, Clone)]
+pub struct TextSearch {
+    search: String,
+    language: Option<Language>,
+    sensitivity: Sensitivity,
+}
+
+impl TextSearch {
+    pub fn parse(operator: &Value) -> CommandResult<Self> {
+        let Value::Document(operator) = operator else {
+            return Err(bad_value("$text expects an object"));
+        };
+        let mut search = None;
+        let mut language = None;
+        let mut sensitivity = Sensitivity::default();
+        for (field, value) in operator.iter() {
+            let flag = || match value {
+                Value::Boolean(flag) => Ok(*flag),
+                _ => Err(bad_value(format!("{} requires a boolean value", field))),
+            };
+            match (field.as_str(), value) {
+                ("$search", Value::String(text)) => search = Some(text.clone()),
+                ("$search", _) => return Err(bad_value("$search requires a string value")),
+                ("$language", Value::String(name)) => language = Some(Language::parse(name)?),
+                ("$language", _) => return Err(bad_value("$language requires a string value")),
+                ("$caseSensitive", _) => sensitivity.case = flag()?,
+                ("$diacriticSensitive", _) => sensitivity.diacritics = flag()?,
+                _ => {
+                    return Err(bad_value(format!(
+                        "extra fields in $text: {}",
+                        Document::from_iter([(field.clone(), value.clone())])
+                    )));
+                }
+            }
+        }
+        let Some(search) = search else {
+            return Err(bad_value("$search required"));
+        };
+        Ok(Self {
+            search,
+            language,
+            sensitivity,
+        })
+    }
+
+    /// The terms and phrases of the search in the language of the query,
+    /// or else the default language of the index. A word prefixed with `-`
+    /// or a quoted phrase prefixed with `-` is excluded. The words of
+    /// phrases are searched for too.
+    pub fn query(&self, default_language: Language) -> TextQuery {
+        let language = self.language.unwrap_or(default_language);
+        let mut query = TextQuery {
+            lookup: BTreeSet::new(),
+            terms: BTreeSet::new(),
+            negated_terms: BTreeSet::new(),
+            phrases: Vec::new(),
+            negated_phrases: Vec::new(),
+            language,
+            sensitivity: self.sensitivity,
+        };
+        // Quotes alternate between text outside and inside phrases
+        let mut negated_phrase = false;
+        for (i, part) in self.search.split('"').enumerate() {
+            if i % 2 == 1 {
+                query.add_words(part, negated_phrase);
+                if !part.trim().is_empty() {
+                    let phrase = normalize(part, self.sensitivity);
+                    if negated_phrase {
+                        query.negated_phrases.push(phrase);
+                    } else {
+                        query.phrases.push(phrase);
+                    }
+                }
+                continue;
+            }
+            // A `-` right before a quote negates the phrase
+            negated_phrase =
+                part.ends_with('-') && part.split_whitespace().next_back() == Some("-");
+            for token in part.split_whitespace() {
+                match token.strip_prefix('-') {
+                    Some(word) => query.add_words(word, true),
+                    None => query.add_words(token, false),
+                }
+            }
+        }
+        query
+    }
+}
+
+/// The terms and phrases of a `$text` query in a language.
+#[derive(Debug, Clone)]
+pub struct TextQuery {
+    /// The terms looked up in the index; documents need one of them.
+    pub lookup: BTreeSet<String>,
+    /// The terms as the query compares them.
+    pub terms: BTreeSet<String>,
+    pub negated_terms: BTreeSet<String>,
+    pub phrases: Vec<String>,
+    pub negated_phrases: Vec<String>,
+    pub language: Language,
+    pub sensitivity: Sensitivity,
+}
+
+impl TextQuery {
+    fn add_words(&mut self, text: &str, negated: bool) {
+        for word in words(text) {
+            let Some(compared) = term(word, self.language, self.sensitivity) else {
+                continue;
+            };
+            if negated {
+                self.negated_terms.insert(compared);
+            } else {
+                self.terms.insert(compared);
+                // Indexes ignore case and diacritics
+                if let Some(looked_up) = term(word, self.language, Sensitivity::default()) {
+                    self.lookup.insert(looked_up);
+                }
+            }
+        }
+    }
+
+    /// The query as `explain` shows it.
+    pub fn explain(&self) -> Document {
+        let strings = |strings: Vec<&String>| {
+            Value::from(
+                strings
+                    .into_iter()
+                    .map(|s| Value::String(s.clone()))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let mut explained = Document::new();
+        explained.insert("terms", strings(self.terms.iter().collect()));
+        explained.insert("negatedTerms", strings(self.negated_terms.iter().collect()));
+        explained.insert("phrases", strings(self.phrases.iter().collect()));
+        explained.insert(
+            "negatedPhrases",
+            strings(self.negated_phrases.iter().collect()),
+        );
+        explained
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::value;
+
+    #[test]
+    fn test_text_query() {
+        let query = |operator: &str| {
+            TextSearch::parse(&value(operator))
+                .unwrap()
+                .query(Language::English)
+        };
+        let parsed = query(r#"{ $search: 'Running coffee -tea "latte art" -"bad cups"' }"#);
+        assert_eq!(
+            parsed.lookup.iter().collect::<Vec<_>>(),
+            vec!["art", "coffe", "latt", "run"]
+        );
+        assert_eq!(
+            parsed.negated_terms.iter().collect::<Vec<_>>(),
+            vec!["bad", "cup", "tea"]
+        );
+        assert_eq!(parsed.phrases, vec!["latte art"]);
+        assert_eq!(parsed.negated_phrases, vec!["bad cups"]);
+
+        let parsed = query("{ $search: 'Café', $caseSensitive: true, $language: 'none' }");
+        assert_eq!(parsed.terms.iter().collect::<Vec<_>>(), vec!["Cafe"]);
+        assert_eq!(parsed.lookup.iter().collect::<Vec<_>>(), vec!["cafe"]);
+
+        for operator in [
+            "{ $search: 1 }",
+            "{ $language: 'en' }",
+            "{ $search: 'a', $language: 'klingon' }",
+            "{ $search: 'a', $other: 1 }",
+        ] {
+            assert!(TextSearch::parse(&value(operator)).is_err(), "{}", operator);
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! The English stemmer of the Snowball project, known as Porter2, which
+//! mongod uses to reduce the words of text indexes to their stems. Words are
+//! lowercase; `Y` marks a `y` acting as a consonant.
+
+fn is_vowel(c: char) -> bool {
+    matches!(c, 'a' | 'e' | 'i' | 'o' | 'u' | 'y')
+}
+
+fn ends_with(word: &[char], suffix: &str) -> bool {
+    let suffix: Vec<char> = suffix.chars().collect();
+    word.ends_with(&suffix)
+}
+
+fn has_vowel(word: &[char]) -> bool {
+    word.iter().any(|c| is_vowel(*c))
+}
+
+fn set_suffix(word: &mut Vec<char>, length: usize, replacement: &str) {
+    word.truncate(word.len() - length);
+    word.extend(replacement.chars());
+}
+
+/// The longest of `suffixes` the word ends with.
+fn longest<'s>(word: &[char], suffixes: &[&'s str]) -> Option<&'s str> {
+    suffixes
+        .iter()
+        .filter(|suffix| ends_with(word, suffix))
+        .max_by_key(|suffix| suffix.len())
+        .copied()
+}
+
+/// Where the region after the first non-vowel following a vowel starts,
+/// looking from `start`.
+fn region(word: &[char], start: usize) -> usize {
+    (start + 1..word.len())
+        .find(|i| is_vowel(word[i - 1]) && !is_vowel(word[*i]))
+        .map_or(word.len(), |i| i + 1)
+}
+
+/// Whether the word ends in a short syllable: a vowel followed by a
+/// non-vowel other than `w`, `x` or `Y` and preceded by a non-vowel, or a
+/// vowel at the start of the word followed by a non-vowel.
+fn ends_short_syllable(word: &[char]) -> bool {
+    match word {
+        [first, second] => is_vowel(*first) && !is_vowel(*second),
+        [.., a, b, c] => {
+            !is_vowel(*a) && is_vowel(*b) && !is_vowel(*c) && !matches!(c, 'w' | 'x' | 'Y')
+        }
+        _ => false,
+    }
+}
+
+/// Words stemmed irregularly.
+fn exception(word: &str) -> Option<&'static str> {
+    Some(match word {
+        "skis" => "ski",
+        "skies" => "sky",
+        "dying" => "die",
+        "lying" => "lie",
+        "tying" => "tie",
+        "idly" => "idl",
+        "gently" => "gentl",
+        "ugly" => "ugli",
+        "early" => "earli",
+        "only" => "onli",
+        "singly" => "singl",
+        "sky" => "sky",
+        "news" => "news",
+        "howe" => "howe",
+        "atlas" => "atlas",
+        "cosmos" => "cosmos",
+        "bias" => "bias",
+        "andes" => "andes",
+        _ => return None,
+    })
+}
+
+pub fn stem(word: &str) -> String {
+    if word.chars().count() <= 2 {
+        return word.to_string();
+    }
+    if let Some(stem) = exception(word) {
+        return stem.to_string();
+    }
+    let mut word: Vec<char> = word.trim_start_matches('\'').chars().collect();
+    if word.first() == Some(&'y') {
+        word[0] = 'Y';
+    }
+    for i in 1..word.len() {
+        if word[i] == 'y' && is_vowel(word[i - 1]) {
+            word[i] = 'Y';
+        }
+    }
+    let text: String = word.iter().collect();
+    let r1 = ["gener", "commun", "arsen"]
+        .iter()
+        .find(|prefix| text.starts_with(*prefix))
+        .map_or_else(|| region(&word, 0), |prefix| prefix.len());
+    let r2 = region(&word, r1);
+
+    step_0(&mut word);
+    step_1a(&mut word);
+    let text: String = word.iter().collect();
+    let invariant = [
+        "inning", "outing", "canning", "herring", "earring", "proceed", "exceed", "succeed",
+    ];
+    if !invariant.contains(&text.as_str()) {
+        step_1b(&mut word, r1);
+        step_1c(&mut word);
+        step_2(&mut word, r1);
+        step_3(&mut word, r1, r2);
+        step_4(&mut word, r2);
+        step_5(&mut word, r1, r2);
+    }
+    word.iter()
+        .map(|c| if *c == 'Y' { 'y' } else { *c })
+        .collect()
+}
+
+fn step_0(word: &mut Vec<char>) {
+    if let Some(suffix) = longest(word, &["'s'", "'s", "'"]) {
+        set_suffix(word, suffix.len(), "");
+    }
+}
+
+fn step_1a(word: &mut Vec<char>) {
+    match longest(word, &["sses", "ied", "ies", "us", "ss", "s"]) {
+        Some("sses") => set_suffix(word, 4, "ss"),
+        Some("ied" | "ies") if word.len() > 4 => set_suffix(word, 3, "i"),
+        Some("ied" | "ies") => set_suffix(word, 3, "ie"),
+        Some("s") if has_vowel(&word[..word.len() - 2]) => set_suffix(word, 1, ""),
+        _ => {}
+    }
+}
+
+fn step_1b(word: &mut Vec<char>, r1: usize) {
+    let Some(suffix) = longest(word, &["eedly", "ingly", "edly", "eed", "ing", "ed"]) else {
+        return;
+    };
+    let start = word.len() - suffix.len();
+    if matches!(suffix, "eed" | "eedly") {
+        if start >= r1 {
+            set_suffix(word, suffix.len(), "ee");
+        }
+        return;
+    }
+    if !has_vowel(&word[..start]) {
+        return;
+    }
+    word.truncate(start);
+    if longest(word, &["at", "bl", "iz"]).is_some() {
+        word.push('e');
+    } else if longest(
+        word,
+        &["bb", "dd", "ff", "gg", "mm", "nn", "pp", "rr", "tt"],
+    )
+    .is_some()
+    {
+        word.pop();
+    } else if ends_short_syllable(word) && region(word, 0) >= word.len() {
+        word.push('e');
+    }
+}
+
+fn step_1c(word: &mut [char]) {
+    let n = word.len();
+    if n > 2 && matches!(word[n - 1], 'y' | 'Y') && !is_vowel(word[n - 2]) {
+        word[n - 1] = 'i';
+    }
+}
+
+fn step_2(word: &mut Vec<char>, r1: usize) {
+    const RULES: &[(&str, &str)] = &[
+        ("ization", "ize"),
+        ("ational", "ate"),
+        ("fulness", "ful"),
+        ("ousness", "ous"),
+        ("iveness", "ive"),
+        ("tional", "tion"),
+        ("biliti", "ble"),
+        ("lessli", "less"),
+        ("entli", "ent"),
+        ("ation", "ate"),
+        ("alism", "al"),
+        ("aliti", "al"),
+        ("ousli", "ous"),
+        ("iviti", "ive"),
+        ("fulli", "ful"),
+        ("enci", "ence"),
+        ("anci", "ance"),
+        ("abli", "able"),
+        ("izer", "ize"),
+        ("ator", "ate"),
+        ("alli", "al"),
+        ("bli", "ble"),
+        ("ogi", "og"),
+        ("li", ""),
+    ];
+    let suffixes: Vec<&str> = RULES.iter().map(|(suffix, _)| *suffix).collect();
+    let Some(suffix) = longest(word, &suffixes) else {
+        return;
+    };
+    let start = word.len() - suffix.len();
+    if start < r1 {
+        return;
+    }
+    let preceding = start.checked_sub(1).map(|i| word[i]);
+    let applies = match suffix {
+        "ogi" => preceding == Some('l'),
+        "li" => preceding.is_some_and(|c| "cdeghkmnrt".contains(c)),
+        _ => true,
+    };
+    if applies {
+        let (_, replacement) = RULES.iter().find(|(s, _)| *s == suffix).expect("a rule");
+        set_suffix(word, suffix.len(), replacement);
+    }
+}
+
+fn step_3(word: &mut Vec<char>, r1: usize, r2: usize) {
+    const RULES: &[(&str, &str)] = &[
+        ("ational", "ate"),
+        ("tional", "tion"),
+        ("alize", "al"),
+        ("icate", "ic"),
+        ("iciti", "ic"),
+        ("ative", ""),
+        ("ical", "ic"),
+        ("ness", ""),
+        ("ful", ""),
+    ];
+    let suffixes: Vec<&str> = RULES.iter().map(|(suffix, _)| *suffix).collect();
+    let Some(suffix) = longest(word, &suffixes) else {
+        return;
+    };
+    let start = word.len() - suffix.len();
+    if start < r1 || (suffix == "ative" && start < r2) {
+        return;
+    }
+    let (_, replacement) = RULES.iter().find(|(s, _)| *s == suffix).expect("a rule");
+    set_suffix(word, suffix.len(), replacement);
+}
+
+fn step_4(word: &mut Vec<char>, r2: usize) {
+    let suffixes = [
+        "ement", "ance", "ence", "able", "ible", "ment", "ant", "ent", "ism", "ate", "iti", "ous",
+        "ive", "ize", "ion", "al", "er", "ic",
+    ];
+    let Some(suffix) = longest(word, &suffixes) else {
+        return;
+    };
+    let start = word.len() - suffix.len();
+    if start < r2 {
+        return;
+    }
+    if suffix == "ion" && !(start > 0 && matches!(word[start - 1], 's' | 't')) {
+        return;
+    }
+    word.truncate(start);
+}
+
+fn step_5(word: &mut Vec<char>, r1: usize, r2: usize) {
+    let n = word.len();
+    match word.last() {
+        Some('e') if n > r2 || (n > r1 && !ends_short_syllable(&word[..n - 1])) => {
+            word.pop();
+        }
+        Some('l') if n > r2 && word[n - 2] == 'l' => {
+            word.pop();
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stem() {
+        for (word, expected) in [
+            ("consign", "consign"),
+            ("consigned", "consign"),
+            ("consignment", "consign"),
+            ("consistency", "consist"),
+            ("knackeries", "knackeri"),
+            ("running", "run"),
+            ("runs", "run"),
+            ("happiness", "happi"),
+            ("caresses", "caress"),
+            ("ponies", "poni"),
+            ("ties", "tie"),
+            ("cries", "cri"),
+            ("hopping", "hop"),
+            ("hoped", "hope"),
+            ("agreed", "agre"),
+            ("generalization", "general"),
+            ("communication", "communic"),
+            ("skies", "sky"),
+            ("succeeding", "succeed"),
+            ("cats", "cat"),
+            ("gas", "gas"),
+            ("coffee", "coffe"),
+            ("controllable", "control"),
+            ("relational", "relat"),
+            ("yelling", "yell"),
+        ] {
+            assert_eq!(stem(word), expected, "stem of {}", word);
+        }
+    }
+}
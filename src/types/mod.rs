@@ -1,7 +1,17 @@
 mod msg_header;
+mod op_delete;
+mod op_get_more;
+mod op_insert;
+mod op_kill_cursors;
 mod op_query;
 mod op_reply;
+mod op_update;
 
 pub use msg_header::MsgHeader;
+pub use op_delete::OpDelete;
+pub use op_get_more::OpGetMore;
+pub use op_insert::OpInsert;
+pub use op_kill_cursors::OpKillCursors;
 pub use op_query::OpQuery;
-pub use op_reply::OpReply;
+pub use op_reply::{OpReply, ResponseFlag};
+pub use op_update::OpUpdate;
@@ -0,0 +1,49 @@
+use std::fmt;
+
+pub struct OpGetMore<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl<'a> OpGetMore<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn full_collection_name(&self) -> String {
+        let mut full_collection_name = String::new();
+        let mut i = 20;
+        while self.bytes[i] != 0 {
+            full_collection_name.push(self.bytes[i] as char);
+            i += 1;
+        }
+        full_collection_name
+    }
+
+    pub fn number_to_return(&self) -> i32 {
+        let i = 20 + self.full_collection_name().len() + 1;
+        i32::from_le_bytes(
+            self.bytes[i..i + 4]
+                .try_into()
+                .expect("message is well formed"),
+        )
+    }
+
+    pub fn cursor_id(&self) -> i64 {
+        let i = 20 + self.full_collection_name().len() + 1 + 4;
+        i64::from_le_bytes(
+            self.bytes[i..i + 8]
+                .try_into()
+                .expect("message is well formed"),
+        )
+    }
+}
+
+impl fmt::Debug for OpGetMore<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpGetMore")
+            .field("full_collection_name", &self.full_collection_name())
+            .field("number_to_return", &self.number_to_return())
+            .field("cursor_id", &self.cursor_id())
+            .finish()
+    }
+}
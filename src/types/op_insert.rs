@@ -0,0 +1,57 @@
+use std::fmt;
+
+use crate::bson::{Bson, Document};
+
+pub struct OpInsert<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl<'a> OpInsert<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn flags(&self) -> i32 {
+        i32::from_le_bytes(
+            self.bytes[16..20]
+                .try_into()
+                .expect("message is well formed"),
+        )
+    }
+
+    pub fn continue_on_error(&self) -> bool {
+        self.flags() & 1 != 0
+    }
+
+    pub fn full_collection_name(&self) -> String {
+        let mut full_collection_name = String::new();
+        let mut i = 20;
+        while self.bytes[i] != 0 {
+            full_collection_name.push(self.bytes[i] as char);
+            i += 1;
+        }
+        full_collection_name
+    }
+
+    /// The documents to insert, which fill the rest of the message.
+    pub fn documents(&self) -> Vec<Document> {
+        let mut documents = Vec::new();
+        let mut i = 20 + self.full_collection_name().len() + 1;
+        while i + 4 <= self.bytes.len() {
+            let bson = Bson::from_bytes(&self.bytes[i..]);
+            documents.push(bson.parse());
+            i += bson.len() as usize;
+        }
+        documents
+    }
+}
+
+impl fmt::Debug for OpInsert<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpInsert")
+            .field("flags", &self.flags())
+            .field("full_collection_name", &self.full_collection_name())
+            .field("documents", &self.documents())
+            .finish()
+    }
+}
@@ -0,0 +1,42 @@
+use std::fmt;
+
+pub struct OpKillCursors<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl<'a> OpKillCursors<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn number_of_cursor_ids(&self) -> i32 {
+        i32::from_le_bytes(
+            self.bytes[20..24]
+                .try_into()
+                .expect("message is well formed"),
+        )
+    }
+
+    pub fn cursor_ids(&self) -> Vec<i64> {
+        (0..self.number_of_cursor_ids().max(0) as usize)
+            .map(|n| 24 + n * 8)
+            .take_while(|i| i + 8 <= self.bytes.len())
+            .map(|i| {
+                i64::from_le_bytes(
+                    self.bytes[i..i + 8]
+                        .try_into()
+                        .expect("message is well formed"),
+                )
+            })
+            .collect()
+    }
+}
+
+impl fmt::Debug for OpKillCursors<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpKillCursors")
+            .field("number_of_cursor_ids", &self.number_of_cursor_ids())
+            .field("cursor_ids", &self.cursor_ids())
+            .finish()
+    }
+}
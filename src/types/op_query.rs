@@ -50,8 +50,17 @@ impl<'a> OpQuery<'a> {
     pub fn query(&self) -> Document {
         let i = 20 + self.full_collection_name().len() + 1 + 8;
         let bson = Bson::from_bytes(&self.bytes[i..]);
-        let result = bson.parse();
-        result
+        bson.parse()
+    }
+
+    /// The optional projection following the query document.
+    pub fn return_fields_selector(&self) -> Option<Document> {
+        let i = 20 + self.full_collection_name().len() + 1 + 8;
+        let i = i + Bson::from_bytes(&self.bytes[i..]).len() as usize;
+        if i + 4 > self.bytes.len() {
+            return None;
+        }
+        Some(Bson::from_bytes(&self.bytes[i..]).parse())
     }
 }
 
@@ -63,6 +72,7 @@ impl fmt::Debug for OpQuery<'_> {
             .field("number_to_skip", &self.number_to_skip())
             .field("number_to_return", &self.number_to_return())
             .field("query", &self.query())
+            .field("return_fields_selector", &self.return_fields_selector())
             .finish()
     }
 }
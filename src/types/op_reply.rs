@@ -1,3 +1,5 @@
+use std::fmt;
+
 use crate::bson::Document;
 
 pub struct OpReply {
@@ -9,6 +11,16 @@ pub struct OpReply {
 }
 
 impl OpReply {
+    pub fn new(documents: Vec<Document>) -> Self {
+        Self {
+            response_flags: 0,
+            cursor_id: 0,
+            starting_from: 0,
+            number_returned: documents.len() as i32,
+            documents,
+        }
+    }
+
     pub fn set_flag(&mut self, flag: ResponseFlag) {
         self.response_flags |= flag as u32;
     }
@@ -17,7 +29,7 @@ impl OpReply {
         self.response_flags & *flag as u32 == *flag as u32
     }
 
-    fn to_bytes(&self, request_id: i32, response_to: i32) -> Vec<u8> {
+    pub fn to_bytes(&self, request_id: i32, response_to: i32) -> Vec<u8> {
         let mut bytes = Vec::new();
 
         // Message length placeholder
@@ -33,7 +45,7 @@ impl OpReply {
         bytes.extend_from_slice(&self.response_flags.to_le_bytes());
         bytes.extend_from_slice(&self.cursor_id.to_le_bytes());
         bytes.extend_from_slice(&self.starting_from.to_le_bytes());
-        bytes.extend_from_slice(&(self.documents.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.number_returned.to_le_bytes());
 
         for document in &self.documents {
             bytes.extend_from_slice(&document.to_bytes());
@@ -47,6 +59,27 @@ impl OpReply {
     }
 }
 
+impl fmt::Debug for OpReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpReply")
+            .field(
+                "cursor_not_found",
+                &self.has_flag(&ResponseFlag::CursorNotFound),
+            )
+            .field("query_failure", &self.has_flag(&ResponseFlag::QueryFailure))
+            .field(
+                "shard_config_stale",
+                &self.has_flag(&ResponseFlag::ShardConfigStale),
+            )
+            .field("await_capable", &self.has_flag(&ResponseFlag::AwaitCapable))
+            .field("cursor_id", &self.cursor_id)
+            .field("starting_from", &self.starting_from)
+            .field("number_returned", &self.number_returned)
+            .field("documents", &self.documents)
+            .finish()
+    }
+}
+
 #[repr(u32)]
 #[derive(Clone, Copy)]
 pub enum ResponseFlag {
@@ -0,0 +1,62 @@
+use std::fmt;
+
+use crate::bson::{Bson, Document};
+
+pub struct OpUpdate<'a> {
+    pub bytes: &'a [u8],
+}
+
+impl<'a> OpUpdate<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn full_collection_name(&self) -> String {
+        let mut full_collection_name = String::new();
+        let mut i = 20;
+        while self.bytes[i] != 0 {
+            full_collection_name.push(self.bytes[i] as char);
+            i += 1;
+        }
+        full_collection_name
+    }
+
+    pub fn flags(&self) -> i32 {
+        let i = 20 + self.full_collection_name().len() + 1;
+        i32::from_le_bytes(
+            self.bytes[i..i + 4]
+                .try_into()
+                .expect("message is well formed"),
+        )
+    }
+
+    pub fn upsert(&self) -> bool {
+        self.flags() & 1 != 0
+    }
+
+    pub fn multi_update(&self) -> bool {
+        self.flags() & 2 != 0
+    }
+
+    pub fn selector(&self) -> Document {
+        let i = 20 + self.full_collection_name().len() + 1 + 4;
+        Bson::from_bytes(&self.bytes[i..]).parse()
+    }
+
+    pub fn update(&self) -> Document {
+        let i = 20 + self.full_collection_name().len() + 1 + 4;
+        let i = i + Bson::from_bytes(&self.bytes[i..]).len() as usize;
+        Bson::from_bytes(&self.bytes[i..]).parse()
+    }
+}
+
+impl fmt::Debug for OpUpdate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OpUpdate")
+            .field("full_collection_name", &self.full_collection_name())
+            .field("flags", &self.flags())
+            .field("selector", &self.selector())
+            .field("update", &self.update())
+            .finish()
+    }
+}
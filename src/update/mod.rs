@@ -0,0 +1,721 @@
+//! Update documents, as accepted by `update` and `findAndModify`: either a
+//! replacement document, a document of update operators such as
+//! `{ $set: { a: 1 }, $inc: { b: 2 } }`, or an aggregation pipeline of
+//! `$addFields`-like stages.
+
+mod modifier;
+mod path;
+
+use std::collections::HashMap;
+
+use crate::{
+    aggregation::{Context, Pipeline},
+    bson::{Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::Matcher,
+};
+
+use modifier::{Modifier, Operator};
+use path::{set_path, Segment};
+
+#[derive(Debug, Clone)]
+pub struct Update {
+    kind: UpdateKind,
+    array_filters: HashMap<String, Matcher>,
+}
+
+#[derive(Debug, Clone)]
+enum UpdateKind {
+    Replacement(Document),
+    Modifiers(Vec<Modifier>),
+    Pipeline(Pipeline),
+}
+
+/// The stages that may appear in an update pipeline.
+const PIPELINE_STAGES: &[&str] = &[
+    "$addFields",
+    "$set",
+    "$project",
+    "$unset",
+    "$replaceRoot",
+    "$replaceWith",
+];
+
+impl Update {
+    /// Parses an update document along with the `arrayFilters` option.
+    pub fn parse(update: &Value, array_filters: Option<&Value>) -> CommandResult<Self> {
+        let update = match update {
+            Value::Document(update) => update,
+            Value::Array(stages) => {
+                if array_filters.is_some() {
+                    return Err(CommandError::new(
+                        ErrorCode::FailedToParse,
+                        "arrayFilters may not be specified for pipeline-style updates",
+                    ));
+                }
+                return Ok(Self {
+                    kind: UpdateKind::Pipeline(parse_pipeline(&stages.0)?),
+                    array_filters: HashMap::new(),
+                });
+            }
+            _ => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    "Update argument must be either an object or an array",
+                ));
+            }
+        };
+
+        let kind = match update.first() {
+            Some((key, _)) if key.starts_with('$') => {
+                UpdateKind::Modifiers(parse_modifiers(update)?)
+            }
+            _ => {
+                if let Some(key) = update.keys().find(|k| k.starts_with('$')) {
+                    return Err(CommandError::new(
+                        ErrorCode::DollarPrefixedFieldName,
+                        format!(
+                            "The dollar ($) prefixed field '{}' in '{}' is not allowed in the context of an update's replacement document. Consider using an aggregation pipeline with $replaceWith.",
+                            key, key
+                        ),
+                    ));
+                }
+                UpdateKind::Replacement(update.clone())
+            }
+        };
+
+        let array_filters = parse_array_filters(array_filters)?;
+        let used: Vec<&str> = match &kind {
+            UpdateKind::Modifiers(modifiers) => modifiers
+                .iter()
+                .flat_map(|m| m.path.segments())
+                .filter_map(|s| match s {
+                    Segment::Filtered(identifier) => Some(identifier.as_str()),
+                    _ => None,
+                })
+                .collect(),
+            UpdateKind::Replacement(_) | UpdateKind::Pipeline(_) => Vec::new(),
+        };
+        if let UpdateKind::Modifiers(modifiers) = &kind {
+            for modifier in modifiers {
+                for segment in modifier.path.segments() {
+                    if let Segment::Filtered(identifier) = segment {
+                        if !array_filters.contains_key(identifier) {
+                            return Err(CommandError::new(
+                                ErrorCode::BadValue,
+                                format!(
+                                    "No array filter found for identifier '{}' in path '{}'",
+                                    identifier, modifier.path
+                                ),
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        if let Some(unused) = array_filters.keys().find(|id| !used.contains(&id.as_str())) {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                format!(
+                    "The array filter for identifier '{}' was not used in the update {}",
+                    unused, update
+                ),
+            ));
+        }
+
+        Ok(Self {
+            kind,
+            array_filters,
+        })
+    }
+
+    pub fn is_replacement(&self) -> bool {
+        matches!(self.kind, UpdateKind::Replacement(_))
+    }
+
+    /// Applies the update to `doc` in place. `position` is the array index
+    /// matched by the query, for the positional `$` operator. Returns whether
+    /// the document changed.
+    pub fn apply(
+        &self,
+        doc: &mut Document,
+        position: Option<usize>,
+        is_insert: bool,
+    ) -> CommandResult<bool> {
+        let original_id = doc.get("_id").cloned();
+        let mut updated = match &self.kind {
+            UpdateKind::Replacement(replacement) => {
+                let mut updated = Document::new();
+                match (&original_id, replacement.get("_id")) {
+                    (Some(id), Some(new_id)) if id != new_id => {
+                        return Err(CommandError::new(
+                            ErrorCode::ImmutableField,
+                            format!(
+                                "After applying the update, the (immutable) field '_id' was found to have been altered to _id: {}",
+                                new_id
+                            ),
+                        ));
+                    }
+                    (Some(id), _) => {
+                        updated.insert("_id", id.clone());
+                    }
+                    (None, _) => {}
+                }
+                for (key, value) in replacement.iter() {
+                    updated.insert(key.clone(), value.clone());
+                }
+                updated
+            }
+            UpdateKind::Modifiers(modifiers) => {
+                let mut updated = doc.clone();
+                self.apply_modifiers(modifiers, &mut updated, position, is_insert)?;
+                if let Some(id) = &original_id {
+                    if updated.get("_id") != Some(id) {
+                        return Err(CommandError::new(
+                            ErrorCode::ImmutableField,
+                            "Performing an update on the path '_id' would modify the immutable field '_id'",
+                        ));
+                    }
+                }
+                updated
+            }
+            UpdateKind::Pipeline(pipeline) => {
+                // Every allowed stage outputs exactly one document per input
+                let mut updated = pipeline
+                    .run(vec![doc.clone()], &Context::default())?
+                    .pop()
+                    .unwrap_or_default();
+                match (&original_id, updated.get("_id")) {
+                    (Some(id), None) => {
+                        updated.insert("_id", id.clone());
+                    }
+                    (Some(id), Some(new_id)) if id != new_id => {
+                        return Err(CommandError::new(
+                            ErrorCode::ImmutableField,
+                            format!(
+                                "After applying the update, the (immutable) field '_id' was found to have been altered to _id: {}",
+                                new_id
+                            ),
+                        ));
+                    }
+                    _ => {}
+                }
+                updated
+            }
+        };
+
+        if let Some(id) = updated.remove("_id") {
+            updated.0.insert(0, ("_id".to_string(), id));
+        }
+        let modified = updated != *doc;
+        *doc = updated;
+        Ok(modified)
+    }
+
+    fn apply_modifiers(
+        &self,
+        modifiers: &[Modifier],
+        doc: &mut Document,
+        position: Option<usize>,
+        is_insert: bool,
+    ) -> CommandResult<()> {
+        let id = match doc.get("_id") {
+            Some(id) => format!("{{_id: {}}}", id),
+            None => "{}".to_string(),
+        };
+
+        // Like mongod, apply updates in field name order rather than in the
+        // order operators appear in the update document.
+        let mut ordered: Vec<&Modifier> = modifiers.iter().collect();
+        ordered.sort_by_key(|m| m.path.literal_segments());
+
+        let mut updated_paths: Vec<Vec<String>> = Vec::new();
+        for modifier in ordered {
+            if matches!(modifier.operator, Operator::SetOnInsert(_)) && !is_insert {
+                continue;
+            }
+            for path in modifier.path.resolve(doc, position, &self.array_filters)? {
+                let conflict = updated_paths
+                    .iter()
+                    .any(|updated| updated.iter().zip(path.iter()).all(|(a, b)| a == b));
+                if conflict {
+                    return Err(CommandError::new(
+                        ErrorCode::ConflictingUpdateOperators,
+                        format!("Update created a conflict at '{}'", path.join(".")),
+                    ));
+                }
+                modifier.apply(doc, &path, &id)?;
+                updated_paths.push(path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the document inserted by an upsert that matched nothing: the
+    /// equality predicates of the query, with the update applied on top.
+    pub fn upsert(&self, query: &Document) -> CommandResult<Document> {
+        let mut doc = Document::new();
+        if let UpdateKind::Modifiers(_) | UpdateKind::Pipeline(_) = self.kind {
+            extract_equalities(query, &mut doc)?;
+        } else if let Some(id) = query.get("_id").filter(|id| !is_operator_value(id)) {
+            doc.insert("_id", id.clone());
+        }
+
+        self.apply(&mut doc, None, true)?;
+        if !doc.contains_key("_id") {
+            doc.0.insert(0, ("_id".to_string(), Value::new_object_id()));
+        }
+        Ok(doc)
+    }
+}
+
+fn is_operator_value(value: &Value) -> bool {
+    match value {
+        Value::Document(doc) => doc.first().is_some_and(|(k, _)| k.starts_with('$')),
+        _ => false,
+    }
+}
+
+fn extract_equalities(query: &Document, doc: &mut Document) -> CommandResult<()> {
+    for (key, value) in query.iter() {
+        if key == "$and" {
+            for clause in value.as_array().into_iter().flatten() {
+                if let Value::Document(clause) = clause {
+                    extract_equalities(clause, doc)?;
+                }
+            }
+            continue;
+        }
+        if key.starts_with('$') {
+            continue;
+        }
+        let value = match value {
+            Value::Document(ops) if is_operator_value(value) => match ops.get("$eq") {
+                Some(value) => value,
+                None => continue,
+            },
+            value => value,
+        };
+        let path: Vec<String> = key.split('.').map(String::from).collect();
+        set_path(doc, &path, value.clone())?;
+    }
+    Ok(())
+}
+
+/// Parses an update pipeline, which may only use the stages reshaping each
+/// document.
+pub fn parse_pipeline(stages: &[Value]) -> CommandResult<Pipeline> {
+    for stage in stages {
+        if let Some(name) = stage_name(stage) {
+            if !PIPELINE_STAGES.contains(&name) {
+                return Err(CommandError::new(
+                    ErrorCode::InvalidOptions,
+                    format!("{} is not allowed to be used within an update", name),
+                ));
+            }
+        }
+    }
+    Pipeline::parse(stages)
+}
+
+fn stage_name(stage: &Value) -> Option<&str> {
+    match stage {
+        Value::Document(stage) => stage.first().map(|(name, _)| name.as_str()),
+        _ => None,
+    }
+}
+
+fn parse_modifiers(update: &Document) -> CommandResult<Vec<Modifier>> {
+    let mut modifiers: Vec<Modifier> = Vec::new();
+    for (name, fields) in update.iter() {
+        let Value::Document(fields) = fields else {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                format!(
+                    "Modifiers operate on fields but we found type {} instead. For example: {{$mod: {{<field>: ...}}}} not {{{}: {}}}",
+                    fields.type_name(),
+                    name,
+                    fields
+                ),
+            ));
+        };
+        if fields.is_empty() {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                format!(
+                    "'{}' is empty. You must specify a field like so: {{{}: {{<field>: ...}}}}",
+                    name, name
+                ),
+            ));
+        }
+
+        for (path, arg) in fields.iter() {
+            let modifier = Modifier::parse(name, path, arg)?;
+
+            let mut paths = vec![&modifier.path];
+            if let Operator::Rename(to) = &modifier.operator {
+                paths.push(to);
+            }
+            for path in paths {
+                for existing in &modifiers {
+                    let mut existing_paths = vec![&existing.path];
+                    if let Operator::Rename(to) = &existing.operator {
+                        existing_paths.push(to);
+                    }
+                    for existing_path in existing_paths {
+                        if let Some(prefix) = path.conflict(existing_path) {
+                            return Err(CommandError::new(
+                                ErrorCode::ConflictingUpdateOperators,
+                                format!(
+                                    "Updating the path '{}' would create a conflict at '{}'",
+                                    path, prefix
+                                ),
+                            ));
+                        }
+                    }
+                }
+            }
+            modifiers.push(modifier);
+        }
+    }
+    Ok(modifiers)
+}
+
+fn parse_array_filters(array_filters: Option<&Value>) -> CommandResult<HashMap<String, Matcher>> {
+    let mut filters = HashMap::new();
+    let Some(array_filters) = array_filters else {
+        return Ok(filters);
+    };
+    let Some(array_filters) = array_filters.as_array() else {
+        return Err(CommandError::new(
+            ErrorCode::TypeMismatch,
+            format!(
+                "BSON field 'arrayFilters' is the wrong type '{}', expected type 'array'",
+                array_filters.type_name()
+            ),
+        ));
+    };
+
+    for filter in array_filters {
+        let Value::Document(filter) = filter else {
+            return Err(CommandError::new(
+                ErrorCode::TypeMismatch,
+                format!(
+                    "Each array filter must be an object, found {}",
+                    filter.type_name()
+                ),
+            ));
+        };
+
+        let mut identifier: Option<&str> = None;
+        for key in filter.keys() {
+            if key.starts_with('$') {
+                continue;
+            }
+            let name = key.split('.').next().unwrap_or_default();
+            match identifier {
+                Some(existing) if existing != name => {
+                    return Err(CommandError::new(
+                        ErrorCode::FailedToParse,
+                        format!(
+                            "Error parsing array filter :: caused by :: Expected a single top-level field name, found '{}' and '{}'",
+                            existing, name
+                        ),
+                    ));
+                }
+                _ => identifier = Some(name),
+            }
+        }
+        let Some(identifier) = identifier else {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                "Error parsing array filter :: caused by :: Cannot use an expression without a top-level field name in arrayFilters",
+            ));
+        };
+        let valid = identifier.starts_with(|c: char| c.is_ascii_lowercase())
+            && identifier.chars().all(|c| c.is_ascii_alphanumeric());
+        if !valid {
+            return Err(CommandError::new(
+                ErrorCode::BadValue,
+                format!(
+                    "Error parsing array filter :: caused by :: The top-level field name must be an alphanumeric string beginning with a lowercase letter, found '{}'",
+                    identifier
+                ),
+            ));
+        }
+        if filters.contains_key(identifier) {
+            return Err(CommandError::new(
+                ErrorCode::FailedToParse,
+                format!(
+                    "Found multiple array filters with the same top-level field name {}",
+                    identifier
+                ),
+            ));
+        }
+        filters.insert(identifier.to_string(), Matcher::new(filter)?);
+    }
+    Ok(filters)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bson::json::{doc, value};
+
+    fn update(original: &str, update: &str) -> CommandResult<Document> {
+        let mut document = doc(original);
+        Update::parse(&value(update), None)?.apply(&mut document, None, false)?;
+        Ok(document)
+    }
+
+    fn error_code(original: &str, spec: &str) -> ErrorCode {
+        update(original, spec).unwrap_err().code
+    }
+
+    #[test]
+    fn test_field_operators() {
+        assert_eq!(
+            update("{ _id: 1, a: 1 }", "{ $set: { 'b.c': 2 }, $inc: { a: 2 } }").unwrap(),
+            doc("{ _id: 1, a: 3, b: { c: 2 } }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, a: 1, b: 2 }",
+                "{ $unset: { a: '' }, $mul: { b: 2.5 } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, b: 5.0 }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, lo: 5, hi: 5 }",
+                "{ $min: { lo: 3 }, $max: { hi: 3 } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, lo: 3, hi: 5 }")
+        );
+        assert_eq!(
+            update("{ _id: 1, a: { b: 1 } }", "{ $rename: { 'a.b': 'c' } }").unwrap(),
+            doc("{ _id: 1, a: {}, c: 1 }")
+        );
+        assert_eq!(
+            update("{ _id: 1, a: 2147483647 }", "{ $inc: { a: 1 } }").unwrap(),
+            doc("{ _id: 1, a: Long(2147483648) }")
+        );
+        assert_eq!(
+            update("{ _id: 1, a: 13 }", "{ $bit: { a: { and: 10 } } }").unwrap(),
+            doc("{ _id: 1, a: 8 }")
+        );
+    }
+
+    #[test]
+    fn test_new_fields_are_created_in_field_order() {
+        assert_eq!(
+            update("{ _id: 1 }", "{ $set: { b: 1 }, $inc: { a: 1 } }").unwrap(),
+            doc("{ _id: 1, a: 1, b: 1 }")
+        );
+    }
+
+    #[test]
+    fn test_array_operators() {
+        assert_eq!(
+            update(
+                "{ _id: 1, a: [5, 1] }",
+                "{ $push: { a: { $each: [4, 2], $sort: -1, $slice: 3 } } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, a: [5, 4, 2] }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, a: [1, 2] }",
+                "{ $push: { a: { $each: [9], $position: -1 } } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, a: [1, 9, 2] }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, a: [{ s: 2 }, { s: 1 }] }",
+                "{ $push: { a: { $each: [{ s: 3 }], $sort: { s: 1 } } } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, a: [{ s: 1 }, { s: 2 }, { s: 3 }] }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, a: [1, 2] }",
+                "{ $addToSet: { a: { $each: [2.0, 3] } } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, a: [1, 2, 3] }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, a: [1, 2, 3], b: [1, 2] }",
+                "{ $pop: { a: -1, b: 1 } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, a: [2, 3], b: [1] }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, a: [1, 5, 8], b: [{ x: 1 }, { x: 2 }], c: [1, 2, 1] }",
+                "{ $pull: { a: { $gte: 5 }, b: { x: 2 } }, $pullAll: { c: [1] } }"
+            )
+            .unwrap(),
+            doc("{ _id: 1, a: [1], b: [{ x: 1 }], c: [2] }")
+        );
+    }
+
+    #[test]
+    fn test_positional_operators() {
+        let query = Matcher::new(&doc("{ grades: 85 }")).unwrap();
+        let mut document = doc("{ _id: 1, grades: [80, 85, 90] }");
+        let position = query.match_position(&document).unwrap();
+        Update::parse(&value("{ $set: { 'grades.$': 82 } }"), None)
+            .unwrap()
+            .apply(&mut document, position, false)
+            .unwrap();
+        assert_eq!(document, doc("{ _id: 1, grades: [80, 82, 90] }"));
+
+        assert_eq!(
+            update("{ _id: 1, a: [1, 2] }", "{ $inc: { 'a.$[]': 10 } }").unwrap(),
+            doc("{ _id: 1, a: [11, 12] }")
+        );
+
+        let mut document = doc("{ _id: 1, a: [{ g: 80 }, { g: 95 }] }");
+        Update::parse(
+            &value("{ $set: { 'a.$[e].top': true } }"),
+            Some(&value("[{ 'e.g': { $gte: 90 } }]")),
+        )
+        .unwrap()
+        .apply(&mut document, None, false)
+        .unwrap();
+        assert_eq!(
+            document,
+            doc("{ _id: 1, a: [{ g: 80 }, { g: 95, top: true }] }")
+        );
+    }
+
+    #[test]
+    fn test_replacement_keeps_id() {
+        assert_eq!(
+            update("{ _id: 1, a: 1 }", "{ b: 2 }").unwrap(),
+            doc("{ _id: 1, b: 2 }")
+        );
+        assert_eq!(
+            error_code("{ _id: 1 }", "{ _id: 2 }"),
+            ErrorCode::ImmutableField
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            update("{ _id: 1 }", "{ $set: { 'a.b': 1 }, $inc: { a: 1 } }")
+                .unwrap_err()
+                .message,
+            "Updating the path 'a' would create a conflict at 'a'"
+        );
+        assert_eq!(
+            error_code("{ _id: 1 }", "{ $set: {} }"),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(
+            error_code("{ _id: 1 }", "{ $foo: { a: 1 } }"),
+            ErrorCode::FailedToParse
+        );
+        assert_eq!(
+            error_code("{ _id: 1, a: 'x' }", "{ $inc: { a: 1 } }"),
+            ErrorCode::TypeMismatch
+        );
+        assert_eq!(
+            error_code("{ _id: 1, a: 1 }", "{ $set: { 'a.b': 1 } }"),
+            ErrorCode::PathNotViable
+        );
+        assert_eq!(
+            error_code("{ _id: 1, a: [] }", "{ $set: { 'a.4000000000': 1 } }"),
+            ErrorCode::BadValue
+        );
+        assert_eq!(
+            error_code("{ _id: 1, a: [] }", "{ $set: { 'a.4000000000.b': 1 } }"),
+            ErrorCode::BadValue
+        );
+        assert_eq!(
+            update("{ _id: 1, a: [] }", "{ $set: { 'a.2': 1 } }").unwrap(),
+            doc("{ _id: 1, a: [null, null, 1] }")
+        );
+        assert_eq!(
+            error_code("{ _id: 1, a: 1 }", "{ $push: { a: 1 } }"),
+            ErrorCode::BadValue
+        );
+        assert_eq!(
+            error_code("{ _id: 1 }", "{ $set: { _id: 2 } }"),
+            ErrorCode::ImmutableField
+        );
+        assert_eq!(
+            error_code("{ _id: 1, a: [1] }", "{ $set: { 'a.$': 2 } }"),
+            ErrorCode::BadValue
+        );
+        assert_eq!(
+            error_code("{ _id: 1, a: [1] }", "{ $set: { 'a.$[x]': 2 } }"),
+            ErrorCode::BadValue
+        );
+    }
+
+    #[test]
+    fn test_upsert() {
+        let update =
+            Update::parse(&value("{ $set: { b: 2 }, $setOnInsert: { c: 3 } }"), None).unwrap();
+        let inserted = update
+            .upsert(&doc("{ _id: 7, a: 1, x: { $gt: 1 } }"))
+            .unwrap();
+        assert_eq!(inserted, doc("{ _id: 7, a: 1, b: 2, c: 3 }"));
+
+        let mut existing = doc("{ _id: 7, a: 1 }");
+        update.apply(&mut existing, None, false).unwrap();
+        assert_eq!(existing, doc("{ _id: 7, a: 1, b: 2 }"));
+    }
+
+    #[test]
+    fn test_pipeline() {
+        assert_eq!(
+            update(
+                "{ _id: 1, a: 1, b: { c: 2 } }",
+                "[{ $set: { total: { $add: ['$a', '$b.c'] }, 'b.d': 3 } }, { $unset: 'a' }]"
+            )
+            .unwrap(),
+            doc("{ _id: 1, b: { c: 2, d: 3 }, total: 3 }")
+        );
+        assert_eq!(
+            update(
+                "{ _id: 1, a: { b: 1 } }",
+                "[{ $replaceWith: { $mergeObjects: ['$a', { c: 2 }] } }]"
+            )
+            .unwrap(),
+            doc("{ _id: 1, b: 1, c: 2 }")
+        );
+        assert_eq!(
+            error_code("{ _id: 1 }", "[{ $set: { _id: 2 } }]"),
+            ErrorCode::ImmutableField
+        );
+        assert_eq!(
+            error_code("{ _id: 1 }", "[{ $match: { a: 1 } }]"),
+            ErrorCode::InvalidOptions
+        );
+        assert_eq!(
+            Update::parse(
+                &value("[{ $set: { a: 1 } }]"),
+                Some(&value("[{ 'x.a': 1 }]"))
+            )
+            .unwrap_err()
+            .code,
+            ErrorCode::FailedToParse
+        );
+
+        let upsert = Update::parse(&value("[{ $set: { b: { $add: ['$a', 1] } } }]"), None).unwrap();
+        let inserted = upsert.upsert(&doc("{ _id: 3, a: 1 }")).unwrap();
+        assert_eq!(inserted, doc("{ _id: 3, a: 1, b: 2 }"));
+    }
+}
@@ -0,0 +1,675 @@
+use std::cmp::Ordering;
+
+use crate::{
+    bson::{Array, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::Matcher,
+};
+
+use super::path::{array_along_path, get_path, set_path, unset_path, FieldPath};
+
+#[derive(Debug, Clone)]
+pub enum Operator {
+    Set(Value),
+    SetOnInsert(Value),
+    Unset,
+    Inc(Value),
+    Mul(Value),
+    Min(Value),
+    Max(Value),
+    Rename(FieldPath),
+    CurrentDate(DateType),
+    Push(Push),
+    AddToSet(Vec<Value>),
+    Pop(PopFrom),
+    Pull(PullCondition),
+    PullAll(Vec<Value>),
+    Bit(Vec<(BitOp, Value)>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DateType {
+    Date,
+    Timestamp,
+}
+
+#[derive(Debug, Clone)]
+pub struct Push {
+    each: Vec<Value>,
+    slice: Option<i64>,
+    sort: Option<SortSpec>,
+    position: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+enum SortSpec {
+    Value(bool),
+    Fields(Vec<(Vec<String>, bool)>),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PopFrom {
+    First,
+    Last,
+}
+
+#[derive(Debug, Clone)]
+pub enum PullCondition {
+    Equals(Value),
+    /// An operator document such as `{ $gte: 6 }`, applied to each element.
+    Predicate(Matcher),
+    /// A query applied to each embedded document element.
+    Query(Matcher),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum BitOp {
+    And,
+    Or,
+    Xor,
+}
+
+/// A single field update, e.g. `{ $inc: { a: 1 } }` becomes an `Inc` on `a`.
+#[derive(Debug, Clone)]
+pub struct Modifier {
+    pub name: &'static str,
+    pub path: FieldPath,
+    pub operator: Operator,
+}
+
+fn bad_value(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::BadValue, message)
+}
+
+fn type_mismatch(message: impl Into<String>) -> CommandError {
+    CommandError::new(ErrorCode::TypeMismatch, message)
+}
+
+impl Modifier {
+    pub fn parse(name: &str, path: &str, arg: &Value) -> CommandResult<Self> {
+        let (name, operator) = match name {
+            "$set" => ("$set", Operator::Set(arg.clone())),
+            "$setOnInsert" => ("$setOnInsert", Operator::SetOnInsert(arg.clone())),
+            "$unset" => ("$unset", Operator::Unset),
+            "$inc" | "$mul" => {
+                if !arg.is_number() {
+                    let verb = if name == "$inc" {
+                        "increment"
+                    } else {
+                        "multiply"
+                    };
+                    return Err(type_mismatch(format!(
+                        "Cannot {} with non-numeric argument: {{{}: {}}}",
+                        verb, path, arg
+                    )));
+                }
+                match name {
+                    "$inc" => ("$inc", Operator::Inc(arg.clone())),
+                    _ => ("$mul", Operator::Mul(arg.clone())),
+                }
+            }
+            "$min" => ("$min", Operator::Min(arg.clone())),
+            "$max" => ("$max", Operator::Max(arg.clone())),
+            "$rename" => ("$rename", Operator::Rename(parse_rename(path, arg)?)),
+            "$currentDate" => (
+                "$currentDate",
+                Operator::CurrentDate(parse_date_type(path, arg)?),
+            ),
+            "$push" => ("$push", Operator::Push(parse_push(arg)?)),
+            "$addToSet" => ("$addToSet", Operator::AddToSet(parse_add_to_set(arg)?)),
+            "$pop" => {
+                let from = match arg.as_f64() {
+                    Some(1.0) => PopFrom::Last,
+                    Some(-1.0) => PopFrom::First,
+                    _ => {
+                        return Err(CommandError::new(
+                            ErrorCode::FailedToParse,
+                            format!("$pop expects 1 or -1, found: {}", arg),
+                        ));
+                    }
+                };
+                ("$pop", Operator::Pop(from))
+            }
+            "$pull" => ("$pull", Operator::Pull(parse_pull(arg)?)),
+            "$pullAll" => {
+                let Some(values) = arg.as_array() else {
+                    return Err(bad_value(format!(
+                        "$pullAll requires an array argument but was given a {}",
+                        arg.type_name()
+                    )));
+                };
+                ("$pullAll", Operator::PullAll(values.clone()))
+            }
+            "$bit" => ("$bit", Operator::Bit(parse_bit(arg)?)),
+            _ => {
+                return Err(CommandError::new(
+                    ErrorCode::FailedToParse,
+                    format!(
+                        "Unknown modifier: {}. Expected a valid update modifier or pipeline-style update specified as an array",
+                        name
+                    ),
+                ));
+            }
+        };
+
+        Ok(Self {
+            name,
+            path: FieldPath::parse(path)?,
+            operator,
+        })
+    }
+
+    /// Applies the modifier at a concrete path of `doc`. `id` describes the
+    /// document in error messages.
+    pub fn apply(&self, doc: &mut Document, path: &[String], id: &str) -> CommandResult<()> {
+        let field = path.join(".");
+        let current = get_path(doc, path);
+
+        let new_value = match &self.operator {
+            Operator::Set(value) | Operator::SetOnInsert(value) => value.clone(),
+            Operator::Unset => {
+                unset_path(doc, path)?;
+                return Ok(());
+            }
+            Operator::Inc(arg) | Operator::Mul(arg) => {
+                let is_inc = matches!(self.operator, Operator::Inc(_));
+                match current {
+                    None if is_inc => arg.clone(),
+                    None => zero_like(arg),
+                    Some(value) if value.is_number() => {
+                        let result = if is_inc {
+                            add(value, arg)
+                        } else {
+                            multiply(value, arg)
+                        };
+                        result.ok_or_else(|| {
+                            bad_value(format!(
+                                "Failed to apply {} operations to current value ({}) for document {}",
+                                self.name, value, id
+                            ))
+                        })?
+                    }
+                    Some(value) => {
+                        return Err(type_mismatch(format!(
+                            "Cannot apply {} to a value of non-numeric type. {} has the field '{}' of non-numeric type {}",
+                            self.name,
+                            id,
+                            path.last().expect("paths are never empty"),
+                            value.type_name()
+                        )));
+                    }
+                }
+            }
+            Operator::Min(arg) | Operator::Max(arg) => {
+                let wanted = match self.operator {
+                    Operator::Min(_) => Ordering::Less,
+                    _ => Ordering::Greater,
+                };
+                match current {
+                    Some(value) if arg.compare(value) != wanted => return Ok(()),
+                    _ => arg.clone(),
+                }
+            }
+            Operator::CurrentDate(DateType::Date) => Value::now(),
+            Operator::CurrentDate(DateType::Timestamp) => {
+                let Value::UtcDateTime(ms) = Value::now() else {
+                    unreachable!("now is a date");
+                };
+                Value::Timestamp(((ms / 1000) as u64) << 32 | 1)
+            }
+            Operator::Rename(to) => {
+                if let Some(field) = array_along_path(doc, path) {
+                    return Err(bad_value(format!(
+                        "The source field cannot be an array element, '{}' in doc with {} has an array field called '{}'",
+                        self.path, id, field
+                    )));
+                }
+                let to_path = to.literal_segments();
+                if let Some(field) = array_along_path(doc, &to_path) {
+                    return Err(bad_value(format!(
+                        "The destination field cannot be an array element, '{}' in doc with {} has an array field called '{}'",
+                        to, id, field
+                    )));
+                }
+                let Some(value) = unset_path(doc, path)? else {
+                    return Ok(());
+                };
+                return set_path(doc, &to_path, value);
+            }
+            Operator::Push(push) => {
+                let mut items = existing_array(current, &field, id)?;
+                push.apply(&mut items);
+                Value::Array(Array(items))
+            }
+            Operator::AddToSet(values) => {
+                let mut items = match current {
+                    None => Vec::new(),
+                    Some(Value::Array(items)) => items.0.clone(),
+                    Some(value) => {
+                        return Err(bad_value(format!(
+                            "Cannot apply $addToSet to non-array field. Field named '{}' has non-array type {}",
+                            path.last().expect("paths are never empty"),
+                            value.type_name()
+                        )));
+                    }
+                };
+                for value in values {
+                    if !items.iter().any(|item| item.equals(value)) {
+                        items.push(value.clone());
+                    }
+                }
+                Value::Array(Array(items))
+            }
+            Operator::Pop(from) => {
+                let mut items = match current {
+                    None => return Ok(()),
+                    Some(Value::Array(items)) if items.0.is_empty() => return Ok(()),
+                    Some(Value::Array(items)) => items.0.clone(),
+                    Some(value) => {
+                        return Err(type_mismatch(format!(
+                            "Path '{}' contains an element of non-array type '{}'",
+                            field,
+                            value.type_name()
+                        )));
+                    }
+                };
+                match from {
+                    PopFrom::First => items.remove(0),
+                    PopFrom::Last => items.pop().expect("array is not empty"),
+                };
+                Value::Array(Array(items))
+            }
+            Operator::Pull(_) | Operator::PullAll(_) => {
+                let items = match current {
+                    None => return Ok(()),
+                    Some(Value::Array(items)) => &items.0,
+                    Some(_) => return Err(bad_value("Cannot apply $pull to a non-array value")),
+                };
+                let items = items
+                    .iter()
+                    .filter(|item| !self.pulls(item))
+                    .cloned()
+                    .collect();
+                Value::Array(Array(items))
+            }
+            Operator::Bit(ops) => {
+                let mut value = match current {
+                    None => Value::Int32(0),
+                    Some(value @ (Value::Int32(_) | Value::Int64(_))) => value.clone(),
+                    Some(value) => {
+                        return Err(bad_value(format!(
+                            "Cannot apply $bit to a value of non-integral type.{} has the field {} of non-integer type {}",
+                            id,
+                            path.last().expect("paths are never empty"),
+                            value.type_name()
+                        )));
+                    }
+                };
+                for (op, arg) in ops {
+                    value = apply_bit(&value, *op, arg);
+                }
+                value
+            }
+        };
+
+        set_path(doc, path, new_value)
+    }
+
+    fn pulls(&self, item: &Value) -> bool {
+        match &self.operator {
+            Operator::PullAll(values) => values.iter().any(|value| value.equals(item)),
+            Operator::Pull(PullCondition::Equals(value)) => value.equals(item),
+            Operator::Pull(PullCondition::Predicate(matcher)) => {
+                let mut element = Document::new();
+                element.insert("", item.clone());
+                matcher.matches(&element)
+            }
+            Operator::Pull(PullCondition::Query(matcher)) => match item {
+                Value::Document(doc) => matcher.matches(doc),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+fn parse_rename(path: &str, arg: &Value) -> CommandResult<FieldPath> {
+    let Some(to) = arg.as_str() else {
+        return Err(bad_value(format!(
+            "The 'to' field for $rename must be a string: {}: {}",
+            path, arg
+        )));
+    };
+    let from = FieldPath::parse(path)?;
+    let to_path = FieldPath::parse(to)?;
+    if from.is_dynamic() {
+        return Err(bad_value(format!(
+            "The source field for $rename may not be dynamic: {}",
+            from
+        )));
+    }
+    if to_path.is_dynamic() {
+        return Err(bad_value(format!(
+            "The destination field for $rename may not be dynamic: {}",
+            to_path
+        )));
+    }
+    if from == to_path {
+        return Err(bad_value(format!(
+            "The source and target field for $rename must differ: {}: {}",
+            path, arg
+        )));
+    }
+    if from.conflict(&to_path).is_some() {
+        return Err(bad_value(format!(
+            "The source and target field for $rename must not be on the same path: {}: {}",
+            path, arg
+        )));
+    }
+    Ok(to_path)
+}
+
+fn parse_date_type(path: &str, arg: &Value) -> CommandResult<DateType> {
+    let invalid = || {
+        bad_value(format!(
+            "{{{}: {}}} is not valid type for $currentDate. Please use a boolean ('true') or a $type expression ({{$type: 'timestamp/date'}}).",
+            path, arg
+        ))
+    };
+    match arg {
+        Value::Boolean(_) => Ok(DateType::Date),
+        Value::Document(spec) => match spec.get("$type").and_then(Value::as_str) {
+            Some("date") if spec.len() == 1 => Ok(DateType::Date),
+            Some("timestamp") if spec.len() == 1 => Ok(DateType::Timestamp),
+            Some(_) if spec.len() == 1 => Err(bad_value(
+                "The '$type' string field is required to be 'date' or 'timestamp': {$currentDate: {field : {$type: 'date'}}}",
+            )),
+            _ => Err(invalid()),
+        },
+        _ => Err(invalid()),
+    }
+}
+
+fn is_each_document(arg: &Value) -> Option<&Document> {
+    match arg {
+        Value::Document(doc) if doc.contains_key("$each") => Some(doc),
+        _ => None,
+    }
+}
+
+fn parse_push(arg: &Value) -> CommandResult<Push> {
+    let Some(spec) = is_each_document(arg) else {
+        return Ok(Push {
+            each: vec![arg.clone()],
+            slice: None,
+            sort: None,
+            position: None,
+        });
+    };
+
+    let mut push = Push {
+        each: Vec::new(),
+        slice: None,
+        sort: None,
+        position: None,
+    };
+    for (key, value) in spec.iter() {
+        match key.as_str() {
+            "$each" => {
+                let Some(items) = value.as_array() else {
+                    return Err(bad_value(format!(
+                        "The argument to $each in $push must be an array but it was of type: {}",
+                        value.type_name()
+                    )));
+                };
+                push.each = items.clone();
+            }
+            "$slice" => match value.as_i64() {
+                Some(slice) => push.slice = Some(slice),
+                None => {
+                    return Err(bad_value(format!(
+                        "The value for $slice must be an integer value but was given type: {}",
+                        value.type_name()
+                    )));
+                }
+            },
+            "$position" => match value.as_i64() {
+                Some(position) => push.position = Some(position),
+                None => {
+                    return Err(bad_value(format!(
+                        "The value for $position must be an integer value, not of type: {}",
+                        value.type_name()
+                    )));
+                }
+            },
+            "$sort" => push.sort = Some(parse_sort(value)?),
+            key => return Err(bad_value(format!("Unrecognized clause in $push: {}", key))),
+        }
+    }
+    Ok(push)
+}
+
+fn parse_sort(value: &Value) -> CommandResult<SortSpec> {
+    let invalid = || {
+        bad_value(
+            "The $sort is invalid: use 1/-1 to sort the whole element, or {field:1/-1} to sort embedded fields",
+        )
+    };
+    let direction = |value: &Value| match value.as_f64() {
+        Some(1.0) => Ok(true),
+        Some(-1.0) => Ok(false),
+        _ => Err(invalid()),
+    };
+    match value {
+        Value::Document(spec) if !spec.is_empty() => {
+            let mut fields = Vec::new();
+            for (key, value) in spec.iter() {
+                if key.is_empty() || key.starts_with('$') || key.split('.').any(str::is_empty) {
+                    return Err(bad_value(
+                        "The $sort field is a dotted field but has an empty part",
+                    ));
+                }
+                fields.push((
+                    key.split('.').map(String::from).collect(),
+                    direction(value)?,
+                ));
+            }
+            Ok(SortSpec::Fields(fields))
+        }
+        Value::Document(_) => Err(invalid()),
+        value => Ok(SortSpec::Value(direction(value)?)),
+    }
+}
+
+fn parse_add_to_set(arg: &Value) -> CommandResult<Vec<Value>> {
+    let Some(spec) = is_each_document(arg) else {
+        return Ok(vec![arg.clone()]);
+    };
+    if spec.len() > 1 {
+        return Err(bad_value(format!(
+            "Found unexpected fields after $each in $addToSet: {}",
+            arg
+        )));
+    }
+    match spec.get("$each").and_then(Value::as_array) {
+        Some(items) => Ok(items.clone()),
+        None => Err(bad_value(format!(
+            "The argument to $each in $addToSet must be an array but it was of type {}",
+            spec.get("$each").map(Value::type_name).unwrap_or_default()
+        ))),
+    }
+}
+
+fn parse_pull(arg: &Value) -> CommandResult<PullCondition> {
+    let Value::Document(doc) = arg else {
+        return Ok(PullCondition::Equals(arg.clone()));
+    };
+    let is_operator = doc
+        .first()
+        .is_some_and(|(k, _)| k.starts_with('$') && !matches!(k.as_str(), "$and" | "$or" | "$nor"));
+    if is_operator {
+        let mut filter = Document::new();
+        filter.insert("", arg.clone());
+        Ok(PullCondition::Predicate(Matcher::new(&filter)?))
+    } else {
+        Ok(PullCondition::Query(Matcher::new(doc)?))
+    }
+}
+
+fn parse_bit(arg: &Value) -> CommandResult<Vec<(BitOp, Value)>> {
+    let Value::Document(spec) = arg else {
+        return Err(bad_value(format!(
+            "The $bit modifier is not compatible with a {}. You must pass in an embedded document: {{$bit: {{field: {{and/or/xor: #}}}}",
+            arg.type_name()
+        )));
+    };
+    let mut ops = Vec::new();
+    for (key, value) in spec.iter() {
+        let op = match key.as_str() {
+            "and" => BitOp::And,
+            "or" => BitOp::Or,
+            "xor" => BitOp::Xor,
+            _ => {
+                return Err(bad_value(format!(
+                    "The $bit modifier only supports 'and', 'or', and 'xor', not '{}' which is an unknown operator: {{{}: {}}}",
+                    key, key, value
+                )));
+            }
+        };
+        if !matches!(value, Value::Int32(_) | Value::Int64(_)) {
+            return Err(bad_value(format!(
+                "The $bit modifier field must be an Integer(32/64 bit); a '{}' is not supported here: {{{}: {}}}",
+                value.type_name(),
+                key,
+                value
+            )));
+        }
+        ops.push((op, value.clone()));
+    }
+    if ops.is_empty() {
+        return Err(bad_value(
+            "You must pass in at least one bitwise operation. The format is: {$bit: {field: {and/or/xor: #}}",
+        ));
+    }
+    Ok(ops)
+}
+
+impl Push {
+    fn apply(&self, items: &mut Vec<Value>) {
+        let len = items.len() as i64;
+        let position = match self.position {
+            None => len,
+            Some(p) if p < 0 => (len + p).max(0),
+            Some(p) => p.min(len),
+        } as usize;
+        items.splice(position..position, self.each.iter().cloned());
+
+        match &self.sort {
+            Some(SortSpec::Value(ascending)) => items.sort_by(|a, b| {
+                let ordering = a.compare(b);
+                if *ascending {
+                    ordering
+                } else {
+                    ordering.reverse()
+                }
+            }),
+            Some(SortSpec::Fields(fields)) => items.sort_by(|a, b| compare_by_fields(a, b, fields)),
+            None => {}
+        }
+
+        match self.slice {
+            Some(slice) if slice >= 0 => items.truncate(slice as usize),
+            Some(slice) => {
+                let keep = slice.unsigned_abs() as usize;
+                if items.len() > keep {
+                    items.drain(..items.len() - keep);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+fn compare_by_fields(a: &Value, b: &Value, fields: &[(Vec<String>, bool)]) -> Ordering {
+    let field = |value: &Value, path: &[String]| match value {
+        Value::Document(doc) => get_path(doc, path).cloned().unwrap_or(Value::Null),
+        _ => Value::Null,
+    };
+    for (path, ascending) in fields {
+        let ordering = field(a, path).compare(&field(b, path));
+        let ordering = if *ascending {
+            ordering
+        } else {
+            ordering.reverse()
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+fn existing_array(current: Option<&Value>, field: &str, id: &str) -> CommandResult<Vec<Value>> {
+    match current {
+        None => Ok(Vec::new()),
+        Some(Value::Array(items)) => Ok(items.0.clone()),
+        Some(value) => Err(bad_value(format!(
+            "The field '{}' must be an array but is of type {} in document {}",
+            field,
+            value.type_name(),
+            id
+        ))),
+    }
+}
+
+fn zero_like(value: &Value) -> Value {
+    match value {
+        Value::Int32(_) => Value::Int32(0),
+        Value::Int64(_) => Value::Int64(0),
+        _ => Value::Double(0.0),
+    }
+}
+
+/// Integer arithmetic widens from int to long on overflow; long overflow is
+/// an error, as in mongod.
+fn add(a: &Value, b: &Value) -> Option<Value> {
+    arithmetic(a, b, i32::checked_add, i64::checked_add, |x, y| x + y)
+}
+
+fn multiply(a: &Value, b: &Value) -> Option<Value> {
+    arithmetic(a, b, i32::checked_mul, i64::checked_mul, |x, y| x * y)
+}
+
+fn arithmetic(
+    a: &Value,
+    b: &Value,
+    int: fn(i32, i32) -> Option<i32>,
+    long: fn(i64, i64) -> Option<i64>,
+    double: fn(f64, f64) -> f64,
+) -> Option<Value> {
+    match (a, b) {
+        (Value::Int32(x), Value::Int32(y)) => Some(match int(*x, *y) {
+            Some(v) => Value::Int32(v),
+            None => Value::Int64(long(*x as i64, *y as i64)?),
+        }),
+        (Value::Int32(_) | Value::Int64(_), Value::Int32(_) | Value::Int64(_)) => {
+            Some(Value::Int64(long(a.as_i64()?, b.as_i64()?)?))
+        }
+        _ => Some(Value::Double(double(a.as_f64()?, b.as_f64()?))),
+    }
+}
+
+fn apply_bit(value: &Value, op: BitOp, arg: &Value) -> Value {
+    let apply = |x: i64, y: i64| match op {
+        BitOp::And => x & y,
+        BitOp::Or => x | y,
+        BitOp::Xor => x ^ y,
+    };
+    match (value, arg) {
+        (Value::Int32(x), Value::Int32(y)) => Value::Int32(apply(*x as i64, *y as i64) as i32),
+        _ => Value::Int64(apply(
+            value.as_i64().unwrap_or_default(),
+            arg.as_i64().unwrap_or_default(),
+        )),
+    }
+}
@@ -0,0 +1,384 @@
+use std::{collections::HashMap, fmt};
+
+use crate::{
+    bson::{Array, Document, Value},
+    error::{CommandError, CommandResult, ErrorCode},
+    query::Matcher,
+};
+
+/// A dotted update path such as `a.$[elem].b`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldPath {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Field(String),
+    /// `$`: the array element matched by the query.
+    Positional,
+    /// `$[]`: every element of the array.
+    AllElements,
+    /// `$[<identifier>]`: the elements matching an array filter.
+    Filtered(String),
+}
+
+impl FieldPath {
+    pub fn parse(raw: &str) -> CommandResult<Self> {
+        if raw.is_empty() {
+            return Err(CommandError::new(
+                ErrorCode::EmptyFieldName,
+                "An empty update path is not valid.",
+            ));
+        }
+
+        let mut segments = Vec::new();
+        for part in raw.split('.') {
+            let segment = match part {
+                "" => {
+                    return Err(CommandError::new(
+                        ErrorCode::EmptyFieldName,
+                        format!(
+                            "The update path '{}' contains an empty field name, which is not allowed.",
+                            raw
+                        ),
+                    ));
+                }
+                "$" => Segment::Positional,
+                "$[]" => Segment::AllElements,
+                part if part.starts_with("$[") && part.ends_with(']') => {
+                    Segment::Filtered(part[2..part.len() - 1].to_string())
+                }
+                part if part.starts_with('$') => {
+                    return Err(CommandError::new(
+                        ErrorCode::DollarPrefixedFieldName,
+                        format!(
+                            "The dollar ($) prefixed field '{}' in '{}' is not valid for storage.",
+                            part, raw
+                        ),
+                    ));
+                }
+                part => Segment::Field(part.to_string()),
+            };
+            segments.push(segment);
+        }
+
+        match segments[0] {
+            Segment::Field(_) => {}
+            Segment::Positional => {
+                return Err(CommandError::new(
+                    ErrorCode::BadValue,
+                    format!(
+                        "Cannot have positional (i.e. '$') element in the first position in path '{}'",
+                        raw
+                    ),
+                ));
+            }
+            _ => {
+                return Err(CommandError::new(
+                    ErrorCode::BadValue,
+                    format!(
+                        "Cannot have array filter identifier (i.e. '$[<id>]') element in the first position in path '{}'",
+                        raw
+                    ),
+                ));
+            }
+        }
+        if segments
+            .iter()
+            .filter(|s| **s == Segment::Positional)
+            .count()
+            > 1
+        {
+            return Err(CommandError::new(
+                ErrorCode::BadValue,
+                format!(
+                    "Too many positional (i.e. '$') elements found in path '{}'",
+                    raw
+                ),
+            ));
+        }
+
+        Ok(Self {
+            raw: raw.to_string(),
+            segments,
+        })
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn is_dynamic(&self) -> bool {
+        self.segments
+            .iter()
+            .any(|s| !matches!(s, Segment::Field(_)))
+    }
+
+    /// Returns the shorter path if one of the paths is a prefix of the other,
+    /// which means they cannot both be updated.
+    pub fn conflict<'a>(&'a self, other: &'a FieldPath) -> Option<&'a FieldPath> {
+        let shorter = if self.segments.len() <= other.segments.len() {
+            self
+        } else {
+            other
+        };
+        self.segments
+            .iter()
+            .zip(other.segments.iter())
+            .all(|(a, b)| a == b)
+            .then_some(shorter)
+    }
+
+    pub fn literal_segments(&self) -> Vec<String> {
+        self.raw.split('.').map(String::from).collect()
+    }
+
+    /// Expands positional segments against `doc`, returning the concrete
+    /// paths the update applies to.
+    pub fn resolve(
+        &self,
+        doc: &Document,
+        position: Option<usize>,
+        array_filters: &HashMap<String, Matcher>,
+    ) -> CommandResult<Vec<Vec<String>>> {
+        // The first segment is always a plain field name (see `parse`).
+        let Segment::Field(name) = &self.segments[0] else {
+            unreachable!("paths start with a field name");
+        };
+        let mut paths = Vec::new();
+        self.resolve_from(
+            doc.get(name),
+            1,
+            &mut vec![name.clone()],
+            position,
+            array_filters,
+            &mut paths,
+        )?;
+        Ok(paths)
+    }
+
+    fn resolve_from(
+        &self,
+        current: Option<&Value>,
+        index: usize,
+        prefix: &mut Vec<String>,
+        position: Option<usize>,
+        array_filters: &HashMap<String, Matcher>,
+        paths: &mut Vec<Vec<String>>,
+    ) -> CommandResult<()> {
+        let Some(segment) = self.segments.get(index) else {
+            paths.push(prefix.clone());
+            return Ok(());
+        };
+
+        let mut descend = |key: String, prefix: &mut Vec<String>| {
+            let child = current.and_then(|value| child(value, &key));
+            prefix.push(key);
+            let result =
+                self.resolve_from(child, index + 1, prefix, position, array_filters, paths);
+            prefix.pop();
+            result
+        };
+
+        match segment {
+            Segment::Field(name) => descend(name.clone(), prefix),
+            Segment::Positional => match position {
+                Some(position) => descend(position.to_string(), prefix),
+                None => Err(CommandError::new(
+                    ErrorCode::BadValue,
+                    "The positional operator did not find the match needed from the query.",
+                )),
+            },
+            Segment::AllElements | Segment::Filtered(_) => {
+                let items = match current {
+                    Some(Value::Array(items)) => items,
+                    None => {
+                        return Err(CommandError::new(
+                            ErrorCode::BadValue,
+                            format!(
+                                "The path '{}' must exist in the document in order to apply array updates.",
+                                prefix.join(".")
+                            ),
+                        ));
+                    }
+                    Some(value) => {
+                        return Err(CommandError::new(
+                            ErrorCode::BadValue,
+                            format!(
+                                "Cannot apply array updates to non-array element {}: {}",
+                                prefix.last().map(String::as_str).unwrap_or_default(),
+                                value
+                            ),
+                        ));
+                    }
+                };
+                for (i, item) in items.0.iter().enumerate() {
+                    if let Segment::Filtered(identifier) = segment {
+                        let filter = &array_filters[identifier];
+                        let mut element = Document::new();
+                        element.insert(identifier.clone(), item.clone());
+                        if !filter.matches(&element) {
+                            continue;
+                        }
+                    }
+                    descend(i.to_string(), prefix)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+fn child<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    match value {
+        Value::Document(doc) => doc.get(key),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.0.get(i)),
+        _ => None,
+    }
+}
+
+/// Reads the value at a concrete path.
+pub fn get_path<'a>(doc: &'a Document, path: &[String]) -> Option<&'a Value> {
+    let mut current = doc.get(&path[0])?;
+    for key in &path[1..] {
+        current = child(current, key)?;
+    }
+    Some(current)
+}
+
+fn path_not_viable(field: &str, parent: &str, value: &Value) -> CommandError {
+    CommandError::new(
+        ErrorCode::PathNotViable,
+        format!(
+            "Cannot create field '{}' in element {{{}: {}}}",
+            field, parent, value
+        ),
+    )
+}
+
+/// The most nulls an update may pad an array with to reach an index, like
+/// mongod's.
+const MAX_PADDING: usize = 1_500_000;
+
+/// Pads `items` with nulls up to `len` elements.
+fn pad(items: &mut Vec<Value>, len: usize) -> CommandResult<()> {
+    if len - items.len() > MAX_PADDING {
+        return Err(CommandError::new(
+            ErrorCode::BadValue,
+            format!("can't backfill more than {} elements", MAX_PADDING),
+        ));
+    }
+    items.resize(len, Value::Null);
+    Ok(())
+}
+
+enum Container<'a> {
+    Document(&'a mut Document),
+    Array(&'a mut Vec<Value>),
+}
+
+/// Finds the container holding the last segment of `path`, creating
+/// intermediate documents (and padding arrays with nulls) along the way when
+/// `create` is set.
+fn parent_mut<'a>(
+    doc: &'a mut Document,
+    path: &[String],
+    create: bool,
+) -> CommandResult<Option<Container<'a>>> {
+    let mut current = Container::Document(doc);
+    for (i, key) in path[..path.len() - 1].iter().enumerate() {
+        let next = match current {
+            Container::Document(doc) => {
+                if !doc.contains_key(key) {
+                    if !create {
+                        return Ok(None);
+                    }
+                    doc.insert(key.clone(), Value::Document(Document::new()));
+                }
+                doc.get_mut(key).expect("field was just checked")
+            }
+            Container::Array(items) => {
+                let Ok(index) = key.parse::<usize>() else {
+                    if !create {
+                        return Ok(None);
+                    }
+                    let parent = Value::Array(Array(items.clone()));
+                    return Err(path_not_viable(key, &path[i - 1], &parent));
+                };
+                if index >= items.len() {
+                    if !create {
+                        return Ok(None);
+                    }
+                    pad(items, index)?;
+                    items.push(Value::Document(Document::new()));
+                }
+                &mut items[index]
+            }
+        };
+        current = match next {
+            Value::Document(doc) => Container::Document(doc),
+            Value::Array(items) => Container::Array(&mut items.0),
+            value => {
+                if !create {
+                    return Ok(None);
+                }
+                return Err(path_not_viable(&path[i + 1], key, value));
+            }
+        };
+    }
+    Ok(Some(current))
+}
+
+/// Sets the value at a concrete path, creating missing parents.
+pub fn set_path(doc: &mut Document, path: &[String], value: Value) -> CommandResult<()> {
+    let key = path.last().expect("paths are never empty");
+    match parent_mut(doc, path, true)?.expect("parents are created") {
+        Container::Document(doc) => {
+            doc.insert(key.clone(), value);
+        }
+        Container::Array(items) => {
+            let Ok(index) = key.parse::<usize>() else {
+                let parent = Value::Array(Array(items.clone()));
+                return Err(path_not_viable(key, &path[path.len() - 2], &parent));
+            };
+            if index >= items.len() {
+                pad(items, index + 1)?;
+            }
+            items[index] = value;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the value at a concrete path. Array elements are set to null
+/// rather than removed, so other elements keep their positions.
+pub fn unset_path(doc: &mut Document, path: &[String]) -> CommandResult<Option<Value>> {
+    let key = path.last().expect("paths are never empty");
+    Ok(match parent_mut(doc, path, false)? {
+        Some(Container::Document(doc)) => doc.remove(key),
+        Some(Container::Array(items)) => match key.parse::<usize>() {
+            Ok(index) if index < items.len() => {
+                Some(std::mem::replace(&mut items[index], Value::Null))
+            }
+            _ => None,
+        },
+        None => None,
+    })
+}
+
+/// Returns the first field along `path` (excluding the last) that holds an
+/// array, if any.
+pub fn array_along_path(doc: &Document, path: &[String]) -> Option<String> {
+    (1..path.len()).find_map(|len| match get_path(doc, &path[..len]) {
+        Some(Value::Array(_)) => Some(path[..len].join(".")),
+        _ => None,
+    })
+}